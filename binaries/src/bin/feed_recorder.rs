@@ -0,0 +1,49 @@
+#![allow(unused_crate_dependencies)]
+use std::{path::PathBuf, time::Duration};
+
+use clap::Parser;
+use server::{LevelFilter, LogFormat, RecordSource, Result, UpstreamNode, init_logging, record_feed};
+
+// Records the event stream of a node, or the relay feed of a running server, for `feed_replayer` to play back.
+#[derive(Debug, Parser)]
+#[command(author, version, about)]
+#[command(group = clap::ArgGroup::new("source").required(true))]
+struct Args {
+    /// Node to record, as `<data dir>[=<info url>]` like the server's `--upstream`. The recording starts once
+    /// the book is built from the node's snapshot.
+    #[arg(long, group = "source")]
+    upstream: Option<UpstreamNode>,
+
+    /// Relay feed to record, as `host:port` of a server started with `--relay-port`.
+    #[arg(long, group = "source")]
+    relay_from: Option<String>,
+
+    /// File to write the recording to.
+    #[arg(long)]
+    output: PathBuf,
+
+    /// Stop recording after this many seconds. Records until interrupted when not set.
+    #[arg(long)]
+    duration_secs: Option<u64>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let _logging = init_logging(LogFormat::Text, LevelFilter::INFO, None)?;
+    let source = match (args.upstream, args.relay_from) {
+        (Some(node), _) => RecordSource::Node(node),
+        (None, Some(address)) => RecordSource::Relay(address),
+        (None, None) => return Err("one of --upstream and --relay-from is required".into()),
+    };
+    let duration = args.duration_secs.map(Duration::from_secs);
+    let stop = async move {
+        match duration {
+            Some(duration) => tokio::time::sleep(duration).await,
+            None => {
+                let _unused = tokio::signal::ctrl_c().await;
+            }
+        }
+    };
+    record_feed(source, &args.output, stop).await
+}
@@ -0,0 +1,35 @@
+#![allow(unused_crate_dependencies)]
+use std::{net::SocketAddr, path::PathBuf};
+
+use clap::Parser;
+use server::{LevelFilter, LogFormat, Result, init_logging, replay_feed};
+use tokio::net::TcpListener;
+
+// Plays a recording of `feed_recorder` back as a relay feed. Start the server with `--relay-from <address>` to
+// ingest it.
+#[derive(Debug, Parser)]
+#[command(author, version, about)]
+struct Args {
+    /// Recording to play back.
+    #[arg(long)]
+    input: PathBuf,
+
+    /// Address to serve the relay feed at.
+    #[arg(long, default_value = "127.0.0.1:8100")]
+    address: SocketAddr,
+
+    /// Playback speed relative to the recording, e.g. `10` for ten times as fast. `0` plays it as fast as
+    /// possible.
+    #[arg(long, default_value_t = 1.0)]
+    speed: f64,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let _logging = init_logging(LogFormat::Text, LevelFilter::INFO, None)?;
+    if !args.speed.is_finite() || args.speed < 0.0 {
+        return Err("--speed can't be negative".into());
+    }
+    replay_feed(args.input, TcpListener::bind(args.address).await?, args.speed).await
+}
@@ -0,0 +1,392 @@
+#![allow(unused_crate_dependencies)]
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    str::FromStr,
+    sync::{
+        Arc, Mutex, PoisonError,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use clap::Parser;
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{Value, json};
+use server::Result;
+use tokio::time::{Instant, interval, sleep, sleep_until};
+use yawc::{CompressionLevel, FrameView, HttpRequestBuilder, OpCode, Options, WebSocket};
+
+// snapshots of big books are larger than the default limit of 1 MiB
+const MAX_PAYLOAD: usize = 64 << 20;
+
+// Opens many websocket connections to a server, and reports the throughput and latency of the messages they get
+// along with every sign of the server falling behind: `seq` gaps in l4 updates, closed connections, and messages
+// older than `--lag-threshold-ms`.
+#[derive(Debug, Parser)]
+#[command(author, version, about)]
+struct Args {
+    /// Websocket endpoint, with any connection options in the query (e.g. `ws://127.0.0.1:8000/ws?batchMs=5`).
+    #[arg(long, default_value = "ws://127.0.0.1:8000/ws")]
+    url: String,
+
+    /// Connections to open.
+    #[arg(long, default_value_t = 100)]
+    connections: usize,
+
+    /// Connections opened per second while ramping up.
+    #[arg(long, default_value_t = 100)]
+    connect_rate: u32,
+
+    /// Subscription mix, as `<channel>[:<weight>]` entries (e.g. `l2Book:3,trades:1`). Channels are `l2Book`,
+    /// `l4Book`, `bbo` and `trades`, with their default options. Subscriptions are spread over the channels by
+    /// weight, and over the coins in turn.
+    #[arg(long, default_value = "l2Book,trades", value_delimiter = ',')]
+    mix: Vec<Weighted>,
+
+    #[arg(long, default_value = "BTC,ETH", value_delimiter = ',')]
+    coins: Vec<String>,
+
+    #[arg(long, default_value_t = 1)]
+    subscriptions_per_connection: usize,
+
+    /// Ask for `permessage-deflate` at this level (0-9), to measure the server's compression.
+    #[arg(long)]
+    compression_level: Option<u32>,
+
+    /// Sent as `Authorization: Bearer <token>`.
+    #[arg(long)]
+    token: Option<String>,
+
+    /// Wait after every frame, to act as a slow client and exercise the server's `--backpressure`.
+    #[arg(long, default_value_t = 0)]
+    read_delay_ms: u64,
+
+    /// Stop after this many seconds.
+    #[arg(long, default_value_t = 60)]
+    duration_secs: u64,
+
+    #[arg(long, default_value_t = 5)]
+    report_secs: u64,
+
+    /// Age of a message, from its block time to its arrival, above which the server counts as lagging.
+    #[arg(long, default_value_t = 1000)]
+    lag_threshold_ms: u64,
+}
+
+#[derive(Debug, Clone)]
+struct Weighted {
+    channel: String,
+    weight: usize,
+}
+
+impl FromStr for Weighted {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (channel, weight) = s.split_once(':').unwrap_or((s, "1"));
+        if !["l2Book", "l4Book", "bbo", "trades"].contains(&channel) {
+            return Err(format!("unknown channel {channel:?}"));
+        }
+        let weight = weight.parse().map_err(|_| format!("invalid weight {weight:?}"))?;
+        Ok(Self { channel: channel.to_string(), weight })
+    }
+}
+
+// the `k`th subscription of the run
+fn subscription(args: &Args, k: usize) -> Value {
+    let coin = &args.coins[k % args.coins.len()];
+    let total = args.mix.iter().map(|entry| entry.weight).sum::<usize>();
+    let mut slot = (k / args.coins.len()) % total;
+    let mut channel = "";
+    for entry in &args.mix {
+        if slot < entry.weight {
+            channel = &entry.channel;
+            break;
+        }
+        slot -= entry.weight;
+    }
+    json!({"method": "subscribe", "subscription": {"type": channel, "coin": coin}})
+}
+
+#[derive(Default)]
+struct Stats {
+    connected: AtomicU64,
+    connect_failures: AtomicU64,
+    frames: AtomicU64,
+    messages: AtomicU64,
+    bytes: AtomicU64,
+    seq_gaps: AtomicU64,
+    errors: AtomicU64,
+    // ms from block time to arrival, and from leaving the server to arrival (with `--include-latency-metadata`),
+    // of the messages since the last report
+    ages: Mutex<Vec<u64>>,
+    transits: Mutex<Vec<u64>>,
+    // connections ended, by reason
+    disconnects: Mutex<HashMap<String, u64>>,
+}
+
+impl Stats {
+    fn disconnected(&self, reason: String) {
+        self.connected.fetch_sub(1, Ordering::Relaxed);
+        *self.disconnects.lock().unwrap_or_else(PoisonError::into_inner).entry(reason).or_default() += 1;
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_millis().try_into().unwrap_or(u64::MAX))
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Arc::new(Args::parse());
+    if args.coins.is_empty() || args.mix.iter().all(|entry| entry.weight == 0) {
+        return Err("--coins and --mix can't be empty".into());
+    }
+    let stats = Arc::new(Stats::default());
+    let start = Instant::now();
+    let end = start + Duration::from_secs(args.duration_secs);
+    println!("Opening {} connections to {}", args.connections, args.url);
+    tokio::spawn({
+        let (args, stats) = (args.clone(), stats.clone());
+        async move {
+            let pause = Duration::from_secs(1) / args.connect_rate.max(1);
+            for i in 0..args.connections {
+                let subscriptions = (0..args.subscriptions_per_connection)
+                    .map(|j| subscription(&args, i * args.subscriptions_per_connection + j))
+                    .collect();
+                tokio::spawn(connection(args.clone(), subscriptions, stats.clone()));
+                sleep(pause).await;
+            }
+        }
+    });
+
+    let mut reports = interval(Duration::from_secs(args.report_secs.max(1)));
+    reports.tick().await;
+    let mut last = Instant::now();
+    let mut totals = Totals::default();
+    loop {
+        tokio::select! {
+            _ = reports.tick() => {}
+            () = sleep_until(end) => break,
+        }
+        println!("{}", report(&args, &stats, &mut totals, start, last.elapsed()));
+        last = Instant::now();
+    }
+    // the part of an interval cut short by the end of the run
+    if last.elapsed() >= Duration::from_millis(100) {
+        println!("{}", report(&args, &stats, &mut totals, start, last.elapsed()));
+    } else {
+        report(&args, &stats, &mut totals, start, last.elapsed());
+    }
+    println!("{}", summary(&stats, &totals, start.elapsed()));
+    Ok(())
+}
+
+async fn connection(args: Arc<Args>, subscriptions: Vec<Value>, stats: Arc<Stats>) {
+    let mut options = Options::default().with_max_payload_read(MAX_PAYLOAD).with_max_read_buffer(2 * MAX_PAYLOAD);
+    if let Some(level) = args.compression_level {
+        options = options.with_compression_level(CompressionLevel::new(level));
+    }
+    let mut request = HttpRequestBuilder::new();
+    if let Some(token) = &args.token {
+        request = request.header("Authorization", format!("Bearer {token}"));
+    }
+    let url = match args.url.parse() {
+        Ok(url) => url,
+        Err(err) => {
+            eprintln!("Invalid url {}: {err}", args.url);
+            stats.connect_failures.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+    };
+    let mut ws = match WebSocket::connect(url).with_options(options).with_request(request).await {
+        Ok(ws) => ws,
+        Err(err) => {
+            eprintln!("Unable to connect: {err}");
+            stats.connect_failures.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+    };
+    stats.connected.fetch_add(1, Ordering::Relaxed);
+    for subscription in subscriptions {
+        if let Err(err) = ws.send(FrameView::text(subscription.to_string())).await {
+            stats.disconnected(err.to_string());
+            return;
+        }
+    }
+    let mut seqs = HashMap::new();
+    let read_delay = Duration::from_millis(args.read_delay_ms);
+    while let Some(frame) = ws.next().await {
+        match frame.opcode {
+            OpCode::Text => {
+                stats.frames.fetch_add(1, Ordering::Relaxed);
+                stats.bytes.fetch_add(frame.payload.len() as u64, Ordering::Relaxed);
+                on_frame(&frame.payload, &mut seqs, &stats);
+            }
+            OpCode::Close => {
+                let reason = frame.close_code().map_or_else(
+                    || "closed".to_string(),
+                    |code| format!("closed with {}: {}", u16::from(code), frame.close_reason().unwrap_or_default()),
+                );
+                stats.disconnected(reason);
+                return;
+            }
+            _ => {}
+        }
+        if !read_delay.is_zero() {
+            sleep(read_delay).await;
+        }
+    }
+    stats.disconnected("connection dropped".to_string());
+}
+
+// a frame holds one message, or an array of them on connections with `batchMs`
+fn on_frame(payload: &[u8], seqs: &mut HashMap<String, u64>, stats: &Stats) {
+    let Ok(value) = serde_json::from_slice::<Value>(payload) else {
+        stats.errors.fetch_add(1, Ordering::Relaxed);
+        return;
+    };
+    let now = now_ms();
+    let msgs = match value {
+        Value::Array(msgs) => msgs,
+        msg => vec![msg],
+    };
+    for msg in msgs {
+        stats.messages.fetch_add(1, Ordering::Relaxed);
+        let data = &msg["data"];
+        match msg["channel"].as_str() {
+            Some("error") => {
+                stats.errors.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            None if msg.get("error").is_some() => {
+                stats.errors.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            Some("l4Book") => check_seq(data, seqs, stats),
+            _ => {}
+        }
+        let latency = &msg["latency"];
+        let block_time = latency["nodeTime"].as_u64().or_else(|| block_time(data));
+        if let Some(time) = block_time {
+            stats.ages.lock().unwrap_or_else(PoisonError::into_inner).push(now.saturating_sub(time));
+        }
+        if let Some(time) = latency["sendTime"].as_u64() {
+            stats.transits.lock().unwrap_or_else(PoisonError::into_inner).push(now.saturating_sub(time));
+        }
+    }
+}
+
+// the time of the block a message is from, in ms
+fn block_time(data: &Value) -> Option<u64> {
+    match data {
+        Value::Array(trades) => trades.iter().filter_map(|trade| trade["time"].as_u64()).min(),
+        data => ["Updates", "Snapshot"]
+            .iter()
+            .fold(data["time"].as_u64(), |time, kind| time.or_else(|| data[kind]["time"].as_u64())),
+    }
+}
+
+// l4 updates follow each other by `seq`, or skip the ones merged into them with `--backpressure conflate`
+fn check_seq(data: &Value, seqs: &mut HashMap<String, u64>, stats: &Stats) {
+    if let Some(snapshot) = data.get("Snapshot") {
+        if let (Some(coin), Some(seq)) = (snapshot["coin"].as_str(), snapshot["seq"].as_u64()) {
+            seqs.insert(coin.to_string(), seq);
+        }
+        return;
+    }
+    let updates = &data["Updates"];
+    let coin =
+        updates["book_diffs"][0]["coin"].as_str().or_else(|| updates["order_statuses"][0]["order"]["coin"].as_str());
+    let (Some(coin), Some(seq)) = (coin, updates["seq"].as_u64()) else {
+        return;
+    };
+    if let Some(last) = seqs.insert(coin.to_string(), seq)
+        && seq > last + 1
+    {
+        stats.seq_gaps.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Default)]
+struct Totals {
+    messages: u64,
+    bytes: u64,
+    frames: u64,
+    max_age: u64,
+    lagging_reports: u64,
+}
+
+// (p50, p99, max) of the samples since the last report
+fn percentiles(samples: &Mutex<Vec<u64>>) -> Option<(u64, u64, u64)> {
+    let mut samples = std::mem::take(&mut *samples.lock().unwrap_or_else(PoisonError::into_inner));
+    samples.sort_unstable();
+    let at = |q: usize| samples[(samples.len() - 1) * q / 100];
+    (!samples.is_empty()).then(|| (at(50), at(99), at(100)))
+}
+
+fn report(args: &Args, stats: &Stats, totals: &mut Totals, start: Instant, interval: Duration) -> String {
+    let secs = interval.as_secs_f64().max(f64::EPSILON);
+    let messages = stats.messages.load(Ordering::Relaxed);
+    let bytes = stats.bytes.load(Ordering::Relaxed);
+    let frames = stats.frames.load(Ordering::Relaxed);
+    let mut line = format!(
+        "{:>5}s  conns {}/{}  msgs/s {:.0}  frames/s {:.0}  MB/s {:.2}",
+        start.elapsed().as_secs(),
+        stats.connected.load(Ordering::Relaxed),
+        args.connections,
+        (messages - totals.messages) as f64 / secs,
+        (frames - totals.frames) as f64 / secs,
+        (bytes - totals.bytes) as f64 / secs / 1e6,
+    );
+    (totals.messages, totals.bytes, totals.frames) = (messages, bytes, frames);
+    if let Some((p50, p99, max)) = percentiles(&stats.ages) {
+        let _unused = write!(line, "  age p50 {p50}ms p99 {p99}ms max {max}ms");
+        totals.max_age = totals.max_age.max(max);
+        if p99 > args.lag_threshold_ms {
+            totals.lagging_reports += 1;
+            let _unused = write!(line, "  LAGGING");
+        }
+    }
+    if let Some((p50, p99, _)) = percentiles(&stats.transits) {
+        let _unused = write!(line, "  transit p50 {p50}ms p99 {p99}ms");
+    }
+    for (name, counter) in
+        [("connect failures", &stats.connect_failures), ("seq gaps", &stats.seq_gaps), ("errors", &stats.errors)]
+    {
+        let count = counter.load(Ordering::Relaxed);
+        if count > 0 {
+            let _unused = write!(line, "  {name} {count}");
+        }
+    }
+    let disconnects = stats.disconnects.lock().unwrap_or_else(PoisonError::into_inner).values().sum::<u64>();
+    if disconnects > 0 {
+        let _unused = write!(line, "  disconnects {disconnects}");
+    }
+    line
+}
+
+fn summary(stats: &Stats, totals: &Totals, elapsed: Duration) -> String {
+    let secs = elapsed.as_secs_f64().max(f64::EPSILON);
+    let mut summary = format!(
+        "Received {} messages in {} frames ({:.1} MB) in {:.0}s, {:.0} messages/s. Oldest message {}ms.",
+        totals.messages,
+        totals.frames,
+        totals.bytes as f64 / 1e6,
+        secs,
+        totals.messages as f64 / secs,
+        totals.max_age,
+    );
+    if totals.lagging_reports > 0 {
+        let _unused = write!(summary, "\nLagging in {} reports.", totals.lagging_reports);
+    }
+    let seq_gaps = stats.seq_gaps.load(Ordering::Relaxed);
+    if seq_gaps > 0 {
+        let _unused = write!(summary, "\n{seq_gaps} seq gaps in l4 updates: the server dropped messages.");
+    }
+    for (reason, count) in stats.disconnects.lock().unwrap_or_else(PoisonError::into_inner).iter() {
+        let _unused = write!(summary, "\n{count} connections {reason}");
+    }
+    summary
+}
@@ -1,19 +1,161 @@
 #![allow(unused_crate_dependencies)]
-use std::net::Ipv4Addr;
+use std::{
+    collections::BTreeMap,
+    env, fmt, fs,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
 
-use clap::Parser;
-use server::{Result, run_websocket_server};
+use clap::{ArgAction, Parser};
+use serde::{Deserialize, Deserializer, de};
+use server::{
+    AnalyticsConfig, ArchiveConfig, AuthConfig, BackpressurePolicy, BandwidthPolicy, CandleConfig, CandleInterval,
+    ConnectionLimits, CorsConfig, CrossedBookPolicy, DeflateConfig, ElectionConfig, FileSnapshotStore,
+    InactivityPolicy, JournalConfig, JwtValidator, KeepaliveConfig, LevelFilter, LoadShedding, LogFormat, MarketConfig,
+    NatsSink, OtlpConfig, ProxyConfig, PublisherConfig, RateLimits, RedisSnapshotStore, ReloadHook, Result,
+    S3ArchiveStore, ServerConfig, SigningConfig, SnapshotStore, SnapshotStoreConfig, SnapshotStrategy, SocketOptions,
+    StaticKeys, TcpKeepalive, Tenant, TlsConfig, TrustedProxy, UpstreamNode, Validator, WatchdogConfig, WebhookConfig,
+    check_websocket_server, dump_schema, init_logging, run_websocket_server,
+};
 
-#[derive(Debug, Parser)]
+// Every option can also be set through an `ORDERBOOK_<OPTION>` environment variable or in the `--config` file,
+// in that order of precedence. Switches take an optional value, so that `--dual-stack=false` or
+// `ORDERBOOK_DUAL_STACK=false` turns off what the file turned on.
+// On SIGHUP or `POST /reload` on the admin port the file is read again, and the rate limits, connection limits,
+// compression level, subscription limit and log level in it are applied without dropping connections.
+#[derive(Debug, Clone, Default, Parser, Deserialize)]
 #[command(author, version, about)]
+#[serde(default, deny_unknown_fields)]
 struct Args {
-    /// Server address (e.g., 0.0.0.0)
+    /// TOML file with any of the options below, named like the flags with `_` instead of `-`
+    /// (e.g. `port = 8000`, `upstreams = ["/home/node-a"]`).
+    #[arg(long, env = "ORDERBOOK_CONFIG")]
+    #[serde(skip)]
+    config: Option<PathBuf>,
+
+    /// Check the configuration, the TLS certificate and key, the ports and the upstream nodes, print what was
+    /// found, and exit without serving. Exits with an error if anything would keep the server from starting.
     #[arg(long)]
-    address: Ipv4Addr,
+    #[serde(skip)]
+    check: bool,
+
+    /// Write the JSON Schemas of the websocket messages of every protocol version and the protobuf definitions of
+    /// the gRPC service to this directory, for clients to generate their types from, and exit without serving.
+    #[arg(long, value_name = "DIR")]
+    #[serde(skip)]
+    dump_schema: Option<PathBuf>,
+
+    /// Server address, IPv4 or IPv6 (e.g., 0.0.0.0 or ::)
+    #[arg(long, env = "ORDERBOOK_ADDRESS")]
+    address: Option<IpAddr>,
 
     /// Server port (e.g., 8000)
-    #[arg(long)]
-    port: u16,
+    #[arg(long, env = "ORDERBOOK_PORT")]
+    port: Option<u16>,
+
+    /// Accept both IPv4 and IPv6 clients on a single IPv6 socket (e.g. `--address :: --dual-stack`).
+    /// Only valid with an IPv6 address.
+    #[arg(long, env = "ORDERBOOK_DUAL_STACK", num_args = 0..=1, default_missing_value = "true", action = ArgAction::Set)]
+    dual_stack: Option<bool>,
+
+    /// Listen with `SO_REUSEPORT`, so that the next release can start listening on the same ports before this
+    /// process is stopped and drains its connections. Unix only.
+    #[arg(long, env = "ORDERBOOK_REUSE_PORT", num_args = 0..=1, default_missing_value = "true", action = ArgAction::Set)]
+    reuse_port: Option<bool>,
+
+    /// Also accept connections on a Unix domain socket at this path (e.g. `/run/orderbook.sock`), for sidecars on
+    /// the same host. It serves the same endpoints as `--port`, without TLS. Unix only.
+    #[arg(long, env = "ORDERBOOK_UNIX_SOCKET")]
+    unix_socket: Option<PathBuf>,
+
+    /// Accept the connections of `--port`, read from them and write to them through `io_uring`. For plain
+    /// connections only, without TLS or the PROXY protocol. Needs Linux and a build with the `io-uring` feature.
+    #[arg(long, env = "ORDERBOOK_IO_URING", num_args = 0..=1, default_missing_value = "true", action = ArgAction::Set)]
+    io_uring: Option<bool>,
+
+    /// Leave Nagle's algorithm on for the connections of `--port`, which holds small frames back to fill a segment.
+    /// By default every frame is sent right away (`TCP_NODELAY`).
+    #[arg(long, env = "ORDERBOOK_TCP_NAGLE", num_args = 0..=1, default_missing_value = "true", action = ArgAction::Set)]
+    tcp_nagle: Option<bool>,
+
+    /// Probe connections idle for this long with TCP keepalives, to find clients that are gone without a trace.
+    /// Off when not set.
+    #[arg(long, env = "ORDERBOOK_TCP_KEEPALIVE_IDLE_SECS")]
+    tcp_keepalive_idle_secs: Option<u64>,
+
+    /// Time between two TCP keepalive probes. Default is 10.
+    #[arg(long, env = "ORDERBOOK_TCP_KEEPALIVE_INTERVAL_SECS")]
+    tcp_keepalive_interval_secs: Option<u64>,
+
+    /// Unanswered TCP keepalive probes in a row after which the kernel closes a connection. Default is 3, Windows always sends 10.
+    #[arg(long, env = "ORDERBOOK_TCP_KEEPALIVE_RETRIES")]
+    tcp_keepalive_retries: Option<u32>,
+
+    /// Send buffer (`SO_SNDBUF`) of the connections of `--port`, in KiB. Tuned by the kernel when not set.
+    #[arg(long, env = "ORDERBOOK_TCP_SEND_BUFFER_KB")]
+    tcp_send_buffer_kb: Option<usize>,
+
+    /// Receive buffer (`SO_RCVBUF`) of the connections of `--port`, in KiB. Tuned by the kernel when not set.
+    #[arg(long, env = "ORDERBOOK_TCP_RECV_BUFFER_KB")]
+    tcp_recv_buffer_kb: Option<usize>,
+
+    /// Connections the kernel queues on each port until the server accepts them. Default is 1024; the kernel caps
+    /// it at `net.core.somaxconn`.
+    #[arg(long, env = "ORDERBOOK_LISTEN_BACKLOG")]
+    listen_backlog: Option<u32>,
+
+    /// Node to ingest events from, as `<data dir>[=<info url>]`: the directory containing the node's `hl/data`
+    /// and its info endpoint (default `http://localhost:3001/info`). Repeat to read from several nodes at once;
+    /// the stream keeps going as long as one of them is healthy. Defaults to a single node writing to the home
+    /// directory.
+    #[arg(long = "upstream", env = "ORDERBOOK_UPSTREAMS", value_delimiter = ',')]
+    #[serde(deserialize_with = "parse_all")]
+    upstreams: Vec<UpstreamNode>,
+
+    /// Further market to read in the same process, as `<name>:<upstream>` with the upstream given like
+    /// `--upstream` (e.g. `testnet:/home/testnet=http://localhost:3002/info`). Its coins are served as
+    /// `<name>:<coin>`. Repeat for more markets, or for more nodes of one market.
+    #[arg(long = "market", env = "ORDERBOOK_MARKETS", value_delimiter = ',')]
+    #[serde(deserialize_with = "parse_all")]
+    markets: Vec<MarketConfig>,
+
+    /// What to do when the node's deltas leave a book crossed or locked: `flag` (default) logs it, counts it in
+    /// `crossed_books_total` and lists the coin in the feed status, `withhold` also stops serving the market's books
+    /// until a new snapshot from the node replaces them.
+    #[arg(long, env = "ORDERBOOK_CROSSED_BOOKS")]
+    #[serde(deserialize_with = "parse")]
+    crossed_books: Option<CrossedBookPolicy>,
+
+    /// `--crossed-books` of one of the `--market`s, as `<name>=<flag|withhold>`. Repeat for more markets.
+    #[arg(long = "market-crossed-books", env = "ORDERBOOK_MARKET_CROSSED_BOOKS", value_delimiter = ',')]
+    market_crossed_books: Vec<String>,
+
+    /// When the books of the `--market`s are loaded: `eager` (default) at startup, `lazy` once a client subscribes
+    /// to one of the market's coins, dropping them again after `--lazy-idle-secs` without subscribers. Lazy markets
+    /// don't hold up readiness. The primary market is always loaded at startup.
+    #[arg(long, env = "ORDERBOOK_SNAPSHOT_STRATEGY")]
+    #[serde(deserialize_with = "parse")]
+    snapshot_strategy: Option<SnapshotStrategy>,
+
+    /// `--snapshot-strategy` of one of the `--market`s, as `<name>=<eager|lazy>`. Repeat for more markets.
+    #[arg(long = "market-snapshot-strategy", env = "ORDERBOOK_MARKET_SNAPSHOT_STRATEGY", value_delimiter = ',')]
+    market_snapshot_strategy: Vec<String>,
+
+    /// Seconds the books of a lazy market are kept without a subscriber. Default is 300.
+    #[arg(long, env = "ORDERBOOK_LAZY_IDLE_SECS")]
+    lazy_idle_secs: Option<u64>,
+
+    /// Seconds between audits of the books against a snapshot fetched from the node. Default is 10.
+    #[arg(long, env = "ORDERBOOK_AUDIT_INTERVAL_SECS")]
+    audit_interval_secs: Option<u64>,
+
+    /// Orders that may differ from the node's snapshot at an audit before the books are replaced by it and every
+    /// client gets a fresh snapshot. Default is 0, healing any divergence.
+    #[arg(long, env = "ORDERBOOK_AUDIT_HEAL_THRESHOLD")]
+    audit_heal_threshold: Option<usize>,
 
     /// Compression level for WebSocket connections.
     /// Accepts values in the range `0..=9`.
@@ -23,28 +165,1029 @@ struct Args {
     ///
     /// The level is passed to `flate2::Compression::new(level)`; see the
     /// documentation for <https://docs.rs/flate2/1.1.2/flate2/struct.Compression.html#method.new> for more info.
-    #[arg(long)]
+    #[arg(long, env = "ORDERBOOK_WEBSOCKET_COMPRESSION_LEVEL")]
     websocket_compression_level: Option<u32>,
 
+    /// Compress each message once for every connection that receives it, instead of once per connection.
+    /// Saves CPU with many clients on the same channels, at a slightly lower compression ratio since clients are
+    /// asked to accept compression without context takeover.
+    #[arg(long, env = "ORDERBOOK_SHARED_COMPRESSION", num_args = 0..=1, default_missing_value = "true", action = ArgAction::Set)]
+    shared_compression: Option<bool>,
+
+    /// LZ77 window the server compresses with, `9..=15` bits (default 15). Smaller windows take less memory per
+    /// connection and compress worse. Needs context takeover, so not with `--shared-compression`.
+    #[arg(long, env = "ORDERBOOK_WEBSOCKET_SERVER_MAX_WINDOW_BITS")]
+    websocket_server_max_window_bits: Option<u8>,
+
+    /// LZ77 window clients are asked to compress with, `9..=15` bits, if they offer to limit it.
+    #[arg(long, env = "ORDERBOOK_WEBSOCKET_CLIENT_MAX_WINDOW_BITS")]
+    websocket_client_max_window_bits: Option<u8>,
+
+    /// Compress every message on its own rather than with the context of the previous ones.
+    #[arg(long, env = "ORDERBOOK_WEBSOCKET_SERVER_NO_CONTEXT_TAKEOVER", num_args = 0..=1, default_missing_value = "true", action = ArgAction::Set)]
+    websocket_server_no_context_takeover: Option<bool>,
+
+    /// Ask clients to compress every message on its own, so that the server keeps no decompression context.
+    #[arg(long, env = "ORDERBOOK_WEBSOCKET_CLIENT_NO_CONTEXT_TAKEOVER", num_args = 0..=1, default_missing_value = "true", action = ArgAction::Set)]
+    websocket_client_no_context_takeover: Option<bool>,
+
+    /// Dictionary for the frames of clients that negotiate `orderbook.msgpack.zstd`, as written by
+    /// `zstd_dictionary`. The built-in dictionary is used when not set.
+    #[arg(long, env = "ORDERBOOK_ZSTD_DICTIONARY")]
+    zstd_dictionary: Option<PathBuf>,
+
     /// Inactivity timeout in seconds before server exits.
     /// If no node events are observed for this duration, the process exits.
     /// Default and minimum is 5 seconds.
-    #[arg(long)]
+    #[arg(long, env = "ORDERBOOK_INACTIVITY_EXIT_SECS")]
     inactivity_exit_secs: Option<u64>,
+
+    /// Instead of exiting after `--inactivity-exit-secs`, tell clients that the stream is stale and keep trying to
+    /// read the node, with exponential backoff. The server exits once there has been no new block for this many
+    /// seconds.
+    #[arg(long, env = "ORDERBOOK_INACTIVITY_DEADLINE_SECS")]
+    inactivity_deadline_secs: Option<u64>,
+
+    /// Path to a PEM encoded TLS certificate chain. Enables `wss://` when set together with `--tls-key`.
+    /// The certificate and key are reloaded automatically when the files change on disk.
+    #[arg(long, env = "ORDERBOOK_TLS_CERT")]
+    tls_cert: Option<PathBuf>,
+
+    /// Path to the PEM encoded private key matching `--tls-cert`.
+    #[arg(long, env = "ORDERBOOK_TLS_KEY")]
+    tls_key: Option<PathBuf>,
+
+    /// Experimental: also serve the websocket protocol over WebTransport (HTTP/3) on this UDP port, with a stream
+    /// per subscription. Needs `--tls-cert` and a build with the `webtransport` feature.
+    #[arg(long, env = "ORDERBOOK_WEBTRANSPORT_PORT")]
+    webtransport_port: Option<u16>,
+
+    /// Expect a PROXY protocol (v1 or v2) header, as sent by `HAProxy` or a cloud load balancer, ahead of every
+    /// connection to `--port`, and take the client's address from it. Connections without one are refused.
+    #[arg(long, env = "ORDERBOOK_PROXY_PROTOCOL", num_args = 0..=1, default_missing_value = "true", action = ArgAction::Set)]
+    proxy_protocol: Option<bool>,
+
+    /// Proxy, or network of proxies (e.g. `10.0.0.0/8`), whose `X-Forwarded-For` header gives the client's
+    /// address. Repeat for more.
+    #[arg(long = "trusted-proxy", env = "ORDERBOOK_TRUSTED_PROXIES", value_delimiter = ',')]
+    #[serde(deserialize_with = "parse_all")]
+    trusted_proxies: Vec<TrustedProxy>,
+
+    /// Origin of browser pages allowed to use the REST, SSE and websocket endpoints, e.g.
+    /// `https://dashboard.example.com`, or `*` for any. Repeat for more. Once set, browsers of other origins are
+    /// refused.
+    #[arg(long = "cors-origin", env = "ORDERBOOK_CORS_ORIGINS", value_delimiter = ',')]
+    cors_origins: Vec<String>,
+
+    /// Request header those pages may send, replacing the default `authorization` and `x-api-key`. Repeat for more.
+    #[arg(long = "cors-header", env = "ORDERBOOK_CORS_HEADERS", value_delimiter = ',')]
+    cors_headers: Vec<String>,
+
+    /// How long browsers may cache the answers to their CORS preflight requests [default: 600].
+    #[arg(long, env = "ORDERBOOK_CORS_MAX_AGE_SECS")]
+    cors_max_age_secs: Option<u64>,
+
+    /// File of API keys clients may authenticate with, one `<name> <key> [max connections] [low|normal|high]` per
+    /// line, the last being the key's priority under `--shed-load-*`.
+    /// Keys are sent as `Authorization: Bearer <key>` or `X-API-Key: <key>`.
+    #[arg(long, env = "ORDERBOOK_API_KEYS_FILE")]
+    api_keys_file: Option<PathBuf>,
+
+    /// File containing the secret for HS256 signed JWTs clients may authenticate with.
+    /// Tokens need a `sub` and an `exp` claim, and are sent like API keys.
+    #[arg(long, env = "ORDERBOOK_JWT_SECRET_FILE")]
+    jwt_secret_file: Option<PathBuf>,
+
+    /// Maximum number of open connections per API key or JWT subject.
+    /// Unlimited when not set; a key's own limit in `--api-keys-file` takes precedence.
+    #[arg(long, env = "ORDERBOOK_MAX_CONNECTIONS_PER_KEY")]
+    max_connections_per_key: Option<usize>,
+
+    /// Tenants sharing the server, only in the config file as `[[tenants]]` tables; see `TenantFile`.
+    #[arg(skip)]
+    tenants: Vec<TenantFile>,
+
+    /// Build the frames of an API key's connections with the same subscriptions once for all of them, so opening
+    /// many duplicate connections costs about as much as one. Aligns their batch windows to the clock. Needs auth.
+    #[arg(long, env = "ORDERBOOK_COALESCE_CONNECTIONS", num_args = 0..=1, default_missing_value = "true", action = ArgAction::Set)]
+    coalesce_connections: Option<bool>,
+
+    /// Port for a Prometheus `/metrics` HTTP endpoint, served on the same address as the websocket server.
+    /// Metrics are disabled when not set.
+    #[arg(long, env = "ORDERBOOK_METRICS_PORT")]
+    metrics_port: Option<u16>,
+
+    /// Port for the `/healthz` and `/readyz` HTTP probes, served on the same address as the websocket server.
+    /// Disabled when not set.
+    #[arg(long, env = "ORDERBOOK_HEALTH_PORT")]
+    health_port: Option<u16>,
+
+    /// Port for the gRPC `OrderBook` service (see `server/proto/orderbook.proto`), served on the same address as
+    /// the websocket server. gRPC is disabled when not set.
+    #[arg(long, env = "ORDERBOOK_GRPC_PORT")]
+    grpc_port: Option<u16>,
+
+    /// Seconds to wait for clients to receive their pending messages and close frame on shutdown
+    /// (SIGTERM/SIGINT or inactivity exit) before exiting anyway. Default is 10 seconds.
+    #[arg(long, env = "ORDERBOOK_DRAIN_TIMEOUT_SECS")]
+    drain_timeout_secs: Option<u64>,
+
+    /// What to do when a client's send queue is full: `disconnect` (default), `drop-oldest`,
+    /// or `conflate` (merge book updates still queued for the same subscription).
+    #[arg(long, env = "ORDERBOOK_BACKPRESSURE")]
+    #[serde(deserialize_with = "parse")]
+    backpressure: Option<BackpressurePolicy>,
+
+    /// Maximum number of messages queued for a single client before the backpressure policy applies.
+    /// Default is 256.
+    #[arg(long, env = "ORDERBOOK_SEND_QUEUE_CAPACITY")]
+    send_queue_capacity: Option<usize>,
+
+    /// Milliseconds the messages of a channel may wait in a client's send queue before they are dropped rather
+    /// than sent, as `<channel>=<ms>` (e.g. `bbo=500,l2Book=1000`). A subscription whose later messages depended on
+    /// a dropped one, e.g. `l4Book` updates, gets a fresh snapshot. Channels without one wait as long as it takes.
+    #[arg(long = "message-ttl-ms", env = "ORDERBOOK_MESSAGE_TTL_MS", value_delimiter = ',')]
+    message_ttl_ms: Vec<String>,
+
+    /// Shed the stream data of low priority API keys (see `--api-keys-file`) once this percentage of the send
+    /// queue capacity of all clients together is in use: their book updates are conflated. Default is 25 when
+    /// `--shed-load-drop-percent` is set; off when neither is.
+    #[arg(long, env = "ORDERBOOK_SHED_LOAD_CONFLATE_PERCENT")]
+    shed_load_conflate_percent: Option<u8>,
+
+    /// From this percentage on, low priority clients also drop their oldest queued messages, and the book updates
+    /// of normal priority clients are conflated. High priority clients are never shed. Default is 50 when
+    /// `--shed-load-conflate-percent` is set.
+    #[arg(long, env = "ORDERBOOK_SHED_LOAD_DROP_PERCENT")]
+    shed_load_drop_percent: Option<u8>,
+
+    /// Shed load in steps while the resident memory of the process is above this many MB: first the book updates
+    /// of every client are conflated, then new connections are rejected, then the slowest clients are evicted.
+    #[arg(long, env = "ORDERBOOK_WATCHDOG_MAX_RSS_MB")]
+    watchdog_max_rss_mb: Option<u64>,
+
+    /// The same while the runtime's timers fire this many milliseconds late.
+    #[arg(long, env = "ORDERBOOK_WATCHDOG_MAX_LAG_MS")]
+    watchdog_max_lag_ms: Option<u64>,
+
+    /// How often the watchdog checks, going a step further or back each time. Default is 5.
+    #[arg(long, env = "ORDERBOOK_WATCHDOG_INTERVAL_SECS")]
+    watchdog_interval_secs: Option<u64>,
+
+    /// Percentage of the clients evicted at every check of the last step, those with the most messages queued
+    /// first. Default is 10.
+    #[arg(long, env = "ORDERBOOK_WATCHDOG_EVICT_PERCENT")]
+    watchdog_evict_percent: Option<u8>,
+
+    /// Number of channels the coins are spread over; a client is only woken up for the channels of the coins
+    /// it subscribed to. 1 sends every message to every client. Default is 8.
+    #[arg(long, env = "ORDERBOOK_BROADCAST_SHARDS")]
+    broadcast_shards: Option<usize>,
+
+    /// Maximum number of messages (subscribe, unsubscribe, ...) a client may send per second.
+    /// Clients exceeding it are disconnected with close code 1008. Unlimited when not set.
+    #[arg(long, env = "ORDERBOOK_CLIENT_MESSAGES_PER_SEC")]
+    client_messages_per_sec: Option<u32>,
+
+    /// Maximum number of messages sent to a single client per second. Excess messages are queued,
+    /// subject to `--backpressure`. Unlimited when not set.
+    #[arg(long, env = "ORDERBOOK_OUTBOUND_MESSAGES_PER_SEC")]
+    outbound_messages_per_sec: Option<u32>,
+
+    /// Maximum number of payload bytes (before compression) sent to a single websocket client per minute.
+    /// Unlimited when not set.
+    #[arg(long, env = "ORDERBOOK_OUTBOUND_BYTES_PER_MIN")]
+    outbound_bytes_per_min: Option<u64>,
+
+    /// What to do with a client over `--outbound-bytes-per-min`: `throttle` (default) holds its messages back,
+    /// subject to `--backpressure`, and `disconnect` closes it with close code 1008.
+    #[arg(long, env = "ORDERBOOK_BANDWIDTH_POLICY")]
+    #[serde(deserialize_with = "parse")]
+    bandwidth_policy: Option<BandwidthPolicy>,
+
+    /// Maximum number of new connections and REST requests per client IP address per minute.
+    /// Excess requests are answered with `429 Too Many Requests`. Unlimited when not set.
+    #[arg(long, env = "ORDERBOOK_CONNECTIONS_PER_IP_PER_MIN")]
+    connections_per_ip_per_min: Option<u32>,
+
+    /// Send a websocket Ping to every client this often, and close clients that miss `--max-missed-pongs`
+    /// of them in a row. Off when not set.
+    #[arg(long, env = "ORDERBOOK_PING_INTERVAL_SECS")]
+    ping_interval_secs: Option<u64>,
+
+    /// Consecutive unanswered pings after which a client is closed. Default is 3.
+    #[arg(long, env = "ORDERBOOK_MAX_MISSED_PONGS")]
+    max_missed_pongs: Option<u32>,
+
+    /// Send every client a `heartbeat` message with the latest sequence numbers this often. Off when not set.
+    #[arg(long, env = "ORDERBOOK_HEARTBEAT_INTERVAL_SECS")]
+    heartbeat_interval_secs: Option<u64>,
+
+    /// Send every client a `time` message with the server's clock this often, to estimate their clock offset with.
+    /// Off when not set.
+    #[arg(long, env = "ORDERBOOK_TIME_INTERVAL_SECS")]
+    time_interval_secs: Option<u64>,
+
+    /// Journal l4 book updates to this directory so clients can replay them with `{"method":"replay",...}`.
+    /// The directory is cleared on startup. Replay is disabled when not set.
+    #[arg(long, env = "ORDERBOOK_JOURNAL_DIR")]
+    journal_dir: Option<PathBuf>,
+
+    /// Maximum size of the journal in MB; the oldest updates are dropped beyond it. Default is 1024.
+    #[arg(long, env = "ORDERBOOK_JOURNAL_MAX_MB")]
+    journal_max_mb: Option<u64>,
+
+    /// Keep this many seconds of l4 book updates in memory, so that clients that reconnect can get the ones they
+    /// missed with `{"method":"resume",...}` instead of a snapshot. Resumes get a snapshot when not set.
+    #[arg(long, env = "ORDERBOOK_RESUME_WINDOW_SECS")]
+    resume_window_secs: Option<u64>,
+
+    /// Give every websocket connection a session token, and keep its subscriptions, message format and l4 book
+    /// positions this many seconds after it drops, for a client reconnecting with `/ws?session=<token>`.
+    #[arg(long, env = "ORDERBOOK_SESSION_GRACE_SECS")]
+    session_grace_secs: Option<u64>,
+
+    /// Intervals of the OHLCV candles built from trades for the `candle` channel and `/candles/{market}`,
+    /// e.g. `1s,1m,5m` (the default). Units are `s`, `m`, `h` and `d`.
+    #[arg(long, env = "ORDERBOOK_CANDLE_INTERVALS", value_delimiter = ',')]
+    candle_intervals: Vec<CandleInterval>,
+
+    /// Candles kept in memory per market and interval. Default is 1000.
+    #[arg(long, env = "ORDERBOOK_CANDLE_HISTORY")]
+    candle_history: Option<usize>,
+
+    /// Publish the spread, book imbalance and VWAP of every market on the `analytics` channel.
+    #[arg(long, env = "ORDERBOOK_ANALYTICS", num_args = 0..=1, default_missing_value = "true", action = ArgAction::Set)]
+    analytics: Option<bool>,
+
+    /// How often the analytics are published, in milliseconds of block time. Default is 1000.
+    #[arg(long, env = "ORDERBOOK_ANALYTICS_INTERVAL_MS")]
+    analytics_interval_ms: Option<u64>,
+
+    /// Price levels on each side of the book that count towards its imbalance, up to 100. Default is 10.
+    #[arg(long, env = "ORDERBOOK_ANALYTICS_DEPTH")]
+    analytics_depth: Option<usize>,
+
+    /// The VWAP is of the trades of this many seconds. Default is 60.
+    #[arg(long, env = "ORDERBOOK_ANALYTICS_VWAP_WINDOW_SECS")]
+    analytics_vwap_window_secs: Option<u64>,
+
+    /// Sign the l4 snapshots and checkpoints of the books with the Ed25519 key in this file, a hex encoded 32 byte
+    /// seed (e.g. from `openssl rand -hex 32`). The public key is sent in the `x-signing-key` response header of
+    /// the handshake. Off when not set.
+    #[arg(long, env = "ORDERBOOK_SIGNING_KEY_FILE")]
+    signing_key_file: Option<PathBuf>,
+
+    /// How often every book is checkpointed on the `checkpoint` channel, in seconds of block time. Default is 10.
+    #[arg(long, env = "ORDERBOOK_CHECKPOINT_INTERVAL_SECS")]
+    checkpoint_interval_secs: Option<u64>,
+
+    /// Publish l4 book updates and trades to the NATS server at this `host:port`, as
+    /// `<prefix>.l4Book.<coin>` and `<prefix>.trades.<coin>`. Off when not set.
+    #[arg(long, env = "ORDERBOOK_PUBLISH_NATS")]
+    publish_nats: Option<String>,
+
+    /// Prefix of the subjects published to. Default is `orderbook`.
+    #[arg(long, env = "ORDERBOOK_PUBLISH_SUBJECT_PREFIX")]
+    publish_subject_prefix: Option<String>,
+
+    /// POST a JSON alert to this URL when a book crosses or locks, the feed goes stale or a market is halted,
+    /// and again when that is over. Repeat for more.
+    #[arg(long = "webhook-url", env = "ORDERBOOK_WEBHOOK_URLS", value_delimiter = ',')]
+    webhook_urls: Vec<String>,
+
+    /// File containing the secret the webhook bodies are signed with (HMAC-SHA256, in `X-Signature-256`).
+    /// Unsigned when not set.
+    #[arg(long, env = "ORDERBOOK_WEBHOOK_SECRET_FILE")]
+    webhook_secret_file: Option<PathBuf>,
+
+    /// Also alert when the spread of a market is wider than this many basis points.
+    #[arg(long, env = "ORDERBOOK_WEBHOOK_SPREAD_BPS")]
+    webhook_spread_bps: Option<f64>,
+
+    /// Retries of a failed webhook delivery, backing off exponentially from a second. Default is 5.
+    #[arg(long, env = "ORDERBOOK_WEBHOOK_RETRIES")]
+    webhook_retries: Option<u32>,
+
+    /// Save the order book state to this file, and continue from it after a restart instead of waiting for a
+    /// snapshot from the node. The events missed in between are read from the node's current files.
+    #[arg(long, env = "ORDERBOOK_SNAPSHOT_FILE")]
+    snapshot_file: Option<PathBuf>,
+
+    /// Like `--snapshot-file`, but saves the state to the Redis server at this `host:port`.
+    #[arg(long, env = "ORDERBOOK_SNAPSHOT_REDIS")]
+    snapshot_redis: Option<String>,
+
+    /// How often the order book state is saved. Default is 30.
+    #[arg(long, env = "ORDERBOOK_SNAPSHOT_INTERVAL_SECS")]
+    snapshot_interval_secs: Option<u64>,
+
+    /// Write snapshots of every book and all their deltas to Parquet files under this directory, partitioned by
+    /// coin and hour, for research. Off when not set.
+    #[arg(long, env = "ORDERBOOK_ARCHIVE_DIR")]
+    archive_dir: Option<PathBuf>,
+
+    /// Upload the archive's files to this `s3://<bucket>[/<prefix>]` once their hour is over, and delete them
+    /// from `--archive-dir`. Credentials are read from `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`.
+    #[arg(long, env = "ORDERBOOK_ARCHIVE_S3")]
+    archive_s3: Option<String>,
+
+    /// Region of the `--archive-s3` bucket. Default is `us-east-1`.
+    #[arg(long, env = "ORDERBOOK_ARCHIVE_S3_REGION")]
+    archive_s3_region: Option<String>,
+
+    /// Endpoint of a store with S3's API to upload to instead of AWS, e.g. `http://localhost:9000`.
+    #[arg(long, env = "ORDERBOOK_ARCHIVE_S3_ENDPOINT")]
+    archive_s3_endpoint: Option<String>,
+
+    /// How often every book is snapshotted into the archive, in seconds of block time. Default is 300.
+    #[arg(long, env = "ORDERBOOK_ARCHIVE_SNAPSHOT_INTERVAL_SECS")]
+    archive_snapshot_interval_secs: Option<u64>,
+
+    /// Serve the relay feed on this port (same address as the websocket server), for edge instances started
+    /// with `--relay-from`. Keep the port private; the feed isn't authenticated.
+    #[arg(long, env = "ORDERBOOK_RELAY_PORT")]
+    relay_port: Option<u16>,
+
+    /// Run as an edge instance: follow the relay feed of the ingest instance at this `host:port` instead of
+    /// reading a node. `--upstream` is ignored.
+    #[arg(long, env = "ORDERBOOK_RELAY_FROM")]
+    relay_from: Option<String>,
+
+    /// Maximum number of subscriptions per client. Unlimited when not set.
+    #[arg(long, env = "ORDERBOOK_MAX_SUBSCRIPTIONS")]
+    max_subscriptions: Option<usize>,
+
+    /// Maximum number of open websocket connections. Connections beyond it are closed with code 1013 (try again
+    /// later). Unlimited when not set.
+    #[arg(long, env = "ORDERBOOK_MAX_CONNECTIONS")]
+    max_connections: Option<usize>,
+
+    /// Maximum number of open websocket connections per client IP address. Connections beyond it are closed with
+    /// code 1008. Unlimited when not set.
+    #[arg(long, env = "ORDERBOOK_MAX_CONNECTIONS_PER_IP")]
+    max_connections_per_ip: Option<usize>,
+
+    /// Maximum size in bytes of a message from a client, up to 1048576 (the default). Clients sending larger
+    /// messages are closed with code 1009.
+    #[arg(long, env = "ORDERBOOK_MAX_MESSAGE_BYTES")]
+    max_message_bytes: Option<usize>,
+
+    /// Add `latency: {nodeTime, ingestTime, sendTime}` (ms since the epoch) to every book, update and trade
+    /// message: the block time, when the server read the block's node events, and when it sent the message.
+    #[arg(long, env = "ORDERBOOK_INCLUDE_LATENCY_METADATA", num_args = 0..=1, default_missing_value = "true", action = ArgAction::Set)]
+    include_latency_metadata: Option<bool>,
+
+    /// Maximum log level (`off`, `error`, `warn`, `info`, `debug` or `trace`). Defaults to `RUST_LOG`, which
+    /// still limits individual modules it names.
+    #[arg(long, env = "ORDERBOOK_LOG_LEVEL")]
+    #[serde(deserialize_with = "parse")]
+    log_level: Option<LevelFilter>,
+
+    /// Write logs as `text` (default) or as `json`, one object per line with the fields of the connection or
+    /// node event it belongs to. Needs a restart to change.
+    #[arg(long, env = "ORDERBOOK_LOG_FORMAT")]
+    #[serde(deserialize_with = "parse")]
+    log_format: Option<LogFormat>,
+
+    /// OTLP/HTTP collector to export traces of the node events to, e.g. `http://localhost:4318`: their decoding,
+    /// applying to the books and publishing, and the fan-out and serialization for each client. Needs a restart
+    /// to change.
+    #[arg(long, env = "ORDERBOOK_OTLP_ENDPOINT")]
+    otlp_endpoint: Option<String>,
+
+    /// Share of traces exported to `--otlp-endpoint`, between 0 and 1. Defaults to 1.
+    #[arg(long, env = "ORDERBOOK_OTLP_SAMPLE_RATIO")]
+    otlp_sample_ratio: Option<f64>,
+
+    /// Port for the admin HTTP API, served on the same address as the websocket server: runtime settings,
+    /// connected clients and maintenance mode (see the README). Only served on loopback without
+    /// `--admin-keys-file`. Disabled when not set.
+    #[arg(long, env = "ORDERBOOK_ADMIN_PORT")]
+    admin_port: Option<u16>,
+
+    /// File of API keys accepted by the admin API, in the format of `--api-keys-file`.
+    /// The admin API only listens on the loopback address, open to anyone on the host, when not set.
+    #[arg(long, env = "ORDERBOOK_ADMIN_KEYS_FILE")]
+    admin_keys_file: Option<PathBuf>,
+
+    /// File the per API key preferences set through the admin API are saved to and read from at startup (see the
+    /// README). Kept in memory only when not set.
+    #[arg(long, env = "ORDERBOOK_PREFERENCES_FILE")]
+    preferences_file: Option<PathBuf>,
+
+    /// Start as a warm standby: follow the upstreams and build the books, but refuse clients and publish nothing
+    /// until promoted with `POST /promote` of the admin API, which needs `--admin-port`. `/readyz` reports
+    /// `standby` until then.
+    #[arg(long, env = "ORDERBOOK_STANDBY", num_args = 0..=1, default_missing_value = "true", action = ArgAction::Set)]
+    standby: Option<bool>,
+
+    /// Base URL of etcd (e.g. `http://etcd:2379`) through which the replicas of a deployment agree on which of them
+    /// serves clients. The server starts on standby and takes over once elected (see the README).
+    #[arg(long, env = "ORDERBOOK_ELECTION_ETCD")]
+    election_etcd: Option<String>,
+
+    /// Key of the election in etcd, the same for all replicas. Default is `/orderbook/leader`.
+    #[arg(long, env = "ORDERBOOK_ELECTION_KEY")]
+    election_key: Option<String>,
+
+    /// Name of this replica in the election. Defaults to `HOSTNAME`.
+    #[arg(long, env = "ORDERBOOK_ELECTION_NAME")]
+    election_name: Option<String>,
+
+    /// Seconds a leader keeps the leadership after it stopped renewing it, e.g. because it crashed, and that its
+    /// feed may be down before it hands over. Default is 10.
+    #[arg(long, env = "ORDERBOOK_ELECTION_TTL_SECS")]
+    election_ttl_secs: Option<u64>,
+}
+
+// a `[[tenants]]` table of the config file
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TenantFile {
+    name: String,
+    /// Its API keys, in the format of `--api-keys-file`.
+    api_keys_file: PathBuf,
+    /// The markets its clients may use, by the name given to `--market`, `primary` for the primary market.
+    /// Every market when not set.
+    #[serde(default)]
+    markets: Vec<String>,
+    /// Open connections of all its keys together.
+    #[serde(default)]
+    max_connections: Option<usize>,
+    /// Like `rate_limits` in the server config (e.g. `rate_limits = { client_messages_per_sec = 5 }`), replacing
+    /// the server's limits for its clients.
+    #[serde(default)]
+    rate_limits: RateLimits,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    env_logger::init();
-
     let args = Args::parse();
+    let file = args.config.as_deref().map(Args::from_file).transpose()?;
+    let options = file.map_or_else(|| args.clone(), |file| args.clone().or(file));
+    if let Some(dir) = &args.dump_schema {
+        for file in dump_schema(dir)? {
+            println!("{}", file.display());
+        }
+        return Ok(());
+    }
+    // reported on stdout, without logs
+    if args.check {
+        return check(server_config(options)).await;
+    }
+    // the level is capped by the runtime settings instead, so that it can change without a restart
+    let _logging = init_logging(options.log_format.unwrap_or_default(), LevelFilter::TRACE, otlp_config(&options)?)?;
+
+    let mut config = server_config(options)?;
+    if let Some(path) = args.config.clone() {
+        config.reload = Some(ReloadHook::new(move || server_config(args.clone().or(Args::from_file(&path)?))));
+    }
+    config.validate()?;
 
-    let full_address = format!("{}:{}", args.address, args.port);
-    println!("Running websocket server on {full_address}");
+    println!("Running websocket server on {}", config.address);
+    run_websocket_server(config).await?;
 
-    let compression_level = args.websocket_compression_level.unwrap_or(/* Some compression */ 1);
-    let inactivity_exit_secs = args.inactivity_exit_secs.unwrap_or(5).max(5);
-    run_websocket_server(&full_address, true, compression_level, inactivity_exit_secs).await?;
+    Ok(())
+}
 
+// the report of `--check`, which fails if any of its checks did
+async fn check(config: Result<ServerConfig>) -> Result<()> {
+    let config = config.inspect_err(|err| println!("FAIL configuration: {err}"))?;
+    let checks = check_websocket_server(&config).await;
+    for check in &checks {
+        println!("{check}");
+    }
+    let failed = checks.iter().filter(|check| check.result.is_err()).count();
+    if failed > 0 {
+        return Err(format!("{failed} of {} checks failed", checks.len()).into());
+    }
+    println!("All {} checks passed", checks.len());
     Ok(())
 }
+
+impl Args {
+    fn from_file(path: &Path) -> Result<Self> {
+        let contents =
+            fs::read_to_string(path).map_err(|err| format!("Unable to read config file {}: {err}", path.display()))?;
+        Ok(toml::from_str(&contents).map_err(|err| format!("Invalid config file {}: {err}", path.display()))?)
+    }
+
+    // options set on the command line (or in the environment) take precedence over the file. A line per option
+    #[allow(clippy::too_many_lines)]
+    fn or(self, file: Self) -> Self {
+        Self {
+            config: self.config,
+            check: self.check,
+            dump_schema: self.dump_schema,
+            address: self.address.or(file.address),
+            port: self.port.or(file.port),
+            dual_stack: self.dual_stack.or(file.dual_stack),
+            reuse_port: self.reuse_port.or(file.reuse_port),
+            unix_socket: self.unix_socket.or(file.unix_socket),
+            io_uring: self.io_uring.or(file.io_uring),
+            tcp_nagle: self.tcp_nagle.or(file.tcp_nagle),
+            tcp_keepalive_idle_secs: self.tcp_keepalive_idle_secs.or(file.tcp_keepalive_idle_secs),
+            tcp_keepalive_interval_secs: self.tcp_keepalive_interval_secs.or(file.tcp_keepalive_interval_secs),
+            tcp_keepalive_retries: self.tcp_keepalive_retries.or(file.tcp_keepalive_retries),
+            tcp_send_buffer_kb: self.tcp_send_buffer_kb.or(file.tcp_send_buffer_kb),
+            tcp_recv_buffer_kb: self.tcp_recv_buffer_kb.or(file.tcp_recv_buffer_kb),
+            listen_backlog: self.listen_backlog.or(file.listen_backlog),
+            upstreams: if self.upstreams.is_empty() { file.upstreams } else { self.upstreams },
+            markets: if self.markets.is_empty() { file.markets } else { self.markets },
+            crossed_books: self.crossed_books.or(file.crossed_books),
+            audit_interval_secs: self.audit_interval_secs.or(file.audit_interval_secs),
+            audit_heal_threshold: self.audit_heal_threshold.or(file.audit_heal_threshold),
+            market_crossed_books: if self.market_crossed_books.is_empty() {
+                file.market_crossed_books
+            } else {
+                self.market_crossed_books
+            },
+            snapshot_strategy: self.snapshot_strategy.or(file.snapshot_strategy),
+            market_snapshot_strategy: if self.market_snapshot_strategy.is_empty() {
+                file.market_snapshot_strategy
+            } else {
+                self.market_snapshot_strategy
+            },
+            lazy_idle_secs: self.lazy_idle_secs.or(file.lazy_idle_secs),
+            websocket_compression_level: self.websocket_compression_level.or(file.websocket_compression_level),
+            shared_compression: self.shared_compression.or(file.shared_compression),
+            websocket_server_max_window_bits: self
+                .websocket_server_max_window_bits
+                .or(file.websocket_server_max_window_bits),
+            websocket_client_max_window_bits: self
+                .websocket_client_max_window_bits
+                .or(file.websocket_client_max_window_bits),
+            websocket_server_no_context_takeover: self
+                .websocket_server_no_context_takeover
+                .or(file.websocket_server_no_context_takeover),
+            websocket_client_no_context_takeover: self
+                .websocket_client_no_context_takeover
+                .or(file.websocket_client_no_context_takeover),
+            zstd_dictionary: self.zstd_dictionary.or(file.zstd_dictionary),
+            inactivity_exit_secs: self.inactivity_exit_secs.or(file.inactivity_exit_secs),
+            inactivity_deadline_secs: self.inactivity_deadline_secs.or(file.inactivity_deadline_secs),
+            tls_cert: self.tls_cert.or(file.tls_cert),
+            tls_key: self.tls_key.or(file.tls_key),
+            webtransport_port: self.webtransport_port.or(file.webtransport_port),
+            proxy_protocol: self.proxy_protocol.or(file.proxy_protocol),
+            trusted_proxies: if self.trusted_proxies.is_empty() { file.trusted_proxies } else { self.trusted_proxies },
+            cors_origins: if self.cors_origins.is_empty() { file.cors_origins } else { self.cors_origins },
+            cors_headers: if self.cors_headers.is_empty() { file.cors_headers } else { self.cors_headers },
+            cors_max_age_secs: self.cors_max_age_secs.or(file.cors_max_age_secs),
+            api_keys_file: self.api_keys_file.or(file.api_keys_file),
+            jwt_secret_file: self.jwt_secret_file.or(file.jwt_secret_file),
+            max_connections_per_key: self.max_connections_per_key.or(file.max_connections_per_key),
+            coalesce_connections: self.coalesce_connections.or(file.coalesce_connections),
+            metrics_port: self.metrics_port.or(file.metrics_port),
+            health_port: self.health_port.or(file.health_port),
+            grpc_port: self.grpc_port.or(file.grpc_port),
+            drain_timeout_secs: self.drain_timeout_secs.or(file.drain_timeout_secs),
+            backpressure: self.backpressure.or(file.backpressure),
+            send_queue_capacity: self.send_queue_capacity.or(file.send_queue_capacity),
+            message_ttl_ms: if self.message_ttl_ms.is_empty() { file.message_ttl_ms } else { self.message_ttl_ms },
+            shed_load_conflate_percent: self.shed_load_conflate_percent.or(file.shed_load_conflate_percent),
+            shed_load_drop_percent: self.shed_load_drop_percent.or(file.shed_load_drop_percent),
+            watchdog_max_rss_mb: self.watchdog_max_rss_mb.or(file.watchdog_max_rss_mb),
+            watchdog_max_lag_ms: self.watchdog_max_lag_ms.or(file.watchdog_max_lag_ms),
+            watchdog_interval_secs: self.watchdog_interval_secs.or(file.watchdog_interval_secs),
+            watchdog_evict_percent: self.watchdog_evict_percent.or(file.watchdog_evict_percent),
+            broadcast_shards: self.broadcast_shards.or(file.broadcast_shards),
+            client_messages_per_sec: self.client_messages_per_sec.or(file.client_messages_per_sec),
+            outbound_messages_per_sec: self.outbound_messages_per_sec.or(file.outbound_messages_per_sec),
+            outbound_bytes_per_min: self.outbound_bytes_per_min.or(file.outbound_bytes_per_min),
+            bandwidth_policy: self.bandwidth_policy.or(file.bandwidth_policy),
+            connections_per_ip_per_min: self.connections_per_ip_per_min.or(file.connections_per_ip_per_min),
+            ping_interval_secs: self.ping_interval_secs.or(file.ping_interval_secs),
+            max_missed_pongs: self.max_missed_pongs.or(file.max_missed_pongs),
+            heartbeat_interval_secs: self.heartbeat_interval_secs.or(file.heartbeat_interval_secs),
+            time_interval_secs: self.time_interval_secs.or(file.time_interval_secs),
+            journal_dir: self.journal_dir.or(file.journal_dir),
+            journal_max_mb: self.journal_max_mb.or(file.journal_max_mb),
+            resume_window_secs: self.resume_window_secs.or(file.resume_window_secs),
+            session_grace_secs: self.session_grace_secs.or(file.session_grace_secs),
+            candle_intervals: if self.candle_intervals.is_empty() {
+                file.candle_intervals
+            } else {
+                self.candle_intervals
+            },
+            candle_history: self.candle_history.or(file.candle_history),
+            analytics: self.analytics.or(file.analytics),
+            analytics_interval_ms: self.analytics_interval_ms.or(file.analytics_interval_ms),
+            analytics_depth: self.analytics_depth.or(file.analytics_depth),
+            analytics_vwap_window_secs: self.analytics_vwap_window_secs.or(file.analytics_vwap_window_secs),
+            signing_key_file: self.signing_key_file.or(file.signing_key_file),
+            checkpoint_interval_secs: self.checkpoint_interval_secs.or(file.checkpoint_interval_secs),
+            publish_nats: self.publish_nats.or(file.publish_nats),
+            publish_subject_prefix: self.publish_subject_prefix.or(file.publish_subject_prefix),
+            webhook_urls: if self.webhook_urls.is_empty() { file.webhook_urls } else { self.webhook_urls },
+            webhook_secret_file: self.webhook_secret_file.or(file.webhook_secret_file),
+            webhook_spread_bps: self.webhook_spread_bps.or(file.webhook_spread_bps),
+            webhook_retries: self.webhook_retries.or(file.webhook_retries),
+            snapshot_file: self.snapshot_file.or(file.snapshot_file),
+            snapshot_redis: self.snapshot_redis.or(file.snapshot_redis),
+            snapshot_interval_secs: self.snapshot_interval_secs.or(file.snapshot_interval_secs),
+            archive_dir: self.archive_dir.or(file.archive_dir),
+            archive_s3: self.archive_s3.or(file.archive_s3),
+            archive_s3_region: self.archive_s3_region.or(file.archive_s3_region),
+            archive_s3_endpoint: self.archive_s3_endpoint.or(file.archive_s3_endpoint),
+            archive_snapshot_interval_secs: self.archive_snapshot_interval_secs.or(file.archive_snapshot_interval_secs),
+            relay_port: self.relay_port.or(file.relay_port),
+            relay_from: self.relay_from.or(file.relay_from),
+            max_subscriptions: self.max_subscriptions.or(file.max_subscriptions),
+            max_connections: self.max_connections.or(file.max_connections),
+            max_connections_per_ip: self.max_connections_per_ip.or(file.max_connections_per_ip),
+            max_message_bytes: self.max_message_bytes.or(file.max_message_bytes),
+            include_latency_metadata: self.include_latency_metadata.or(file.include_latency_metadata),
+            log_level: self.log_level.or(file.log_level),
+            log_format: self.log_format.or(file.log_format),
+            otlp_endpoint: self.otlp_endpoint.or(file.otlp_endpoint),
+            otlp_sample_ratio: self.otlp_sample_ratio.or(file.otlp_sample_ratio),
+            admin_port: self.admin_port.or(file.admin_port),
+            admin_keys_file: self.admin_keys_file.or(file.admin_keys_file),
+            preferences_file: self.preferences_file.or(file.preferences_file),
+            standby: self.standby.or(file.standby),
+            election_etcd: self.election_etcd.or(file.election_etcd),
+            election_key: self.election_key.or(file.election_key),
+            election_name: self.election_name.or(file.election_name),
+            election_ttl_secs: self.election_ttl_secs.or(file.election_ttl_secs),
+            // only in the file
+            tenants: file.tenants,
+        }
+    }
+}
+
+fn otlp_config(args: &Args) -> Result<Option<OtlpConfig>> {
+    let Some(endpoint) = args.otlp_endpoint.clone() else {
+        return Ok(None);
+    };
+    let mut config = OtlpConfig::new(endpoint);
+    if let Some(ratio) = args.otlp_sample_ratio {
+        if !(0.0..=1.0).contains(&ratio) {
+            return Err("--otlp-sample-ratio must be between 0 and 1".into());
+        }
+        config.sample_ratio = ratio;
+    }
+    Ok(Some(config))
+}
+
+// clients have to authenticate once there are keys, tenants or a JWT secret
+fn auth_config(args: &Args) -> Result<Option<AuthConfig>> {
+    let mut validators: Vec<Arc<dyn Validator>> = Vec::new();
+    if let Some(path) = &args.api_keys_file {
+        validators.push(Arc::new(StaticKeys::from_file(path)?));
+    }
+    let mut tenants = Vec::new();
+    for tenant in &args.tenants {
+        validators.push(Arc::new(StaticKeys::from_file(&tenant.api_keys_file)?.for_tenant(&tenant.name)));
+        tenants.push(Tenant {
+            name: tenant.name.clone(),
+            markets: tenant.markets.clone(),
+            max_connections: tenant.max_connections,
+            rate_limits: tenant.rate_limits,
+        });
+    }
+    if let Some(path) = &args.jwt_secret_file {
+        validators.push(Arc::new(JwtValidator::from_file(path)?));
+    }
+    if validators.is_empty() {
+        return Ok(None);
+    }
+    let mut auth = AuthConfig::new(validators);
+    auth.max_connections_per_key = args.max_connections_per_key;
+    auth.tenants = tenants;
+    Ok(Some(auth))
+}
+
+const fn deflate_config(args: &Args) -> DeflateConfig {
+    DeflateConfig {
+        server_max_window_bits: args.websocket_server_max_window_bits,
+        client_max_window_bits: args.websocket_client_max_window_bits,
+        server_no_context_takeover: matches!(args.websocket_server_no_context_takeover, Some(true)),
+        client_no_context_takeover: matches!(args.websocket_client_no_context_takeover, Some(true)),
+    }
+}
+
+fn candle_config(args: &Args) -> CandleConfig {
+    let mut candles = CandleConfig::default();
+    if !args.candle_intervals.is_empty() {
+        candles.intervals.clone_from(&args.candle_intervals);
+    }
+    if let Some(history) = args.candle_history {
+        candles.history = history;
+    }
+    candles
+}
+
+fn analytics_config(args: &Args) -> Option<AnalyticsConfig> {
+    if !args.analytics.unwrap_or_default() {
+        return None;
+    }
+    let mut analytics = AnalyticsConfig::default();
+    if let Some(interval_ms) = args.analytics_interval_ms {
+        analytics.interval = Duration::from_millis(interval_ms);
+    }
+    if let Some(depth) = args.analytics_depth {
+        analytics.depth = depth;
+    }
+    if let Some(window_secs) = args.analytics_vwap_window_secs {
+        analytics.vwap_window = Duration::from_secs(window_secs);
+    }
+    Some(analytics)
+}
+
+fn signing_config(args: &Args) -> Option<SigningConfig> {
+    let mut signing = SigningConfig::new(args.signing_key_file.clone()?);
+    if let Some(interval_secs) = args.checkpoint_interval_secs {
+        signing.checkpoint_interval = Duration::from_secs(interval_secs);
+    }
+    Some(signing)
+}
+
+fn archive_config(args: &Args) -> Result<Option<ArchiveConfig>> {
+    let Some(dir) = args.archive_dir.clone() else {
+        if args.archive_s3.is_some() {
+            return Err("--archive-s3 needs an --archive-dir to write the files to first".into());
+        }
+        return Ok(None);
+    };
+    let mut archive = ArchiveConfig::new(dir);
+    if let Some(url) = &args.archive_s3 {
+        let region = args.archive_s3_region.clone().unwrap_or_else(|| "us-east-1".to_string());
+        archive.store = Some(Arc::new(S3ArchiveStore::from_env(url, region, args.archive_s3_endpoint.clone())?));
+    }
+    if let Some(secs) = args.archive_snapshot_interval_secs {
+        archive.snapshot_interval = Duration::from_secs(secs);
+    }
+    Ok(Some(archive))
+}
+
+fn webhook_config(args: &Args) -> Result<Option<WebhookConfig>> {
+    if args.webhook_urls.is_empty() {
+        return Ok(None);
+    }
+    let mut webhooks = WebhookConfig::new(args.webhook_urls.clone());
+    if let Some(path) = &args.webhook_secret_file {
+        let secret = fs::read_to_string(path)
+            .map_err(|err| format!("Unable to read webhook secret {}: {err}", path.display()))?;
+        webhooks.secret = Some(secret.trim().as_bytes().to_vec());
+    }
+    webhooks.spread_bps = args.webhook_spread_bps;
+    webhooks.retries = args.webhook_retries.unwrap_or(webhooks.retries);
+    Ok(Some(webhooks))
+}
+
+// on when either threshold is set
+fn load_shedding(args: &Args) -> Option<LoadShedding> {
+    if args.shed_load_conflate_percent.is_none() && args.shed_load_drop_percent.is_none() {
+        return None;
+    }
+    let default = LoadShedding::default();
+    Some(LoadShedding {
+        conflate_percent: args.shed_load_conflate_percent.unwrap_or(default.conflate_percent),
+        drop_percent: args.shed_load_drop_percent.unwrap_or(default.drop_percent),
+    })
+}
+
+// on when either threshold is set
+fn watchdog_config(args: &Args) -> Option<WatchdogConfig> {
+    if args.watchdog_max_rss_mb.is_none() && args.watchdog_max_lag_ms.is_none() {
+        return None;
+    }
+    Some(WatchdogConfig {
+        max_rss_bytes: args.watchdog_max_rss_mb.map(|mb| mb.saturating_mul(1 << 20)),
+        max_lag: args.watchdog_max_lag_ms.map(Duration::from_millis),
+        interval: Duration::from_secs(args.watchdog_interval_secs.unwrap_or(5)),
+        evict_percent: args.watchdog_evict_percent.unwrap_or(10),
+    })
+}
+
+fn socket_options(args: &Args) -> SocketOptions {
+    SocketOptions {
+        nodelay: !args.tcp_nagle.unwrap_or_default(),
+        keepalive: args.tcp_keepalive_idle_secs.map(|idle| TcpKeepalive {
+            idle: Duration::from_secs(idle),
+            interval: Duration::from_secs(args.tcp_keepalive_interval_secs.unwrap_or(10)),
+            retries: args.tcp_keepalive_retries.unwrap_or(3),
+        }),
+        send_buffer: args.tcp_send_buffer_kb.map(|kb| kb.saturating_mul(1 << 10)),
+        recv_buffer: args.tcp_recv_buffer_kb.map(|kb| kb.saturating_mul(1 << 10)),
+        backlog: args.listen_backlog.unwrap_or(SocketOptions::new().backlog),
+    }
+}
+
+// mostly a line per option
+#[allow(clippy::too_many_lines)]
+fn server_config(args: Args) -> Result<ServerConfig> {
+    let address = args.address.ok_or("--address is required")?;
+    let port = args.port.ok_or("--port is required")?;
+    let mut config = ServerConfig::new(SocketAddr::new(address, port));
+    config.auth = auth_config(&args)?;
+    config.deflate = deflate_config(&args);
+    config.candles = Some(candle_config(&args));
+    config.analytics = analytics_config(&args);
+    config.signing = signing_config(&args);
+    config.archive = archive_config(&args)?;
+    config.load_shedding = load_shedding(&args);
+    config.watchdog = watchdog_config(&args);
+    config.socket = socket_options(&args);
+    config.webhooks = webhook_config(&args)?;
+    config.dual_stack = args.dual_stack.unwrap_or_default();
+    config.reuse_port = args.reuse_port.unwrap_or_default();
+    config.unix_socket = args.unix_socket;
+    config.io_uring = args.io_uring.unwrap_or_default();
+    config.upstreams = args.upstreams;
+    config.markets = group_markets(args.markets, &args.market_crossed_books, &args.market_snapshot_strategy)?;
+    config.crossed_books = args.crossed_books.unwrap_or_default();
+    config.snapshot_strategy = args.snapshot_strategy.unwrap_or_default();
+    config.lazy_idle_timeout = args.lazy_idle_secs.map_or(config.lazy_idle_timeout, Duration::from_secs);
+    config.audit.interval = args.audit_interval_secs.map_or(config.audit.interval, Duration::from_secs);
+    config.audit.heal_threshold = args.audit_heal_threshold.unwrap_or(config.audit.heal_threshold);
+    config.compression_level = args.websocket_compression_level.unwrap_or(config.compression_level);
+    config.shared_compression = args.shared_compression.unwrap_or_default();
+    config.coalesce_connections = args.coalesce_connections.unwrap_or_default();
+    config.zstd_dictionary = args.zstd_dictionary;
+    config.inactivity_exit_secs = args.inactivity_exit_secs.unwrap_or(5).max(5);
+    if let Some(secs) = args.inactivity_deadline_secs {
+        config.inactivity_policy = InactivityPolicy::Reconnect { deadline: Duration::from_secs(secs) };
+    }
+    config.tls = match (args.tls_cert, args.tls_key) {
+        (Some(cert_path), Some(key_path)) => Some(TlsConfig::new(cert_path, key_path)),
+        (None, None) => None,
+        _ => return Err("--tls-cert and --tls-key have to be set together".into()),
+    };
+    config.webtransport_port = args.webtransport_port;
+    config.proxy = ProxyConfig { protocol: args.proxy_protocol.unwrap_or_default(), trusted: args.trusted_proxies };
+    if !args.cors_origins.is_empty() {
+        let mut cors = CorsConfig::new(args.cors_origins);
+        if !args.cors_headers.is_empty() {
+            cors.allowed_headers = args.cors_headers;
+        }
+        if let Some(secs) = args.cors_max_age_secs {
+            cors.max_age = Some(Duration::from_secs(secs));
+        }
+        config.cors = Some(cors);
+    }
+    config.metrics_port = args.metrics_port;
+    config.health_port = args.health_port;
+    config.grpc_port = args.grpc_port;
+    if let Some(drain_timeout_secs) = args.drain_timeout_secs {
+        config.drain_timeout = Duration::from_secs(drain_timeout_secs);
+    }
+    if let Some(backpressure) = args.backpressure {
+        config.backpressure = backpressure;
+    }
+    config.send_queue_capacity = args.send_queue_capacity.unwrap_or(config.send_queue_capacity);
+    config.message_ttls = message_ttls(&args.message_ttl_ms)?;
+    if let Some(broadcast_shards) = args.broadcast_shards {
+        config.broadcast_shards = broadcast_shards;
+    }
+    config.rate_limits = RateLimits {
+        client_messages_per_sec: args.client_messages_per_sec,
+        outbound_messages_per_sec: args.outbound_messages_per_sec,
+        connections_per_ip_per_min: args.connections_per_ip_per_min,
+        outbound_bytes_per_min: args.outbound_bytes_per_min,
+        bandwidth_policy: args.bandwidth_policy.unwrap_or_default(),
+    };
+    config.keepalive = KeepaliveConfig {
+        ping_interval: args.ping_interval_secs.map(Duration::from_secs),
+        max_missed_pongs: args.max_missed_pongs.unwrap_or(config.keepalive.max_missed_pongs),
+        heartbeat_interval: args.heartbeat_interval_secs.map(Duration::from_secs),
+        time_interval: args.time_interval_secs.map(Duration::from_secs),
+    };
+    if let Some(dir) = args.journal_dir {
+        let mut journal = JournalConfig::new(dir);
+        if let Some(max_mb) = args.journal_max_mb {
+            journal.max_bytes = max_mb << 20;
+        }
+        config.journal = Some(journal);
+    }
+    config.resume_window = args.resume_window_secs.map(Duration::from_secs);
+    config.session_grace = args.session_grace_secs.map(Duration::from_secs);
+    if let Some(address) = args.publish_nats {
+        let mut publisher = PublisherConfig::new(Arc::new(NatsSink::new(address)));
+        if let Some(prefix) = args.publish_subject_prefix {
+            publisher.subject_prefix = prefix;
+        }
+        config.publisher = Some(publisher);
+    }
+    let snapshot_store: Option<Arc<dyn SnapshotStore>> = match (args.snapshot_file, args.snapshot_redis) {
+        (Some(path), None) => Some(Arc::new(FileSnapshotStore { path })),
+        (None, Some(address)) => Some(Arc::new(RedisSnapshotStore::new(address))),
+        (None, None) => None,
+        (Some(_), Some(_)) => return Err("--snapshot-file and --snapshot-redis can't be used together".into()),
+    };
+    config.snapshot_store = snapshot_store.map(|store| {
+        let mut snapshot_store = SnapshotStoreConfig::new(store);
+        if let Some(secs) = args.snapshot_interval_secs {
+            snapshot_store.interval = Duration::from_secs(secs);
+        }
+        snapshot_store
+    });
+    config.max_subscriptions = args.max_subscriptions;
+    config.connection_limits = ConnectionLimits {
+        max_connections: args.max_connections,
+        max_connections_per_ip: args.max_connections_per_ip,
+        max_message_bytes: args.max_message_bytes,
+    };
+    config.include_latency_metadata = args.include_latency_metadata.unwrap_or_default();
+    // errors only without `RUST_LOG`
+    config.log_level = args.log_level.or_else(|| env::var_os("RUST_LOG").is_none().then_some(LevelFilter::ERROR));
+    config.relay_port = args.relay_port;
+    config.relay_upstream = args.relay_from;
+    config.admin_port = args.admin_port;
+    if let Some(path) = &args.admin_keys_file {
+        config.admin_auth = Some(AuthConfig::new(vec![Arc::new(StaticKeys::from_file(path)?)]));
+    }
+    config.preferences = args.preferences_file;
+    config.standby = args.standby.unwrap_or_default();
+    if let Some(endpoint) = args.election_etcd {
+        let name = args.election_name.or_else(|| env::var("HOSTNAME").ok());
+        let name = name.unwrap_or_else(|| format!("orderbook-{}", std::process::id()));
+        let mut election = ElectionConfig::new(endpoint, name);
+        election.key = args.election_key.unwrap_or(election.key);
+        election.ttl = args.election_ttl_secs.map_or(election.ttl, Duration::from_secs);
+        config.election = Some(election);
+    }
+    Ok(config)
+}
+
+// each `--market` is a single node, merged into one market per name
+fn group_markets(
+    markets: Vec<MarketConfig>,
+    crossed_books: &[String],
+    snapshot_strategies: &[String],
+) -> Result<Vec<MarketConfig>> {
+    let mut grouped = Vec::<MarketConfig>::new();
+    for market in markets {
+        match grouped.iter_mut().find(|other| other.name == market.name) {
+            Some(other) => other.upstreams.extend(market.upstreams),
+            None => grouped.push(market),
+        }
+    }
+    for entry in crossed_books {
+        let (name, policy) = entry
+            .split_once('=')
+            .ok_or_else(|| format!("invalid --market-crossed-books {entry} (expected <name>=<flag|withhold>)"))?;
+        let market =
+            grouped.iter_mut().find(|market| market.name == name).ok_or_else(|| format!("unknown market {name}"))?;
+        market.crossed_books = Some(policy.parse()?);
+    }
+    for entry in snapshot_strategies {
+        let (name, strategy) = entry
+            .split_once('=')
+            .ok_or_else(|| format!("invalid --market-snapshot-strategy {entry} (expected <name>=<eager|lazy>)"))?;
+        let market =
+            grouped.iter_mut().find(|market| market.name == name).ok_or_else(|| format!("unknown market {name}"))?;
+        market.snapshot_strategy = Some(strategy.parse()?);
+    }
+    Ok(grouped)
+}
+
+fn message_ttls(entries: &[String]) -> Result<BTreeMap<String, Duration>> {
+    entries
+        .iter()
+        .map(|entry| {
+            let invalid = || format!("invalid --message-ttl-ms {entry} (expected <channel>=<ms>)");
+            let (channel, ms) = entry.split_once('=').ok_or_else(invalid)?;
+            Ok((channel.to_string(), Duration::from_millis(ms.parse().map_err(|_| invalid())?)))
+        })
+        .collect()
+}
+
+// options given as strings in the config file, parsed like their flags
+fn parse<'de, D: Deserializer<'de>, T: FromStr<Err: fmt::Display>>(
+    deserializer: D,
+) -> std::result::Result<Option<T>, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    s.parse().map(Some).map_err(de::Error::custom)
+}
+
+fn parse_all<'de, D: Deserializer<'de>, T: FromStr<Err: fmt::Display>>(
+    deserializer: D,
+) -> std::result::Result<Vec<T>, D::Error> {
+    let strings = Vec::<String>::deserialize(deserializer)?;
+    strings.iter().map(|s| s.parse().map_err(de::Error::custom)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_line_over_file() -> Result<()> {
+        let file: Args =
+            toml::from_str("address = \"::\"\ndual_stack = true\nshared_compression = true\nport = 8000\n")?;
+        let args = Args::try_parse_from(["websocket_server", "--dual-stack=false", "--analytics", "--port", "9000"])?;
+        let args = args.or(file);
+        assert_eq!(args.dual_stack, Some(false));
+        assert_eq!(args.shared_compression, Some(true));
+        assert_eq!(args.analytics, Some(true));
+        assert_eq!(args.standby, None);
+        assert_eq!(args.port, Some(9000));
+
+        let config = server_config(args)?;
+        assert!(!config.dual_stack && config.shared_compression);
+        Ok(())
+    }
+}
@@ -0,0 +1,89 @@
+#![allow(unused_crate_dependencies)]
+use std::{path::PathBuf, time::Duration};
+
+use clap::Parser;
+use futures_util::{SinkExt, StreamExt};
+use serde_json::json;
+use server::{Result, train_zstd_dictionary};
+use tokio::time::{Instant, timeout_at};
+use yawc::{FrameView, HttpRequestBuilder, OpCode, Options, WebSocket};
+
+// snapshots of big books are larger than the default limit of 1 MiB
+const MAX_PAYLOAD: usize = 64 << 20;
+
+// Trains a zstd dictionary for the server's `--zstd-dictionary` on the MessagePack frames a running server sends,
+// so that it fits the markets and subscriptions of the clients it serves.
+#[derive(Debug, Parser)]
+#[command(author, version, about)]
+struct Args {
+    /// Websocket endpoint of the server to sample.
+    #[arg(long, default_value = "ws://127.0.0.1:8000/ws")]
+    url: String,
+
+    /// Channels to subscribe to for every coin, e.g. `l2Book,l4Book,trades`.
+    #[arg(long, default_value = "l2Book,l4Book,trades,bbo", value_delimiter = ',')]
+    channels: Vec<String>,
+
+    #[arg(long, default_value = "BTC,ETH", value_delimiter = ',')]
+    coins: Vec<String>,
+
+    /// Frames to train on.
+    #[arg(long, default_value_t = 10_000)]
+    samples: usize,
+
+    /// Stop sampling after this many seconds, even if fewer frames arrived.
+    #[arg(long, default_value_t = 300)]
+    duration_secs: u64,
+
+    /// Largest size of the dictionary in KB. A larger dictionary helps small frames, and every connection that
+    /// negotiates zstd shares it.
+    #[arg(long, default_value_t = 16)]
+    max_size_kb: usize,
+
+    /// Sent as `Authorization: Bearer <token>`.
+    #[arg(long)]
+    token: Option<String>,
+
+    /// File to write the dictionary to.
+    #[arg(long)]
+    output: PathBuf,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let options = Options::default().with_max_payload_read(MAX_PAYLOAD).with_max_read_buffer(2 * MAX_PAYLOAD);
+    let mut request = HttpRequestBuilder::new().header("Sec-WebSocket-Protocol", "orderbook.msgpack");
+    if let Some(token) = &args.token {
+        request = request.header("Authorization", format!("Bearer {token}"));
+    }
+    let mut ws = WebSocket::connect(args.url.parse()?).with_options(options).with_request(request).await?;
+    for coin in &args.coins {
+        for channel in &args.channels {
+            let subscription = json!({"method": "subscribe", "subscription": {"type": channel, "coin": coin}});
+            ws.send(FrameView::text(subscription.to_string())).await?;
+        }
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(args.duration_secs);
+    let mut samples = Vec::with_capacity(args.samples);
+    while samples.len() < args.samples {
+        let Ok(frame) = timeout_at(deadline, ws.next()).await else {
+            break;
+        };
+        let Some(frame) = frame else {
+            return Err("the server closed the connection".into());
+        };
+        match frame.opcode {
+            OpCode::Binary => samples.push(frame.payload.to_vec()),
+            OpCode::Close => return Err("the server closed the connection".into()),
+            _ => {}
+        }
+    }
+    let bytes = samples.iter().map(Vec::len).sum::<usize>();
+    println!("Training on {} frames of {bytes} bytes", samples.len());
+    let dictionary = train_zstd_dictionary(&samples, args.max_size_kb << 10)?;
+    std::fs::write(&args.output, &dictionary)?;
+    println!("Wrote a dictionary of {} bytes to {}", dictionary.len(), args.output.display());
+    Ok(())
+}
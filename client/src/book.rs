@@ -0,0 +1,543 @@
+//! A local copy of one coin's book, kept from the messages of an `l2Book` or `l4Book` subscription.
+
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
+    fmt,
+};
+
+use crate::messages::{L2Book, L2Delta, L4Book, L4BookUpdates, L4Order, Level, LevelChange, Message, OrderDiff, Side};
+
+/// Levels per side covered by a checksum.
+pub const CHECKSUM_LEVELS: usize = 25;
+
+// prices and sizes are fixed point numbers with this many decimals on the server
+const DECIMALS: usize = 8;
+const SCALE: u64 = 100_000_000;
+
+/// CRC32 of the top levels of a book, as sent by the server: of `bid px:bid sz:ask px:ask sz:...` over the top
+/// [`CHECKSUM_LEVELS`] levels, leaving out a side once it runs out.
+#[must_use]
+pub fn checksum(levels: &[Vec<Level>; 2]) -> u32 {
+    let [bids, asks] = levels;
+    let mut fields = Vec::with_capacity(4 * CHECKSUM_LEVELS);
+    for i in 0..CHECKSUM_LEVELS {
+        for level in [bids.get(i), asks.get(i)].into_iter().flatten() {
+            fields.push(level.px.as_str());
+            fields.push(level.sz.as_str());
+        }
+    }
+    crc32fast::hash(fields.join(":").as_bytes())
+}
+
+/// Why a message couldn't be applied. The book is out of sync afterwards: it takes a new snapshot, e.g. through
+/// a [`Request::Snapshot`](crate::Request::Snapshot), to apply further updates.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BookError {
+    /// An update was missed.
+    Gap {
+        expected: u64,
+        got: u64,
+    },
+    /// The book differs from the server's.
+    ChecksumMismatch {
+        expected: u32,
+        actual: u32,
+    },
+    /// An update changes an order that isn't on the book.
+    UnknownOrder(u64),
+    /// An l2 delta references a level by an index its epoch doesn't have.
+    UnknownLevel(usize),
+    InvalidNumber(String),
+}
+
+impl fmt::Display for BookError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Gap { expected, got } => write!(f, "expected update {expected}, got {got}"),
+            Self::ChecksumMismatch { expected, actual } => {
+                write!(f, "checksum mismatch: expected {expected}, book has {actual}")
+            }
+            Self::UnknownOrder(oid) => write!(f, "order {oid} is not on the book"),
+            Self::UnknownLevel(i) => write!(f, "level {i} is not in the epoch"),
+            Self::InvalidNumber(value) => write!(f, "invalid number {value:?}"),
+        }
+    }
+}
+
+impl std::error::Error for BookError {}
+
+// a price or size, in units of 10^-8
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+struct Decimal(u64);
+
+impl Decimal {
+    fn parse(value: &str) -> Result<Self, BookError> {
+        let invalid = || BookError::InvalidNumber(value.to_string());
+        let (int, frac) = value.split_once('.').unwrap_or((value, ""));
+        let digits = |digits: &str| digits.bytes().all(|b| b.is_ascii_digit());
+        if int.is_empty() || !digits(int) || !digits(frac) {
+            return Err(invalid());
+        }
+        let frac = format!("{:0<DECIMALS$}", &frac[..frac.len().min(DECIMALS)]);
+        let int = int.parse::<u64>().map_err(|_| invalid())?;
+        let frac = frac.parse::<u64>().map_err(|_| invalid())?;
+        int.checked_mul(SCALE).and_then(|int| int.checked_add(frac)).map(Self).ok_or_else(invalid)
+    }
+}
+
+// without trailing zeros, and without the decimal point for whole numbers, like the server writes them
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (int, frac) = (self.0 / SCALE, self.0 % SCALE);
+        if frac == 0 {
+            write!(f, "{int}")
+        } else {
+            let frac = format!("{frac:0DECIMALS$}");
+            write!(f, "{int}.{}", frac.trim_end_matches('0'))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Order {
+    side: Side,
+    px: Decimal,
+    sz: Decimal,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Aggregate {
+    sz: Decimal,
+    n: usize,
+}
+
+/// One coin's book, built from a snapshot and kept up to date by the messages that follow it. Checked against
+/// the checksum of every message, and against gaps in the `seq` of l4 updates and l2 deltas.
+///
+/// Messages for other coins and updates that arrive before the first snapshot are ignored.
+#[derive(Debug, Clone)]
+pub struct OrderBook {
+    coin: String,
+    // set once the book has a snapshot
+    seq: Option<u64>,
+    time: u64,
+    bids: BTreeMap<Reverse<Decimal>, Aggregate>,
+    asks: BTreeMap<Decimal, Aggregate>,
+    // of l4 books only
+    orders: HashMap<u64, Order>,
+    // of l2 books only: the prices of each side by their index in the epoch l2 deltas reference, which started with
+    // the l2 book with the seq `epoch`
+    epoch: u64,
+    prices: [Vec<Decimal>; 2],
+    // the updates of conflated subscriptions skip the seqs of the updates merged into them
+    conflated: bool,
+}
+
+impl OrderBook {
+    #[must_use]
+    pub fn new(coin: impl Into<String>) -> Self {
+        Self {
+            coin: coin.into(),
+            seq: None,
+            time: 0,
+            bids: BTreeMap::new(),
+            asks: BTreeMap::new(),
+            orders: HashMap::new(),
+            epoch: 0,
+            prices: [Vec::new(), Vec::new()],
+            conflated: false,
+        }
+    }
+
+    /// A book of a conflated `l4Book` subscription. Its updates carry the `seq` of the last update they merge,
+    /// so only their checksums show a gap.
+    #[must_use]
+    pub fn conflated(coin: impl Into<String>) -> Self {
+        Self { conflated: true, ..Self::new(coin) }
+    }
+
+    #[must_use]
+    pub fn coin(&self) -> &str {
+        &self.coin
+    }
+
+    /// `seq` of the latest message applied, or None until the book has a snapshot.
+    #[must_use]
+    pub const fn seq(&self) -> Option<u64> {
+        self.seq
+    }
+
+    /// Time of the latest message applied, in ms.
+    #[must_use]
+    pub const fn time(&self) -> u64 {
+        self.time
+    }
+
+    /// Applies a message, returning whether it changed the book. On an error, the book waits for a new snapshot.
+    pub fn apply(&mut self, msg: &Message) -> Result<bool, BookError> {
+        let res = match msg {
+            Message::L2Book(book) if book.coin == self.coin => self.apply_l2_book(book).map(|()| true),
+            Message::L2Delta(delta) if delta.coin == self.coin => self.apply_l2_delta(delta),
+            Message::L4Book(L4Book::Snapshot { coin, time, seq, levels, checksum, .. }) if *coin == self.coin => {
+                self.apply_l4_snapshot(*time, *seq, levels, *checksum).map(|()| true)
+            }
+            Message::L4Book(L4Book::Updates(updates)) if updates.coin() == Some(self.coin.as_str()) => {
+                self.apply_l4_updates(updates)
+            }
+            _ => return Ok(false),
+        };
+        if res.is_err() {
+            self.seq = None;
+        }
+        res
+    }
+
+    /// Up to `depth` levels per side, bids then asks, best first.
+    #[must_use]
+    pub fn levels(&self, depth: usize) -> [Vec<Level>; 2] {
+        let level = |px: Decimal, aggregate: &Aggregate| Level {
+            px: px.to_string(),
+            sz: aggregate.sz.to_string(),
+            n: aggregate.n,
+        };
+        [
+            self.bids.iter().take(depth).map(|(Reverse(px), aggregate)| level(*px, aggregate)).collect(),
+            self.asks.iter().take(depth).map(|(px, aggregate)| level(*px, aggregate)).collect(),
+        ]
+    }
+
+    #[must_use]
+    pub fn best_bid(&self) -> Option<Level> {
+        self.levels(1)[0].pop()
+    }
+
+    #[must_use]
+    pub fn best_ask(&self) -> Option<Level> {
+        self.levels(1)[1].pop()
+    }
+
+    /// Of the current book, to compare with the checksum of the server's messages.
+    #[must_use]
+    pub fn checksum(&self) -> u32 {
+        checksum(&self.levels(CHECKSUM_LEVELS))
+    }
+
+    fn apply_l2_book(&mut self, book: &L2Book) -> Result<(), BookError> {
+        self.clear();
+        let [bids, asks] = &book.levels;
+        for level in bids {
+            let (px, aggregate) =
+                (Decimal::parse(&level.px)?, Aggregate { sz: Decimal::parse(&level.sz)?, n: level.n });
+            self.bids.insert(Reverse(px), aggregate);
+            self.prices[0].push(px);
+        }
+        for level in asks {
+            let (px, aggregate) =
+                (Decimal::parse(&level.px)?, Aggregate { sz: Decimal::parse(&level.sz)?, n: level.n });
+            self.asks.insert(px, aggregate);
+            self.prices[1].push(px);
+        }
+        self.verify(book.checksum)?;
+        self.seq = Some(book.seq);
+        self.time = book.time;
+        self.epoch = book.seq;
+        Ok(())
+    }
+
+    fn apply_l2_delta(&mut self, delta: &L2Delta) -> Result<bool, BookError> {
+        let Some(seq) = self.seq else {
+            return Ok(false);
+        };
+        if delta.prev_seq != seq || delta.epoch != self.epoch {
+            return Err(BookError::Gap { expected: seq, got: delta.prev_seq });
+        }
+        let [bids, asks] = &delta.changes;
+        for change in bids {
+            match self.change(0, change)? {
+                (px, Some(aggregate)) => self.bids.insert(Reverse(px), aggregate),
+                (px, None) => self.bids.remove(&Reverse(px)),
+            };
+        }
+        for change in asks {
+            match self.change(1, change)? {
+                (px, Some(aggregate)) => self.asks.insert(px, aggregate),
+                (px, None) => self.asks.remove(&px),
+            };
+        }
+        self.verify(delta.checksum)?;
+        self.seq = Some(delta.seq);
+        self.time = delta.time;
+        Ok(true)
+    }
+
+    // the price of the level a change references, taking the next index of the side for a new one, and the level it
+    // leaves there, None if it removes it
+    fn change(&mut self, side: usize, change: &LevelChange) -> Result<(Decimal, Option<Aggregate>), BookError> {
+        let prices = &mut self.prices[side];
+        let px = match &change.px {
+            Some(px) if change.i == prices.len() => {
+                let px = Decimal::parse(px)?;
+                prices.push(px);
+                px
+            }
+            Some(_) => return Err(BookError::UnknownLevel(change.i)),
+            None => *prices.get(change.i).ok_or(BookError::UnknownLevel(change.i))?,
+        };
+        let sz = Decimal::parse(&change.sz)?;
+        Ok((px, (sz.0 > 0).then_some(Aggregate { sz, n: change.n })))
+    }
+
+    fn apply_l4_snapshot(
+        &mut self,
+        time: u64,
+        seq: u64,
+        levels: &[Vec<L4Order>; 2],
+        checksum: u32,
+    ) -> Result<(), BookError> {
+        self.clear();
+        for order in levels.iter().flatten() {
+            let (px, sz) = (Decimal::parse(&order.limit_px)?, Decimal::parse(&order.sz)?);
+            self.add(order.oid, Order { side: order.side, px, sz });
+        }
+        self.verify(checksum)?;
+        self.seq = Some(seq);
+        self.time = time;
+        Ok(())
+    }
+
+    fn apply_l4_updates(&mut self, updates: &L4BookUpdates) -> Result<bool, BookError> {
+        let Some(seq) = self.seq else {
+            return Ok(false);
+        };
+        // already part of the snapshot
+        if updates.seq <= seq {
+            return Ok(false);
+        }
+        if updates.seq != seq + 1 && !self.conflated {
+            return Err(BookError::Gap { expected: seq + 1, got: updates.seq });
+        }
+        for diff in &updates.book_diffs {
+            match &diff.raw_book_diff {
+                OrderDiff::New { sz } => {
+                    let status = updates.order_statuses.iter().rev().find(|status| status.order.oid == diff.oid);
+                    let side = status.ok_or(BookError::UnknownOrder(diff.oid))?.order.side;
+                    self.add(diff.oid, Order { side, px: Decimal::parse(&diff.px)?, sz: Decimal::parse(sz)? });
+                }
+                OrderDiff::Update { new_sz, .. } => {
+                    let order = self.remove(diff.oid)?;
+                    self.add(diff.oid, Order { sz: Decimal::parse(new_sz)?, ..order });
+                }
+                OrderDiff::Remove => {
+                    self.remove(diff.oid)?;
+                }
+            }
+        }
+        self.verify(updates.checksum)?;
+        self.seq = Some(updates.seq);
+        self.time = updates.time;
+        Ok(true)
+    }
+
+    // a checksum of 0 is left unchecked, older servers don't send one
+    fn verify(&self, expected: u32) -> Result<(), BookError> {
+        let actual = self.checksum();
+        if expected != 0 && expected != actual {
+            return Err(BookError::ChecksumMismatch { expected, actual });
+        }
+        Ok(())
+    }
+
+    fn clear(&mut self) {
+        self.bids.clear();
+        self.asks.clear();
+        self.orders.clear();
+        self.prices.iter_mut().for_each(Vec::clear);
+    }
+
+    fn add(&mut self, oid: u64, order: Order) {
+        let aggregate = match order.side {
+            Side::Bid => self.bids.entry(Reverse(order.px)).or_default(),
+            Side::Ask => self.asks.entry(order.px).or_default(),
+        };
+        aggregate.sz.0 += order.sz.0;
+        aggregate.n += 1;
+        self.orders.insert(oid, order);
+    }
+
+    fn remove(&mut self, oid: u64) -> Result<Order, BookError> {
+        let order = self.orders.remove(&oid).ok_or(BookError::UnknownOrder(oid))?;
+        let aggregate = match order.side {
+            Side::Bid => self.bids.get_mut(&Reverse(order.px)),
+            Side::Ask => self.asks.get_mut(&order.px),
+        };
+        if let Some(aggregate) = aggregate {
+            aggregate.sz.0 = aggregate.sz.0.saturating_sub(order.sz.0);
+            aggregate.n = aggregate.n.saturating_sub(1);
+            if aggregate.n == 0 {
+                match order.side {
+                    Side::Bid => self.bids.remove(&Reverse(order.px)),
+                    Side::Ask => self.asks.remove(&order.px),
+                };
+            }
+        }
+        Ok(order)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::{BookDiff, OrderStatus};
+
+    fn order(oid: u64, side: Side, px: &str, sz: &str) -> L4Order {
+        L4Order {
+            user: None,
+            coin: "BTC".to_string(),
+            side,
+            limit_px: px.to_string(),
+            sz: sz.to_string(),
+            oid,
+            timestamp: 0,
+            trigger_condition: "N/A".to_string(),
+            is_trigger: false,
+            trigger_px: "0.0".to_string(),
+            is_position_tpsl: false,
+            reduce_only: false,
+            order_type: "Limit".to_string(),
+            tif: Some("Gtc".to_string()),
+            cloid: None,
+        }
+    }
+
+    fn diff(oid: u64, px: &str, raw_book_diff: OrderDiff) -> BookDiff {
+        BookDiff { user: String::new(), oid, px: px.to_string(), coin: "BTC".to_string(), raw_book_diff }
+    }
+
+    fn level(px: &str, sz: &str, n: usize) -> Level {
+        Level { px: px.to_string(), sz: sz.to_string(), n }
+    }
+
+    #[test]
+    fn test_decimal() -> Result<(), BookError> {
+        assert_eq!(Decimal::parse("106296.0")?.to_string(), "106296");
+        assert_eq!(Decimal::parse("0.00017")?.to_string(), "0.00017");
+        assert_eq!(Decimal::parse("3")?.0, 300_000_000);
+        assert!(Decimal::parse("-1").is_err());
+        assert!(Decimal::parse("1e5").is_err());
+        assert!(Decimal::parse(".5").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_l4_book() {
+        let mut book = OrderBook::new("BTC");
+        let orders = [
+            vec![
+                order(1, Side::Bid, "100.0", "1.5"),
+                order(2, Side::Bid, "100.0", "0.5"),
+                order(3, Side::Bid, "99.5", "2"),
+            ],
+            vec![order(4, Side::Ask, "101.0", "1")],
+        ];
+        let expected = [vec![level("100", "2", 2), level("99.5", "2", 1)], vec![level("101", "1", 1)]];
+        let snapshot = Message::L4Book(L4Book::Snapshot {
+            coin: "BTC".to_string(),
+            time: 1,
+            height: 10,
+            seq: 5,
+            levels: orders,
+            checksum: checksum(&expected),
+            checkpoint: None,
+        });
+        assert_eq!(book.apply(&snapshot), Ok(true));
+        assert_eq!(book.levels(10), expected);
+
+        let status = OrderStatus {
+            time: "2025-06-24T02:56:36.172847427".to_string(),
+            user: String::new(),
+            status: "open".to_string(),
+            order: order(5, Side::Ask, "100.5", "3"),
+        };
+        let book_diffs = vec![
+            diff(5, "100.5", OrderDiff::New { sz: "3".to_string() }),
+            diff(1, "100.0", OrderDiff::Update { orig_sz: "1.5".to_string(), new_sz: "1".to_string() }),
+            diff(3, "99.5", OrderDiff::Remove),
+        ];
+        let expected = [vec![level("100", "1.5", 2)], vec![level("100.5", "3", 1), level("101", "1", 1)]];
+        let mut updates = L4BookUpdates {
+            time: 2,
+            height: 11,
+            seq: 6,
+            checksum: checksum(&expected),
+            order_statuses: vec![status],
+            book_diffs,
+        };
+        assert_eq!(book.apply(&Message::L4Book(L4Book::Updates(updates.clone()))), Ok(true));
+        assert_eq!(book.levels(10), expected);
+        assert_eq!(book.best_ask(), Some(level("100.5", "3", 1)));
+        assert_eq!((book.seq(), book.time()), (Some(6), 2));
+
+        // updates already applied are skipped, missed ones put the book out of sync
+        assert_eq!(book.apply(&Message::L4Book(L4Book::Updates(updates.clone()))), Ok(false));
+        updates.seq = 8;
+        assert_eq!(book.apply(&Message::L4Book(L4Book::Updates(updates))), Err(BookError::Gap { expected: 7, got: 8 }));
+        assert_eq!(book.seq(), None);
+    }
+
+    #[test]
+    fn test_l2_book_checksum() {
+        let mut book = OrderBook::new("BTC");
+        let levels = [vec![level("100", "2", 2)], vec![level("101", "1", 1)]];
+        let l2 = |checksum| L2Book { coin: "BTC".to_string(), time: 1, levels: levels.clone(), seq: 3, checksum };
+        assert_eq!(book.apply(&Message::L2Book(l2(checksum(&levels)))), Ok(true));
+        assert_eq!(book.best_bid(), Some(level("100", "2", 2)));
+        let res = book.apply(&Message::L2Book(l2(7)));
+        assert_eq!(res, Err(BookError::ChecksumMismatch { expected: 7, actual: checksum(&levels) }));
+        let other = L2Book { coin: "ETH".to_string(), ..l2(7) };
+        assert_eq!(book.apply(&Message::L2Book(other)), Ok(false));
+    }
+
+    #[test]
+    fn test_l2_deltas() {
+        let mut book = OrderBook::new("BTC");
+        let levels = [vec![level("100", "2", 2), level("99", "1", 1)], vec![level("101", "1", 1)]];
+        let snapshot = L2Book { coin: "BTC".to_string(), time: 1, levels: levels.clone(), seq: 3, checksum: 0 };
+        assert_eq!(book.apply(&Message::L2Book(snapshot)), Ok(true));
+
+        let change =
+            |i, px: Option<&str>, sz: &str, n| LevelChange { i, px: px.map(str::to_string), sz: sz.to_string(), n };
+        let expected = [vec![level("100", "2", 2), level("98", "4", 1)], vec![level("101", "3", 2)]];
+        let mut delta = L2Delta {
+            coin: "BTC".to_string(),
+            time: 2,
+            seq: 4,
+            prev_seq: 3,
+            epoch: 3,
+            changes: [vec![change(1, None, "0", 0), change(2, Some("98"), "4", 1)], vec![change(0, None, "3", 2)]],
+            checksum: checksum(&expected),
+        };
+        assert_eq!(book.apply(&Message::L2Delta(delta.clone())), Ok(true));
+        assert_eq!(book.levels(10), expected);
+        // a price of the epoch comes back by its index
+        let expected = [vec![level("100", "2", 2), level("99", "5", 3), level("98", "4", 1)], expected[1].clone()];
+        let next = L2Delta {
+            seq: 5,
+            prev_seq: 4,
+            changes: [vec![change(1, None, "5", 3)], Vec::new()],
+            checksum: checksum(&expected),
+            ..delta.clone()
+        };
+        assert_eq!(book.apply(&Message::L2Delta(next.clone())), Ok(true));
+        assert_eq!((book.levels(10), book.seq()), (expected, Some(5)));
+
+        // a missed delta, or an index the epoch doesn't have, put the book out of sync
+        assert_eq!(book.apply(&Message::L2Delta(delta.clone())), Err(BookError::Gap { expected: 5, got: 3 }));
+        assert_eq!(book.apply(&Message::L2Delta(next)), Ok(false));
+        let mut book = OrderBook::new("BTC");
+        let snapshot = L2Book { coin: "BTC".to_string(), time: 1, levels, seq: 3, checksum: 0 };
+        assert_eq!(book.apply(&Message::L2Book(snapshot)), Ok(true));
+        delta.changes = [vec![change(7, None, "1", 1)], Vec::new()];
+        assert_eq!(book.apply(&Message::L2Delta(delta)), Err(BookError::UnknownLevel(7)));
+    }
+}
@@ -0,0 +1,270 @@
+//! A websocket client that stays connected: it reconnects with a backoff whenever the connection drops, and
+//! subscribes to the same subscriptions again.
+
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::{
+    select,
+    sync::mpsc::{Receiver, Sender, UnboundedReceiver, UnboundedSender, channel, unbounded_channel},
+    task::JoinHandle,
+    time::sleep,
+};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{
+        self,
+        client::IntoClientRequest,
+        http::{
+            HeaderValue,
+            header::{AUTHORIZATION, SEC_WEBSOCKET_PROTOCOL},
+        },
+        protocol::Message as Frame,
+    },
+};
+
+use crate::messages::{Message, Request, Subscription};
+
+// messages the application hasn't taken yet; the connection stops reading beyond them
+const EVENTS_CAPACITY: usize = 1024;
+const MSGPACK_SUBPROTOCOL: &str = "orderbook.msgpack";
+
+/// Where to connect, and what to subscribe to once connected.
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// e.g. `ws://localhost:8000/ws`.
+    pub url: String,
+    /// Sent as `Authorization: Bearer <token>`.
+    pub token: Option<String>,
+    pub subscriptions: Vec<Subscription>,
+    /// Wait before the first reconnect, doubled after every failed attempt up to `max_reconnect_delay`.
+    pub reconnect_delay: Duration,
+    pub max_reconnect_delay: Duration,
+    /// Ask for `MessagePack` frames (the `orderbook.msgpack` subprotocol), which are smaller than JSON.
+    pub msgpack: bool,
+}
+
+impl ClientConfig {
+    #[must_use]
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            token: None,
+            subscriptions: Vec::new(),
+            reconnect_delay: Duration::from_millis(500),
+            max_reconnect_delay: Duration::from_secs(30),
+            msgpack: false,
+        }
+    }
+
+    #[must_use]
+    pub fn token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    #[must_use]
+    pub const fn msgpack(mut self) -> Self {
+        self.msgpack = true;
+        self
+    }
+
+    #[must_use]
+    pub fn subscribe(mut self, subscription: Subscription) -> Self {
+        self.subscriptions.push(subscription);
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// A connection is open and the subscriptions are requested again. Books kept from the messages of an earlier
+    /// connection start over from the snapshots that follow.
+    Connected,
+    Message(Message),
+    /// The connection dropped, with the reason. A new one is opened after the reconnect delay.
+    Disconnected(String),
+}
+
+/// A connection to the server, kept open in a background task until the client is dropped.
+pub struct Client {
+    requests: UnboundedSender<Request>,
+    events: Receiver<Event>,
+    task: JoinHandle<()>,
+}
+
+impl Client {
+    /// Starts connecting, on the current tokio runtime.
+    #[must_use]
+    pub fn connect(config: ClientConfig) -> Self {
+        let (requests, requests_rx) = unbounded_channel();
+        let (events_tx, events) = channel(EVENTS_CAPACITY);
+        let task = tokio::spawn(run(config, requests_rx, events_tx));
+        Self { requests, events, task }
+    }
+
+    /// The next event, or None once the connection task stopped.
+    pub async fn next(&mut self) -> Option<Event> {
+        self.events.recv().await
+    }
+
+    /// Subscribes now, and again on every reconnect.
+    pub fn subscribe(&self, subscription: Subscription) {
+        let _unused = self.requests.send(Request::Subscribe { subscription });
+    }
+
+    pub fn unsubscribe(&self, subscription: Subscription) {
+        let _unused = self.requests.send(Request::Unsubscribe { subscription });
+    }
+
+    /// Asks for a fresh snapshot of a subscription, e.g. after [`OrderBook::apply`](crate::OrderBook::apply)
+    /// failed.
+    pub fn snapshot(&self, subscription: Subscription) {
+        let _unused = self.requests.send(Request::Snapshot { subscription });
+    }
+
+    /// Sends any other request. Requests sent while disconnected are dropped.
+    pub fn send(&self, request: Request) {
+        let _unused = self.requests.send(request);
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn run(mut config: ClientConfig, mut requests: UnboundedReceiver<Request>, events: Sender<Event>) {
+    let mut delay = config.reconnect_delay;
+    loop {
+        let reason = match connect(&config).await {
+            Ok(ws) => {
+                delay = config.reconnect_delay;
+                if events.send(Event::Connected).await.is_err() {
+                    return;
+                }
+                match serve(ws, &mut config, &mut requests, &events).await {
+                    Some(reason) => reason,
+                    None => return,
+                }
+            }
+            Err(err) => err.to_string(),
+        };
+        if events.send(Event::Disconnected(reason)).await.is_err() {
+            return;
+        }
+        sleep(delay).await;
+        delay = (delay * 2).min(config.max_reconnect_delay);
+        // requests made while disconnected only change the subscriptions to restore
+        while let Ok(request) = requests.try_recv() {
+            track(&mut config.subscriptions, &request);
+        }
+    }
+}
+
+type Socket = tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+async fn connect(config: &ClientConfig) -> tungstenite::Result<Socket> {
+    let mut request = config.url.as_str().into_client_request()?;
+    if let Some(token) = &config.token {
+        let value = HeaderValue::from_str(&format!("Bearer {token}")).map_err(tungstenite::http::Error::from)?;
+        request.headers_mut().insert(AUTHORIZATION, value);
+    }
+    if config.msgpack {
+        request.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(MSGPACK_SUBPROTOCOL));
+    }
+    Ok(connect_async(request).await?.0)
+}
+
+// forwards messages until the connection drops, returning why; None once the client is gone
+async fn serve(
+    mut ws: Socket,
+    config: &mut ClientConfig,
+    requests: &mut UnboundedReceiver<Request>,
+    events: &Sender<Event>,
+) -> Option<String> {
+    for subscription in config.subscriptions.clone() {
+        if let Err(err) = send(&mut ws, &Request::Subscribe { subscription }).await {
+            return Some(err.to_string());
+        }
+    }
+    loop {
+        select! {
+            request = requests.recv() => {
+                let request = request?;
+                track(&mut config.subscriptions, &request);
+                if let Err(err) = send(&mut ws, &request).await {
+                    return Some(err.to_string());
+                }
+            }
+            frame = ws.next() => match frame {
+                Some(Ok(Frame::Text(text))) => match Message::parse(&text) {
+                    Ok(msgs) => {
+                        for msg in msgs {
+                            events.send(Event::Message(msg)).await.ok()?;
+                        }
+                    }
+                    Err(err) => return Some(format!("unreadable message: {err}")),
+                },
+                Some(Ok(Frame::Binary(payload))) => match Message::parse_msgpack(&payload) {
+                    Ok(msgs) => {
+                        for msg in msgs {
+                            events.send(Event::Message(msg)).await.ok()?;
+                        }
+                    }
+                    Err(err) => return Some(format!("unreadable message: {err}")),
+                },
+                Some(Ok(Frame::Close(frame))) => {
+                    return Some(frame.map_or_else(|| "closed".to_string(), |frame| format!("closed: {frame}")));
+                }
+                Some(Ok(_)) => {}
+                Some(Err(err)) => return Some(err.to_string()),
+                None => return Some("connection closed".to_string()),
+            },
+        }
+    }
+}
+
+async fn send(ws: &mut Socket, request: &Request) -> tungstenite::Result<()> {
+    let text = serde_json::to_string(request).unwrap_or_default();
+    ws.send(Frame::text(text)).await
+}
+
+// the subscriptions to restore on the next connection
+fn track(subscriptions: &mut Vec<Subscription>, request: &Request) {
+    match request {
+        Request::Subscribe { subscription }
+        | Request::Replay { subscription, .. }
+        | Request::Resume { subscription, .. } => {
+            if !subscriptions.contains(subscription) {
+                subscriptions.push(subscription.clone());
+            }
+        }
+        Request::Unsubscribe { subscription } => subscriptions.retain(|sub| sub != subscription),
+        Request::Snapshot { .. }
+        | Request::Auth { .. }
+        | Request::ListMarkets
+        | Request::GetSnapshot { .. }
+        | Request::GetMarkets { .. }
+        | Request::ServerTime { .. }
+        | Request::Ping { .. }
+        | Request::Stats { .. } => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_track_subscriptions() {
+        let mut subscriptions = vec![Subscription::l2_book("BTC")];
+        track(&mut subscriptions, &Request::Subscribe { subscription: Subscription::l4_book("ETH") });
+        track(&mut subscriptions, &Request::Subscribe { subscription: Subscription::l2_book("BTC") });
+        track(&mut subscriptions, &Request::Snapshot { subscription: Subscription::l4_book("SOL") });
+        assert_eq!(subscriptions, vec![Subscription::l2_book("BTC"), Subscription::l4_book("ETH")]);
+        track(&mut subscriptions, &Request::Unsubscribe { subscription: Subscription::l2_book("BTC") });
+        assert_eq!(subscriptions, vec![Subscription::l4_book("ETH")]);
+    }
+}
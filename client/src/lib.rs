@@ -0,0 +1,34 @@
+//! Client for the order book websocket server.
+//!
+//! - [`messages`]: the requests and messages of the websocket protocol.
+//! - [`Client`]: a connection that reconnects and subscribes again by itself.
+//! - [`OrderBook`]: a coin's book, built from snapshots and updates and checked against their checksums.
+//!
+//! ```no_run
+//! use order_book_client::{Client, ClientConfig, Event, OrderBook, Subscription};
+//!
+//! # async fn run() {
+//! let subscription = Subscription::l4_book("BTC");
+//! let mut client = Client::connect(ClientConfig::new("ws://localhost:8000/ws").subscribe(subscription.clone()));
+//! let mut book = OrderBook::new("BTC");
+//! while let Some(event) = client.next().await {
+//!     if let Event::Message(msg) = event {
+//!         match book.apply(&msg) {
+//!             Ok(true) => println!("best bid {:?}, best ask {:?}", book.best_bid(), book.best_ask()),
+//!             Ok(false) => {}
+//!             Err(_) => client.snapshot(subscription.clone()),
+//!         }
+//!     }
+//! }
+//! # }
+//! ```
+
+mod book;
+mod client;
+pub mod messages;
+
+// only needed for its crypto provider, which the TLS connections pick up
+pub use book::{BookError, CHECKSUM_LEVELS, OrderBook, checksum};
+pub use client::{Client, ClientConfig, Event};
+pub use messages::{Message, Request, Subscription, user_orders_message};
+use rustls as _;
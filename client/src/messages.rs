@@ -0,0 +1,801 @@
+//! The messages of the websocket protocol, as sent and received by clients. See the API section of the server's
+//! README for what each of them means.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+/// A request sent to the server.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "method", rename_all = "camelCase")]
+pub enum Request {
+    Subscribe {
+        subscription: Subscription,
+    },
+    Unsubscribe {
+        subscription: Subscription,
+    },
+    /// A fresh snapshot of an existing subscription, e.g. after a sequence gap.
+    Snapshot {
+        subscription: Subscription,
+    },
+    /// Only accepted as the first message of a connection.
+    Auth {
+        token: String,
+    },
+    /// The journaled l4 updates from a seq or block time (in ms) on, then the live stream. Takes one of them.
+    #[serde(rename_all = "camelCase")]
+    Replay {
+        subscription: Subscription,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        from_seq: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        from_ts: Option<u64>,
+    },
+    /// The l4 updates from a seq on that the server still has in memory, then the live stream. The server sends a
+    /// snapshot instead when it doesn't have all of them.
+    #[serde(rename_all = "camelCase")]
+    Resume {
+        subscription: Subscription,
+        from_seq: u64,
+    },
+    /// The markets of every market the server reads, answered with [`Message::Markets`].
+    ListMarkets,
+    /// The message a subscription would start with, without subscribing. Answered with [`Message::Response`], as
+    /// are the requests below.
+    GetSnapshot {
+        id: RequestId,
+        subscription: Subscription,
+    },
+    /// The markets the connection may subscribe to.
+    GetMarkets {
+        id: RequestId,
+    },
+    /// The server's clock, as `{"time": <ms since the epoch>}`. `client_time`, the client's clock when it sent the
+    /// request, is echoed as `clientTime`, so that the response alone tells the round trip.
+    #[serde(rename_all = "camelCase")]
+    ServerTime {
+        id: RequestId,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        client_time: Option<u64>,
+    },
+    /// Answered with `"pong"`.
+    Ping {
+        id: RequestId,
+    },
+    /// The connection as the server sees it: the depth and capacity of its send queue, the messages dropped or
+    /// conflated for it, the subprotocol, compression and number format it negotiated, and its subscriptions.
+    Stats {
+        id: RequestId,
+    },
+}
+
+/// The id of a request, echoed in its [`Response`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum RequestId {
+    Number(u64),
+    String(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Subscription {
+    #[serde(rename_all = "camelCase")]
+    Trades {
+        coin: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    L2Book {
+        coin: String,
+        #[serde(default)]
+        n_sig_figs: Option<u32>,
+        /// Levels per side, 20 by default.
+        #[serde(default)]
+        n_levels: Option<usize>,
+        #[serde(default)]
+        mantissa: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        conflate_ms: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        tick_size: Option<String>,
+        /// The optional fields to send, of `time`, `seq`, `checksum` and `n`; all of them if not set.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        fields: Option<Vec<String>>,
+        /// Send the changes to the book as [`Message::L2Delta`], after a full book that starts each epoch.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        delta: Option<bool>,
+    },
+    #[serde(rename_all = "camelCase")]
+    Bbo {
+        coin: String,
+        /// The optional fields to send, of `time`, `seq`, `mid` and `n`; all of them if not set.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        fields: Option<Vec<String>>,
+    },
+    /// `interval` is one of the server's candle intervals, e.g. `1m`.
+    #[serde(rename_all = "camelCase")]
+    Candle {
+        coin: String,
+        interval: String,
+    },
+    /// Spread, book imbalance and VWAP of a coin, on servers with analytics enabled.
+    #[serde(rename_all = "camelCase")]
+    Analytics {
+        coin: String,
+    },
+    /// Signed checkpoints of a coin's book, on servers with signing enabled.
+    #[serde(rename_all = "camelCase")]
+    Checkpoint {
+        coin: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    L4Book {
+        coin: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        conflate_ms: Option<u64>,
+    },
+    /// Every order of a coin's book and their changes, without their users.
+    #[serde(rename_all = "camelCase")]
+    L3Book {
+        coin: String,
+    },
+    /// The statuses and fills of an account's orders, on every coin. Build it with [`Subscription::user_orders`].
+    #[serde(rename_all = "camelCase")]
+    UserOrders {
+        user: String,
+        timestamp: u64,
+        signature: String,
+    },
+    Status,
+    /// Markets added to, changed in or removed from the node's metadata.
+    Markets,
+}
+
+impl Subscription {
+    /// The l2 book of a coin, with the server's defaults.
+    #[must_use]
+    pub fn l2_book(coin: impl Into<String>) -> Self {
+        Self::L2Book {
+            coin: coin.into(),
+            n_sig_figs: None,
+            n_levels: None,
+            mantissa: None,
+            conflate_ms: None,
+            tick_size: None,
+            fields: None,
+            delta: None,
+        }
+    }
+
+    /// The l2 book of a coin, sent as deltas to the previous message.
+    #[must_use]
+    pub fn l2_deltas(coin: impl Into<String>) -> Self {
+        Self::L2Book {
+            coin: coin.into(),
+            n_sig_figs: None,
+            n_levels: None,
+            mantissa: None,
+            conflate_ms: None,
+            tick_size: None,
+            fields: None,
+            delta: Some(true),
+        }
+    }
+
+    /// Every order of a coin's book.
+    #[must_use]
+    pub fn l4_book(coin: impl Into<String>) -> Self {
+        Self::L4Book { coin: coin.into(), conflate_ms: None }
+    }
+
+    /// Every order of a coin's book, without the users.
+    #[must_use]
+    pub fn l3_book(coin: impl Into<String>) -> Self {
+        Self::L3Book { coin: coin.into() }
+    }
+
+    /// The orders of `user`, with its EIP-191 (`personal_sign`) signature of [`user_orders_message`] at `timestamp`,
+    /// in ms. The server takes signatures up to 5 minutes old.
+    #[must_use]
+    pub fn user_orders(user: impl Into<String>, timestamp: u64, signature: impl Into<String>) -> Self {
+        Self::UserOrders { user: user.into(), timestamp, signature: signature.into() }
+    }
+}
+
+/// The message an account signs to subscribe to its orders.
+#[must_use]
+pub fn user_orders_message(user: &str, timestamp: u64) -> String {
+    format!("userOrders:{}:{timestamp}", user.to_lowercase())
+}
+
+/// A message from the server.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "channel", content = "data", rename_all = "camelCase")]
+pub enum Message {
+    /// Confirms a subscribe or unsubscribe request.
+    SubscriptionResponse(Request),
+    L2Book(L2Book),
+    /// The changes to the l2 book of a delta subscription.
+    L2Delta(L2Delta),
+    Bbo(Bbo),
+    L4Book(L4Book),
+    L3Book(L3Book),
+    UserOrders(UserOrders),
+    Trades(Vec<Trade>),
+    Candle(Candle),
+    Analytics(Analytics),
+    Checkpoint(Checkpoint),
+    Heartbeat(Heartbeat),
+    /// The server's clock, on servers with a time interval.
+    Time(TimeSync),
+    /// The book data of a subscribed market is held back from now on.
+    Halt(MarketHalt),
+    /// The book data of a halted market is sent again, starting with fresh snapshots.
+    Resume(MarketHalt),
+    Status(StreamStatus),
+    Markets(Vec<MarketInfo>),
+    MarketChanges(Vec<MarketChange>),
+    Maintenance(Maintenance),
+    /// The token of the connection, on servers that keep the sessions of dropped connections.
+    Session(Session),
+    /// The last message before the server closes a connection that reads too slowly.
+    Evicted(SlowConsumerReport),
+    /// The answer to a request with an id.
+    Response(Response),
+    Error(String),
+    /// A request the server couldn't read, or any rejected request on version 2 connections.
+    #[serde(untagged)]
+    ProtocolError {
+        error: ProtocolError,
+    },
+}
+
+impl Message {
+    /// The messages of a text frame, which holds an array of them on connections that batch messages.
+    pub fn parse(text: &str) -> serde_json::Result<Vec<Self>> {
+        Ok(serde_json::from_str::<Frame>(text)?.into_messages())
+    }
+
+    /// The messages of a binary frame of a connection with the `orderbook.msgpack` subprotocol.
+    pub fn parse_msgpack(payload: &[u8]) -> Result<Vec<Self>, rmp_serde::decode::Error> {
+        Ok(rmp_serde::from_slice::<Frame>(payload)?.into_messages())
+    }
+}
+
+// a single message, or the array of a batch
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Frame {
+    Batch(Vec<Message>),
+    Single(Message),
+}
+
+impl Frame {
+    fn into_messages(self) -> Vec<Message> {
+        match self {
+            Self::Batch(msgs) => msgs,
+            Self::Single(msg) => vec![msg],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Side {
+    #[serde(rename = "A")]
+    Ask,
+    #[serde(rename = "B")]
+    Bid,
+}
+
+/// Aggregated orders at one price. Prices and sizes are decimal strings.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Level {
+    pub px: String,
+    pub sz: String,
+    /// Number of orders, 0 on subscriptions that leave it out.
+    #[serde(default)]
+    pub n: usize,
+}
+
+/// A full l2 book, bids then asks, best first.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct L2Book {
+    pub coin: String,
+    #[serde(default)]
+    pub time: u64,
+    pub levels: [Vec<Level>; 2],
+    #[serde(default)]
+    pub seq: u64,
+    /// Of the levels in this message, see [`checksum`](crate::checksum).
+    #[serde(default)]
+    pub checksum: u32,
+}
+
+/// The changes to an l2 book since the message with `prev_seq`.
+///
+/// Levels are referenced by their index on their side: the indices of the l2 book that started the epoch, which has
+/// the seq `epoch`, and then of the prices that were referenced for the first time since, in the order they were.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct L2Delta {
+    pub coin: String,
+    #[serde(default)]
+    pub time: u64,
+    pub seq: u64,
+    pub prev_seq: u64,
+    pub epoch: u64,
+    /// Bids, asks.
+    pub changes: [Vec<LevelChange>; 2],
+    /// Of the levels of the book after the changes.
+    #[serde(default)]
+    pub checksum: u32,
+}
+
+/// A level of an [`L2Delta`] that changed, or was removed with a size of 0.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LevelChange {
+    pub i: usize,
+    /// Only set the first time the price is referenced in the epoch, when it takes the next index.
+    #[serde(default)]
+    pub px: Option<String>,
+    pub sz: String,
+    #[serde(default)]
+    pub n: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Bbo {
+    pub coin: String,
+    #[serde(default)]
+    pub time: u64,
+    #[serde(default)]
+    pub seq: u64,
+    pub bid: Option<Level>,
+    pub ask: Option<Level>,
+    pub mid: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum L4Book {
+    /// Every order of the book, bids then asks. Updates following it start at `seq + 1`.
+    Snapshot {
+        coin: String,
+        time: u64,
+        height: u64,
+        #[serde(default)]
+        seq: u64,
+        levels: [Vec<L4Order>; 2],
+        /// Of the book aggregated into levels.
+        #[serde(default)]
+        checksum: u32,
+        /// Signed by the server, on servers with signing enabled.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        checkpoint: Option<Checkpoint>,
+    },
+    Updates(L4BookUpdates),
+}
+
+/// The changes of one block to a coin's book.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct L4BookUpdates {
+    pub time: u64,
+    pub height: u64,
+    /// Increases by exactly one with every update of the coin.
+    #[serde(default)]
+    pub seq: u64,
+    /// Of the coin's book once this update is applied, aggregated into levels.
+    #[serde(default)]
+    pub checksum: u32,
+    pub order_statuses: Vec<OrderStatus>,
+    pub book_diffs: Vec<BookDiff>,
+}
+
+impl L4BookUpdates {
+    /// The coin whose book the update changes.
+    #[must_use]
+    pub fn coin(&self) -> Option<&str> {
+        self.book_diffs
+            .first()
+            .map(|diff| diff.coin.as_str())
+            .or_else(|| self.order_statuses.first().map(|status| status.order.coin.as_str()))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct L4Order {
+    /// Set in snapshots, in updates the user is on the order's status.
+    #[serde(default)]
+    pub user: Option<String>,
+    pub coin: String,
+    pub side: Side,
+    pub limit_px: String,
+    pub sz: String,
+    pub oid: u64,
+    pub timestamp: u64,
+    pub trigger_condition: String,
+    pub is_trigger: bool,
+    pub trigger_px: String,
+    pub is_position_tpsl: bool,
+    pub reduce_only: bool,
+    pub order_type: String,
+    pub tif: Option<String>,
+    pub cloid: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OrderStatus {
+    pub time: String,
+    pub user: String,
+    pub status: String,
+    pub order: L4Order,
+}
+
+/// A change to one order of the book.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BookDiff {
+    pub user: String,
+    pub oid: u64,
+    pub px: String,
+    pub coin: String,
+    pub raw_book_diff: OrderDiff,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum OrderDiff {
+    /// An order enters the book, the rest of it is in the update's order statuses.
+    #[serde(rename_all = "camelCase")]
+    New {
+        sz: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    Update {
+        orig_sz: String,
+        new_sz: String,
+    },
+    Remove,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum L3Book {
+    /// Every order of the book, bids then asks, best price first and in the order they are matched at each price.
+    /// Updates following it start at `seq + 1`.
+    Snapshot {
+        coin: String,
+        time: u64,
+        height: u64,
+        seq: u64,
+        levels: [Vec<L3Order>; 2],
+    },
+    Updates(L3BookUpdates),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct L3Order {
+    pub oid: u64,
+    pub px: String,
+    pub sz: String,
+    /// When it entered the book, in ms.
+    pub timestamp: u64,
+}
+
+/// The changes of one block to the orders of a coin's book, in the order they were made.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct L3BookUpdates {
+    pub coin: String,
+    pub time: u64,
+    pub height: u64,
+    /// That of the l4 update of the same block, so it increases by exactly one with every update of the coin.
+    pub seq: u64,
+    pub events: Vec<OrderEvent>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum OrderEvent {
+    /// An order entering the book, behind the orders resting at its price.
+    Add { oid: u64, side: Side, px: String, sz: String, timestamp: u64 },
+    /// A resting order partially filled or resized, which keeps its place.
+    #[serde(rename_all = "camelCase")]
+    Modify { oid: u64, side: Side, px: String, orig_sz: String, sz: String },
+    /// An order taken off the book, with its status in the block if there is one, e.g. `filled` or `canceled`.
+    Cancel { oid: u64, side: Side, px: String, status: Option<String> },
+}
+
+/// The events of one account's orders in a block.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserOrders {
+    pub user: String,
+    pub time: u64,
+    pub height: u64,
+    pub events: Vec<UserEvent>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum UserEvent {
+    /// The order was placed, filled, canceled, rejected or triggered.
+    Order(OrderStatus),
+    /// A fill of one of its orders, partial unless the order's status is `filled`.
+    Fill(Fill),
+}
+
+/// A fill from the side of one account.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Fill {
+    pub coin: String,
+    pub px: String,
+    pub sz: String,
+    pub side: Side,
+    pub time: u64,
+    pub start_position: String,
+    pub dir: String,
+    pub closed_pnl: String,
+    pub hash: String,
+    pub oid: u64,
+    /// Whether the order took liquidity.
+    pub crossed: bool,
+    pub fee: String,
+    pub tid: u64,
+    pub fee_token: String,
+    pub liquidation: Option<Liquidation>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Liquidation {
+    pub liquidated_user: String,
+    pub mark_px: String,
+    pub method: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Trade {
+    pub coin: String,
+    /// The taker's side.
+    pub side: Side,
+    pub px: String,
+    pub sz: String,
+    pub hash: String,
+    pub time: u64,
+    pub tid: u64,
+    /// Buyer, seller.
+    pub users: [String; 2],
+    #[serde(default)]
+    pub taker: String,
+    #[serde(default)]
+    pub maker: String,
+}
+
+/// In the format of Hyperliquid's candle channel, times in ms.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Candle {
+    pub t: u64,
+    #[serde(rename = "T")]
+    pub close_time: u64,
+    pub s: String,
+    pub i: String,
+    pub o: String,
+    pub c: String,
+    pub h: String,
+    pub l: String,
+    pub v: String,
+    pub n: u64,
+}
+
+/// Rolling metrics of a market at `time`. The fields of an empty side of the book are `None`, as is the VWAP when
+/// nothing traded in the last `vwap_window` ms.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Analytics {
+    pub coin: String,
+    pub time: u64,
+    pub bid: Option<String>,
+    pub ask: Option<String>,
+    pub spread: Option<String>,
+    pub spread_bps: Option<f64>,
+    /// `(bid size - ask size) / (bid size + ask size)` over the first `depth` levels of each side.
+    pub imbalance: Option<f64>,
+    pub depth: usize,
+    pub vwap: Option<String>,
+    pub vwap_window: u64,
+    /// Traded in the window, in the base asset.
+    pub volume: String,
+    pub trades: usize,
+}
+
+/// A coin's book at `seq`, signed with the server's Ed25519 key.
+///
+/// `digest` is the SHA-256 of `bid px:bid sz:ask px:ask sz:...` over every level of the book, and `signature` the
+/// signature of `coin:time:height:seq:checksum:digest`, both hex encoded.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Checkpoint {
+    pub coin: String,
+    pub time: u64,
+    pub height: u64,
+    pub seq: u64,
+    pub checksum: u32,
+    pub digest: String,
+    pub signature: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Heartbeat {
+    pub time: u64,
+    pub l2_seq: u64,
+    pub l4_seqs: BTreeMap<String, u64>,
+}
+
+/// When the server wrote the message, in ms since the epoch.
+///
+/// The difference to the client's clock on receipt is its clock offset plus the one-way latency.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TimeSync {
+    pub send_time: u64,
+}
+
+/// A market whose book data is held back, or sent again.
+///
+/// `reason` is `market` when the node has no book for it, e.g. because the chain paused it, and `audit` when the
+/// server's book diverged from the node's.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MarketHalt {
+    pub coin: String,
+    pub reason: String,
+    pub time: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamStatus {
+    /// The market whose feed it is, `None` for the primary one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub market: Option<String>,
+    pub stale: bool,
+    pub ready: bool,
+    pub last_block: u64,
+    pub last_block_time: Option<u64>,
+    pub lag_ms: Option<u64>,
+    pub upstreams: Vec<UpstreamStatus>,
+    pub snapshot_in_progress: bool,
+    #[serde(default)]
+    pub gaps: u64,
+    /// The coins whose books the node's deltas left crossed or locked.
+    #[serde(default)]
+    pub crossed_books: Vec<String>,
+    pub maintenance: bool,
+    pub time: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpstreamStatus {
+    pub node: String,
+    pub connected: bool,
+}
+
+/// A market of the node's metadata. Sizes are multiples of `lot_size`, and prices multiples of `tick_size` with at
+/// most 5 significant figures.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MarketInfo {
+    pub coin: String,
+    /// `perp` or `spot`.
+    pub kind: String,
+    pub base: String,
+    pub quote: String,
+    pub sz_decimals: u32,
+    pub lot_size: String,
+    pub tick_size: String,
+    /// `active`, `halted` (the node has no book for it) or `delisted`.
+    pub status: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MarketChange {
+    /// `added`, `changed` or `removed`.
+    pub change: String,
+    pub market: MarketInfo,
+}
+
+/// Scheduled downtime, sent to every client.
+///
+/// At `start` (ms since the epoch) the server closes the connections with `close_code` and rejects new ones until
+/// the maintenance is over. A notice without a start calls it off.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Maintenance {
+    pub start: Option<u64>,
+    pub message: Option<String>,
+    pub close_code: u16,
+}
+
+/// The token to restore a dropped connection with.
+///
+/// Reconnecting with `?session=<token>` in the URL within `grace_secs` of the connection dropping restores its
+/// subscriptions, and resumes its l4 books after their last update. `resumed` tells whether this connection
+/// restored one.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Session {
+    pub token: String,
+    pub resumed: bool,
+    pub grace_secs: u64,
+}
+
+/// Why the server evicted a client.
+///
+/// `action` is `evicted`, `reason` is `queueFull` or `bandwidthQuota`. `queue_depth_history` holds the deepest the
+/// client's send queue got in each of the last seconds, oldest first, and `drain_rate` the messages it read per
+/// second over them.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SlowConsumerReport {
+    pub action: String,
+    pub reason: String,
+    pub time: u64,
+    pub queue_capacity: usize,
+    pub queue_depth_history: Vec<usize>,
+    pub drain_rate: f64,
+    pub subscriptions: Vec<Subscription>,
+}
+
+/// The answer to a request with an id: its `result`, or the `error` it was rejected with.
+///
+/// The result of a [`Request::GetSnapshot`] is a message, which [`Response::message`] reads.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Response {
+    pub id: RequestId,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<ProtocolError>,
+}
+
+impl Response {
+    /// The message of a snapshot response.
+    pub fn message(&self) -> Option<serde_json::Result<Message>> {
+        self.result.clone().map(serde_json::from_value)
+    }
+}
+
+/// A rejected request. `code` is one of the error codes in the server's README.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProtocolError {
+    pub code: u16,
+    pub msg: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_frames() -> serde_json::Result<()> {
+        let trades = r#"{"channel":"trades","data":[],"latency":{"nodeTime":1,"ingestTime":3,"sendTime":7}}"#;
+        assert_eq!(Message::parse(trades)?, vec![Message::Trades(Vec::new())]);
+        let batch = r#"[{"channel":"error","data":"Invalid subscription"},{"error":{"code":1003,"msg":"unknown"}}]"#;
+        let error = ProtocolError { code: 1003, msg: "unknown".to_string() };
+        assert_eq!(
+            Message::parse(batch)?,
+            vec![Message::Error("Invalid subscription".to_string()), Message::ProtocolError { error }]
+        );
+        let response = r#"{"channel":"response","data":{"id":"a","result":{"channel":"trades","data":[]}}}"#;
+        let [Message::Response(response)] = &Message::parse(response)?[..] else { panic!("expected a response") };
+        assert_eq!(response.id, RequestId::String("a".to_string()));
+        assert_eq!(response.message().transpose()?, Some(Message::Trades(Vec::new())));
+        let request = Request::Subscribe { subscription: Subscription::l2_book("BTC") };
+        assert_eq!(
+            serde_json::to_string(&request)?,
+            r#"{"method":"subscribe","subscription":{"type":"l2Book","coin":"BTC","nSigFigs":null,"nLevels":null,"mantissa":null}}"#
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,6 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// every client message, however malformed, is answered with a request or a protocol error
+fuzz_target!(|data: &[u8]| server::fuzz_parse_request(data));
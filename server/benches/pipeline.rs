@@ -0,0 +1,104 @@
+#![allow(unused_crate_dependencies, clippy::unwrap_used)]
+use std::{hint::black_box, time::Duration};
+
+use criterion::{BatchSize, BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use server::bench::{Churn, Fanout, Fixture, Format, Message, Stack, Transport, compress};
+
+const COINS: usize = 4;
+const ORDERS: u64 = 10_000;
+const BLOCK_SIZE: u64 = 200;
+const SUBSCRIBERS: usize = 1000;
+// orders that open and close a price level each
+const CHURN: u64 = 1000;
+// connections of the transport benchmark, each with a server and a client socket: needs `ulimit -n 25000` or so
+const CONNECTIONS: usize = 10_000;
+
+fn fixture() -> Fixture {
+    Fixture::new(COINS, ORDERS, BLOCK_SIZE).unwrap()
+}
+
+fn apply_block(c: &mut Criterion) {
+    let fixture = fixture();
+    let mut group = c.benchmark_group("apply_block");
+    group.throughput(Throughput::Elements(COINS as u64 * BLOCK_SIZE));
+    group.bench_function("mixed", |b| {
+        b.iter_batched_ref(|| fixture.block(), |block| block.apply().unwrap(), BatchSize::LargeInput);
+    });
+    group.finish();
+}
+
+fn churn(c: &mut Criterion) {
+    let mut group = c.benchmark_group("churn");
+    let mut churn = Churn::new(ORDERS, CHURN);
+    group.throughput(Throughput::Elements(CHURN));
+    group.bench_function("new_levels", |b| b.iter(|| churn.run()));
+    group.finish();
+}
+
+const MESSAGES: [(&str, Message); 3] =
+    [("l4_snapshot", Message::L4Snapshot), ("l4_updates", Message::L4Updates), ("l2_book", Message::L2Book)];
+
+fn serialize(c: &mut Criterion) {
+    let fixture = fixture();
+    let mut group = c.benchmark_group("serialize");
+    for (name, msg) in MESSAGES {
+        for (format_name, format) in [("json", Format::Json), ("msgpack", Format::MessagePack)] {
+            group.throughput(Throughput::Bytes(fixture.encode(msg, format).unwrap().len() as u64));
+            group.bench_function(BenchmarkId::new(name, format_name), |b| {
+                b.iter(|| fixture.encode(black_box(msg), format).unwrap());
+            });
+        }
+    }
+    group.finish();
+}
+
+fn compression(c: &mut Criterion) {
+    let fixture = fixture();
+    let mut group = c.benchmark_group("compress");
+    for (name, msg) in MESSAGES {
+        let payload = fixture.encode(msg, Format::Json).unwrap();
+        group.throughput(Throughput::Bytes(payload.len() as u64));
+        for level in [1, 6, 9] {
+            group.bench_with_input(BenchmarkId::new(name, level), &payload, |b, payload| {
+                b.iter(|| compress(level, black_box(payload)).unwrap());
+            });
+        }
+    }
+    group.finish();
+}
+
+fn fanout(c: &mut Criterion) {
+    let fixture = fixture();
+    let mut group = c.benchmark_group("fanout");
+    group.throughput(Throughput::Elements(SUBSCRIBERS as u64));
+    for (name, compression_level) in [("uncompressed", None), ("compressed", Some(1))] {
+        let mut fanout = Fanout::new(&fixture, SUBSCRIBERS, compression_level).unwrap();
+        group.bench_function(BenchmarkId::new(name, SUBSCRIBERS), |b| b.iter(|| fanout.broadcast().unwrap()));
+    }
+    group.finish();
+}
+
+fn transport(c: &mut Criterion) {
+    let fixture = fixture();
+    let mut group = c.benchmark_group("transport");
+    group.throughput(Throughput::Elements(CONNECTIONS as u64));
+    let stacks = [
+        ("tokio", Stack::Tokio),
+        #[cfg(feature = "io-uring")]
+        ("io_uring", Stack::IoUring),
+    ];
+    for (name, stack) in stacks {
+        // one at a time, so that both fit the file descriptor limit
+        let mut transport = Transport::new(&fixture, stack, CONNECTIONS).unwrap();
+        group.bench_function(BenchmarkId::new(name, CONNECTIONS), |b| b.iter(|| transport.broadcast().unwrap()));
+    }
+    group.finish();
+}
+
+// smaller changes are not reported as regressions; CI, whose shared runners are noisier, raises it to 10%
+criterion_group! {
+    name = benches;
+    config = Criterion::default().noise_threshold(0.05).measurement_time(Duration::from_secs(10));
+    targets = apply_block, churn, serialize, compression, fanout, transport
+}
+criterion_main!(benches);
@@ -0,0 +1,7 @@
+// compiles the gRPC definitions with a pure Rust protobuf parser, so building doesn't require protoc
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-changed=proto");
+    let file_descriptors = protox::compile(["proto/orderbook.proto"], ["proto"])?;
+    tonic_build::configure().compile_fds(file_descriptors)?;
+    Ok(())
+}
@@ -0,0 +1,262 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    listeners::order_book::L2SnapshotParams,
+    order_book::{Coin, Px, Side, Snapshot, Sz},
+    types::{
+        inner::InnerLevel,
+        node_data::{Batch, NodeDataFill},
+    },
+};
+
+/// Rolling metrics computed from the books and trades of every market for the `analytics` channel.
+#[derive(Debug, Clone)]
+pub struct AnalyticsConfig {
+    /// How often the metrics are published, in block time.
+    pub interval: Duration,
+    /// Price levels on each side of the book that count towards its imbalance.
+    pub depth: usize,
+    /// The VWAP is of the trades in this much block time.
+    pub vwap_window: Duration,
+}
+
+impl Default for AnalyticsConfig {
+    fn default() -> Self {
+        Self { interval: Duration::from_secs(1), depth: 10, vwap_window: Duration::from_mins(1) }
+    }
+}
+
+// the metrics of a market at `time`. Prices are strings like in the books, ratios are numbers
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct MarketAnalytics {
+    pub coin: String,
+    pub time: u64,
+    pub bid: Option<String>,
+    pub ask: Option<String>,
+    pub spread: Option<String>,
+    // spread over the mid price, in basis points
+    pub spread_bps: Option<f64>,
+    // (bid size - ask size) / (bid size + ask size) over the first `depth` levels of each side, from -1 to 1
+    pub imbalance: Option<f64>,
+    pub depth: usize,
+    // of the trades in the last `vwapWindow` milliseconds, none if there were none
+    pub vwap: Option<String>,
+    pub vwap_window: u64,
+    // traded in the window, in the base asset
+    pub volume: String,
+    pub trades: usize,
+}
+
+// trades in the VWAP window of a market, with running sums of their notional and size
+#[derive(Default)]
+struct Trades {
+    // time, px, sz, oldest first
+    trades: VecDeque<(u64, Px, Sz)>,
+    notional: u128,
+    volume: u128,
+}
+
+impl Trades {
+    fn push(&mut self, time: u64, px: Px, sz: Sz) {
+        self.notional += u128::from(px.value()) * u128::from(sz.value());
+        self.volume += u128::from(sz.value());
+        self.trades.push_back((time, px, sz));
+    }
+
+    fn expire(&mut self, time: u64, window: u64) {
+        while let Some(&(trade_time, px, sz)) = self.trades.front()
+            && trade_time + window < time
+        {
+            self.notional -= u128::from(px.value()) * u128::from(sz.value());
+            self.volume -= u128::from(sz.value());
+            self.trades.pop_front();
+        }
+    }
+
+    fn vwap(&self) -> Option<Px> {
+        // both are scaled by the same multiplier, so the quotient is a price
+        let vwap = self.notional.checked_div(self.volume)?;
+        Some(Px::new(u64::try_from(vwap).ok()?))
+    }
+
+    fn volume(&self) -> Sz {
+        Sz::new(u64::try_from(self.volume).unwrap_or(u64::MAX))
+    }
+}
+
+// the metrics of every market, updated from its fills and published with its books every `interval`
+pub(crate) struct Analytics {
+    config: AnalyticsConfig,
+    trades: HashMap<String, Trades>,
+    last_published: Option<u64>,
+    latest: HashMap<String, MarketAnalytics>,
+}
+
+impl Analytics {
+    pub(crate) fn new(config: AnalyticsConfig) -> Self {
+        Self { config, trades: HashMap::new(), last_published: None, latest: HashMap::new() }
+    }
+
+    pub(crate) const fn config(&self) -> &AnalyticsConfig {
+        &self.config
+    }
+
+    fn window(&self) -> u64 {
+        u64::try_from(self.config.vwap_window.as_millis()).unwrap_or(u64::MAX)
+    }
+
+    pub(crate) fn on_fills(&mut self, batch: &Batch<NodeDataFill>) {
+        let window = self.window();
+        // every trade has a fill for each side
+        for NodeDataFill(_, fill) in batch.events_ref().iter().filter(|fill| fill.1.side == Side::Bid) {
+            let (Ok(px), Ok(sz)) = (Px::parse_from_str(&fill.px), Sz::parse_from_str(&fill.sz)) else {
+                continue;
+            };
+            let trades = self.trades.entry(fill.coin.clone()).or_default();
+            trades.push(fill.time, px, sz);
+            trades.expire(fill.time, window);
+        }
+    }
+
+    // the metrics of every book at `time`, if they are due
+    pub(crate) fn on_books(
+        &mut self,
+        time: u64,
+        books: &HashMap<Coin, HashMap<L2SnapshotParams, Snapshot<InnerLevel>>>,
+    ) -> Vec<MarketAnalytics> {
+        let interval = u64::try_from(self.config.interval.as_millis()).unwrap_or(u64::MAX);
+        if self.last_published.is_some_and(|last| time < last.saturating_add(interval)) {
+            return Vec::new();
+        }
+        self.last_published = Some(time);
+        let window = self.window();
+        let params = L2SnapshotParams::new(None, None, None);
+        let mut analytics = Vec::new();
+        for (coin, snapshots) in books {
+            let Some(snapshot) = snapshots.get(&params) else {
+                continue;
+            };
+            let [bids, asks] = snapshot.as_ref();
+            let (bid, ask) = (bids.first().map(|level| level.px), asks.first().map(|level| level.px));
+            let spread = bid.zip(ask).map(|(bid, ask)| Px::new(ask.value().saturating_sub(bid.value())));
+            #[allow(clippy::cast_precision_loss)]
+            let spread_bps = bid.zip(ask).and_then(|(bid, ask)| {
+                let mid = f64::midpoint(bid.value() as f64, ask.value() as f64);
+                (mid > 0.0).then(|| (ask.value() as f64 - bid.value() as f64) / mid * 10_000.0)
+            });
+            let (bid_sz, ask_sz) = (depth_size(bids, self.config.depth), depth_size(asks, self.config.depth));
+            #[allow(clippy::cast_precision_loss)]
+            let imbalance =
+                (bid_sz + ask_sz > 0).then(|| (bid_sz as f64 - ask_sz as f64) / (bid_sz as f64 + ask_sz as f64));
+            let trades = self.trades.entry(coin.value()).or_default();
+            trades.expire(time, window);
+            let market = MarketAnalytics {
+                coin: coin.value(),
+                time,
+                bid: bid.map(Px::to_str),
+                ask: ask.map(Px::to_str),
+                spread: spread.map(Px::to_str),
+                spread_bps,
+                imbalance,
+                depth: self.config.depth,
+                vwap: trades.vwap().map(Px::to_str),
+                vwap_window: window,
+                volume: trades.volume().to_str(),
+                trades: trades.trades.len(),
+            };
+            self.latest.insert(market.coin.clone(), market.clone());
+            analytics.push(market);
+        }
+        // markets that are gone keep no trades
+        self.trades.retain(|coin, _| self.latest.contains_key(coin));
+        analytics
+    }
+
+    pub(crate) fn latest(&self, coin: &str) -> Option<MarketAnalytics> {
+        self.latest.get(coin).cloned()
+    }
+}
+
+fn depth_size(levels: &[InnerLevel], depth: usize) -> u64 {
+    levels.iter().take(depth).map(|level| level.sz.value()).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::primitives::Address;
+
+    use super::*;
+
+    fn batch(trades: &[(&str, &str, u64)]) -> Batch<NodeDataFill> {
+        let fills = trades
+            .iter()
+            .flat_map(|(px, sz, time)| {
+                ["B", "A"].map(|side| {
+                    format!(
+                        r#"[{:?},{{"coin":"BTC","px":"{px}","sz":"{sz}","side":"{side}","time":{time},"startPosition":"0.0","dir":"","closedPnl":"0.0","hash":"0x0","oid":1,"crossed":true,"fee":"0.0","tid":7,"feeToken":"USDC","liquidation":null}}]"#,
+                        Address::ZERO.to_string()
+                    )
+                })
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        serde_json::from_str(&format!(
+            r#"{{"local_time":"2025-01-01T00:00:00","block_time":"2025-01-01T00:00:00","block_number":1,"events":[{fills}]}}"#
+        ))
+        .unwrap()
+    }
+
+    fn books(
+        bids: &[(&str, &str)],
+        asks: &[(&str, &str)],
+    ) -> HashMap<Coin, HashMap<L2SnapshotParams, Snapshot<InnerLevel>>> {
+        let levels = |levels: &[(&str, &str)]| {
+            levels
+                .iter()
+                .map(|(px, sz)| InnerLevel {
+                    px: Px::parse_from_str(px).unwrap(),
+                    sz: Sz::parse_from_str(sz).unwrap(),
+                    n: 1,
+                })
+                .collect()
+        };
+        let snapshot = Snapshot::new([levels(bids), levels(asks)]);
+        HashMap::from([(Coin::new("BTC"), HashMap::from([(L2SnapshotParams::new(None, None, None), snapshot)]))])
+    }
+
+    #[test]
+    fn test_analytics() {
+        let config =
+            AnalyticsConfig { interval: Duration::from_secs(1), depth: 2, vwap_window: Duration::from_secs(10) };
+        let mut analytics = Analytics::new(config);
+        analytics.on_fills(&batch(&[("100", "1", 1000), ("103", "2", 2000)]));
+        let btc = books(&[("99", "3"), ("98", "1"), ("97", "10")], &[("101", "1"), ("102", "1")]);
+        let published = analytics.on_books(2000, &btc);
+        assert_eq!(published.len(), 1);
+        let market = &published[0];
+        assert_eq!(
+            (market.bid.as_deref(), market.ask.as_deref(), market.spread.as_deref()),
+            (Some("99"), Some("101"), Some("2"))
+        );
+        assert_eq!(market.spread_bps, Some(200.0));
+        // the third bid level is beyond the depth
+        assert_eq!(market.imbalance, Some(1.0 / 3.0));
+        assert_eq!((market.vwap.as_deref(), market.volume.as_str(), market.trades), (Some("102"), "3", 2));
+        assert_eq!(analytics.latest("BTC").as_ref(), Some(market));
+
+        // not due before the interval is over
+        assert!(analytics.on_books(2500, &btc).is_empty());
+        // the first trade left the window
+        let published = analytics.on_books(11_500, &btc);
+        assert_eq!((published[0].vwap.as_deref(), published[0].trades), (Some("103"), 1));
+        let published = analytics.on_books(30_000, &books(&[], &[("101", "1")]));
+        assert_eq!((published[0].vwap.as_ref(), published[0].spread.as_ref()), (None, None));
+        assert_eq!(published[0].imbalance, Some(-1.0));
+    }
+}
@@ -0,0 +1,222 @@
+use std::{collections::BTreeMap, fmt, path::Path};
+
+use crate::{
+    archive::table::{Delta, HOUR_MS, RestingOrder, files, hours, read_file},
+    order_book::types::{Px, Side, Sz},
+    prelude::*,
+    types::{L3Book, L3Order},
+};
+
+#[derive(Debug)]
+pub(crate) enum HistoryError {
+    // no snapshot of the coin at or before the time
+    NotArchived,
+    // the deltas up to the time are still being written
+    InProgress,
+    Unreadable(Error),
+}
+
+impl fmt::Display for HistoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotArchived => write!(f, "No archived snapshot of the book at or before that time"),
+            Self::InProgress => write!(f, "The book isn't archived up to that time yet (files are closed every hour)"),
+            Self::Unreadable(err) => write!(f, "Unable to read the archive: {err}"),
+        }
+    }
+}
+
+impl From<Error> for HistoryError {
+    fn from(err: Error) -> Self {
+        Self::Unreadable(err)
+    }
+}
+
+// an order of the book being rebuilt
+struct Resting {
+    oid: u64,
+    sz: i64,
+    timestamp: u64,
+}
+
+// the orders of a book by side and price, in their queue order at each price
+#[derive(Default)]
+struct Book {
+    levels: [BTreeMap<i64, Vec<Resting>>; 2],
+}
+
+impl Book {
+    fn level(&mut self, side: Side, px: i64) -> &mut Vec<Resting> {
+        self.levels[usize::from(side == Side::Ask)].entry(px).or_default()
+    }
+
+    fn apply(&mut self, delta: &Delta) {
+        let level = self.level(delta.side, delta.px);
+        match delta.event {
+            "add" => level.push(Resting {
+                oid: delta.oid,
+                sz: delta.sz.unwrap_or_default(),
+                timestamp: delta.timestamp.unwrap_or_default(),
+            }),
+            "modify" => {
+                if let Some(order) = level.iter_mut().find(|order| order.oid == delta.oid) {
+                    order.sz = delta.sz.unwrap_or_default();
+                }
+            }
+            _ => level.retain(|order| order.oid != delta.oid),
+        }
+        if level.is_empty() {
+            self.levels[usize::from(delta.side == Side::Ask)].remove(&delta.px);
+        }
+    }
+
+    // bids and asks, best price first
+    fn into_levels(self) -> Result<[Vec<L3Order>; 2]> {
+        let [bids, asks] = self.levels;
+        let orders = |levels: Vec<(i64, Vec<Resting>)>| -> Result<Vec<L3Order>> {
+            let mut orders = Vec::new();
+            for (px, level) in levels {
+                let px = Px::new(u64::try_from(px)?).to_str();
+                for Resting { oid, sz, timestamp } in level {
+                    orders.push(L3Order { oid, px: px.clone(), sz: Sz::new(u64::try_from(sz)?).to_str(), timestamp });
+                }
+            }
+            Ok(orders)
+        };
+        Ok([orders(bids.into_iter().rev().collect())?, orders(asks.into_iter().collect())?])
+    }
+}
+
+// the book of `coin` as it was at block time `time`, rebuilt from the archive's last snapshot of it at or before
+// then and the deltas that followed. Blocks, reading the files
+pub(crate) fn book_at(dir: &Path, coin: &str, time: u64) -> std::result::Result<L3Book, HistoryError> {
+    let hour = time / HOUR_MS;
+    let mut snapshot = Vec::new();
+    for snapshot_hour in
+        hours::<RestingOrder>(dir, coin)?.into_iter().rev().filter(|snapshot_hour| *snapshot_hour <= hour)
+    {
+        let (paths, _) = files::<RestingOrder>(dir, coin, snapshot_hour)?;
+        let mut rows = Vec::new();
+        for path in paths {
+            rows.extend(read_file::<RestingOrder>(&path)?.into_iter().filter(|row| row.time <= time));
+        }
+        // the hour's last snapshot
+        if let Some(last) = rows.iter().map(|row| (row.time, row.height)).max() {
+            snapshot = rows.into_iter().filter(|row| (row.time, row.height) == last).collect();
+            break;
+        }
+    }
+    // an empty book leaves no rows, and is rebuilt from an earlier snapshot then
+    let Some(first) = snapshot.first() else {
+        return Err(HistoryError::NotArchived);
+    };
+    let (mut book_time, mut height, mut seq) = (first.time, first.height, first.seq);
+    let mut book = Book::default();
+    for order in &snapshot {
+        book.level(order.side, order.px).push(Resting { oid: order.oid, sz: order.sz, timestamp: order.timestamp });
+    }
+
+    for delta_hour in book_time / HOUR_MS..=hour {
+        let (paths, in_progress) = files::<Delta>(dir, coin, delta_hour)?;
+        if in_progress {
+            return Err(HistoryError::InProgress);
+        }
+        for path in paths {
+            for delta in read_file::<Delta>(&path)? {
+                if delta.height <= first.height || delta.time > time {
+                    continue;
+                }
+                book.apply(&delta);
+                (book_time, height, seq) = (delta.time, delta.height, delta.seq);
+            }
+        }
+    }
+    Ok(L3Book::Snapshot { coin: coin.to_string(), time: book_time, height, seq, levels: book.into_levels()? })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::table::Partitions;
+
+    const HOUR: u64 = 1_751_428_800_000;
+
+    fn order(time: u64, height: u64, side: Side, oid: u64, px: i64) -> RestingOrder {
+        RestingOrder {
+            time,
+            height,
+            seq: height,
+            coin: "BTC".to_string(),
+            side,
+            oid,
+            px,
+            sz: 100_000_000,
+            timestamp: 1,
+        }
+    }
+
+    fn delta(time: u64, height: u64, event: &'static str, oid: u64, sz: Option<i64>) -> Delta {
+        Delta {
+            time,
+            height,
+            seq: height,
+            coin: "BTC".to_string(),
+            event,
+            oid,
+            side: Side::Bid,
+            px: 10_000_000_000,
+            orig_sz: None,
+            sz,
+            timestamp: sz.map(|_| time),
+            status: None,
+        }
+    }
+
+    fn prices(book: &L3Book) -> [Vec<(u64, String, String)>; 2] {
+        let L3Book::Snapshot { levels, .. } = book else {
+            panic!("not a snapshot");
+        };
+        levels.clone().map(|orders| orders.into_iter().map(|order| (order.oid, order.px, order.sz)).collect())
+    }
+
+    #[test]
+    fn test_book_at() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut snapshots = Partitions::new(dir.path().to_path_buf());
+        snapshots.push(vec![
+            order(HOUR + 10, 10, Side::Bid, 1, 10_000_000_000),
+            order(HOUR + 10, 10, Side::Bid, 2, 9_900_000_000),
+            order(HOUR + 10, 10, Side::Ask, 3, 10_100_000_000),
+        ])?;
+        let mut deltas = Partitions::new(dir.path().to_path_buf());
+        // the block of the snapshot, which it already holds
+        deltas.push(vec![delta(HOUR + 10, 10, "add", 1, Some(100_000_000))])?;
+        deltas.push(vec![
+            delta(HOUR + 20, 11, "add", 4, Some(200_000_000)),
+            Delta { px: 9_900_000_000, ..delta(HOUR + 20, 11, "cancel", 2, None) },
+        ])?;
+        deltas.push(vec![delta(HOUR + HOUR_MS + 30, 12, "modify", 1, Some(50_000_000))])?;
+        snapshots.close_before(None);
+        assert!(matches!(book_at(dir.path(), "BTC", HOUR + 20), Err(HistoryError::InProgress)));
+        deltas.close_before(None);
+
+        assert!(matches!(book_at(dir.path(), "BTC", HOUR + 5), Err(HistoryError::NotArchived)));
+        assert!(matches!(book_at(dir.path(), "ETH", HOUR + 20), Err(HistoryError::NotArchived)));
+        let book = book_at(dir.path(), "BTC", HOUR + 15).map_err(|err| err.to_string())?;
+        assert_eq!(
+            prices(&book),
+            [
+                vec![(1, "100".to_string(), "1".to_string()), (2, "99".to_string(), "1".to_string())],
+                vec![(3, "101".to_string(), "1".to_string())]
+            ]
+        );
+        let book = book_at(dir.path(), "BTC", HOUR + HOUR_MS + 30).map_err(|err| err.to_string())?;
+        assert!(matches!(book, L3Book::Snapshot { height: 12, time, .. } if time == HOUR + HOUR_MS + 30));
+        // behind the order resting at its price
+        assert_eq!(
+            prices(&book)[0],
+            vec![(1, "100".to_string(), "0.5".to_string()), (4, "100".to_string(), "2".to_string())]
+        );
+        Ok(())
+    }
+}
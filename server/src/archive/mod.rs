@@ -0,0 +1,325 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::{
+    select,
+    sync::{
+        Mutex,
+        broadcast::error::RecvError,
+        mpsc::{self, Receiver},
+    },
+    task::{JoinHandle, spawn_blocking},
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    archive::table::{Delta, Partitions, RestingOrder},
+    listeners::order_book::{Broadcast, InternalMessage, OrderBookListener, TimedSnapshots},
+    metrics::METRICS,
+    order_book::types::{Px, Sz},
+    prelude::*,
+    servers::{markets::Markets, shutdown::Shutdown},
+    types::{L3BookUpdates, OrderEvent},
+};
+
+mod history;
+mod s3;
+mod table;
+
+pub(crate) use history::{HistoryError, book_at};
+pub use s3::S3ArchiveStore;
+
+// batches of rows queued for the writer; the archiver falls behind the broadcast beyond them
+const ARCHIVE_BUFFER: usize = 1_000;
+
+/// Where completed archive files go once they are written. S3 is built in as [`S3ArchiveStore`].
+///
+/// Calls block, and are made from a blocking task.
+pub trait ArchiveStore: Debug + Send + Sync {
+    /// Stores the complete file at `path` as `key`, e.g. `deltas/coin=BTC/date=2025-07-02/hour=04/663712836.parquet`.
+    fn put(&self, key: &str, path: &Path) -> Result<()>;
+}
+
+/// Writes snapshots of every book and every change to them to Parquet files, for research and backtesting.
+///
+/// Files are partitioned by coin and hour of block time, as
+/// `<deltas|snapshots>/coin=<coin>/date=<yyyy-mm-dd>/hour=<hh>/<height>.parquet` under `dir`, with the height of
+/// their first row. A file is closed once its hour is over, or on shutdown.
+#[derive(Debug, Clone)]
+pub struct ArchiveConfig {
+    pub dir: PathBuf,
+    /// Closed files are uploaded here and then deleted from `dir`. They stay in `dir` if not set, or if their
+    /// upload fails.
+    pub store: Option<Arc<dyn ArchiveStore>>,
+    /// How often every book is snapshotted, in block time.
+    pub snapshot_interval: Duration,
+}
+
+impl ArchiveConfig {
+    #[must_use]
+    pub const fn new(dir: PathBuf) -> Self {
+        Self { dir, store: None, snapshot_interval: Duration::from_mins(5) }
+    }
+}
+
+// the rows of a block for the writer
+enum Rows {
+    Deltas(u64, Vec<Delta>),
+    Snapshots(u64, Vec<RestingOrder>),
+}
+
+// runs until shutdown. The returned task ends once the last files are closed
+pub(crate) fn start_archiver(
+    config: Option<ArchiveConfig>,
+    internal_message_tx: &Broadcast,
+    markets: Markets,
+    shutdown: Shutdown,
+) -> Result<Option<JoinHandle<()>>> {
+    let Some(config) = config else {
+        return Ok(None);
+    };
+    fs::create_dir_all(&config.dir)
+        .map_err(|err| format!("Unable to create the archive directory {}: {err}", config.dir.display()))?;
+    // every coin is archived
+    let mut rx = internal_message_tx.subscribe_all();
+    let (tx, rows) = mpsc::channel(ARCHIVE_BUFFER);
+    let writer = spawn_blocking({
+        let config = config.clone();
+        move || write_rows(&config, rows)
+    });
+    let interval = u64::try_from(config.snapshot_interval.as_millis()).unwrap_or(u64::MAX);
+    Ok(Some(tokio::spawn(async move {
+        let mut last_snapshot = None::<u64>;
+        loop {
+            let msg = select! {
+                msg = rx.recv() => msg,
+                () = shutdown.cancelled() => break,
+            };
+            let updates = match msg {
+                Ok(msg) => match msg.as_ref() {
+                    InternalMessage::L3BookUpdates { updates, .. } => deltas(updates),
+                    _ => continue,
+                },
+                Err(RecvError::Lagged(n)) => {
+                    METRICS.dropped_messages.inc_by(n);
+                    warn!("Archiver fell behind, {n} messages not archived");
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            let Some((time, deltas)) = updates else {
+                continue;
+            };
+            if tx.send(Rows::Deltas(time, deltas)).await.is_err() {
+                break;
+            }
+            if last_snapshot.is_none_or(|last| time >= last.saturating_add(interval)) {
+                last_snapshot = Some(time);
+                for listener in markets.listeners() {
+                    let Some(snapshot) = snapshot(listener).await else {
+                        continue;
+                    };
+                    if tx.send(Rows::Snapshots(time, snapshot)).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+        drop(tx);
+        if let Err(err) = writer.await {
+            error!("Archive writer failed: {err}");
+        }
+    })))
+}
+
+// the events of a block's l3 updates, with its block time
+fn deltas(updates: &HashMap<String, L3BookUpdates>) -> Option<(u64, Vec<Delta>)> {
+    let time = updates.values().next()?.time;
+    let mut rows = Vec::new();
+    for update in updates.values() {
+        for event in &update.events {
+            match delta(update, event) {
+                Ok(row) => rows.push(row),
+                Err(err) => warn!("Unable to archive an event of {}: {err}", update.coin),
+            }
+        }
+    }
+    Some((time, rows))
+}
+
+fn delta(update: &L3BookUpdates, event: &OrderEvent) -> Result<Delta> {
+    let L3BookUpdates { coin, time, height, seq, .. } = update;
+    let px = |px: &str| Px::parse_from_str(px).and_then(|px| Ok(i64::try_from(px.value())?));
+    let sz = |sz: &str| Sz::parse_from_str(sz).and_then(|sz| Ok(i64::try_from(sz.value())?));
+    let row = |event, oid, side, px| Delta {
+        time: *time,
+        height: *height,
+        seq: *seq,
+        coin: coin.clone(),
+        event,
+        oid,
+        side,
+        px,
+        orig_sz: None,
+        sz: None,
+        timestamp: None,
+        status: None,
+    };
+    Ok(match event {
+        OrderEvent::Add { oid, side, px: add_px, sz: add_sz, timestamp } => {
+            Delta { sz: Some(sz(add_sz)?), timestamp: Some(*timestamp), ..row("add", *oid, *side, px(add_px)?) }
+        }
+        OrderEvent::Modify { oid, side, px: modify_px, orig_sz, sz: modify_sz } => Delta {
+            orig_sz: Some(sz(orig_sz)?),
+            sz: Some(sz(modify_sz)?),
+            ..row("modify", *oid, *side, px(modify_px)?)
+        },
+        OrderEvent::Cancel { oid, side, px: cancel_px, status } => {
+            Delta { status: status.clone(), ..row("cancel", *oid, *side, px(cancel_px)?) }
+        }
+    })
+}
+
+// every resting order of the listener's books
+async fn snapshot(listener: &Mutex<OrderBookListener>) -> Option<Vec<RestingOrder>> {
+    let mut guard = listener.lock().await;
+    let TimedSnapshots { time, height, snapshot } = guard.compute_snapshot()?;
+    let seqs = snapshot.as_ref().keys().map(|coin| (coin.clone(), guard.l4_seq(coin))).collect::<HashMap<_, _>>();
+    drop(guard);
+    let mut rows = Vec::new();
+    for (coin, book) in snapshot.value() {
+        let seq = seqs.get(&coin).copied().unwrap_or_default();
+        for order in book.as_ref().iter().flatten() {
+            let (Ok(px), Ok(sz)) = (i64::try_from(order.limit_px.value()), i64::try_from(order.sz.value())) else {
+                continue;
+            };
+            let coin = coin.value();
+            let (side, oid, timestamp) = (order.side, order.oid, order.timestamp);
+            rows.push(RestingOrder { time, height, seq, coin, side, oid, px, sz, timestamp });
+        }
+    }
+    Some(rows)
+}
+
+// writes the rows as they come, closing the files of every hour that is over
+fn write_rows(config: &ArchiveConfig, mut rows: Receiver<Rows>) {
+    let mut deltas = Partitions::<Delta>::new(config.dir.clone());
+    let mut snapshots = Partitions::<RestingOrder>::new(config.dir.clone());
+    while let Some(rows) = rows.blocking_recv() {
+        let (time, res) = match rows {
+            Rows::Deltas(time, rows) => (time, deltas.push(rows)),
+            Rows::Snapshots(time, rows) => (time, snapshots.push(rows)),
+        };
+        if let Err(err) = res {
+            error!("Unable to archive the block at {time}: {err}");
+        }
+        finish(config, deltas.close_before(Some(time)));
+        finish(config, snapshots.close_before(Some(time)));
+    }
+    finish(config, deltas.close_before(None));
+    finish(config, snapshots.close_before(None));
+}
+
+// uploads the closed files, if they go to a store
+fn finish(config: &ArchiveConfig, files: Vec<(String, PathBuf)>) {
+    for (key, path) in files {
+        let Some(store) = &config.store else {
+            info!("Archived {key}");
+            continue;
+        };
+        match store.put(&key, &path).and_then(|()| Ok(fs::remove_file(&path)?)) {
+            Ok(()) => info!("Archived {key} to {store:?}"),
+            Err(err) => error!("Unable to upload {key} to {store:?}, it stays in the archive directory: {err}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex as StdMutex;
+
+    use super::*;
+    use crate::order_book::types::Side;
+
+    #[derive(Debug, Default)]
+    struct MemoryStore {
+        keys: StdMutex<Vec<String>>,
+    }
+
+    impl ArchiveStore for MemoryStore {
+        fn put(&self, key: &str, path: &Path) -> Result<()> {
+            assert!(path.exists());
+            self.keys.lock().unwrap().push(key.to_string());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_deltas() {
+        let events = vec![
+            OrderEvent::Add { oid: 1, side: Side::Bid, px: "100.5".to_string(), sz: "2".to_string(), timestamp: 3 },
+            OrderEvent::Modify {
+                oid: 1,
+                side: Side::Bid,
+                px: "100.5".to_string(),
+                orig_sz: "2".to_string(),
+                sz: "0.5".to_string(),
+            },
+            OrderEvent::Cancel { oid: 1, side: Side::Bid, px: "100.5".to_string(), status: Some("filled".to_string()) },
+        ];
+        let update = L3BookUpdates { coin: "BTC".to_string(), time: 10, height: 11, seq: 12, events };
+        let (time, rows) = deltas(&HashMap::from([("BTC".to_string(), update)])).unwrap();
+        assert_eq!(time, 10);
+        let fields = rows.iter().map(|row| (row.event, row.px, row.orig_sz, row.sz, row.timestamp)).collect::<Vec<_>>();
+        assert_eq!(
+            fields,
+            vec![
+                ("add", 10_050_000_000, None, Some(200_000_000), Some(3)),
+                ("modify", 10_050_000_000, Some(200_000_000), Some(50_000_000), None),
+                ("cancel", 10_050_000_000, None, None, None),
+            ]
+        );
+        assert_eq!(rows[2].status.as_deref(), Some("filled"));
+        assert!(deltas(&HashMap::new()).is_none());
+    }
+
+    #[test]
+    fn test_write_rows() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let store = Arc::new(MemoryStore::default());
+        let config = ArchiveConfig { store: Some(store.clone()), ..ArchiveConfig::new(dir.path().to_path_buf()) };
+        let hour = 1_751_428_800_000;
+        let order = |time| RestingOrder {
+            time,
+            height: 1,
+            seq: 2,
+            coin: "BTC".to_string(),
+            side: Side::Ask,
+            oid: 3,
+            px: 4,
+            sz: 5,
+            timestamp: 6,
+        };
+        let (tx, rx) = mpsc::channel(10);
+        tx.try_send(Rows::Snapshots(hour, vec![order(hour)]))?;
+        tx.try_send(Rows::Snapshots(hour + 3_600_000, vec![order(hour + 3_600_000)]))?;
+        drop(tx);
+        write_rows(&config, rx);
+        let mut keys = store.keys.lock().unwrap().clone();
+        keys.sort();
+        assert_eq!(
+            keys,
+            vec![
+                "snapshots/coin=BTC/date=2025-07-02/hour=04/1.parquet",
+                "snapshots/coin=BTC/date=2025-07-02/hour=05/1.parquet"
+            ]
+        );
+        // uploaded files are deleted
+        assert!(!dir.path().join(&keys[0]).exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,603 @@
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    fs::File,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use chrono::{DateTime, NaiveDate};
+use parquet::{
+    basic::{Compression, ZstdLevel},
+    column::reader::{ColumnReader, ColumnReaderImpl},
+    data_type::{ByteArray, ByteArrayType, DataType, Int64Type},
+    file::{
+        properties::WriterProperties,
+        reader::{FileReader, SerializedFileReader},
+        writer::{SerializedFileWriter, SerializedRowGroupWriter},
+    },
+    schema::parser::parse_message_type,
+};
+
+use crate::{order_book::types::Side, prelude::*};
+
+// rows buffered per file before they are written out as a row group
+const ROW_GROUP_ROWS: usize = 100_000;
+pub(super) const HOUR_MS: u64 = 60 * 60 * 1000;
+const IN_PROGRESS_EXTENSION: &str = "inprogress";
+
+// a change to an order of a book, as the events of the `l3Book` channel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct Delta {
+    pub time: u64,
+    pub height: u64,
+    pub seq: u64,
+    pub coin: String,
+    // `add`, `modify` or `cancel`
+    pub event: &'static str,
+    pub oid: u64,
+    pub side: Side,
+    // in units of 10^-8, like every price and size
+    pub px: i64,
+    // the size before a modify
+    pub orig_sz: Option<i64>,
+    // the size once added or modified
+    pub sz: Option<i64>,
+    // when an added order entered the book
+    pub timestamp: Option<u64>,
+    // of a cancel, e.g. `filled` or `canceled`
+    pub status: Option<String>,
+}
+
+// an order resting on a book when it was snapshotted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct RestingOrder {
+    pub time: u64,
+    pub height: u64,
+    pub seq: u64,
+    pub coin: String,
+    pub side: Side,
+    pub oid: u64,
+    pub px: i64,
+    pub sz: i64,
+    pub timestamp: u64,
+}
+
+// the rows of one kind of file
+pub(super) trait Row: Sized {
+    // the top directory of its files
+    const KIND: &'static str;
+    const SCHEMA: &'static str;
+
+    fn coin(&self) -> &str;
+    fn time(&self) -> u64;
+    fn height(&self) -> u64;
+    // a column for each of the schema's, in order
+    fn write(rows: &[Self], row_group: &mut SerializedRowGroupWriter<'_, File>) -> Result<()>;
+    fn read(columns: &Columns) -> Result<Vec<Self>>;
+}
+
+impl Row for Delta {
+    const KIND: &'static str = "deltas";
+    const SCHEMA: &'static str = "
+        message delta {
+            required int64 time (TIMESTAMP(MILLIS, true));
+            required int64 height;
+            required int64 seq;
+            required binary coin (STRING);
+            required binary event (STRING);
+            required int64 oid;
+            required binary side (STRING);
+            required int64 px (DECIMAL(18, 8));
+            optional int64 orig_sz (DECIMAL(18, 8));
+            optional int64 sz (DECIMAL(18, 8));
+            optional int64 timestamp (TIMESTAMP(MILLIS, true));
+            optional binary status (STRING);
+        }
+    ";
+
+    fn coin(&self) -> &str {
+        &self.coin
+    }
+
+    fn time(&self) -> u64 {
+        self.time
+    }
+
+    fn height(&self) -> u64 {
+        self.height
+    }
+
+    fn write(rows: &[Self], row_group: &mut SerializedRowGroupWriter<'_, File>) -> Result<()> {
+        int64(row_group, rows.iter().map(|row| Some(to_i64(row.time))))?;
+        int64(row_group, rows.iter().map(|row| Some(to_i64(row.height))))?;
+        int64(row_group, rows.iter().map(|row| Some(to_i64(row.seq))))?;
+        string(row_group, rows.iter().map(|row| Some(row.coin.as_str())))?;
+        string(row_group, rows.iter().map(|row| Some(row.event)))?;
+        int64(row_group, rows.iter().map(|row| Some(to_i64(row.oid))))?;
+        string(row_group, rows.iter().map(|row| Some(side(row.side))))?;
+        int64(row_group, rows.iter().map(|row| Some(row.px)))?;
+        int64(row_group, rows.iter().map(|row| row.orig_sz))?;
+        int64(row_group, rows.iter().map(|row| row.sz))?;
+        int64(row_group, rows.iter().map(|row| row.timestamp.map(to_i64)))?;
+        string(row_group, rows.iter().map(|row| row.status.as_deref()))
+    }
+
+    fn read(columns: &Columns) -> Result<Vec<Self>> {
+        let mut rows = Vec::with_capacity(columns.len);
+        for i in 0..columns.len {
+            let event = match columns.string("event", i)? {
+                "add" => "add",
+                "modify" => "modify",
+                "cancel" => "cancel",
+                event => return Err(format!("unknown event {event}").into()),
+            };
+            rows.push(Self {
+                time: to_u64(columns.int64("time", i)?)?,
+                height: to_u64(columns.int64("height", i)?)?,
+                seq: to_u64(columns.int64("seq", i)?)?,
+                coin: columns.string("coin", i)?.to_string(),
+                event,
+                oid: to_u64(columns.int64("oid", i)?)?,
+                side: parse_side(columns.string("side", i)?)?,
+                px: columns.int64("px", i)?,
+                orig_sz: columns.optional_int64("orig_sz", i)?,
+                sz: columns.optional_int64("sz", i)?,
+                timestamp: columns.optional_int64("timestamp", i)?.map(to_u64).transpose()?,
+                status: columns.optional_string("status", i)?.map(str::to_string),
+            });
+        }
+        Ok(rows)
+    }
+}
+
+impl Row for RestingOrder {
+    const KIND: &'static str = "snapshots";
+    const SCHEMA: &'static str = "
+        message snapshot {
+            required int64 time (TIMESTAMP(MILLIS, true));
+            required int64 height;
+            required int64 seq;
+            required binary coin (STRING);
+            required binary side (STRING);
+            required int64 oid;
+            required int64 px (DECIMAL(18, 8));
+            required int64 sz (DECIMAL(18, 8));
+            required int64 timestamp (TIMESTAMP(MILLIS, true));
+        }
+    ";
+
+    fn coin(&self) -> &str {
+        &self.coin
+    }
+
+    fn time(&self) -> u64 {
+        self.time
+    }
+
+    fn height(&self) -> u64 {
+        self.height
+    }
+
+    fn write(rows: &[Self], row_group: &mut SerializedRowGroupWriter<'_, File>) -> Result<()> {
+        int64(row_group, rows.iter().map(|row| Some(to_i64(row.time))))?;
+        int64(row_group, rows.iter().map(|row| Some(to_i64(row.height))))?;
+        int64(row_group, rows.iter().map(|row| Some(to_i64(row.seq))))?;
+        string(row_group, rows.iter().map(|row| Some(row.coin.as_str())))?;
+        string(row_group, rows.iter().map(|row| Some(side(row.side))))?;
+        int64(row_group, rows.iter().map(|row| Some(to_i64(row.oid))))?;
+        int64(row_group, rows.iter().map(|row| Some(row.px)))?;
+        int64(row_group, rows.iter().map(|row| Some(row.sz)))?;
+        int64(row_group, rows.iter().map(|row| Some(to_i64(row.timestamp))))
+    }
+
+    fn read(columns: &Columns) -> Result<Vec<Self>> {
+        let mut rows = Vec::with_capacity(columns.len);
+        for i in 0..columns.len {
+            rows.push(Self {
+                time: to_u64(columns.int64("time", i)?)?,
+                height: to_u64(columns.int64("height", i)?)?,
+                seq: to_u64(columns.int64("seq", i)?)?,
+                coin: columns.string("coin", i)?.to_string(),
+                side: parse_side(columns.string("side", i)?)?,
+                oid: to_u64(columns.int64("oid", i)?)?,
+                px: columns.int64("px", i)?,
+                sz: columns.int64("sz", i)?,
+                timestamp: to_u64(columns.int64("timestamp", i)?)?,
+            });
+        }
+        Ok(rows)
+    }
+}
+
+// block times, heights and oids are far below i64::MAX
+#[allow(clippy::cast_possible_wrap)]
+const fn to_i64(value: u64) -> i64 {
+    value as i64
+}
+
+fn to_u64(value: i64) -> Result<u64> {
+    Ok(u64::try_from(value).map_err(|_| format!("negative value {value}"))?)
+}
+
+const fn side(side: Side) -> &'static str {
+    match side {
+        Side::Bid => "B",
+        Side::Ask => "A",
+    }
+}
+
+fn parse_side(side: &str) -> Result<Side> {
+    match side {
+        "B" => Ok(Side::Bid),
+        "A" => Ok(Side::Ask),
+        side => Err(format!("unknown side {side}").into()),
+    }
+}
+
+fn int64(row_group: &mut SerializedRowGroupWriter<'_, File>, values: impl Iterator<Item = Option<i64>>) -> Result<()> {
+    column::<Int64Type>(row_group, values)
+}
+
+fn string<S: Into<ByteArray>>(
+    row_group: &mut SerializedRowGroupWriter<'_, File>,
+    values: impl Iterator<Item = Option<S>>,
+) -> Result<()> {
+    column::<ByteArrayType>(row_group, values.map(|value| value.map(Into::into)))
+}
+
+// the next column of the row group; nulls are left out of the values of optional columns
+fn column<T: DataType>(
+    row_group: &mut SerializedRowGroupWriter<'_, File>,
+    values: impl Iterator<Item = Option<T::T>>,
+) -> Result<()> {
+    let mut column = row_group.next_column()?.ok_or("more columns than in the schema")?;
+    let writer = column.typed::<T>();
+    let (mut levels, mut present) = (Vec::new(), Vec::new());
+    for value in values {
+        levels.push(i16::from(value.is_some()));
+        present.extend(value);
+    }
+    let levels = (writer.get_descriptor().max_def_level() > 0).then_some(levels.as_slice());
+    writer.write_batch(&present, levels, None)?;
+    column.close()?;
+    Ok(())
+}
+
+enum Column {
+    Int64(Vec<Option<i64>>),
+    String(Vec<Option<String>>),
+}
+
+// the columns of a row group read back, by name
+pub(super) struct Columns {
+    len: usize,
+    columns: HashMap<String, Column>,
+}
+
+impl Columns {
+    fn optional_int64(&self, name: &str, i: usize) -> Result<Option<i64>> {
+        match self.columns.get(name) {
+            Some(Column::Int64(values)) => Ok(values.get(i).copied().flatten()),
+            _ => Err(format!("no int64 column {name}").into()),
+        }
+    }
+
+    fn int64(&self, name: &str, i: usize) -> Result<i64> {
+        Ok(self.optional_int64(name, i)?.ok_or_else(|| format!("{name} is null"))?)
+    }
+
+    fn optional_string(&self, name: &str, i: usize) -> Result<Option<&str>> {
+        match self.columns.get(name) {
+            Some(Column::String(values)) => Ok(values.get(i).and_then(Option::as_deref)),
+            _ => Err(format!("no string column {name}").into()),
+        }
+    }
+
+    fn string(&self, name: &str, i: usize) -> Result<&str> {
+        Ok(self.optional_string(name, i)?.ok_or_else(|| format!("{name} is null"))?)
+    }
+}
+
+// every row of a complete file, in the order they were written
+pub(super) fn read_file<R: Row>(path: &Path) -> Result<Vec<R>> {
+    let reader = SerializedFileReader::new(File::open(path)?)?;
+    let mut rows = Vec::new();
+    for i in 0..reader.num_row_groups() {
+        let row_group = reader.get_row_group(i)?;
+        let len = usize::try_from(row_group.metadata().num_rows())?;
+        let mut columns = HashMap::new();
+        for (j, descriptor) in row_group.metadata().schema_descr().columns().iter().enumerate() {
+            let max_def_level = descriptor.max_def_level();
+            let column = match row_group.get_column_reader(j)? {
+                ColumnReader::Int64ColumnReader(mut reader) => {
+                    Column::Int64(read_column(&mut reader, max_def_level, len)?)
+                }
+                ColumnReader::ByteArrayColumnReader(mut reader) => Column::String(
+                    read_column(&mut reader, max_def_level, len)?
+                        .into_iter()
+                        .map(|value| value.map(|value| value.as_utf8().map(str::to_string)).transpose())
+                        .collect::<std::result::Result<_, _>>()?,
+                ),
+                _ => return Err(format!("unexpected type of column {}", descriptor.name()).into()),
+            };
+            columns.insert(descriptor.name().to_string(), column);
+        }
+        rows.extend(R::read(&Columns { len, columns })?);
+    }
+    Ok(rows)
+}
+
+// the values of a column of `len` rows, with the nulls of optional columns
+fn read_column<T: DataType>(
+    reader: &mut ColumnReaderImpl<T>,
+    max_def_level: i16,
+    len: usize,
+) -> Result<Vec<Option<T::T>>> {
+    let (mut levels, mut values) = (Vec::new(), Vec::new());
+    let mut records = 0;
+    while records < len {
+        let levels = (max_def_level > 0).then_some(&mut levels);
+        let (read, _, _) = reader.read_records(len - records, levels, None, &mut values)?;
+        if read == 0 {
+            break;
+        }
+        records += read;
+    }
+    if max_def_level == 0 {
+        return Ok(values.into_iter().map(Some).collect());
+    }
+    let mut values = values.into_iter();
+    Ok(levels.iter().map(|level| if *level > 0 { values.next() } else { None }).collect())
+}
+
+// the hours, oldest first, that a coin has files of in the archive
+pub(super) fn hours<R: Row>(dir: &Path, coin: &str) -> Result<Vec<u64>> {
+    let coin_dir = dir.join(R::KIND).join(format!("coin={}", partition_value(coin)));
+    let mut hours = Vec::new();
+    if !coin_dir.exists() {
+        return Ok(hours);
+    }
+    for date in fs::read_dir(coin_dir)? {
+        let date = date?;
+        let name = date.file_name();
+        let Some(day) = name.to_str().and_then(|name| NaiveDate::parse_from_str(name, "date=%Y-%m-%d").ok()) else {
+            continue;
+        };
+        let days = u64::try_from((day - DateTime::UNIX_EPOCH.date_naive()).num_days()).unwrap_or_default();
+        for hour in fs::read_dir(date.path())? {
+            let name = hour?.file_name();
+            if let Some(hour) = name.to_str().and_then(|name| name.strip_prefix("hour=")?.parse::<u64>().ok()) {
+                hours.push(days * 24 + hour);
+            }
+        }
+    }
+    hours.sort_unstable();
+    Ok(hours)
+}
+
+// the complete files of a coin and hour in the order of their first block, and whether one is still being written
+pub(super) fn files<R: Row>(dir: &Path, coin: &str, hour: u64) -> Result<(Vec<PathBuf>, bool)> {
+    let hour_dir = dir.join(hour_dir(R::KIND, coin, hour));
+    if !hour_dir.exists() {
+        return Ok((Vec::new(), false));
+    }
+    let (mut files, mut in_progress) = (Vec::new(), false);
+    for entry in fs::read_dir(hour_dir)? {
+        let path = entry?.path();
+        in_progress |= path.extension().is_some_and(|extension| extension == IN_PROGRESS_EXTENSION);
+        let height = path.extension().filter(|extension| *extension == "parquet").and_then(|_| path.file_stem());
+        if let Some(height) = height.and_then(|height| height.to_str()?.parse::<u64>().ok()) {
+            files.push((height, path));
+        }
+    }
+    files.sort_unstable();
+    Ok((files.into_iter().map(|(_, path)| path).collect(), in_progress))
+}
+
+// the hour of block time and coin of a file
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct PartitionKey {
+    coin: String,
+    hour: u64,
+}
+
+// a file being written. It is written to `<path>.inprogress` and renamed once closed, so that only complete files
+// have the `.parquet` extension
+struct Partition<R> {
+    key: String,
+    path: PathBuf,
+    writer: SerializedFileWriter<File>,
+    rows: Vec<R>,
+}
+
+impl<R: Row> Partition<R> {
+    fn create(dir: &Path, coin: &str, hour: u64, height: u64) -> Result<Self> {
+        let key = file_key(R::KIND, coin, hour, height);
+        let path = dir.join(&key);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = File::create(path.with_extension(IN_PROGRESS_EXTENSION))?;
+        let schema = Arc::new(parse_message_type(R::SCHEMA)?);
+        let properties = WriterProperties::builder().set_compression(Compression::ZSTD(ZstdLevel::default())).build();
+        let writer = SerializedFileWriter::new(file, schema, Arc::new(properties))?;
+        Ok(Self { key, path, writer, rows: Vec::new() })
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.rows.is_empty() {
+            return Ok(());
+        }
+        let mut row_group = self.writer.next_row_group()?;
+        R::write(&self.rows, &mut row_group)?;
+        row_group.close()?;
+        self.rows.clear();
+        Ok(())
+    }
+
+    // the key of the complete file
+    fn close(mut self) -> Result<(String, PathBuf)> {
+        self.flush()?;
+        self.writer.close()?;
+        fs::rename(self.path.with_extension(IN_PROGRESS_EXTENSION), &self.path)?;
+        Ok((self.key, self.path))
+    }
+}
+
+// the files of one kind being written, a file per coin and hour
+pub(super) struct Partitions<R> {
+    dir: PathBuf,
+    open: HashMap<PartitionKey, Partition<R>>,
+}
+
+impl<R: Row> Partitions<R> {
+    pub(super) fn new(dir: PathBuf) -> Self {
+        Self { dir, open: HashMap::new() }
+    }
+
+    pub(super) fn push(&mut self, rows: Vec<R>) -> Result<()> {
+        for row in rows {
+            let key = PartitionKey { coin: row.coin().to_string(), hour: row.time() / HOUR_MS };
+            let partition = match self.open.entry(key) {
+                std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+                std::collections::hash_map::Entry::Vacant(entry) => {
+                    let PartitionKey { coin, hour } = entry.key();
+                    let partition = Partition::create(&self.dir, coin, *hour, row.height())?;
+                    entry.insert(partition)
+                }
+            };
+            partition.rows.push(row);
+            if partition.rows.len() >= ROW_GROUP_ROWS {
+                partition.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    // closes the files of the hours before the one `time` is in, or every file; the keys and paths of the
+    // complete files. A file that can't be closed is left `.inprogress`
+    pub(super) fn close_before(&mut self, time: Option<u64>) -> Vec<(String, PathBuf)> {
+        let done = self
+            .open
+            .keys()
+            .filter(|key| time.is_none_or(|time| key.hour < time / HOUR_MS))
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut closed = Vec::new();
+        for key in done {
+            let Some(partition) = self.open.remove(&key) else {
+                continue;
+            };
+            match partition.close() {
+                Ok(file) => closed.push(file),
+                Err(err) => {
+                    tracing::error!("Unable to close the archive file of {} at hour {}: {err}", key.coin, key.hour);
+                }
+            }
+        }
+        closed
+    }
+}
+
+// `<kind>/coin=<coin>/date=<yyyy-mm-dd>/hour=<hh>/<height>.parquet`, with the height of its first row
+fn file_key(kind: &str, coin: &str, hour: u64, height: u64) -> String {
+    format!("{}/{height}.parquet", hour_dir(kind, coin, hour))
+}
+
+fn hour_dir(kind: &str, coin: &str, hour: u64) -> String {
+    let start = i64::try_from(hour * HOUR_MS).ok().and_then(DateTime::from_timestamp_millis).unwrap_or_default();
+    format!("{kind}/coin={}/{}", partition_value(coin), start.format("date=%Y-%m-%d/hour=%H"))
+}
+
+// coins are percent-encoded where they could be read as part of the path, e.g. `PURR/USDC`
+fn partition_value(coin: &str) -> String {
+    let mut value = String::with_capacity(coin.len());
+    for byte in coin.bytes() {
+        if byte.is_ascii_alphanumeric() || b"@:_.-".contains(&byte) {
+            value.push(char::from(byte));
+        } else {
+            // writing to a string can't fail
+            let _unused = write!(value, "%{byte:02X}");
+        }
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use parquet::{
+        file::reader::{FileReader, SerializedFileReader},
+        record::Field,
+    };
+
+    use super::*;
+
+    fn delta(coin: &str, time: u64, oid: u64) -> Delta {
+        Delta {
+            time,
+            height: time / 100,
+            seq: 1,
+            coin: coin.to_string(),
+            event: "cancel",
+            oid,
+            side: Side::Ask,
+            px: 10_629_600_000_000,
+            orig_sz: None,
+            sz: None,
+            timestamp: None,
+            status: Some("filled".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_file_key() {
+        assert_eq!(
+            file_key("deltas", "BTC", 1_751_430_933_565 / HOUR_MS, 7),
+            "deltas/coin=BTC/date=2025-07-02/hour=04/7.parquet"
+        );
+        assert_eq!(partition_value("testnet:PURR/USDC"), "testnet:PURR%2FUSDC");
+        assert_eq!(partition_value("@107"), "@107");
+    }
+
+    #[test]
+    fn test_partitions() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut partitions = Partitions::new(dir.path().to_path_buf());
+        let hour = 1_751_428_800_000;
+        partitions.push(vec![delta("BTC", hour + 1, 1), delta("ETH", hour + 2, 2), delta("BTC", hour + 3, 3)])?;
+        // still being written
+        assert!(partitions.close_before(Some(hour + 4)).is_empty());
+        partitions.push(vec![delta("BTC", hour + HOUR_MS, 4)])?;
+        let mut closed = partitions.close_before(Some(hour + HOUR_MS));
+        closed.sort();
+        let keys = closed.iter().map(|(key, _)| key.as_str()).collect::<Vec<_>>();
+        let btc = format!("deltas/coin=BTC/date=2025-07-02/hour=04/{}.parquet", (hour + 1) / 100);
+        let eth = format!("deltas/coin=ETH/date=2025-07-02/hour=04/{}.parquet", (hour + 2) / 100);
+        assert_eq!(keys, vec![btc.as_str(), eth.as_str()]);
+
+        let reader = SerializedFileReader::new(File::open(dir.path().join(&btc))?)?;
+        let rows = reader.get_row_iter(None)?.collect::<std::result::Result<Vec<_>, _>>()?;
+        assert_eq!(rows.len(), 2);
+        let columns = rows[0].get_column_iter().map(|(name, field)| (name.as_str(), field)).collect::<HashMap<_, _>>();
+        assert_eq!(columns["oid"], &Field::Long(1));
+        assert_eq!(columns["side"], &Field::Str("A".to_string()));
+        assert!(matches!(columns["px"], Field::Decimal(_)));
+        assert_eq!(columns["sz"], &Field::Null);
+        assert_eq!(columns["status"], &Field::Str("filled".to_string()));
+        assert_eq!(
+            read_file::<Delta>(&dir.path().join(&btc))?,
+            vec![delta("BTC", hour + 1, 1), delta("BTC", hour + 3, 3)]
+        );
+
+        // the hour still open
+        let open = dir.path().join(file_key("deltas", "BTC", hour / HOUR_MS + 1, (hour + HOUR_MS) / 100));
+        assert!(!open.exists() && open.with_extension(IN_PROGRESS_EXTENSION).exists());
+        assert_eq!(files::<Delta>(dir.path(), "BTC", hour / HOUR_MS + 1)?, (Vec::new(), true));
+        assert_eq!(partitions.close_before(None).len(), 1);
+        assert!(open.exists());
+        assert_eq!(hours::<Delta>(dir.path(), "BTC")?, vec![hour / HOUR_MS, hour / HOUR_MS + 1]);
+        assert_eq!(files::<Delta>(dir.path(), "BTC", hour / HOUR_MS + 1)?, (vec![open], false));
+        Ok(())
+    }
+}
@@ -0,0 +1,500 @@
+//! Entry points for the benchmarks in `benches/`: the work the server does on every block, from applying the node's
+//! events to the books to sending the resulting messages to every subscriber.
+
+use std::{collections::HashMap, sync::Arc};
+
+use alloy::primitives::Address;
+use axum::serve::{Listener, ListenerExt};
+use bytes::Bytes;
+use futures_util::{FutureExt, future::try_join_all};
+use serde::de::DeserializeOwned;
+use serde_json::{Value, json};
+use tokio::{
+    io::{AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    runtime::Runtime,
+    sync::Mutex,
+};
+use tracing::Span;
+
+use crate::{
+    latency::Stamps,
+    listeners::order_book::{InternalMessage, L2Snapshots, OrderBookListener, TimedSnapshots, state::OrderBookState},
+    metrics::MeteredListener,
+    order_book::{Coin, Oid, OrderBook, Px, Side, Sz, multi_book::Snapshots},
+    prelude::*,
+    servers::{
+        encoding::{Encoding, Numbers, Version},
+        markets::Markets,
+        outbound::SharedResponses,
+        protocol::Versioned,
+        replay::Replays,
+        send_queue::{BackpressurePolicy, Outgoing, SendQueue},
+        shared_compression::deflate,
+        socket::SocketOptions,
+        websocket_server::{Universe, send_internal_message},
+    },
+    types::{
+        L2Book, L4Book, L4BookUpdates, L4Order,
+        inner::InnerL4Order,
+        node_data::{Batch, NodeDataOrderDiff, NodeDataOrderStatus},
+        subscription::{DEFAULT_LEVELS, ServerResponse, Subscription, SubscriptionManager},
+    },
+};
+
+const TIME: &str = "2025-06-24T02:56:36.172847427";
+const USER: &str = "0x0000000000000000000000000000000000000001";
+// price levels per side, around a mid price of 100000
+const LEVELS: u64 = 500;
+const MID: u64 = 100_000;
+// a multiple of the orders over both sides' levels
+const OIDS_PER_COIN: u64 = 1_000_000_000;
+
+type Events = (Batch<NodeDataOrderStatus>, Batch<NodeDataOrderDiff>);
+
+/// Books of `coins` coins with `orders` resting orders each, and the events of the next block: new orders, size
+/// updates and removals, `block_size` of them per coin.
+pub struct Fixture {
+    state: OrderBookState,
+    coins: Vec<String>,
+    block: Events,
+    // the state once the block is applied, and the l4 updates it sent
+    next: OrderBookState,
+    updates: HashMap<String, L4BookUpdates>,
+    // by `Message`
+    messages: Vec<ServerResponse>,
+}
+
+/// Messages of the first coin, as sent to its subscribers.
+#[derive(Debug, Clone, Copy)]
+pub enum Message {
+    /// Every order of the book, the first message of an l4 subscription.
+    L4Snapshot,
+    /// The l4 updates of a block.
+    L4Updates,
+    /// The top levels of the l2 book.
+    L2Book,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Format {
+    Json,
+    MessagePack,
+}
+
+impl Fixture {
+    pub fn new(coins: usize, orders: u64, block_size: u64) -> Result<Self> {
+        let coins = (0..coins).map(|coin| format!("COIN{coin}")).collect::<Vec<_>>();
+        let mut state = OrderBookState::from_snapshot(Snapshots::new(HashMap::new()), 0, 0, true, true);
+        // oids are unique across coins, and the `n`th order of every coin has the same price and size
+        let oid = |coin: usize, n: u64| coin as u64 * OIDS_PER_COIN + n;
+        let resting = coins
+            .iter()
+            .enumerate()
+            .flat_map(|(i, coin)| (0..orders).map(move |n| Event::New(coin, oid(i, n))))
+            .collect();
+        let resting = block(1, resting)?;
+        advance(&mut state, resting)?;
+        // new orders after the resting ones, and updates and removals of the resting ones, nearest to the top of
+        // the book first
+        let events = coins.iter().enumerate().flat_map(|(i, coin)| {
+            (0..block_size).map(move |n| match n % 4 {
+                0 | 1 => Event::New(coin, oid(i, orders + n)),
+                2 => Event::Update(coin, oid(i, n % orders)),
+                _ => Event::Remove(coin, oid(i, n % orders)),
+            })
+        });
+        let block = block(2, events.collect())?;
+        let mut next = state.clone();
+        let updates = advance(&mut next, block.clone())?;
+        let messages = [Message::L4Snapshot, Message::L4Updates, Message::L2Book]
+            .into_iter()
+            .map(|msg| message(&next, &updates, &Coin::new(&coins[0]), msg))
+            .collect::<Result<_>>()?;
+        Ok(Self { state, coins, block, next, updates, messages })
+    }
+
+    /// A copy of the books and the block to apply to them, see [`Block::apply`].
+    #[must_use]
+    pub fn block(&self) -> Block {
+        Block { state: self.state.clone(), events: Some(self.block.clone()) }
+    }
+
+    pub fn encode(&self, msg: Message, format: Format) -> Result<Bytes> {
+        let msg = &self.messages[msg as usize];
+        let encoding = match format {
+            Format::Json => Encoding::Json,
+            Format::MessagePack => Encoding::MessagePack,
+        };
+        Ok(encoding.encode(&Versioned::new(msg, Version::V1))?.payload)
+    }
+}
+
+// the message of a coin built from the state once the block is applied
+fn message(
+    state: &OrderBookState,
+    updates: &HashMap<String, L4BookUpdates>,
+    coin: &Coin,
+    msg: Message,
+) -> Result<ServerResponse> {
+    Ok(match msg {
+        Message::L4Snapshot => {
+            let TimedSnapshots { time, height, snapshot } = state.compute_snapshot();
+            let levels = snapshot
+                .value()
+                .remove(coin)
+                .map(|snapshot| snapshot.as_ref().clone().map(|orders| orders.into_iter().map(L4Order::from).collect()))
+                .ok_or("coin not found")?;
+            let seq = state.l4_seq(coin);
+            ServerResponse::L4Book(L4Book::Snapshot {
+                coin: coin.value(),
+                time,
+                height,
+                seq,
+                levels,
+                checksum: 0,
+                checkpoint: None,
+            })
+        }
+        Message::L4Updates => {
+            ServerResponse::L4Book(L4Book::Updates(updates.get(&coin.value()).ok_or("coin not found")?.clone()))
+        }
+        Message::L2Book => {
+            let (time, seq, levels) = state.l2_snapshot(coin, DEFAULT_LEVELS, None, None).ok_or("coin not found")?;
+            ServerResponse::L2Book(L2Book::from_l2_snapshot(coin.value(), levels.export_inner_snapshot(), time, seq))
+        }
+    })
+}
+
+/// Books and a block of events for them, consumed by applying the block.
+pub struct Block {
+    state: OrderBookState,
+    events: Option<Events>,
+}
+
+impl Block {
+    /// Applies the block as the listener does, and returns the number of coins it updated. The books are kept, so
+    /// that dropping them isn't part of the measurement.
+    pub fn apply(&mut self) -> Result<usize> {
+        let events = self.events.take().ok_or("block already applied")?;
+        Ok(advance(&mut self.state, events)?.len())
+    }
+}
+
+/// A book of `resting` orders, and `churn` orders that each open a price level of their own and close it again, as
+/// in a market whose quotes move away from the top of the book with every block.
+pub struct Churn {
+    book: OrderBook<InnerL4Order>,
+    orders: Vec<InnerL4Order>,
+}
+
+impl Churn {
+    #[must_use]
+    pub fn new(resting: u64, churn: u64) -> Self {
+        let mut book = OrderBook::new();
+        for oid in 0..resting {
+            let (side, px, sz) = order(oid);
+            book.add_order(inner_order(oid, side, px, sz));
+        }
+        // beyond the resting levels, so that they never match
+        let orders = (0..churn)
+            .map(|n| {
+                let oid = resting + n;
+                let level = LEVELS + 1 + n / 2;
+                let (side, px) = if n.is_multiple_of(2) { ("B", MID - level) } else { ("A", MID + level) };
+                inner_order(oid, side, px, 1)
+            })
+            .collect();
+        Self { book, orders }
+    }
+
+    /// Adds the churning orders, resizes and cancels them, which leaves the book as it was. Returns the number of
+    /// orders.
+    pub fn run(&mut self) -> usize {
+        for order in &self.orders {
+            self.book.add_order(order.clone());
+        }
+        for order in &self.orders {
+            self.book.modify_sz(Oid::new(order.oid), Sz::new(2));
+        }
+        for order in &self.orders {
+            self.book.cancel_order(Oid::new(order.oid));
+        }
+        self.orders.len()
+    }
+}
+
+// without strings, so that copying it into the book doesn't allocate
+fn inner_order(oid: u64, side: &str, px: u64, sz: u64) -> InnerL4Order {
+    InnerL4Order {
+        user: Address::ZERO,
+        coin: Coin::new(""),
+        side: if side == "B" { Side::Bid } else { Side::Ask },
+        limit_px: Px::new(px),
+        sz: Sz::new(sz),
+        oid,
+        timestamp: 1,
+        trigger_condition: String::new(),
+        is_trigger: false,
+        trigger_px: String::new(),
+        is_position_tpsl: false,
+        reduce_only: false,
+        order_type: String::new(),
+        tif: None,
+        cloid: None,
+    }
+}
+
+/// Deflates a payload as sent to connections with `permessage-deflate`.
+pub fn compress(level: u32, payload: &[u8]) -> Result<Bytes> {
+    Ok(deflate(level, payload)?)
+}
+
+// the queue and subscriptions of one connection
+struct Subscriber {
+    queue: SendQueue,
+    manager: SubscriptionManager,
+    replays: Replays,
+    universe: Universe,
+}
+
+/// Connections subscribed to the l2 and l4 book of one of the coins of a [`Fixture`] each.
+pub struct Fanout {
+    subscribers: Vec<Subscriber>,
+    // the l2 books before and after the block, sent in turn so that every one of them is a change
+    snapshots: [(u64, u64, L2Snapshots); 2],
+    updates: HashMap<String, L4BookUpdates>,
+    next: usize,
+    compression_level: Option<u32>,
+}
+
+impl Fanout {
+    /// Compresses the messages at `compression_level` if given, once for all the connections that send them.
+    pub fn new(fixture: &Fixture, subscribers: usize, compression_level: Option<u32>) -> Result<Self> {
+        let snapshot = |state: &OrderBookState| state.clone().l2_snapshots(false).ok_or("no l2 snapshots");
+        let snapshots = [snapshot(&fixture.state)?, snapshot(&fixture.next)?];
+        let markets = Markets::new(Arc::new(Mutex::new(OrderBookListener::new(None, true))));
+        let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+        let subscribers = (0..subscribers)
+            .map(|i| {
+                let coin = fixture.coins[i % fixture.coins.len()].clone();
+                let mut manager = SubscriptionManager::default();
+                manager.subscribe(Subscription::L2Book {
+                    coin: coin.clone(),
+                    n_sig_figs: None,
+                    n_levels: None,
+                    mantissa: None,
+                    conflate_ms: None,
+                    tick_size: None,
+                    fields: None,
+                    delta: None,
+                });
+                manager.subscribe(Subscription::L4Book { coin, conflate_ms: None });
+                Subscriber {
+                    queue: SendQueue::new(BackpressurePolicy::Disconnect, 256),
+                    manager,
+                    replays: Replays::new(None),
+                    universe: runtime.block_on(Universe::new(markets.clone(), true, None)),
+                }
+            })
+            .collect();
+        Ok(Self { subscribers, snapshots, updates: fixture.updates.clone(), next: 0, compression_level })
+    }
+
+    /// Sends the messages of a block to every connection and serializes them as the connections' writers do.
+    /// Returns the bytes written.
+    pub fn broadcast(&mut self) -> Result<usize> {
+        let stamps = Stamps { node_time: 0, ingest_time: 0 };
+        let (time, seq, l2_snapshots) = self.snapshots[self.next].clone();
+        self.next = 1 - self.next;
+        // new messages of the listener, which nothing was built from yet
+        let msgs = [
+            InternalMessage::Snapshot {
+                l2_snapshots,
+                time,
+                seq,
+                trace: Span::none(),
+                stamps,
+                shared: SharedResponses::default(),
+            },
+            InternalMessage::L4BookUpdates {
+                updates: self.updates.clone(),
+                trace: Span::none(),
+                stamps,
+                shared: SharedResponses::default(),
+            },
+        ];
+        let mut bytes = 0;
+        for Subscriber { queue, manager, replays, universe } in &mut self.subscribers {
+            for msg in &msgs {
+                send_internal_message(queue, manager, replays, universe, msg);
+            }
+            while let Some(Some(Outgoing::Message(msg, _))) = queue.next().now_or_never() {
+                bytes += match self.compression_level {
+                    Some(level) => msg.compressed(Encoding::Json, Version::V1, level)?.len(),
+                    None => msg.payload(Encoding::Json, Version::V1, Numbers::String)?.len(),
+                };
+            }
+        }
+        Ok(bytes)
+    }
+}
+
+/// The sockets of the websocket port.
+#[derive(Debug, Clone, Copy)]
+pub enum Stack {
+    /// Tokio's sockets, driven by epoll.
+    Tokio,
+    /// The `io_uring` listener and its ring thread.
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    IoUring,
+}
+
+type ServerStream = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Connections accepted by the websocket port's listener over loopback, and a message to write to all of them.
+///
+/// Every connection needs two file descriptors, see `ulimit -n`.
+pub struct Transport {
+    runtime: Runtime,
+    servers: Vec<ServerStream>,
+    clients: Vec<TcpStream>,
+    payload: Bytes,
+    // the client side of a connection, read into
+    received: Vec<u8>,
+}
+
+impl Transport {
+    pub fn new(fixture: &Fixture, stack: Stack, connections: usize) -> Result<Self> {
+        let payload = fixture.encode(Message::L2Book, Format::Json)?;
+        let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build()?;
+        let (servers, clients) = runtime.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            match stack {
+                Stack::Tokio => {
+                    let listener = listener.tap_io(|stream| SocketOptions::new().apply(&*stream));
+                    connect(MeteredListener(listener), connections).await
+                }
+                #[cfg(all(feature = "io-uring", target_os = "linux"))]
+                Stack::IoUring => {
+                    connect(
+                        MeteredListener(crate::servers::uring::UringListener::new(listener, SocketOptions::new())?),
+                        connections,
+                    )
+                    .await
+                }
+            }
+        })?;
+        Ok(Self { runtime, servers, clients, received: vec![0; payload.len()], payload })
+    }
+
+    /// Writes the message to every connection and flushes them all at once, then reads it on the client side of
+    /// every connection. Returns the bytes written.
+    pub fn broadcast(&mut self) -> Result<usize> {
+        let Self { runtime, servers, clients, payload, received } = self;
+        runtime.block_on(async {
+            try_join_all(servers.iter_mut().map(async |server| {
+                server.write_all(payload).await?;
+                server.flush().await
+            }))
+            .await?;
+            for client in clients.iter_mut() {
+                client.read_exact(received).await?;
+            }
+            Ok(payload.len() * servers.len())
+        })
+    }
+}
+
+async fn connect<L>(mut listener: L, connections: usize) -> Result<(Vec<ServerStream>, Vec<TcpStream>)>
+where
+    L: Listener<Addr = std::net::SocketAddr>,
+    L::Io: AsyncWrite + Send + Unpin,
+{
+    let address = listener.local_addr()?;
+    let accept = tokio::spawn(async move {
+        let mut servers: Vec<ServerStream> = Vec::with_capacity(connections);
+        while servers.len() < connections {
+            servers.push(Box::new(listener.accept().await.0));
+        }
+        servers
+    });
+    let mut clients = Vec::with_capacity(connections);
+    for _ in 0..connections {
+        clients.push(TcpStream::connect(address).await?);
+    }
+    Ok((accept.await?, clients))
+}
+
+enum Event<'a> {
+    New(&'a str, u64),
+    Update(&'a str, u64),
+    Remove(&'a str, u64),
+}
+
+// orders alternate between the sides, over `LEVELS` prices of each
+const fn order(oid: u64) -> (&'static str, u64, u64) {
+    let level = (oid / 2) % LEVELS + 1;
+    let (side, px) = if oid.is_multiple_of(2) { ("B", MID - level) } else { ("A", MID + level) };
+    (side, px, 1 + oid % 7)
+}
+
+fn block(height: u64, events: Vec<Event<'_>>) -> Result<Events> {
+    let (mut statuses, mut diffs) = (Vec::new(), Vec::new());
+    for event in events {
+        match event {
+            Event::New(coin, oid) => {
+                let (side, px, sz) = order(oid);
+                statuses.push(json!({
+                    "time": TIME,
+                    "user": USER,
+                    "status": "open",
+                    "order": {
+                        "user": null,
+                        "coin": coin,
+                        "side": side,
+                        "limitPx": px.to_string(),
+                        "sz": sz.to_string(),
+                        "oid": oid,
+                        "timestamp": 1,
+                        "triggerCondition": "N/A",
+                        "isTrigger": false,
+                        "triggerPx": "0.0",
+                        "isPositionTpsl": false,
+                        "reduceOnly": false,
+                        "orderType": "Limit",
+                        "tif": "Gtc",
+                        "cloid": null,
+                    },
+                }));
+                diffs.push(diff(coin, oid, json!({"new": {"sz": sz.to_string()}})));
+            }
+            Event::Update(coin, oid) => {
+                let (_, _, sz) = order(oid);
+                diffs.push(diff(
+                    coin,
+                    oid,
+                    json!({"update": {"origSz": sz.to_string(), "newSz": (sz + 1).to_string()}}),
+                ));
+            }
+            Event::Remove(coin, oid) => diffs.push(diff(coin, oid, json!("remove"))),
+        }
+    }
+    Ok((batch(height, statuses)?, batch(height, diffs)?))
+}
+
+fn diff(coin: &str, oid: u64, raw_book_diff: Value) -> Value {
+    let (_, px, _) = order(oid);
+    json!({"user": USER, "oid": oid, "px": px.to_string(), "coin": coin, "raw_book_diff": raw_book_diff})
+}
+
+fn batch<E: DeserializeOwned>(height: u64, events: Vec<Value>) -> Result<Batch<E>> {
+    let batch = json!({"local_time": TIME, "block_time": TIME, "block_number": height, "events": events});
+    Ok(serde_json::from_value(batch)?)
+}
+
+// like the listener: the books first, then the updates with the checksums of the new books
+fn advance(state: &mut OrderBookState, (statuses, diffs): Events) -> Result<HashMap<String, L4BookUpdates>> {
+    state.apply_updates(statuses.clone(), diffs.clone())?;
+    Ok(state.book_updates(statuses, diffs))
+}
@@ -0,0 +1,275 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    str::FromStr,
+};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+
+use crate::{
+    order_book::{Px, Side, Sz},
+    prelude::*,
+    types::node_data::{Batch, NodeDataFill},
+};
+
+/// Which OHLCV candles to build from trades, and how many of each to keep for the history endpoint.
+#[derive(Debug, Clone)]
+pub struct CandleConfig {
+    /// No candles are built when empty.
+    pub intervals: Vec<CandleInterval>,
+    /// Candles kept per market and interval, including the one still open.
+    pub history: usize,
+}
+
+impl Default for CandleConfig {
+    fn default() -> Self {
+        Self { intervals: ["1s", "1m", "5m"].iter().filter_map(|s| s.parse().ok()).collect(), history: 1000 }
+    }
+}
+
+/// Length of a candle, written like `1s`, `1m`, `5m`, `1h` or `1d`. Candles start at multiples of it since the epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CandleInterval {
+    millis: u64,
+}
+
+impl CandleInterval {
+    // start of the candle `time` falls into
+    const fn open_time(self, time: u64) -> u64 {
+        time - time % self.millis
+    }
+}
+
+impl FromStr for CandleInterval {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || format!("invalid candle interval {s:?}, expected e.g. 1s, 1m, 5m, 1h or 1d");
+        let split = s.find(|c: char| !c.is_ascii_digit()).ok_or_else(invalid)?;
+        let (count, unit) = s.split_at(split);
+        let count = count.parse::<u64>().map_err(|_| invalid())?;
+        let unit = match unit {
+            "s" => 1000,
+            "m" => 60_000,
+            "h" => 3_600_000,
+            "d" => 86_400_000,
+            _ => return Err(invalid()),
+        };
+        if count == 0 {
+            return Err(invalid());
+        }
+        Ok(Self { millis: count * unit })
+    }
+}
+
+impl fmt::Display for CandleInterval {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (unit, millis) = [("d", 86_400_000), ("h", 3_600_000), ("m", 60_000), ("s", 1000)]
+            .into_iter()
+            .find(|(_, millis)| self.millis.is_multiple_of(*millis))
+            .unwrap_or(("s", 1000));
+        write!(f, "{}{unit}", self.millis / millis)
+    }
+}
+
+impl Serialize for CandleInterval {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for CandleInterval {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(de::Error::custom)
+    }
+}
+
+// same format as Hyperliquid's candle channel. Times are in milliseconds, `T` being the last one in the candle
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Candle {
+    pub t: u64,
+    #[serde(rename = "T")]
+    pub close_time: u64,
+    pub s: String,
+    pub i: CandleInterval,
+    pub o: String,
+    pub c: String,
+    pub h: String,
+    pub l: String,
+    // volume in the base asset
+    pub v: String,
+    // number of trades
+    pub n: u64,
+}
+
+#[derive(Clone, Copy)]
+struct Bar {
+    open_time: u64,
+    open: Px,
+    high: Px,
+    low: Px,
+    close: Px,
+    volume: Sz,
+    trades: u64,
+}
+
+impl Bar {
+    const fn new(open_time: u64, px: Px, sz: Sz) -> Self {
+        Self { open_time, open: px, high: px, low: px, close: px, volume: sz, trades: 1 }
+    }
+
+    fn add(&mut self, px: Px, sz: Sz) {
+        self.high = self.high.max(px);
+        self.low = self.low.min(px);
+        self.close = px;
+        self.volume = self.volume + sz;
+        self.trades += 1;
+    }
+
+    fn candle(&self, coin: &str, interval: CandleInterval) -> Candle {
+        Candle {
+            t: self.open_time,
+            close_time: self.open_time + interval.millis - 1,
+            s: coin.to_string(),
+            i: interval,
+            o: self.open.to_str(),
+            c: self.close.to_str(),
+            h: self.high.to_str(),
+            l: self.low.to_str(),
+            v: self.volume.to_str(),
+            n: self.trades,
+        }
+    }
+}
+
+// candles of every market that traded, built from the fills as they arrive. Intervals without trades have no candle
+pub(crate) struct Candles {
+    config: CandleConfig,
+    // oldest first, the last one may still be open
+    bars: HashMap<(String, CandleInterval), VecDeque<Bar>>,
+}
+
+impl Candles {
+    pub(crate) fn new(config: CandleConfig) -> Self {
+        Self { config, bars: HashMap::new() }
+    }
+
+    pub(crate) const fn config(&self) -> &CandleConfig {
+        &self.config
+    }
+
+    pub(crate) fn has_interval(&self, interval: CandleInterval) -> bool {
+        self.config.intervals.contains(&interval)
+    }
+
+    // adds the trades of a batch, returning the candles they changed
+    pub(crate) fn on_fills(&mut self, batch: &Batch<NodeDataFill>) -> Vec<Candle> {
+        let mut changed = Vec::new();
+        // every trade has a fill for each side
+        for NodeDataFill(_, fill) in batch.events_ref().iter().filter(|fill| fill.1.side == Side::Bid) {
+            let (Ok(px), Ok(sz)) = (Px::parse_from_str(&fill.px), Sz::parse_from_str(&fill.sz)) else {
+                continue;
+            };
+            for &interval in &self.config.intervals {
+                let key = (fill.coin.clone(), interval);
+                let bars = self.bars.entry(key.clone()).or_default();
+                let open_time = interval.open_time(fill.time);
+                match bars.back_mut() {
+                    Some(bar) if bar.open_time == open_time => bar.add(px, sz),
+                    // trades arrive in order, so older ones can only be late parts of the open candle
+                    Some(bar) if bar.open_time > open_time => continue,
+                    _ => {
+                        if bars.len() >= self.config.history {
+                            bars.pop_front();
+                        }
+                        bars.push_back(Bar::new(open_time, px, sz));
+                    }
+                }
+                if !changed.contains(&key) {
+                    changed.push(key);
+                }
+            }
+        }
+        changed.into_iter().filter_map(|(coin, interval)| self.latest(&coin, interval)).collect()
+    }
+
+    pub(crate) fn latest(&self, coin: &str, interval: CandleInterval) -> Option<Candle> {
+        let bars = self.bars.get(&(coin.to_string(), interval))?;
+        bars.back().map(|bar| bar.candle(coin, interval))
+    }
+
+    // the last `limit` candles that open within `start..=end`, oldest first
+    pub(crate) fn history(
+        &self,
+        coin: &str,
+        interval: CandleInterval,
+        start: Option<u64>,
+        end: Option<u64>,
+        limit: Option<usize>,
+    ) -> Vec<Candle> {
+        let Some(bars) = self.bars.get(&(coin.to_string(), interval)) else {
+            return Vec::new();
+        };
+        let bars = bars
+            .iter()
+            .filter(|bar| {
+                start.is_none_or(|start| bar.open_time >= start) && end.is_none_or(|end| bar.open_time <= end)
+            })
+            .collect::<Vec<_>>();
+        let skip = limit.map_or(0, |limit| bars.len().saturating_sub(limit));
+        bars[skip..].iter().map(|bar| bar.candle(coin, interval)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::primitives::Address;
+
+    use super::*;
+
+    fn batch(trades: &[(&str, &str, u64)]) -> Batch<NodeDataFill> {
+        let fills = trades
+            .iter()
+            .flat_map(|(px, sz, time)| {
+                ["B", "A"].map(|side| {
+                    format!(
+                        r#"[{:?},{{"coin":"BTC","px":"{px}","sz":"{sz}","side":"{side}","time":{time},"startPosition":"0.0","dir":"","closedPnl":"0.0","hash":"0x0","oid":1,"crossed":true,"fee":"0.0","tid":7,"feeToken":"USDC","liquidation":null}}]"#,
+                        Address::ZERO.to_string()
+                    )
+                })
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        serde_json::from_str(&format!(
+            r#"{{"local_time":"2025-01-01T00:00:00","block_time":"2025-01-01T00:00:00","block_number":1,"events":[{fills}]}}"#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_candles() -> Result<()> {
+        let minute: CandleInterval = "1m".parse()?;
+        let mut candles =
+            Candles::new(CandleConfig { intervals: vec!["1s".parse()?, minute, "5m".parse()?], history: 2 });
+        let changed = candles.on_fills(&batch(&[("100", "1", 60_000), ("105", "0.5", 61_500), ("99", "2", 62_000)]));
+        assert_eq!(changed.len(), 3);
+        let candle = candles.latest("BTC", minute).unwrap();
+        assert_eq!((candle.t, candle.close_time), (60_000, 119_999));
+        assert_eq!(
+            [candle.o, candle.h, candle.l, candle.c, candle.v],
+            ["100", "105", "99", "99", "3.5"].map(String::from)
+        );
+        assert_eq!(candle.n, 3);
+        assert_eq!(candles.history("BTC", "1s".parse()?, None, None, None).len(), 2);
+
+        candles.on_fills(&batch(&[("101", "1", 120_000), ("102", "1", 180_000)]));
+        let history = candles.history("BTC", minute, None, None, None);
+        assert_eq!(history.iter().map(|candle| candle.t).collect::<Vec<_>>(), vec![120_000, 180_000]);
+        assert_eq!(candles.history("BTC", minute, None, Some(150_000), Some(1))[0].t, 120_000);
+        assert_eq!(serde_json::to_value(&history[0])?["i"], "1m");
+        assert_eq!(minute.to_string(), "1m");
+        assert_eq!("90s".parse::<CandleInterval>()?.to_string(), "90s");
+        assert!("0m".parse::<CandleInterval>().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,178 @@
+use std::{
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
+
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use tokio::time::{Instant, sleep, sleep_until};
+use tracing::warn;
+
+use crate::prelude::*;
+
+// the faults injected into this process, set through the admin API
+static FAULTS: LazyLock<Faults> = LazyLock::new(|| Faults { rng: SystemRandom::new(), state: Mutex::default() });
+
+// how often a stalled writer checks whether the stall was lifted early
+const STALL_POLL: Duration = Duration::from_millis(100);
+
+/// Faults injected into the server for resilience testing, e.g. `{"upstream_delay_ms": 500,
+/// "drop_frames_percent": 5}`. Everything left out is off.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct FaultConfig {
+    // each event of the node's files is read this late
+    pub(crate) upstream_delay_ms: u64,
+    // of the frames written to the clients, the ones silently dropped
+    pub(crate) drop_frames_percent: f64,
+}
+
+impl FaultConfig {
+    fn validate(&self) -> Result<()> {
+        if !(0.0..=100.0).contains(&self.drop_frames_percent) {
+            return Err("drop_frames_percent must be between 0 and 100".into());
+        }
+        Ok(())
+    }
+}
+
+/// The faults currently injected.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct FaultStatus {
+    #[serde(flatten)]
+    pub(crate) config: FaultConfig,
+    // until the simulated upstream disconnect ends, if one is going on
+    pub(crate) upstream_disconnected_ms: Option<u64>,
+}
+
+struct Faults {
+    rng: SystemRandom,
+    state: Mutex<FaultState>,
+}
+
+#[derive(Default)]
+struct FaultState {
+    config: FaultConfig,
+    disconnected_until: Option<Instant>,
+}
+
+impl FaultState {
+    fn disconnected_for(&self) -> Option<Duration> {
+        self.disconnected_until
+            .map(|until| until.saturating_duration_since(Instant::now()))
+            .filter(|left| !left.is_zero())
+    }
+}
+
+// a connection's writer holds its messages back until then, so that its queue fills up
+#[derive(Debug, Default)]
+pub(crate) struct Stall(Mutex<Option<Instant>>);
+
+impl Stall {
+    // a zero duration lifts the stall
+    pub(crate) fn set(&self, duration: Duration) {
+        if let Ok(mut until) = self.0.lock() {
+            *until = Some(Instant::now() + duration);
+        }
+    }
+
+    fn until(&self) -> Option<Instant> {
+        self.0.lock().ok().and_then(|until| *until).filter(|until| *until > Instant::now())
+    }
+}
+
+pub(crate) fn fault_status() -> FaultStatus {
+    let Ok(state) = FAULTS.state.lock() else {
+        return FaultStatus { config: FaultConfig::default(), upstream_disconnected_ms: None };
+    };
+    let upstream_disconnected_ms =
+        state.disconnected_for().map(|left| u64::try_from(left.as_millis()).unwrap_or(u64::MAX));
+    FaultStatus { config: state.config.clone(), upstream_disconnected_ms }
+}
+
+pub(crate) fn inject_faults(config: FaultConfig) -> Result<()> {
+    config.validate()?;
+    warn!("Injecting faults: {config:?}");
+    FAULTS.state.lock().map_err(|_| "Fault state poisoned")?.config = config;
+    Ok(())
+}
+
+// lifts every fault, the upstream disconnect included
+pub(crate) fn clear_faults() {
+    if let Ok(mut state) = FAULTS.state.lock() {
+        *state = FaultState::default();
+    }
+}
+
+// the node's events are ignored for the duration, as if its files had stopped changing; they are read once it ends
+pub(crate) fn disconnect_upstream(duration: Duration) {
+    warn!("Simulating an upstream disconnect for {duration:.0?}");
+    if let Ok(mut state) = FAULTS.state.lock() {
+        state.disconnected_until = Some(Instant::now() + duration);
+    }
+}
+
+pub(crate) fn is_upstream_disconnected() -> bool {
+    FAULTS.state.lock().is_ok_and(|state| state.disconnected_for().is_some())
+}
+
+// waits out the delay of an event of the node's files. False if the event should be ignored
+pub(crate) async fn upstream_event() -> bool {
+    let delay = match FAULTS.state.lock() {
+        Ok(state) if state.disconnected_for().is_some() => return false,
+        Ok(state) => Duration::from_millis(state.config.upstream_delay_ms),
+        Err(_) => Duration::ZERO,
+    };
+    if !delay.is_zero() {
+        sleep(delay).await;
+    }
+    true
+}
+
+// waits out the connection's stall. False if the frame should be dropped
+pub(crate) async fn outbound_frame(stall: &Stall) -> bool {
+    while let Some(until) = stall.until() {
+        sleep_until(until.min(Instant::now() + STALL_POLL)).await;
+    }
+    let percent = FAULTS.state.lock().map_or(0.0, |state| state.config.drop_frames_percent);
+    if percent <= 0.0 {
+        return true;
+    }
+    let mut bytes = [0; 4];
+    if FAULTS.rng.fill(&mut bytes).is_err() {
+        return true;
+    }
+    f64::from(u32::from_le_bytes(bytes)) / f64::from(u32::MAX) * 100.0 >= percent
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_faults() {
+        assert!(inject_faults(FaultConfig { drop_frames_percent: 101.0, ..FaultConfig::default() }).is_err());
+        inject_faults(FaultConfig { upstream_delay_ms: 50, drop_frames_percent: 100.0 }).unwrap();
+        let stall = Stall::default();
+        assert!(!outbound_frame(&stall).await);
+
+        let start = Instant::now();
+        assert!(upstream_event().await);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        disconnect_upstream(Duration::from_secs(10));
+        assert!(is_upstream_disconnected());
+        assert!(!upstream_event().await);
+        assert!(fault_status().upstream_disconnected_ms.is_some_and(|left| left > 9_000));
+
+        clear_faults();
+        assert!(!is_upstream_disconnected());
+        assert_eq!(fault_status().config, FaultConfig::default());
+        let start = Instant::now();
+        stall.set(Duration::from_millis(200));
+        assert!(outbound_frame(&stall).await);
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        stall.set(Duration::ZERO);
+        assert!(outbound_frame(&stall).await);
+    }
+}
@@ -0,0 +1,284 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{BufRead, BufReader, BufWriter, Seek, SeekFrom, Write},
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use chrono::Utc;
+use fs::File;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{prelude::*, types::L4BookUpdates};
+
+const SEGMENT_EXTENSION: &str = "jsonl";
+const MIN_SEGMENT_BYTES: u64 = 1 << 20;
+
+/// Where to keep the journal of l4 book updates used to replay history to clients, and how much of it to keep.
+///
+/// The journal is started fresh on every start, since sequence numbers start over with the order book.
+#[derive(Debug, Clone)]
+pub struct JournalConfig {
+    pub dir: PathBuf,
+    /// Oldest updates are dropped once the journal is larger than this.
+    pub max_bytes: u64,
+}
+
+impl JournalConfig {
+    #[must_use]
+    pub const fn new(dir: PathBuf) -> Self {
+        Self { dir, max_bytes: 1 << 30 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ReplayFrom {
+    Seq(u64),
+    // block time in milliseconds
+    Time(u64),
+}
+
+impl ReplayFrom {
+    pub(crate) const fn includes(self, update: &L4BookUpdates) -> bool {
+        match self {
+            Self::Seq(seq) => update.seq >= seq,
+            Self::Time(time) => update.time >= time,
+        }
+    }
+}
+
+// where a replay continues reading from
+pub(crate) struct ReplayCursor {
+    from: ReplayFrom,
+    position: Option<(PathBuf, u64)>,
+}
+
+impl ReplayCursor {
+    pub(crate) const fn new(from: ReplayFrom) -> Self {
+        Self { from, position: None }
+    }
+}
+
+#[derive(Serialize)]
+struct EntryRef<'a> {
+    coin: &'a str,
+    update: &'a L4BookUpdates,
+}
+
+#[derive(Deserialize)]
+struct Entry {
+    update: L4BookUpdates,
+}
+
+struct Segment {
+    path: PathBuf,
+    len: u64,
+    last_time: u64,
+    // first and last seq per coin
+    seqs: HashMap<String, (u64, u64)>,
+}
+
+impl Segment {
+    fn may_contain(&self, coin: &str, from: ReplayFrom) -> bool {
+        self.seqs.get(coin).is_some_and(|(_, last_seq)| match from {
+            ReplayFrom::Seq(seq) => *last_seq >= seq,
+            ReplayFrom::Time(time) => self.last_time >= time,
+        })
+    }
+}
+
+struct Inner {
+    segments: VecDeque<Segment>,
+    writer: BufWriter<File>,
+    next_segment: u64,
+    total_bytes: u64,
+}
+
+/// Append-only log of every l4 book update, split into segment files of one JSON line per coin and block.
+/// Written by the order book listener before an update is broadcast, so a replay that has read up to the end of the
+/// journal can't miss an update that a connection hasn't received live yet.
+pub(crate) struct Journal {
+    config: JournalConfig,
+    segment_bytes: u64,
+    // wall clock time the journal was started at, in milliseconds
+    start_time: u64,
+    inner: Mutex<Inner>,
+}
+
+impl Journal {
+    pub(crate) fn create(config: JournalConfig) -> Result<Self> {
+        fs::create_dir_all(&config.dir)?;
+        for entry in fs::read_dir(&config.dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|extension| extension == SEGMENT_EXTENSION) {
+                fs::remove_file(path)?;
+            }
+        }
+        let segment_bytes = (config.max_bytes / 16).max(MIN_SEGMENT_BYTES);
+        let (segment, writer) = open_segment(&config, 0)?;
+        info!("Journaling book updates to {}", config.dir.display());
+        let inner = Inner { segments: VecDeque::from([segment]), writer, next_segment: 1, total_bytes: 0 };
+        #[allow(clippy::cast_sign_loss)]
+        let start_time = Utc::now().timestamp_millis() as u64;
+        Ok(Self { config, segment_bytes, start_time, inner: Mutex::new(inner) })
+    }
+
+    pub(crate) const fn start_time(&self) -> u64 {
+        self.start_time
+    }
+
+    pub(crate) fn append(&self, updates: &HashMap<String, L4BookUpdates>) -> Result<()> {
+        let mut guard = self.inner.lock().map_err(|_| "journal lock poisoned")?;
+        let inner = &mut *guard;
+        let segment = inner.segments.back_mut().ok_or("journal has no segment")?;
+        for (coin, update) in updates {
+            let mut line = serde_json::to_vec(&EntryRef { coin, update })?;
+            line.push(b'\n');
+            inner.writer.write_all(&line)?;
+            segment.len += line.len() as u64;
+            inner.total_bytes += line.len() as u64;
+            segment.last_time = segment.last_time.max(update.time);
+            segment
+                .seqs
+                .entry(coin.clone())
+                .and_modify(|(_, last)| *last = update.seq)
+                .or_insert((update.seq, update.seq));
+        }
+        inner.writer.flush()?;
+        if segment.len >= self.segment_bytes {
+            let (segment, writer) = open_segment(&self.config, inner.next_segment)?;
+            inner.next_segment += 1;
+            inner.segments.push_back(segment);
+            inner.writer = writer;
+        }
+        while inner.total_bytes > self.config.max_bytes && inner.segments.len() > 1 {
+            if let Some(oldest) = inner.segments.pop_front() {
+                inner.total_bytes -= oldest.len;
+                fs::remove_file(&oldest.path)?;
+            }
+        }
+        drop(guard);
+        Ok(())
+    }
+
+    // last journaled seq of a coin
+    pub(crate) fn last_seq(&self, coin: &str) -> Option<u64> {
+        let inner = self.inner.lock().ok()?;
+        inner.segments.iter().rev().find_map(|segment| segment.seqs.get(coin).map(|(_, last)| *last))
+    }
+
+    // first journaled seq of a coin
+    pub(crate) fn first_seq(&self, coin: &str) -> Option<u64> {
+        let inner = self.inner.lock().ok()?;
+        inner.segments.iter().find_map(|segment| segment.seqs.get(coin).map(|(first, _)| *first))
+    }
+
+    // reads up to `limit` updates of `coin` from the cursor on; blocking
+    pub(crate) fn read(&self, coin: &str, cursor: &mut ReplayCursor, limit: usize) -> Result<Vec<L4BookUpdates>> {
+        let segments: Vec<(PathBuf, u64)> = {
+            let inner = self.inner.lock().map_err(|_| "journal lock poisoned")?;
+            let start = match &cursor.position {
+                Some((path, _)) => inner
+                    .segments
+                    .iter()
+                    .position(|segment| &segment.path == path)
+                    .ok_or("replay fell behind the journal's retention")?,
+                None => match inner.segments.iter().position(|segment| segment.may_contain(coin, cursor.from)) {
+                    Some(start) => start,
+                    None => return Ok(Vec::new()),
+                },
+            };
+            inner.segments.iter().skip(start).map(|segment| (segment.path.clone(), segment.len)).collect()
+        };
+        let prefix = format!(r#"{{"coin":{},"#, serde_json::to_string(coin)?);
+        let mut updates = Vec::new();
+        for (path, len) in segments {
+            let mut offset = match &cursor.position {
+                Some((position, offset)) if *position == path => *offset,
+                _ => 0,
+            };
+            let mut file = File::open(&path)?;
+            file.seek(SeekFrom::Start(offset))?;
+            // only read what was flushed when the segments were listed
+            let mut reader = BufReader::new(io::Read::take(file, len.saturating_sub(offset)));
+            let mut line = String::new();
+            while reader.read_line(&mut line)? > 0 {
+                offset += line.len() as u64;
+                if line.starts_with(&prefix) {
+                    let Entry { update } = serde_json::from_str(&line)?;
+                    if cursor.from.includes(&update) {
+                        updates.push(update);
+                    }
+                }
+                line.clear();
+                if updates.len() >= limit {
+                    break;
+                }
+            }
+            cursor.position = Some((path, offset));
+            if updates.len() >= limit {
+                break;
+            }
+        }
+        Ok(updates)
+    }
+}
+
+fn open_segment(config: &JournalConfig, index: u64) -> Result<(Segment, BufWriter<File>)> {
+    let path = config.dir.join(format!("{index:010}.{SEGMENT_EXTENSION}"));
+    let file = File::create(&path)?;
+    let segment = Segment { path, len: 0, last_time: 0, seqs: HashMap::new() };
+    Ok((segment, BufWriter::new(file)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn updates(coin: &str, seq: u64) -> HashMap<String, L4BookUpdates> {
+        let mut update = L4BookUpdates::new(seq * 1000, seq);
+        update.seq = seq;
+        HashMap::from([(coin.to_string(), update)])
+    }
+
+    #[test]
+    fn test_read_from_seq_and_time() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let journal = Journal::create(JournalConfig::new(dir.path().to_path_buf()))?;
+        for seq in 1..=5 {
+            journal.append(&updates("BTC", seq))?;
+            journal.append(&updates("ETH", seq))?;
+        }
+        let mut cursor = ReplayCursor::new(ReplayFrom::Seq(2));
+        let seqs = |updates: Vec<L4BookUpdates>| updates.into_iter().map(|update| update.seq).collect::<Vec<_>>();
+        assert_eq!(seqs(journal.read("BTC", &mut cursor, 2)?), vec![2, 3]);
+        journal.append(&updates("BTC", 6))?;
+        assert_eq!(seqs(journal.read("BTC", &mut cursor, 10)?), vec![4, 5, 6]);
+        assert!(journal.read("BTC", &mut cursor, 10)?.is_empty());
+
+        let mut cursor = ReplayCursor::new(ReplayFrom::Time(4500));
+        assert_eq!(seqs(journal.read("ETH", &mut cursor, 10)?), vec![5]);
+        assert_eq!(journal.first_seq("BTC"), Some(1));
+        assert_eq!(journal.last_seq("BTC"), Some(6));
+        Ok(())
+    }
+
+    #[test]
+    fn test_retention_drops_oldest_segments() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        // every update gets its own segment, and only one fits
+        let update = &updates("BTC", 1)["BTC"];
+        let mut config = JournalConfig::new(dir.path().to_path_buf());
+        config.max_bytes = serde_json::to_vec(&EntryRef { coin: "BTC", update })?.len() as u64 + 1;
+        let journal = Journal { segment_bytes: 1, ..Journal::create(config)? };
+        for seq in 1..=3 {
+            journal.append(&updates("BTC", seq))?;
+        }
+        assert_eq!(journal.first_seq("BTC"), Some(3));
+        let mut cursor = ReplayCursor::new(ReplayFrom::Seq(1));
+        let replayed = journal.read("BTC", &mut cursor, 10)?;
+        assert_eq!(replayed.iter().map(|update| update.seq).collect::<Vec<_>>(), vec![3]);
+        Ok(())
+    }
+}
@@ -0,0 +1,69 @@
+use chrono::Utc;
+use serde::Serialize;
+
+use crate::{metrics::METRICS, servers::protocol::Versioned};
+
+#[allow(clippy::cast_sign_loss)]
+pub(crate) fn now_ms() -> u64 {
+    Utc::now().timestamp_millis() as u64
+}
+
+// when the data of a message was produced by the node and read by the server, in ms since the epoch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Stamps {
+    pub(crate) node_time: u64,
+    pub(crate) ingest_time: u64,
+}
+
+impl Stamps {
+    // observes the latency of a message sent at `send_time`
+    pub(crate) fn observe(self, send_time: u64) {
+        let Self { node_time, ingest_time } = self;
+        METRICS.observe_latency("node_to_ingest", ingest_time.saturating_sub(node_time));
+        METRICS.observe_latency("ingest_to_send", send_time.saturating_sub(ingest_time));
+        METRICS.observe_latency("node_to_send", send_time.saturating_sub(node_time));
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Latency {
+    node_time: u64,
+    ingest_time: u64,
+    send_time: u64,
+}
+
+// a message with its latency metadata, for clients that asked for it
+#[derive(Serialize)]
+pub(crate) struct Stamped<'a> {
+    #[serde(flatten)]
+    msg: Versioned<'a>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    latency: Option<Latency>,
+}
+
+impl<'a> Stamped<'a> {
+    pub(crate) fn new(msg: Versioned<'a>, stamps: Option<Stamps>, send_time: u64) -> Self {
+        let latency = stamps.map(|Stamps { node_time, ingest_time }| Latency { node_time, ingest_time, send_time });
+        Self { msg, latency }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{prelude::*, servers::encoding::Version, types::subscription::ServerResponse};
+
+    #[test]
+    fn test_stamped_message() -> Result<()> {
+        let msg = ServerResponse::Trades(Vec::new());
+        let stamps = Stamps { node_time: 1, ingest_time: 3 };
+        assert_eq!(
+            serde_json::to_string(&Stamped::new(Versioned::new(&msg, Version::V1), Some(stamps), 7))?,
+            r#"{"channel":"trades","data":[],"latency":{"nodeTime":1,"ingestTime":3,"sendTime":7}}"#
+        );
+        let unstamped = Stamped::new(Versioned::new(&msg, Version::V1), None, 7);
+        assert_eq!(serde_json::to_string(&unstamped)?, serde_json::to_string(&msg)?);
+        Ok(())
+    }
+}
@@ -1,11 +1,72 @@
 #![cfg_attr(test, allow(clippy::unwrap_used, clippy::expect_used))]
+mod analytics;
+mod archive;
+#[cfg(feature = "bench")]
+pub mod bench;
+mod candles;
+#[cfg(feature = "chaos")]
+mod chaos;
+mod journal;
+mod latency;
 mod listeners;
+mod logging;
+mod metrics;
 mod order_book;
 mod prelude;
 mod servers;
+mod signing;
+mod snapshot_store;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 mod types;
 
+pub use analytics::AnalyticsConfig;
+pub use archive::{ArchiveConfig, ArchiveStore, S3ArchiveStore};
+pub use candles::{CandleConfig, CandleInterval};
+// a dev-dependency of the benchmarks only
+#[cfg(test)]
+use criterion as _;
+pub use journal::JournalConfig;
+pub use listeners::order_book::{
+    AuditConfig, CrossedBookPolicy, InactivityPolicy, RecordSource, SnapshotStrategy, UpstreamNode, record_feed,
+    replay_feed,
+};
+pub use logging::{LogFormat, LoggingGuard, OtlpConfig, init_logging};
 pub use prelude::Result;
-pub use servers::websocket_server::run_websocket_server;
+#[cfg(feature = "fuzzing")]
+pub use servers::protocol::fuzz_parse_request;
+pub use servers::{
+    auth::{AuthConfig, Identity, JwtValidator, StaticKeys, Validator},
+    check::{Check, check_websocket_server},
+    config::ServerConfig,
+    cors::CorsConfig,
+    deflate::DeflateConfig,
+    election::ElectionConfig,
+    keepalive::KeepaliveConfig,
+    limits::ConnectionLimits,
+    markets::MarketConfig,
+    priority::{LoadShedding, Priority},
+    proxy::{ProxyConfig, TrustedProxy},
+    publisher::{NatsSink, PublishSink, PublisherConfig},
+    rate_limit::{BandwidthPolicy, RateLimits},
+    schema::dump_schema,
+    send_queue::BackpressurePolicy,
+    settings::ReloadHook,
+    shutdown::{ShutdownRequest, ShutdownTrigger, Signals},
+    socket::{SocketOptions, TcpKeepalive},
+    standby::{PromotionRequest, PromotionTrigger},
+    tenants::Tenant,
+    tls::TlsConfig,
+    watchdog::WatchdogConfig,
+    webhooks::WebhookConfig,
+    websocket_server::run_websocket_server,
+    zstd_dictionary::train_zstd_dictionary,
+};
+pub use signing::SigningConfig;
+pub use snapshot_store::{FileSnapshotStore, RedisSnapshotStore, SnapshotStore, SnapshotStoreConfig};
+// a dev-dependency of the integration tests in `tests/` only
+#[cfg(test)]
+use tokio_tungstenite as _;
+pub use tracing::level_filters::LevelFilter;
 
 pub const HL_NODE: &str = "hl-node";
@@ -35,7 +35,6 @@ mod tests {
     };
 
     use fs::{File, create_dir_all, read_dir, remove_dir_all, remove_file};
-    use log::{error, info};
     use notify::{RecursiveMode, Watcher, recommended_watcher};
     use rand::{Rng, SeedableRng, rngs::StdRng};
     use tempfile::tempdir;
@@ -45,6 +44,7 @@ mod tests {
         sync::mpsc::{UnboundedSender, unbounded_channel},
         time::{sleep, timeout},
     };
+    use tracing::{error, info};
 
     use crate::{
         listeners::directory::{DirectoryListener, EventSource},
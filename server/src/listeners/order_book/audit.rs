@@ -0,0 +1,139 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Duration,
+};
+
+use crate::order_book::{InnerOrder, Oid, multi_book::Snapshots};
+
+/// How often the books are audited against a snapshot fetched from the node, and how far they may drift from it
+/// before they are healed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuditConfig {
+    pub interval: Duration,
+    /// Orders missing from, extra in or different in the books beyond which the books are replaced by the node's
+    /// snapshot and every client gets a fresh one. Fewer are only reported.
+    pub heal_threshold: usize,
+}
+
+impl AuditConfig {
+    // every 10 seconds, healing any divergence
+    pub(crate) const fn new() -> Self {
+        Self { interval: Duration::from_secs(10), heal_threshold: 0 }
+    }
+}
+
+// the orders of each book that the node's books don't have, have and the book doesn't, or have with another size
+// or price, by coin. Books that match aren't listed
+pub(super) fn book_divergence<O: InnerOrder + PartialEq>(
+    books: &Snapshots<O>,
+    expected: &Snapshots<O>,
+    ignore_spot: bool,
+) -> BTreeMap<String, usize> {
+    fn orders<O: InnerOrder>(snapshots: &Snapshots<O>, ignore_spot: bool) -> HashMap<String, HashMap<Oid, &O>> {
+        snapshots
+            .as_ref()
+            .iter()
+            .filter(|(coin, _)| !ignore_spot || !coin.is_spot())
+            .map(|(coin, book)| {
+                let orders =
+                    book.as_ref().iter().flatten().map(|order| (order.oid(), order)).collect::<HashMap<_, _>>();
+                (coin.value(), orders)
+            })
+            .collect()
+    }
+    let (books, mut expected) = (orders(books, ignore_spot), orders(expected, ignore_spot));
+    let mut divergence = BTreeMap::new();
+    for (coin, orders) in books {
+        let mut expected = expected.remove(&coin).unwrap_or_default();
+        let differing = orders
+            .into_iter()
+            .filter(|(oid, order)| expected.remove(oid).is_none_or(|expected| expected != *order))
+            .count();
+        divergence.insert(coin, differing + expected.len());
+    }
+    // the books missing altogether
+    divergence.extend(expected.into_iter().map(|(coin, orders)| (coin, orders.len())));
+    divergence.retain(|_, orders| *orders > 0);
+    divergence
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        listeners::order_book::{OrderBookListener, state::OrderBookState},
+        order_book::{Coin, Side, Snapshot},
+        types::{HaltReason, L4Order, inner::InnerL4Order},
+    };
+
+    fn order(coin: &str, oid: u64, sz: &str) -> InnerL4Order {
+        let order = L4Order {
+            user: None,
+            coin: coin.to_string(),
+            side: Side::Bid,
+            limit_px: "100".to_string(),
+            sz: sz.to_string(),
+            oid,
+            timestamp: 1,
+            trigger_condition: "N/A".to_string(),
+            is_trigger: false,
+            trigger_px: "0".to_string(),
+            is_position_tpsl: false,
+            reduce_only: false,
+            order_type: "Limit".to_string(),
+            tif: None,
+            cloid: None,
+        };
+        (alloy::primitives::Address::ZERO, order).try_into().unwrap()
+    }
+
+    fn snapshots(books: Vec<(&str, Vec<InnerL4Order>)>) -> Snapshots<InnerL4Order> {
+        Snapshots::new(
+            books.into_iter().map(|(coin, orders)| (Coin::new(coin), Snapshot::new([orders, Vec::new()]))).collect(),
+        )
+    }
+
+    #[test]
+    fn test_book_divergence() {
+        let expected = snapshots(vec![
+            ("BTC", vec![order("BTC", 1, "1"), order("BTC", 2, "1")]),
+            ("ETH", vec![order("ETH", 3, "1")]),
+            ("SOL", vec![order("SOL", 4, "1")]),
+        ]);
+        assert!(book_divergence(&expected, &expected, true).is_empty());
+        let books = snapshots(vec![
+            // one order changed, one missing and one extra
+            ("BTC", vec![order("BTC", 1, "0.5"), order("BTC", 5, "1")]),
+            ("ETH", vec![order("ETH", 3, "1")]),
+            ("@1", vec![order("@1", 6, "1")]),
+        ]);
+        let divergence = book_divergence(&books, &expected, true);
+        assert_eq!(divergence, BTreeMap::from([("BTC".to_string(), 3), ("SOL".to_string(), 1)]));
+        assert_eq!(book_divergence(&books, &expected, false)["@1"], 1);
+    }
+
+    #[test]
+    fn test_audit_heals() {
+        let oids = |listener: &mut OrderBookListener| {
+            let books = listener.compute_snapshot().unwrap().snapshot.value();
+            books[&Coin::new("BTC")].as_ref()[0].iter().map(|order| order.oid).collect::<Vec<_>>()
+        };
+        let mut listener = OrderBookListener::new(None, true);
+        let books = snapshots(vec![("BTC", vec![order("BTC", 1, "1")])]);
+        listener.set_state(OrderBookState::from_snapshot(books, 5, 0, true, true));
+        let expected = || snapshots(vec![("BTC", vec![order("BTC", 1, "1"), order("BTC", 2, "1")])]);
+        let divergence = BTreeMap::from([("BTC".to_string(), 1)]);
+
+        listener.set_audit(AuditConfig { heal_threshold: 1, ..AuditConfig::new() });
+        assert!(!listener.on_audit(&divergence, expected(), 5, Vec::new()));
+        assert_eq!(oids(&mut listener), [1]);
+        assert!(listener.halt("BTC").is_none());
+        // a snapshot behind the books needs the blocks since to replace them, and the book is halted until then
+        listener.set_audit(AuditConfig::new());
+        assert!(!listener.on_audit(&divergence, expected(), 4, Vec::new()));
+        assert!(listener.halt("BTC").is_some_and(|halt| halt.reason == HaltReason::Audit));
+        assert!(listener.on_audit(&divergence, expected(), 5, Vec::new()));
+        assert_eq!(oids(&mut listener), [1, 2]);
+        assert!(listener.halt("BTC").is_none());
+    }
+}
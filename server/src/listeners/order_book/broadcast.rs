@@ -0,0 +1,278 @@
+use std::{collections::HashMap, hash::Hash, sync::Arc};
+
+use futures_util::{FutureExt, StreamExt};
+use tokio::sync::broadcast::{
+    self, Sender,
+    error::{RecvError, TryRecvError},
+};
+use tokio_stream::{
+    StreamMap,
+    wrappers::{BroadcastStream, errors::BroadcastStreamRecvError},
+};
+
+use crate::{
+    listeners::order_book::{InternalMessage, L2Snapshots},
+    servers::outbound::SharedResponses,
+    types::subscription::Subscription,
+};
+
+// messages kept per channel for the receivers that are behind
+const CAPACITY: usize = 100;
+
+/// The channels the listeners broadcast their messages on. The coins of all markets are split into shards with a
+/// channel each, and a connection only receives from the shards of the coins it subscribed to, so that it isn't
+/// woken up for the messages of every coin. The messages for every connection, such as the status, go on a channel
+/// of their own.
+#[derive(Clone)]
+pub(crate) struct Broadcast {
+    shards: Arc<[Sender<Arc<InternalMessage>>]>,
+    common: Sender<Arc<InternalMessage>>,
+}
+
+// the channel a message, or a part of it, goes on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Channel {
+    Common,
+    Shard(usize),
+}
+
+impl Broadcast {
+    pub(crate) fn new(shards: usize) -> Self {
+        let shards = (0..shards.max(1)).map(|_| broadcast::channel(CAPACITY).0).collect();
+        Self { shards, common: broadcast::channel(CAPACITY).0 }
+    }
+
+    fn shard_of(&self, coin: &str) -> usize {
+        crc32fast::hash(coin.as_bytes()) as usize % self.shards.len()
+    }
+
+    fn sender(&self, channel: Channel) -> &Sender<Arc<InternalMessage>> {
+        match channel {
+            Channel::Common => &self.common,
+            Channel::Shard(shard) => &self.shards[shard],
+        }
+    }
+
+    /// Sends each part of a message to the shard of its coins. Whether anyone received any of them.
+    pub(crate) fn send(&self, msg: InternalMessage) -> bool {
+        let mut received = false;
+        for (channel, msg) in self.split(msg) {
+            received |= self.sender(channel).send(Arc::new(msg)).is_ok();
+        }
+        received
+    }
+
+    // the parts of a message by channel; a single shard gets the messages whole
+    fn split(&self, msg: InternalMessage) -> Vec<(Channel, InternalMessage)> {
+        match msg {
+            InternalMessage::Snapshot { l2_snapshots, time, seq, trace, stamps, .. } => self
+                .partition(l2_snapshots.0, |coin| coin.as_str())
+                .map(|(shard, l2_snapshots)| {
+                    let l2_snapshots = L2Snapshots(l2_snapshots);
+                    let (trace, shared) = (trace.clone(), SharedResponses::default());
+                    (shard, InternalMessage::Snapshot { l2_snapshots, time, seq, trace, stamps, shared })
+                })
+                .collect(),
+            InternalMessage::Fills { batch, trace, stamps, .. } => {
+                let batches = if self.shards.len() == 1 {
+                    vec![(0, batch)]
+                } else {
+                    batch.split(self.shards.len(), |fill| self.shard_of(&fill.1.coin))
+                };
+                batches
+                    .into_iter()
+                    .map(|(shard, batch)| {
+                        let (trace, shared) = (trace.clone(), SharedResponses::default());
+                        (Channel::Shard(shard), InternalMessage::Fills { batch, trace, stamps, shared })
+                    })
+                    .collect()
+            }
+            InternalMessage::L4BookUpdates { updates, trace, stamps, .. } => self
+                .partition(updates, String::as_str)
+                .map(|(shard, updates)| {
+                    let (trace, shared) = (trace.clone(), SharedResponses::default());
+                    (shard, InternalMessage::L4BookUpdates { updates, trace, stamps, shared })
+                })
+                .collect(),
+            InternalMessage::L3BookUpdates { updates, trace, stamps, .. } => self
+                .partition(updates, String::as_str)
+                .map(|(shard, updates)| {
+                    let (trace, shared) = (trace.clone(), SharedResponses::default());
+                    (shard, InternalMessage::L3BookUpdates { updates, trace, stamps, shared })
+                })
+                .collect(),
+            InternalMessage::Candles { candles } => {
+                let mut shards = HashMap::<_, Vec<_>>::new();
+                for candle in candles {
+                    shards.entry(Channel::Shard(self.shard_of(&candle.s))).or_default().push(candle);
+                }
+                shards.into_iter().map(|(shard, candles)| (shard, InternalMessage::Candles { candles })).collect()
+            }
+            InternalMessage::Analytics { analytics } => {
+                let mut shards = HashMap::<_, Vec<_>>::new();
+                for market in analytics {
+                    shards.entry(Channel::Shard(self.shard_of(&market.coin))).or_default().push(market);
+                }
+                shards.into_iter().map(|(shard, analytics)| (shard, InternalMessage::Analytics { analytics })).collect()
+            }
+            InternalMessage::Checkpoints { checkpoints } => {
+                let mut shards = HashMap::<_, Vec<_>>::new();
+                for checkpoint in checkpoints {
+                    shards.entry(Channel::Shard(self.shard_of(&checkpoint.coin))).or_default().push(checkpoint);
+                }
+                shards
+                    .into_iter()
+                    .map(|(shard, checkpoints)| (shard, InternalMessage::Checkpoints { checkpoints }))
+                    .collect()
+            }
+            // on the shard of the market's book data, to arrive in order with it
+            InternalMessage::Halt { halt, resumed } => {
+                vec![(Channel::Shard(self.shard_of(&halt.coin)), InternalMessage::Halt { halt, resumed })]
+            }
+            msg @ (InternalMessage::Status { .. }
+            | InternalMessage::Universe { .. }
+            | InternalMessage::MarketChanges { .. }) => vec![(Channel::Common, msg)],
+        }
+    }
+
+    fn partition<K: Eq + Hash, V>(
+        &self,
+        map: HashMap<K, V>,
+        coin: impl Fn(&K) -> &str,
+    ) -> impl Iterator<Item = (Channel, HashMap<K, V>)> {
+        if self.shards.len() == 1 {
+            return vec![(Channel::Shard(0), map)].into_iter();
+        }
+        let mut shards = HashMap::<_, HashMap<K, V>>::new();
+        for (key, value) in map {
+            let shard = self.shard_of(coin(&key));
+            shards.entry(Channel::Shard(shard)).or_default().insert(key, value);
+        }
+        shards.into_iter().collect::<Vec<_>>().into_iter()
+    }
+
+    /// Receives the messages for every connection, and those of the shards it follows from then on.
+    pub(crate) fn subscribe(&self) -> Receivers {
+        let mut streams = StreamMap::new();
+        streams.insert(Channel::Common, BroadcastStream::new(self.common.subscribe()));
+        Receivers { broadcast: self.clone(), streams }
+    }
+
+    /// Receives the messages of every shard.
+    pub(crate) fn subscribe_all(&self) -> Receivers {
+        let mut receivers = self.subscribe();
+        for shard in 0..self.shards.len() {
+            receivers.follow_shard(shard);
+        }
+        receivers
+    }
+}
+
+/// The channels a connection receives from. Shards are followed until the connection ends, so that a client
+/// moving between coins doesn't miss messages, and at worst it receives every shard.
+pub(crate) struct Receivers {
+    broadcast: Broadcast,
+    streams: StreamMap<Channel, BroadcastStream<Arc<InternalMessage>>>,
+}
+
+impl Receivers {
+    // the messages of the subscription's coin from now on; to be called before its snapshot is taken, so that no
+    // update after the snapshot is missed
+    pub(crate) fn follow(&mut self, subscription: &Subscription) {
+        if let Some(coin) = subscription.coin() {
+            self.follow_shard(self.broadcast.shard_of(coin));
+        } else if let Subscription::UserOrders { .. } = subscription {
+            // an account's orders can be on any coin
+            for shard in 0..self.broadcast.shards.len() {
+                self.follow_shard(shard);
+            }
+        }
+    }
+
+    fn follow_shard(&mut self, shard: usize) {
+        let channel = Channel::Shard(shard);
+        if !self.streams.contains_key(&channel) {
+            self.streams.insert(channel, BroadcastStream::new(self.broadcast.shards[shard].subscribe()));
+        }
+    }
+
+    pub(crate) async fn recv(&mut self) -> Result<Arc<InternalMessage>, RecvError> {
+        match self.streams.next().await {
+            Some((_, Ok(msg))) => Ok(msg),
+            Some((_, Err(BroadcastStreamRecvError::Lagged(n)))) => Err(RecvError::Lagged(n)),
+            None => Err(RecvError::Closed),
+        }
+    }
+
+    pub(crate) fn try_recv(&mut self) -> Result<Arc<InternalMessage>, TryRecvError> {
+        match self.recv().now_or_never() {
+            Some(Ok(msg)) => Ok(msg),
+            Some(Err(RecvError::Lagged(n))) => Err(TryRecvError::Lagged(n)),
+            Some(Err(RecvError::Closed)) => Err(TryRecvError::Closed),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use tracing::Span;
+
+    use super::*;
+    use crate::{latency::Stamps, types::L4BookUpdates};
+
+    fn updates(coins: &[&str]) -> InternalMessage {
+        let updates = coins.iter().map(|coin| (coin.to_string(), L4BookUpdates::new(1, 1))).collect();
+        let stamps = Stamps { node_time: 0, ingest_time: 0 };
+        InternalMessage::L4BookUpdates { updates, trace: Span::none(), stamps, shared: SharedResponses::default() }
+    }
+
+    fn coins(receivers: &mut Receivers) -> HashSet<String> {
+        let mut coins = HashSet::new();
+        while let Ok(msg) = receivers.try_recv() {
+            if let InternalMessage::L4BookUpdates { updates, .. } = msg.as_ref() {
+                coins.extend(updates.keys().cloned());
+            }
+        }
+        coins
+    }
+
+    #[tokio::test]
+    async fn test_receives_the_shards_it_follows() {
+        let broadcast = Broadcast::new(16);
+        let all = ["BTC", "ETH", "SOL", "HYPE", "testnet:BTC"];
+        // shards that differ
+        let btc = broadcast.shard_of("BTC");
+        let other = all.into_iter().find(|coin| broadcast.shard_of(coin) != btc).unwrap_or_default();
+
+        let mut receivers = broadcast.subscribe();
+        receivers.follow(&Subscription::Trades { coin: "BTC".to_string() });
+        let mut everything = broadcast.subscribe_all();
+        assert!(broadcast.send(updates(&all)));
+        let received = coins(&mut receivers);
+        assert!(received.contains("BTC"));
+        assert!(!received.contains(other));
+        assert_eq!(coins(&mut everything), all.into_iter().map(str::to_string).collect());
+
+        // the messages for every connection are received whatever it follows
+        let mut other = broadcast.subscribe();
+        assert!(broadcast.send(InternalMessage::Universe { coins: HashSet::new() }));
+        assert!(other.try_recv().is_ok_and(|msg| matches!(msg.as_ref(), InternalMessage::Universe { .. })));
+        assert!(receivers.try_recv().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_single_shard_sends_messages_whole() {
+        let broadcast = Broadcast::new(1);
+        let mut receivers = broadcast.subscribe();
+        receivers.follow(&Subscription::L4Book { coin: "BTC".to_string(), conflate_ms: None });
+        broadcast.send(updates(&["BTC", "ETH"]));
+        let msg = receivers.try_recv();
+        assert!(msg.is_ok_and(
+            |msg| matches!(msg.as_ref(), InternalMessage::L4BookUpdates { updates, .. } if updates.len() == 2)
+        ));
+        assert!(receivers.try_recv().is_err());
+    }
+}
@@ -0,0 +1,100 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
+use crate::types::L4BookUpdates;
+
+// updates kept per coin whatever the window, so that a burst of blocks can't take up all memory
+const MAX_UPDATES_PER_COIN: usize = 10_000;
+
+/// The l4 book updates of the last `window` of block time for every coin, so that a client that reconnects can
+/// get the updates it missed instead of a new snapshot.
+pub(crate) struct CatchUp {
+    window: Duration,
+    coins: HashMap<String, VecDeque<L4BookUpdates>>,
+}
+
+impl CatchUp {
+    pub(crate) fn new(window: Duration) -> Self {
+        Self { window, coins: HashMap::new() }
+    }
+
+    pub(crate) const fn window(&self) -> Duration {
+        self.window
+    }
+
+    pub(crate) fn append(&mut self, updates: &HashMap<String, L4BookUpdates>) {
+        let window = u64::try_from(self.window.as_millis()).unwrap_or(u64::MAX);
+        for (coin, update) in updates {
+            let buffer = self.coins.entry(coin.clone()).or_default();
+            // only a run of consecutive updates can be sent from
+            if buffer.back().is_some_and(|last| last.seq + 1 != update.seq) {
+                buffer.clear();
+            }
+            buffer.push_back(update.clone());
+            while buffer.len() > MAX_UPDATES_PER_COIN
+                || buffer.front().is_some_and(|first| first.time + window < update.time)
+            {
+                buffer.pop_front();
+            }
+        }
+    }
+
+    // the book was replaced, and its updates start a new sequence
+    pub(crate) fn clear(&mut self) {
+        self.coins.clear();
+    }
+
+    // the updates of a coin from `from_seq` on, if they are all still here. `last_seq` is the last one sent
+    pub(crate) fn since(&self, coin: &str, from_seq: u64, last_seq: u64) -> Option<Vec<L4BookUpdates>> {
+        if from_seq == last_seq + 1 {
+            return Some(Vec::new());
+        }
+        let buffer = self.coins.get(coin)?;
+        let first = buffer.front()?.seq;
+        if from_seq < first || from_seq > last_seq || buffer.back()?.seq != last_seq {
+            return None;
+        }
+        let skip = usize::try_from(from_seq - first).ok()?;
+        Some(buffer.iter().skip(skip).cloned().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update(seq: u64, time: u64) -> HashMap<String, L4BookUpdates> {
+        let mut update = L4BookUpdates::new(time, seq);
+        update.seq = seq;
+        HashMap::from([("BTC".to_string(), update)])
+    }
+
+    fn seqs(catch_up: &CatchUp, from_seq: u64, last_seq: u64) -> Option<Vec<u64>> {
+        let updates = catch_up.since("BTC", from_seq, last_seq)?;
+        Some(updates.into_iter().map(|update| update.seq).collect())
+    }
+
+    #[test]
+    fn test_since() {
+        let mut catch_up = CatchUp::new(Duration::from_secs(2));
+        for seq in 1..=5 {
+            catch_up.append(&update(seq, seq * 1000));
+        }
+        // the first two are older than the window
+        assert_eq!(seqs(&catch_up, 3, 5), Some(vec![3, 4, 5]));
+        assert_eq!(seqs(&catch_up, 5, 5), Some(vec![5]));
+        assert_eq!(seqs(&catch_up, 6, 5), Some(vec![]));
+        assert_eq!(seqs(&catch_up, 2, 5), None);
+        assert_eq!(seqs(&catch_up, 7, 5), None);
+        assert!(catch_up.since("ETH", 1, 3).is_none());
+
+        // a gap in the sequence starts the buffer over
+        catch_up.append(&update(7, 6000));
+        assert_eq!(seqs(&catch_up, 7, 7), Some(vec![7]));
+        assert_eq!(seqs(&catch_up, 5, 7), None);
+        catch_up.clear();
+        assert_eq!(seqs(&catch_up, 7, 7), None);
+    }
+}
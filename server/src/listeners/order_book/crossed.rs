@@ -0,0 +1,115 @@
+use std::{collections::BTreeSet, fmt, str::FromStr};
+
+use tracing::{info, warn};
+
+use crate::{metrics::METRICS, order_book::Coin};
+
+/// What happens when the deltas of a block leave a book crossed or locked (its best bid at or above its best ask),
+/// which the node's books never are.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CrossedBookPolicy {
+    /// Log it, count it in `crossed_books_total` and list the coin in the `crossedBooks` of the feed status.
+    #[default]
+    Flag,
+    /// Also stop serving the market's books until a new snapshot from the node replaced them, like after a gap in
+    /// the node's events.
+    Withhold,
+}
+
+impl FromStr for CrossedBookPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "flag" => Ok(Self::Flag),
+            "withhold" => Ok(Self::Withhold),
+            _ => Err(format!("unknown crossed book policy {s} (expected flag or withhold)")),
+        }
+    }
+}
+
+impl fmt::Display for CrossedBookPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Flag => "flag",
+            Self::Withhold => "withhold",
+        })
+    }
+}
+
+// the coins of a market whose books are crossed or locked
+pub(super) struct CrossedBooks {
+    policy: CrossedBookPolicy,
+    coins: BTreeSet<String>,
+}
+
+impl CrossedBooks {
+    pub(super) const fn new() -> Self {
+        Self { policy: CrossedBookPolicy::Flag, coins: BTreeSet::new() }
+    }
+
+    pub(super) const fn policy(&self) -> CrossedBookPolicy {
+        self.policy
+    }
+
+    pub(super) const fn set_policy(&mut self, policy: CrossedBookPolicy) {
+        self.policy = policy;
+    }
+
+    pub(super) fn coins(&self) -> Vec<String> {
+        self.coins.iter().cloned().collect()
+    }
+
+    // the books changed by block `height`, with whether each is locked (`Some(true)`), crossed (`Some(false)`) or
+    // neither. Returns whether the market's books are to be withheld
+    pub(super) fn on_block(&mut self, books: Vec<(Coin, Option<bool>)>, height: u64) -> bool {
+        let mut crossed = false;
+        for (coin, locked) in books {
+            let coin = coin.value();
+            match locked {
+                Some(locked) if !self.coins.contains(&coin) => {
+                    warn!("The {coin} book is {} after block {height}", if locked { "locked" } else { "crossed" });
+                    METRICS.crossed_books.with_label_values(&[&coin]).inc();
+                    self.coins.insert(coin);
+                    crossed = true;
+                }
+                Some(_) => {}
+                None => {
+                    if self.coins.remove(&coin) {
+                        info!("The {coin} book is no longer crossed after block {height}");
+                    }
+                }
+            }
+        }
+        crossed && self.policy == CrossedBookPolicy::Withhold
+    }
+
+    // the books were replaced by a snapshot of the node, which is taken as it is, crossed or not
+    pub(super) fn reset(&mut self, coins: BTreeSet<String>) {
+        self.coins = coins;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crossed_books() {
+        let (btc, eth) = (Coin::new("BTC"), Coin::new("ETH"));
+        let mut books = CrossedBooks::new();
+        assert!(!books.on_block(vec![(btc.clone(), Some(true)), (eth.clone(), None)], 1));
+        assert_eq!(books.coins(), ["BTC"]);
+        assert!(!books.on_block(vec![(btc.clone(), None)], 2));
+        assert!(books.coins().is_empty());
+
+        books.set_policy(CrossedBookPolicy::Withhold);
+        assert!(books.on_block(vec![(eth.clone(), Some(false))], 3));
+        // the snapshot that replaced the books is crossed too, and only a book newly crossed is withheld again
+        books.reset(BTreeSet::from(["ETH".to_string()]));
+        assert!(!books.on_block(vec![(eth, Some(false))], 4));
+        assert!(books.on_block(vec![(btc, Some(false))], 5));
+        assert_eq!(books.coins(), ["BTC", "ETH"]);
+        assert_eq!("withhold".parse(), Ok(CrossedBookPolicy::Withhold));
+    }
+}
@@ -0,0 +1,134 @@
+use std::{fmt, str::FromStr, sync::Arc, time::Duration};
+
+use tokio::{sync::Notify, time::Instant};
+
+/// When the books of a market are loaded from a snapshot of its node.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SnapshotStrategy {
+    /// At startup, and kept for as long as the server runs.
+    #[default]
+    Eager,
+    /// Once a client subscribes to one of the market's coins, and dropped again once no client subscribed to the
+    /// market for [`ServerConfig::lazy_idle_timeout`](crate::ServerConfig::lazy_idle_timeout).
+    Lazy,
+}
+
+impl FromStr for SnapshotStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "eager" => Ok(Self::Eager),
+            "lazy" => Ok(Self::Lazy),
+            _ => Err(format!("unknown snapshot strategy {s} (expected eager or lazy)")),
+        }
+    }
+}
+
+impl fmt::Display for SnapshotStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Eager => "eager",
+            Self::Lazy => "lazy",
+        })
+    }
+}
+
+// the books of a lazy market, loaded for its first subscriber and dropped once it had none for a while
+pub(super) struct LazyBooks {
+    // wakes the task that loads the books
+    wake: Arc<Notify>,
+    loaded: bool,
+    // when a subscription to the market was last seen
+    watched_at: Instant,
+}
+
+impl LazyBooks {
+    pub(super) fn new() -> Self {
+        Self { wake: Arc::new(Notify::new()), loaded: false, watched_at: Instant::now() }
+    }
+
+    pub(super) fn wake(&self) -> Arc<Notify> {
+        self.wake.clone()
+    }
+
+    pub(super) const fn is_loaded(&self) -> bool {
+        self.loaded
+    }
+
+    pub(super) fn set_loaded(&mut self, loaded: bool) {
+        self.loaded = loaded;
+        self.watched_at = Instant::now();
+    }
+
+    // a subscription wants the books; they are loaded if they aren't yet
+    pub(super) fn watch(&mut self) {
+        self.watched_at = Instant::now();
+        if !self.loaded {
+            self.wake.notify_one();
+        }
+    }
+
+    pub(super) fn idle_for(&self) -> Duration {
+        self.watched_at.elapsed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::{
+        listeners::order_book::OrderBookListener,
+        order_book::Coin,
+        types::{MarketInfo, MarketKind, MarketStatus},
+    };
+
+    #[tokio::test]
+    async fn test_lazy_books() {
+        assert_eq!("lazy".parse::<SnapshotStrategy>(), Ok(SnapshotStrategy::Lazy));
+        assert!("later".parse::<SnapshotStrategy>().is_err());
+        assert_eq!(SnapshotStrategy::default().to_string(), "eager");
+
+        let mut books = LazyBooks::new();
+        let wake = books.wake();
+        // the first subscription wakes the loader even if it isn't waiting yet
+        books.watch();
+        tokio::time::timeout(Duration::from_secs(1), wake.notified()).await.unwrap();
+        books.set_loaded(true);
+        // further ones only keep the books
+        books.watch();
+        assert!(tokio::time::timeout(Duration::from_millis(50), wake.notified()).await.is_err());
+        assert!(books.is_loaded() && books.idle_for() >= Duration::from_millis(50));
+        books.watch();
+        assert!(books.idle_for() < Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_lazy_market() {
+        let mut listener = OrderBookListener::new(None, true).for_market("testnet");
+        let _wake = listener.set_lazy();
+        listener.set_market_info(vec![MarketInfo {
+            coin: "BTC".to_string(),
+            kind: MarketKind::Perp,
+            base: "BTC".to_string(),
+            quote: "USDC".to_string(),
+            sz_decimals: 5,
+            lot_size: "0.00001".to_string(),
+            tick_size: "1".to_string(),
+            status: MarketStatus::Active,
+        }]);
+        // its coins can be subscribed to before the books are loaded, and the subscriptions wait for them
+        assert_eq!(listener.universe(), HashSet::from([Coin::new("testnet:BTC")]));
+        assert!(listener.is_loading());
+        // and it has no status until it is loaded
+        listener.send_status(false);
+        assert!(listener.last_status().is_none());
+        listener.load();
+        listener.send_status(false);
+        assert!(listener.last_status().is_some_and(|status| !status.ready));
+        listener.unload();
+        assert!(listener.last_status().is_none() && listener.is_loading());
+    }
+}
@@ -0,0 +1,195 @@
+use std::{collections::HashMap, time::Duration};
+
+use reqwest::Client;
+use serde::{Deserialize, de::DeserializeOwned};
+use serde_json::json;
+
+use crate::{
+    listeners::order_book::UpstreamNode,
+    prelude::*,
+    types::{MarketInfo, MarketKind, MarketStatus},
+};
+
+// prices have at most this many decimals less the size decimals of the market
+const MAX_PERP_DECIMALS: u32 = 6;
+const MAX_SPOT_DECIMALS: u32 = 8;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Deserialize)]
+struct Meta {
+    universe: Vec<PerpAsset>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PerpAsset {
+    name: String,
+    sz_decimals: u32,
+    #[serde(default)]
+    is_delisted: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct SpotMeta {
+    universe: Vec<SpotPair>,
+    tokens: Vec<Token>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SpotPair {
+    name: String,
+    // base, quote
+    tokens: [usize; 2],
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Token {
+    name: String,
+    sz_decimals: u32,
+    index: usize,
+}
+
+// the perp and spot markets from the node's info endpoint, all of them active
+pub(crate) async fn fetch_market_info(upstream: &UpstreamNode) -> Result<Vec<MarketInfo>> {
+    let client = Client::builder().timeout(REQUEST_TIMEOUT).build()?;
+    let meta: Meta = info_request(&client, &upstream.info_url, "meta").await?;
+    let spot_meta: SpotMeta = info_request(&client, &upstream.info_url, "spotMeta").await?;
+    Ok(markets_from_meta(meta, spot_meta))
+}
+
+async fn info_request<T: DeserializeOwned>(client: &Client, info_url: &str, kind: &str) -> Result<T> {
+    let res = client.post(info_url).json(&json!({"type": kind})).send().await?.error_for_status()?;
+    Ok(res.json().await?)
+}
+
+fn markets_from_meta(meta: Meta, spot_meta: SpotMeta) -> Vec<MarketInfo> {
+    let perps = meta.universe.into_iter().map(|asset| MarketInfo {
+        lot_size: increment(asset.sz_decimals),
+        tick_size: increment(MAX_PERP_DECIMALS.saturating_sub(asset.sz_decimals)),
+        coin: asset.name.clone(),
+        kind: MarketKind::Perp,
+        base: asset.name,
+        quote: "USDC".to_string(),
+        sz_decimals: asset.sz_decimals,
+        status: if asset.is_delisted { MarketStatus::Delisted } else { MarketStatus::Active },
+    });
+    let tokens = spot_meta.tokens.iter().map(|token| (token.index, token)).collect::<HashMap<_, _>>();
+    // pairs of tokens that aren't listed themselves are left out
+    let spots = spot_meta.universe.into_iter().filter_map(|pair| {
+        let (base, quote) = (tokens.get(&pair.tokens[0])?, tokens.get(&pair.tokens[1])?);
+        Some(MarketInfo {
+            coin: pair.name,
+            kind: MarketKind::Spot,
+            base: base.name.clone(),
+            quote: quote.name.clone(),
+            sz_decimals: base.sz_decimals,
+            lot_size: increment(base.sz_decimals),
+            tick_size: increment(MAX_SPOT_DECIMALS.saturating_sub(base.sz_decimals)),
+            status: MarketStatus::Active,
+        })
+    });
+    perps.chain(spots).collect()
+}
+
+// the smallest step of a decimal with this many decimals, e.g. "0.01" for 2
+fn increment(decimals: u32) -> String {
+    match decimals {
+        0 => "1".to_string(),
+        decimals => format!("0.{}1", "0".repeat(decimals as usize - 1)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    };
+
+    use axum::{Json, Router, routing::post};
+    use serde_json::Value;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{
+        listeners::order_book::{Broadcast, InternalMessage, OrderBookListener},
+        types::MarketChangeKind,
+    };
+
+    const META: &str = r#"{"universe":[{"szDecimals":5,"name":"BTC","maxLeverage":40},{"szDecimals":1,"name":"MATIC","maxLeverage":20,"isDelisted":true}]}"#;
+    const SPOT_META: &str = r#"{"universe":[{"tokens":[1,0],"name":"PURR/USDC","index":0,"isCanonical":true},{"tokens":[2,0],"name":"@1","index":1,"isCanonical":false},{"tokens":[9,0],"name":"@2","index":2,"isCanonical":false}],"tokens":[{"name":"USDC","szDecimals":8,"weiDecimals":8,"index":0},{"name":"PURR","szDecimals":0,"weiDecimals":5,"index":1},{"name":"HFUN","szDecimals":2,"weiDecimals":8,"index":2}]}"#;
+
+    #[test]
+    fn test_markets_from_meta() -> Result<()> {
+        let markets = markets_from_meta(serde_json::from_str(META)?, serde_json::from_str(SPOT_META)?);
+        let summary = markets
+            .iter()
+            .map(|market| (market.coin.as_str(), market.lot_size.as_str(), market.tick_size.as_str(), market.status))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                ("BTC", "0.00001", "0.1", MarketStatus::Active),
+                ("MATIC", "0.1", "0.00001", MarketStatus::Delisted),
+                ("PURR/USDC", "1", "0.00000001", MarketStatus::Active),
+                ("@1", "0.01", "0.000001", MarketStatus::Active),
+            ]
+        );
+        assert_eq!((markets[3].base.as_str(), markets[3].quote.as_str()), ("HFUN", "USDC"));
+        assert_eq!(markets[0].kind, MarketKind::Perp);
+        Ok(())
+    }
+
+    // changes are sent from the second fetch on
+    #[tokio::test]
+    async fn test_market_changes() -> Result<()> {
+        let delisted = Arc::new(AtomicBool::new(false));
+        let node_delisted = delisted.clone();
+        let info = Router::new().route(
+            "/info",
+            post(async move |Json(request): Json<Value>| {
+                let meta = if node_delisted.load(Ordering::Relaxed) {
+                    META.replace("40}", "40,\"isDelisted\":true}")
+                } else {
+                    META.to_string()
+                };
+                let body = if request["type"] == "meta" { meta } else { SPOT_META.to_string() };
+                serde_json::from_str::<Value>(&body).map(Json).map_err(|_| ())
+            }),
+        );
+        let tcp_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let upstream =
+            UpstreamNode { data_dir: "/tmp".into(), info_url: format!("http://{}/info", tcp_listener.local_addr()?) };
+        tokio::spawn(async move { axum::serve(tcp_listener, info).await });
+
+        let broadcast = Broadcast::new(1);
+        let mut receivers = broadcast.subscribe();
+        let mut listener = OrderBookListener::new(Some(broadcast), true).for_market("testnet");
+        listener.set_market_info(fetch_market_info(&upstream).await?);
+        // spot markets are ignored like their books
+        let coins = listener.market_info().iter().map(|market| market.coin.as_str()).collect::<Vec<_>>();
+        assert_eq!(coins, ["testnet:BTC", "testnet:MATIC"]);
+        assert!(receivers.try_recv().is_err());
+
+        delisted.store(true, Ordering::Relaxed);
+        listener.set_market_info(fetch_market_info(&upstream).await?);
+        let msg = receivers.try_recv()?;
+        let InternalMessage::MarketChanges { changes } = msg.as_ref() else {
+            return Err("no market changes".into());
+        };
+        assert_eq!(changes.len(), 1);
+        assert_eq!((changes[0].change, changes[0].market.coin.as_str()), (MarketChangeKind::Changed, "testnet:BTC"));
+        assert_eq!(changes[0].market.status, MarketStatus::Delisted);
+
+        let mut markets = fetch_market_info(&upstream).await?;
+        markets.retain(|market| market.coin != "MATIC");
+        listener.set_market_info(markets);
+        let msg = receivers.try_recv()?;
+        assert!(
+            matches!(msg.as_ref(), InternalMessage::MarketChanges { changes } if changes[0].change == MarketChangeKind::Removed)
+        );
+        Ok(())
+    }
+}
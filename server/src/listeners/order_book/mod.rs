@@ -1,229 +1,322 @@
 use std::{
     cmp::Ordering,
-    collections::{HashMap, HashSet, VecDeque},
-    io::{Read, Seek, SeekFrom},
-    path::PathBuf,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
     sync::Arc,
     time::Duration,
 };
 
-use alloy::primitives::Address;
-use fs::File;
-use log::{error, info};
-use notify::{Event, RecursiveMode, Watcher, recommended_watcher};
-use tokio::{
-    sync::{
-        Mutex,
-        broadcast::Sender,
-        mpsc::{UnboundedSender, unbounded_channel},
-    },
-    time::{Instant, interval_at, sleep},
-};
-use utils::{BatchQueue, EventBatch, process_rmp_file, validate_snapshot_consistency};
+use chrono::Utc;
+use tokio::sync::{Mutex, Notify};
+use tracing::{Span, debug, debug_span, error, info, info_span, warn};
+use utils::{BatchQueue, EventBatch};
 
 use crate::{
-    HL_NODE,
-    listeners::{directory::DirectoryListener, order_book::state::OrderBookState},
-    order_book::{
-        Coin, Snapshot,
-        multi_book::{Snapshots, load_snapshots_from_json},
-    },
+    analytics::{Analytics, MarketAnalytics},
+    candles::{Candle, CandleInterval, Candles},
+    journal::Journal,
+    latency::{Stamps, now_ms},
+    listeners::order_book::state::OrderBookState,
+    logging::PIPELINE,
+    metrics::METRICS,
+    order_book::{Coin, Px, Snapshot, multi_book::Snapshots},
     prelude::*,
+    servers::outbound::SharedResponses,
+    signing::{Checkpoint, Checkpoints, Signer},
+    snapshot_store::StoredBooks,
     types::{
-        L4Order,
+        HaltReason, L3BookUpdates, L4BookUpdates, MarketChange, MarketChangeKind, MarketHalt, MarketInfo, MarketStatus,
+        StreamStatus, UpstreamStatus,
         inner::{InnerL4Order, InnerLevel},
         node_data::{Batch, EventSource, NodeDataFill, NodeDataOrderDiff, NodeDataOrderStatus},
     },
 };
 
-mod state;
+mod audit;
+mod broadcast;
+mod catch_up;
+mod crossed;
+mod lazy;
+mod market_info;
+mod node;
+mod recording;
+mod relay;
+#[cfg(test)]
+mod simulation;
+mod source;
+pub(crate) mod state;
+mod upstream;
 mod utils;
 
-// WARNING - this code assumes no other file system operations are occurring in the watched directories
-// if there are scripts running, this may not work as intended
-pub(crate) async fn hl_listen(
-    listener: Arc<Mutex<OrderBookListener>>,
-    dir: PathBuf,
-    inactivity_exit_secs: u64,
-) -> Result<()> {
-    let order_statuses_dir = EventSource::OrderStatuses.event_source_dir(&dir).canonicalize()?;
-    let fills_dir = EventSource::Fills.event_source_dir(&dir).canonicalize()?;
-    let order_diffs_dir = EventSource::OrderDiffs.event_source_dir(&dir).canonicalize()?;
-    info!("Monitoring order status directory: {}", order_statuses_dir.display());
-    info!("Monitoring order diffs directory: {}", order_diffs_dir.display());
-    info!("Monitoring fills directory: {}", fills_dir.display());
-
-    // monitoring the directory via the notify crate (gives file system events)
-    let (fs_event_tx, mut fs_event_rx) = unbounded_channel();
-    let mut watcher = recommended_watcher(move |res| {
-        let fs_event_tx = fs_event_tx.clone();
-        if let Err(err) = fs_event_tx.send(res) {
-            error!("Error sending fs event to processor via channel: {err}");
-        }
-    })?;
-
-    let ignore_spot = {
-        let listener = listener.lock().await;
-        listener.ignore_spot
-    };
-
-    // every so often, we fetch a new snapshot and the snapshot_fetch_task starts running.
-    // Result is sent back along this channel (if error, we want to return to top level)
-    let (snapshot_fetch_task_tx, mut snapshot_fetch_task_rx) = unbounded_channel::<Result<()>>();
-
-    watcher.watch(&order_statuses_dir, RecursiveMode::Recursive)?;
-    watcher.watch(&fills_dir, RecursiveMode::Recursive)?;
-    watcher.watch(&order_diffs_dir, RecursiveMode::Recursive)?;
-    let start = Instant::now() + Duration::from_secs(5);
-    let mut ticker = interval_at(start, Duration::from_secs(10));
-    loop {
-        tokio::select! {
-            event = fs_event_rx.recv() =>  match event {
-                Some(Ok(event)) => {
-                    if event.kind.is_create() || event.kind.is_modify() {
-                        let new_path = &event.paths[0];
-                        if new_path.starts_with(&order_statuses_dir) && new_path.is_file() {
-                            listener
-                                .lock()
-                                .await
-                                .process_update(&event, new_path, EventSource::OrderStatuses)
-                                .map_err(|err| format!("Order status processing error: {err}"))?;
-                        } else if new_path.starts_with(&fills_dir) && new_path.is_file() {
-                            listener
-                                .lock()
-                                .await
-                                .process_update(&event, new_path, EventSource::Fills)
-                                .map_err(|err| format!("Fill update processing error: {err}"))?;
-                        } else if new_path.starts_with(&order_diffs_dir) && new_path.is_file() {
-                            listener
-                                .lock()
-                                .await
-                                .process_update(&event, new_path, EventSource::OrderDiffs)
-                                .map_err(|err| format!("Book diff processing error: {err}"))?;
-                        }
-                    }
-                }
-                Some(Err(err)) => {
-                    error!("Watcher error: {err}");
-                    return Err(format!("Watcher error: {err}").into());
-                }
-                None => {
-                    error!("Channel closed. Listener exiting");
-                    return Err("Channel closed.".into());
-                }
-            },
-            snapshot_fetch_res = snapshot_fetch_task_rx.recv() => {
-                match snapshot_fetch_res {
-                    None => {
-                        return Err("Snapshot fetch task sender dropped".into());
-                    }
-                    Some(Err(err)) => {
-                        return Err(format!("Abci state reading error: {err}").into());
-                    }
-                    Some(Ok(())) => {}
-                }
-            }
-            _ = ticker.tick() => {
-                let listener = listener.clone();
-                let snapshot_fetch_task_tx = snapshot_fetch_task_tx.clone();
-                fetch_snapshot(dir.clone(), listener, snapshot_fetch_task_tx, ignore_spot);
-            }
-            () = sleep(Duration::from_secs(inactivity_exit_secs)) => {
-                let listener = listener.lock().await;
-                if listener.is_ready() {
-                    return Err(format!("Stream has fallen behind ({HL_NODE} failed?)").into());
-                }
-            }
-        }
-    }
-}
-
-fn fetch_snapshot(
-    dir: PathBuf,
-    listener: Arc<Mutex<OrderBookListener>>,
-    tx: UnboundedSender<Result<()>>,
-    ignore_spot: bool,
-) {
-    let tx = tx.clone();
-    tokio::spawn(async move {
-        let res = match process_rmp_file(&dir).await {
-            Ok(output_fln) => {
-                let state = {
-                    let mut listener = listener.lock().await;
-                    listener.begin_caching();
-                    listener.clone_state()
-                };
-                let snapshot = load_snapshots_from_json::<InnerL4Order, (Address, L4Order)>(&output_fln).await;
-                info!("Snapshot fetched");
-                // sleep to let some updates build up.
-                sleep(Duration::from_secs(1)).await;
-                let mut cache = {
-                    let mut listener = listener.lock().await;
-                    listener.take_cache()
-                };
-                info!("Cache has {} elements", cache.len());
-                match snapshot {
-                    Ok((height, expected_snapshot)) => {
-                        if let Some(mut state) = state {
-                            while state.height() < height {
-                                if let Some((order_statuses, order_diffs)) = cache.pop_front() {
-                                    state.apply_updates(order_statuses, order_diffs)?;
-                                } else {
-                                    return Err::<(), Error>("Not enough cached updates".into());
-                                }
-                            }
-                            if state.height() > height {
-                                return Err("Fetched snapshot lagging stored state".into());
-                            }
-                            let stored_snapshot = state.compute_snapshot().snapshot;
-                            info!("Validating snapshot");
-                            validate_snapshot_consistency(&stored_snapshot, expected_snapshot, ignore_spot)
-                        } else {
-                            listener.lock().await.init_from_snapshot(expected_snapshot, height);
-                            Ok(())
-                        }
-                    }
-                    Err(err) => Err(err),
-                }
-            }
-            Err(err) => Err(err),
-        };
-        let _unused = tx.send(res);
-        Ok(())
-    });
-}
+pub use audit::AuditConfig;
+pub(crate) use broadcast::{Broadcast, Receivers};
+use catch_up::CatchUp;
+pub use crossed::CrossedBookPolicy;
+use crossed::CrossedBooks;
+use lazy::LazyBooks;
+pub use lazy::SnapshotStrategy;
+pub(crate) use market_info::fetch_market_info;
+pub(crate) use node::NodeSource;
+pub use recording::{RecordSource, record_feed, replay_feed};
+use relay::RelayFeed;
+#[cfg(any(test, feature = "testing"))]
+pub(crate) use relay::RelaySnapshot;
+pub(crate) use relay::{RelaySource, serve_relay};
+pub(crate) use source::listen_source;
+#[cfg(any(test, feature = "testing"))]
+pub(crate) use source::{SourceEvent, UpstreamSource};
+pub use upstream::{InactivityPolicy, UpstreamNode};
 
 pub(crate) struct OrderBookListener {
     ignore_spot: bool,
-    fill_status_file: Option<File>,
-    order_status_file: Option<File>,
-    order_diff_file: Option<File>,
     // None if we haven't seen a valid snapshot yet
     order_book_state: Option<OrderBookState>,
+    // loaded from the snapshot store; becomes the order book state once the node's events continue it
+    restored: Option<OrderBookState>,
     last_fill: Option<u64>,
+    // highest block received from any upstream, for any event source
+    latest_block: u64,
     order_diff_cache: BatchQueue<NodeDataOrderDiff>,
     order_status_cache: BatchQueue<NodeDataOrderStatus>,
     // Only Some when we want it to collect updates
     fetched_snapshot_cache: Option<VecDeque<(Batch<NodeDataOrderStatus>, Batch<NodeDataOrderDiff>)>>,
-    internal_message_tx: Option<Sender<Arc<InternalMessage>>>,
+    internal_message_tx: Option<Broadcast>,
+    // the coins of the last snapshot published, to tell connections when they change
+    published_coins: Option<HashSet<Coin>>,
+    journal: Option<Arc<Journal>>,
+    // the recent l4 updates, for clients resuming after a reconnect
+    catch_up: Option<CatchUp>,
+    candles: Option<Candles>,
+    analytics: Option<Analytics>,
+    checkpoints: Option<Checkpoints>,
+    // the events read are passed on to edge instances when set
+    relay: Option<RelayFeed>,
+    // `<market>:` for the coins of markets other than the primary one
+    coin_prefix: Option<String>,
+    // the tick sizes subscribed to, added again whenever the book is replaced
+    tick_groups: BTreeSet<(Coin, Px)>,
+    // feed health, see `send_status`
+    stale: bool,
+    latest_block_time: Option<u64>,
+    upstreams: Vec<UpstreamStatus>,
+    last_status: Option<StreamStatus>,
+    // gaps in the block numbers of the node events since the start
+    gaps: u64,
+    // a gap left the book behind the node; a snapshot is to be fetched right away
+    resync_requested: bool,
+    // the book was dropped for a gap, so its clients need new snapshots once it is back
+    resyncing: bool,
+    // the markets of the node's metadata, once it was fetched
+    market_info: Option<Vec<MarketInfo>>,
+    crossed_books: CrossedBooks,
+    audit: AuditConfig,
+    // the coins whose books diverged from the node's at the last audit
+    divergent: BTreeSet<String>,
+    // the markets whose book data is held back, once for each reason
+    halts: Vec<MarketHalt>,
+    // set for markets whose books are only kept while they have subscribers
+    lazy: Option<LazyBooks>,
 }
 
 impl OrderBookListener {
-    pub(crate) const fn new(internal_message_tx: Option<Sender<Arc<InternalMessage>>>, ignore_spot: bool) -> Self {
+    pub(crate) const fn new(internal_message_tx: Option<Broadcast>, ignore_spot: bool) -> Self {
         Self {
             ignore_spot,
-            fill_status_file: None,
-            order_status_file: None,
-            order_diff_file: None,
             order_book_state: None,
+            restored: None,
             last_fill: None,
+            latest_block: 0,
             fetched_snapshot_cache: None,
             internal_message_tx,
+            published_coins: None,
             order_diff_cache: BatchQueue::new(),
             order_status_cache: BatchQueue::new(),
+            journal: None,
+            catch_up: None,
+            candles: None,
+            analytics: None,
+            checkpoints: None,
+            relay: None,
+            coin_prefix: None,
+            tick_groups: BTreeSet::new(),
+            stale: false,
+            latest_block_time: None,
+            upstreams: Vec::new(),
+            last_status: None,
+            gaps: 0,
+            resync_requested: false,
+            resyncing: false,
+            market_info: None,
+            crossed_books: CrossedBooks::new(),
+            audit: AuditConfig::new(),
+            divergent: BTreeSet::new(),
+            halts: Vec::new(),
+            lazy: None,
         }
     }
 
+    // a listener for another market, whose coins are served as `<name>:<coin>`. It shares the channels, journal
+    // and settings of this one
+    pub(crate) fn for_market(&self, name: &str) -> Self {
+        let mut listener = Self::new(self.internal_message_tx.clone(), self.ignore_spot);
+        listener.journal.clone_from(&self.journal);
+        listener.catch_up = self.catch_up.as_ref().map(|catch_up| CatchUp::new(catch_up.window()));
+        listener.candles = self.candles.as_ref().map(|candles| Candles::new(candles.config().clone()));
+        listener.analytics = self.analytics.as_ref().map(|analytics| Analytics::new(analytics.config().clone()));
+        listener.checkpoints = self
+            .checkpoints
+            .as_ref()
+            .map(|checkpoints| Checkpoints::new(checkpoints.signer().clone(), checkpoints.interval()));
+        listener.coin_prefix = Some(format!("{name}:"));
+        listener.crossed_books.set_policy(self.crossed_books.policy());
+        listener.audit = self.audit;
+        listener
+    }
+
+    // every l4 book update is written to the journal before it is broadcast
+    pub(crate) fn set_journal(&mut self, journal: Arc<Journal>) {
+        self.journal = Some(journal);
+    }
+
+    // keeps the l4 updates of the last `window` of block time in memory, like the journal before they are broadcast
+    pub(crate) fn set_catch_up(&mut self, window: Duration) {
+        self.catch_up = Some(CatchUp::new(window));
+    }
+
+    // the updates of a coin from `from_seq` on, if they were all kept for catching up
+    pub(crate) fn missed_updates(&self, coin: &str, from_seq: u64) -> Option<Vec<L4BookUpdates>> {
+        self.catch_up.as_ref()?.since(coin, from_seq, self.l4_seq(&Coin::new(coin)))
+    }
+
+    pub(crate) const fn set_audit(&mut self, audit: AuditConfig) {
+        self.audit = audit;
+    }
+
+    pub(crate) const fn set_crossed_books(&mut self, policy: CrossedBookPolicy) {
+        self.crossed_books.set_policy(policy);
+    }
+
+    // the books are only loaded once `watch` wakes the returned notify. The clients subscribed by then get
+    // snapshots once they are, like after a gap
+    pub(crate) fn set_lazy(&mut self) -> Arc<Notify> {
+        let lazy = LazyBooks::new();
+        let wake = lazy.wake();
+        self.lazy = Some(lazy);
+        self.resyncing = true;
+        wake
+    }
+
+    pub(crate) const fn is_lazy(&self) -> bool {
+        self.lazy.is_some()
+    }
+
+    // a subscription to the market wants its books, which keeps them loaded or loads them
+    pub(crate) fn watch(&mut self) {
+        if let Some(lazy) = &mut self.lazy {
+            lazy.watch();
+        }
+    }
+
+    // how long no subscription to the lazy market was seen
+    pub(crate) fn idle_for(&self) -> Duration {
+        self.lazy.as_ref().map_or(Duration::ZERO, LazyBooks::idle_for)
+    }
+
+    // a lazy market whose books were dropped, or not loaded yet
+    fn is_unloaded(&self) -> bool {
+        self.lazy.as_ref().is_some_and(|lazy| !lazy.is_loaded())
+    }
+
+    // a lazy market whose books are wanted but not there yet, which its subscriptions wait for
+    pub(crate) const fn is_loading(&self) -> bool {
+        self.lazy.is_some() && !self.is_ready()
+    }
+
+    pub(crate) fn load(&mut self) {
+        if let Some(lazy) = &mut self.lazy {
+            lazy.set_loaded(true);
+        }
+    }
+
+    // drops the books and what was read of the node's events, until the market is loaded again
+    pub(crate) fn unload(&mut self) {
+        if let Some(lazy) = &mut self.lazy {
+            lazy.set_loaded(false);
+        }
+        self.order_book_state = None;
+        self.restored = None;
+        self.last_fill = None;
+        self.order_diff_cache = BatchQueue::new();
+        self.order_status_cache = BatchQueue::new();
+        self.fetched_snapshot_cache = None;
+        if let Some(catch_up) = &mut self.catch_up {
+            catch_up.clear();
+        }
+        self.stale = false;
+        self.upstreams.clear();
+        self.last_status = None;
+        self.resync_requested = false;
+        self.resyncing = true;
+    }
+
+    pub(crate) fn set_candles(&mut self, candles: Candles) {
+        self.candles = Some(candles);
+    }
+
+    pub(crate) fn has_candles(&self, interval: CandleInterval) -> bool {
+        self.candles.as_ref().is_some_and(|candles| candles.has_interval(interval))
+    }
+
+    pub(crate) const fn candles(&self) -> Option<&Candles> {
+        self.candles.as_ref()
+    }
+
+    pub(crate) fn set_analytics(&mut self, analytics: Analytics) {
+        self.analytics = Some(analytics);
+    }
+
+    pub(crate) const fn analytics(&self) -> Option<&Analytics> {
+        self.analytics.as_ref()
+    }
+
+    // the books are checkpointed every `interval` and l4 snapshots are signed with the key of `signer`
+    pub(crate) fn set_signer(&mut self, signer: Arc<Signer>, interval: Duration) {
+        self.checkpoints = Some(Checkpoints::new(signer, interval));
+    }
+
+    pub(crate) const fn checkpoints(&self) -> Option<&Checkpoints> {
+        self.checkpoints.as_ref()
+    }
+
+    // the signed checkpoint of a coin's book, for its l4 snapshot at `time` and `height`
+    pub(crate) fn sign_book(&self, coin: &Coin, time: u64, height: u64) -> Option<Checkpoint> {
+        let signer = self.checkpoints.as_ref()?.signer();
+        let (_, _, levels) = self.l2_snapshot(coin, usize::MAX, None, None)?;
+        Some(signer.checkpoint(coin.value(), time, height, self.l4_seq(coin), &levels.export_inner_snapshot()))
+    }
+
+    // fills up to its last fill were already published before the restart
+    pub(crate) fn restore(&mut self, stored: StoredBooks) -> Result<()> {
+        let last_fill = stored.last_fill;
+        let state = OrderBookState::from_stored(stored, self.ignore_spot)?;
+        self.last_fill = Some(last_fill.unwrap_or_else(|| state.height()));
+        self.restored = Some(state);
+        Ok(())
+    }
+
+    // the events since the restored state are at the start of the node's current files
+    pub(super) const fn is_restoring(&self) -> bool {
+        self.restored.is_some()
+    }
+
+    // cloned under the lock, converted after
+    pub(crate) async fn stored_books(listener: &Mutex<Self>) -> Option<StoredBooks> {
+        let (state, last_fill) = {
+            let listener = listener.lock().await;
+            (listener.clone_state()?, listener.last_fill)
+        };
+        Some(StoredBooks { last_fill, ..state.to_stored() })
+    }
+
     fn clone_state(&self) -> Option<OrderBookState> {
         self.order_book_state.clone()
     }
@@ -232,8 +325,17 @@ impl OrderBookListener {
         self.order_book_state.is_some()
     }
 
+    pub(crate) const fn latest_block(&self) -> u64 {
+        self.latest_block
+    }
+
+    // the coins of a lazy market are those of its metadata while its books aren't loaded
     pub(crate) fn universe(&self) -> HashSet<Coin> {
-        self.order_book_state.as_ref().map_or_else(HashSet::new, OrderBookState::compute_universe)
+        match &self.order_book_state {
+            Some(state) => state.compute_universe(),
+            None if self.lazy.is_some() => self.market_info().iter().map(|market| Coin::new(&market.coin)).collect(),
+            None => HashSet::new(),
+        }
     }
 
     #[allow(clippy::type_complexity)]
@@ -263,51 +365,313 @@ impl OrderBookListener {
         None
     }
 
-    fn receive_batch(&mut self, updates: EventBatch) -> Result<()> {
-        match updates {
-            EventBatch::Orders(batch) => {
-                self.order_status_cache.push(batch);
-            }
-            EventBatch::BookDiffs(batch) => {
-                self.order_diff_cache.push(batch);
-            }
+    // blocks that were already received (e.g. from another upstream) are dropped
+    // `trace` is the span of the read, which the messages carry on to the clients, and `ingest_time` when it started
+    fn receive_batch(&mut self, updates: EventBatch, trace: &Span, ingest_time: u64) -> Result<()> {
+        let height = match &updates {
+            EventBatch::Orders(batch) => batch.block_number(),
+            EventBatch::BookDiffs(batch) => batch.block_number(),
+            EventBatch::Fills(batch) => batch.block_number(),
+        };
+        self.check_gap(&updates, height);
+        let block_time = updates.block_time();
+        let stamps = Stamps { node_time: block_time, ingest_time };
+        let is_new = match updates {
+            EventBatch::Orders(batch) => self.order_status_cache.push(batch),
+            EventBatch::BookDiffs(batch) => self.order_diff_cache.push(batch),
             EventBatch::Fills(batch) => {
-                if self.last_fill.is_none_or(|height| height < batch.block_number()) {
+                let is_new = self.last_fill.is_none_or(|last_fill| last_fill < batch.block_number());
+                if is_new {
+                    self.last_fill = Some(batch.block_number());
+                    if let Some(candles) = &mut self.candles {
+                        let candles = candles.on_fills(&batch);
+                        if !candles.is_empty()
+                            && let Some(tx) = &self.internal_message_tx
+                            && tx.send(InternalMessage::Candles { candles })
+                        {
+                            METRICS.messages_broadcast.with_label_values(&["candles"]).inc();
+                        }
+                    }
+                    if let Some(analytics) = &mut self.analytics {
+                        analytics.on_fills(&batch);
+                    }
                     // send fill updates if we received a new update
                     if let Some(tx) = &self.internal_message_tx {
                         let tx = tx.clone();
+                        let trace = trace.clone();
                         tokio::spawn(async move {
-                            let snapshot = Arc::new(InternalMessage::Fills { batch });
-                            let _unused = tx.send(snapshot);
+                            let fills =
+                                InternalMessage::Fills { batch, trace, stamps, shared: SharedResponses::default() };
+                            if tx.send(fills) {
+                                METRICS.messages_broadcast.with_label_values(&["fills"]).inc();
+                            }
                         });
                     }
                 }
+                is_new
             }
+        };
+        if is_new && height >= self.latest_block {
+            self.latest_block = height;
+            self.latest_block_time = Some(block_time);
+        }
+        if self.restored.is_some() {
+            self.catch_up_restored();
         }
         if self.is_ready()
             && let Some((order_statuses, order_diffs)) = self.pop_cache()
+            && let Some(state) = self.order_book_state.as_mut()
         {
-            self.order_book_state
-                .as_mut()
-                .map(|book| book.apply_updates(order_statuses.clone(), order_diffs.clone()))
-                .transpose()?;
+            let prev_height = state.height();
+            state.apply_updates(order_statuses.clone(), order_diffs.clone())?;
+            let applied = state.height() > prev_height;
+            let crossed = if applied { state.crossed_books() } else { Vec::new() };
             if let Some(cache) = &mut self.fetched_snapshot_cache {
                 cache.push_back((order_statuses.clone(), order_diffs.clone()));
             }
-            if let Some(tx) = &self.internal_message_tx {
-                let tx = tx.clone();
-                tokio::spawn(async move {
-                    let updates = Arc::new(InternalMessage::L4BookUpdates {
-                        diff_batch: order_diffs,
-                        status_batch: order_statuses,
-                    });
-                    let _unused = tx.send(updates);
-                });
+            if self.crossed_books.on_block(crossed, height) {
+                warn!("Withholding the books until a new snapshot from the node replaces them");
+                self.drop_books();
+                return Ok(());
+            }
+            // sent synchronously (rather than from a spawned task) so that sequence numbers arrive in order
+            if applied
+                && let Some(state) = self.order_book_state.as_mut()
+                && let Some(tx) = &self.internal_message_tx
+            {
+                let updates = state.book_updates(order_statuses, order_diffs);
+                let l3_updates = state.l3_updates(&updates);
+                // journaled first, so a replay that reaches the end of the journal has seen everything broadcast
+                if let Some(journal) = &self.journal
+                    && let Err(err) = journal.append(&updates)
+                {
+                    error!("Unable to journal book updates: {err}");
+                }
+                if let Some(catch_up) = &mut self.catch_up {
+                    catch_up.append(&updates);
+                }
+                let (mut updates, mut l3_updates) = (updates, l3_updates);
+                if !self.halts.is_empty() {
+                    updates.retain(|coin, _| !is_halted(&self.halts, coin));
+                    l3_updates.retain(|coin, _| !is_halted(&self.halts, coin));
+                }
+                if tx.send(InternalMessage::L4BookUpdates {
+                    updates,
+                    trace: trace.clone(),
+                    stamps,
+                    shared: SharedResponses::default(),
+                }) {
+                    METRICS.messages_broadcast.with_label_values(&["l4_book_updates"]).inc();
+                }
+                let shared = SharedResponses::default();
+                let l3 = InternalMessage::L3BookUpdates { updates: l3_updates, trace: trace.clone(), stamps, shared };
+                if tx.send(l3) {
+                    METRICS.messages_broadcast.with_label_values(&["l3_book_updates"]).inc();
+                }
             }
         }
         Ok(())
     }
 
+    // the node writes every block to every event source, and blocks that were already received are dropped, so a
+    // block past the next one means the blocks in between will never arrive. The book can't be continued past them,
+    // and is dropped until a new snapshot from the node replaces it, rather than serving a book that misses orders
+    fn check_gap(&mut self, updates: &EventBatch, height: u64) {
+        let (event_source, last) = match updates {
+            EventBatch::Orders(_) => (EventSource::OrderStatuses, self.order_status_cache.last_block()),
+            EventBatch::BookDiffs(_) => (EventSource::OrderDiffs, self.order_diff_cache.last_block()),
+            EventBatch::Fills(_) => (EventSource::Fills, self.last_fill),
+        };
+        let Some(last) = last.filter(|last| height > last + 1) else {
+            return;
+        };
+        METRICS.event_gaps.with_label_values(&[event_source.to_string()]).inc();
+        self.gaps += 1;
+        if matches!(event_source, EventSource::Fills) {
+            // fills don't make up the books, only trades and candles miss them
+            warn!("{event_source} skipped blocks {} to {}", last + 1, height - 1);
+            return;
+        }
+        warn!("{event_source} skipped blocks {} to {}, fetching a new snapshot", last + 1, height - 1);
+        self.drop_books();
+    }
+
+    // the books aren't served until a new snapshot from the node, fetched right away, replaces them
+    fn drop_books(&mut self) {
+        if self.order_book_state.take().is_some() {
+            self.resyncing = true;
+        }
+        self.restored = None;
+        self.resync_requested = true;
+    }
+
+    // whether a gap or a crossed book asked for a snapshot since the last call
+    pub(super) fn take_resync_request(&mut self) -> bool {
+        std::mem::take(&mut self.resync_requested)
+    }
+
+    // applies the cached events to the restored state. It is taken up once they are known to continue it,
+    // and dropped if they don't, leaving the book to the next snapshot from the node
+    fn catch_up_restored(&mut self) {
+        let Some(mut state) = self.restored.take() else {
+            return;
+        };
+        let height = state.height();
+        while let Some((order_statuses, order_diffs)) = self.pop_cache() {
+            if let Err(err) = state.apply_updates(order_statuses, order_diffs) {
+                warn!("Unable to continue the saved order book state of block {height}: {err}");
+                return;
+            }
+        }
+        if state.height() > height {
+            info!("Order book restored, continuing from block {}", state.height());
+            self.set_state(state);
+        } else {
+            self.restored = Some(state);
+        }
+    }
+
+    pub(super) const fn set_stale(&mut self, stale: bool) {
+        self.stale = stale;
+    }
+
+    pub(super) fn set_upstreams(&mut self, upstreams: Vec<UpstreamStatus>) {
+        self.upstreams = upstreams;
+    }
+
+    // name of the market the listener reads, `None` for the primary one
+    pub(crate) fn market(&self) -> Option<&str> {
+        self.coin_prefix.as_deref().and_then(|prefix| prefix.strip_suffix(':'))
+    }
+
+    // broadcasts the health of the feed, which every client gets if the stream went stale or recovered since the
+    // last time
+    pub(crate) fn send_status(&mut self, maintenance: bool) {
+        if self.is_unloaded() {
+            return;
+        }
+        #[allow(clippy::cast_sign_loss)]
+        let time = Utc::now().timestamp_millis() as u64;
+        let status = StreamStatus {
+            market: self.market().map(str::to_string),
+            stale: self.stale,
+            ready: self.is_ready(),
+            last_block: self.latest_block,
+            last_block_time: self.latest_block_time,
+            lag_ms: self.latest_block_time.map(|block_time| time.saturating_sub(block_time)),
+            upstreams: self.upstreams.clone(),
+            snapshot_in_progress: self.fetched_snapshot_cache.is_some(),
+            gaps: self.gaps,
+            crossed_books: self.crossed_books.coins(),
+            maintenance,
+            time,
+        };
+        let stale_changed = self.last_status.as_ref().is_some_and(|last| last.stale != status.stale);
+        self.last_status = Some(status.clone());
+        if let Some(tx) = &self.internal_message_tx
+            && tx.send(InternalMessage::Status { status, stale_changed })
+        {
+            METRICS.messages_broadcast.with_label_values(&["status"]).inc();
+        }
+    }
+
+    pub(crate) fn market_info(&self) -> &[MarketInfo] {
+        self.market_info.as_deref().unwrap_or_default()
+    }
+
+    // replaces the markets with those of newly fetched metadata, and tells the clients what changed since the
+    // previous fetch. Markets without a book are halted, once there is a book
+    pub(crate) fn set_market_info(&mut self, markets: Vec<MarketInfo>) {
+        let universe = self.universe();
+        let markets = markets
+            .into_iter()
+            .filter(|market| !self.ignore_spot || !Coin::new(&market.coin).is_spot())
+            .map(|mut market| {
+                if let Some(prefix) = &self.coin_prefix {
+                    market.coin = format!("{prefix}{}", market.coin);
+                }
+                if market.status == MarketStatus::Active
+                    && self.is_ready()
+                    && !universe.contains(&Coin::new(&market.coin))
+                {
+                    market.status = MarketStatus::Halted;
+                }
+                market
+            })
+            .collect::<Vec<_>>();
+        let changes = self.market_info.as_ref().map(|previous| market_changes(previous, &markets)).unwrap_or_default();
+        let halted = markets
+            .iter()
+            .filter(|market| market.status == MarketStatus::Halted)
+            .map(|market| market.coin.clone())
+            .collect();
+        self.market_info = Some(markets);
+        if !changes.is_empty()
+            && let Some(tx) = &self.internal_message_tx
+            && tx.send(InternalMessage::MarketChanges { changes })
+        {
+            METRICS.messages_broadcast.with_label_values(&["market_changes"]).inc();
+        }
+        self.set_halts(HaltReason::Market, &halted);
+    }
+
+    // the halt of a market whose book data is held back, the first one if there are several
+    pub(crate) fn halt(&self, coin: &str) -> Option<MarketHalt> {
+        self.halts.iter().find(|halt| halt.coin == coin).cloned()
+    }
+
+    // replaces the markets halted for a reason. Subscribers are told when the first halt of a market starts, and
+    // when its last one ends
+    fn set_halts(&mut self, reason: HaltReason, coins: &BTreeSet<String>) {
+        let time = now_ms();
+        let (lifted, halts): (Vec<_>, Vec<_>) = std::mem::take(&mut self.halts)
+            .into_iter()
+            .partition(|halt| halt.reason == reason && !coins.contains(&halt.coin));
+        self.halts = halts;
+        for coin in coins {
+            if self.halts.iter().any(|halt| halt.reason == reason && halt.coin == *coin) {
+                continue;
+            }
+            let halt = MarketHalt { coin: coin.clone(), reason, time };
+            if !is_halted(&self.halts, coin) {
+                warn!("Holding back the book data of {coin}: {reason:?} halt");
+                self.send_halt(halt.clone(), false);
+            }
+            self.halts.push(halt);
+        }
+        for halt in lifted {
+            if !is_halted(&self.halts, &halt.coin) {
+                info!("Resuming the book data of {}", halt.coin);
+                self.send_halt(MarketHalt { time, ..halt }, true);
+            }
+        }
+    }
+
+    fn send_halt(&self, halt: MarketHalt, resumed: bool) {
+        if let Some(tx) = &self.internal_message_tx
+            && tx.send(InternalMessage::Halt { halt, resumed })
+        {
+            METRICS.messages_broadcast.with_label_values(&["halts"]).inc();
+        }
+    }
+
+    // markets the node has a book for again resume right away, rather than at the next fetch of the metadata
+    fn lift_market_halts<V>(&mut self, books: &HashMap<Coin, V>) {
+        let halted = self.halts.iter().filter(|halt| halt.reason == HaltReason::Market);
+        if halted.clone().any(|halt| books.contains_key(&Coin::new(&halt.coin))) {
+            let halted = halted
+                .filter(|halt| !books.contains_key(&Coin::new(&halt.coin)))
+                .map(|halt| halt.coin.clone())
+                .collect();
+            self.set_halts(HaltReason::Market, &halted);
+        }
+    }
+
+    // the status last sent, for clients that just subscribed
+    pub(crate) fn last_status(&self) -> Option<StreamStatus> {
+        self.last_status.clone()
+    }
+
     fn begin_caching(&mut self) {
         self.fetched_snapshot_cache = Some(VecDeque::new());
     }
@@ -317,8 +681,14 @@ impl OrderBookListener {
         self.fetched_snapshot_cache.take().unwrap_or_default()
     }
 
-    fn init_from_snapshot(&mut self, snapshot: Snapshots<InnerL4Order>, height: u64) {
+    // returns whether the book replaced one that was dropped for a gap
+    fn init_from_snapshot(&mut self, snapshot: Snapshots<InnerL4Order>, height: u64) -> bool {
+        // another fetch got there first, or one of a lazy market's books that were dropped since
+        if self.is_ready() || self.is_unloaded() {
+            return false;
+        }
         info!("No existing snapshot");
+        self.restored = None;
         let mut new_order_book = OrderBookState::from_snapshot(snapshot, height, 0, true, self.ignore_spot);
         let mut retry = false;
         while let Some((order_statuses, order_diffs)) = self.pop_cache() {
@@ -330,10 +700,76 @@ impl OrderBookListener {
                 break;
             }
         }
-        if !retry {
-            self.order_book_state = Some(new_order_book);
-            info!("Order book ready");
+        if retry {
+            return false;
         }
+        self.set_state(new_order_book);
+        info!("Order book ready");
+        std::mem::take(&mut self.resyncing)
+    }
+
+    // reports how far the books diverged from the node's snapshot of block `height`, and replaces them with the
+    // snapshot, continued by the blocks `applied` since, if they diverged further than the heal threshold. Books
+    // that passed are rid of their stale levels. Returns whether the books were replaced
+    fn on_audit(
+        &mut self,
+        divergence: &BTreeMap<String, usize>,
+        snapshot: Snapshots<InnerL4Order>,
+        height: u64,
+        applied: Vec<(Batch<NodeDataOrderStatus>, Batch<NodeDataOrderDiff>)>,
+    ) -> bool {
+        for coin in self.divergent.iter().filter(|coin| !divergence.contains_key(*coin)) {
+            METRICS.book_divergence.with_label_values(&[coin]).set(0);
+        }
+        for (coin, orders) in divergence {
+            METRICS.book_divergence.with_label_values(&[coin]).set(i64::try_from(*orders).unwrap_or(i64::MAX));
+        }
+        self.divergent = divergence.keys().cloned().collect();
+        let orders = divergence.values().sum::<usize>();
+        let Some(current) = &mut self.order_book_state else {
+            // dropped for a gap since, and replaced by the next snapshot
+            return false;
+        };
+        if orders > 0 {
+            warn!("{orders} orders of {} books diverged from the snapshot of block {height}", divergence.len());
+        }
+        if orders <= self.audit.heal_threshold {
+            let stale = current.collect_stale();
+            if stale > 0 {
+                info!("Collected {stale} empty levels and orders without size");
+                METRICS.stale_levels_collected.inc_by(u64::try_from(stale).unwrap_or_default());
+            }
+            METRICS.book_audits.with_label_values(&[if orders == 0 { "consistent" } else { "diverged" }]).inc();
+            // a halted book stays halted until it is consistent again
+            let halted = self
+                .halts
+                .iter()
+                .filter(|halt| halt.reason == HaltReason::Audit && divergence.contains_key(&halt.coin))
+                .map(|halt| halt.coin.clone())
+                .collect();
+            self.set_halts(HaltReason::Audit, &halted);
+            return false;
+        }
+        let mut healed = OrderBookState::from_snapshot(snapshot, height, 0, true, self.ignore_spot);
+        for (order_statuses, order_diffs) in applied {
+            if let Err(err) = healed.apply_updates(order_statuses, order_diffs) {
+                warn!("Unable to continue the snapshot of block {height}, the books stay as they are: {err}");
+                METRICS.book_audits.with_label_values(&["diverged"]).inc();
+                self.set_halts(HaltReason::Audit, &divergence.keys().cloned().collect());
+                return false;
+            }
+        }
+        if healed.height() != current.height() {
+            warn!("The snapshot of block {height} didn't reach block {}, the books stay as they are", current.height());
+            METRICS.book_audits.with_label_values(&["diverged"]).inc();
+            self.set_halts(HaltReason::Audit, &divergence.keys().cloned().collect());
+            return false;
+        }
+        info!("Replaced the books with the snapshot of block {height}");
+        healed.continue_from(current);
+        self.set_state(healed);
+        METRICS.book_audits.with_label_values(&["healed"]).inc();
+        true
     }
 
     // forcibly grab current snapshot
@@ -341,74 +777,80 @@ impl OrderBookListener {
         self.order_book_state.as_mut().map(|o| o.compute_snapshot())
     }
 
-    // prevent snapshotting mutiple times at the same height
-    fn l2_snapshots(&mut self, prevent_future_snaps: bool) -> Option<(u64, L2Snapshots)> {
-        self.order_book_state.as_mut().and_then(|o| o.l2_snapshots(prevent_future_snaps))
+    // current l2 book of a single coin - (time, seq, snapshot)
+    pub(crate) fn l2_snapshot(
+        &self,
+        coin: &Coin,
+        n_levels: usize,
+        n_sig_figs: Option<u32>,
+        mantissa: Option<u64>,
+    ) -> Option<(u64, u64, Snapshot<InnerLevel>)> {
+        self.order_book_state.as_ref().and_then(|o| o.l2_snapshot(coin, n_levels, n_sig_figs, mantissa))
     }
-}
 
-impl OrderBookListener {
-    fn process_update(&mut self, event: &Event, new_path: &PathBuf, event_source: EventSource) -> Result<()> {
-        if event.kind.is_create() {
-            info!("-- Event: {} created --", new_path.display());
-            self.on_file_creation(new_path.clone(), event_source)?;
-        }
-        // Check for `Modify` event (only if the file is already initialized)
-        else {
-            // If we are not tracking anything right now, we treat a file update as declaring that it has been created.
-            // Unfortunately, we miss the update that occurs at this time step.
-            // We go to the end of the file to read for updates after that.
-            if self.is_reading(event_source) {
-                self.on_file_modification(event_source)?;
-            } else {
-                info!("-- Event: {} modified, tracking it now --", new_path.display());
-                let file = self.file_mut(event_source);
-                let mut new_file = File::open(new_path)?;
-                new_file.seek(SeekFrom::End(0))?;
-                *file = Some(new_file);
-            }
+    // current l2 book of a single coin aggregated by a tick size - (time, seq, snapshot)
+    pub(crate) fn tick_snapshot(
+        &self,
+        coin: &Coin,
+        tick: Px,
+        n_levels: usize,
+    ) -> Option<(u64, u64, Snapshot<InnerLevel>)> {
+        self.order_book_state.as_ref().and_then(|o| o.tick_snapshot(coin, tick, n_levels))
+    }
+
+    // l2 snapshots include the book of this coin aggregated by `tick` from then on
+    pub(crate) fn add_tick_group(&mut self, coin: &Coin, tick: Px) -> Result<()> {
+        // a lazy market's books that are being loaded get the group once they are
+        if !self.is_loading() {
+            self.order_book_state.as_mut().ok_or("Order book not ready")?.add_tick_group(coin, tick)?;
         }
+        self.tick_groups.insert((coin.clone(), tick));
         Ok(())
     }
-}
 
-impl DirectoryListener for OrderBookListener {
-    fn is_reading(&self, event_source: EventSource) -> bool {
-        match event_source {
-            EventSource::Fills => self.fill_status_file.is_some(),
-            EventSource::OrderStatuses => self.order_status_file.is_some(),
-            EventSource::OrderDiffs => self.order_diff_file.is_some(),
+    fn set_state(&mut self, mut state: OrderBookState) {
+        for (coin, tick) in &self.tick_groups {
+            if let Err(err) = state.add_tick_group(coin, *tick) {
+                warn!("Unable to aggregate {} by {tick:?}: {err}", coin.value());
+            }
+        }
+        if let Some(catch_up) = &mut self.catch_up {
+            catch_up.clear();
         }
+        self.crossed_books.reset(state.all_crossed());
+        self.order_book_state = Some(state);
+        // the books of the node replace the ones that diverged from them
+        self.set_halts(HaltReason::Audit, &BTreeSet::new());
     }
 
-    fn file_mut(&mut self, event_source: EventSource) -> &mut Option<File> {
-        match event_source {
-            EventSource::Fills => &mut self.fill_status_file,
-            EventSource::OrderStatuses => &mut self.order_status_file,
-            EventSource::OrderDiffs => &mut self.order_diff_file,
-        }
+    // sequence number of the last l4 update sent for this coin
+    pub(crate) fn l4_seq(&self, coin: &Coin) -> u64 {
+        self.order_book_state.as_ref().map_or(0, |o| o.l4_seq(coin))
     }
 
-    fn on_file_creation(&mut self, new_file: PathBuf, event_source: EventSource) -> Result<()> {
-        if let Some(file) = self.file_mut(event_source).as_mut() {
-            let mut buf = String::new();
-            file.read_to_string(&mut buf)?;
-            if !buf.is_empty() {
-                self.process_data(buf, event_source)?;
-            }
-        }
-        *self.file_mut(event_source) = Some(File::open(new_file)?);
-        Ok(())
+    // prevent snapshotting mutiple times at the same height
+    fn l2_snapshots(&mut self, prevent_future_snaps: bool) -> Option<(u64, u64, L2Snapshots)> {
+        self.order_book_state.as_mut().and_then(|o| o.l2_snapshots(prevent_future_snaps))
     }
+}
 
-    fn process_data(&mut self, data: String, event_source: EventSource) -> Result<()> {
-        let total_len = data.len();
-        let lines = data.lines();
-        for line in lines {
+impl OrderBookListener {
+    // parses and applies the lines of data read from an upstream's event file
+    pub(super) fn process_data(&mut self, data: &str, event_source: EventSource) -> Result<()> {
+        let ingest_time = now_ms();
+        // open until the last client is done with the messages of this read
+        let trace = info_span!(target: PIPELINE, "ingest", source = %event_source, bytes = data.len());
+        let _trace = trace.enter();
+        // the complete lines, which are relayed as they are
+        let mut consumed = 0;
+        for line in data.split_inclusive('\n') {
+            let len = line.len();
+            let line = line.trim_end_matches(['\n', '\r']);
             if line.is_empty() {
+                consumed += len;
                 continue;
             }
-            let res = match event_source {
+            let res = info_span!(target: PIPELINE, "decode").in_scope(|| match event_source {
                 EventSource::Fills => serde_json::from_str::<Batch<NodeDataFill>>(line).map(|batch| {
                     let height = batch.block_number();
                     (height, EventBatch::Fills(batch))
@@ -417,47 +859,89 @@ impl DirectoryListener for OrderBookListener {
                     .map(|batch: Batch<NodeDataOrderStatus>| (batch.block_number(), EventBatch::Orders(batch))),
                 EventSource::OrderDiffs => serde_json::from_str(line)
                     .map(|batch: Batch<NodeDataOrderDiff>| (batch.block_number(), EventBatch::BookDiffs(batch))),
-            };
-            let (height, event_batch) = match res {
+            });
+            let (height, mut event_batch) = match res {
                 Ok(data) => data,
                 Err(err) => {
                     // Build a safe preview of the line (up to 100 *characters*).
                     let preview: String = line.chars().take(100).collect();
 
-                    // if we run into a serialization error (hitting EOF), just return to last line.
+                    // the rest of the data is dropped, and the gap it leaves is filled by a snapshot
                     error!(
                         "{event_source} serialization error {err}, height: {:?}, line: {:?}",
                         self.order_book_state.as_ref().map(OrderBookState::height),
                         preview,
                     );
-                    #[allow(clippy::unwrap_used)]
-                    let total_len: i64 = total_len.try_into().unwrap();
-                    self.file_mut(event_source).as_mut().map(|f| f.seek_relative(-total_len));
                     break;
                 }
             };
             if height % 100 == 0 {
                 info!("{event_source} block: {height}");
             }
-            if let Err(err) = self.receive_batch(event_batch) {
+            let _span = debug_span!("event", source = %event_source, block = height).entered();
+            consumed += len;
+            if let Some(prefix) = &self.coin_prefix {
+                event_batch.prefix_coins(prefix);
+            }
+            METRICS.set_node_event_lag(event_source, event_batch.block_time());
+            let res = info_span!(target: PIPELINE, "apply", block = height)
+                .in_scope(|| self.receive_batch(event_batch, &trace, ingest_time));
+            if let Err(err) = res {
                 self.order_book_state = None;
                 return Err(err);
             }
         }
-        let snapshot = self.l2_snapshots(true);
-        if let Some(snapshot) = snapshot
+        if let Some(relay) = &mut self.relay {
+            relay.send(event_source, &data[..consumed]);
+        }
+        let snapshot = info_span!(target: PIPELINE, "l2_books").in_scope(|| self.l2_snapshots(true));
+        if let Some((_, _, l2_snapshots)) = &snapshot {
+            self.lift_market_halts(l2_snapshots.as_ref());
+        }
+        if let Some((time, seq, mut l2_snapshots)) = snapshot
             && let Some(tx) = &self.internal_message_tx
         {
-            let tx = tx.clone();
-            tokio::spawn(async move {
-                let snapshot = Arc::new(InternalMessage::Snapshot { l2_snapshots: snapshot.1, time: snapshot.0 });
-                let _unused = tx.send(snapshot);
-            });
+            let block = self.order_book_state.as_ref().map(OrderBookState::height);
+            let _span = info_span!(target: PIPELINE, "publish", block, seq).entered();
+            let (trace, stamps) = (trace.clone(), Stamps { node_time: time, ingest_time });
+            // the snapshots are split between the shards, so the coins that are listed go separately
+            let coins = l2_snapshots.as_ref();
+            if self.published_coins.as_ref().is_none_or(|published| {
+                published.len() != coins.len() || coins.keys().any(|coin| !published.contains(coin))
+            }) {
+                let coins = coins.keys().cloned().collect::<HashSet<_>>();
+                self.published_coins = Some(coins.clone());
+                tx.send(InternalMessage::Universe { coins });
+            }
+            let analytics =
+                self.analytics.as_mut().map(|analytics| analytics.on_books(time, coins)).unwrap_or_default();
+            let checkpoints = checkpoint_books(self.checkpoints.as_mut(), self.order_book_state.as_ref(), time, coins);
+            if !self.halts.is_empty() {
+                l2_snapshots.0.retain(|coin, _| !is_halted(&self.halts, coin.as_str()));
+            }
+            if tx.send(InternalMessage::Snapshot {
+                l2_snapshots,
+                time,
+                seq,
+                trace,
+                stamps,
+                shared: SharedResponses::default(),
+            }) {
+                METRICS.messages_broadcast.with_label_values(&["l2_snapshots"]).inc();
+                debug!("Published l2 books");
+            }
+            if !analytics.is_empty() && tx.send(InternalMessage::Analytics { analytics }) {
+                METRICS.messages_broadcast.with_label_values(&["analytics"]).inc();
+            }
+            if !checkpoints.is_empty() && tx.send(InternalMessage::Checkpoints { checkpoints }) {
+                METRICS.messages_broadcast.with_label_values(&["checkpoints"]).inc();
+            }
         }
         Ok(())
     }
 }
 
+#[derive(Clone)]
 pub(crate) struct L2Snapshots(HashMap<Coin, HashMap<L2SnapshotParams, Snapshot<InnerLevel>>>);
 
 impl L2Snapshots {
@@ -472,15 +956,100 @@ pub(crate) struct TimedSnapshots {
     pub(crate) snapshot: Snapshots<InnerL4Order>,
 }
 
-// Messages sent from node data listener to websocket dispatch to support streaming
+// signed checkpoints of every book at `time`, if they are due
+fn checkpoint_books(
+    checkpoints: Option<&mut Checkpoints>,
+    state: Option<&OrderBookState>,
+    time: u64,
+    books: &HashMap<Coin, HashMap<L2SnapshotParams, Snapshot<InnerLevel>>>,
+) -> Vec<Checkpoint> {
+    let (Some(checkpoints), Some(state)) = (checkpoints, state) else {
+        return Vec::new();
+    };
+    if !checkpoints.is_due(time) {
+        return Vec::new();
+    }
+    let params = L2SnapshotParams::new(None, None, None);
+    let published = books
+        .iter()
+        .filter_map(|(coin, snapshots)| {
+            let levels = snapshots.get(&params)?.clone().export_inner_snapshot();
+            Some(checkpoints.signer().checkpoint(coin.value(), time, state.height(), state.l4_seq(coin), &levels))
+        })
+        .collect::<Vec<_>>();
+    checkpoints.publish(&published);
+    published
+}
+
+fn market_changes(previous: &[MarketInfo], markets: &[MarketInfo]) -> Vec<MarketChange> {
+    let mut previous = previous.iter().map(|market| (market.coin.as_str(), market)).collect::<HashMap<_, _>>();
+    let mut changes = Vec::new();
+    for market in markets {
+        let change = match previous.remove(market.coin.as_str()) {
+            None => MarketChangeKind::Added,
+            Some(previous) if previous != market => MarketChangeKind::Changed,
+            Some(_) => continue,
+        };
+        changes.push(MarketChange { change, market: market.clone() });
+    }
+    // the ones left are gone from the metadata
+    let mut removed = previous.into_values().cloned().collect::<Vec<_>>();
+    removed.sort_by(|a, b| a.coin.cmp(&b.coin));
+    changes.extend(removed.into_iter().map(|market| MarketChange { change: MarketChangeKind::Removed, market }));
+    changes
+}
+
+// Messages sent from node data listener to websocket dispatch to support streaming. `trace` is the span of the
+// read of node events they come from, parent of the spans sending them to the clients, and `stamps` when the
+// events were produced and read. The messages for the connections are built from them once, by the first connection
+// that needs each of them, and kept in `shared`
 pub(crate) enum InternalMessage {
-    Snapshot { l2_snapshots: L2Snapshots, time: u64 },
-    Fills { batch: Batch<NodeDataFill> },
-    L4BookUpdates { diff_batch: Batch<NodeDataOrderDiff>, status_batch: Batch<NodeDataOrderStatus> },
+    Snapshot { l2_snapshots: L2Snapshots, time: u64, seq: u64, trace: Span, stamps: Stamps, shared: SharedResponses },
+    Fills { batch: Batch<NodeDataFill>, trace: Span, stamps: Stamps, shared: SharedResponses },
+    L4BookUpdates { updates: HashMap<String, L4BookUpdates>, trace: Span, stamps: Stamps, shared: SharedResponses },
+    // the same block's updates as changes to the orders, sent right after its l4 updates
+    L3BookUpdates { updates: HashMap<String, L3BookUpdates>, trace: Span, stamps: Stamps, shared: SharedResponses },
+    // the candles changed by a batch of fills
+    Candles { candles: Vec<Candle> },
+    // the metrics of the markets, every analytics interval
+    Analytics { analytics: Vec<MarketAnalytics> },
+    // signed checkpoints of the books, every checkpoint interval
+    Checkpoints { checkpoints: Vec<Checkpoint> },
+    // `stale_changed` if the stream went stale or recovered since the previous status
+    Status { status: StreamStatus, stale_changed: bool },
+    // the coins of a market, sent before its first snapshot and whenever they change
+    Universe { coins: HashSet<Coin> },
+    // the markets added to, changed in or removed from the node's metadata
+    MarketChanges { changes: Vec<MarketChange> },
+    // a market whose book data is held back from now on, or again sent with `resumed`
+    Halt { halt: MarketHalt, resumed: bool },
+}
+
+impl InternalMessage {
+    pub(crate) const fn trace(&self) -> Option<&Span> {
+        match self {
+            Self::Snapshot { trace, .. }
+            | Self::Fills { trace, .. }
+            | Self::L4BookUpdates { trace, .. }
+            | Self::L3BookUpdates { trace, .. } => Some(trace),
+            Self::Candles { .. }
+            | Self::Analytics { .. }
+            | Self::Checkpoints { .. }
+            | Self::Status { .. }
+            | Self::Universe { .. }
+            | Self::MarketChanges { .. }
+            | Self::Halt { .. } => None,
+        }
+    }
+}
+
+fn is_halted(halts: &[MarketHalt], coin: &str) -> bool {
+    halts.iter().any(|halt| halt.coin == coin)
 }
 
-#[derive(Eq, PartialEq, Hash)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub(crate) struct L2SnapshotParams {
     n_sig_figs: Option<u32>,
     mantissa: Option<u64>,
+    tick_size: Option<Px>,
 }
@@ -0,0 +1,294 @@
+use std::{collections::VecDeque, sync::Arc, time::Duration};
+
+use alloy::primitives::Address;
+use notify::{Event, RecommendedWatcher};
+use tokio::{
+    sync::{
+        Mutex,
+        mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+    },
+    time::{Instant, Interval, interval_at, sleep, sleep_until},
+};
+use tracing::{error, info, warn};
+
+#[cfg(feature = "chaos")]
+use crate::chaos;
+use crate::{
+    listeners::order_book::{
+        OrderBookListener,
+        audit::book_divergence,
+        source::{SourceEvent, UpstreamSource},
+        upstream::{Inactivity, InactivityPolicy, Upstream, UpstreamNode, watch_upstreams},
+        utils::{prefix_snapshot_coins, process_rmp_file},
+    },
+    order_book::multi_book::load_snapshots_from_json,
+    prelude::*,
+    types::{L4Order, inner::InnerL4Order, node_data::EventSource},
+};
+
+// The event files of the nodes, which are all read at once, as a source of events. The snapshots to start from, to
+// replace a book that fell behind and to audit the books against are fetched from the nodes and applied to the
+// listener directly, since the events applied in the meantime are cached by the listener itself.
+// WARNING - this code assumes no other file system operations are occurring in the watched directories
+// if there are scripts running, this may not work as intended
+pub(crate) struct NodeSource {
+    listener: Arc<Mutex<OrderBookListener>>,
+    upstreams: Vec<Upstream>,
+    // monitoring the directory via the notify crate (gives file system events)
+    fs_event_tx: UnboundedSender<notify::Result<Event>>,
+    fs_event_rx: UnboundedReceiver<notify::Result<Event>>,
+    watcher: RecommendedWatcher,
+    // every so often, we fetch a new snapshot and the snapshot_fetch_task starts running.
+    // Result is sent back along this channel (if error, we want to return to top level)
+    snapshot_fetch_task_tx: UnboundedSender<Result<bool>>,
+    snapshot_fetch_task_rx: UnboundedReceiver<Result<bool>>,
+    snapshot_params: (bool, Option<String>),
+    ticker: Interval,
+    inactivity_timeout: Duration,
+    inactivity: Inactivity,
+    // the lines read and not handed to the listener yet, with the upstream they were read from
+    read: VecDeque<(usize, EventSource, String)>,
+    // where the last lines handed to the listener were read from
+    last_read: Option<(usize, EventSource)>,
+    latest_block: u64,
+}
+
+impl NodeSource {
+    pub(crate) async fn new(
+        listener: Arc<Mutex<OrderBookListener>>,
+        upstreams: Vec<UpstreamNode>,
+        inactivity_timeout: Duration,
+        inactivity_policy: InactivityPolicy,
+    ) -> Result<Self> {
+        let upstreams = upstreams.into_iter().map(Upstream::new).collect::<Result<Vec<_>>>()?;
+        let (fs_event_tx, fs_event_rx) = unbounded_channel();
+        let watcher = watch_upstreams(&upstreams, &fs_event_tx)?;
+        let (snapshot_params, audit_interval) = {
+            let mut listener = listener.lock().await;
+            listener.set_upstreams(upstreams.iter().map(Upstream::status).collect());
+            ((listener.ignore_spot, listener.coin_prefix.clone()), listener.audit.interval)
+        };
+        let (snapshot_fetch_task_tx, snapshot_fetch_task_rx) = unbounded_channel();
+        let start = Instant::now() + Duration::from_secs(5);
+        Ok(Self {
+            listener,
+            upstreams,
+            fs_event_tx,
+            fs_event_rx,
+            watcher,
+            snapshot_fetch_task_tx,
+            snapshot_fetch_task_rx,
+            snapshot_params,
+            ticker: interval_at(start, audit_interval),
+            inactivity_timeout,
+            inactivity: Inactivity::new(inactivity_policy, inactivity_timeout),
+            read: VecDeque::new(),
+            last_read: None,
+            latest_block: 0,
+        })
+    }
+
+    // reads what changed in the file of an event
+    async fn read_event(&mut self, event: &Event) -> Result<()> {
+        if !event.kind.is_create() && !event.kind.is_modify() {
+            return Ok(());
+        }
+        let new_path = &event.paths[0];
+        let upstream = self.upstreams.iter().enumerate().find_map(|(i, upstream)| {
+            let event_source = upstream.event_source(new_path)?;
+            Some((i, event_source))
+        });
+        let Some((i, event_source)) = upstream.filter(|_| new_path.is_file()) else {
+            return Ok(());
+        };
+        let restoring = self.listener.lock().await.is_restoring();
+        let upstream = &mut self.upstreams[i];
+        let read = upstream
+            .process_update(event, new_path, event_source, restoring)
+            .map_err(|err| format!("{event_source} processing error ({}): {err}", upstream.node))?;
+        self.read.extend(read.into_iter().map(|(event_source, data)| (i, event_source, data)));
+        Ok(())
+    }
+
+    fn fetch_snapshot(&self, upstream: UpstreamNode) {
+        let (listener, tx) = (self.listener.clone(), self.snapshot_fetch_task_tx.clone());
+        fetch_snapshot(upstream, listener, tx, self.snapshot_params.clone());
+    }
+
+    // the listener applied the lines handed to it last, which may have been new blocks
+    async fn on_applied(&mut self) {
+        let mut listener = self.listener.lock().await;
+        if listener.latest_block() > self.latest_block {
+            self.latest_block = listener.latest_block();
+            self.inactivity.on_progress(&mut listener);
+        }
+        drop(listener);
+    }
+}
+
+impl UpstreamSource for NodeSource {
+    async fn next_event(&mut self) -> Result<Option<SourceEvent>> {
+        self.on_applied().await;
+        loop {
+            if let Some((i, event_source, data)) = self.read.pop_front() {
+                self.last_read = Some((i, event_source));
+                return Ok(Some(SourceEvent::Events { event_source, data }));
+            }
+            tokio::select! {
+                event = self.fs_event_rx.recv() =>  match event {
+                    Some(Ok(event)) => {
+                        #[cfg(feature = "chaos")]
+                        if !chaos::upstream_event().await {
+                            continue;
+                        }
+                        self.read_event(&event).await?;
+                    }
+                    Some(Err(err)) => {
+                        error!("Watcher error: {err}");
+                        return Err(format!("Watcher error: {err}").into());
+                    }
+                    None => {
+                        error!("Channel closed. Listener exiting");
+                        return Err("Channel closed.".into());
+                    }
+                },
+                snapshot_fetch_res = self.snapshot_fetch_task_rx.recv() => {
+                    match snapshot_fetch_res {
+                        None => {
+                            return Err("Snapshot fetch task sender dropped".into());
+                        }
+                        Some(Err(err)) => {
+                            return Err(format!("Abci state reading error: {err}").into());
+                        }
+                        Some(Ok(true)) => return Ok(Some(SourceEvent::Replaced)),
+                        Some(Ok(false)) => {}
+                    }
+                }
+                _ = self.ticker.tick() => {
+                    for upstream in &mut self.upstreams {
+                        upstream.check_health(self.inactivity_timeout);
+                    }
+                    // audit against the healthy upstream that is furthest ahead, preferring upstreams configured first
+                    let upstream = self
+                        .upstreams
+                        .iter()
+                        .rev()
+                        .filter(|upstream| upstream.is_healthy())
+                        .max_by_key(|upstream| upstream.last_block());
+                    if let Some(upstream) = upstream {
+                        self.fetch_snapshot(upstream.node.clone());
+                    }
+                    return Ok(Some(SourceEvent::Upstreams(self.upstreams.iter().map(Upstream::status).collect())));
+                }
+                () = sleep_until(self.inactivity.deadline()) => {
+                    if !self.inactivity.on_timeout(&mut *self.listener.lock().await)? {
+                        continue;
+                    }
+                    // the watcher may have stopped delivering events, e.g. after a directory was recreated
+                    match watch_upstreams(&self.upstreams, &self.fs_event_tx) {
+                        // the old watcher stops once dropped
+                        Ok(new_watcher) => drop(std::mem::replace(&mut self.watcher, new_watcher)),
+                        Err(err) => warn!("Unable to watch the node's files again: {err}"),
+                    }
+                    #[cfg(feature = "chaos")]
+                    if chaos::is_upstream_disconnected() {
+                        continue;
+                    }
+                    for (i, upstream) in self.upstreams.iter_mut().enumerate() {
+                        let read = upstream.poll().map_err(|err| format!("Processing error ({}): {err}", upstream.node))?;
+                        self.read.extend(read.into_iter().map(|(event_source, data)| (i, event_source, data)));
+                    }
+                }
+            }
+        }
+    }
+
+    // the node's events are applied as they were written, so there is no starting over from them
+    fn resync(&mut self, err: Error) -> Result<()> {
+        let Some((i, event_source)) = self.last_read else {
+            return Err(err);
+        };
+        Err(format!("{event_source} processing error ({}): {err}", self.upstreams[i].node).into())
+    }
+
+    // the node that skipped the blocks has them in its snapshot
+    fn skipped_blocks(&mut self) -> Result<()> {
+        let (i, _) = self.last_read.ok_or("no events read")?;
+        self.fetch_snapshot(self.upstreams[i].node.clone());
+        Ok(())
+    }
+}
+
+// `coin_prefix` namespaces the snapshot's coins like the market's events. Sends whether the snapshot replaced a
+// book, one that was dropped for a gap or one that diverged from it
+fn fetch_snapshot(
+    upstream: UpstreamNode,
+    listener: Arc<Mutex<OrderBookListener>>,
+    tx: UnboundedSender<Result<bool>>,
+    (ignore_spot, coin_prefix): (bool, Option<String>),
+) {
+    let tx = tx.clone();
+    tokio::spawn(async move {
+        let res = match process_rmp_file(&upstream).await {
+            Ok(output_fln) => {
+                let state = {
+                    let mut listener = listener.lock().await;
+                    listener.begin_caching();
+                    listener.clone_state()
+                };
+                let snapshot = load_snapshots_from_json::<InnerL4Order, (Address, L4Order)>(&output_fln).await.map(
+                    |(height, snapshot)| match &coin_prefix {
+                        Some(prefix) => (height, prefix_snapshot_coins(snapshot, prefix)),
+                        None => (height, snapshot),
+                    },
+                );
+                info!("Snapshot fetched from {upstream}");
+                // sleep to let some updates build up.
+                sleep(Duration::from_secs(1)).await;
+                // a gap while fetching dropped the book, which the snapshot replaces instead of auditing it. The blocks
+                // applied during the audit are cached too, to continue the snapshot with if it heals the book
+                let (mut cache, ready) = {
+                    let mut listener = listener.lock().await;
+                    let cache = listener.take_cache();
+                    if listener.is_ready() {
+                        listener.begin_caching();
+                    }
+                    (cache, listener.is_ready())
+                };
+                info!("Cache has {} elements", cache.len());
+                match snapshot {
+                    Ok((height, expected_snapshot)) => {
+                        if let Some(mut state) = state
+                            && ready
+                        {
+                            while state.height() < height {
+                                if let Some((order_statuses, order_diffs)) = cache.pop_front() {
+                                    state.apply_updates(order_statuses, order_diffs)?;
+                                } else {
+                                    return Err::<(), Error>("Not enough cached updates".into());
+                                }
+                            }
+                            if state.height() > height {
+                                return Err("Fetched snapshot lagging stored state".into());
+                            }
+                            let stored_snapshot = state.compute_snapshot().snapshot;
+                            info!("Auditing the books");
+                            let divergence = book_divergence(&stored_snapshot, &expected_snapshot, ignore_spot);
+                            let mut listener = listener.lock().await;
+                            let applied = cache.into_iter().chain(listener.take_cache()).collect();
+                            Ok(listener.on_audit(&divergence, expected_snapshot, height, applied))
+                        } else {
+                            let mut listener = listener.lock().await;
+                            listener.take_cache();
+                            Ok(listener.init_from_snapshot(expected_snapshot, height))
+                        }
+                    }
+                    Err(err) => Err(err),
+                }
+            }
+            Err(err) => Err(err),
+        };
+        let _unused = tx.send(res);
+        Ok(())
+    });
+}
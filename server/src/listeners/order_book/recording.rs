@@ -0,0 +1,246 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::{
+    fs::File,
+    io::{AsyncWriteExt, BufReader, BufWriter},
+    net::{TcpListener, TcpStream},
+    select,
+    sync::{Mutex, broadcast::error::RecvError},
+    task::spawn_blocking,
+    time::{Instant, interval, sleep, sleep_until},
+};
+use tracing::{info, warn};
+
+use crate::{
+    listeners::order_book::{
+        InactivityPolicy, NodeSource, OrderBookListener, UpstreamNode, listen_source,
+        relay::{PAYLOAD_TIMEOUT, PING_INTERVAL, event_source_name, read_message, write_message},
+    },
+    prelude::*,
+};
+
+// A recording is a relay feed with the milliseconds since the recording started in front of every message:
+// `<ms> <kind> [<args>] <payload length>` followed by the payload, without the pings. It starts with the snapshot,
+// so an edge following the replayed feed builds the same books, with the same sequence numbers, as the recorded
+// instance did.
+
+// a relay feed pings every second
+const RELAY_TIMEOUT: Duration = Duration::from_secs(10);
+const NODE_INACTIVITY: Duration = Duration::from_mins(1);
+const READY_POLL: Duration = Duration::from_millis(100);
+
+/// Where a feed is recorded from.
+#[derive(Debug, Clone)]
+pub enum RecordSource {
+    /// A node's event files, read by a listener of the recorder's own.
+    Node(UpstreamNode),
+    /// The relay feed of an instance started with `--relay-port`, as `host:port`.
+    Relay(String),
+}
+
+/// Records the feed of `source` to the file at `path`, until `stop` completes or the source ends.
+pub async fn record_feed(source: RecordSource, path: &Path, stop: impl Future<Output = ()>) -> Result<()> {
+    let mut file = BufWriter::new(File::create(path).await?);
+    let start = Instant::now();
+    info!("Recording to {}", path.display());
+    let res = select! {
+        res = async {
+            match source {
+                RecordSource::Node(node) => record_node(node, &mut file, start).await,
+                RecordSource::Relay(address) => record_relay(&address, &mut file, start).await,
+            }
+        } => res,
+        () = stop => Ok(()),
+    };
+    file.flush().await?;
+    info!("Recorded {:?} of the feed", start.elapsed());
+    res
+}
+
+async fn record(file: &mut BufWriter<File>, start: Instant, header: &str, payload: &[u8]) -> Result<()> {
+    write_message(file, &format!("{} {header}", start.elapsed().as_millis()), payload).await
+}
+
+async fn record_relay(address: &str, file: &mut BufWriter<File>, start: Instant) -> Result<()> {
+    let mut reader = BufReader::new(TcpStream::connect(address).await?);
+    while let Some((header, payload)) = read_message(&mut reader, RELAY_TIMEOUT).await? {
+        if header != "PING" {
+            record(file, start, &header, &payload).await?;
+        }
+    }
+    Ok(())
+}
+
+// follows the node like an ingest instance, and records the relay feed it would serve
+async fn record_node(node: UpstreamNode, file: &mut BufWriter<File>, start: Instant) -> Result<()> {
+    let mut listener = OrderBookListener::new(None, false);
+    listener.enable_relay();
+    let listener = Arc::new(Mutex::new(listener));
+    let source = NodeSource::new(listener.clone(), vec![node], NODE_INACTIVITY, InactivityPolicy::Exit).await?;
+    let mut listening = tokio::spawn(listen_source(listener.clone(), source, || {}));
+    let (snapshot, mut rx) = loop {
+        if let Some(subscription) = OrderBookListener::relay_subscribe(&listener).await {
+            break subscription;
+        }
+        select! {
+            res = &mut listening => return res?,
+            () = sleep(READY_POLL) => {}
+        }
+    };
+    let snapshot = spawn_blocking(move || snapshot.encode()).await??;
+    record(file, start, "SNAPSHOT", &snapshot).await?;
+    loop {
+        select! {
+            events = rx.recv() => match events {
+                Ok(events) => {
+                    let header = format!("EVENTS {} {}", events.seq, event_source_name(events.event_source));
+                    record(file, start, &header, events.data.as_bytes()).await?;
+                }
+                Err(RecvError::Lagged(n)) => return Err(format!("fell behind by {n} event chunks").into()),
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            res = &mut listening => return res?,
+        }
+    }
+}
+
+/// Serves the recording at `path` as a relay feed, for instances started with `--relay-from`.
+///
+/// Every connection gets the whole recording from its start, `speed` times as fast as it was recorded (as fast as possible for 0),
+/// and then pings until it disconnects.
+pub async fn replay_feed(path: PathBuf, tcp_listener: TcpListener, speed: f64) -> Result<()> {
+    info!("Replaying {} at {}", path.display(), tcp_listener.local_addr()?);
+    loop {
+        let (stream, peer) = tcp_listener.accept().await?;
+        let path = path.clone();
+        tokio::spawn(async move {
+            info!("Replaying to {peer}");
+            match replay(stream, &path, speed).await {
+                Ok(()) => info!("{peer} disconnected from the replay"),
+                Err(err) => warn!("{peer} disconnected from the replay: {err}"),
+            }
+        });
+    }
+}
+
+async fn replay(stream: TcpStream, path: &Path, speed: f64) -> Result<()> {
+    stream.set_nodelay(true)?;
+    let mut write = BufWriter::new(stream);
+    let mut reader = BufReader::new(File::open(path).await?);
+    let mut ping = interval(PING_INTERVAL);
+    let start = Instant::now();
+    loop {
+        let (header, payload) = match read_message(&mut reader, PAYLOAD_TIMEOUT).await {
+            Ok(Some(message)) => message,
+            Ok(None) => break,
+            // the recorder was stopped in the middle of a message
+            Err(err) => {
+                warn!("Recording ends with an incomplete message: {err}");
+                break;
+            }
+        };
+        let (ms, header) = header.split_once(' ').ok_or_else(|| format!("malformed recording line {header:?}"))?;
+        if speed > 0.0 {
+            let deadline = start + Duration::from_millis(ms.parse()?).div_f64(speed);
+            loop {
+                select! {
+                    () = sleep_until(deadline) => break,
+                    _ = ping.tick() => write_message(&mut write, "PING", &[]).await?,
+                }
+            }
+        }
+        write_message(&mut write, header, &payload).await?;
+    }
+    info!("Replayed the whole recording in {:?}", start.elapsed());
+    loop {
+        ping.tick().await;
+        write_message(&mut write, "PING", &[]).await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use tempfile::tempdir;
+    use tokio::{sync::oneshot, time::timeout};
+
+    use super::*;
+    use crate::{
+        listeners::order_book::{RelaySource, listen_source, serve_relay, state::OrderBookState},
+        order_book::multi_book::Snapshots,
+        servers::shutdown::Shutdown,
+        types::node_data::EventSource,
+    };
+
+    fn batch(height: u64) -> String {
+        format!(
+            r#"{{"local_time":"2025-06-24T02:56:36.1","block_time":"2025-06-24T02:56:36.0","block_number":{height},"events":[]}}"#
+        ) + "\n"
+    }
+
+    // the snapshot is gzipped, so the recording isn't text
+    async fn wait_for_recorded(path: &Path, kind: &str, count: usize) -> Result<()> {
+        let recorded = async {
+            while String::from_utf8_lossy(&tokio::fs::read(path).await.unwrap_or_default()).matches(kind).count()
+                < count
+            {
+                sleep(Duration::from_millis(10)).await;
+            }
+        };
+        Ok(timeout(Duration::from_secs(5), recorded).await?)
+    }
+
+    async fn wait_for_block(listener: &Mutex<OrderBookListener>, height: u64) -> Result<()> {
+        let height_reached = async {
+            while listener.lock().await.latest_block() < height {
+                sleep(Duration::from_millis(10)).await;
+            }
+        };
+        Ok(timeout(Duration::from_secs(5), height_reached).await?)
+    }
+
+    #[tokio::test]
+    async fn test_record_and_replay() -> Result<()> {
+        let mut ingest = OrderBookListener::new(None, true);
+        ingest.enable_relay();
+        ingest.order_book_state = Some(OrderBookState::from_snapshot(Snapshots::new(HashMap::new()), 1, 0, true, true));
+        let ingest = Arc::new(Mutex::new(ingest));
+        let tcp_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = tcp_listener.local_addr()?.to_string();
+        serve_relay(tcp_listener, ingest.clone(), Shutdown::default())?;
+
+        let dir = tempdir()?;
+        let path = dir.path().join("feed.rec");
+        let (stop, stopped) = oneshot::channel::<()>();
+        let recording = tokio::spawn({
+            let path = path.clone();
+            async move { record_feed(RecordSource::Relay(address), &path, async { stopped.await.unwrap_or(()) }).await }
+        });
+        wait_for_recorded(&path, "SNAPSHOT", 1).await?;
+        for (data, event_source) in [
+            (batch(2), EventSource::OrderStatuses),
+            (batch(2), EventSource::OrderDiffs),
+            (batch(3) + &batch(4), EventSource::OrderStatuses),
+            (batch(3) + &batch(4), EventSource::OrderDiffs),
+        ] {
+            ingest.lock().await.process_data(&data, event_source)?;
+        }
+        wait_for_recorded(&path, "EVENTS", 4).await?;
+        let _unused = stop.send(());
+        recording.await??;
+
+        let tcp_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = tcp_listener.local_addr()?.to_string();
+        tokio::spawn(replay_feed(path.clone(), tcp_listener, 0.0));
+        let edge = Arc::new(Mutex::new(OrderBookListener::new(None, true)));
+        let source = RelaySource::new(address, Duration::from_secs(5));
+        tokio::spawn(listen_source(edge.clone(), source, || {}));
+        wait_for_block(&edge, 4).await?;
+        Ok(())
+    }
+}
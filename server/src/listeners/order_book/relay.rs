@@ -0,0 +1,393 @@
+use std::{io::Read, sync::Arc, time::Duration};
+
+use flate2::{Compression, read::GzDecoder, write::GzEncoder};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter},
+    net::{TcpListener, TcpStream},
+    select,
+    sync::{
+        Mutex,
+        broadcast::{self, Receiver, error::RecvError},
+    },
+    task::spawn_blocking,
+    time::{interval, sleep, timeout},
+};
+use tracing::{info, warn};
+
+use crate::{
+    listeners::order_book::{
+        OrderBookListener,
+        source::{SourceEvent, UpstreamSource},
+        state::OrderBookState,
+        utils::BatchQueue,
+    },
+    prelude::*,
+    servers::shutdown::Shutdown,
+    snapshot_store::StoredBooks,
+    types::{
+        UpstreamStatus,
+        node_data::{EventSource, NodeDataOrderDiff, NodeDataOrderStatus},
+    },
+};
+
+// event chunks queued for an edge; one that falls further behind is disconnected and starts over
+const RELAY_BUFFER: usize = 10_000;
+pub(super) const PING_INTERVAL: Duration = Duration::from_secs(1);
+// a large snapshot takes a while to transfer
+pub(super) const PAYLOAD_TIMEOUT: Duration = Duration::from_mins(1);
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+// The relay feed lets edge instances serve the books of an ingest instance, which is the only one reading the
+// node. An edge gets the ingest instance's state first, then every chunk of events it reads, and runs them
+// through its own listener. Both therefore produce the same messages with the same sequence numbers.
+//
+// Messages are a `<kind> [<args>] <payload length>` line followed by the payload:
+// - `SNAPSHOT`: a gzipped JSON `RelaySnapshot`, sent once per connection
+// - `EVENTS <seq> <event source>`: lines of a node event file, numbered from the snapshot's `seq` on
+// - `PING`: sent every second, so that edges notice a dead feed
+pub(super) struct RelayFeed {
+    tx: broadcast::Sender<Arc<RelayEvents>>,
+    seq: u64,
+}
+
+impl RelayFeed {
+    fn new() -> Self {
+        Self { tx: broadcast::channel(RELAY_BUFFER).0, seq: 0 }
+    }
+
+    pub(super) fn send(&mut self, event_source: EventSource, data: &str) {
+        if data.is_empty() || self.tx.receiver_count() == 0 {
+            return;
+        }
+        self.seq += 1;
+        let _unused = self.tx.send(Arc::new(RelayEvents { seq: self.seq, event_source, data: data.to_string() }));
+    }
+}
+
+pub(super) struct RelayEvents {
+    pub(super) seq: u64,
+    pub(super) event_source: EventSource,
+    pub(super) data: String,
+}
+
+// the ingest instance's state after the event chunk `seq`, including the events it holds until their block is
+// complete
+#[derive(Serialize, Deserialize)]
+pub(crate) struct RelaySnapshot {
+    seq: u64,
+    books: StoredBooks,
+    latest_block: u64,
+    order_statuses: BatchQueue<NodeDataOrderStatus>,
+    order_diffs: BatchQueue<NodeDataOrderDiff>,
+}
+
+impl RelaySnapshot {
+    // books without orders at `height`, for scripted sources
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) fn empty(height: u64) -> Self {
+        let books = crate::order_book::multi_book::Snapshots::new(std::collections::HashMap::new());
+        let state = OrderBookState::from_snapshot(books, height, 0, true, true);
+        let (order_statuses, order_diffs) = (BatchQueue::new(), BatchQueue::new());
+        Self { seq: 0, books: state.to_stored(), latest_block: height, order_statuses, order_diffs }
+    }
+
+    pub(super) fn encode(&self) -> Result<Vec<u8>> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+        serde_json::to_writer(&mut encoder, self)?;
+        Ok(encoder.finish()?)
+    }
+
+    fn decode(data: &[u8]) -> Result<Self> {
+        let mut json = Vec::new();
+        GzDecoder::new(data).read_to_end(&mut json)?;
+        Ok(serde_json::from_slice(&json)?)
+    }
+}
+
+impl OrderBookListener {
+    pub(crate) fn enable_relay(&mut self) {
+        self.relay = Some(RelayFeed::new());
+    }
+
+    // None until the book is ready. The receiver gets every chunk of events read after the snapshot
+    pub(super) async fn relay_subscribe(listener: &Mutex<Self>) -> Option<(RelaySnapshot, Receiver<Arc<RelayEvents>>)> {
+        let listener = listener.lock().await;
+        let relay = listener.relay.as_ref()?;
+        let state = listener.clone_state()?;
+        let (seq, rx) = (relay.seq, relay.tx.subscribe());
+        let (last_fill, latest_block) = (listener.last_fill, listener.latest_block);
+        let order_statuses = listener.order_status_cache.clone();
+        let order_diffs = listener.order_diff_cache.clone();
+        drop(listener);
+        let books = StoredBooks { last_fill, ..state.to_stored() };
+        Some((RelaySnapshot { seq, books, latest_block, order_statuses, order_diffs }, rx))
+    }
+
+    // replaces the edge's state with the ingest instance's
+    pub(super) fn init_from_relay(&mut self, snapshot: RelaySnapshot) -> Result<()> {
+        let RelaySnapshot { seq: _, books, latest_block, order_statuses, order_diffs } = snapshot;
+        self.last_fill = books.last_fill;
+        self.set_state(OrderBookState::from_stored(books, self.ignore_spot)?);
+        self.restored = None;
+        self.latest_block = latest_block;
+        self.order_status_cache = order_statuses;
+        self.order_diff_cache = order_diffs;
+        Ok(())
+    }
+}
+
+// serves the relay feed to edge instances until shutdown. The listener needs the relay enabled
+pub(crate) fn serve_relay(
+    tcp_listener: TcpListener,
+    listener: Arc<Mutex<OrderBookListener>>,
+    shutdown: Shutdown,
+) -> Result<()> {
+    info!("Relay feed running at {}", tcp_listener.local_addr()?);
+    tokio::spawn(async move {
+        loop {
+            select! {
+                res = tcp_listener.accept() => match res {
+                    Ok((stream, peer)) => {
+                        let listener = listener.clone();
+                        let shutdown = shutdown.clone();
+                        tokio::spawn(async move {
+                            info!("Edge {peer} connected to the relay feed");
+                            match feed_edge(stream, &listener, &shutdown).await {
+                                Ok(()) => info!("Edge {peer} disconnected from the relay feed"),
+                                Err(err) => warn!("Edge {peer} disconnected from the relay feed: {err}"),
+                            }
+                        });
+                    }
+                    Err(err) => warn!("Unable to accept a relay feed connection: {err}"),
+                },
+                () = shutdown.cancelled() => return,
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn feed_edge(stream: TcpStream, listener: &Mutex<OrderBookListener>, shutdown: &Shutdown) -> Result<()> {
+    stream.set_nodelay(true)?;
+    let mut write = BufWriter::new(stream);
+    let mut ping = interval(PING_INTERVAL);
+    // an edge that connects before the book is ready waits for it
+    let (snapshot, mut rx) = loop {
+        if let Some(subscription) = OrderBookListener::relay_subscribe(listener).await {
+            break subscription;
+        }
+        select! {
+            _ = ping.tick() => write_message(&mut write, "PING", &[]).await?,
+            () = shutdown.cancelled() => return Ok(()),
+        }
+    };
+    let mut encoding = spawn_blocking(move || snapshot.encode());
+    let snapshot = loop {
+        select! {
+            res = &mut encoding => break res??,
+            _ = ping.tick() => write_message(&mut write, "PING", &[]).await?,
+        }
+    };
+    write_message(&mut write, "SNAPSHOT", &snapshot).await?;
+    loop {
+        select! {
+            events = rx.recv() => match events {
+                Ok(events) => {
+                    let header = format!("EVENTS {} {}", events.seq, event_source_name(events.event_source));
+                    write_message(&mut write, &header, events.data.as_bytes()).await?;
+                }
+                Err(RecvError::Lagged(n)) => return Err(format!("fell behind by {n} event chunks").into()),
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            _ = ping.tick() => write_message(&mut write, "PING", &[]).await?,
+            () = shutdown.cancelled() => return Ok(()),
+        }
+    }
+}
+
+pub(super) async fn write_message(write: &mut (impl AsyncWrite + Unpin), header: &str, payload: &[u8]) -> Result<()> {
+    write.write_all(format!("{header} {}\n", payload.len()).as_bytes()).await?;
+    write.write_all(payload).await?;
+    Ok(write.flush().await?)
+}
+
+// The relay feed of an ingest instance at `address` (`host:port`) as a source of events. It starts over with a new
+// connection, and so a new state, whenever the feed fails, stays silent for `inactivity_timeout` or the listener
+// can't follow it
+pub(crate) struct RelaySource {
+    address: String,
+    inactivity_timeout: Duration,
+    reader: Option<BufReader<TcpStream>>,
+    next_seq: Option<u64>,
+    // the feed was lost, which is reported before reconnecting
+    disconnected: bool,
+}
+
+impl RelaySource {
+    pub(crate) const fn new(address: String, inactivity_timeout: Duration) -> Self {
+        Self { address, inactivity_timeout, reader: None, next_seq: None, disconnected: false }
+    }
+
+    fn status(&self, connected: bool) -> SourceEvent {
+        SourceEvent::Upstreams(vec![UpstreamStatus { node: self.address.clone(), connected }])
+    }
+
+    fn disconnect(&mut self, err: Option<&Error>) {
+        if let Some(err) = err {
+            warn!("Relay feed from {} failed, reconnecting: {err}", self.address);
+        } else {
+            warn!("Relay feed from {} closed, reconnecting", self.address);
+        }
+        self.reader = None;
+        self.disconnected = true;
+    }
+
+    async fn connect(&mut self) -> Result<()> {
+        let connect = timeout(self.inactivity_timeout, TcpStream::connect(&self.address));
+        let stream = connect.await.map_err(|_| "connect timed out")??;
+        self.reader = Some(BufReader::new(stream));
+        self.next_seq = None;
+        Ok(())
+    }
+
+    // `None` once the ingest instance closed the feed
+    async fn read(&mut self) -> Result<Option<SourceEvent>> {
+        let Some(reader) = &mut self.reader else {
+            return Ok(None);
+        };
+        while let Some((header, payload)) = read_message(reader, self.inactivity_timeout).await? {
+            let mut args = header.split(' ');
+            match (args.next(), args.next(), args.next()) {
+                (Some("PING"), None, None) => {}
+                (Some("SNAPSHOT"), None, None) => {
+                    let snapshot = spawn_blocking(move || RelaySnapshot::decode(&payload)).await??;
+                    info!("Following the relay feed from {} from block {}", self.address, snapshot.books.height);
+                    self.next_seq = Some(snapshot.seq + 1);
+                    return Ok(Some(SourceEvent::State(Box::new(snapshot))));
+                }
+                (Some("EVENTS"), Some(seq), Some(event_source)) => {
+                    let seq = seq.parse::<u64>()?;
+                    if self.next_seq != Some(seq) {
+                        return Err(format!("expected event chunk {:?}, got {seq}", self.next_seq).into());
+                    }
+                    self.next_seq = Some(seq + 1);
+                    let event_source = parse_event_source(event_source)?;
+                    return Ok(Some(SourceEvent::Events { event_source, data: String::from_utf8(payload)? }));
+                }
+                _ => return Err(format!("unexpected message {header:?}").into()),
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl UpstreamSource for RelaySource {
+    async fn next_event(&mut self) -> Result<Option<SourceEvent>> {
+        loop {
+            if std::mem::take(&mut self.disconnected) {
+                return Ok(Some(self.status(false)));
+            }
+            if self.reader.is_none() {
+                match self.connect().await {
+                    Ok(()) => return Ok(Some(self.status(true))),
+                    Err(err) => {
+                        self.disconnect(Some(&err));
+                        sleep(RECONNECT_DELAY).await;
+                        continue;
+                    }
+                }
+            }
+            match self.read().await {
+                Ok(Some(event)) => return Ok(Some(event)),
+                Ok(None) => self.disconnect(None),
+                Err(err) => self.disconnect(Some(&err)),
+            }
+            sleep(RECONNECT_DELAY).await;
+        }
+    }
+
+    // the ingest instance relays the events it read, so its next state is past the gap
+    fn resync(&mut self, err: Error) -> Result<()> {
+        self.disconnect(Some(&err));
+        Ok(())
+    }
+}
+
+// `None` once the ingest instance closed the feed
+pub(super) async fn read_message(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    inactivity_timeout: Duration,
+) -> Result<Option<(String, Vec<u8>)>> {
+    let mut line = String::new();
+    let read = timeout(inactivity_timeout, reader.read_line(&mut line)).await.map_err(|_| "no message in time")??;
+    if read == 0 {
+        return Ok(None);
+    }
+    let (header, len) = line.trim_end().rsplit_once(' ').ok_or_else(|| format!("malformed message {line:?}"))?;
+    let mut payload = vec![0; len.parse()?];
+    timeout(PAYLOAD_TIMEOUT, reader.read_exact(&mut payload)).await.map_err(|_| "payload timed out")??;
+    Ok(Some((header.to_string(), payload)))
+}
+
+pub(super) const fn event_source_name(event_source: EventSource) -> &'static str {
+    match event_source {
+        EventSource::Fills => "fills",
+        EventSource::OrderStatuses => "orderStatuses",
+        EventSource::OrderDiffs => "orderDiffs",
+    }
+}
+
+fn parse_event_source(name: &str) -> Result<EventSource> {
+    [EventSource::Fills, EventSource::OrderStatuses, EventSource::OrderDiffs]
+        .into_iter()
+        .find(|event_source| event_source_name(*event_source) == name)
+        .ok_or_else(|| format!("unknown event source {name:?}").into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::{listeners::order_book::listen_source, order_book::multi_book::Snapshots};
+
+    fn batch(height: u64) -> String {
+        format!(
+            r#"{{"local_time":"2025-06-24T02:56:36.1","block_time":"2025-06-24T02:56:36.0","block_number":{height},"events":[]}}"#
+        ) + "\n"
+    }
+
+    async fn wait_for(listener: &Mutex<OrderBookListener>, height: u64) -> Result<()> {
+        let height_reached = async {
+            while listener.lock().await.compute_snapshot().is_none_or(|snapshot| snapshot.height < height) {
+                sleep(Duration::from_millis(10)).await;
+            }
+        };
+        Ok(timeout(Duration::from_secs(5), height_reached).await?)
+    }
+
+    #[tokio::test]
+    async fn test_edge_follows_ingest() -> Result<()> {
+        let mut ingest = OrderBookListener::new(None, true);
+        ingest.enable_relay();
+        ingest.order_book_state = Some(OrderBookState::from_snapshot(Snapshots::new(HashMap::new()), 1, 0, true, true));
+        let ingest = Arc::new(Mutex::new(ingest));
+        let tcp_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = tcp_listener.local_addr()?.to_string();
+        serve_relay(tcp_listener, ingest.clone(), Shutdown::default())?;
+        // held by the ingest instance until the diffs arrive, so the edge gets it with the snapshot
+        ingest.lock().await.process_data(&batch(2), EventSource::OrderStatuses)?;
+
+        let edge = Arc::new(Mutex::new(OrderBookListener::new(None, true)));
+        let source = RelaySource::new(address, Duration::from_secs(5));
+        tokio::spawn(listen_source(edge.clone(), source, || {}));
+        wait_for(&edge, 1).await?;
+        ingest.lock().await.process_data(&batch(2), EventSource::OrderDiffs)?;
+        ingest.lock().await.process_data(&(batch(3) + &batch(4)), EventSource::OrderStatuses)?;
+        ingest.lock().await.process_data(&(batch(3) + &batch(4)), EventSource::OrderDiffs)?;
+        wait_for(&edge, 4).await?;
+        assert_eq!(edge.lock().await.latest_block(), 4);
+        Ok(())
+    }
+}
@@ -0,0 +1,401 @@
+// Property tests of the book state against a reference model. Random blocks of node events are applied to both:
+// the model is a plain list of resting orders per coin, from which every view of the book is computed from scratch.
+// After every block the server's books, l2 snapshots of every aggregation, tick groups, l4 snapshots, update
+// sequence numbers and checksums have to match the model, and clients that follow the updates (or their conflated
+// net deltas) from a snapshot, as well as instances restored from the state, have to end up with the same books.
+
+use std::collections::{BTreeMap, HashMap};
+
+use order_book_client::{Message, OrderBook as ClientBook};
+use proptest::{collection::vec, prelude::*, sample::Index};
+use serde::de::DeserializeOwned;
+use serde_json::{Value, json};
+
+use crate::{
+    listeners::order_book::{TimedSnapshots, state::OrderBookState},
+    order_book::{Coin, Px, Side, Snapshot, multi_book::Snapshots},
+    prelude::*,
+    types::{
+        CHECKSUM_LEVELS, L4Book, L4BookUpdates, L4Order, Level, checksum,
+        node_data::{Batch, NodeDataOrderDiff, NodeDataOrderStatus},
+        subscription::ServerResponse,
+    },
+};
+
+const COINS: [&str; 2] = ["BTC", "ETH"];
+// prices and sizes in units of 10^-8, as in the books. Bids and asks don't cross, as the node only reports
+// what rests on the book
+const BID_MIN: u64 = 9_500_000_000;
+const ASK_MIN: u64 = 10_000_000_000;
+const PX_STEP: u64 = 5_000_000;
+const PX_STEPS: u64 = 100;
+const SZ_STEP: u64 = 1_000_000;
+const MAX_SZ_STEPS: u64 = 500;
+// 0.25 and 1
+const TICKS: [u64; 2] = [25_000_000, 100_000_000];
+const TIME: &str = "2025-06-24T02:56:36.172847427";
+const USER: &str = "0x0000000000000000000000000000000000000001";
+
+#[derive(Debug, Clone)]
+enum Op {
+    New { coin: usize, side: Side, px_steps: u64, sz_steps: u64 },
+    // of a random resting order
+    Update { order: Index, sz_steps: u64 },
+    Remove { order: Index },
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    let side = prop_oneof![Just(Side::Bid), Just(Side::Ask)];
+    prop_oneof![
+        3 => (0..COINS.len(), side, 0..PX_STEPS, 1..=MAX_SZ_STEPS)
+            .prop_map(|(coin, side, px_steps, sz_steps)| Op::New { coin, side, px_steps, sz_steps }),
+        1 => (any::<Index>(), 1..=MAX_SZ_STEPS).prop_map(|(order, sz_steps)| Op::Update { order, sz_steps }),
+        1 => any::<Index>().prop_map(|order| Op::Remove { order }),
+    ]
+}
+
+#[derive(Debug, Clone)]
+struct ModelOrder {
+    oid: u64,
+    side: Side,
+    px: u64,
+    sz: u64,
+}
+
+// the resting orders of every coin, in the order they entered the book
+#[derive(Default)]
+struct Model {
+    books: BTreeMap<String, Vec<ModelOrder>>,
+    next_oid: u64,
+}
+
+impl Model {
+    // the node's events for a block of operations
+    fn block(&mut self, height: u64, ops: &[Op]) -> (Batch<NodeDataOrderStatus>, Batch<NodeDataOrderDiff>) {
+        let (mut statuses, mut diffs) = (Vec::new(), Vec::new());
+        for op in ops {
+            match *op {
+                Op::New { coin, side, px_steps, sz_steps } => {
+                    self.next_oid += 1;
+                    let min = if side == Side::Bid { BID_MIN } else { ASK_MIN };
+                    let order =
+                        ModelOrder { oid: self.next_oid, side, px: min + px_steps * PX_STEP, sz: sz_steps * SZ_STEP };
+                    statuses.push(status(COINS[coin], &order));
+                    diffs.push(diff(COINS[coin], &order, json!({"new": {"sz": decimal(order.sz)}})));
+                    self.books.entry(COINS[coin].to_string()).or_default().push(order);
+                }
+                Op::Update { order, sz_steps } => {
+                    let Some((coin, order)) = self.pick(order) else { continue };
+                    let sz = sz_steps * SZ_STEP;
+                    if let Some(order) = self.books.get_mut(&coin).and_then(|orders| orders.get_mut(order)) {
+                        let update = json!({"update": {"origSz": decimal(order.sz), "newSz": decimal(sz)}});
+                        diffs.push(diff(&coin, order, update));
+                        order.sz = sz;
+                    }
+                }
+                Op::Remove { order } => {
+                    let Some((coin, order)) = self.pick(order) else { continue };
+                    if let Some(orders) = self.books.get_mut(&coin) {
+                        let order = orders.remove(order);
+                        diffs.push(diff(&coin, &order, json!("remove")));
+                    }
+                }
+            }
+        }
+        (batch(height, statuses), batch(height, diffs))
+    }
+
+    fn pick(&self, order: Index) -> Option<(String, usize)> {
+        let resting = self
+            .books
+            .iter()
+            .flat_map(|(coin, orders)| (0..orders.len()).map(move |i| (coin.clone(), i)))
+            .collect::<Vec<_>>();
+        (!resting.is_empty()).then(|| order.get(&resting).clone())
+    }
+
+    // one side's orders, best first, and by time at the same price
+    fn orders(&self, coin: &str, side: Side) -> Vec<ModelOrder> {
+        let mut orders = self.books[coin].iter().filter(|order| order.side == side).cloned().collect::<Vec<_>>();
+        match side {
+            Side::Bid => orders.sort_by_key(|order| u64::MAX - order.px),
+            Side::Ask => orders.sort_by_key(|order| order.px),
+        }
+        orders
+    }
+
+    // the levels of a coin's book, with prices mapped to the price of their bucket
+    fn levels(&self, coin: &str, bucket: impl Fn(u64, Side) -> u64) -> [Vec<Level>; 2] {
+        [Side::Bid, Side::Ask].map(|side| {
+            let mut levels: Vec<(u64, u64, usize)> = Vec::new();
+            for order in self.orders(coin, side) {
+                let px = bucket(order.px, side);
+                match levels.last_mut() {
+                    Some(level) if level.0 == px => {
+                        level.1 += order.sz;
+                        level.2 += 1;
+                    }
+                    _ => levels.push((px, order.sz, 1)),
+                }
+            }
+            levels.into_iter().map(|(px, sz, n)| Level::new(decimal(px), decimal(sz), n)).collect()
+        })
+    }
+}
+
+const fn exact(px: u64, _: Side) -> u64 {
+    px
+}
+
+// bids round down and asks up, to a multiple of `inc`
+const fn round(px: u64, side: Side, inc: u64) -> u64 {
+    match side {
+        Side::Bid => px / inc * inc,
+        Side::Ask => px.div_ceil(inc) * inc,
+    }
+}
+
+// to `n_sig_figs` significant figures, in steps of `mantissa` in the last one
+fn sig_figs(n_sig_figs: u32, mantissa: u64) -> impl Fn(u64, Side) -> u64 {
+    move |px, side| {
+        let digits = px.checked_ilog10().map_or(1, |log| log + 1);
+        round(px, side, mantissa * 10u64.pow(digits.saturating_sub(n_sig_figs)))
+    }
+}
+
+// the way the messages write numbers: without trailing zeros, and without the point for whole numbers
+fn decimal(units: u64) -> String {
+    let (whole, fraction) = (units / 100_000_000, units % 100_000_000);
+    if fraction == 0 {
+        whole.to_string()
+    } else {
+        format!("{whole}.{}", format!("{fraction:08}").trim_end_matches('0'))
+    }
+}
+
+fn status(coin: &str, order: &ModelOrder) -> Value {
+    json!({
+        "time": TIME,
+        "user": USER,
+        "status": "open",
+        "order": {
+            "user": null,
+            "coin": coin,
+            "side": order.side,
+            "limitPx": decimal(order.px),
+            "sz": decimal(order.sz),
+            "oid": order.oid,
+            "timestamp": 1,
+            "triggerCondition": "N/A",
+            "isTrigger": false,
+            "triggerPx": "0.0",
+            "isPositionTpsl": false,
+            "reduceOnly": false,
+            "orderType": "Limit",
+            "tif": "Gtc",
+            "cloid": null,
+        },
+    })
+}
+
+fn diff(coin: &str, order: &ModelOrder, raw_book_diff: Value) -> Value {
+    json!({"user": USER, "oid": order.oid, "px": decimal(order.px), "coin": coin, "raw_book_diff": raw_book_diff})
+}
+
+fn batch<E: DeserializeOwned>(height: u64, events: Vec<Value>) -> Batch<E> {
+    let batch = json!({"local_time": TIME, "block_time": TIME, "block_number": height, "events": events});
+    serde_json::from_value(batch).unwrap()
+}
+
+// applies a block as the listener does, keeping the tick groups of every book
+fn advance(
+    state: &mut OrderBookState,
+    (statuses, diffs): (Batch<NodeDataOrderStatus>, Batch<NodeDataOrderDiff>),
+) -> Result<HashMap<String, L4BookUpdates>> {
+    state.apply_updates(statuses.clone(), diffs.clone())?;
+    let updates = state.book_updates(statuses, diffs);
+    for coin in state.compute_universe() {
+        for tick in TICKS {
+            state.add_tick_group(&coin, Px::new(tick))?;
+        }
+    }
+    Ok(updates)
+}
+
+fn assert_matches_model(state: &mut OrderBookState, model: &Model) {
+    let TimedSnapshots { snapshot, .. } = state.compute_snapshot();
+    let snapshots = snapshot.value();
+    assert_eq!(snapshots.len(), model.books.len());
+    for (coin, snapshot) in snapshots {
+        let orders = snapshot.as_ref().clone().map(|orders| {
+            orders.into_iter().map(|order| (order.oid, order.limit_px.value(), order.sz.value())).collect::<Vec<_>>()
+        });
+        let expected = [Side::Bid, Side::Ask]
+            .map(|side| model.orders(&coin.value(), side).iter().map(|o| (o.oid, o.px, o.sz)).collect::<Vec<_>>());
+        assert_eq!(orders, expected, "l4 book of {}", coin.value());
+    }
+
+    let Some((_, _, l2_snapshots)) = state.l2_snapshots(false) else { panic!("no l2 snapshots after a block") };
+    for (coin, snapshots) in l2_snapshots.as_ref() {
+        for (params, snapshot) in snapshots {
+            let expected = match (params.tick_size, params.n_sig_figs) {
+                (Some(tick), _) => model.levels(&coin.value(), |px, side| round(px, side, tick.value())),
+                (None, Some(n_sig_figs)) => {
+                    model.levels(&coin.value(), sig_figs(n_sig_figs, params.mantissa.unwrap_or(1)))
+                }
+                (None, None) => model.levels(&coin.value(), exact),
+            };
+            assert_eq!(snapshot.clone().export_inner_snapshot(), expected, "l2 book of {} by {params:?}", coin.value());
+        }
+        let depth = state.l2_snapshot(coin, CHECKSUM_LEVELS, None, None).map(|(_, _, levels)| levels);
+        let expected =
+            model.levels(&coin.value(), exact).map(|levels| levels.into_iter().take(CHECKSUM_LEVELS).collect());
+        assert_eq!(depth.map(Snapshot::export_inner_snapshot), Some(expected));
+    }
+}
+
+// the message a new l4 subscription of the coin starts with
+fn l4_snapshot(state: &OrderBookState, coin: &Coin) -> ServerResponse {
+    let TimedSnapshots { time, height, snapshot } = state.compute_snapshot();
+    let levels = snapshot
+        .value()
+        .remove(coin)
+        .map(|snapshot| snapshot.as_ref().clone().map(|orders| orders.into_iter().map(L4Order::from).collect()))
+        .unwrap_or_default();
+    let levels_checksum = state
+        .l2_snapshot(coin, CHECKSUM_LEVELS, None, None)
+        .map_or(0, |(_, _, levels)| checksum(&levels.export_inner_snapshot()));
+    let seq = state.l4_seq(coin);
+    ServerResponse::L4Book(L4Book::Snapshot {
+        coin: coin.value(),
+        time,
+        height,
+        seq,
+        levels,
+        checksum: levels_checksum,
+        checkpoint: None,
+    })
+}
+
+// as read by a client
+fn received(msg: &ServerResponse) -> Message {
+    Message::parse(&serde_json::to_string(msg).unwrap()).unwrap().remove(0)
+}
+
+fn client_levels(levels: [Vec<Level>; 2]) -> [Vec<order_book_client::messages::Level>; 2] {
+    serde_json::from_value(serde_json::to_value(levels).unwrap()).unwrap()
+}
+
+// clients subscribed at some point: one applying every update, one the net delta of every few blocks
+#[derive(Default)]
+struct Clients {
+    live: HashMap<String, ClientBook>,
+    conflated: HashMap<String, (ClientBook, Option<L4BookUpdates>)>,
+}
+
+impl Clients {
+    fn subscribe(&mut self, state: &OrderBookState) {
+        for coin in state.compute_universe() {
+            let mut book = ClientBook::new(coin.value());
+            assert_eq!(book.apply(&received(&l4_snapshot(state, &coin))), Ok(true));
+            self.live.insert(coin.value(), book);
+            let mut book = ClientBook::conflated(coin.value());
+            assert_eq!(book.apply(&received(&l4_snapshot(state, &coin))), Ok(true));
+            self.conflated.insert(coin.value(), (book, None));
+        }
+    }
+
+    fn on_updates(&mut self, updates: &HashMap<String, L4BookUpdates>) {
+        for (coin, update) in updates {
+            if let Some(book) = self.live.get_mut(coin) {
+                let msg = received(&ServerResponse::L4Book(L4Book::Updates(update.clone())));
+                assert_eq!(book.apply(&msg), Ok(true), "update {} of {coin}", update.seq);
+            }
+            if let Some((_, pending)) = self.conflated.get_mut(coin) {
+                match pending {
+                    Some(pending) => pending.merge(update.clone()),
+                    None => *pending = Some(update.clone()),
+                }
+            }
+        }
+    }
+
+    fn flush_conflated(&mut self) {
+        for (coin, (book, pending)) in &mut self.conflated {
+            if let Some(pending) = pending.take() {
+                let seq = pending.seq;
+                let msg = received(&ServerResponse::L4Book(L4Book::Updates(pending.into_net_delta())));
+                // a delta whose diffs all cancel out has no coin to apply it to, and leaves the book as it is
+                assert!(book.apply(&msg).is_ok(), "net delta up to {seq} of {coin}");
+            }
+        }
+    }
+
+    fn assert_matches_model(&self, model: &Model) {
+        // conflated books only once the held updates are sent
+        let conflated = self.conflated.iter().filter(|(_, (_, pending))| pending.is_none());
+        for (coin, book) in self.live.iter().chain(conflated.map(|(coin, (book, _))| (coin, book))) {
+            assert_eq!(book.levels(usize::MAX), client_levels(model.levels(coin, exact)), "client book of {coin}");
+        }
+    }
+}
+
+// the blocks after `join` are also followed by clients subscribed then, and by instances restored from the state
+fn simulate(blocks: &[Vec<Op>], join: usize, conflation: usize) -> Result<()> {
+    let mut model = Model::default();
+    let mut state = OrderBookState::from_snapshot(Snapshots::new(HashMap::new()), 0, 0, true, true);
+    let mut seqs = HashMap::<String, u64>::new();
+    let mut clients = Clients::default();
+    let mut restored = Vec::new();
+    for (height, ops) in (1..).zip(blocks) {
+        let events = model.block(height, ops);
+        for restored in &mut restored {
+            advance(restored, events.clone())?;
+        }
+        let updates = advance(&mut state, events)?;
+        for (coin, update) in &updates {
+            let seq = seqs.entry(coin.clone()).or_default();
+            *seq += 1;
+            assert_eq!(update.seq, *seq, "seq of {coin}");
+            let levels = client_levels(model.levels(coin, exact));
+            assert_eq!(update.checksum, order_book_client::checksum(&levels), "checksum of {coin}");
+        }
+        clients.on_updates(&updates);
+        if height % conflation as u64 == 0 {
+            clients.flush_conflated();
+        }
+        assert_matches_model(&mut state, &model);
+        if height == join as u64 {
+            clients.subscribe(&state);
+            let TimedSnapshots { time, snapshot, .. } = state.compute_snapshot();
+            restored.push(OrderBookState::from_snapshot(snapshot, height, time, true, true));
+            restored.push(OrderBookState::from_stored(state.to_stored(), true)?);
+        }
+        clients.assert_matches_model(&model);
+    }
+    clients.flush_conflated();
+    clients.assert_matches_model(&model);
+    for restored in &mut restored {
+        assert_matches_model(restored, &model);
+    }
+    if let Some(stored) = restored.get(1) {
+        for coin in state.compute_universe() {
+            assert_eq!(stored.l4_seq(&coin), state.l4_seq(&coin), "restored seq of {}", coin.value());
+        }
+    }
+    Ok(())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn test_books_converge_to_model(
+        blocks in vec(vec(op(), 0..16), 1..32),
+        join in any::<Index>(),
+        conflation in 1..4usize,
+    ) {
+        let join = join.index(blocks.len()) + 1;
+        simulate(&blocks, join, conflation).map_err(|err| TestCaseError::fail(err.to_string()))?;
+    }
+}
@@ -0,0 +1,153 @@
+use std::sync::Arc;
+
+use tokio::sync::Mutex;
+
+use crate::{
+    listeners::order_book::{OrderBookListener, relay::RelaySnapshot},
+    prelude::*,
+    types::{UpstreamStatus, node_data::EventSource},
+};
+
+// what a source of node events hands to the listener
+pub(crate) enum SourceEvent {
+    // the books with the events held until their block is complete, to start from or to replace a book that fell
+    // behind
+    State(Box<RelaySnapshot>),
+    // lines of one of the node's event files
+    Events { event_source: EventSource, data: String },
+    // the health of the nodes the events come from
+    Upstreams(Vec<UpstreamStatus>),
+    // the source replaced the book itself, e.g. with the snapshot of a node after a gap
+    Replaced,
+}
+
+// Where a listener gets its events from: the event files of the nodes, the relay feed of an ingest instance or a
+// scripted feed in tests.
+// `next_event` is `None` once the source has ended
+pub(crate) trait UpstreamSource: Send {
+    fn next_event(&mut self) -> impl Future<Output = Result<Option<SourceEvent>>> + Send;
+
+    // the listener couldn't apply the last events, so it needs a new snapshot or state. Sources that can't start
+    // over return the error
+    fn resync(&mut self, err: Error) -> Result<()>;
+
+    // the last events skipped blocks, so the book was dropped until a new snapshot or state replaces it
+    fn skipped_blocks(&mut self) -> Result<()> {
+        self.resync("the events skipped blocks".into())
+    }
+}
+
+// feeds the events of `source` to the listener until the source ends. `resynced` is called when a state replaced
+// the book, since clients may have missed updates in between
+pub(crate) async fn listen_source(
+    listener: Arc<Mutex<OrderBookListener>>,
+    mut source: impl UpstreamSource,
+    resynced: impl Fn() + Send + Sync,
+) -> Result<()> {
+    let mut has_state = false;
+    while let Some(event) = source.next_event().await? {
+        let mut listener = listener.lock().await;
+        let replaced = match event {
+            SourceEvent::State(state) => match listener.init_from_relay(*state) {
+                Ok(()) => std::mem::replace(&mut has_state, true),
+                Err(err) => {
+                    source.resync(err)?;
+                    false
+                }
+            },
+            SourceEvent::Events { event_source, data } => {
+                match listener.process_data(&data, event_source) {
+                    Ok(()) if listener.take_resync_request() => source.skipped_blocks()?,
+                    Ok(()) => {}
+                    Err(err) => source.resync(err)?,
+                }
+                false
+            }
+            SourceEvent::Upstreams(upstreams) => {
+                listener.set_upstreams(upstreams);
+                false
+            }
+            SourceEvent::Replaced => true,
+        };
+        drop(listener);
+        if replaced {
+            resynced();
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use super::*;
+    use crate::{
+        listeners::order_book::{Broadcast, InternalMessage, state::OrderBookState},
+        order_book::multi_book::Snapshots,
+        testing::ScriptedSource,
+    };
+
+    fn events(event_source: EventSource, blocks: std::ops::RangeInclusive<u64>) -> SourceEvent {
+        let data = blocks
+            .map(|block| {
+                format!(
+                    r#"{{"local_time":"2025-06-24T02:56:36.1","block_time":"2025-06-24T02:56:36.0","block_number":{block},"events":[]}}"#
+                ) + "\n"
+            })
+            .collect();
+        SourceEvent::Events { event_source, data }
+    }
+
+    // the state an ingest instance would relay at `height`
+    async fn state(height: u64) -> Result<SourceEvent> {
+        let mut ingest = OrderBookListener::new(None, true);
+        ingest.enable_relay();
+        ingest.order_book_state =
+            Some(OrderBookState::from_snapshot(Snapshots::new(HashMap::new()), height, 0, true, true));
+        let (snapshot, _) = OrderBookListener::relay_subscribe(&Mutex::new(ingest)).await.ok_or("not ready")?;
+        Ok(SourceEvent::State(Box::new(snapshot)))
+    }
+
+    #[tokio::test]
+    async fn test_scripted_source() -> Result<()> {
+        let broadcast = Broadcast::new(1);
+        let mut rx = broadcast.subscribe_all();
+        let listener = Arc::new(Mutex::new(OrderBookListener::new(Some(broadcast), true)));
+        let source = ScriptedSource::from_events([
+            SourceEvent::Upstreams(vec![UpstreamStatus { node: "script".to_string(), connected: true }]),
+            state(1).await?,
+            events(EventSource::OrderStatuses, 2..=3),
+            events(EventSource::OrderDiffs, 2..=3),
+            // blocks 4 and 5 are lost
+            events(EventSource::OrderStatuses, 6..=6),
+            events(EventSource::OrderDiffs, 6..=6),
+            state(6).await?,
+        ]);
+        let resyncs = source.resyncs.clone();
+        let resynced = Arc::new(AtomicUsize::new(0));
+        let counter = resynced.clone();
+        listen_source(listener.clone(), source, move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+        })
+        .await?;
+
+        // the gap is in the order statuses and in the diffs
+        assert_eq!(*resyncs.lock().map_err(|_| "poisoned")?, ["the events skipped blocks"; 2]);
+        assert_eq!(resynced.load(Ordering::Relaxed), 1);
+        let mut listener = listener.lock().await;
+        assert_eq!(listener.compute_snapshot().map(|snapshot| snapshot.height), Some(6));
+        listener.send_status(false);
+        assert_eq!(listener.last_status().map(|status| status.upstreams.len()), Some(1));
+        drop(listener);
+        let mut published = false;
+        while let Ok(msg) = rx.try_recv() {
+            published |= matches!(msg.as_ref(), InternalMessage::Snapshot { .. });
+        }
+        assert!(published);
+        Ok(())
+    }
+}
@@ -1,29 +1,37 @@
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
 
 use crate::{
     listeners::order_book::{L2Snapshots, TimedSnapshots, utils::compute_l2_snapshots},
     order_book::{
-        Coin, InnerOrder, Oid,
+        Coin, InnerOrder, Oid, Px, Snapshot,
         multi_book::{OrderBooks, Snapshots},
     },
     prelude::*,
+    snapshot_store::StoredBooks,
     types::{
-        inner::{InnerL4Order, InnerOrderDiff},
+        CHECKSUM_LEVELS, L3BookUpdates, L4BookUpdates, L4Order, OrderEvent, checksum,
+        inner::{InnerL4Order, InnerLevel, InnerOrderDiff},
         node_data::{Batch, NodeDataOrderDiff, NodeDataOrderStatus},
     },
 };
 
 #[derive(Clone)]
-pub(super) struct OrderBookState {
+pub(crate) struct OrderBookState {
     order_book: OrderBooks<InnerL4Order>,
     height: u64,
     time: u64,
     snapped: bool,
     ignore_spot: bool,
+    // sequence of the last published l2 snapshot (shared by all coins, since they are published together)
+    l2_seq: u64,
+    // sequence of the last published l4 update per coin
+    l4_seqs: HashMap<Coin, u64>,
+    // the changes to the orders of the block applied last, by coin, until they are sent with its updates
+    order_events: HashMap<Coin, Vec<OrderEvent>>,
 }
 
 impl OrderBookState {
-    pub(super) fn from_snapshot(
+    pub(crate) fn from_snapshot(
         snapshot: Snapshots<InnerL4Order>,
         height: u64,
         time: u64,
@@ -36,6 +44,52 @@ impl OrderBookState {
             height,
             order_book: OrderBooks::from_snapshots(snapshot, ignore_triggers),
             snapped: false,
+            l2_seq: 0,
+            l4_seqs: HashMap::new(),
+            order_events: HashMap::new(),
+        }
+    }
+
+    // continues the sequence numbers of the saved state
+    pub(super) fn from_stored(stored: StoredBooks, ignore_spot: bool) -> Result<Self> {
+        let StoredBooks { height, time, l2_seq, l4_seqs, books, snapped, last_fill: _ } = stored;
+        let books = books
+            .into_iter()
+            .map(|(coin, orders)| {
+                let [bids, asks] =
+                    orders.map(|orders| orders.into_iter().map(InnerL4Order::try_from).collect::<Result<Vec<_>>>());
+                Ok((Coin::new(&coin), Snapshot::new([bids?, asks?])))
+            })
+            .collect::<Result<HashMap<_, _>>>()?;
+        let mut state = Self::from_snapshot(Snapshots::new(books), height, time, true, ignore_spot);
+        state.l2_seq = l2_seq;
+        state.snapped = snapped;
+        state.l4_seqs = l4_seqs.into_iter().map(|(coin, seq)| (Coin::new(&coin), seq)).collect();
+        Ok(state)
+    }
+
+    pub(super) fn to_stored(&self) -> StoredBooks {
+        let books = self
+            .order_book
+            .as_ref()
+            .iter()
+            .map(|(coin, book)| {
+                let orders = book
+                    .to_snapshot()
+                    .as_ref()
+                    .clone()
+                    .map(|orders| orders.into_iter().map(|order| (order.user, L4Order::from(order))).collect());
+                (coin.value(), orders)
+            })
+            .collect();
+        StoredBooks {
+            height: self.height,
+            time: self.time,
+            l2_seq: self.l2_seq,
+            l4_seqs: self.l4_seqs.iter().map(|(coin, seq)| (coin.value(), *seq)).collect(),
+            books,
+            snapped: self.snapped,
+            last_fill: None,
         }
     }
 
@@ -44,25 +98,128 @@ impl OrderBookState {
     }
 
     // forcibly take snapshot - (time, height, snapshot)
-    pub(super) fn compute_snapshot(&self) -> TimedSnapshots {
+    pub(crate) fn compute_snapshot(&self) -> TimedSnapshots {
         TimedSnapshots { time: self.time, height: self.height, snapshot: self.order_book.to_snapshots_par() }
     }
 
-    // (time, snapshot)
-    pub(super) fn l2_snapshots(&mut self, prevent_future_snaps: bool) -> Option<(u64, L2Snapshots)> {
+    // (time, seq, snapshot)
+    pub(crate) fn l2_snapshots(&mut self, prevent_future_snaps: bool) -> Option<(u64, u64, L2Snapshots)> {
         if self.snapped {
             None
         } else {
             self.snapped = prevent_future_snaps || self.snapped;
-            Some((self.time, compute_l2_snapshots(&self.order_book)))
+            self.l2_seq += 1;
+            Some((self.time, self.l2_seq, compute_l2_snapshots(&self.order_book)))
         }
     }
 
+    // (time, seq, snapshot) of a single coin, tagged with the sequence of the last published l2 snapshot
+    pub(crate) fn l2_snapshot(
+        &self,
+        coin: &Coin,
+        n_levels: usize,
+        n_sig_figs: Option<u32>,
+        mantissa: Option<u64>,
+    ) -> Option<(u64, u64, Snapshot<InnerLevel>)> {
+        self.order_book
+            .as_ref()
+            .get(coin)
+            .map(|book| (self.time, self.l2_seq, book.to_l2_snapshot(Some(n_levels), n_sig_figs, mantissa)))
+    }
+
+    // like `l2_snapshot`, aggregated by a tick size added before
+    pub(super) fn tick_snapshot(
+        &self,
+        coin: &Coin,
+        tick: Px,
+        n_levels: usize,
+    ) -> Option<(u64, u64, Snapshot<InnerLevel>)> {
+        let book = self.order_book.as_ref().get(coin)?;
+        Some((self.time, self.l2_seq, book.tick_snapshot(tick, Some(n_levels))?))
+    }
+
+    pub(super) fn add_tick_group(&mut self, coin: &Coin, tick: Px) -> Result<()> {
+        self.order_book.add_tick_group(coin, tick)
+    }
+
+    pub(crate) fn l4_seq(&self, coin: &Coin) -> u64 {
+        self.l4_seqs.get(coin).copied().unwrap_or_default()
+    }
+
+    // group a block's updates by coin, assigning each coin the next sequence number
+    pub(crate) fn book_updates(
+        &mut self,
+        order_statuses: Batch<NodeDataOrderStatus>,
+        order_diffs: Batch<NodeDataOrderDiff>,
+    ) -> HashMap<String, L4BookUpdates> {
+        let time = order_diffs.block_time();
+        let height = order_diffs.block_number();
+        let mut updates = HashMap::new();
+        for diff in order_diffs.events() {
+            let coin = diff.coin().value();
+            updates.entry(coin).or_insert_with(|| L4BookUpdates::new(time, height)).book_diffs.push(diff);
+        }
+        for status in order_statuses.events() {
+            let coin = status.order.coin.clone();
+            updates.entry(coin).or_insert_with(|| L4BookUpdates::new(time, height)).order_statuses.push(status);
+        }
+        for (coin, update) in &mut updates {
+            let seq = self.l4_seqs.entry(Coin::new(coin)).or_default();
+            *seq += 1;
+            update.seq = *seq;
+            update.checksum = self
+                .l2_snapshot(&Coin::new(coin), CHECKSUM_LEVELS, None, None)
+                .map_or(0, |(_, _, book)| checksum(&book.export_inner_snapshot()));
+        }
+        updates
+    }
+
+    // the l3 updates of the block whose l4 updates these are, with the same sequence numbers
+    pub(crate) fn l3_updates(&mut self, updates: &HashMap<String, L4BookUpdates>) -> HashMap<String, L3BookUpdates> {
+        updates
+            .iter()
+            .map(|(coin, update)| {
+                let events = self.order_events.remove(&Coin::new(coin)).unwrap_or_default();
+                let L4BookUpdates { time, height, seq, .. } = *update;
+                (coin.clone(), L3BookUpdates { coin: coin.clone(), time, height, seq, events })
+            })
+            .collect()
+    }
+
+    // the books changed by the last block applied, with whether each is locked (`Some(true)`), crossed
+    // (`Some(false)`) or neither
+    pub(super) fn crossed_books(&self) -> Vec<(Coin, Option<bool>)> {
+        let books = self.order_book.as_ref();
+        self.order_events.keys().filter_map(|coin| Some((coin.clone(), books.get(coin)?.crossing()))).collect()
+    }
+
+    // the coins of every crossed or locked book
+    pub(super) fn all_crossed(&self) -> BTreeSet<String> {
+        self.order_book
+            .as_ref()
+            .iter()
+            .filter(|(_, book)| book.crossing().is_some())
+            .map(|(coin, _)| coin.value())
+            .collect()
+    }
+
+    // the empty levels and orders without size taken off the books
+    pub(super) fn collect_stale(&mut self) -> usize {
+        self.order_book.collect_stale()
+    }
+
+    // a book rebuilt from a snapshot of the node takes the place of this one, continuing its sequence numbers
+    pub(super) fn continue_from(&mut self, replaced: &Self) {
+        self.time = replaced.time;
+        self.l2_seq = replaced.l2_seq;
+        self.l4_seqs.clone_from(&replaced.l4_seqs);
+    }
+
     pub(super) fn compute_universe(&self) -> HashSet<Coin> {
         self.order_book.as_ref().keys().cloned().collect()
     }
 
-    pub(super) fn apply_updates(
+    pub(crate) fn apply_updates(
         &mut self,
         order_statuses: Batch<NodeDataOrderStatus>,
         order_diffs: Batch<NodeDataOrderDiff>,
@@ -76,7 +233,10 @@ impl OrderBookState {
             // This is not an error in case we started caching long before a snapshot is fetched
             return Ok(());
         }
+        self.order_events.clear();
         let mut diffs = order_diffs.events().into_iter().collect::<VecDeque<_>>();
+        // the statuses of the orders leaving the book, e.g. filled or canceled
+        let mut statuses = HashMap::new();
         let mut order_map = order_statuses
             .events()
             .into_iter()
@@ -84,6 +244,7 @@ impl OrderBookState {
                 if order_status.is_inserted_into_book() {
                     Some((Oid::new(order_status.order.oid), order_status))
                 } else {
+                    statuses.insert(Oid::new(order_status.order.oid), order_status.status);
                     None
                 }
             })
@@ -95,7 +256,9 @@ impl OrderBookState {
                 continue;
             }
             let inner_diff = diff.diff().try_into()?;
-            match inner_diff {
+            // the side and price of an order already on the book
+            let resting = self.order_book.order(&oid, &coin).map(|order| (order.side, order.limit_px.to_str()));
+            let event = match inner_diff {
                 InnerOrderDiff::New { sz } => {
                     if let Some(order) = order_map.remove(&oid) {
                         let time = order.time.and_utc().timestamp_millis();
@@ -104,26 +267,133 @@ impl OrderBookState {
                         // must replace time with time of entering book, which is the timestamp of the order status update
                         #[allow(clippy::unwrap_used)]
                         inner_order.convert_trigger(time.try_into().unwrap());
+                        let InnerL4Order { oid, side, limit_px, timestamp, .. } = inner_order;
                         self.order_book.add_order(inner_order);
+                        OrderEvent::Add { oid, side, px: limit_px.to_str(), sz: sz.to_str(), timestamp }
                     } else {
                         return Err(format!("Unable to find order opening status {diff:?}").into());
                     }
                 }
-                InnerOrderDiff::Update { new_sz, .. } => {
-                    if !self.order_book.modify_sz(oid, coin, new_sz) {
-                        return Err(format!("Unable to find order on the book {diff:?}").into());
+                InnerOrderDiff::Update { orig_sz, new_sz } => match resting {
+                    Some((side, px)) if self.order_book.modify_sz(oid.clone(), coin.clone(), new_sz) => {
+                        OrderEvent::Modify {
+                            oid: oid.value(),
+                            side,
+                            px,
+                            orig_sz: orig_sz.to_str(),
+                            sz: new_sz.to_str(),
+                        }
                     }
-                }
-                InnerOrderDiff::Remove => {
-                    if !self.order_book.cancel_order(oid, coin) {
-                        return Err(format!("Unable to find order on the book {diff:?}").into());
+                    _ => return Err(format!("Unable to find order on the book {diff:?}").into()),
+                },
+                InnerOrderDiff::Remove => match resting {
+                    Some((side, px)) if self.order_book.cancel_order(oid.clone(), coin.clone()) => {
+                        OrderEvent::Cancel { oid: oid.value(), side, px, status: statuses.remove(&oid) }
                     }
-                }
-            }
+                    _ => return Err(format!("Unable to find order on the book {diff:?}").into()),
+                },
+            };
+            self.order_events.entry(coin).or_default().push(event);
         }
+        self.order_book.update_tick_groups();
         self.height += 1;
         self.time = time;
         self.snapped = false;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::order_book::Side;
+
+    fn diff_batch(height: u64, coins: &[&str]) -> Batch<NodeDataOrderDiff> {
+        let events = coins
+            .iter()
+            .map(|coin| {
+                format!(
+                    r#"{{"user":"0x0000000000000000000000000000000000000000","oid":1,"px":"1.0","coin":"{coin}","raw_book_diff":"remove"}}"#
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        let json = format!(
+            r#"{{"local_time":"2025-06-24T02:56:36.172847427","block_time":"2025-06-24T02:56:36.172847427","block_number":{height},"events":[{events}]}}"#
+        );
+        serde_json::from_str(&json).unwrap()
+    }
+
+    fn status_batch(height: u64) -> Batch<NodeDataOrderStatus> {
+        let json = format!(
+            r#"{{"local_time":"2025-06-24T02:56:36.172847427","block_time":"2025-06-24T02:56:36.172847427","block_number":{height},"events":[]}}"#
+        );
+        serde_json::from_str(&json).unwrap()
+    }
+
+    fn batch<E: serde::de::DeserializeOwned>(height: u64, events: &[String]) -> Batch<E> {
+        let json = format!(
+            r#"{{"local_time":"2025-06-24T02:56:36.172847427","block_time":"2025-06-24T02:56:36.172847427","block_number":{height},"events":[{}]}}"#,
+            events.join(",")
+        );
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[test]
+    fn test_l3_updates() {
+        let user = "0x0000000000000000000000000000000000000001";
+        let order = r#"{"coin":"BTC","side":"B","limitPx":"100.0","sz":"1.5","oid":1,"timestamp":1,"triggerCondition":"N/A","isTrigger":false,"triggerPx":"0.0","isPositionTpsl":false,"reduceOnly":false,"orderType":"Limit","tif":"Gtc","cloid":null}"#;
+        let status = |status| {
+            format!(r#"{{"time":"2025-06-24T02:56:36.172847427","user":"{user}","status":"{status}","order":{order}}}"#)
+        };
+        let diff = |diff| format!(r#"{{"user":"{user}","oid":1,"px":"100.0","coin":"BTC","raw_book_diff":{diff}}}"#);
+        let blocks = [
+            (vec![status("open")], diff(r#"{"new":{"sz":"1.5"}}"#)),
+            (Vec::new(), diff(r#"{"update":{"origSz":"1.5","newSz":"0.5"}}"#)),
+            (vec![status("canceled")], diff(r#""remove""#)),
+        ];
+        let mut state = OrderBookState::from_snapshot(Snapshots::new(HashMap::new()), 0, 0, true, true);
+        let mut events = Vec::new();
+        for (height, (statuses, diff)) in (1..).zip(blocks) {
+            let (statuses, diffs) = (batch::<NodeDataOrderStatus>(height, &statuses), batch(height, &[diff]));
+            state.apply_updates(statuses.clone(), diffs.clone()).unwrap();
+            let updates = state.book_updates(statuses, diffs);
+            let mut l3 = state.l3_updates(&updates);
+            let update = l3.remove("BTC").unwrap();
+            assert_eq!(update.seq, height);
+            events.extend(update.events);
+        }
+        let (side, px) = (Side::Bid, "100".to_string());
+        assert_eq!(
+            events,
+            [
+                OrderEvent::Add { oid: 1, side, px: px.clone(), sz: "1.5".to_string(), timestamp: 1 },
+                OrderEvent::Modify { oid: 1, side, px: px.clone(), orig_sz: "1.5".to_string(), sz: "0.5".to_string() },
+                OrderEvent::Cancel { oid: 1, side, px, status: Some("canceled".to_string()) },
+            ]
+        );
+        // nothing is left over for the next block
+        assert!(
+            state.l3_updates(&HashMap::from([("BTC".to_string(), L4BookUpdates::new(0, 0))]))["BTC"].events.is_empty()
+        );
+    }
+
+    #[test]
+    fn test_book_updates_sequence_per_coin() {
+        let mut state = OrderBookState::from_snapshot(Snapshots::new(HashMap::new()), 0, 0, true, true);
+        let updates = state.book_updates(status_batch(1), diff_batch(1, &["BTC", "ETH"]));
+        assert_eq!(updates["BTC"].seq, 1);
+        assert_eq!(updates["ETH"].seq, 1);
+        let updates = state.book_updates(status_batch(2), diff_batch(2, &["BTC", "BTC"]));
+        assert_eq!(updates["BTC"].seq, 2);
+        assert_eq!(updates["BTC"].book_diffs.len(), 2);
+        assert!(!updates.contains_key("ETH"));
+        assert_eq!(state.l4_seq(&Coin::new("ETH")), 1);
+        assert_eq!(state.l4_seq(&Coin::new("SOL")), 0);
+
+        let stored = state.to_stored();
+        assert_eq!((stored.height, stored.l4_seqs["BTC"]), (0, 2));
+        let restored = OrderBookState::from_stored(stored, true).unwrap();
+        assert_eq!(restored.l4_seq(&Coin::new("BTC")), 2);
+    }
+}
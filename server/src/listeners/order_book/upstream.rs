@@ -0,0 +1,500 @@
+use std::{
+    fmt,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use fs::File;
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher, recommended_watcher};
+use serde::Deserialize;
+use tokio::{sync::mpsc::UnboundedSender, time::Instant};
+use tracing::{error, info, info_span, warn};
+
+use crate::{
+    HL_NODE,
+    listeners::{directory::DirectoryListener, order_book::OrderBookListener},
+    metrics::METRICS,
+    prelude::*,
+    types::{UpstreamStatus, node_data::EventSource},
+};
+
+pub(crate) const DEFAULT_INFO_URL: &str = "http://localhost:3001/info";
+const RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_mins(1);
+
+/// A node to ingest events from: the directory its `hl/data` event files are written to,
+/// and the info endpoint used to fetch order book snapshots from it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpstreamNode {
+    pub data_dir: PathBuf,
+    pub info_url: String,
+}
+
+impl UpstreamNode {
+    #[must_use]
+    pub fn new(data_dir: PathBuf) -> Self {
+        Self { data_dir, info_url: DEFAULT_INFO_URL.to_string() }
+    }
+}
+
+// `<data dir>[=<info url>]`
+impl FromStr for UpstreamNode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((data_dir, info_url)) if !data_dir.is_empty() && !info_url.is_empty() => {
+                Ok(Self { data_dir: data_dir.into(), info_url: info_url.to_string() })
+            }
+            Some(_) => Err(format!("invalid upstream {s} (expected <data dir>[=<info url>])")),
+            None if s.is_empty() => Err("empty upstream data directory".to_string()),
+            None => Ok(Self::new(s.into())),
+        }
+    }
+}
+
+impl fmt::Display for UpstreamNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.data_dir.display())
+    }
+}
+
+/// What happens once none of the upstreams delivered a new block for `inactivity_exit_secs`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InactivityPolicy {
+    /// Exit, for a supervisor to restart the server.
+    Exit,
+    /// Tell clients that the stream is stale and watch the node's files again, backing off exponentially between
+    /// attempts. Exit only once there has been no new block for `deadline`.
+    Reconnect { deadline: Duration },
+}
+
+// watches the event directories of every upstream, sending their events to `tx`
+pub(super) fn watch_upstreams(
+    upstreams: &[Upstream],
+    tx: &UnboundedSender<notify::Result<Event>>,
+) -> Result<RecommendedWatcher> {
+    let tx = tx.clone();
+    let mut watcher = recommended_watcher(move |res| {
+        if let Err(err) = tx.send(res) {
+            error!("Error sending fs event to processor via channel: {err}");
+        }
+    })?;
+    for upstream in upstreams {
+        upstream.watch(&mut watcher)?;
+    }
+    Ok(watcher)
+}
+
+// Whether any upstream still delivers new blocks, and what to do once none does
+pub(super) struct Inactivity {
+    policy: InactivityPolicy,
+    timeout: Duration,
+    // last time any upstream delivered a block we hadn't seen yet
+    last_progress: Instant,
+    stale: bool,
+    // reconnect attempts since the stream went stale
+    attempts: u32,
+    next_attempt: Instant,
+}
+
+impl Inactivity {
+    pub(super) fn new(policy: InactivityPolicy, timeout: Duration) -> Self {
+        let now = Instant::now();
+        Self { policy, timeout, last_progress: now, stale: false, attempts: 0, next_attempt: now }
+    }
+
+    pub(super) fn on_progress(&mut self, listener: &mut OrderBookListener) {
+        if self.stale {
+            info!("Node stream recovered after {:.1?}", self.last_progress.elapsed());
+            listener.set_stale(false);
+            self.stale = false;
+            self.attempts = 0;
+        }
+        self.last_progress = Instant::now();
+    }
+
+    // when `on_timeout` is due
+    pub(super) fn deadline(&self) -> Instant {
+        if self.stale { self.next_attempt } else { self.last_progress + self.timeout }
+    }
+
+    // true if the upstreams should be watched again. Errors once the server should exit
+    pub(super) fn on_timeout(&mut self, listener: &mut OrderBookListener) -> Result<bool> {
+        // nothing to serve yet, so nothing to go stale
+        if !listener.is_ready() {
+            self.last_progress = Instant::now();
+            return Ok(false);
+        }
+        let InactivityPolicy::Reconnect { deadline } = self.policy else {
+            return Err(format!("Stream has fallen behind ({HL_NODE} failed on every upstream?)").into());
+        };
+        let stale_for = self.last_progress.elapsed();
+        if stale_for >= deadline {
+            return Err(format!("No new blocks for {stale_for:.0?} ({HL_NODE} failed on every upstream?)").into());
+        }
+        if !self.stale {
+            warn!("No new blocks for {stale_for:.1?}, watching the node's files again");
+            listener.set_stale(true);
+            self.stale = true;
+        }
+        let backoff = RECONNECT_BACKOFF.saturating_mul(1 << self.attempts.min(6)).min(MAX_RECONNECT_BACKOFF);
+        self.attempts += 1;
+        self.next_attempt = Instant::now() + backoff;
+        Ok(true)
+    }
+}
+
+#[derive(Default)]
+struct EventFiles {
+    fills: Option<File>,
+    order_statuses: Option<File>,
+    order_diffs: Option<File>,
+}
+
+// Every upstream is read at the same time. The order book listener drops blocks it has already seen,
+// so whichever node delivers a block first wins, and a halted node is simply no longer heard from.
+pub(super) struct Upstream {
+    pub(super) node: UpstreamNode,
+    dirs: Vec<(EventSource, PathBuf)>,
+    files: EventFiles,
+    // highest block read from this node (duplicates included) and when it was read
+    last_block: Option<u64>,
+    last_progress: Instant,
+    healthy: bool,
+}
+
+impl Upstream {
+    pub(super) fn new(node: UpstreamNode) -> Result<Self> {
+        let mut dirs = Vec::new();
+        for event_source in [EventSource::OrderStatuses, EventSource::Fills, EventSource::OrderDiffs] {
+            let dir = event_source.event_source_dir(&node.data_dir);
+            let dir = dir.canonicalize().map_err(|err| format!("Unable to open {}: {err}", dir.display()))?;
+            dirs.push((event_source, dir));
+        }
+        METRICS.upstream_healthy.with_label_values(&[node.to_string()]).set(1);
+        Ok(Self {
+            node,
+            dirs,
+            files: EventFiles::default(),
+            last_block: None,
+            last_progress: Instant::now(),
+            healthy: true,
+        })
+    }
+
+    pub(super) fn watch(&self, watcher: &mut impl Watcher) -> Result<()> {
+        for (event_source, dir) in &self.dirs {
+            info!("Monitoring {event_source} directory: {}", dir.display());
+            watcher.watch(dir, RecursiveMode::Recursive)?;
+        }
+        Ok(())
+    }
+
+    pub(super) fn event_source(&self, path: &Path) -> Option<EventSource> {
+        self.dirs.iter().find(|(_, dir)| path.starts_with(dir)).map(|(event_source, _)| *event_source)
+    }
+
+    pub(super) const fn last_block(&self) -> Option<u64> {
+        self.last_block
+    }
+
+    pub(super) const fn is_healthy(&self) -> bool {
+        self.healthy
+    }
+
+    pub(super) fn status(&self) -> UpstreamStatus {
+        UpstreamStatus { node: self.node.to_string(), connected: self.healthy }
+    }
+
+    // a node is unhealthy once it hasn't produced a new block for `timeout`
+    pub(super) fn check_health(&mut self, timeout: Duration) {
+        let healthy = self.last_progress.elapsed() < timeout;
+        if healthy != self.healthy {
+            if healthy {
+                info!("Upstream {} recovered at block {:?}", self.node, self.last_block);
+            } else {
+                warn!(
+                    "Upstream {} stalled at block {:?}, continuing with the remaining upstreams",
+                    self.node, self.last_block
+                );
+            }
+            self.healthy = healthy;
+            METRICS.upstream_healthy.with_label_values(&[self.node.to_string()]).set(i64::from(healthy));
+        }
+    }
+
+    const fn reader<'a>(&'a mut self, read: &'a mut Vec<(EventSource, String)>) -> UpstreamReader<'a> {
+        UpstreamReader {
+            files: &mut self.files,
+            read,
+            last_block: &mut self.last_block,
+            last_progress: &mut self.last_progress,
+        }
+    }
+
+    // reads whatever was appended to the files being read, in case the watcher missed it
+    pub(super) fn poll(&mut self) -> Result<Vec<(EventSource, String)>> {
+        let _span = info_span!("upstream", node = %self.node).entered();
+        let mut read = Vec::new();
+        let mut reader = self.reader(&mut read);
+        for event_source in [EventSource::OrderStatuses, EventSource::Fills, EventSource::OrderDiffs] {
+            if reader.is_reading(event_source) {
+                reader.on_file_modification(event_source)?;
+            }
+        }
+        Ok(read)
+    }
+
+    // the complete lines read for an event of the watcher. A `restoring` order book needs the events it missed, from
+    // the start of the first file read
+    pub(super) fn process_update(
+        &mut self,
+        event: &Event,
+        new_path: &PathBuf,
+        event_source: EventSource,
+        restoring: bool,
+    ) -> Result<Vec<(EventSource, String)>> {
+        let _span = info_span!("upstream", node = %self.node).entered();
+        let mut read = Vec::new();
+        let mut reader = self.reader(&mut read);
+        if event.kind.is_create() {
+            info!("-- Event: {} created --", new_path.display());
+            reader.on_file_creation(new_path.clone(), event_source)?;
+        }
+        // Check for `Modify` event (only if the file is already initialized)
+        else {
+            // If we are not tracking anything right now, we treat a file update as declaring that it has been created.
+            // Unfortunately, we miss the update that occurs at this time step.
+            // We go to the end of the file to read for updates after that.
+            if reader.is_reading(event_source) {
+                reader.on_file_modification(event_source)?;
+            } else {
+                info!("-- Event: {} modified, tracking it now --", new_path.display());
+                let mut new_file = File::open(new_path)?;
+                if !restoring {
+                    new_file.seek(SeekFrom::End(0))?;
+                }
+                *reader.file_mut(event_source) = Some(new_file);
+                if restoring {
+                    reader.on_file_modification(event_source)?;
+                }
+            }
+        }
+        Ok(read)
+    }
+}
+
+// reads the event files of a single upstream, keeping the complete lines for the order book listener
+struct UpstreamReader<'a> {
+    files: &'a mut EventFiles,
+    read: &'a mut Vec<(EventSource, String)>,
+    last_block: &'a mut Option<u64>,
+    last_progress: &'a mut Instant,
+}
+
+impl DirectoryListener for UpstreamReader<'_> {
+    fn is_reading(&self, event_source: EventSource) -> bool {
+        match event_source {
+            EventSource::Fills => self.files.fills.is_some(),
+            EventSource::OrderStatuses => self.files.order_statuses.is_some(),
+            EventSource::OrderDiffs => self.files.order_diffs.is_some(),
+        }
+    }
+
+    fn file_mut(&mut self, event_source: EventSource) -> &mut Option<File> {
+        match event_source {
+            EventSource::Fills => &mut self.files.fills,
+            EventSource::OrderStatuses => &mut self.files.order_statuses,
+            EventSource::OrderDiffs => &mut self.files.order_diffs,
+        }
+    }
+
+    fn on_file_creation(&mut self, new_file: PathBuf, event_source: EventSource) -> Result<()> {
+        if let Some(file) = self.file_mut(event_source).as_mut() {
+            let mut buf = String::new();
+            file.read_to_string(&mut buf)?;
+            if !buf.is_empty() {
+                self.process_data(buf, event_source)?;
+            }
+        }
+        *self.file_mut(event_source) = Some(File::open(new_file)?);
+        Ok(())
+    }
+
+    fn process_data(&mut self, mut data: String, event_source: EventSource) -> Result<()> {
+        // the last line may still be being written, read it again next time
+        let complete = data.rfind('\n').map_or(0, |end| end + 1);
+        if complete < data.len() {
+            let partial = i64::try_from(data.len() - complete)?;
+            if let Some(file) = self.file_mut(event_source).as_mut() {
+                file.seek_relative(-partial)?;
+            }
+            data.truncate(complete);
+        }
+        if let Some(block) = last_block(&data)
+            && self.last_block.is_none_or(|last_block| last_block < block)
+        {
+            *self.last_block = Some(block);
+            *self.last_progress = Instant::now();
+        }
+        if !data.is_empty() {
+            self.read.push((event_source, data));
+        }
+        Ok(())
+    }
+}
+
+// the block of the last line, without decoding its events
+fn last_block(data: &str) -> Option<u64> {
+    #[derive(Deserialize)]
+    struct Block {
+        block_number: u64,
+    }
+    let line = data.trim_end().rsplit('\n').next()?;
+    serde_json::from_str::<Block>(line).ok().map(|block| block.block_number)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use std::io::Write;
+
+    use notify::event::{CreateKind, EventKind};
+    use tokio::time::sleep;
+
+    use super::*;
+    use crate::{
+        listeners::order_book::{Broadcast, InternalMessage, state::OrderBookState},
+        order_book::multi_book::Snapshots,
+    };
+
+    fn fills(blocks: std::ops::RangeInclusive<u64>) -> String {
+        blocks
+            .map(|block| {
+                format!(
+                    r#"{{"local_time":"2025-06-24T02:56:36.1","block_time":"2025-06-24T02:56:36.0","block_number":{block},"events":[]}}"#
+                ) + "\n"
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_reconnects_until_deadline() -> Result<()> {
+        let tx = Broadcast::new(1);
+        let mut rx = tx.subscribe_all();
+        let mut listener = OrderBookListener::new(Some(tx), true);
+        let mut inactivity = Inactivity::new(InactivityPolicy::Exit, Duration::from_millis(10));
+        // not ready yet
+        assert!(!inactivity.on_timeout(&mut listener)?);
+        listener.order_book_state =
+            Some(OrderBookState::from_snapshot(Snapshots::new(HashMap::new()), 0, 0, true, true));
+        assert!(inactivity.on_timeout(&mut listener).is_err());
+
+        let policy = InactivityPolicy::Reconnect { deadline: Duration::from_millis(100) };
+        let mut inactivity = Inactivity::new(policy, Duration::from_millis(10));
+        listener.send_status(false);
+        assert!(matches!(rx.try_recv()?.as_ref(), InternalMessage::Status { stale_changed: false, .. }));
+        assert!(inactivity.on_timeout(&mut listener)?);
+        assert!(inactivity.on_timeout(&mut listener)?);
+        listener.send_status(false);
+        assert!(matches!(
+            rx.try_recv()?.as_ref(),
+            InternalMessage::Status { status, stale_changed: true } if status.stale && status.ready
+        ));
+        inactivity.on_progress(&mut listener);
+        listener.send_status(true);
+        assert!(matches!(
+            rx.try_recv()?.as_ref(),
+            InternalMessage::Status { status, stale_changed: true } if !status.stale && status.maintenance
+        ));
+        sleep(Duration::from_millis(100)).await;
+        assert!(inactivity.on_timeout(&mut listener).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_upstream() {
+        assert_eq!("/data/a".parse(), Ok(UpstreamNode::new("/data/a".into())));
+        let node: UpstreamNode = "/data/b=http://10.0.0.2:3001/info".parse().unwrap();
+        assert_eq!(node.info_url, "http://10.0.0.2:3001/info");
+        assert!("/data/c=".parse::<UpstreamNode>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_blocks_are_deduplicated_across_upstreams() -> Result<()> {
+        let tx = Broadcast::new(1);
+        let mut rx = tx.subscribe_all();
+        let mut listener = OrderBookListener::new(Some(tx), true);
+        listener.process_data(&fills(1..=2), EventSource::Fills)?;
+        // a second node that lags behind, then takes over
+        listener.process_data(&fills(1..=4), EventSource::Fills)?;
+        assert_eq!(listener.latest_block(), 4);
+        let mut blocks = Vec::new();
+        while blocks.len() < 4 {
+            if let InternalMessage::Fills { batch, .. } = rx.recv().await?.as_ref() {
+                blocks.push(batch.block_number());
+            }
+        }
+        blocks.sort_unstable();
+        assert_eq!(blocks, vec![1, 2, 3, 4]);
+        assert!(rx.try_recv().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_gap_drops_book_until_snapshot() -> Result<()> {
+        let mut listener = OrderBookListener::new(None, true);
+        listener.order_book_state =
+            Some(OrderBookState::from_snapshot(Snapshots::new(HashMap::new()), 0, 0, true, true));
+        for event_source in [EventSource::OrderStatuses, EventSource::OrderDiffs, EventSource::Fills] {
+            listener.process_data(&fills(1..=2), event_source)?;
+        }
+        assert_eq!(listener.compute_snapshot().map(|snapshot| snapshot.height), Some(2));
+        // missed fills don't affect the book
+        listener.process_data(&fills(4..=4), EventSource::Fills)?;
+        assert!(listener.is_ready());
+        assert!(!listener.take_resync_request());
+
+        listener.process_data(&fills(4..=5), EventSource::OrderStatuses)?;
+        assert!(!listener.is_ready());
+        assert!(listener.take_resync_request());
+        assert!(!listener.take_resync_request());
+        listener.process_data(&fills(4..=5), EventSource::OrderDiffs)?;
+        listener.send_status(false);
+        assert_eq!(listener.last_status().map(|status| status.gaps), Some(3));
+
+        // a snapshot past the gap brings the book back, for its clients to get new snapshots
+        assert!(listener.init_from_snapshot(Snapshots::new(HashMap::new()), 4));
+        assert_eq!(listener.compute_snapshot().map(|snapshot| snapshot.height), Some(5));
+        assert!(!listener.init_from_snapshot(Snapshots::new(HashMap::new()), 5));
+        Ok(())
+    }
+    #[test]
+    fn test_reads_complete_lines() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        for event_source in [EventSource::OrderStatuses, EventSource::Fills, EventSource::OrderDiffs] {
+            fs::create_dir_all(event_source.event_source_dir(dir.path()))?;
+        }
+        let mut upstream = Upstream::new(UpstreamNode::new(dir.path().to_path_buf()))?;
+        let path = EventSource::Fills.event_source_dir(dir.path()).canonicalize()?.join("0");
+        let lines = fills(1..=2);
+        let (written, partial) = lines.split_at(lines.len() - 10);
+        fs::write(&path, written)?;
+        let event = Event::new(EventKind::Create(CreateKind::File)).add_path(path.clone());
+        let lines = |read: Vec<(EventSource, String)>| read.into_iter().map(|(_, data)| data).collect::<Vec<_>>();
+
+        assert!(upstream.process_update(&event, &path, EventSource::Fills, false)?.is_empty());
+        // the second line is still being written
+        assert_eq!(lines(upstream.poll()?), [fills(1..=1)]);
+        assert_eq!(upstream.last_block(), Some(1));
+        assert!(upstream.poll()?.is_empty());
+        fs::OpenOptions::new().append(true).open(&path)?.write_all(partial.as_bytes())?;
+        assert_eq!(lines(upstream.poll()?), [fills(2..=2)]);
+        assert_eq!(upstream.last_block(), Some(2));
+        Ok(())
+    }
+}
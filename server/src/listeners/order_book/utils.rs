@@ -1,28 +1,29 @@
 use std::{
     collections::{HashMap, VecDeque},
-    path::{Path, PathBuf},
+    path::PathBuf,
 };
 
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
 use reqwest::Client;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 
 use crate::{
-    listeners::order_book::{L2SnapshotParams, L2Snapshots},
+    listeners::order_book::{L2SnapshotParams, L2Snapshots, UpstreamNode},
     order_book::{
-        Snapshot,
+        Coin, Px, Snapshot,
         multi_book::{OrderBooks, Snapshots},
         types::InnerOrder,
     },
     prelude::*,
     types::{
-        inner::InnerLevel,
+        inner::{InnerL4Order, InnerLevel},
         node_data::{Batch, NodeDataFill, NodeDataOrderDiff, NodeDataOrderStatus},
     },
 };
 
-pub(super) async fn process_rmp_file(dir: &Path) -> Result<PathBuf> {
-    let output_path = dir.join("out.json");
+pub(super) async fn process_rmp_file(upstream: &UpstreamNode) -> Result<PathBuf> {
+    let output_path = upstream.data_dir.join("out.json");
     let payload = json!({
         "type": "fileSnapshot",
         "request": {
@@ -36,7 +37,7 @@ pub(super) async fn process_rmp_file(dir: &Path) -> Result<PathBuf> {
 
     let client = Client::new();
     client
-        .post("http://localhost:3001/info")
+        .post(&upstream.info_url)
         .header("Content-Type", "application/json")
         .json(&payload)
         .send()
@@ -45,42 +46,9 @@ pub(super) async fn process_rmp_file(dir: &Path) -> Result<PathBuf> {
     Ok(output_path)
 }
 
-pub(super) fn validate_snapshot_consistency<O: Clone + PartialEq + Debug>(
-    snapshot: &Snapshots<O>,
-    expected: Snapshots<O>,
-    ignore_spot: bool,
-) -> Result<()> {
-    let mut snapshot_map: HashMap<_, _> =
-        expected.value().into_iter().filter(|(c, _)| !c.is_spot() || !ignore_spot).collect();
-
-    for (coin, book) in snapshot.as_ref() {
-        if ignore_spot && coin.is_spot() {
-            continue;
-        }
-        let book1 = book.as_ref();
-        if let Some(book2) = snapshot_map.remove(coin) {
-            for (orders1, orders2) in book1.as_ref().iter().zip(book2.as_ref()) {
-                for (order1, order2) in orders1.iter().zip(orders2.iter()) {
-                    if *order1 != *order2 {
-                        return Err(
-                            format!("Orders do not match, expected: {:?} received: {:?}", *order2, *order1).into()
-                        );
-                    }
-                }
-            }
-        } else if !book1[0].is_empty() || !book1[1].is_empty() {
-            return Err(format!("Missing {} book", coin.value()).into());
-        }
-    }
-    if !snapshot_map.is_empty() {
-        return Err("Extra orderbooks detected".to_string().into());
-    }
-    Ok(())
-}
-
 impl L2SnapshotParams {
-    pub(crate) const fn new(n_sig_figs: Option<u32>, mantissa: Option<u64>) -> Self {
-        Self { n_sig_figs, mantissa }
+    pub(crate) const fn new(n_sig_figs: Option<u32>, mantissa: Option<u64>, tick_size: Option<Px>) -> Self {
+        Self { n_sig_figs, mantissa, tick_size }
     }
 }
 
@@ -92,11 +60,11 @@ pub(super) fn compute_l2_snapshots<O: InnerOrder + Send + Sync>(order_books: &Or
             .map(|(coin, order_book)| {
                 let mut entries = Vec::new();
                 let snapshot = order_book.to_l2_snapshot(None, None, None);
-                entries.push((L2SnapshotParams { n_sig_figs: None, mantissa: None }, snapshot));
+                entries.push((L2SnapshotParams::new(None, None, None), snapshot));
                 let mut add_new_snapshot = |n_sig_figs: Option<u32>, mantissa: Option<u64>, idx: usize| {
                     if let Some((_, last_snapshot)) = &entries.get(entries.len() - idx) {
                         let snapshot = last_snapshot.to_l2_snapshot(None, n_sig_figs, mantissa);
-                        entries.push((L2SnapshotParams { n_sig_figs, mantissa }, snapshot));
+                        entries.push((L2SnapshotParams::new(n_sig_figs, mantissa, None), snapshot));
                     }
                 };
                 for n_sig_figs in (2..=5).rev() {
@@ -113,6 +81,12 @@ pub(super) fn compute_l2_snapshots<O: InnerOrder + Send + Sync>(order_books: &Or
                         add_new_snapshot(Some(n_sig_figs), None, 1);
                     }
                 }
+                // tick groups are kept up to date by the book
+                for tick in order_book.tick_sizes() {
+                    if let Some(snapshot) = order_book.tick_snapshot(tick, None) {
+                        entries.push((L2SnapshotParams::new(None, None, Some(tick)), snapshot));
+                    }
+                }
                 (coin.clone(), entries.into_iter().collect::<HashMap<L2SnapshotParams, Snapshot<InnerLevel>>>())
             })
             .collect(),
@@ -125,6 +99,42 @@ pub(super) enum EventBatch {
     Fills(Batch<NodeDataFill>),
 }
 
+impl EventBatch {
+    pub(super) fn block_time(&self) -> u64 {
+        match self {
+            Self::Orders(batch) => batch.block_time(),
+            Self::BookDiffs(batch) => batch.block_time(),
+            Self::Fills(batch) => batch.block_time(),
+        }
+    }
+
+    pub(super) fn prefix_coins(&mut self, prefix: &str) {
+        match self {
+            Self::Orders(batch) => batch.prefix_coins(prefix),
+            Self::BookDiffs(batch) => batch.prefix_coins(prefix),
+            Self::Fills(batch) => batch.prefix_coins(prefix),
+        }
+    }
+}
+
+// namespaces the coins of a snapshot from the node like the events of its market
+pub(super) fn prefix_snapshot_coins(snapshot: Snapshots<InnerL4Order>, prefix: &str) -> Snapshots<InnerL4Order> {
+    Snapshots::new(
+        snapshot
+            .value()
+            .into_iter()
+            .map(|(coin, book)| {
+                let coin = Coin::new(&format!("{prefix}{}", coin.value()));
+                let orders = book.as_ref().clone().map(|orders| {
+                    orders.into_iter().map(|order| InnerL4Order { coin: coin.clone(), ..order }).collect()
+                });
+                (coin, Snapshot::new(orders))
+            })
+            .collect(),
+    )
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub(super) struct BatchQueue<T> {
     deque: VecDeque<Batch<T>>,
     last_ts: Option<u64>,
@@ -146,6 +156,11 @@ impl<T> BatchQueue<T> {
         true
     }
 
+    // the latest block pushed
+    pub(super) const fn last_block(&self) -> Option<u64> {
+        self.last_ts
+    }
+
     pub(super) fn pop_front(&mut self) -> Option<Batch<T>> {
         self.deque.pop_front()
     }
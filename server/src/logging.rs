@@ -0,0 +1,349 @@
+use std::{
+    env, fmt, io,
+    str::FromStr,
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    Resource,
+    trace::{Sampler, SdkTracerProvider},
+};
+use serde::Serialize;
+use tokio::time::sleep;
+use tracing::{error, info, level_filters::LevelFilter};
+use tracing_subscriber::{
+    EnvFilter, Layer, Registry, filter::filter_fn, layer::SubscriberExt, reload, util::SubscriberInitExt,
+};
+
+use crate::{latency::now_ms, prelude::*};
+
+// the target of the spans following node events through the server to the clients, which are exported over OTLP
+pub(crate) const PIPELINE: &str = "order_book_server::pipeline";
+
+// the filters of the log lines, changed by the runtime settings, the admin API and SIGUSR1
+static FILTERS: OnceLock<Filters> = OnceLock::new();
+
+// what SIGUSR1 switches the log to, and back from
+#[cfg(unix)]
+const SIGUSR1_FILTER: &str = "info,server=debug";
+
+struct Filters {
+    max_level: reload::Handle<LevelFilter, Registry>,
+    directives: reload::Handle<Option<EnvFilter>, Registry>,
+    state: Mutex<FilterState>,
+}
+
+struct FilterState {
+    // of the runtime settings, left aside while an override is set
+    max_level: LevelFilter,
+    // `RUST_LOG`, likewise
+    env: Option<String>,
+    current: Option<LogOverride>,
+    // counts the overrides, so that the expiry of one that was replaced leaves its successor alone
+    generation: u64,
+}
+
+/// Directives in the syntax of `RUST_LOG` (e.g. `info,server::servers::websocket_server=trace`) that replace the
+/// log level and `RUST_LOG` until `until`, in ms since the epoch, or until they are cleared.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct LogOverride {
+    pub(crate) filter: String,
+    pub(crate) until: Option<u64>,
+}
+
+/// The filters applied to the log, as listed by the admin API.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct LogFilters {
+    pub(crate) level: String,
+    pub(crate) env: Option<String>,
+    #[serde(rename = "override")]
+    pub(crate) current: Option<LogOverride>,
+}
+
+/// How log lines are written to stderr.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// One human readable line per event, prefixed by its spans.
+    #[default]
+    Text,
+    /// One JSON object per event, with its fields and those of its spans (e.g. `client` and `remote_addr` of a
+    /// connection, `block` of a node event).
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("invalid log format {s} (expected json or text)")),
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Text => "text",
+            Self::Json => "json",
+        })
+    }
+}
+
+/// Where the pipeline spans are exported to over OTLP/HTTP: one trace per read of a node's events, with spans for
+/// decoding, applying, publishing and the fan-out and serialization for each client.
+#[derive(Debug, Clone)]
+pub struct OtlpConfig {
+    /// The collector's base URL, e.g. `http://localhost:4318`. Spans are posted to `<endpoint>/v1/traces`.
+    pub endpoint: String,
+    pub service_name: String,
+    /// The share of traces exported, between 0 and 1.
+    pub sample_ratio: f64,
+}
+
+impl OtlpConfig {
+    #[must_use]
+    pub fn new(endpoint: String) -> Self {
+        Self { endpoint, service_name: "order_book_server".to_string(), sample_ratio: 1.0 }
+    }
+
+    fn tracer_provider(&self) -> Result<SdkTracerProvider> {
+        let endpoint = format!("{}/v1/traces", self.endpoint.trim_end_matches('/'));
+        let exporter = SpanExporter::builder().with_http().with_endpoint(endpoint).build()?;
+        let sampler = Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(self.sample_ratio)));
+        Ok(SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_sampler(sampler)
+            .with_resource(Resource::builder().with_service_name(self.service_name.clone()).build())
+            .build())
+    }
+}
+
+/// Flushes the spans not exported yet when dropped.
+#[must_use]
+pub struct LoggingGuard(Option<SdkTracerProvider>);
+
+impl Drop for LoggingGuard {
+    fn drop(&mut self) {
+        if let Some(provider) = self.0.take()
+            && let Err(err) = provider.shutdown()
+        {
+            error!("Unable to export the remaining spans: {err}");
+        }
+    }
+}
+
+/// Installs the global logger, which also takes the records of crates logging through `log`.
+///
+/// Events are logged up to `max_level`, which [`ServerConfig::log_level`](crate::ServerConfig::log_level) changes later on, and
+/// filtered further by the directives in `RUST_LOG`, if set. The pipeline spans are exported with `otlp`
+/// regardless of the log level.
+pub fn init_logging(format: LogFormat, max_level: LevelFilter, otlp: Option<OtlpConfig>) -> Result<LoggingGuard> {
+    let level = max_level;
+    let (max_level, max_level_handle) = reload::Layer::new(max_level);
+    let env = env::var("RUST_LOG").ok();
+    let (env_filter, directives_handle) = reload::Layer::new(env.as_deref().map(directives));
+    let layer = tracing_subscriber::fmt::layer().with_writer(io::stderr);
+    let layer = match format {
+        LogFormat::Text => layer.boxed(),
+        LogFormat::Json => layer.json().boxed(),
+    };
+    let mut layers = vec![layer.with_filter(max_level).with_filter(env_filter).boxed()];
+    let provider = otlp.map(|otlp| otlp.tracer_provider()).transpose()?;
+    if let Some(provider) = &provider {
+        let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer("order_book_server"));
+        layers.push(layer.with_filter(filter_fn(|meta| meta.is_span() && meta.target() == PIPELINE)).boxed());
+    }
+    tracing_subscriber::registry().with(layers).try_init()?;
+    let state = FilterState { max_level: level, env, current: None, generation: 0 };
+    let _unused =
+        FILTERS.set(Filters { max_level: max_level_handle, directives: directives_handle, state: Mutex::new(state) });
+    Ok(LoggingGuard(provider))
+}
+
+// `RUST_LOG` is read leniently, like `EnvFilter::from_default_env` does
+fn directives(filter: &str) -> EnvFilter {
+    EnvFilter::builder().parse_lossy(filter)
+}
+
+impl Filters {
+    fn apply(&self, max_level: LevelFilter, directives: Option<EnvFilter>) {
+        let res = self.max_level.modify(|current| *current = max_level);
+        if let Err(err) = res.and_then(|()| self.directives.modify(|current| *current = directives)) {
+            error!("Unable to change the log filters: {err}");
+        }
+    }
+}
+
+// no-op until the logger is installed. Takes effect once an override ends
+pub(crate) fn set_max_level(level: LevelFilter) {
+    let Some(filters) = FILTERS.get() else {
+        return;
+    };
+    let Ok(mut state) = filters.state.lock() else {
+        return;
+    };
+    state.max_level = level;
+    if state.current.is_none() {
+        filters.apply(level, state.env.as_deref().map(directives));
+    }
+}
+
+// None until the logger is installed
+pub(crate) fn log_filters() -> Option<LogFilters> {
+    let state = FILTERS.get()?.state.lock().ok()?;
+    Some(LogFilters {
+        level: state.max_level.to_string().to_lowercase(),
+        env: state.env.clone(),
+        current: state.current.clone(),
+    })
+}
+
+// replaces the log level and `RUST_LOG` by `filter`, for `duration` if set. Needs a runtime for the expiry
+pub(crate) fn override_log_filter(filter: &str, duration: Option<Duration>) -> Result<LogOverride> {
+    let filters = FILTERS.get().ok_or("Logging isn't set up by this server")?;
+    let parsed = EnvFilter::builder().parse(filter).map_err(|err| format!("invalid log filter {filter}: {err}"))?;
+    let mut state = filters.state.lock().map_err(|_| "log filters poisoned")?;
+    state.generation += 1;
+    let until = duration.map(|duration| now_ms() + u64::try_from(duration.as_millis()).unwrap_or(u64::MAX));
+    let current = LogOverride { filter: filter.to_string(), until };
+    state.current = Some(current.clone());
+    filters.apply(LevelFilter::TRACE, Some(parsed));
+    let generation = state.generation;
+    drop(state);
+    if let Some(duration) = duration {
+        info!("Log filter set to {filter} for {}s", duration.as_secs());
+        tokio::spawn(async move {
+            sleep(duration).await;
+            clear_override(Some(generation));
+        });
+    } else {
+        info!("Log filter set to {filter}");
+    }
+    Ok(current)
+}
+
+// back to the log level and `RUST_LOG`; false if no override was set
+pub(crate) fn clear_log_filter() -> bool {
+    clear_override(None)
+}
+
+// only the override of `generation`, if given
+fn clear_override(generation: Option<u64>) -> bool {
+    let Some(filters) = FILTERS.get() else {
+        return false;
+    };
+    let Ok(mut state) = filters.state.lock() else {
+        return false;
+    };
+    if state.current.is_none() || generation.is_some_and(|generation| generation != state.generation) {
+        return false;
+    }
+    state.current = None;
+    filters.apply(state.max_level, state.env.as_deref().map(directives));
+    drop(state);
+    info!("Log filter override ended");
+    true
+}
+
+// SIGUSR1 switches to debug logs of the server, and back on the next one. Ignored without our logger, and on
+// Windows, which has no SIGUSR1; `PUT /log` of the admin API does the same
+#[cfg(unix)]
+pub(crate) fn toggle_on_sigusr1() -> Result<()> {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    if FILTERS.get().is_none() {
+        return Ok(());
+    }
+    let mut sigusr1 = signal(SignalKind::user_defined1())?;
+    tokio::spawn(async move {
+        while sigusr1.recv().await.is_some() {
+            info!("Received SIGUSR1, toggling debug logs");
+            if !clear_log_filter()
+                && let Err(err) = override_log_filter(SIGUSR1_FILTER, None)
+            {
+                error!("Unable to change the log filters: {err}");
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+#[allow(clippy::unnecessary_wraps)]
+pub(crate) const fn toggle_on_sigusr1() -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    use tracing::info_span;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_log_format() {
+        assert_eq!("json".parse::<LogFormat>(), Ok(LogFormat::Json));
+        assert_eq!("text".parse::<LogFormat>(), Ok(LogFormat::Text));
+        assert!("yaml".parse::<LogFormat>().is_err());
+        assert_eq!(LogFormat::Json.to_string(), "json");
+    }
+
+    // the only test installing the global logger
+    #[tokio::test]
+    async fn test_override_log_filter() -> Result<()> {
+        let _logging = init_logging(LogFormat::Text, LevelFilter::INFO, None)?;
+        assert!(override_log_filter("server=loud", None).is_err());
+        let set = override_log_filter("info,server::servers=trace", Some(Duration::from_millis(50)))?;
+        // the level of the settings waits for the override to end
+        set_max_level(LevelFilter::WARN);
+        let filters = log_filters().ok_or("no filters")?;
+        assert_eq!((filters.level.as_str(), filters.current), ("warn", Some(set)));
+        sleep(Duration::from_millis(200)).await;
+        assert_eq!(log_filters().and_then(|filters| filters.current), None);
+        assert!(!clear_log_filter());
+
+        // a replaced override outlives the expiry of the one before
+        override_log_filter("debug", Some(Duration::from_millis(50)))?;
+        override_log_filter("trace", None)?;
+        sleep(Duration::from_millis(200)).await;
+        assert!(log_filters().and_then(|filters| filters.current).is_some());
+        assert!(clear_log_filter());
+        // keeps the other tests quiet
+        override_log_filter("off", None)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_exports_pipeline_spans() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let config = OtlpConfig::new(format!("http://{}/", listener.local_addr()?));
+        let collector = thread::spawn(move || -> Result<String> {
+            let (mut stream, _) = listener.accept()?;
+            let mut buf = [0; 4096];
+            let n = stream.read(&mut buf)?;
+            stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")?;
+            Ok(String::from_utf8_lossy(&buf[..n]).into_owned())
+        });
+        let provider = config.tracer_provider()?;
+        let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer("order_book_server"));
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), || {
+            info_span!(target: PIPELINE, "ingest").in_scope(|| info_span!(target: PIPELINE, "decode").in_scope(|| ()));
+        });
+        provider.shutdown()?;
+        let request = collector.join().unwrap()?;
+        assert!(request.starts_with("POST /v1/traces "));
+        Ok(())
+    }
+}
@@ -0,0 +1,504 @@
+use std::{
+    pin::Pin,
+    sync::{
+        Arc, LazyLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::{
+    Router,
+    http::{StatusCode, header::CONTENT_TYPE},
+    response::IntoResponse,
+    routing::get,
+    serve::Listener,
+};
+use chrono::Utc;
+use prometheus::{
+    Encoder, Gauge, GaugeVec, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec,
+    Opts, Registry, TextEncoder, core::Collector,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpListener,
+};
+use tracing::{error, info};
+
+use crate::{prelude::*, types::node_data::EventSource};
+
+pub(crate) static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub(crate) struct Metrics {
+    registry: Registry,
+    pub(crate) connections: IntGauge,
+    pub(crate) connections_total: IntCounter,
+    // how long connections stayed open, observed when they close
+    connection_duration: Histogram,
+    // internal messages handed to the websocket connections, by kind
+    pub(crate) messages_broadcast: IntCounterVec,
+    pub(crate) messages_sent: IntCounter,
+    // number of messages queued for a connection, sampled whenever one is queued
+    send_queue_depth: Histogram,
+    // messages a connection missed because it fell behind (full send queue or broadcast channel)
+    pub(crate) dropped_messages: IntCounter,
+    pub(crate) conflated_messages: IntCounter,
+    pub(crate) expired_messages: IntCounter,
+    // connections closed because they stopped answering pings
+    pub(crate) dead_connections: IntCounter,
+    // connections evicted or throttled for reading too slowly, by action and reason
+    pub(crate) slow_consumers: IntCounterVec,
+    // requests and connections refused by a rate limit, by limit
+    pub(crate) rate_limited: IntCounterVec,
+    // connections, subscriptions and messages refused by a connection limit, by limit
+    pub(crate) limit_rejections: IntCounterVec,
+    // encoded (uncompressed) payload bytes vs bytes written to the sockets; their ratio is computed at scrape time
+    pub(crate) payload_bytes: IntCounter,
+    wire_bytes: IntCounter,
+    compression_ratio: Gauge,
+    // the same per tenant (empty for clients of none) and identity (API key or token subject), `anonymous` for
+    // clients without one
+    pub(crate) client_payload_bytes: IntCounterVec,
+    pub(crate) client_wire_bytes: IntCounterVec,
+    // open connections of the clients of each tenant
+    pub(crate) tenant_connections: IntGaugeVec,
+    // wall clock time minus block time of the last batch read from each node event source
+    node_event_lag: GaugeVec,
+    // 1 while an upstream node keeps producing new blocks
+    pub(crate) upstream_healthy: IntGaugeVec,
+    // blocks skipped by the node events, by event source
+    pub(crate) event_gaps: IntCounterVec,
+    // books left crossed or locked by the deltas of a block, by coin
+    pub(crate) crossed_books: IntCounterVec,
+    // audits of the books against a snapshot of the node, by result: consistent, diverged or healed
+    pub(crate) book_audits: IntCounterVec,
+    // orders of each book that differed from the node's at the last audit
+    pub(crate) book_divergence: IntGaugeVec,
+    // empty levels and orders without size taken off the books by the audits
+    pub(crate) stale_levels_collected: IntCounter,
+    // latency of the messages sent to websocket clients, by stage: block time to reading the node event, reading
+    // it to sending the message, and both
+    latency: HistogramVec,
+    // webhook alerts delivered, given up on after their retries, or dropped while a URL was behind
+    pub(crate) webhook_deliveries: IntCounterVec,
+    // as measured by the watchdog, and how far it sheds load: 0 not at all, 1 conflating, 2 rejecting new
+    // connections, 3 evicting the slowest
+    resident_memory: IntGauge,
+    event_loop_lag: Gauge,
+    pub(crate) watchdog_step: IntGauge,
+    pub(crate) standby: IntGauge,
+    pub(crate) encoder_cache: EncoderCache,
+}
+
+// lookups of the messages shared by the subscriptions of a listener's message, and of their serialized payloads,
+// resolved once since they are counted for every message sent
+pub(crate) struct EncoderCache {
+    pub(crate) responses: CacheCounters,
+    pub(crate) payloads: CacheCounters,
+}
+
+pub(crate) struct CacheCounters {
+    hits: IntCounter,
+    misses: IntCounter,
+}
+
+impl CacheCounters {
+    fn new(lookups: &IntCounterVec, cache: &str) -> Self {
+        Self { hits: lookups.with_label_values(&[cache, "hit"]), misses: lookups.with_label_values(&[cache, "miss"]) }
+    }
+
+    pub(crate) fn hit(&self) {
+        self.hits.inc();
+    }
+
+    pub(crate) fn miss(&self) {
+        self.misses.inc();
+    }
+}
+
+impl Metrics {
+    // metric names and labels are static, so registering them can only fail on programming errors
+    // a list of metrics, however long
+    #[allow(clippy::expect_used, clippy::too_many_lines)]
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("orderbook".to_string()), None).expect("valid registry prefix");
+        let connections = IntGauge::new("ws_connections", "Open websocket connections").expect("valid metric");
+        let connections_total =
+            IntCounter::new("ws_connections_total", "Websocket connections accepted").expect("valid metric");
+        let connection_duration = Histogram::with_opts(
+            HistogramOpts::new("ws_connection_duration_seconds", "How long websocket connections stayed open")
+                .buckets(vec![1.0, 10.0, 60.0, 300.0, 1800.0, 3600.0, 21600.0, 86400.0]),
+        )
+        .expect("valid metric");
+        let messages_broadcast = IntCounterVec::new(
+            Opts::new("messages_broadcast_total", "Messages broadcast to websocket connections"),
+            &["kind"],
+        )
+        .expect("valid metric");
+        let messages_sent =
+            IntCounter::new("ws_messages_sent_total", "Messages sent to websocket clients").expect("valid metric");
+        let send_queue_depth = Histogram::with_opts(
+            HistogramOpts::new("ws_send_queue_depth", "Messages queued for a websocket connection")
+                .buckets(vec![0.0, 1.0, 2.0, 5.0, 10.0, 25.0, 50.0, 100.0]),
+        )
+        .expect("valid metric");
+        let dropped_messages =
+            IntCounter::new("ws_dropped_messages_total", "Messages dropped because a websocket connection fell behind")
+                .expect("valid metric");
+        let conflated_messages = IntCounter::new(
+            "ws_conflated_messages_total",
+            "Messages merged into a message already queued for a slow websocket connection",
+        )
+        .expect("valid metric");
+        let expired_messages = IntCounter::new(
+            "ws_expired_messages_total",
+            "Of the dropped messages, those that waited in a send queue longer than the TTL of their channel",
+        )
+        .expect("valid metric");
+        let dead_connections =
+            IntCounter::new("ws_dead_connections_total", "Websocket connections closed for not answering pings")
+                .expect("valid metric");
+        let slow_consumers = IntCounterVec::new(
+            Opts::new("ws_slow_consumers_total", "Websocket connections evicted or throttled for reading too slowly"),
+            &["action", "reason"],
+        )
+        .expect("valid metric");
+        let rate_limited = IntCounterVec::new(
+            Opts::new("rate_limited_total", "Requests and connections refused by a rate limit"),
+            &["limit"],
+        )
+        .expect("valid metric");
+        let limit_rejections = IntCounterVec::new(
+            Opts::new(
+                "limit_rejections_total",
+                "Connections, subscriptions and messages refused by a connection limit",
+            ),
+            &["limit"],
+        )
+        .expect("valid metric");
+        let payload_bytes =
+            IntCounter::new("ws_payload_bytes_total", "Uncompressed payload bytes sent to websocket clients")
+                .expect("valid metric");
+        let wire_bytes =
+            IntCounter::new("ws_wire_bytes_total", "Bytes written to websocket sockets").expect("valid metric");
+        let compression_ratio =
+            Gauge::new("ws_compression_ratio", "Payload bytes per byte written to websocket sockets")
+                .expect("valid metric");
+        let client_payload_bytes = IntCounterVec::new(
+            Opts::new("client_payload_bytes_total", "Uncompressed payload bytes sent to the clients of an identity"),
+            &["tenant", "identity"],
+        )
+        .expect("valid metric");
+        let client_wire_bytes = IntCounterVec::new(
+            Opts::new("client_wire_bytes_total", "Bytes written to the sockets of the clients of an identity"),
+            &["tenant", "identity"],
+        )
+        .expect("valid metric");
+        let tenant_connections = IntGaugeVec::new(
+            Opts::new("tenant_connections", "Open connections of the clients of a tenant"),
+            &["tenant"],
+        )
+        .expect("valid metric");
+        let node_event_lag = GaugeVec::new(
+            Opts::new("node_event_lag_seconds", "Delay between block time and reading the block's node events"),
+            &["source"],
+        )
+        .expect("valid metric");
+        let upstream_healthy = IntGaugeVec::new(
+            Opts::new("upstream_healthy", "Whether an upstream node is producing new blocks"),
+            &["node"],
+        )
+        .expect("valid metric");
+        let event_gaps = IntCounterVec::new(
+            Opts::new("node_event_gaps_total", "Gaps in the block numbers of the node events read"),
+            &["source"],
+        )
+        .expect("valid metric");
+        let crossed_books = IntCounterVec::new(
+            Opts::new("crossed_books_total", "Books left crossed or locked by the deltas of a block"),
+            &["coin"],
+        )
+        .expect("valid metric");
+        let book_audits = IntCounterVec::new(
+            Opts::new("book_audits_total", "Audits of the books against a snapshot of the node"),
+            &["result"],
+        )
+        .expect("valid metric");
+        let book_divergence = IntGaugeVec::new(
+            Opts::new("book_divergence_orders", "Orders of a book that differed from the node's at the last audit"),
+            &["coin"],
+        )
+        .expect("valid metric");
+        let stale_levels_collected = IntCounter::new(
+            "stale_levels_collected_total",
+            "Empty levels and orders without size taken off the books by the audits",
+        )
+        .expect("valid metric");
+        let latency = HistogramVec::new(
+            HistogramOpts::new("latency_seconds", "Latency of the messages sent to websocket clients")
+                .buckets(vec![0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0]),
+            &["stage"],
+        )
+        .expect("valid metric");
+        let webhook_deliveries = IntCounterVec::new(
+            Opts::new("webhook_deliveries_total", "Webhook alerts delivered, failed or dropped"),
+            &["result"],
+        )
+        .expect("valid metric");
+        let resident_memory =
+            IntGauge::new("process_resident_memory_bytes", "Resident memory of the process").expect("valid metric");
+        let event_loop_lag =
+            Gauge::new("event_loop_lag_seconds", "How late the watchdog's timer fired").expect("valid metric");
+        let watchdog_step = IntGauge::new(
+            "watchdog_shedding_step",
+            "How far the watchdog sheds load: 0 not, 1 conflating, 2 rejecting connections, 3 evicting",
+        )
+        .expect("valid metric");
+        let standby = IntGauge::new("standby", "1 while the server is a standby that refuses clients, 0 once promoted")
+            .expect("valid metric");
+        let encoder_cache_lookups = IntCounterVec::new(
+            Opts::new(
+                "encoder_cache_lookups_total",
+                "Lookups of the messages shared by subscriptions and of their serialized payloads",
+            ),
+            &["cache", "result"],
+        )
+        .expect("valid metric");
+        let encoder_cache = EncoderCache {
+            responses: CacheCounters::new(&encoder_cache_lookups, "response"),
+            payloads: CacheCounters::new(&encoder_cache_lookups, "payload"),
+        };
+        let collectors: [Box<dyn Collector>; 33] = [
+            Box::new(connections.clone()),
+            Box::new(connections_total.clone()),
+            Box::new(connection_duration.clone()),
+            Box::new(messages_broadcast.clone()),
+            Box::new(messages_sent.clone()),
+            Box::new(send_queue_depth.clone()),
+            Box::new(dropped_messages.clone()),
+            Box::new(conflated_messages.clone()),
+            Box::new(expired_messages.clone()),
+            Box::new(dead_connections.clone()),
+            Box::new(slow_consumers.clone()),
+            Box::new(rate_limited.clone()),
+            Box::new(limit_rejections.clone()),
+            Box::new(payload_bytes.clone()),
+            Box::new(wire_bytes.clone()),
+            Box::new(compression_ratio.clone()),
+            Box::new(client_payload_bytes.clone()),
+            Box::new(client_wire_bytes.clone()),
+            Box::new(tenant_connections.clone()),
+            Box::new(node_event_lag.clone()),
+            Box::new(upstream_healthy.clone()),
+            Box::new(event_gaps.clone()),
+            Box::new(crossed_books.clone()),
+            Box::new(book_audits.clone()),
+            Box::new(book_divergence.clone()),
+            Box::new(stale_levels_collected.clone()),
+            Box::new(latency.clone()),
+            Box::new(webhook_deliveries.clone()),
+            Box::new(resident_memory.clone()),
+            Box::new(event_loop_lag.clone()),
+            Box::new(watchdog_step.clone()),
+            Box::new(standby.clone()),
+            Box::new(encoder_cache_lookups),
+        ];
+        for collector in collectors {
+            registry.register(collector).expect("unique metric");
+        }
+        Self {
+            registry,
+            connections,
+            connections_total,
+            connection_duration,
+            messages_broadcast,
+            messages_sent,
+            send_queue_depth,
+            dropped_messages,
+            conflated_messages,
+            expired_messages,
+            dead_connections,
+            slow_consumers,
+            rate_limited,
+            limit_rejections,
+            payload_bytes,
+            wire_bytes,
+            compression_ratio,
+            client_payload_bytes,
+            client_wire_bytes,
+            tenant_connections,
+            node_event_lag,
+            upstream_healthy,
+            event_gaps,
+            crossed_books,
+            book_audits,
+            book_divergence,
+            stale_levels_collected,
+            latency,
+            webhook_deliveries,
+            resident_memory,
+            event_loop_lag,
+            watchdog_step,
+            standby,
+            encoder_cache,
+        }
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    pub(crate) fn observe_send_queue_depth(&self, depth: usize) {
+        self.send_queue_depth.observe(depth as f64);
+    }
+
+    pub(crate) fn observe_connection_duration(&self, duration: Duration) {
+        self.connection_duration.observe(duration.as_secs_f64());
+    }
+
+    #[allow(clippy::cast_precision_loss, clippy::cast_possible_wrap)]
+    pub(crate) fn set_node_event_lag(&self, event_source: EventSource, block_time_ms: u64) {
+        let lag_ms = Utc::now().timestamp_millis() - block_time_ms as i64;
+        self.node_event_lag.with_label_values(&[event_source.to_string()]).set(lag_ms as f64 / 1000.0);
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    pub(crate) fn observe_latency(&self, stage: &str, ms: u64) {
+        self.latency.with_label_values(&[stage]).observe(ms as f64 / 1000.0);
+    }
+
+    pub(crate) fn set_resource_usage(&self, rss_bytes: Option<u64>, lag: Duration) {
+        if let Some(rss_bytes) = rss_bytes {
+            self.resident_memory.set(i64::try_from(rss_bytes).unwrap_or(i64::MAX));
+        }
+        self.event_loop_lag.set(lag.as_secs_f64());
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn render(&self) -> Result<String> {
+        let wire_bytes = self.wire_bytes.get();
+        if wire_bytes > 0 {
+            self.compression_ratio.set(self.payload_bytes.get() as f64 / wire_bytes as f64);
+        }
+        let mut buf = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buf)?;
+        Ok(String::from_utf8(buf)?)
+    }
+}
+
+pub(crate) fn serve_metrics(listener: TcpListener) -> Result<()> {
+    let address = listener.local_addr()?;
+    let app = Router::new().route(
+        "/metrics",
+        get(async || match METRICS.render() {
+            Ok(body) => ([(CONTENT_TYPE, prometheus::TEXT_FORMAT)], body).into_response(),
+            Err(err) => {
+                error!("Unable to render metrics: {err}");
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        }),
+    );
+    info!("Metrics server running at http://{address}/metrics");
+    tokio::spawn(async move {
+        if let Err(err) = axum::serve(listener, app.into_make_service()).await {
+            error!("Metrics server error: {err}");
+        }
+    });
+    Ok(())
+}
+
+/// Bytes written to a single connection, after compression.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct WireBytes(Arc<AtomicU64>);
+
+impl WireBytes {
+    pub(crate) fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Wraps a listener to count the bytes written to every accepted connection, after compression.
+pub(crate) struct MeteredListener<L>(pub(crate) L);
+
+impl<L: Listener> Listener for MeteredListener<L> {
+    type Io = MeteredIo<L::Io>;
+    type Addr = L::Addr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        let (io, addr) = self.0.accept().await;
+        (MeteredIo(io, WireBytes::default()), addr)
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        self.0.local_addr()
+    }
+}
+
+pub(crate) struct MeteredIo<T>(T, WireBytes);
+
+impl<T> MeteredIo<T> {
+    pub(crate) fn wire_bytes(&self) -> WireBytes {
+        self.1.clone()
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for MeteredIo<T> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for MeteredIo<T> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut self.0).poll_write(cx, buf);
+        count_written(&res, &self.1);
+        res
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut self.0).poll_write_vectored(cx, bufs);
+        count_written(&res, &self.1);
+        res
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.0.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+fn count_written(res: &Poll<io::Result<usize>>, connection: &WireBytes) {
+    if let Poll::Ready(Ok(n)) = res {
+        METRICS.wire_bytes.inc_by(*n as u64);
+        connection.0.fetch_add(*n as u64, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_render_includes_wire_bytes_and_compression_ratio() -> Result<()> {
+        let mut io = MeteredIo(Vec::new(), WireBytes::default());
+        io.write_all(b"hello").await?;
+        assert_eq!(io.wire_bytes().get(), 5);
+        METRICS.payload_bytes.inc_by(10);
+        let body = METRICS.render()?;
+        assert!(METRICS.wire_bytes.get() >= 5);
+        assert!(body.contains("orderbook_ws_wire_bytes_total"));
+        assert!(body.contains("orderbook_ws_compression_ratio"));
+        Ok(())
+    }
+}
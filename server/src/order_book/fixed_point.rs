@@ -0,0 +1,121 @@
+use std::fmt;
+
+use crate::prelude::*;
+
+// digits after the point kept of prices and sizes, the most Hyperliquid's assets have
+pub(crate) const DECIMALS: u32 = 8;
+const SCALE: u128 = 10_u128.pow(DECIMALS);
+
+// A decimal number as a whole number of 10^-DECIMALS, converted from and to decimal strings without going through
+// floats. Digits past `DECIMALS` are rounded half away from zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct FixedPoint {
+    negative: bool,
+    units: u128,
+}
+
+impl FixedPoint {
+    pub(crate) const fn from_units(units: u128) -> Self {
+        Self { negative: false, units }
+    }
+
+    pub(crate) const fn units(self) -> u128 {
+        self.units
+    }
+
+    pub(crate) const fn is_negative(self) -> bool {
+        self.negative
+    }
+
+    // e.g. `106296.5`, `-0.00017`, `.5` or `12`. Exponents aren't accepted
+    pub(crate) fn parse(value: &str) -> Result<Self> {
+        let negative = value.starts_with('-');
+        let digits = value.strip_prefix(['-', '+']).unwrap_or(value);
+        let (whole, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+        if whole.is_empty() && fraction.is_empty() || !whole.bytes().chain(fraction.bytes()).all(|b| b.is_ascii_digit())
+        {
+            return Err(format!("Invalid decimal number: {value}").into());
+        }
+        let padded = fraction.bytes().chain(std::iter::repeat(b'0')).take(DECIMALS as usize);
+        let mut units = 0_u128;
+        for digit in whole.bytes().chain(padded) {
+            units = units
+                .checked_mul(10)
+                .and_then(|units| units.checked_add(u128::from(digit - b'0')))
+                .ok_or_else(|| format!("Decimal number out of range: {value}"))?;
+        }
+        if fraction.as_bytes().get(DECIMALS as usize).is_some_and(|digit| *digit >= b'5') {
+            units = units.checked_add(1).ok_or_else(|| format!("Decimal number out of range: {value}"))?;
+        }
+        Ok(Self { negative: negative && units > 0, units })
+    }
+}
+
+// the shortest decimal string of the number, without trailing zeros
+impl fmt::Display for FixedPoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.negative { "-" } else { "" };
+        let (whole, fraction) = (self.units / SCALE, self.units % SCALE);
+        if fraction == 0 {
+            return write!(f, "{sign}{whole}");
+        }
+        let fraction = format!("{fraction:0width$}", width = DECIMALS as usize);
+        write!(f, "{sign}{whole}.{}", fraction.trim_end_matches('0'))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let units = |value| FixedPoint::parse(value).map(FixedPoint::units);
+        assert_eq!(units("106296.0")?, 10_629_600_000_000);
+        assert_eq!(units("0.00017")?, 17_000);
+        assert_eq!(units(".5")?, 50_000_000);
+        assert_eq!(units("12")?, 1_200_000_000);
+        assert_eq!(units("12.")?, 1_200_000_000);
+        // past f64's 15 significant digits
+        assert_eq!(units("123456789012.12345678")?, 12_345_678_901_212_345_678);
+        assert!(FixedPoint::parse("-1.5")?.is_negative());
+        assert!(!FixedPoint::parse("-0.000")?.is_negative());
+        for invalid in ["", ".", "-", "1e5", "1.2.3", "0x10", "inf", "NaN", " 1"] {
+            assert!(FixedPoint::parse(invalid).is_err(), "{invalid}");
+        }
+        assert!(FixedPoint::parse(&"9".repeat(40)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rounding() -> Result<()> {
+        let units = |value| FixedPoint::parse(value).map(FixedPoint::units);
+        assert_eq!(units("0.000000014")?, 1);
+        assert_eq!(units("0.000000015")?, 2);
+        assert_eq!(units("0.0000000149999")?, 1);
+        assert_eq!(units("0.000000005")?, 1);
+        assert_eq!(units("0.000000004999")?, 0);
+        assert_eq!(units("1.999999995")?, 200_000_000);
+        // away from zero
+        assert_eq!(FixedPoint::parse("-0.000000015")?.to_string(), "-0.00000002");
+        assert_eq!(FixedPoint::parse("-0.000000004")?.to_string(), "0");
+        Ok(())
+    }
+
+    #[test]
+    fn test_display() -> Result<()> {
+        for (value, formatted) in [
+            ("106296.0", "106296"),
+            ("0.00017", "0.00017"),
+            ("100.50", "100.5"),
+            ("-3.25", "-3.25"),
+            ("0", "0"),
+            ("00012.0001000", "12.0001"),
+            ("123456789012.12345678", "123456789012.12345678"),
+        ] {
+            assert_eq!(FixedPoint::parse(value)?.to_string(), formatted);
+        }
+        assert_eq!(FixedPoint::from_units(1).to_string(), "0.00000001");
+        Ok(())
+    }
+}
@@ -1,7 +1,10 @@
 use std::collections::BTreeMap;
 
 use crate::{
-    order_book::{InnerOrder, Oid, OrderBook, Px, Side, Snapshot, Sz, linked_list::LinkedList},
+    order_book::{
+        InnerOrder, OrderBook, Px, Side, Snapshot, Sz,
+        linked_list::{Arena, List},
+    },
     types::{Level, inner::InnerLevel},
 };
 
@@ -27,10 +30,8 @@ impl<O: InnerOrder> OrderBook<O> {
         n_sig_figs: Option<u32>,
         mantissa: Option<u64>,
     ) -> Snapshot<InnerLevel> {
-        let bids = &self.bids;
-        let asks = &self.asks;
-        let bids = map_to_l2_levels(bids, Side::Bid, n_levels, n_sig_figs, mantissa);
-        let asks = map_to_l2_levels(asks, Side::Ask, n_levels, n_sig_figs, mantissa);
+        let bids = map_to_l2_levels(&self.orders, &self.bids, Side::Bid, n_levels, n_sig_figs, mantissa);
+        let asks = map_to_l2_levels(&self.orders, &self.asks, Side::Ask, n_levels, n_sig_figs, mantissa);
         Snapshot([bids, asks])
     }
 }
@@ -78,7 +79,8 @@ fn l2_levels_to_l2_levels(
 
 #[must_use]
 fn map_to_l2_levels<O: InnerOrder>(
-    orders: &BTreeMap<Px, LinkedList<Oid, O>>,
+    orders: &Arena<O>,
+    book: &BTreeMap<Px, List>,
     side: Side,
     n_levels: Option<usize>,
     n_sig_figs: Option<u32>,
@@ -89,14 +91,14 @@ fn map_to_l2_levels<O: InnerOrder>(
         return levels;
     }
     let mut cur_level: Option<InnerLevel> = None;
-    let order_iter: Box<dyn Iterator<Item = (&Px, &LinkedList<Oid, O>)>> = match side {
-        Side::Ask => Box::new(orders.iter()),
-        Side::Bid => Box::new(orders.iter().rev()),
+    let order_iter: Box<dyn Iterator<Item = (&Px, &List)>> = match side {
+        Side::Ask => Box::new(book.iter()),
+        Side::Bid => Box::new(book.iter().rev()),
     };
-    for (px, orders) in order_iter {
+    for (px, list) in order_iter {
         // could be done a bit more efficiently using caching
-        let sz = orders.fold(Sz::new(0), |sz, order| *sz = *sz + order.sz());
-        let n = orders.fold(0, |n, _| *n += 1);
+        let sz = orders.iter(*list).fold(Sz::new(0), |sz, order| sz + order.sz());
+        let n = list.len();
         if build_l2_level(
             &mut cur_level,
             &mut levels,
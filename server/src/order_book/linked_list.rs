@@ -1,225 +1,156 @@
-use std::{collections::HashMap, hash::Hash, marker::PhantomData};
+use std::iter;
 
 use slab::Slab;
 
-use crate::prelude::*;
-
 #[derive(Clone)]
-struct Node<K, T> {
-    key: K,
+struct Node<T> {
     value: T,
     next: Option<usize>,
     prev: Option<usize>,
 }
 
-impl<K, T> Node<K, T> {
-    pub(crate) const fn new(key: K, value: T) -> Self {
-        Self { key, value, next: None, prev: None }
-    }
+/// Linked lists whose nodes all live in one slab. The slot of a removed node goes to the next node pushed to any of
+/// the lists, so lists that churn, or that are created and emptied all the time, don't allocate once the slab has
+/// grown to the size of the book.
+#[derive(Clone)]
+pub(crate) struct Arena<T> {
+    slab: Slab<Node<T>>,
 }
 
-#[derive(Clone)]
-// Implicit assumption is that when we remove a node, it is never used again
-pub(crate) struct LinkedList<K, T> {
-    key_to_sid: HashMap<K, usize>,
-    slab: Slab<Node<K, T>>,
+/// A list of an [`Arena`]: only its ends and length, cheap to keep one for every price level.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct List {
     head: Option<usize>,
     tail: Option<usize>,
-    phantom_data: PhantomData<T>,
+    len: usize,
 }
 
-impl<K: Clone + Eq + Hash, T: Clone> LinkedList<K, T> {
+impl List {
     #[must_use]
-    pub(crate) fn new() -> Self {
-        Self { key_to_sid: HashMap::new(), slab: Slab::new(), head: None, tail: None, phantom_data: PhantomData }
+    pub(crate) const fn is_empty(&self) -> bool {
+        self.head.is_none()
     }
 
-    pub(crate) fn push_back(&mut self, key: K, value: T) -> bool {
-        if self.key_to_sid.contains_key(&key) {
-            false
-        } else {
-            let node = Node::new(key.clone(), value);
-            let sid = self.slab.insert(node);
-            self.key_to_sid.insert(key, sid);
-            match self.tail {
-                None => {
-                    self.head = Some(sid);
-                    self.tail = Some(sid);
-                }
-                Some(t) => {
-                    let tail_order = &mut self.slab[t];
-                    tail_order.next = Some(sid);
-                    let new_order = &mut self.slab[sid];
-                    new_order.prev = Some(t);
-                    self.tail = Some(sid);
-                }
-            }
-            true
-        }
+    #[must_use]
+    pub(crate) const fn len(&self) -> usize {
+        self.len
     }
 
     #[must_use]
-    pub(crate) const fn is_empty(&self) -> bool {
-        self.head.is_none()
+    pub(crate) const fn head(&self) -> Option<usize> {
+        self.head
     }
+}
 
-    pub(crate) fn head_value_ref_mut_unsafe(&mut self) -> Option<&mut T> {
-        self.head.as_ref().map(|&h| &mut self.slab[h].value)
+impl<T> Default for Arena<T> {
+    fn default() -> Self {
+        Self { slab: Slab::new() }
     }
+}
 
-    pub(crate) fn remove_front(&mut self) -> Result<()> {
-        if let Some(h) = self.head {
-            let new_head = {
-                let head_order = self.slab.remove(h);
-                self.key_to_sid.remove(&head_order.key);
-                head_order.next
-            };
-            match new_head {
-                None => {
-                    self.head = None;
-                    self.tail = None;
-                }
-                Some(n) => {
-                    self.head = Some(n);
-                    let new_order = &mut self.slab[n];
-                    new_order.prev = None;
-                }
-            }
-            Ok(())
-        } else {
-            Err("List is empty".into())
+impl<T> Arena<T> {
+    // the key of the new node, until it is removed
+    pub(crate) fn push_back(&mut self, list: &mut List, value: T) -> usize {
+        let key = self.slab.insert(Node { value, next: None, prev: list.tail });
+        match list.tail {
+            None => list.head = Some(key),
+            Some(t) => self.slab[t].next = Some(key),
         }
+        list.tail = Some(key);
+        list.len += 1;
+        key
     }
 
-    pub(crate) fn remove_node(&mut self, key: K) -> bool {
-        if let Some((_, sid)) = self.key_to_sid.remove_entry(&key) {
-            let (prev, next) = {
-                let order = self.slab.remove(sid);
-                (order.prev, order.next)
-            };
-            if let Some(p) = prev {
-                let prev_order = &mut self.slab[p];
-                prev_order.next = next;
-            } else {
-                self.head = next;
-            }
-            if let Some(n) = next {
-                let next_order = &mut self.slab[n];
-                next_order.prev = prev;
-            } else {
-                self.tail = prev;
-            }
-            true
-        } else {
-            false
+    // `key` must be a node of `list`
+    pub(crate) fn remove(&mut self, list: &mut List, key: usize) -> Option<T> {
+        let node = self.slab.try_remove(key)?;
+        match node.prev {
+            Some(p) => self.slab[p].next = node.next,
+            None => list.head = node.next,
         }
+        match node.next {
+            Some(n) => self.slab[n].prev = node.prev,
+            None => list.tail = node.prev,
+        }
+        list.len -= 1;
+        Some(node.value)
     }
 
-    pub(crate) fn node_value_mut(&mut self, key: &K) -> Option<&mut T> {
-        if let Some(sid) = self.key_to_sid.get(key) { Some(&mut self.slab[*sid].value) } else { None }
+    pub(crate) fn get(&self, key: usize) -> Option<&T> {
+        self.slab.get(key).map(|node| &node.value)
     }
 
-    #[must_use]
-    pub(crate) fn to_vec(&self) -> Vec<&T> {
-        let mut res = Vec::new();
-        let mut cur = self.head;
-        while let Some(c) = cur {
-            let node = &self.slab[c];
-            res.push(&node.value);
-            cur = node.next;
-        }
-        res
+    pub(crate) fn get_mut(&mut self, key: usize) -> Option<&mut T> {
+        self.slab.get_mut(key).map(|node| &mut node.value)
     }
 
-    pub(crate) fn fold<F, Acc>(&self, mut init: Acc, f: F) -> Acc
-    where
-        F: Fn(&mut Acc, &T),
-    {
-        let mut cur = self.head;
-        while let Some(c) = cur {
-            let node = &self.slab[c];
-            f(&mut init, &node.value);
-            cur = node.next;
-        }
-        init
+    // front to back
+    pub(crate) fn iter(&self, list: List) -> impl Iterator<Item = &T> {
+        iter::successors(list.head, |&key| self.slab[key].next).map(|key| &self.slab[key].value)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::collections::VecDeque;
+    use std::{collections::VecDeque, fmt::Debug};
 
     use itertools::Itertools;
 
     use super::*;
 
-    #[must_use]
-    fn to_rev_vec<K: Clone + Eq + Hash, T: Clone>(list: &LinkedList<K, T>) -> Vec<&T> {
-        let mut res = Vec::new();
-        let mut cur = list.tail;
-        while let Some(c) = cur {
-            let node = &list.slab[c];
-            res.push(&node.value);
-            cur = node.prev;
-        }
-        res
+    fn to_rev_vec<T: Clone>(arena: &Arena<T>, list: List) -> Vec<T> {
+        iter::successors(list.tail, |&key| arena.slab[key].prev).map(|key| arena.slab[key].value.clone()).collect()
+    }
+
+    fn assert_vec_deque_list_eq<T: Debug + Clone + Eq>(deque: &VecDeque<T>, arena: &Arena<T>, list: List) {
+        let expected = deque.iter().cloned().collect_vec();
+        assert_eq!(arena.iter(list).cloned().collect_vec(), expected);
+        assert_eq!(to_rev_vec(arena, list), expected.into_iter().rev().collect_vec());
+        assert_eq!(list.len(), deque.len());
     }
 
     #[test]
-    fn simple_linked_list_test() -> Result<()> {
+    fn simple_linked_list_test() {
+        let mut arena = Arena::default();
         let mut deque = (0..11).collect::<VecDeque<_>>();
-        let mut keys = Vec::new();
-        let mut list = LinkedList::new();
-        for &elt in &deque {
-            keys.push(elt);
-            list.push_back(elt, elt);
+        let mut list = List::default();
+        let keys = deque.iter().map(|&elt| arena.push_back(&mut list, elt)).collect_vec();
+        assert_vec_deque_list_eq(&deque, &arena, list);
+
+        for _ in 0..2 {
+            let head = list.head().unwrap_or_default();
+            assert_eq!(arena.remove(&mut list, head), deque.pop_front());
+            assert_vec_deque_list_eq(&deque, &arena, list);
         }
 
-        assert_vec_deque_list_eq(&deque, &list);
-
-        list.remove_front()?;
-        deque.pop_front();
-
-        assert_vec_deque_list_eq(&deque, &list);
-
-        list.remove_front()?;
-        deque.pop_front();
-
-        assert_vec_deque_list_eq(&deque, &list);
-
-        list.remove_node(keys[4]);
+        assert_eq!(arena.remove(&mut list, keys[4]), Some(4));
         deque.remove(2);
+        assert_vec_deque_list_eq(&deque, &arena, list);
+        assert_eq!(arena.remove(&mut list, keys[4]), None);
 
-        assert_vec_deque_list_eq(&deque, &list);
-
-        for _ in 0..5 {
-            list.remove_front()?;
-            deque.pop_front();
-            assert_vec_deque_list_eq(&deque, &list);
+        for k in keys.iter().skip(8).rev() {
+            assert_eq!(arena.remove(&mut list, *k), deque.pop_back());
+            assert_vec_deque_list_eq(&deque, &arena, list);
         }
-
-        for k in keys.iter().skip(8) {
-            list.remove_node(*k);
-            deque.pop_front();
-            assert_vec_deque_list_eq(&deque, &list);
+        while let Some(head) = list.head() {
+            assert_eq!(arena.remove(&mut list, head), deque.pop_front());
+            assert_vec_deque_list_eq(&deque, &arena, list);
         }
-
         assert!(list.is_empty());
-        Ok(())
     }
 
-    fn assert_vec_deque_list_eq<K: Clone + Eq + Hash, T: Debug + Clone + Eq + PartialEq>(
-        deque: &VecDeque<T>,
-        list: &LinkedList<K, T>,
-    ) {
-        let evec = deque.iter().cloned().collect_vec();
-        for (a, b) in list.to_vec().iter().zip(evec.iter()) {
-            assert_eq!(**a, *b);
-        }
-        let mut rev = evec;
-        rev.reverse();
-        for (a, b) in to_rev_vec(list).iter().zip(rev.iter()) {
-            assert_eq!(**a, *b);
-        }
+    #[test]
+    fn test_lists_share_the_slab() {
+        let mut arena = Arena::default();
+        let (mut a, mut b) = (List::default(), List::default());
+        let first = arena.push_back(&mut a, 1);
+        arena.push_back(&mut b, 2);
+        arena.push_back(&mut a, 3);
+        assert_eq!(arena.remove(&mut a, first), Some(1));
+        // the freed slot is reused by the next node of any list
+        assert_eq!(arena.push_back(&mut b, 4), first);
+        assert_eq!(arena.iter(a).copied().collect_vec(), vec![3]);
+        assert_eq!(arena.iter(b).copied().collect_vec(), vec![2, 4]);
+        assert_eq!(arena.get(first), Some(&4));
     }
 }
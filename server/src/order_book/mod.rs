@@ -1,28 +1,43 @@
 use std::collections::{BTreeMap, HashMap, HashSet};
 
 use itertools::Itertools;
-use linked_list::LinkedList;
+use linked_list::{Arena, List};
 
 use crate::prelude::*;
 
+pub(crate) mod fixed_point;
 pub(crate) mod levels;
 mod linked_list;
 pub(crate) mod multi_book;
+mod ticks;
 pub(crate) mod types;
 
+use ticks::TickGroup;
 pub(crate) use types::{Coin, InnerOrder, Oid, Px, Side, Sz};
 
+// the resting orders of both sides are kept in one arena, each in the list of its price level. Levels are only the
+// ends of their lists, so opening and closing them doesn't allocate, and neither does replacing an order by another.
 #[derive(Clone, Default)]
 pub(crate) struct OrderBook<O> {
-    oid_to_side_px: HashMap<Oid, (Side, Px)>,
-    bids: BTreeMap<Px, LinkedList<Oid, O>>,
-    asks: BTreeMap<Px, LinkedList<Oid, O>>,
+    orders: Arena<O>,
+    oid_to_key: HashMap<Oid, usize>,
+    bids: BTreeMap<Px, List>,
+    asks: BTreeMap<Px, List>,
+    // levels aggregated by tick size, kept up to date for the l2 subscriptions that ask for them
+    tick_groups: BTreeMap<Px, TickGroup>,
+    // price levels changed since the tick groups were last updated
+    touched: Vec<(Side, Px)>,
 }
 
 #[derive(Debug, Clone)]
 pub(crate) struct Snapshot<O>([Vec<O>; 2]);
 
 impl<O: Clone> Snapshot<O> {
+    // bids and asks, best first
+    pub(crate) const fn new(orders: [Vec<O>; 2]) -> Self {
+        Self(orders)
+    }
+
     pub(crate) const fn as_ref(&self) -> &[Vec<O>; 2] {
         &self.0
     }
@@ -58,65 +73,124 @@ impl<O: InnerOrder> Snapshot<O> {
 impl<O: InnerOrder> OrderBook<O> {
     #[must_use]
     pub(crate) fn new() -> Self {
-        Self { oid_to_side_px: HashMap::new(), bids: BTreeMap::new(), asks: BTreeMap::new() }
+        Self {
+            orders: Arena::default(),
+            oid_to_key: HashMap::new(),
+            bids: BTreeMap::new(),
+            asks: BTreeMap::new(),
+            tick_groups: BTreeMap::new(),
+            touched: Vec::new(),
+        }
     }
 
     pub(crate) fn add_order(&mut self, mut order: O) {
-        let (maker_orders, resting_book) = match order.side() {
+        let side = order.side();
+        let (maker_orders, resting_book) = match side {
             Side::Ask => (&mut self.bids, &mut self.asks),
             Side::Bid => (&mut self.asks, &mut self.bids),
         };
-        let oids = match_order(maker_orders, &mut order);
+        let (oids, matched) = match_order(&mut self.orders, maker_orders, &mut order);
         for oid in oids {
-            self.oid_to_side_px.remove(&oid);
+            self.oid_to_key.remove(&oid);
+        }
+        if !self.tick_groups.is_empty() {
+            let maker_side = match side {
+                Side::Ask => Side::Bid,
+                Side::Bid => Side::Ask,
+            };
+            self.touched.extend(matched.into_iter().map(|px| (maker_side, px)));
+            if order.sz().is_positive() {
+                self.touched.push((side, order.limit_px()));
+            }
         }
-        if order.sz().is_positive() {
-            self.oid_to_side_px.insert(order.oid(), (order.side(), order.limit_px()));
-            add_order_to_book(resting_book, order);
+        // an order that is already resting keeps its place
+        if order.sz().is_positive() && !self.oid_to_key.contains_key(&order.oid()) {
+            let oid = order.oid();
+            let list = resting_book.entry(order.limit_px()).or_default();
+            self.oid_to_key.insert(oid, self.orders.push_back(list, order));
         }
     }
 
     pub(crate) fn cancel_order(&mut self, oid: Oid) -> bool {
-        if let Some((side, px)) = self.oid_to_side_px.remove(&oid) {
-            let map = match side {
-                Side::Ask => &mut self.asks,
-                Side::Bid => &mut self.bids,
-            };
-            let list = map.get_mut(&px);
-            if let Some(list) = list {
-                let success = list.remove_node(oid.clone());
-                if list.is_empty() {
-                    map.remove(&px);
-                }
-                return success;
-            }
+        let Some(key) = self.oid_to_key.remove(&oid) else {
+            return false;
+        };
+        let Some((side, px)) = self.orders.get(key).map(|order| (order.side(), order.limit_px())) else {
+            return false;
+        };
+        self.touch(side, px);
+        let map = match side {
+            Side::Ask => &mut self.asks,
+            Side::Bid => &mut self.bids,
+        };
+        let Some(list) = map.get_mut(&px) else {
+            return false;
+        };
+        let success = self.orders.remove(list, key).is_some();
+        if list.is_empty() {
+            map.remove(&px);
         }
-        false
+        success
     }
 
     pub(crate) fn modify_sz(&mut self, oid: Oid, sz: Sz) -> bool {
-        if let Some((side, px)) = self.oid_to_side_px.get(&oid) {
-            let map = match side {
-                Side::Ask => &mut self.asks,
-                Side::Bid => &mut self.bids,
-            };
-            let list = map.get_mut(px);
-            if let Some(list) = list {
-                let old_order = list.node_value_mut(&oid);
-                if let Some(old_order) = old_order {
-                    old_order.modify_sz(sz);
-                    return true;
-                }
-                return false;
-            }
+        let Some(&key) = self.oid_to_key.get(&oid) else {
+            return false;
+        };
+        let Some(order) = self.orders.get_mut(key) else {
+            return false;
+        };
+        order.modify_sz(sz);
+        let (side, px) = (order.side(), order.limit_px());
+        self.touch(side, px);
+        true
+    }
+
+    pub(crate) fn order(&self, oid: &Oid) -> Option<&O> {
+        self.oid_to_key.get(oid).and_then(|key| self.orders.get(*key))
+    }
+
+    // takes off the orders modified down to no size, which the node removes from its book, and the levels left
+    // without orders. Returns how many of them there were
+    pub(crate) fn collect_stale(&mut self) -> usize {
+        let empty = self.orders_without_size();
+        for oid in &empty {
+            self.cancel_order(oid.clone());
         }
-        false
+        let levels = self.bids.len() + self.asks.len();
+        self.bids.retain(|_, list| !list.is_empty());
+        self.asks.retain(|_, list| !list.is_empty());
+        empty.len() + levels - self.bids.len() - self.asks.len()
+    }
+
+    fn orders_without_size(&self) -> Vec<Oid> {
+        [Side::Bid, Side::Ask]
+            .into_iter()
+            .flat_map(|side| self.side_orders(side))
+            .filter(|order| order.sz().is_zero())
+            .map(InnerOrder::oid)
+            .collect()
+    }
+
+    // whether the best bid is at (locked, `Some(true)`) or above (`Some(false)`) the best ask
+    pub(crate) fn crossing(&self) -> Option<bool> {
+        let (bid, ask) = (self.bids.keys().next_back()?, self.asks.keys().next()?);
+        (bid >= ask).then_some(bid == ask)
+    }
+
+    // the orders of a side, best level first
+    fn side_orders(&self, side: Side) -> impl Iterator<Item = &O> {
+        let levels: Box<dyn Iterator<Item = &List>> = match side {
+            Side::Ask => Box::new(self.asks.values()),
+            Side::Bid => Box::new(self.bids.values().rev()),
+        };
+        levels.flat_map(|list| self.orders.iter(*list))
     }
 
     // we go by the convention that prioritized orders go first in the vector; this makes aggregation step later easier.
     pub(crate) fn to_snapshot(&self) -> Snapshot<O> {
-        let bids = self.bids.iter().rev().flat_map(|(_, l)| l.to_vec().into_iter().cloned()).collect_vec();
-        let asks = self.asks.iter().flat_map(|(_, l)| l.to_vec().into_iter().cloned()).collect_vec();
+        let bids = self.side_orders(Side::Bid).cloned().collect_vec();
+        let asks = self.side_orders(Side::Ask).cloned().collect_vec();
         Snapshot([bids, asks])
     }
 
@@ -135,18 +209,18 @@ impl<O: InnerOrder> OrderBook<O> {
     }
 }
 
-fn add_order_to_book<O: InnerOrder>(map: &mut BTreeMap<Px, LinkedList<Oid, O>>, order: O) {
-    let oid = order.oid();
-    let limit_px = order.limit_px();
-    map.entry(limit_px).or_insert_with(|| LinkedList::new()).push_back(oid, order);
-}
-
-fn match_order<O: InnerOrder>(maker_orders: &mut BTreeMap<Px, LinkedList<Oid, O>>, taker_order: &mut O) -> Vec<Oid> {
+// the filled orders, and the price levels that were matched against
+fn match_order<O: InnerOrder>(
+    orders: &mut Arena<O>,
+    maker_orders: &mut BTreeMap<Px, List>,
+    taker_order: &mut O,
+) -> (Vec<Oid>, Vec<Px>) {
     let mut filled_oids = Vec::new();
+    let mut matched_pxs = Vec::new();
     let mut keys_to_remove = Vec::new();
     let taker_side = taker_order.side();
     let limit_px = taker_order.limit_px();
-    let order_iter: Box<dyn Iterator<Item = (&Px, &mut LinkedList<Oid, O>)>> = match taker_side {
+    let order_iter: Box<dyn Iterator<Item = (&Px, &mut List)>> = match taker_side {
         Side::Ask => Box::new(maker_orders.iter_mut().rev()),
         Side::Bid => Box::new(maker_orders.iter_mut()),
     };
@@ -158,11 +232,14 @@ fn match_order<O: InnerOrder>(maker_orders: &mut BTreeMap<Px, LinkedList<Oid, O>
         if !matches {
             break;
         }
-        while let Some(match_order) = list.head_value_ref_mut_unsafe() {
+        matched_pxs.push(px);
+        while let Some(head) = list.head()
+            && let Some(match_order) = orders.get_mut(head)
+        {
             taker_order.fill(match_order);
             if match_order.sz().is_zero() {
                 filled_oids.push(match_order.oid());
-                let _unused = list.remove_front();
+                orders.remove(list, head);
             }
             if taker_order.sz().is_zero() {
                 break;
@@ -178,7 +255,7 @@ fn match_order<O: InnerOrder>(maker_orders: &mut BTreeMap<Px, LinkedList<Oid, O>
     for key in keys_to_remove {
         maker_orders.remove(&key);
     }
-    filled_oids
+    (filled_oids, matched_pxs)
 }
 
 #[cfg(test)]
@@ -308,6 +385,25 @@ mod tests {
         assert_same_book(Snapshot([bids.clone(), asks.clone()]), book.to_snapshot());
     }
 
+    #[test]
+    fn test_collect_stale() {
+        let mut factory = OrderFactory::default();
+        let mut book = OrderBook::new();
+        for order in
+            [factory.order(100, 5, Side::Bid), factory.order(100, 5, Side::Bid), factory.order(100, 6, Side::Ask)]
+        {
+            book.add_order(order);
+        }
+        assert_eq!(book.crossing(), None);
+        assert_eq!(book.collect_stale(), 0);
+        book.modify_sz(Oid::new(0), Sz::new(0));
+        book.modify_sz(Oid::new(2), Sz::new(0));
+        // the ask level goes with its only order
+        assert_eq!(book.collect_stale(), 2);
+        assert_eq!(book.to_snapshot().0.map(|orders| orders.len()), [1, 0]);
+        assert!(book.order(&Oid::new(0)).is_none());
+    }
+
     fn assert_same_book(s1: Snapshot<MinimalOrder>, s2: Snapshot<MinimalOrder>) {
         let [b1, a1] = s1.0.map(BTreeSet::from_iter);
         let [b2, a2] = s2.0.map(BTreeSet::from_iter);
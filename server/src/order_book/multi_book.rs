@@ -8,7 +8,7 @@ use serde::{Deserialize, Serialize};
 use tokio::fs::read_to_string;
 
 use crate::{
-    order_book::{Coin, InnerOrder, Oid, OrderBook, Snapshot, Sz},
+    order_book::{Coin, InnerOrder, Oid, OrderBook, Px, Snapshot, Sz},
     prelude::*,
 };
 
@@ -61,6 +61,28 @@ impl<O: InnerOrder> OrderBooks<O> {
     pub(crate) fn modify_sz(&mut self, oid: Oid, coin: Coin, sz: Sz) -> bool {
         self.order_books.get_mut(&coin).is_some_and(|book| book.modify_sz(oid, sz))
     }
+
+    pub(crate) fn order(&self, oid: &Oid, coin: &Coin) -> Option<&O> {
+        self.order_books.get(coin).and_then(|book| book.order(oid))
+    }
+
+    pub(crate) fn add_tick_group(&mut self, coin: &Coin, tick: Px) -> Result<()> {
+        self.order_books.get_mut(coin).ok_or_else(|| format!("No book for {}", coin.value()))?.add_tick_group(tick)
+    }
+
+    // called once the updates of a block are applied
+    // see `OrderBook::collect_stale`
+    pub(crate) fn collect_stale(&mut self) -> usize {
+        let stale = self.order_books.values_mut().map(OrderBook::collect_stale).sum();
+        self.update_tick_groups();
+        stale
+    }
+
+    pub(crate) fn update_tick_groups(&mut self) {
+        for book in self.order_books.values_mut() {
+            book.update_tick_groups();
+        }
+    }
 }
 
 impl<O: Send + Sync + InnerOrder> OrderBooks<O> {
@@ -0,0 +1,187 @@
+use std::collections::{BTreeMap, HashSet};
+
+use crate::{
+    order_book::{
+        InnerOrder, OrderBook, Px, Side, Snapshot, Sz,
+        linked_list::{Arena, List},
+    },
+    prelude::*,
+    types::inner::InnerLevel,
+};
+
+// tick sizes kept per book, as every one of them is updated with each block
+const MAX_TICK_GROUPS: usize = 8;
+
+// the levels of a book aggregated by a tick size, keyed by the price of their bucket
+#[derive(Clone, Default)]
+pub(crate) struct TickGroup {
+    bids: BTreeMap<Px, InnerLevel>,
+    asks: BTreeMap<Px, InnerLevel>,
+}
+
+impl TickGroup {
+    const fn side_mut(&mut self, side: Side) -> &mut BTreeMap<Px, InnerLevel> {
+        match side {
+            Side::Ask => &mut self.asks,
+            Side::Bid => &mut self.bids,
+        }
+    }
+}
+
+// like `nSigFigs` aggregation, asks are rounded up and bids down
+const fn bucket(px: Px, side: Side, tick: Px) -> Px {
+    let tick = tick.value();
+    match side {
+        Side::Ask => Px::new(px.value().div_ceil(tick) * tick),
+        Side::Bid => Px::new(px.value() / tick * tick),
+    }
+}
+
+// the sum of the levels in a bucket, `None` if it is empty
+fn sum_bucket<O: InnerOrder>(
+    orders: &Arena<O>,
+    book: &BTreeMap<Px, List>,
+    side: Side,
+    tick: Px,
+    bucket: Px,
+) -> Option<InnerLevel> {
+    let (tick, bucket) = (tick.value(), bucket.value());
+    let range = match side {
+        Side::Ask => Px::new(bucket.saturating_sub(tick) + 1)..=Px::new(bucket),
+        Side::Bid => Px::new(bucket)..=Px::new(bucket.saturating_add(tick - 1)),
+    };
+    let mut level = InnerLevel { px: Px::new(bucket), sz: Sz::new(0), n: 0 };
+    for list in book.range(range).map(|(_, list)| list) {
+        level.sz = orders.iter(*list).fold(level.sz, |sz, order| sz + order.sz());
+        level.n += list.len();
+    }
+    (level.n > 0).then_some(level)
+}
+
+impl<O: InnerOrder> OrderBook<O> {
+    // no-op if the book already keeps this tick size
+    pub(crate) fn add_tick_group(&mut self, tick: Px) -> Result<()> {
+        if tick.value() == 0 {
+            return Err("Tick size must be positive".into());
+        }
+        if self.tick_groups.contains_key(&tick) {
+            return Ok(());
+        }
+        if self.tick_groups.len() >= MAX_TICK_GROUPS {
+            return Err(format!("At most {MAX_TICK_GROUPS} tick sizes per market").into());
+        }
+        let mut group = TickGroup::default();
+        for (side, book) in [(Side::Bid, &self.bids), (Side::Ask, &self.asks)] {
+            let buckets = book.keys().map(|px| bucket(*px, side, tick)).collect::<HashSet<_>>();
+            for bucket in buckets {
+                if let Some(level) = sum_bucket(&self.orders, book, side, tick, bucket) {
+                    group.side_mut(side).insert(bucket, level);
+                }
+            }
+        }
+        self.tick_groups.insert(tick, group);
+        Ok(())
+    }
+
+    pub(crate) fn tick_sizes(&self) -> impl Iterator<Item = Px> + '_ {
+        self.tick_groups.keys().copied()
+    }
+
+    pub(super) fn touch(&mut self, side: Side, px: Px) {
+        if !self.tick_groups.is_empty() {
+            self.touched.push((side, px));
+        }
+    }
+
+    // re-sums the buckets of the levels changed since the last update
+    pub(crate) fn update_tick_groups(&mut self) {
+        if self.touched.is_empty() {
+            return;
+        }
+        let touched = std::mem::take(&mut self.touched);
+        for (tick, group) in &mut self.tick_groups {
+            let buckets = touched.iter().map(|(side, px)| (*side, bucket(*px, *side, *tick))).collect::<HashSet<_>>();
+            for (side, bucket) in buckets {
+                let book = match side {
+                    Side::Ask => &self.asks,
+                    Side::Bid => &self.bids,
+                };
+                match sum_bucket(&self.orders, book, side, *tick, bucket) {
+                    Some(level) => group.side_mut(side).insert(bucket, level),
+                    None => group.side_mut(side).remove(&bucket),
+                };
+            }
+        }
+    }
+
+    // bids and asks, best first. `None` if the book doesn't keep this tick size
+    pub(crate) fn tick_snapshot(&self, tick: Px, n_levels: Option<usize>) -> Option<Snapshot<InnerLevel>> {
+        let group = self.tick_groups.get(&tick)?;
+        let n_levels = n_levels.unwrap_or(usize::MAX);
+        let bids = group.bids.values().rev().take(n_levels).cloned().collect();
+        let asks = group.asks.values().take(n_levels).cloned().collect();
+        Some(Snapshot([bids, asks]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::primitives::Address;
+
+    use super::*;
+    use crate::{
+        order_book::{Coin, Oid},
+        types::inner::InnerL4Order,
+    };
+
+    fn order(oid: u64, side: Side, px: u64, sz: u64) -> InnerL4Order {
+        InnerL4Order {
+            user: Address::default(),
+            coin: Coin::new("BTC"),
+            side,
+            limit_px: Px::new(px),
+            sz: Sz::new(sz),
+            oid,
+            timestamp: 0,
+            trigger_condition: String::new(),
+            is_trigger: false,
+            trigger_px: String::new(),
+            is_position_tpsl: false,
+            reduce_only: false,
+            order_type: String::new(),
+            tif: None,
+            cloid: None,
+        }
+    }
+
+    fn levels(snapshot: &Snapshot<InnerLevel>) -> Vec<Vec<(u64, u64, usize)>> {
+        snapshot.as_ref().iter().map(|side| side.iter().map(|l| (l.px.value(), l.sz.value(), l.n)).collect()).collect()
+    }
+
+    #[test]
+    fn test_tick_groups_follow_the_book() -> Result<()> {
+        let tick = Px::new(50);
+        let mut book = OrderBook::new();
+        for (oid, side, px) in [(1, Side::Bid, 90), (2, Side::Bid, 99), (3, Side::Bid, 140), (4, Side::Ask, 160)] {
+            book.add_order(order(oid, side, px, 10));
+        }
+        book.add_tick_group(tick)?;
+        let snapshot = book.tick_snapshot(tick, None).unwrap();
+        assert_eq!(levels(&snapshot), vec![vec![(100, 10, 1), (50, 20, 2)], vec![(200, 10, 1)]]);
+
+        book.add_order(order(5, Side::Ask, 151, 5));
+        assert!(book.modify_sz(Oid::new(1), Sz::new(4)));
+        assert!(book.cancel_order(Oid::new(3)));
+        // crosses the bid at 99 and rests the rest
+        book.add_order(order(6, Side::Ask, 95, 12));
+        book.update_tick_groups();
+        let mut fresh = book.clone();
+        fresh.tick_groups.clear();
+        fresh.add_tick_group(tick)?;
+        let snapshot = book.tick_snapshot(tick, None).unwrap();
+        assert_eq!(levels(&snapshot), levels(&fresh.tick_snapshot(tick, None).unwrap()));
+        assert_eq!(levels(&snapshot), vec![vec![(50, 4, 1)], vec![(100, 2, 1), (200, 15, 2)]]);
+        assert_eq!(levels(&book.tick_snapshot(tick, Some(1)).unwrap())[1], vec![(100, 2, 1)]);
+        Ok(())
+    }
+}
@@ -5,7 +5,7 @@ use std::{
 
 use serde::{Deserialize, Serialize};
 
-use crate::prelude::*;
+use crate::{order_book::fixed_point::FixedPoint, prelude::*};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub(crate) enum Side {
@@ -18,7 +18,7 @@ pub(crate) enum Side {
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub(crate) struct Oid(u64);
 
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub(crate) struct Px(u64);
 
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -58,6 +58,9 @@ impl Oid {
     pub(crate) const fn new(value: u64) -> Self {
         Self(value)
     }
+    pub(crate) const fn value(&self) -> u64 {
+        self.0
+    }
 }
 
 pub(crate) trait InnerOrder: Clone {
@@ -81,8 +84,14 @@ impl Coin {
         self.0.clone()
     }
 
+    pub(crate) fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    // of the coin's own market, for coins namespaced by one (e.g. `testnet:@1`)
     pub(crate) fn is_spot(&self) -> bool {
-        self.0.starts_with('@') || self.0 == "PURR/USDC"
+        let coin = self.0.rsplit(':').next().unwrap_or_default();
+        coin.starts_with('@') || coin == "PURR/USDC"
     }
 }
 
@@ -94,34 +103,35 @@ impl Add<Self> for Sz {
     }
 }
 
-// Multiply all sizes and prices by 10^MAX_DECIMALS for ease of computation.
-const MULTIPLIER: f64 = 100_000_000.0;
-
 impl Debug for Px {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", (self.value() as f64 / MULTIPLIER))
+        write!(f, "{}", self.to_str())
     }
 }
 
 impl Debug for Sz {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", (self.value() as f64 / MULTIPLIER))
+        write!(f, "{}", self.to_str())
     }
 }
 
+// prices and sizes are kept as whole numbers of 10^-DECIMALS, parsed and formatted exactly
+fn parse_units(value: &str) -> Result<u64> {
+    let fixed = FixedPoint::parse(value)?;
+    if fixed.is_negative() {
+        return Err(format!("Negative price or size: {value}").into());
+    }
+    Ok(u64::try_from(fixed.units()).map_err(|_| format!("Price or size out of range: {value}"))?)
+}
+
 impl Px {
-    #[allow(clippy::cast_possible_truncation)]
-    #[allow(clippy::cast_sign_loss)]
     pub(crate) fn parse_from_str(value: &str) -> Result<Self> {
-        let value = (value.parse::<f64>()? * MULTIPLIER).round() as u64;
-        Ok(Self::new(value))
+        Ok(Self::new(parse_units(value)?))
     }
 
     #[must_use]
     pub(crate) fn to_str(self) -> String {
-        let s = format!("{:.8}", (self.value() as f64) / MULTIPLIER);
-        let s = s.trim_end_matches('0');
-        s.trim_end_matches('.').to_string()
+        FixedPoint::from_units(u128::from(self.value())).to_string()
     }
 
     #[allow(clippy::cast_possible_truncation)]
@@ -132,17 +142,12 @@ impl Px {
 }
 
 impl Sz {
-    #[allow(clippy::cast_possible_truncation)]
-    #[allow(clippy::cast_sign_loss)]
     pub(crate) fn parse_from_str(value: &str) -> Result<Self> {
-        let value = (value.parse::<f64>()? * MULTIPLIER).round() as u64;
-        Ok(Self::new(value))
+        Ok(Self::new(parse_units(value)?))
     }
 
     #[must_use]
     pub(crate) fn to_str(self) -> String {
-        let s = format!("{:.8}", (self.value() as f64) / MULTIPLIER);
-        let s = s.trim_end_matches('0');
-        s.trim_end_matches('.').to_string()
+        FixedPoint::from_units(u128::from(self.value())).to_string()
     }
 }
@@ -0,0 +1,497 @@
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+use axum::{
+    Extension, Json, Router,
+    extract::{Path, Request, State},
+    http::{
+        HeaderMap, StatusCode,
+        header::{AUTHORIZATION, WWW_AUTHENTICATE},
+    },
+    middleware::{Next, from_fn_with_state},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use tokio::net::TcpListener;
+use tracing::{error, info, warn};
+
+use crate::{
+    logging::{LogFilters, clear_log_filter, log_filters, override_log_filter},
+    prelude::*,
+    servers::{
+        auth::{Authenticator, Identity},
+        preferences::{PreferenceChange, PreferenceStore, Preferences},
+        registry::{ClientInfo, ConnectionRegistry, SlowConsumer, Usage},
+        settings::{RuntimeSettings, Settings, patch_settings},
+        shutdown::AdminShutdown,
+        standby::Standby,
+    },
+};
+
+const BASIC_REALM: &str = r#"Basic realm="admin", charset="UTF-8""#;
+
+// what the handlers act on
+#[derive(Clone)]
+pub(crate) struct AdminState {
+    pub(crate) settings: Settings,
+    pub(crate) registry: Arc<ConnectionRegistry>,
+    pub(crate) preferences: Arc<PreferenceStore>,
+    pub(crate) shutdown: Arc<AdminShutdown>,
+    pub(crate) standby: Standby,
+}
+
+// of a shutdown or promotion
+#[derive(Debug, Deserialize)]
+struct Reason {
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+// `at` schedules the maintenance for that time in ms since the epoch, it starts right away without it
+#[derive(Debug, Serialize, Deserialize)]
+struct Maintenance {
+    enabled: bool,
+    #[serde(default)]
+    at: Option<u64>,
+    #[serde(default)]
+    message: Option<String>,
+    // connections are being rejected
+    #[serde(default)]
+    started: bool,
+}
+
+// e.g. `{"filter": "info,server::servers::websocket_server=trace", "duration_secs": 300}`, until cleared without
+// a duration
+#[derive(Debug, Deserialize)]
+struct LogRequest {
+    filter: String,
+    #[serde(default)]
+    duration_secs: Option<u64>,
+}
+
+impl Maintenance {
+    fn current(registry: &ConnectionRegistry) -> Self {
+        let notice = registry.maintenance();
+        Self {
+            enabled: notice.is_some(),
+            at: notice.as_ref().and_then(|notice| notice.start),
+            message: notice.and_then(|notice| notice.message),
+            started: registry.is_maintenance(),
+        }
+    }
+}
+
+// the operator API; every request needs a credential accepted by `auth` when it is set
+pub(crate) fn serve_admin(
+    listener: TcpListener,
+    state: AdminState,
+    auth: Option<Arc<Authenticator>>,
+    // the websocket URL the viewer connects to, `{host}` standing for the host of the page
+    #[cfg_attr(not(feature = "viewer"), allow(unused_variables))] feed: &str,
+) -> Result<()> {
+    let address = listener.local_addr()?;
+    let app = Router::new()
+        .route("/settings", get(get_settings).put(put_settings))
+        .route("/reload", post(reload))
+        .route("/log", get(get_log).put(put_log).delete(delete_log))
+        .route("/clients", get(clients))
+        .route("/clients/closed", get(closed_clients))
+        .route("/clients/{id}", delete(kick))
+        .route("/usage", get(usage))
+        .route("/slow-consumers", get(slow_consumers))
+        .route("/maintenance", get(get_maintenance).put(put_maintenance))
+        .route("/snapshots", post(resnapshot))
+        .route("/shutdown", post(shutdown))
+        .route("/standby", get(get_standby))
+        .route("/promote", post(promote))
+        .route("/preferences", get(all_preferences))
+        .route("/preferences/{key}", get(get_preferences).put(put_preferences).delete(delete_preferences))
+        .route("/preference-changes", get(preference_changes));
+    #[cfg(feature = "chaos")]
+    let app = app.merge(chaos::routes());
+    // the page is only served behind admin keys, since it hands out the feed
+    #[cfg(feature = "viewer")]
+    let app = if auth.is_some() {
+        app.merge(viewer::routes(feed))
+    } else {
+        warn!("The order book viewer isn't served without admin keys");
+        app
+    };
+    let app = app.with_state(state);
+    let app = if let Some(auth) = auth {
+        app.layer(from_fn_with_state(auth, authenticate))
+    } else {
+        warn!("The admin API at http://{address} doesn't require authentication, so it is only served on loopback");
+        app
+    };
+    info!("Admin server running at http://{address}");
+    tokio::spawn(async move {
+        if let Err(err) = axum::serve(listener, app.into_make_service()).await {
+            error!("Admin server error: {err}");
+        }
+    });
+    Ok(())
+}
+
+// the identity is passed on to the handlers, for the audit trail of changes
+async fn authenticate(State(auth): State<Arc<Authenticator>>, mut request: Request, next: Next) -> Response {
+    let identity =
+        basic_credential(request.headers()).map_or_else(|| auth.check(request.headers()), |key| auth.validate(&key));
+    match identity {
+        Ok(identity) => {
+            request.extensions_mut().insert(identity);
+            next.run(request).await
+        }
+        // browsers ask for a key then
+        Err(err) => (StatusCode::UNAUTHORIZED, [(WWW_AUTHENTICATE, BASIC_REALM)], err.to_string()).into_response(),
+    }
+}
+
+// browsers, e.g. on the viewer page, send the key as the password of basic authentication
+fn basic_credential(headers: &HeaderMap) -> Option<String> {
+    let encoded = headers.get(AUTHORIZATION)?.to_str().ok()?.strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(BASE64_STANDARD.decode(encoded.trim()).ok()?).ok()?;
+    decoded.split_once(':').map(|(_, key)| key.to_string())
+}
+
+async fn get_settings(State(state): State<AdminState>) -> Json<RuntimeSettings> {
+    Json(state.settings.current())
+}
+
+// the body only needs the settings to change, e.g. `{"rate_limits": {"client_messages_per_sec": 5}}`
+async fn put_settings(State(state): State<AdminState>, Json(patch): Json<Value>) -> Response {
+    let res = patch_settings(state.settings.current(), patch).and_then(|new| state.settings.update(new));
+    match res {
+        Ok(()) => Json(state.settings.current()).into_response(),
+        Err(err) => (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    }
+}
+
+async fn reload(State(state): State<AdminState>) -> Response {
+    match state.settings.reload() {
+        Ok(()) => Json(state.settings.current()).into_response(),
+        Err(err) => (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    }
+}
+
+async fn get_log() -> Response {
+    log_response(log_filters())
+}
+
+async fn put_log(Json(request): Json<LogRequest>) -> Response {
+    match override_log_filter(&request.filter, request.duration_secs.map(Duration::from_secs)) {
+        Ok(_) => log_response(log_filters()),
+        Err(err) => (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    }
+}
+
+async fn delete_log() -> Response {
+    clear_log_filter();
+    log_response(log_filters())
+}
+
+// the filters are only known to the logger of `init_logging`
+fn log_response(filters: Option<LogFilters>) -> Response {
+    let Some(filters) = filters else {
+        return (StatusCode::NOT_IMPLEMENTED, "Logging isn't set up by this server").into_response();
+    };
+    Json(filters).into_response()
+}
+
+async fn clients(State(state): State<AdminState>) -> Json<Vec<ClientInfo>> {
+    Json(state.registry.clients().await)
+}
+
+async fn closed_clients(State(state): State<AdminState>) -> Json<Vec<ClientInfo>> {
+    Json(state.registry.closed_clients())
+}
+
+async fn usage(State(state): State<AdminState>) -> Json<Vec<Usage>> {
+    Json(state.registry.usage())
+}
+
+async fn slow_consumers(State(state): State<AdminState>) -> Json<Vec<SlowConsumer>> {
+    Json(state.registry.slow_consumers())
+}
+
+async fn kick(State(state): State<AdminState>, Path(id): Path<u64>) -> StatusCode {
+    if state.registry.kick(id) {
+        info!("Disconnecting client {id} on request of the admin API");
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+async fn get_maintenance(State(state): State<AdminState>) -> Json<Maintenance> {
+    Json(Maintenance::current(&state.registry))
+}
+
+// e.g. `{"enabled": true, "at": 1767225600000, "message": "node upgrade"}`, or `{"enabled": false}` to call it off
+async fn put_maintenance(State(state): State<AdminState>, Json(maintenance): Json<Maintenance>) -> Json<Maintenance> {
+    if maintenance.enabled {
+        let start = maintenance.at.unwrap_or_else(|| u64::try_from(Utc::now().timestamp_millis()).unwrap_or_default());
+        info!("Maintenance scheduled for {start}");
+        state.registry.schedule_maintenance(start, maintenance.message);
+    } else {
+        info!("Maintenance mode off");
+        state.registry.end_maintenance();
+    }
+    Json(Maintenance::current(&state.registry))
+}
+
+async fn resnapshot(State(state): State<AdminState>) -> Json<Value> {
+    let connections = state.registry.resnapshot();
+    info!("Sending fresh snapshots to {connections} connection(s)");
+    Json(json!({ "connections": connections }))
+}
+
+// e.g. `{"reason": "moving to another host"}`, which is optional. Answers once the shutdown started; the server
+// drains its connections as on SIGTERM
+async fn shutdown(State(state): State<AdminState>, request: Option<Json<Reason>>) -> StatusCode {
+    let reason = request.and_then(|Json(request)| request.reason);
+    state.shutdown.request(reason.unwrap_or_else(|| "requested through the admin API".to_string()));
+    StatusCode::ACCEPTED
+}
+
+async fn get_standby(State(state): State<AdminState>) -> Json<Value> {
+    Json(json!({ "standby": state.standby.is_on() }))
+}
+
+// e.g. `{"reason": "primary lost its upstream"}`, which is optional. The server accepts clients and publishes from
+// then on; `promoted` is false if it already did
+async fn promote(State(state): State<AdminState>, request: Option<Json<Reason>>) -> Json<Value> {
+    let reason = request.and_then(|Json(request)| request.reason);
+    let promoted = state.standby.promote(reason.as_deref().unwrap_or("requested through the admin API"));
+    Json(json!({ "standby": false, "promoted": promoted }))
+}
+
+async fn all_preferences(State(state): State<AdminState>) -> Json<BTreeMap<String, Preferences>> {
+    Json(state.preferences.all())
+}
+
+// `key` is the name of the API key's identity, `<tenant>:<name>` for identities of a tenant
+async fn get_preferences(State(state): State<AdminState>, Path(key): Path<String>) -> Response {
+    state
+        .preferences
+        .get(&key)
+        .map_or_else(|| StatusCode::NOT_FOUND.into_response(), |prefs| Json(prefs).into_response())
+}
+
+// replaces the key's preferences, e.g. `{"numbers": "float", "nLevels": 50, "conflateMs": 100, "fields": ["seq"]}`.
+// They apply to the key's connections from the next one on
+async fn put_preferences(
+    State(state): State<AdminState>,
+    identity: Option<Extension<Identity>>,
+    Path(key): Path<String>,
+    Json(preferences): Json<Preferences>,
+) -> Response {
+    let by = identity.map(|Extension(identity)| identity.name);
+    match state.preferences.set(&key, Some(preferences.clone()), by) {
+        Ok(_) => Json(preferences).into_response(),
+        Err(err) => (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    }
+}
+
+async fn delete_preferences(
+    State(state): State<AdminState>,
+    identity: Option<Extension<Identity>>,
+    Path(key): Path<String>,
+) -> Response {
+    let by = identity.map(|Extension(identity)| identity.name);
+    match state.preferences.set(&key, None, by) {
+        Ok(Some(_)) => StatusCode::NO_CONTENT.into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    }
+}
+
+// oldest first
+async fn preference_changes(State(state): State<AdminState>) -> Json<Vec<PreferenceChange>> {
+    Json(state.preferences.changes())
+}
+
+// fault injection, in builds with the `chaos` feature only
+#[cfg(feature = "chaos")]
+mod chaos {
+    use super::{
+        AdminState, Deserialize, Duration, IntoResponse, Json, Path, Response, Router, State, StatusCode, get, info,
+        post, warn,
+    };
+    use crate::chaos::{FaultConfig, FaultStatus, clear_faults, disconnect_upstream, fault_status, inject_faults};
+
+    // e.g. `{"duration_secs": 30}`, which is 0 to end it early
+    #[derive(Debug, Deserialize)]
+    struct FaultDuration {
+        duration_secs: u64,
+    }
+
+    pub(super) fn routes() -> Router<AdminState> {
+        Router::new()
+            .route("/chaos", get(get_faults).put(put_faults).delete(delete_faults))
+            .route("/chaos/upstream-disconnect", post(upstream_disconnect))
+            .route("/chaos/stalls/{id}", post(stall))
+    }
+
+    async fn get_faults() -> Json<FaultStatus> {
+        Json(fault_status())
+    }
+
+    async fn put_faults(Json(config): Json<FaultConfig>) -> Response {
+        match inject_faults(config) {
+            Ok(()) => Json(fault_status()).into_response(),
+            Err(err) => (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+        }
+    }
+
+    async fn delete_faults() -> Json<FaultStatus> {
+        info!("Faults lifted");
+        clear_faults();
+        Json(fault_status())
+    }
+
+    async fn upstream_disconnect(Json(request): Json<FaultDuration>) -> Json<FaultStatus> {
+        disconnect_upstream(Duration::from_secs(request.duration_secs));
+        Json(fault_status())
+    }
+
+    async fn stall(
+        State(state): State<AdminState>,
+        Path(id): Path<u64>,
+        Json(request): Json<FaultDuration>,
+    ) -> StatusCode {
+        if state.registry.stall(id, Duration::from_secs(request.duration_secs)) {
+            warn!("Stalling client {id} for {}s", request.duration_secs);
+            StatusCode::NO_CONTENT
+        } else {
+            StatusCode::NOT_FOUND
+        }
+    }
+}
+
+// a page rendering the live book of a market off the websocket feed, in builds with the `viewer` feature only
+#[cfg(feature = "viewer")]
+mod viewer {
+    use super::{AdminState, Router, get};
+    use axum::response::Html;
+
+    const PAGE: &str = include_str!("../../assets/viewer.html");
+
+    pub(super) fn routes(feed: &str) -> Router<AdminState> {
+        let page = PAGE.replace("{{FEED}}", feed);
+        Router::new().route("/debug/viewer", get(async move || Html(page)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ServerConfig,
+        servers::{
+            auth::{AuthConfig, StaticKeys},
+            priority::Priority,
+            rate_limit::ConnectionRateLimiter,
+            shutdown::ShutdownTrigger,
+        },
+    };
+
+    #[tokio::test]
+    async fn test_requires_admin_key() -> Result<()> {
+        let mut keys = StaticKeys::default();
+        keys.insert(
+            "ops".to_string(),
+            Identity { name: "ops".to_string(), tenant: None, max_connections: None, priority: Priority::default() },
+        );
+        let auth = Arc::new(Authenticator::new(AuthConfig::new(vec![Arc::new(keys)])));
+        let config = ServerConfig::new("127.0.0.1:0".parse()?);
+        let settings = Settings::new(&config, Arc::new(ConnectionRateLimiter::new(None)));
+        let registry = Arc::new(ConnectionRegistry::default());
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        let preferences = Arc::new(PreferenceStore::default());
+        let shutdown = Arc::new(AdminShutdown::default());
+        let standby = Standby::new(true);
+        let state = AdminState {
+            settings,
+            registry: registry.clone(),
+            preferences: preferences.clone(),
+            shutdown: shutdown.clone(),
+            standby: standby.clone(),
+        };
+        serve_admin(listener, state, Some(auth), "ws://{host}:8000/ws")?;
+
+        let client = reqwest::Client::new();
+        let url = format!("http://{address}/maintenance");
+        let put = |key: &str| {
+            client.put(&url).bearer_auth(key).header("content-type", "application/json").body(r#"{"enabled":true}"#)
+        };
+        assert_eq!(put("wrong").send().await?.status(), StatusCode::UNAUTHORIZED);
+        assert!(!registry.is_maintenance());
+        assert_eq!(put("ops").send().await?.status(), StatusCode::OK);
+        assert!(registry.is_maintenance());
+
+        // changes to preferences are recorded with the admin's identity
+        let url = format!("http://{address}/preferences/alice");
+        let put = |body: &'static str| {
+            client.put(&url).bearer_auth("ops").header("content-type", "application/json").body(body)
+        };
+        assert_eq!(put(r#"{"nLevels":500}"#).send().await?.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(put(r#"{"nLevels":50}"#).send().await?.status(), StatusCode::OK);
+        assert_eq!(preferences.get("alice").and_then(|preferences| preferences.n_levels), Some(50));
+        let changes = preferences.changes();
+        assert_eq!((changes.len(), changes[0].by.as_deref()), (1, Some("ops")));
+        assert_eq!(client.delete(&url).bearer_auth("ops").send().await?.status(), StatusCode::NO_CONTENT);
+        assert_eq!(client.get(&url).bearer_auth("ops").send().await?.status(), StatusCode::NOT_FOUND);
+
+        // browsers are asked for the key, as the password of basic authentication
+        let url = format!("http://{address}/clients");
+        let missing = client.get(&url).send().await?;
+        assert_eq!(missing.status(), StatusCode::UNAUTHORIZED);
+        assert!(missing.headers().contains_key(WWW_AUTHENTICATE));
+        assert_eq!(client.get(&url).basic_auth("me", Some("ops")).send().await?.status(), StatusCode::OK);
+        assert_eq!(client.get(&url).basic_auth("me", Some("wrong")).send().await?.status(), StatusCode::UNAUTHORIZED);
+        #[cfg(feature = "viewer")]
+        {
+            let page =
+                client.get(format!("http://{address}/debug/viewer")).basic_auth("me", Some("ops")).send().await?;
+            assert!(page.text().await?.contains(r#"const FEED = "ws://{host}:8000/ws""#));
+        }
+
+        let url = format!("http://{address}/promote");
+        let promoted: Value = client.post(&url).bearer_auth("ops").send().await?.json().await?;
+        assert_eq!(promoted, json!({ "standby": false, "promoted": true }));
+        assert!(!standby.is_on());
+        let promoted: Value = client.post(&url).bearer_auth("ops").send().await?.json().await?;
+        assert_eq!(promoted["promoted"], false);
+
+        let requested = shutdown.listen()?;
+        let url = format!("http://{address}/shutdown");
+        assert_eq!(client.post(&url).bearer_auth("ops").send().await?.status(), StatusCode::ACCEPTED);
+        assert_eq!(requested.await, "requested through the admin API");
+        Ok(())
+    }
+
+    #[cfg(feature = "viewer")]
+    #[tokio::test]
+    async fn test_viewer_needs_admin_keys() -> Result<()> {
+        let config = ServerConfig::new("127.0.0.1:0".parse()?);
+        let state = AdminState {
+            settings: Settings::new(&config, Arc::new(ConnectionRateLimiter::new(None))),
+            registry: Arc::default(),
+            preferences: Arc::default(),
+            shutdown: Arc::default(),
+            standby: Standby::new(false),
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        serve_admin(listener, state, None, "ws://{host}:8000/ws")?;
+
+        let page = reqwest::get(format!("http://{address}/debug/viewer")).await?;
+        assert_eq!(page.status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+}
@@ -0,0 +1,432 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use axum::http::{HeaderMap, header::AUTHORIZATION};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation, decode};
+use serde::Deserialize;
+
+use crate::{
+    metrics::METRICS,
+    prelude::*,
+    servers::{priority::Priority, tenants::Tenant},
+};
+
+const API_KEY_HEADER: &str = "x-api-key";
+
+/// Who a credential belongs to. Connection limits are counted per identity.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    /// Unique within its tenant.
+    pub name: String,
+    /// Name of the [`Tenant`] it belongs to, one of [`AuthConfig::tenants`]. An identity of a tenant that isn't
+    /// configured is refused.
+    pub tenant: Option<String>,
+    /// Overrides [`AuthConfig::max_connections_per_key`] for this identity.
+    pub max_connections: Option<usize>,
+    /// How its connections are treated under [`LoadShedding`](crate::LoadShedding).
+    pub priority: Priority,
+}
+
+/// Checks a credential (API key or token) presented by a client.
+/// Implement this to plug in a custom scheme; validators are tried in order and the first match wins.
+pub trait Validator: Debug + Send + Sync {
+    fn validate(&self, credential: &str) -> Option<Identity>;
+}
+
+/// A fixed list of API keys.
+#[derive(Debug, Clone, Default)]
+pub struct StaticKeys {
+    keys: HashMap<String, Identity>,
+}
+
+impl StaticKeys {
+    pub fn insert(&mut self, key: String, identity: Identity) {
+        self.keys.insert(key, identity);
+    }
+
+    /// Reads one key per line as `<name> <key> [max connections] [priority]`, where the optional fields can come in
+    /// either order. Empty lines and lines starting with `#` are skipped.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let contents =
+            fs::read_to_string(path).map_err(|err| format!("Unable to read API keys {}: {err}", path.display()))?;
+        let mut keys = Self::default();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || format!("Invalid API key entry on line {} of {}", i + 1, path.display());
+            let mut fields = line.split_whitespace();
+            let (Some(name), Some(key)) = (fields.next(), fields.next()) else {
+                return Err(invalid().into());
+            };
+            let (mut max_connections, mut priority) = (None, None);
+            for field in fields {
+                match (field.parse::<usize>(), field.parse::<Priority>()) {
+                    (Ok(limit), _) if max_connections.is_none() => max_connections = Some(limit),
+                    (_, Ok(level)) if priority.is_none() => priority = Some(level),
+                    _ => return Err(invalid().into()),
+                }
+            }
+            let identity = Identity {
+                name: name.to_string(),
+                tenant: None,
+                max_connections,
+                priority: priority.unwrap_or_default(),
+            };
+            keys.insert(key.to_string(), identity);
+        }
+        Ok(keys)
+    }
+
+    /// Makes every key one of `tenant`'s.
+    #[must_use]
+    pub fn for_tenant(mut self, tenant: &str) -> Self {
+        for identity in self.keys.values_mut() {
+            identity.tenant = Some(tenant.to_string());
+        }
+        self
+    }
+}
+
+impl Validator for StaticKeys {
+    fn validate(&self, credential: &str) -> Option<Identity> {
+        self.keys.get(credential).cloned()
+    }
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+    #[serde(default)]
+    max_connections: Option<usize>,
+    #[serde(default)]
+    priority: Priority,
+    #[serde(default)]
+    tenant: Option<String>,
+}
+
+/// HS256 signed JWTs. The `sub` claim names the identity and `exp` is required.
+///
+/// An optional `max_connections` claim overrides the per-key connection limit, an optional `priority` claim
+/// (`low`, `normal` or `high`) sets the identity's priority, and an optional `tenant` claim the tenant it belongs to.
+pub struct JwtValidator {
+    key: DecodingKey,
+    validation: Validation,
+}
+
+impl JwtValidator {
+    #[must_use]
+    pub fn new(secret: &[u8]) -> Self {
+        Self { key: DecodingKey::from_secret(secret), validation: Validation::new(Algorithm::HS256) }
+    }
+
+    // the secret is the file's contents without surrounding whitespace
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let secret =
+            fs::read_to_string(path).map_err(|err| format!("Unable to read JWT secret {}: {err}", path.display()))?;
+        let secret = secret.trim();
+        if secret.is_empty() {
+            return Err(format!("JWT secret {} is empty", path.display()).into());
+        }
+        Ok(Self::new(secret.as_bytes()))
+    }
+}
+
+impl Debug for JwtValidator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JwtValidator").finish_non_exhaustive()
+    }
+}
+
+impl Validator for JwtValidator {
+    fn validate(&self, credential: &str) -> Option<Identity> {
+        let claims = decode::<Claims>(credential, &self.key, &self.validation).ok()?.claims;
+        Some(Identity {
+            name: claims.sub,
+            tenant: claims.tenant,
+            max_connections: claims.max_connections,
+            priority: claims.priority,
+        })
+    }
+}
+
+/// Require clients to authenticate with one of `validators`.
+#[derive(Debug, Clone)]
+pub struct AuthConfig {
+    pub validators: Vec<Arc<dyn Validator>>,
+    /// Maximum number of open connections per identity, unless the identity sets its own.
+    pub max_connections_per_key: Option<usize>,
+    /// The tenants whose identities the validators return; see [`StaticKeys::for_tenant`].
+    pub tenants: Vec<Tenant>,
+}
+
+impl AuthConfig {
+    #[must_use]
+    pub const fn new(validators: Vec<Arc<dyn Validator>>) -> Self {
+        Self { validators, max_connections_per_key: None, tenants: Vec::new() }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AuthError {
+    Missing,
+    Invalid,
+    // at the identity's limit of open connections
+    TooManyConnections(usize),
+    // at the limit of its tenant
+    TooManyTenantConnections(usize),
+}
+
+impl AuthError {
+    // the client may try again later
+    pub(crate) const fn is_limit(self) -> bool {
+        matches!(self, Self::TooManyConnections(_) | Self::TooManyTenantConnections(_))
+    }
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Missing => f.write_str("authentication required"),
+            Self::Invalid => f.write_str("invalid credentials"),
+            Self::TooManyConnections(limit) => {
+                write!(f, "too many connections for this key (at most {limit} open at once)")
+            }
+            Self::TooManyTenantConnections(limit) => {
+                write!(f, "too many connections for this tenant (at most {limit} open at once)")
+            }
+        }
+    }
+}
+
+// open connections by identity, and by tenant
+#[derive(Default)]
+struct Counts {
+    identities: HashMap<(Option<String>, String), usize>,
+    tenants: HashMap<String, usize>,
+}
+
+pub(crate) struct Authenticator {
+    config: AuthConfig,
+    tenants: HashMap<String, Arc<Tenant>>,
+    counts: Arc<Mutex<Counts>>,
+}
+
+impl Authenticator {
+    pub(crate) fn new(config: AuthConfig) -> Self {
+        let tenants = config.tenants.iter().map(|tenant| (tenant.name.clone(), Arc::new(tenant.clone()))).collect();
+        Self { config, tenants, counts: Arc::default() }
+    }
+
+    // `Authorization: Bearer <token>` or `X-API-Key: <key>`; other schemes, like the `Basic` of a proxy in front, are
+    // not ours and leave the key to be checked
+    pub(crate) fn credential(headers: &HeaderMap) -> Option<&str> {
+        headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .or_else(|| headers.get(API_KEY_HEADER).and_then(|value| value.to_str().ok()))
+            .map(str::trim)
+    }
+
+    pub(crate) fn validate(&self, credential: &str) -> std::result::Result<Identity, AuthError> {
+        let identity = self
+            .config
+            .validators
+            .iter()
+            .find_map(|validator| validator.validate(credential))
+            .ok_or(AuthError::Invalid)?;
+        if identity.tenant.as_ref().is_some_and(|tenant| !self.tenants.contains_key(tenant)) {
+            return Err(AuthError::Invalid);
+        }
+        Ok(identity)
+    }
+
+    // for one-off requests that don't hold a connection open
+    pub(crate) fn check(&self, headers: &HeaderMap) -> std::result::Result<Identity, AuthError> {
+        self.validate(Self::credential(headers).ok_or(AuthError::Missing)?)
+    }
+
+    pub(crate) fn tenant(&self, identity: &Identity) -> Option<Arc<Tenant>> {
+        identity.tenant.as_ref().and_then(|tenant| self.tenants.get(tenant)).cloned()
+    }
+
+    // the connection counts against the limits of the identity and its tenant until the permit is dropped
+    pub(crate) fn admit(&self, credential: &str) -> std::result::Result<ConnectionPermit, AuthError> {
+        let identity = self.validate(credential)?;
+        let tenant = self.tenant(&identity);
+        let limit = identity.max_connections.or(self.config.max_connections_per_key);
+        let mut counts = self.counts.lock().map_err(|_| AuthError::Invalid)?;
+        let key = (identity.tenant, identity.name);
+        if let Some(limit) = limit
+            && counts.identities.get(&key).is_some_and(|count| *count >= limit)
+        {
+            return Err(AuthError::TooManyConnections(limit));
+        }
+        if let Some(tenant) = &tenant {
+            let count = counts.tenants.entry(tenant.name.clone()).or_default();
+            if let Some(limit) = tenant.max_connections
+                && *count >= limit
+            {
+                METRICS.limit_rejections.with_label_values(&["tenant_connections"]).inc();
+                return Err(AuthError::TooManyTenantConnections(limit));
+            }
+            *count += 1;
+        }
+        *counts.identities.entry(key.clone()).or_default() += 1;
+        drop(counts);
+        Ok(ConnectionPermit { key, priority: identity.priority, tenant, counts: self.counts.clone() })
+    }
+}
+
+pub(crate) struct ConnectionPermit {
+    // the tenant and name of the identity
+    key: (Option<String>, String),
+    priority: Priority,
+    tenant: Option<Arc<Tenant>>,
+    counts: Arc<Mutex<Counts>>,
+}
+
+impl ConnectionPermit {
+    pub(crate) fn name(&self) -> &str {
+        &self.key.1
+    }
+
+    pub(crate) const fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    pub(crate) const fn tenant(&self) -> Option<&Arc<Tenant>> {
+        self.tenant.as_ref()
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let Ok(mut counts) = self.counts.lock() else {
+            return;
+        };
+        if let Some(count) = counts.identities.get_mut(&self.key) {
+            *count -= 1;
+            if *count == 0 {
+                counts.identities.remove(&self.key);
+            }
+        }
+        if let Some(tenant) = &self.key.0
+            && let Some(count) = counts.tenants.get_mut(tenant)
+        {
+            *count -= 1;
+            if *count == 0 {
+                counts.tenants.remove(tenant);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use axum::http::HeaderValue;
+    use jsonwebtoken::{EncodingKey, Header, encode};
+    use serde_json::json;
+
+    use super::*;
+
+    fn token(secret: &[u8], sub: &str, exp_offset: i64) -> String {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs().cast_signed();
+        let claims = json!({ "sub": sub, "exp": now + exp_offset });
+        encode(&Header::default(), &claims, &EncodingKey::from_secret(secret)).unwrap()
+    }
+
+    #[test]
+    fn test_connection_limit_per_key() -> Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        fs::write(file.path(), "# name key limit\nalice key-a 1 high\n\nbob key-b\ncarol key-c low\n")?;
+        let mut config = AuthConfig::new(vec![Arc::new(StaticKeys::from_file(file.path())?)]);
+        config.max_connections_per_key = Some(2);
+        let auth = Authenticator::new(config);
+
+        let permit = auth.admit("key-a").map_err(|err| err.to_string())?;
+        assert_eq!((permit.name(), permit.priority()), ("alice", Priority::High));
+        // alice's own limit
+        assert_eq!(auth.admit("key-a").err(), Some(AuthError::TooManyConnections(1)));
+        drop(permit);
+        assert!(auth.admit("key-a").is_ok());
+
+        let _b1 = auth.admit("key-b").map_err(|err| err.to_string())?;
+        let _b2 = auth.admit("key-b").map_err(|err| err.to_string())?;
+        assert_eq!(auth.admit("key-b").err(), Some(AuthError::TooManyConnections(2)));
+        assert_eq!(auth.admit("key-c").map(|permit| permit.priority()).ok(), Some(Priority::Low));
+        assert_eq!(auth.admit("key-d").err(), Some(AuthError::Invalid));
+        Ok(())
+    }
+
+    #[test]
+    fn test_tenant_connection_limit() -> Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        fs::write(file.path(), "alice key-a\nbob key-b\ncarol key-d\n")?;
+        let mut keys = StaticKeys::from_file(file.path())?.for_tenant("acme");
+        keys.insert(
+            "key-c".to_string(),
+            Identity {
+                name: "alice".to_string(),
+                tenant: Some("other".to_string()),
+                max_connections: None,
+                priority: Priority::default(),
+            },
+        );
+        let mut config = AuthConfig::new(vec![Arc::new(keys)]);
+        config.max_connections_per_key = Some(1);
+        config.tenants = vec![Tenant { name: "acme".to_string(), max_connections: Some(2), ..Tenant::default() }];
+        let auth = Authenticator::new(config);
+
+        let alice = auth.admit("key-a").map_err(|err| err.to_string())?;
+        assert_eq!(alice.tenant().map(|tenant| tenant.name.as_str()), Some("acme"));
+        let bob = auth.admit("key-b").map_err(|err| err.to_string())?;
+        assert_eq!(auth.admit("key-a").err(), Some(AuthError::TooManyConnections(1)));
+        assert_eq!(auth.admit("key-d").err(), Some(AuthError::TooManyTenantConnections(2)));
+        drop(bob);
+        assert!(auth.admit("key-d").is_ok());
+        drop(alice);
+        // the same name in a tenant that isn't configured
+        assert_eq!(auth.admit("key-c").err(), Some(AuthError::Invalid));
+        Ok(())
+    }
+
+    #[test]
+    fn test_jwt_validator() {
+        let auth = Authenticator::new(AuthConfig::new(vec![Arc::new(JwtValidator::new(b"secret"))]));
+        let mut headers = HeaderMap::new();
+        assert_eq!(auth.check(&headers), Err(AuthError::Missing));
+
+        let valid = format!("Bearer {}", token(b"secret", "alice", 60));
+        headers.insert(AUTHORIZATION, HeaderValue::from_str(&valid).unwrap());
+        assert_eq!(auth.check(&headers).map(|identity| identity.name), Ok("alice".to_string()));
+
+        assert_eq!(auth.validate(&token(b"other", "alice", 60)), Err(AuthError::Invalid));
+        assert_eq!(auth.validate(&token(b"secret", "alice", -120)), Err(AuthError::Invalid));
+    }
+
+    #[test]
+    fn test_api_key_behind_another_authorization_scheme() -> Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        fs::write(file.path(), "alice key-a\n")?;
+        let auth = Authenticator::new(AuthConfig::new(vec![Arc::new(StaticKeys::from_file(file.path())?)]));
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Basic cHJveHk6c2VjcmV0"));
+        assert_eq!(auth.check(&headers), Err(AuthError::Missing));
+
+        headers.insert(API_KEY_HEADER, HeaderValue::from_static("key-a"));
+        assert_eq!(auth.check(&headers).map(|identity| identity.name), Ok("alice".to_string()));
+        // a bearer token still takes precedence over the key
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer key-b"));
+        assert_eq!(auth.check(&headers), Err(AuthError::Invalid));
+        Ok(())
+    }
+}
@@ -0,0 +1,146 @@
+use std::{env::home_dir, fmt, iter, net::SocketAddr, time::Duration};
+
+use reqwest::Url;
+use tokio::{net::TcpStream, time::timeout};
+
+use crate::{
+    listeners::order_book::UpstreamNode,
+    prelude::*,
+    servers::{config::ServerConfig, socket::bind_tcp_listener, zstd_dictionary::ZstdDictionary},
+    types::node_data::EventSource,
+};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// One line of the report of [`check_websocket_server`]: what was checked, and what was found or why it failed.
+#[derive(Debug)]
+pub struct Check {
+    pub name: String,
+    pub result: std::result::Result<String, String>,
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.result {
+            Ok(found) => write!(f, "ok   {}: {found}", self.name),
+            Err(err) => write!(f, "FAIL {}: {err}", self.name),
+        }
+    }
+}
+
+impl Check {
+    fn new(name: impl Into<String>, result: Result<String>) -> Self {
+        Self { name: name.into(), result: result.map_err(|err| err.to_string()) }
+    }
+}
+
+/// Checks what [`run_websocket_server`](crate::run_websocket_server) needs to start with `config`, without starting it.
+///
+/// These are the settings, the TLS certificate and key, the zstd dictionary and the ports, which are bound and
+/// released again, and the upstream nodes, whose event directories have to exist and whose info endpoints have to
+/// accept connections. Every check runs even once one failed, so that the report lists every problem at once.
+pub async fn check_websocket_server(config: &ServerConfig) -> Vec<Check> {
+    let mut checks = vec![Check::new("configuration", config.validate().map(|()| "valid".to_string()))];
+    if let Some(tls) = &config.tls {
+        let name = format!("TLS certificate {}", tls.cert_path.display());
+        checks.push(Check::new(name, tls.load_acceptor().map(|_| format!("matches {}", tls.key_path.display()))));
+    }
+    if let Some(path) = &config.zstd_dictionary {
+        let dictionary = ZstdDictionary::from_file(path).map(|_| "loaded".to_string());
+        checks.push(Check::new(format!("zstd dictionary {}", path.display()), dictionary));
+    }
+    let ports = [config.metrics_port, config.grpc_port, config.health_port, config.relay_port];
+    let addresses = ports.into_iter().flatten().map(|port| SocketAddr::new(config.address.ip(), port));
+    for address in iter::once(config.address).chain(addresses).chain(config.admin_address()) {
+        // released once dropped
+        let bound = bind_tcp_listener(address, config.dual_stack, config.reuse_port, config.socket.backlog)
+            .map(|_| "can be bound".to_string());
+        checks.push(Check::new(format!("address {address}"), bound));
+    }
+    if let Some(address) = &config.relay_upstream {
+        checks.push(Check::new(format!("relay feed {address}"), connect(address).await));
+        return checks;
+    }
+    let mut upstreams = config.upstreams.clone();
+    if upstreams.is_empty() {
+        match home_dir() {
+            Some(home) => upstreams.push(UpstreamNode::new(home)),
+            None => checks.push(Check::new("upstream", Err("Could not find home directory".into()))),
+        }
+    }
+    upstreams.extend(config.markets.iter().flat_map(|market| market.upstreams.iter().cloned()));
+    for upstream in upstreams {
+        checks.push(Check::new(format!("upstream {upstream}"), event_dirs(&upstream)));
+        checks
+            .push(Check::new(format!("info endpoint {}", upstream.info_url), info_endpoint(&upstream.info_url).await));
+    }
+    checks
+}
+
+// the node writes a directory of files for every event source
+fn event_dirs(upstream: &UpstreamNode) -> Result<String> {
+    for event_source in [EventSource::Fills, EventSource::OrderStatuses, EventSource::OrderDiffs] {
+        let dir = event_source.event_source_dir(&upstream.data_dir);
+        if !dir.is_dir() {
+            return Err(format!("no {event_source} directory at {}", dir.display()).into());
+        }
+    }
+    Ok("event directories found".to_string())
+}
+
+// snapshots are only requested from the node later, which takes a while, so connecting is enough here
+async fn info_endpoint(info_url: &str) -> Result<String> {
+    let url = Url::parse(info_url)?;
+    let host = url.host_str().ok_or("no host")?;
+    let port = url.port_or_known_default().ok_or("no port")?;
+    connect(&format!("{host}:{port}")).await
+}
+
+async fn connect(address: &str) -> Result<String> {
+    let stream = timeout(CONNECT_TIMEOUT, TcpStream::connect(address)).await.map_err(|_| "connect timed out")??;
+    Ok(format!("connected to {}", stream.peer_addr()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::servers::tls::TlsConfig;
+
+    fn failed(checks: &[Check]) -> Vec<&str> {
+        checks.iter().filter(|check| check.result.is_err()).map(|check| check.name.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_check() -> Result<()> {
+        let dir = tempdir()?;
+        let info = TcpListener::bind("127.0.0.1:0").await?;
+        let node = UpstreamNode {
+            data_dir: dir.path().to_path_buf(),
+            info_url: format!("http://{}/info", info.local_addr()?),
+        };
+        for event_source in [EventSource::Fills, EventSource::OrderStatuses, EventSource::OrderDiffs] {
+            fs::create_dir_all(event_source.event_source_dir(dir.path()))?;
+        }
+        let mut config = ServerConfig::new("127.0.0.1:0".parse()?);
+        config.upstreams = vec![node];
+        let checks = check_websocket_server(&config).await;
+        assert_eq!(checks.len(), 4);
+        assert!(failed(&checks).is_empty(), "{checks:?}");
+
+        // a port in use, a missing certificate and a node that isn't running are all reported
+        let taken = TcpListener::bind("127.0.0.1:0").await?;
+        config.metrics_port = Some(taken.local_addr()?.port());
+        config.tls = Some(TlsConfig::new(dir.path().join("cert.pem"), dir.path().join("key.pem")));
+        drop(info);
+        let checks = check_websocket_server(&config).await;
+        let address = format!("address {}", taken.local_addr()?);
+        let cert = format!("TLS certificate {}", dir.path().join("cert.pem").display());
+        assert_eq!(failed(&checks).len(), 3, "{checks:?}");
+        assert!(failed(&checks).contains(&address.as_str()));
+        assert!(failed(&checks).contains(&cert.as_str()));
+        Ok(())
+    }
+}
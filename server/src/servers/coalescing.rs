@@ -0,0 +1,192 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex, OnceLock, Weak},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use tokio::time::Instant;
+use yawc::FrameView;
+
+use crate::{
+    latency::now_ms,
+    prelude::*,
+    servers::{
+        encoding::{Numbers, Subprotocol},
+        outbound::Outbound,
+        shared_compression::deflate,
+    },
+};
+
+// frames kept per identity; its connections send the same frame within moments of each other
+const CACHED_FRAMES: usize = 64;
+
+/// The frames recently sent to each identity (API key or token subject), so that its connections with the same
+/// subscriptions and options send the frame the first of them built and compressed, instead of each building its
+/// own.
+#[derive(Default)]
+pub(crate) struct Coalescer {
+    identities: Mutex<HashMap<String, Weak<IdentityFrames>>>,
+}
+
+impl Coalescer {
+    // shared by the identity's connections, and dropped with the last of them
+    pub(crate) fn frames(&self, identity: &str) -> Arc<IdentityFrames> {
+        let Ok(mut identities) = self.identities.lock() else {
+            return Arc::default();
+        };
+        if let Some(frames) = identities.get(identity).and_then(Weak::upgrade) {
+            return frames;
+        }
+        identities.retain(|_, frames| frames.strong_count() > 0);
+        let frames = Arc::<IdentityFrames>::default();
+        identities.insert(identity.to_string(), Arc::downgrade(&frames));
+        frames
+    }
+}
+
+// the end of the batch window that `now` (in ms since the epoch) is in. Windows start at multiples of their length,
+// so the connections of an identity that get the same messages put them in the same batches
+pub(crate) fn aligned_deadline(window: Duration, now: u64) -> Instant {
+    let window_ms = u64::try_from(window.as_millis()).unwrap_or(u64::MAX).max(1);
+    Instant::now() + Duration::from_millis(window_ms - now % window_ms)
+}
+
+pub(crate) fn batch_deadline(window: Duration, coalesced: bool) -> Instant {
+    if coalesced { aligned_deadline(window, now_ms()) } else { Instant::now() + window }
+}
+
+// how a frame was made from its messages, besides the messages themselves
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) struct FrameOptions {
+    pub(crate) subprotocol: Subprotocol,
+    pub(crate) numbers: Numbers,
+    pub(crate) batched: bool,
+}
+
+struct Key {
+    options: FrameOptions,
+    // the outbound messages are shared by every connection with the same subscription, so equal frames hold the
+    // same ones
+    msgs: Vec<Arc<Outbound>>,
+}
+
+impl Key {
+    fn matches(&self, options: FrameOptions, msgs: &[Arc<Outbound>]) -> bool {
+        self.options == options
+            && self.msgs.len() == msgs.len()
+            && self.msgs.iter().zip(msgs).all(|(cached, msg)| Arc::ptr_eq(cached, msg))
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct IdentityFrames {
+    // oldest first
+    frames: Mutex<VecDeque<(Key, Arc<SharedFrame>)>>,
+}
+
+impl IdentityFrames {
+    // the frame of the messages, built by the first connection that sends it
+    pub(crate) fn frame(&self, options: FrameOptions, msgs: &[Arc<Outbound>]) -> Arc<SharedFrame> {
+        let Ok(mut frames) = self.frames.lock() else {
+            return Arc::default();
+        };
+        if let Some((_, frame)) = frames.iter().find(|(key, _)| key.matches(options, msgs)) {
+            return frame.clone();
+        }
+        if frames.len() >= CACHED_FRAMES {
+            frames.pop_front();
+        }
+        let frame = Arc::<SharedFrame>::default();
+        frames.push_back((Key { options, msgs: msgs.to_vec() }, frame.clone()));
+        frame
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct SharedFrame {
+    frame: OnceLock<Option<FrameView>>,
+    // deflated payload per compression level
+    compressed: Mutex<HashMap<u32, Bytes>>,
+}
+
+impl SharedFrame {
+    // connections sending it at once wait for the first one to build it
+    pub(crate) fn get_or_build(&self, build: impl FnOnce() -> Result<FrameView>) -> Result<FrameView> {
+        let mut err = None;
+        let frame = self.frame.get_or_init(|| build().map_err(|build_err| err = Some(build_err)).ok());
+        match (frame, err) {
+            (Some(frame), _) => Ok(frame.clone()),
+            (None, Some(err)) => Err(err),
+            (None, None) => Err("Unable to build the frame".into()),
+        }
+    }
+
+    // the payload of the built frame, deflated once per compression level
+    pub(crate) fn compressed(&self, level: u32) -> Result<Bytes> {
+        let Some(Some(frame)) = self.frame.get() else {
+            return Err("The frame isn't built".into());
+        };
+        let Ok(mut compressed) = self.compressed.lock() else {
+            return Ok(deflate(level, &frame.payload)?);
+        };
+        if let Some(compressed) = compressed.get(&level) {
+            return Ok(compressed.clone());
+        }
+        let deflated = deflate(level, &frame.payload)?;
+        compressed.insert(level, deflated.clone());
+        Ok(deflated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        servers::encoding::Encoding,
+        types::{L4Book, L4BookUpdates, subscription::ServerResponse},
+    };
+
+    fn msg(seq: u64) -> Arc<Outbound> {
+        ServerResponse::L4Book(L4Book::Updates(L4BookUpdates::new(seq, seq))).into()
+    }
+
+    #[test]
+    fn test_frames_shared_by_identity() -> Result<()> {
+        let coalescer = Coalescer::default();
+        let (first, second) = (coalescer.frames("alice"), coalescer.frames("alice"));
+        assert!(Arc::ptr_eq(&first, &second));
+        assert!(!Arc::ptr_eq(&first, &coalescer.frames("bob")));
+
+        let options = FrameOptions { subprotocol: Subprotocol::default(), numbers: Numbers::String, batched: true };
+        let msgs = vec![msg(1), msg(2)];
+        let build = || Ok(Encoding::Json.frame(Bytes::from_static(b"[1,2]")));
+        let frame = first.frame(options, &msgs).get_or_build(build)?;
+        // the other connection doesn't build it again
+        let shared = second.frame(options, &msgs);
+        assert_eq!(shared.get_or_build(|| Err("built twice".into()))?.payload, frame.payload);
+        assert_eq!(frame.payload.as_ref(), b"[1,2]");
+        let compressed = shared.compressed(6)?;
+        assert_eq!(first.frame(options, &msgs).compressed(6)?.as_ptr(), compressed.as_ptr());
+
+        // other messages, or the same ones framed differently
+        assert!(first.frame(options, &[msg(1), msg(2)]).get_or_build(|| Err("not cached".into())).is_err());
+        let options = FrameOptions { numbers: Numbers::Integer, ..options };
+        assert!(first.frame(options, &msgs).get_or_build(|| Err("not cached".into())).is_err());
+
+        // dropped with the identity's last connection
+        drop((first, second));
+        assert!(coalescer.frames("alice").frame(options, &msgs).get_or_build(|| Err("gone".into())).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_aligned_deadline() {
+        let window = Duration::from_millis(100);
+        let start = Instant::now();
+        let deadline = aligned_deadline(window, 1_751_430_933_565);
+        assert!(deadline - start >= Duration::from_millis(35) && deadline - start < Duration::from_millis(40));
+        let deadline = aligned_deadline(window, 1_751_430_933_500);
+        assert!(deadline - start >= Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,516 @@
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use tracing::level_filters::LevelFilter;
+
+use crate::{
+    analytics::AnalyticsConfig,
+    archive::ArchiveConfig,
+    candles::CandleConfig,
+    journal::JournalConfig,
+    listeners::order_book::{AuditConfig, CrossedBookPolicy, InactivityPolicy, SnapshotStrategy, UpstreamNode},
+    prelude::*,
+    servers::{
+        auth::AuthConfig,
+        cors::CorsConfig,
+        deflate::DeflateConfig,
+        election::ElectionConfig,
+        keepalive::KeepaliveConfig,
+        limits::ConnectionLimits,
+        markets::MarketConfig,
+        priority::LoadShedding,
+        proxy::ProxyConfig,
+        publisher::PublisherConfig,
+        rate_limit::{BandwidthPolicy, RateLimits},
+        send_queue::{self, BackpressurePolicy},
+        settings::{ReloadHook, RuntimeSettings},
+        shutdown::ShutdownTrigger,
+        socket::SocketOptions,
+        standby::PromotionTrigger,
+        tenants::{self, PRIMARY_MARKET},
+        tls::TlsConfig,
+        watchdog::WatchdogConfig,
+        webhooks::WebhookConfig,
+    },
+    signing::SigningConfig,
+    snapshot_store::SnapshotStoreConfig,
+};
+
+/// Settings for [`run_websocket_server`](crate::run_websocket_server).
+/// Start from [`ServerConfig::new`] and override the fields that differ from the defaults.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub address: SocketAddr,
+    /// Accept IPv4 clients on an IPv6 socket. Only valid with an IPv6 address.
+    pub dual_stack: bool,
+    /// Listen with `SO_REUSEPORT`, so that the next server process can bind the same ports while this one drains
+    /// its connections.
+    pub reuse_port: bool,
+    /// Also serve the websocket port's routes on a Unix domain socket at this path, for clients on the same host.
+    /// A socket file left behind at the path is replaced.
+    pub unix_socket: Option<PathBuf>,
+    /// Accept the websocket port's connections, read from them and write to them through `io_uring`, on a thread of
+    /// its own. Plain connections only, without `tls` or the PROXY protocol. Needs Linux and the `io-uring` feature.
+    pub io_uring: bool,
+    /// TCP options of the websocket port's connections, such as `TCP_NODELAY`, and the listen backlog of all ports.
+    pub socket: SocketOptions,
+    pub ignore_spot: bool,
+    /// Nodes to ingest events from. All of them are read at once and duplicate blocks are dropped,
+    /// so the stream continues as long as one of them is healthy. Empty means a single node writing to the home directory.
+    pub upstreams: Vec<UpstreamNode>,
+    /// Further markets to read in the same process, whose coins are served as `<name>:<coin>`. The status
+    /// channel, snapshot store and relay feed cover the primary market only.
+    pub markets: Vec<MarketConfig>,
+    /// What happens when the node's deltas leave a book crossed or locked, in the primary market and in the
+    /// markets that don't set their own.
+    pub crossed_books: CrossedBookPolicy,
+    /// When the books of the further markets that don't set their own are loaded. Lazy markets don't hold up
+    /// readiness, the primary market is always loaded at startup.
+    pub snapshot_strategy: SnapshotStrategy,
+    /// How long the books of a lazy market are kept without a subscriber, checked every 10 seconds.
+    pub lazy_idle_timeout: Duration,
+    /// How often the books of every market are audited against a snapshot of its node, and when they are healed.
+    pub audit: AuditConfig,
+    /// Websocket deflate compression level, `0..=9`. Applies to connections opened after a reload.
+    pub compression_level: u32,
+    /// Compress each message once for all connections with the same compression level, instead of once per
+    /// connection. Clients are asked to accept compression without context takeover, which compresses a bit worse.
+    pub shared_compression: bool,
+    /// Build the frames of an API key's connections with the same subscriptions once for all of them, aligning
+    /// their batch windows so they batch the same messages. Needs `auth` to tell keys apart.
+    pub coalesce_connections: bool,
+    /// Window sizes and context takeover of websocket compression. `shared_compression` implies
+    /// `server_no_context_takeover`.
+    pub deflate: DeflateConfig,
+    /// Dictionary to compress the frames of `orderbook.msgpack.zstd` connections with, as trained by
+    /// [`train_zstd_dictionary`](crate::train_zstd_dictionary). The built-in one when not set.
+    pub zstd_dictionary: Option<PathBuf>,
+    /// Exit if no node events are observed for this many seconds, or as `inactivity_policy` says.
+    pub inactivity_exit_secs: u64,
+    pub inactivity_policy: InactivityPolicy,
+    pub tls: Option<TlsConfig>,
+    /// Serve the websocket protocol over WebTransport on this UDP port (same address as the websocket server), with
+    /// a unidirectional stream per subscription. Needs `tls` for its certificate and the `webtransport` feature.
+    /// Experimental.
+    pub webtransport_port: Option<u16>,
+    /// Real client addresses behind a load balancer, for rate limits, logs and the admin API.
+    pub proxy: ProxyConfig,
+    /// Let browser pages of these origins use the websocket port's routes. Browsers of other sites are refused
+    /// when set, and get no CORS headers when not set.
+    pub cors: Option<CorsConfig>,
+    /// Require clients to authenticate. Open to anyone when not set.
+    pub auth: Option<AuthConfig>,
+    /// Serve Prometheus metrics on this port (same address as the websocket server).
+    pub metrics_port: Option<u16>,
+    /// Serve the gRPC `OrderBook` service on this port (same address as the websocket server).
+    pub grpc_port: Option<u16>,
+    /// Serve the `/healthz` and `/readyz` probes on this port (same address as the websocket server).
+    pub health_port: Option<u16>,
+    /// How long to wait for clients to drain and close once shutdown starts.
+    pub drain_timeout: Duration,
+    /// Also shut down gracefully when any of these fires, as on the operating system's [`Signals`] and
+    /// `POST /shutdown` of the admin API.
+    pub shutdown_triggers: Vec<Arc<dyn ShutdownTrigger>>,
+    /// Start as a warm standby: follow the upstreams and keep the books, but refuse clients and publish nothing
+    /// until promoted, through `POST /promote` of the admin API or by one of the `promotion_triggers`.
+    pub standby: bool,
+    /// Promote a standby server when any of these fires, e.g. once a leader election elected it.
+    pub promotion_triggers: Vec<Arc<dyn PromotionTrigger>>,
+    /// Agree with the other replicas on which of them serves clients, through etcd. The server starts on standby
+    /// then, whatever `standby` says.
+    pub election: Option<ElectionConfig>,
+    /// What to do with clients whose send queue is full.
+    pub backpressure: BackpressurePolicy,
+    /// Maximum number of messages queued for a single client.
+    pub send_queue_capacity: usize,
+    /// How long the messages of a channel of stream data (e.g. `bbo` or `l4Book`) may wait in a client's send
+    /// queue. Older ones are dropped rather than sent, and a subscription whose later messages depended on them
+    /// gets a fresh snapshot. The messages of other channels wait as long as it takes.
+    pub message_ttls: BTreeMap<String, Duration>,
+    /// Shed the stream data of lower priority identities first when the send queues of all clients fill up.
+    /// Priorities have no effect when not set.
+    pub load_shedding: Option<LoadShedding>,
+    /// Shed load in steps while the process uses too much memory or falls behind. Off when not set.
+    pub watchdog: Option<WatchdogConfig>,
+    /// Number of channels the coins of all markets are spread over. A client only receives the messages of the
+    /// channels of the coins it subscribed to; 1 sends every message to every client.
+    pub broadcast_shards: usize,
+    pub rate_limits: RateLimits,
+    pub keepalive: KeepaliveConfig,
+    /// Journal l4 book updates so clients can replay them. Replay is unavailable when not set.
+    pub journal: Option<JournalConfig>,
+    /// Keep the l4 book updates of this much block time in memory, so that clients that reconnect can resume
+    /// with the updates they missed instead of a snapshot. Resumes always get a snapshot when not set.
+    pub resume_window: Option<Duration>,
+    /// Keep the subscriptions, message format and l4 book positions of a dropped websocket connection this long,
+    /// for a client reconnecting with the connection's session token. No tokens are issued when not set.
+    pub session_grace: Option<Duration>,
+    /// Build OHLCV candles from trades for the `candle` channel and the `/candles` endpoint. Off when not set.
+    pub candles: Option<CandleConfig>,
+    /// Publish the spread, imbalance and VWAP of every market on the `analytics` channel. Off when not set.
+    pub analytics: Option<AnalyticsConfig>,
+    /// Sign the l4 snapshots and checkpoints of the books with an Ed25519 key. Off when not set.
+    pub signing: Option<SigningConfig>,
+    /// Mirror l4 book updates and trades onto a message bus such as NATS. Off when not set.
+    pub publisher: Option<PublisherConfig>,
+    /// POST alerts about wide spreads, crossed books, a stale feed and halted markets to URLs. Off when not set.
+    pub webhooks: Option<WebhookConfig>,
+    /// Save the order book state periodically and continue from it after a restart. Off when not set.
+    pub snapshot_store: Option<SnapshotStoreConfig>,
+    /// Write book snapshots and deltas to Parquet files for research, locally or to S3. Off when not set.
+    pub archive: Option<ArchiveConfig>,
+    /// Serve the relay feed for edge instances on this port (same address as the websocket server).
+    pub relay_port: Option<u16>,
+    /// Follow the relay feed of an ingest instance at `host:port` instead of reading a node. `upstreams` are
+    /// unused then.
+    pub relay_upstream: Option<String>,
+    /// Maximum number of subscriptions per client.
+    pub max_subscriptions: Option<usize>,
+    pub connection_limits: ConnectionLimits,
+    /// Overrides the maximum log level, e.g. after a reload. Left to the logger when not set.
+    pub log_level: Option<LevelFilter>,
+    /// Add the block time, the time the server read the block's node events and the send time to every message
+    /// of stream data, as `latency: {nodeTime, ingestTime, sendTime}` in ms since the epoch.
+    pub include_latency_metadata: bool,
+    /// Serve the admin API (runtime settings, connected clients, maintenance mode) on this port
+    /// (same address as the websocket server, or its loopback address without `admin_auth`).
+    pub admin_port: Option<u16>,
+    /// Credentials accepted by the admin API. Without them the API only listens on the loopback address, open to
+    /// anyone on the host.
+    pub admin_auth: Option<AuthConfig>,
+    /// File the per API key preferences set through the admin API are saved to, and read from at startup. They're
+    /// kept in memory only when not set.
+    pub preferences: Option<PathBuf>,
+    /// Where reloads on SIGHUP or through the admin API get their settings from. SIGHUP isn't handled when not set.
+    pub reload: Option<ReloadHook>,
+}
+
+impl ServerConfig {
+    #[must_use]
+    pub const fn new(address: SocketAddr) -> Self {
+        Self {
+            address,
+            dual_stack: false,
+            reuse_port: false,
+            unix_socket: None,
+            io_uring: false,
+            socket: SocketOptions::new(),
+            ignore_spot: true,
+            upstreams: Vec::new(),
+            markets: Vec::new(),
+            compression_level: 1,
+            shared_compression: false,
+            coalesce_connections: false,
+            deflate: DeflateConfig {
+                server_max_window_bits: None,
+                client_max_window_bits: None,
+                server_no_context_takeover: false,
+                client_no_context_takeover: false,
+            },
+            zstd_dictionary: None,
+            crossed_books: CrossedBookPolicy::Flag,
+            snapshot_strategy: SnapshotStrategy::Eager,
+            lazy_idle_timeout: Duration::from_mins(5),
+            audit: AuditConfig::new(),
+            inactivity_exit_secs: 5,
+            inactivity_policy: InactivityPolicy::Exit,
+            tls: None,
+            webtransport_port: None,
+            proxy: ProxyConfig { protocol: false, trusted: Vec::new() },
+            cors: None,
+            auth: None,
+            metrics_port: None,
+            health_port: None,
+            grpc_port: None,
+            drain_timeout: Duration::from_secs(10),
+            shutdown_triggers: Vec::new(),
+            standby: false,
+            promotion_triggers: Vec::new(),
+            election: None,
+            backpressure: BackpressurePolicy::Disconnect,
+            send_queue_capacity: 256,
+            message_ttls: BTreeMap::new(),
+            load_shedding: None,
+            watchdog: None,
+            broadcast_shards: 8,
+            rate_limits: RateLimits {
+                client_messages_per_sec: None,
+                outbound_messages_per_sec: None,
+                connections_per_ip_per_min: None,
+                outbound_bytes_per_min: None,
+                bandwidth_policy: BandwidthPolicy::Throttle,
+            },
+            keepalive: KeepaliveConfig {
+                ping_interval: None,
+                max_missed_pongs: 3,
+                heartbeat_interval: None,
+                time_interval: None,
+            },
+            journal: None,
+            resume_window: None,
+            session_grace: None,
+            candles: None,
+            analytics: None,
+            signing: None,
+            publisher: None,
+            webhooks: None,
+            snapshot_store: None,
+            archive: None,
+            relay_port: None,
+            relay_upstream: None,
+            max_subscriptions: None,
+            connection_limits: ConnectionLimits {
+                max_connections: None,
+                max_connections_per_ip: None,
+                max_message_bytes: None,
+            },
+            log_level: None,
+            include_latency_metadata: false,
+            admin_port: None,
+            admin_auth: None,
+            preferences: None,
+            reload: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Checks the settings that would otherwise only fail once the server is running.
+    pub fn validate(&self) -> Result<()> {
+        RuntimeSettings::new(self).validate()?;
+        if self.dual_stack && self.address.is_ipv4() {
+            return Err("dual stack needs an IPv6 address".into());
+        }
+        let ports = [
+            Some(self.address.port()),
+            self.metrics_port,
+            self.grpc_port,
+            self.health_port,
+            self.admin_port,
+            self.relay_port,
+        ];
+        let ports = ports.iter().flatten().filter(|port| **port != 0).collect::<Vec<_>>();
+        if ports.iter().enumerate().any(|(i, port)| ports[..i].contains(port)) {
+            return Err("the websocket, metrics, gRPC, health, admin and relay ports have to differ".into());
+        }
+        self.validate_transports()?;
+        self.socket.validate()?;
+        if self.send_queue_capacity == 0 {
+            return Err("send queue capacity has to be at least 1".into());
+        }
+        send_queue::validate_ttls(&self.message_ttls)?;
+        if let Some(load_shedding) = self.load_shedding {
+            load_shedding.validate()?;
+        }
+        self.watchdog.as_ref().map(WatchdogConfig::validate).transpose()?;
+        self.deflate.validate()?;
+        self.cors.as_ref().map(CorsConfig::validate).transpose()?;
+        if self.shared_compression && self.deflate.server_max_window_bits.is_some() {
+            return Err("shared compression compresses without context takeover, so with the full window".into());
+        }
+        self.validate_standby()?;
+        if self.coalesce_connections && self.auth.is_none() {
+            return Err("coalescing connections needs auth to tell their API keys apart".into());
+        }
+        if self.broadcast_shards == 0 {
+            return Err("there has to be at least 1 broadcast shard".into());
+        }
+        let KeepaliveConfig { ping_interval, heartbeat_interval, time_interval, .. } = self.keepalive;
+        if [ping_interval, heartbeat_interval, time_interval].contains(&Some(Duration::ZERO)) {
+            return Err("ping, heartbeat and time intervals have to be at least a second".into());
+        }
+        if [self.resume_window, self.session_grace].contains(&Some(Duration::ZERO)) {
+            return Err("the resume window and the session grace period have to be at least a second".into());
+        }
+        if self.journal.as_ref().is_some_and(|journal| journal.max_bytes == 0) {
+            return Err("journal size has to be at least 1 MB".into());
+        }
+        if self.snapshot_store.as_ref().is_some_and(|store| store.interval.is_zero()) {
+            return Err("the snapshot interval has to be at least a second".into());
+        }
+        if self.audit.interval.is_zero() {
+            return Err("the audit interval has to be at least a second".into());
+        }
+        if self.lazy_idle_timeout.is_zero() {
+            return Err("the idle timeout of lazy markets has to be at least a second".into());
+        }
+        if self.archive.as_ref().is_some_and(|archive| archive.snapshot_interval.is_zero()) {
+            return Err("the archive snapshot interval has to be at least a second".into());
+        }
+        if let InactivityPolicy::Reconnect { deadline } = self.inactivity_policy
+            && deadline.as_secs() <= self.inactivity_exit_secs
+        {
+            return Err("the inactivity deadline has to be longer than the inactivity timeout".into());
+        }
+        if self.relay_upstream.is_some() && self.snapshot_store.is_some() {
+            return Err("an edge following a relay feed gets its state from it, not from a snapshot store".into());
+        }
+        for (i, market) in self.markets.iter().enumerate() {
+            if market.name.is_empty() || market.name.contains(':') || market.name == PRIMARY_MARKET {
+                return Err(format!("invalid market name {:?}", market.name).into());
+            }
+            if self.markets[..i].iter().any(|other| other.name == market.name) {
+                return Err(format!("market {} is configured twice", market.name).into());
+            }
+            if market.upstreams.is_empty() {
+                return Err(format!("market {} needs at least one node", market.name).into());
+            }
+        }
+        if let Some(auth) = &self.auth {
+            tenants::validate(&auth.tenants, &self.markets)?;
+        }
+        if self.relay_upstream.is_some() && !self.markets.is_empty() {
+            return Err("an edge following a relay feed serves the markets of its ingest instance only".into());
+        }
+        if self.candles.as_ref().is_some_and(|candles| candles.intervals.is_empty() || candles.history == 0) {
+            return Err("candles need at least one interval and a history of at least 1".into());
+        }
+        if let Some(analytics) = &self.analytics
+            && (analytics.interval.is_zero()
+                || analytics.vwap_window.is_zero()
+                || !(1..=100).contains(&analytics.depth))
+        {
+            return Err("analytics need an interval and a VWAP window, and a depth from 1 to 100".into());
+        }
+        if self.signing.as_ref().is_some_and(|signing| signing.checkpoint_interval.is_zero()) {
+            return Err("signing needs a checkpoint interval".into());
+        }
+        Ok(())
+    }
+
+    // the admin API is only served to other hosts when it checks credentials
+    pub(crate) fn admin_address(&self) -> Option<SocketAddr> {
+        let ip = match (&self.admin_auth, self.address.ip()) {
+            (Some(_), ip) => ip,
+            (None, IpAddr::V4(_)) => Ipv4Addr::LOCALHOST.into(),
+            (None, IpAddr::V6(_)) => Ipv6Addr::LOCALHOST.into(),
+        };
+        self.admin_port.map(|port| SocketAddr::new(ip, port))
+    }
+
+    // something has to promote a standby
+    fn validate_standby(&self) -> Result<()> {
+        self.election.as_ref().map(ElectionConfig::validate).transpose()?;
+        if self.standby && self.admin_port.is_none() && self.promotion_triggers.is_empty() && self.election.is_none() {
+            return Err("a standby server needs the admin API or a promotion trigger to be promoted".into());
+        }
+        Ok(())
+    }
+
+    // the listeners that need a feature of the build
+    fn validate_transports(&self) -> Result<()> {
+        if self.unix_socket.is_some() && !cfg!(unix) {
+            return Err("Unix domain sockets are only served on Unix".into());
+        }
+        if self.reuse_port && !cfg!(unix) {
+            return Err("reusing the port needs SO_REUSEPORT, which only Unix has".into());
+        }
+        if self.webtransport_port.is_some() {
+            if !cfg!(feature = "webtransport") {
+                return Err("this server is built without the webtransport feature".into());
+            }
+            if self.tls.is_none() {
+                return Err("WebTransport needs a TLS certificate".into());
+            }
+        }
+        if self.io_uring {
+            if !cfg!(all(feature = "io-uring", target_os = "linux")) {
+                return Err("this server is built without the io-uring feature".into());
+            }
+            if self.tls.is_some() || self.proxy.protocol {
+                return Err("io_uring serves plain connections only, without TLS or the PROXY protocol".into());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let mut config = ServerConfig::new("0.0.0.0:8000".parse().unwrap());
+        assert!(config.validate().is_ok());
+        config.metrics_port = Some(8000);
+        assert!(config.validate().is_err());
+        config.metrics_port = Some(9000);
+        config.dual_stack = true;
+        assert!(config.validate().is_err());
+        config.address = "[::]:8000".parse().unwrap();
+        assert!(config.validate().is_ok());
+        config.rate_limits.client_messages_per_sec = Some(0);
+        assert!(config.validate().is_err());
+        config.rate_limits.client_messages_per_sec = None;
+        config.max_subscriptions = Some(0);
+        assert!(config.validate().is_err());
+        config.max_subscriptions = None;
+        config.connection_limits.max_message_bytes = Some(2 << 20);
+        assert!(config.validate().is_err());
+        config.connection_limits.max_message_bytes = None;
+        config.lazy_idle_timeout = Duration::ZERO;
+        assert!(config.validate().is_err());
+        config.lazy_idle_timeout = Duration::from_mins(1);
+        config.message_ttls = BTreeMap::from([("bbo".to_string(), Duration::from_millis(500))]);
+        assert!(config.validate().is_ok());
+        config.message_ttls.insert("status".to_string(), Duration::from_secs(1));
+        assert!(config.validate().is_err());
+        config.message_ttls = BTreeMap::from([("bbo".to_string(), Duration::ZERO)]);
+        assert!(config.validate().is_err());
+        config.message_ttls.clear();
+        // nothing could promote it
+        config.standby = true;
+        assert!(config.validate().is_err());
+        config.admin_port = Some(9100);
+        assert!(config.validate().is_ok());
+        config.standby = false;
+        config.admin_port = None;
+        let mut election = ElectionConfig::new("http://etcd:2379".to_string(), "a".to_string());
+        election.ttl = Duration::from_secs(1);
+        config.election = Some(election);
+        assert!(config.validate().is_err());
+        config.election = None;
+        let market = |name: &str| MarketConfig {
+            name: name.to_string(),
+            upstreams: vec![UpstreamNode::new("/tmp".into())],
+            crossed_books: None,
+            snapshot_strategy: None,
+        };
+        config.markets = vec![market("testnet")];
+        assert!(config.validate().is_ok());
+        config.markets.push(market("testnet"));
+        assert!(config.validate().is_err());
+        config.markets = vec![market("test:net")];
+        assert!(config.validate().is_err());
+        config.markets = vec![market("primary")];
+        assert!(config.validate().is_err());
+        config.markets = Vec::new();
+        config.webtransport_port = Some(8000);
+        assert!(config.validate().is_err());
+        config.tls = Some(TlsConfig::new("cert.pem".into(), "key.pem".into()));
+        assert_eq!(config.validate().is_ok(), cfg!(feature = "webtransport"));
+        config.webtransport_port = None;
+        config.io_uring = true;
+        assert!(config.validate().is_err());
+        config.tls = None;
+        assert_eq!(config.validate().is_ok(), cfg!(all(feature = "io-uring", target_os = "linux")));
+    }
+
+    #[test]
+    fn test_admin_address() {
+        let mut config = ServerConfig::new("0.0.0.0:8000".parse().unwrap());
+        assert_eq!(config.admin_address(), None);
+        config.admin_port = Some(9100);
+        assert_eq!(config.admin_address(), Some("127.0.0.1:9100".parse().unwrap()));
+        config.address = "[::]:8000".parse().unwrap();
+        assert_eq!(config.admin_address(), Some("[::1]:9100".parse().unwrap()));
+        config.admin_auth = Some(AuthConfig::new(Vec::new()));
+        assert_eq!(config.admin_address(), Some("[::]:9100".parse().unwrap()));
+    }
+}
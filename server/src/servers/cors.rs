@@ -0,0 +1,145 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    extract::{Request, State},
+    http::{
+        HeaderName, HeaderValue, Method, StatusCode,
+        header::{
+            ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+            ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY,
+        },
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tracing::info;
+
+use crate::prelude::*;
+
+// every route of the websocket port is read only
+const ALLOWED_METHODS: &str = "GET, OPTIONS";
+
+/// Lets pages of other sites use the REST and SSE endpoints and open websockets from the browser.
+///
+/// Requests whose `Origin` isn't allowed are refused with 403, websocket upgrades included: browsers don't apply
+/// CORS to those, so the server checks them itself. Clients outside browsers send no `Origin` and aren't affected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorsConfig {
+    /// Origins as browsers send them, e.g. `https://dashboard.example.com`, or `*` for any.
+    pub allowed_origins: Vec<String>,
+    /// Request headers pages may send besides those browsers always allow, by default the credentials this server
+    /// reads (`authorization` and `x-api-key`).
+    pub allowed_headers: Vec<String>,
+    /// How long browsers may cache the answer to a preflight request.
+    pub max_age: Option<Duration>,
+}
+
+impl CorsConfig {
+    #[must_use]
+    pub fn new(allowed_origins: Vec<String>) -> Self {
+        Self {
+            allowed_origins,
+            allowed_headers: vec!["authorization".to_string(), "x-api-key".to_string()],
+            max_age: Some(Duration::from_mins(10)),
+        }
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.allowed_origins.is_empty() {
+            return Err("CORS needs at least one allowed origin".into());
+        }
+        // an origin is a scheme and a host, without a path
+        let valid = |origin: &str| {
+            origin == "*"
+                || origin == "null"
+                || origin
+                    .split_once("://")
+                    .is_some_and(|(scheme, host)| !scheme.is_empty() && !host.is_empty() && !host.contains('/'))
+        };
+        if let Some(origin) = self.allowed_origins.iter().find(|origin| !valid(origin)) {
+            return Err(format!("invalid CORS origin {origin} (expected e.g. https://example.com)").into());
+        }
+        if let Some(header) = self.allowed_headers.iter().find(|header| HeaderName::try_from(header.as_str()).is_err())
+        {
+            return Err(format!("invalid CORS header {header}").into());
+        }
+        Ok(())
+    }
+
+    fn allows(&self, origin: &str) -> bool {
+        self.allowed_origins.iter().any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(origin))
+    }
+}
+
+// axum middleware: `middleware::from_fn_with_state(config, cors)`
+pub(crate) async fn cors(State(config): State<Arc<CorsConfig>>, request: Request, next: Next) -> Response {
+    let Some(origin) = request.headers().get(ORIGIN).cloned() else {
+        return next.run(request).await;
+    };
+    if !origin.to_str().is_ok_and(|origin| config.allows(origin)) {
+        info!("Refusing request from origin {}", String::from_utf8_lossy(origin.as_bytes()));
+        return (StatusCode::FORBIDDEN, "Origin not allowed").into_response();
+    }
+    let mut response =
+        if request.method() == Method::OPTIONS && request.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD) {
+            let mut response = StatusCode::NO_CONTENT.into_response();
+            let headers = response.headers_mut();
+            headers.insert(ACCESS_CONTROL_ALLOW_METHODS, HeaderValue::from_static(ALLOWED_METHODS));
+            if let Ok(allowed) = HeaderValue::try_from(config.allowed_headers.join(", ")) {
+                headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, allowed);
+            }
+            if let Some(max_age) = config.max_age {
+                headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from(max_age.as_secs()));
+            }
+            response
+        } else {
+            next.run(request).await
+        };
+    // the origin itself rather than `*`, so that caches keep the answers to different origins apart
+    let headers = response.headers_mut();
+    headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    headers.append(VARY, HeaderValue::from_static("origin"));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{Router, middleware::from_fn_with_state, routing::get};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_cors() -> Result<()> {
+        let config = CorsConfig::new(vec!["https://dashboard.example.com".to_string()]);
+        config.validate()?;
+        assert!(CorsConfig::new(vec!["https://example.com/path".to_string()]).validate().is_err());
+        let app = Router::new().route("/", get(async || "ok")).layer(from_fn_with_state(Arc::new(config), cors));
+        let tcp_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/", tcp_listener.local_addr()?);
+        tokio::spawn(async move { axum::serve(tcp_listener, app).await });
+        let client = reqwest::Client::new();
+
+        // not from a browser
+        let res = client.get(&url).send().await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(!res.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+
+        let res = client.get(&url).header(ORIGIN, "https://dashboard.example.com").send().await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[ACCESS_CONTROL_ALLOW_ORIGIN], "https://dashboard.example.com");
+        let preflight = client
+            .request(Method::OPTIONS, &url)
+            .header(ORIGIN, "https://dashboard.example.com")
+            .header(ACCESS_CONTROL_REQUEST_METHOD, "GET")
+            .send()
+            .await?;
+        assert_eq!(preflight.status(), StatusCode::NO_CONTENT);
+        assert_eq!(preflight.headers()[ACCESS_CONTROL_ALLOW_HEADERS], "authorization, x-api-key");
+        assert_eq!(preflight.headers()[ACCESS_CONTROL_MAX_AGE], "600");
+
+        let res = client.get(&url).header(ORIGIN, "https://evil.example.com").send().await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        Ok(())
+    }
+}
@@ -0,0 +1,162 @@
+use axum::{
+    extract::{Request, State},
+    http::{HeaderValue, header::SEC_WEBSOCKET_EXTENSIONS},
+    middleware::Next,
+    response::Response,
+};
+use yawc::{CompressionLevel, DeflateOptions, Options};
+
+use crate::prelude::*;
+
+// the windows zlib can compress raw deflate streams with
+const WINDOW_BITS: std::ops::RangeInclusive<u8> = 9..=15;
+
+/// The `permessage-deflate` parameters (RFC 7692) negotiated with websocket clients, besides the compression level.
+///
+/// The defaults keep a compression context of 15 bits for either side of every connection. The memory level of the
+/// compressor is zlib's default of 8.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeflateConfig {
+    /// Window the server compresses with, `9..=15` bits. A smaller window needs less memory per connection and
+    /// compresses worse. Only with context takeover.
+    pub server_max_window_bits: Option<u8>,
+    /// Window clients are asked to compress with, `9..=15` bits, if they offer to limit it.
+    pub client_max_window_bits: Option<u8>,
+    /// Compress every message on its own, so that no compression context is kept between messages.
+    pub server_no_context_takeover: bool,
+    /// Ask clients to compress every message on its own, so that the server keeps no decompression context.
+    pub client_no_context_takeover: bool,
+}
+
+impl DeflateConfig {
+    pub(crate) fn validate(self) -> Result<()> {
+        for (side, bits) in [("server", self.server_max_window_bits), ("client", self.client_max_window_bits)] {
+            if bits.is_some_and(|bits| !WINDOW_BITS.contains(&bits)) {
+                return Err(format!("the {side} window has to be between 9 and 15 bits").into());
+            }
+        }
+        if self.server_no_context_takeover && self.server_max_window_bits.is_some() {
+            return Err("the server window only applies with context takeover".into());
+        }
+        Ok(())
+    }
+
+    // shared compression needs every message compressed on its own
+    pub(crate) const fn with_shared_compression(self, shared: bool) -> Self {
+        Self { server_no_context_takeover: self.server_no_context_takeover || shared, ..self }
+    }
+
+    pub(crate) fn options(self, level: u32) -> Options {
+        Options {
+            compression: Some(DeflateOptions {
+                level: CompressionLevel::new(level),
+                server_max_window_bits: self.server_max_window_bits,
+                client_max_window_bits: self.client_max_window_bits,
+                server_no_context_takeover: self.server_no_context_takeover,
+                client_no_context_takeover: self.client_no_context_takeover,
+            }),
+            ..Options::default()
+        }
+    }
+
+    // the client's first `permessage-deflate` offer as the server accepts it, `None` to decline compression. yawc
+    // settles on the smaller window of the offer and the server's, and takes a window without a value as 0, so
+    // only windows it can use are left in the offer
+    pub(crate) fn accept_offer(self, extensions: &str) -> Option<String> {
+        let offer = extensions
+            .split(',')
+            .find(|offer| offer.split(';').next().is_some_and(|name| name.trim() == "permessage-deflate"))?;
+        let mut accepted = vec!["permessage-deflate".to_string()];
+        for param in offer.split(';').skip(1) {
+            let (name, value) = param.split_once('=').map_or_else(
+                || (param.trim(), None),
+                |(name, value)| (name.trim(), Some(value.trim().trim_matches('"'))),
+            );
+            match (name, value.map(str::parse::<u8>)) {
+                // without its context the server compresses with the full window
+                ("server_no_context_takeover", None) if self.server_max_window_bits.is_some() => return None,
+                ("server_no_context_takeover" | "client_no_context_takeover", None) => accepted.push(name.to_string()),
+                ("server_max_window_bits", Some(Ok(15))) => {}
+                // a client limiting the server's window needs it to compress with that window
+                ("server_max_window_bits", Some(Ok(bits))) => {
+                    if !WINDOW_BITS.contains(&bits) || self.server_no_context_takeover {
+                        return None;
+                    }
+                    accepted.push(format!("server_max_window_bits={bits}"));
+                }
+                // a client able to limit its window is asked to if one is configured
+                ("client_max_window_bits", None | Some(Ok(_))) => {
+                    let offered = value.and_then(|value| value.parse().ok()).unwrap_or(15);
+                    if let Some(bits) = self.client_max_window_bits.map(|bits| bits.min(offered))
+                        && WINDOW_BITS.contains(&bits)
+                    {
+                        accepted.push(format!("client_max_window_bits={bits}"));
+                    }
+                }
+                _ => return None,
+            }
+        }
+        Some(accepted.join("; "))
+    }
+}
+
+// axum middleware: `middleware::from_fn_with_state(config, accept_deflate)`, ahead of the websocket upgrade
+pub(crate) async fn accept_deflate(State(config): State<DeflateConfig>, mut request: Request, next: Next) -> Response {
+    let headers = request.headers_mut();
+    if let Some(offer) = headers.get(SEC_WEBSOCKET_EXTENSIONS).and_then(|offer| offer.to_str().ok()) {
+        match config.accept_offer(offer).and_then(|accepted| HeaderValue::from_str(&accepted).ok()) {
+            Some(accepted) => headers.insert(SEC_WEBSOCKET_EXTENSIONS, accepted),
+            None => headers.remove(SEC_WEBSOCKET_EXTENSIONS),
+        };
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accept_offer() {
+        let config = DeflateConfig::default();
+        let browser = "permessage-deflate; client_max_window_bits";
+        assert_eq!(config.accept_offer(browser).as_deref(), Some("permessage-deflate"));
+        let limited = DeflateConfig { client_max_window_bits: Some(10), ..config };
+        assert_eq!(limited.accept_offer(browser).as_deref(), Some("permessage-deflate; client_max_window_bits=10"));
+        let offer = "x-webkit-deflate-frame, permessage-deflate; server_no_context_takeover; client_max_window_bits=9";
+        assert_eq!(
+            limited.accept_offer(offer).as_deref(),
+            Some("permessage-deflate; server_no_context_takeover; client_max_window_bits=9")
+        );
+
+        // the server can only compress with a smaller window if it keeps its context
+        let small = "permessage-deflate; server_max_window_bits=10";
+        assert_eq!(config.accept_offer(small).as_deref(), Some(small));
+        let small_window = DeflateConfig { server_max_window_bits: Some(12), ..config };
+        assert_eq!(small_window.accept_offer("permessage-deflate; server_no_context_takeover"), None);
+        let no_takeover = DeflateConfig { server_no_context_takeover: true, ..config };
+        assert_eq!(no_takeover.accept_offer(small), None);
+        assert_eq!(
+            no_takeover.accept_offer("permessage-deflate; server_max_window_bits=15").as_deref(),
+            Some("permessage-deflate")
+        );
+
+        for declined in
+            ["permessage-deflate; server_max_window_bits=8", "permessage-deflate; unknown", "x-webkit-deflate-frame"]
+        {
+            assert_eq!(config.accept_offer(declined), None, "{declined}");
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(DeflateConfig::default().validate().is_ok());
+        assert!(DeflateConfig { server_max_window_bits: Some(8), ..DeflateConfig::default() }.validate().is_err());
+        let config = DeflateConfig {
+            server_max_window_bits: Some(10),
+            server_no_context_takeover: true,
+            ..DeflateConfig::default()
+        };
+        assert!(config.validate().is_err());
+    }
+}
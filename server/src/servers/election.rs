@@ -0,0 +1,390 @@
+use std::{sync::Arc, time::Duration};
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+use reqwest::Client;
+use serde::{Deserialize, de::DeserializeOwned};
+use serde_json::{Value, json};
+use tokio::{
+    select,
+    sync::Mutex,
+    task::JoinHandle,
+    time::{Instant, MissedTickBehavior, interval},
+};
+use tracing::{info, warn};
+use yawc::close::CloseCode;
+
+use crate::{
+    listeners::order_book::OrderBookListener,
+    prelude::*,
+    servers::{health::not_ready, shutdown::Shutdown, standby::Standby},
+};
+
+/// Active-passive coordination of replicas through a lease in etcd: the replica holding the key serves clients,
+/// the others stay on standby.
+///
+/// Every replica starts on standby and claims the key once it is free and its own feed is live. The lease of a
+/// replica that crashes expires after `ttl`, and one whose feed stays down for `ttl` revokes it and shuts down, so
+/// that a standby takes over either way. etcd is reached through its JSON gateway.
+#[derive(Debug, Clone)]
+pub struct ElectionConfig {
+    /// e.g. `http://etcd:2379`
+    pub endpoint: String,
+    /// The same for all replicas of a deployment.
+    pub key: String,
+    /// Of this replica, stored under the key while it leads.
+    pub name: String,
+    /// How long a leader that stopped renewing its lease keeps it. Renewed every third of it, and a leader that
+    /// couldn't renew it for two thirds of it steps down.
+    pub ttl: Duration,
+}
+
+impl ElectionConfig {
+    #[must_use]
+    pub fn new(endpoint: String, name: String) -> Self {
+        Self { endpoint, key: "/orderbook/leader".to_string(), name, ttl: Duration::from_secs(10) }
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.ttl < Duration::from_secs(3) {
+            return Err("the election's lease has to last at least 3 seconds".into());
+        }
+        if self.key.is_empty() || self.name.is_empty() {
+            return Err("the election needs a key and a name".into());
+        }
+        Ok(())
+    }
+}
+
+// campaigns until shutdown, then gives up the leadership for a standby to take over right away
+pub(crate) fn spawn_election(
+    config: ElectionConfig,
+    listener: Arc<Mutex<OrderBookListener>>,
+    standby: Standby,
+    shutdown: Shutdown,
+) -> Result<JoinHandle<()>> {
+    let mut election = Election::new(config, standby)?;
+    let mut ticker = interval(election.config.ttl / 3);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    Ok(tokio::spawn(async move {
+        loop {
+            select! {
+                _ = ticker.tick() => {
+                    let live = not_ready(listener.lock().await.last_status().as_ref(), false).is_empty();
+                    if let Some(reason) = election.round(live).await {
+                        shutdown.trigger(CloseCode::Restart, &reason);
+                    }
+                }
+                () = shutdown.cancelled() => {
+                    election.resign().await;
+                    return;
+                }
+            }
+        }
+    }))
+}
+
+struct Election {
+    config: ElectionConfig,
+    etcd: Etcd,
+    standby: Standby,
+    // while leading
+    lease: Option<String>,
+    renewed: Instant,
+    // since when the feed isn't live
+    down_since: Option<Instant>,
+}
+
+impl Election {
+    fn new(config: ElectionConfig, standby: Standby) -> Result<Self> {
+        let etcd = Etcd::new(&config.endpoint, config.ttl / 3)?;
+        Ok(Self { config, etcd, standby, lease: None, renewed: Instant::now(), down_since: None })
+    }
+
+    // a follower whose feed is live claims the key once it is free, a leader renews its lease. Returns why the
+    // leader steps down, if it does
+    async fn round(&mut self, live: bool) -> Option<String> {
+        let now = Instant::now();
+        if live {
+            self.down_since = None;
+        } else {
+            self.down_since.get_or_insert(now);
+        }
+        let Some(lease) = self.lease.clone() else {
+            if live && let Err(err) = self.campaign().await {
+                warn!("Unable to campaign for the leadership: {err}");
+            }
+            return None;
+        };
+        if self.down_since.is_some_and(|since| now - since >= self.config.ttl) {
+            self.resign().await;
+            return Some("feed down, leadership handed over".to_string());
+        }
+        // a renewal sent now, which can take a third of the ttl, could land once the lease expired and another
+        // replica leads
+        if now - self.renewed >= self.config.ttl * 2 / 3 {
+            self.lease = None;
+            return Some("leadership lost, etcd unreachable".to_string());
+        }
+        match self.etcd.keep_alive(&lease).await {
+            Ok(true) => self.renewed = now,
+            Ok(false) => {
+                self.lease = None;
+                return Some("leadership lost".to_string());
+            }
+            Err(err) => warn!("Unable to renew the leadership: {err}"),
+        }
+        None
+    }
+
+    async fn campaign(&mut self) -> Result<()> {
+        if self.etcd.holder(&self.config.key).await?.is_some() {
+            return Ok(());
+        }
+        // the lease runs from when it was asked for
+        let granted = Instant::now();
+        let lease = self.etcd.grant(self.config.ttl).await?;
+        if !self.etcd.claim(&self.config.key, &self.config.name, &lease).await? {
+            return self.etcd.revoke(&lease).await;
+        }
+        info!("Elected leader as {} through etcd", self.config.name);
+        self.lease = Some(lease);
+        self.renewed = granted;
+        self.standby.promote("elected leader through etcd");
+        Ok(())
+    }
+
+    async fn resign(&mut self) {
+        let Some(lease) = self.lease.take() else {
+            return;
+        };
+        match self.etcd.revoke(&lease).await {
+            Ok(()) => info!("Gave up the leadership"),
+            // it expires after the ttl then
+            Err(err) => warn!("Unable to give up the leadership: {err}"),
+        }
+    }
+}
+
+// the calls of etcd's v3 JSON gateway the election needs; keys and values are base64, ids strings
+struct Etcd {
+    client: Client,
+    endpoint: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct LeaseResponse {
+    #[serde(rename = "ID")]
+    id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct KeepAliveResponse {
+    result: KeepAliveResult,
+}
+
+// without a TTL once the lease expired
+#[derive(Debug, Deserialize)]
+struct KeepAliveResult {
+    #[serde(rename = "TTL", default)]
+    ttl: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RangeResponse {
+    #[serde(default)]
+    kvs: Vec<KeyValue>,
+}
+
+#[derive(Debug, Deserialize)]
+struct KeyValue {
+    #[serde(default)]
+    value: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TxnResponse {
+    #[serde(default)]
+    succeeded: bool,
+}
+
+impl Etcd {
+    fn new(endpoint: &str, timeout: Duration) -> Result<Self> {
+        let client = Client::builder().timeout(timeout).build()?;
+        Ok(Self { client, endpoint: endpoint.trim_end_matches('/').to_string() })
+    }
+
+    async fn call<T: DeserializeOwned>(&self, path: &str, body: Value) -> Result<T> {
+        let url = format!("{}/v3/{path}", self.endpoint);
+        let res = self.client.post(url).json(&body).send().await?.error_for_status()?;
+        Ok(res.json().await?)
+    }
+
+    async fn grant(&self, ttl: Duration) -> Result<String> {
+        let lease: LeaseResponse = self.call("lease/grant", json!({ "TTL": ttl.as_secs() })).await?;
+        Ok(lease.id)
+    }
+
+    // false if the lease expired
+    async fn keep_alive(&self, lease: &str) -> Result<bool> {
+        let res: KeepAliveResponse = self.call("lease/keepalive", json!({ "ID": lease })).await?;
+        Ok(res.result.ttl.and_then(|ttl| ttl.parse::<u64>().ok()).is_some_and(|ttl| ttl > 0))
+    }
+
+    async fn revoke(&self, lease: &str) -> Result<()> {
+        self.call::<Value>("lease/revoke", json!({ "ID": lease })).await?;
+        Ok(())
+    }
+
+    // the name of the leader, if there is one
+    async fn holder(&self, key: &str) -> Result<Option<String>> {
+        let res: RangeResponse = self.call("kv/range", json!({ "key": BASE64_STANDARD.encode(key) })).await?;
+        let Some(kv) = res.kvs.into_iter().next() else {
+            return Ok(None);
+        };
+        Ok(Some(String::from_utf8(BASE64_STANDARD.decode(kv.value)?)?))
+    }
+
+    // puts the name under the key with the lease, unless the key exists
+    async fn claim(&self, key: &str, name: &str, lease: &str) -> Result<bool> {
+        let key = BASE64_STANDARD.encode(key);
+        let txn = json!({
+            "compare": [{ "key": key, "target": "CREATE", "result": "EQUAL", "create_revision": "0" }],
+            "success": [{ "request_put": { "key": key, "value": BASE64_STANDARD.encode(name), "lease": lease } }],
+        });
+        let res: TxnResponse = self.call("kv/txn", txn).await?;
+        Ok(res.succeeded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use axum::{Json, Router, extract::State, routing::post};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    // the key with its value and lease, and the leases with whether they are alive
+    #[derive(Default)]
+    struct FakeEtcd {
+        key: Option<(String, String)>,
+        leases: HashMap<String, bool>,
+    }
+
+    type Fake = Arc<std::sync::Mutex<FakeEtcd>>;
+
+    impl FakeEtcd {
+        fn grant(&mut self) -> Value {
+            let id = (self.leases.len() + 1).to_string();
+            self.leases.insert(id.clone(), true);
+            json!({ "ID": id, "TTL": "10" })
+        }
+
+        fn keep_alive(&self, body: &Value) -> Value {
+            let alive = body["ID"].as_str().is_some_and(|id| self.leases.get(id) == Some(&true));
+            if alive { json!({ "result": { "TTL": "10" } }) } else { json!({ "result": {} }) }
+        }
+
+        fn revoke(&mut self, body: &Value) -> Value {
+            let lease = body["ID"].as_str().unwrap_or_default().to_string();
+            if self.key.as_ref().is_some_and(|(_, held)| *held == lease) {
+                self.key = None;
+            }
+            self.leases.insert(lease, false);
+            json!({})
+        }
+
+        fn range(&self) -> Value {
+            self.key.as_ref().map_or_else(|| json!({}), |(value, _)| json!({ "kvs": [{ "value": value }] }))
+        }
+
+        fn txn(&mut self, body: &Value) -> Value {
+            let put = &body["success"][0]["request_put"];
+            let succeeded = self.key.is_none();
+            if succeeded {
+                let (value, lease) = (put["value"].as_str(), put["lease"].as_str());
+                self.key = Some((value.unwrap_or_default().to_string(), lease.unwrap_or_default().to_string()));
+            }
+            json!({ "succeeded": succeeded })
+        }
+    }
+
+    async fn fake_etcd() -> Result<(String, Fake)> {
+        let fake = Fake::default();
+        let app = Router::new()
+            .route("/v3/lease/grant", post(async |State(fake): State<Fake>| Json(fake.lock().unwrap().grant())))
+            .route(
+                "/v3/lease/keepalive",
+                post(async |State(fake): State<Fake>, Json(body): Json<Value>| {
+                    Json(fake.lock().unwrap().keep_alive(&body))
+                }),
+            )
+            .route(
+                "/v3/lease/revoke",
+                post(async |State(fake): State<Fake>, Json(body): Json<Value>| {
+                    Json(fake.lock().unwrap().revoke(&body))
+                }),
+            )
+            .route("/v3/kv/range", post(async |State(fake): State<Fake>| Json(fake.lock().unwrap().range())))
+            .route(
+                "/v3/kv/txn",
+                post(async |State(fake): State<Fake>, Json(body): Json<Value>| Json(fake.lock().unwrap().txn(&body))),
+            )
+            .with_state(fake.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        Ok((format!("http://{address}/"), fake))
+    }
+
+    fn election(endpoint: &str, name: &str) -> Result<(Election, Standby)> {
+        let standby = Standby::new(true);
+        Ok((Election::new(ElectionConfig::new(endpoint.to_string(), name.to_string()), standby.clone())?, standby))
+    }
+
+    #[tokio::test]
+    async fn test_election() -> Result<()> {
+        let (endpoint, fake) = fake_etcd().await?;
+        let (mut a, a_standby) = election(&endpoint, "a")?;
+        let (mut b, b_standby) = election(&endpoint, "b")?;
+        // a replica without a live feed doesn't campaign
+        assert!(b.round(false).await.is_none());
+        assert!(a.round(true).await.is_none() && b.round(true).await.is_none());
+        assert!(!a_standby.is_on() && b_standby.is_on());
+        assert_eq!(a.etcd.holder(&a.config.key).await?.as_deref(), Some("a"));
+
+        // the leader's feed going down hands over once it stays down for the ttl
+        assert!(a.round(false).await.is_none());
+        a.down_since = a.down_since.and_then(|since| since.checked_sub(a.config.ttl));
+        assert_eq!(a.round(false).await.as_deref(), Some("feed down, leadership handed over"));
+        assert!(b.round(true).await.is_none());
+        assert!(!b_standby.is_on());
+
+        // as does a leader whose lease expired, e.g. after a pause
+        let lease = b.lease.clone().ok_or("b leads")?;
+        fake.lock().unwrap().leases.insert(lease, false);
+        assert_eq!(b.round(true).await.as_deref(), Some("leadership lost"));
+        assert!(b.lease.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_etcd_unreachable() -> Result<()> {
+        let (endpoint, _fake) = fake_etcd().await?;
+        let (mut a, _) = election(&endpoint, "a")?;
+        assert!(a.round(true).await.is_none());
+        let closed = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        a.etcd = Etcd::new(&format!("http://{closed}"), a.config.ttl / 3)?;
+
+        // a failed renewal is retried while the next one can still land within the lease
+        assert!(a.round(true).await.is_none());
+        assert!(a.lease.is_some());
+        a.renewed = a.renewed.checked_sub(a.config.ttl / 3).ok_or("too early")?;
+        assert!(a.round(true).await.is_none());
+        a.renewed = a.renewed.checked_sub(a.config.ttl / 3).ok_or("too early")?;
+        assert_eq!(a.round(true).await.as_deref(), Some("leadership lost, etcd unreachable"));
+        assert!(a.lease.is_none());
+        Ok(())
+    }
+}
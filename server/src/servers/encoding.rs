@@ -0,0 +1,394 @@
+use axum::{
+    Json,
+    http::{
+        HeaderMap, HeaderValue, StatusCode,
+        header::{ACCEPT, CONTENT_TYPE, SEC_WEBSOCKET_PROTOCOL},
+    },
+    response::{IntoResponse, Response},
+};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use serde_json::{Number, Value, json};
+use yawc::FrameView;
+
+use crate::{prelude::*, servers::protobuf};
+
+/// Wire format of messages sent to a client, negotiated once per connection through `Sec-WebSocket-Protocol`.
+/// Clients that do not ask for a subprotocol get JSON text frames.
+///
+/// `MessagePack` frames are binary and carry the same structure as the JSON messages (maps with the same field
+/// names and values, addresses and hashes included as hex strings), so generic `MessagePack` decoders can read them.
+/// Client requests are always JSON.
+///
+/// Protobuf frames are the `ServerMessage` of `proto/orderbook.proto`, or a `Batch` of them on connections that batch.
+/// Prices and sizes are always decimal strings in them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub(crate) enum Encoding {
+    #[default]
+    Json,
+    MessagePack,
+    Protobuf,
+}
+
+// media types of MessagePack in `Accept`, the registered one and the one most libraries still send
+const MSGPACK_MEDIA_TYPES: [&str; 2] = ["application/msgpack", "application/x-msgpack"];
+
+impl Encoding {
+    // the encoding of a REST response: MessagePack if the client lists it in `Accept` before JSON
+    pub(crate) fn accepted(headers: &HeaderMap) -> Self {
+        let accepted = headers
+            .get_all(ACCEPT)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|media_type| media_type.split(';').next())
+            .map(str::trim)
+            .find(|media_type| *media_type == "application/json" || MSGPACK_MEDIA_TYPES.contains(media_type));
+        match accepted {
+            Some(media_type) if MSGPACK_MEDIA_TYPES.contains(&media_type) => Self::MessagePack,
+            _ => Self::Json,
+        }
+    }
+
+    pub(crate) fn response<T: Serialize>(self, body: &T) -> Response {
+        match self {
+            // REST responses are never protobuf, see `accepted`
+            Self::Json | Self::Protobuf => Json(body).into_response(),
+            Self::MessagePack => match msgpack(body) {
+                Ok(payload) => ([(CONTENT_TYPE, MSGPACK_MEDIA_TYPES[0])], payload).into_response(),
+                Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+            },
+        }
+    }
+
+    pub(crate) fn encode<T: Serialize>(self, msg: &T) -> Result<FrameView> {
+        Ok(match self {
+            Self::Json => FrameView::text(serde_json::to_string(msg)?),
+            Self::MessagePack => FrameView::binary(msgpack(msg)?),
+            // the messages protobuf has a schema for are encoded by `protobuf::encode`
+            Self::Protobuf => FrameView::binary(protobuf::encode_json(serde_json::to_string(msg)?)),
+        })
+    }
+
+    // a frame of a payload serialized before
+    pub(crate) fn frame(self, payload: Bytes) -> FrameView {
+        match self {
+            Self::Json => FrameView::text(payload),
+            Self::MessagePack | Self::Protobuf => FrameView::binary(payload),
+        }
+    }
+}
+
+// as maps with the names of the fields, and with the values JSON has, such as the hex strings of addresses rather
+// than their bytes
+fn msgpack<T: Serialize + ?Sized>(msg: &T) -> Result<Vec<u8>> {
+    let mut payload = Vec::new();
+    msg.serialize(&mut rmp_serde::Serializer::new(&mut payload).with_struct_map().with_human_readable())?;
+    Ok(payload)
+}
+
+/// How the prices and sizes of the messages are written for a connection, chosen with `numbers` in the query string
+/// of its upgrade request. Decimal strings are sent as received from the node, integers are the digits of the decimal
+/// with the number of them past the point, `{"units": 1062965, "decimals": 1}` for `"106296.5"`, exact like the
+/// strings, and floats are the nearest `f64` to the decimal.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum Numbers {
+    #[default]
+    String,
+    Integer,
+    Float,
+}
+
+// the fields of the messages holding a price or an amount as a decimal string
+const DECIMAL_FIELDS: [&str; 23] = [
+    "px",
+    "sz",
+    "limitPx",
+    "triggerPx",
+    "origSz",
+    "newSz",
+    "mid",
+    "markPx",
+    "startPosition",
+    "closedPnl",
+    "fee",
+    "lotSize",
+    "tickSize",
+    "o",
+    "c",
+    "h",
+    "l",
+    "v",
+    "bid",
+    "ask",
+    "spread",
+    "vwap",
+    "volume",
+];
+
+impl Numbers {
+    // `msg` with its decimal strings replaced by numbers. Strings that aren't decimals are left as they are, and a
+    // decimal with more digits than an integer of 64 bits holds is an error rather than a string among integers
+    pub(crate) fn convert(self, msg: &mut Value) -> Result<()> {
+        match msg {
+            Value::Array(values) => values.iter_mut().try_for_each(|value| self.convert(value)),
+            Value::Object(fields) => fields.iter_mut().try_for_each(|(field, value)| match value {
+                Value::String(decimal) if DECIMAL_FIELDS.contains(&field.as_str()) => {
+                    if let Some(number) = self.number(decimal)? {
+                        *value = number;
+                    }
+                    Ok(())
+                }
+                _ => self.convert(value),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    fn number(self, decimal: &str) -> Result<Option<Value>> {
+        match self {
+            Self::String => Ok(None),
+            Self::Integer => {
+                let Some((units, decimals)) = integer(decimal) else {
+                    return Ok(None);
+                };
+                let units =
+                    units.parse::<i64>().map_err(|_| format!("{decimal} has too many digits for numbers=integer"))?;
+                Ok(Some(json!({ "units": units, "decimals": decimals })))
+            }
+            Self::Float => Ok(decimal.parse().ok().and_then(Number::from_f64).map(Value::Number)),
+        }
+    }
+}
+
+// the digits of a decimal, with its sign, and the number of them past the point, if it is a decimal
+fn integer(decimal: &str) -> Option<(String, usize)> {
+    let (sign, digits) = decimal.strip_prefix('-').map_or(("", decimal), |digits| ("-", digits));
+    let (whole, fraction) = match digits.split_once('.') {
+        Some((_, "")) => return None,
+        Some((whole, fraction)) => (whole, fraction),
+        None => (digits, ""),
+    };
+    if whole.is_empty() || !whole.bytes().chain(fraction.bytes()).all(|digit| digit.is_ascii_digit()) {
+        return None;
+    }
+    Some((format!("{sign}{whole}{fraction}"), fraction.len()))
+}
+
+/// Version of the message format, negotiated together with the encoding. Connections of every version are served
+/// side by side, so the format can change without breaking the clients of an older version.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub(crate) enum Version {
+    #[default]
+    V1,
+    // every error is `{"error": {"code": ..., "msg": ...}}`, none is sent on the `error` channel
+    V2,
+}
+
+/// How the frames of a connection are compressed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum Compression {
+    #[default]
+    None,
+    // `permessage-deflate`, by the connection
+    Deflate,
+    // `permessage-deflate`, once for all the connections that share compression
+    SharedDeflate,
+    // with the server's zstd dictionary
+    Zstd,
+}
+
+impl Compression {
+    // of a connection that negotiated `permessage-deflate` if `deflate`, and shares its compression if `shared`
+    pub(crate) const fn negotiated(subprotocol: Subprotocol, deflate: bool, shared: bool) -> Self {
+        match (subprotocol.zstd, deflate, shared) {
+            (true, ..) => Self::Zstd,
+            (false, true, true) => Self::SharedDeflate,
+            (false, true, false) => Self::Deflate,
+            (false, false, _) => Self::None,
+        }
+    }
+}
+
+/// What a connection negotiated when it was opened, as reported by its `stats` request. The subprotocol is the one
+/// that applies, `orderbook.json` for clients that didn't ask for one.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Negotiated {
+    pub(crate) subprotocol: String,
+    pub(crate) compression: Compression,
+    pub(crate) numbers: Numbers,
+    pub(crate) batch_ms: Option<u64>,
+}
+
+/// The version and encoding of a connection, as offered by the client in one subprotocol.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub(crate) struct Subprotocol {
+    pub(crate) version: Version,
+    pub(crate) encoding: Encoding,
+    // MessagePack frames compressed with the server's zstd dictionary, instead of `permessage-deflate`
+    pub(crate) zstd: bool,
+}
+
+impl Subprotocol {
+    const ALL: [(&str, Self); 8] = [
+        ("orderbook.json", Self::new(Version::V1, Encoding::Json)),
+        ("orderbook.msgpack", Self::new(Version::V1, Encoding::MessagePack)),
+        ("orderbook.msgpack.zstd", Self { zstd: true, ..Self::new(Version::V1, Encoding::MessagePack) }),
+        ("orderbook.proto", Self::new(Version::V1, Encoding::Protobuf)),
+        ("orderbook.v2", Self::new(Version::V2, Encoding::Json)),
+        ("orderbook.v2.msgpack", Self::new(Version::V2, Encoding::MessagePack)),
+        ("orderbook.v2.msgpack.zstd", Self { zstd: true, ..Self::new(Version::V2, Encoding::MessagePack) }),
+        ("orderbook.v2.proto", Self::new(Version::V2, Encoding::Protobuf)),
+    ];
+
+    pub(crate) const fn new(version: Version, encoding: Encoding) -> Self {
+        Self { version, encoding, zstd: false }
+    }
+
+    fn from_name(protocol: &str) -> Option<Self> {
+        let protocol = protocol.trim();
+        Self::ALL.into_iter().find_map(|(name, subprotocol)| (name == protocol).then_some(subprotocol))
+    }
+
+    #[must_use]
+    pub(crate) fn name(self) -> HeaderValue {
+        HeaderValue::from_static(self.as_str())
+    }
+
+    pub(crate) fn as_str(self) -> &'static str {
+        Self::ALL.into_iter().find_map(|(name, subprotocol)| (subprotocol == self).then_some(name)).unwrap_or_default()
+    }
+
+    // picks the first subprotocol offered by the client that we support.
+    // Returns None if the client did not offer any subprotocol, and an error if none of the offered ones are known.
+    pub(crate) fn negotiate(headers: &HeaderMap) -> Result<Option<Self>> {
+        let mut offered = headers
+            .get_all(SEC_WEBSOCKET_PROTOCOL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .peekable();
+        if offered.peek().is_none() {
+            return Ok(None);
+        }
+        let offered = offered.collect::<Vec<_>>();
+        offered
+            .iter()
+            .find_map(|protocol| Self::from_name(protocol))
+            .map(Some)
+            .ok_or_else(|| format!("Unsupported subprotocol(s): {}", offered.join(",")).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use yawc::OpCode;
+
+    use super::*;
+    use crate::types::subscription::ServerResponse;
+
+    const TRADES: &str = r#"{"channel":"trades","data":[{"coin":"BTC","side":"A","px":"106296.0","sz":"0.00017","time":1751430933565,"hash":"0xde93a8a0729ade63d8840417805ba9010b008818422ddedb1285744426b73503","tid":293353986402527,"users":["0xcc0a3b6e3267c84361e91d8230868eea53431e4b","0xc64cc00b46101bd40aa1c3121195e85c0b0918d8"]}]}"#;
+
+    fn headers(protocols: &[&'static str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for protocol in protocols {
+            headers.append(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(protocol));
+        }
+        headers
+    }
+
+    #[test]
+    fn test_negotiate() -> Result<()> {
+        let negotiate = |protocols| Subprotocol::negotiate(&headers(protocols));
+        let v1 = |encoding| Some(Subprotocol::new(Version::V1, encoding));
+        assert_eq!(negotiate(&[])?, None);
+        assert_eq!(negotiate(&["orderbook.json"])?, v1(Encoding::Json));
+        assert_eq!(negotiate(&["foo, orderbook.msgpack"])?, v1(Encoding::MessagePack));
+        assert_eq!(negotiate(&["foo", "orderbook.json"])?, v1(Encoding::Json));
+        assert!(negotiate(&["orderbook.xml"]).is_err());
+
+        let v2 = negotiate(&["orderbook.v3, orderbook.v2.msgpack, orderbook.msgpack"])?;
+        assert_eq!(v2, Some(Subprotocol::new(Version::V2, Encoding::MessagePack)));
+        assert_eq!(v2.map(Subprotocol::name), Some(HeaderValue::from_static("orderbook.v2.msgpack")));
+
+        let proto = negotiate(&["orderbook.v2.proto"])?;
+        assert_eq!(proto, Some(Subprotocol::new(Version::V2, Encoding::Protobuf)));
+        assert_eq!(proto.map(Subprotocol::name), Some(HeaderValue::from_static("orderbook.v2.proto")));
+
+        let zstd = negotiate(&["orderbook.msgpack.zstd, orderbook.msgpack"])?;
+        assert_eq!(zstd, Some(Subprotocol { zstd: true, ..Subprotocol::new(Version::V1, Encoding::MessagePack) }));
+        assert_eq!(zstd.map(Subprotocol::name), Some(HeaderValue::from_static("orderbook.msgpack.zstd")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_accepted() {
+        let accepted = |values: &[&'static str]| {
+            let mut headers = HeaderMap::new();
+            for value in values {
+                headers.append(ACCEPT, HeaderValue::from_static(value));
+            }
+            Encoding::accepted(&headers)
+        };
+        assert_eq!(accepted(&[]), Encoding::Json);
+        assert_eq!(accepted(&["*/*"]), Encoding::Json);
+        assert_eq!(accepted(&["application/msgpack"]), Encoding::MessagePack);
+        assert_eq!(accepted(&["text/html, application/x-msgpack;q=0.9"]), Encoding::MessagePack);
+        assert_eq!(accepted(&["application/json", "application/msgpack"]), Encoding::Json);
+    }
+
+    #[test]
+    fn test_numbers() -> Result<()> {
+        let convert = |numbers: Numbers, msg: &str| -> Result<String> {
+            let mut msg = serde_json::from_str(msg)?;
+            numbers.convert(&mut msg)?;
+            Ok(serde_json::to_string(&msg)?)
+        };
+        let book =
+            r#"{"channel":"l2Book","data":{"coin":"BTC","levels":[[{"px":"106296.5","sz":"0.00017","n":1}],[]]}}"#;
+        assert_eq!(convert(Numbers::String, book)?, book);
+        assert_eq!(
+            convert(Numbers::Integer, book)?,
+            r#"{"channel":"l2Book","data":{"coin":"BTC","levels":[[{"px":{"units":1062965,"decimals":1},"sz":{"units":17,"decimals":5},"n":1}],[]]}}"#
+        );
+        assert_eq!(
+            convert(Numbers::Float, book)?,
+            r#"{"channel":"l2Book","data":{"coin":"BTC","levels":[[{"px":106296.5,"sz":0.00017,"n":1}],[]]}}"#
+        );
+
+        // integers keep every digit, however many decimals there are; other strings, and decimals in other fields,
+        // are left alone
+        let candle = r#"{"s":"BTC","i":"1m","o":"-0.000000015","h":"12","c":"N/A","l":"1.","tid":"12"}"#;
+        assert_eq!(
+            convert(Numbers::Integer, candle)?,
+            r#"{"s":"BTC","i":"1m","o":{"units":-15,"decimals":9},"h":{"units":12,"decimals":0},"c":"N/A","l":"1.","tid":"12"}"#
+        );
+        assert_eq!(
+            convert(Numbers::Float, candle)?,
+            r#"{"s":"BTC","i":"1m","o":-1.5e-8,"h":12.0,"c":"N/A","l":1.0,"tid":"12"}"#
+        );
+
+        // rather than a string among the integers, a decimal they can't hold exactly is an error
+        let huge = r#"{"v":"99999999999999999999"}"#;
+        assert!(convert(Numbers::Integer, huge).is_err());
+        assert_eq!(convert(Numbers::Float, huge)?, r#"{"v":1e20}"#);
+        Ok(())
+    }
+
+    #[test]
+    fn test_msgpack_round_trip() -> Result<()> {
+        let msg: ServerResponse = serde_json::from_str(TRADES)?;
+        let frame = Encoding::MessagePack.encode(&msg)?;
+        assert_eq!(frame.opcode, OpCode::Binary);
+        assert!(frame.payload.len() < serde_json::to_string(&msg)?.len());
+        let decoded: ServerResponse = rmp_serde::from_slice(&frame.payload)?;
+        assert_eq!(serde_json::to_string(&decoded)?, serde_json::to_string(&msg)?);
+        // read by a generic decoder, it is the JSON message
+        let generic: Value = rmp_serde::from_slice(&frame.payload)?;
+        assert_eq!(generic, serde_json::to_value(&msg)?);
+        Ok(())
+    }
+}
@@ -0,0 +1,218 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use tokio::{
+    net::TcpListener,
+    select,
+    sync::{broadcast::error::RecvError, mpsc},
+};
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::{Request, Response, Status, transport::Server};
+use tracing::{error, info};
+
+use crate::{
+    listeners::order_book::{Broadcast, InternalMessage},
+    metrics::METRICS,
+    prelude::*,
+    servers::{
+        auth::{AuthError, Authenticator, ConnectionPermit},
+        markets::Markets,
+        rest::{BookRequestError, l2_subscription},
+        shutdown::Shutdown,
+        standby::Standby,
+        tenants::Tenant,
+        websocket_server::l2_book_from_snapshots,
+    },
+    types::{self, subscription::ServerResponse},
+};
+
+use crate::servers::protobuf::proto::{
+    BookRequest, L2Book,
+    order_book_server::{OrderBook, OrderBookServer},
+};
+
+// books buffered per stream before a slow client starts lagging behind the broadcast channel
+const STREAM_BUFFER: usize = 16;
+
+struct OrderBookService {
+    markets: Markets,
+    internal_message_tx: Broadcast,
+    auth: Option<Arc<Authenticator>>,
+    standby: Standby,
+    shutdown: Shutdown,
+}
+
+pub(crate) fn serve_grpc(
+    listener: TcpListener,
+    markets: Markets,
+    internal_message_tx: Broadcast,
+    auth: Option<Arc<Authenticator>>,
+    standby: Standby,
+    shutdown: Shutdown,
+) -> Result<()> {
+    let address: SocketAddr = listener.local_addr()?;
+    let service = OrderBookService { markets, internal_message_tx, auth, standby, shutdown: shutdown.clone() };
+    info!("gRPC server running at {address}");
+    tokio::spawn(async move {
+        let res = Server::builder()
+            .add_service(OrderBookServer::new(service))
+            .serve_with_incoming_shutdown(TcpListenerStream::new(listener), async move { shutdown.cancelled().await })
+            .await;
+        if let Err(err) = res {
+            error!("gRPC server error: {err}");
+        }
+    });
+    Ok(())
+}
+
+impl OrderBookService {
+    // of a coin the tenant's clients may use
+    async fn subscription(
+        &self,
+        request: BookRequest,
+        tenant: Option<&Tenant>,
+    ) -> std::result::Result<types::subscription::Subscription, Status> {
+        if !self.markets.allows(tenant, &request.coin) {
+            return Err(Status::permission_denied(format!("Market not available to this key: {}", request.coin)));
+        }
+        let depth = request.depth.map(|depth| depth as usize);
+        l2_subscription(self.markets.for_coin(&request.coin), request.coin, depth, request.n_sig_figs, request.mantissa)
+            .await
+            .map_err(|err| match err {
+                BookRequestError::NotReady => Status::unavailable(err.to_string()),
+                BookRequestError::UnknownMarket(_) => Status::not_found(err.to_string()),
+                BookRequestError::InvalidParams => Status::invalid_argument(err.to_string()),
+            })
+    }
+
+    async fn snapshot(&self, subscription: &types::subscription::Subscription) -> std::result::Result<L2Book, Status> {
+        match subscription.handle_immediate_snapshot(self.markets.for_subscription(subscription)).await {
+            Ok(Some(ServerResponse::L2Book(book))) => Ok(L2Book::from(&book)),
+            Ok(_) => Err(Status::internal("Unexpected snapshot")),
+            Err(err) => Err(Status::unavailable(format!("Unable to grab order book snapshot: {err}"))),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl OrderBook for OrderBookService {
+    async fn get_snapshot(&self, request: Request<BookRequest>) -> std::result::Result<Response<L2Book>, Status> {
+        let tenant = match &self.auth {
+            Some(auth) => {
+                let identity = auth.check(&request.metadata().clone().into_headers()).map_err(auth_status)?;
+                auth.tenant(&identity)
+            }
+            None => None,
+        };
+        let subscription = self.subscription(request.into_inner(), tenant.as_deref()).await?;
+        Ok(Response::new(self.snapshot(&subscription).await?))
+    }
+
+    type SubscribeOrderBookStream = ReceiverStream<std::result::Result<L2Book, Status>>;
+
+    async fn subscribe_order_book(
+        &self,
+        request: Request<BookRequest>,
+    ) -> std::result::Result<Response<Self::SubscribeOrderBookStream>, Status> {
+        if self.standby.is_on() {
+            return Err(Status::unavailable("Server on standby"));
+        }
+        // streams count against the connection limit of their key until they end
+        let permit = match &self.auth {
+            Some(auth) => {
+                let headers = request.metadata().clone().into_headers();
+                let credential = Authenticator::credential(&headers).ok_or(AuthError::Missing).map_err(auth_status)?;
+                Some(auth.admit(credential).map_err(auth_status)?)
+            }
+            None => None,
+        };
+        let tenant = permit.as_ref().and_then(ConnectionPermit::tenant).map(Arc::as_ref);
+        let subscription = self.subscription(request.into_inner(), tenant).await?;
+        // subscribe before taking the snapshot so that no book published in between is missed
+        let mut internal_message_rx = self.internal_message_tx.subscribe();
+        internal_message_rx.follow(&subscription);
+        let snapshot = self.snapshot(&subscription).await?;
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+        let shutdown = self.shutdown.clone();
+        tokio::spawn(async move {
+            let _permit = permit;
+            let mut last_seq = snapshot.seq;
+            // books whose levels didn't change are skipped
+            let mut last_levels = (snapshot.bids.clone(), snapshot.asks.clone());
+            if tx.send(Ok(snapshot)).await.is_err() {
+                return;
+            }
+            loop {
+                let msg = select! {
+                    msg = internal_message_rx.recv() => msg,
+                    () = shutdown.cancelled() => {
+                        let _unused = tx.send(Err(Status::unavailable("Server shutting down"))).await;
+                        return;
+                    }
+                };
+                match msg {
+                    Ok(msg) => {
+                        if let InternalMessage::Snapshot { l2_snapshots, time, seq, .. } = msg.as_ref()
+                            && *seq > last_seq
+                            && let Some(book) =
+                                l2_book_from_snapshots(&subscription, l2_snapshots.as_ref(), *time, *seq)
+                        {
+                            last_seq = *seq;
+                            let book = L2Book::from(&book);
+                            if book.bids == last_levels.0 && book.asks == last_levels.1 {
+                                continue;
+                            }
+                            last_levels = (book.bids.clone(), book.asks.clone());
+                            if tx.send(Ok(book)).await.is_err() {
+                                return;
+                            }
+                        }
+                    }
+                    Err(RecvError::Lagged(n)) => {
+                        METRICS.dropped_messages.inc_by(n);
+                        let _unused = tx.send(Err(Status::data_loss("Stream fell behind"))).await;
+                        return;
+                    }
+                    Err(RecvError::Closed) => return,
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+fn auth_status(err: AuthError) -> Status {
+    match err {
+        AuthError::Missing | AuthError::Invalid => Status::unauthenticated(err.to_string()),
+        AuthError::TooManyConnections(_) | AuthError::TooManyTenantConnections(_) => {
+            Status::resource_exhausted(err.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::Mutex;
+    use tonic::Code;
+
+    use super::*;
+    use crate::listeners::order_book::OrderBookListener;
+    use crate::servers::protobuf::proto::order_book_client::OrderBookClient;
+
+    #[tokio::test]
+    async fn test_get_snapshot_unavailable_until_ready() -> Result<()> {
+        let internal_message_tx = Broadcast::new(1);
+        let listener = Arc::new(Mutex::new(OrderBookListener::new(None, true)));
+        let tcp_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = tcp_listener.local_addr()?;
+        let standby = Standby::new(true);
+        serve_grpc(tcp_listener, Markets::new(listener), internal_message_tx, None, standby, Shutdown::default())?;
+        let mut client = OrderBookClient::connect(format!("http://{address}")).await?;
+        let request = BookRequest { coin: "BTC".to_string(), depth: Some(50), n_sig_figs: None, mantissa: None };
+        let err = client.get_snapshot(request.clone()).await.err().ok_or("expected an error")?;
+        assert_eq!(err.code(), Code::Unavailable);
+        // a standby answers snapshots, but doesn't stream
+        let err = client.subscribe_order_book(request).await.err().ok_or("expected an error")?;
+        assert_eq!((err.code(), err.message()), (Code::Unavailable, "Server on standby"));
+        Ok(())
+    }
+}
@@ -0,0 +1,167 @@
+use std::sync::Arc;
+
+use axum::{Json, Router, extract::State, http::StatusCode, routing::get};
+use serde::Serialize;
+use tokio::net::TcpListener;
+use tracing::{error, info};
+
+use crate::{
+    prelude::*,
+    servers::{markets::Markets, registry::ConnectionRegistry, standby::Standby},
+    types::StreamStatus,
+};
+
+#[derive(Clone)]
+struct HealthState {
+    markets: Markets,
+    registry: Arc<ConnectionRegistry>,
+    standby: Standby,
+}
+
+#[derive(Debug, Serialize)]
+struct Readiness {
+    ready: bool,
+    // why not, if not; those of other markets start with `<market>: `
+    reasons: Vec<String>,
+    status: Option<StreamStatus>,
+    // the statuses of the other markets
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    markets: Vec<StreamStatus>,
+}
+
+// liveness and readiness probes for orchestrators, without authentication
+pub(crate) fn serve_health(
+    listener: TcpListener,
+    markets: Markets,
+    registry: Arc<ConnectionRegistry>,
+    standby: Standby,
+) -> Result<()> {
+    let address = listener.local_addr()?;
+    let app = Router::new()
+        .route("/healthz", get(async || "ok"))
+        .route("/readyz", get(readyz))
+        .with_state(HealthState { markets, registry, standby });
+    info!("Health server running at http://{address}");
+    tokio::spawn(async move {
+        if let Err(err) = axum::serve(listener, app.into_make_service()).await {
+            error!("Health server error: {err}");
+        }
+    });
+    Ok(())
+}
+
+// ready while the books of every market are served from a live feed and new connections are accepted. A standby
+// whose books are live is only held back by `standby`
+async fn readyz(State(state): State<HealthState>) -> (StatusCode, Json<Readiness>) {
+    let status = state.markets.primary().lock().await.last_status();
+    let mut reasons =
+        not_ready(status.as_ref(), state.registry.is_maintenance()).into_iter().map(str::to_string).collect::<Vec<_>>();
+    let mut markets = Vec::new();
+    for (name, listener) in state.markets.named() {
+        let (status, lazy) = {
+            let listener = listener.lock().await;
+            (listener.last_status(), listener.is_lazy())
+        };
+        // lazy markets load their books on demand, without holding up the server
+        if !lazy {
+            reasons.extend(not_ready(status.as_ref(), false).into_iter().map(|reason| format!("{name}: {reason}")));
+        }
+        markets.extend(status);
+    }
+    if state.standby.is_on() {
+        reasons.push("standby".to_string());
+    }
+    let ready = reasons.is_empty();
+    let code = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (code, Json(Readiness { ready, reasons, status, markets }))
+}
+
+// the status is sent every second, so it is at most that old
+pub(crate) fn not_ready(status: Option<&StreamStatus>, maintenance: bool) -> Vec<&'static str> {
+    let mut reasons = Vec::new();
+    match status {
+        None => reasons.push("starting"),
+        Some(status) => {
+            if !status.ready {
+                reasons.push("no order book yet");
+            }
+            if status.stale {
+                reasons.push("stale feed");
+            }
+            if !status.upstreams.iter().any(|upstream| upstream.connected) {
+                reasons.push("no upstream connected");
+            }
+        }
+    }
+    if maintenance {
+        reasons.push("maintenance");
+    }
+    reasons
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value, json};
+    use tokio::sync::Mutex;
+
+    use super::*;
+    use crate::{listeners::order_book::OrderBookListener, types::UpstreamStatus};
+
+    #[test]
+    fn test_not_ready() {
+        let mut status = StreamStatus {
+            market: None,
+            stale: false,
+            ready: true,
+            last_block: 1,
+            last_block_time: Some(2),
+            lag_ms: Some(3),
+            upstreams: vec![UpstreamStatus { node: "a".to_string(), connected: true }],
+            snapshot_in_progress: false,
+            gaps: 0,
+            crossed_books: Vec::new(),
+            maintenance: false,
+            time: 4,
+        };
+        assert!(not_ready(Some(&status), false).is_empty());
+        assert_eq!(not_ready(Some(&status), true), ["maintenance"]);
+        status.stale = true;
+        status.upstreams[0].connected = false;
+        assert_eq!(not_ready(Some(&status), false), ["stale feed", "no upstream connected"]);
+        assert_eq!(not_ready(None, false), ["starting"]);
+    }
+
+    #[tokio::test]
+    async fn test_probes() -> Result<()> {
+        let order_book = Arc::new(Mutex::new(OrderBookListener::new(None, true)));
+        let testnet = Arc::new(Mutex::new(order_book.lock().await.for_market("testnet")));
+        let mut markets = Markets::new(order_book.clone());
+        markets.add("testnet".to_string(), testnet.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        let standby = Standby::new(true);
+        serve_health(listener, markets, Arc::new(ConnectionRegistry::default()), standby.clone())?;
+        assert_eq!(reqwest::get(format!("http://{address}/healthz")).await?.status(), StatusCode::OK);
+
+        order_book.lock().await.send_status(false);
+        let response = reqwest::get(format!("http://{address}/readyz")).await?;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body: Value = response.json().await?;
+        assert_eq!(
+            body["reasons"],
+            json!(["no order book yet", "no upstream connected", "testnet: starting", "standby"])
+        );
+        assert_eq!(body["status"]["ready"], false);
+        assert!(body["status"].get("market").is_none());
+
+        testnet.lock().await.send_status(false);
+        let body: Value = reqwest::get(format!("http://{address}/readyz")).await?.json().await?;
+        assert_eq!(body["reasons"][2], "testnet: no order book yet");
+        assert_eq!(body["markets"][0]["market"], "testnet");
+        assert_eq!(body["reasons"][4], "standby");
+        standby.promote("test");
+        let body: Value = reqwest::get(format!("http://{address}/readyz")).await?.json().await?;
+        assert!(body["reasons"].as_array().is_some_and(|reasons| !reasons.contains(&json!("standby"))));
+        Ok(())
+    }
+}
@@ -0,0 +1,216 @@
+use std::{collections::HashMap, time::Duration};
+
+use chrono::Utc;
+use tokio::time::{Instant, Interval, MissedTickBehavior, interval_at};
+use tracing::info;
+
+use crate::{
+    listeners::order_book::InternalMessage,
+    metrics::METRICS,
+    servers::send_queue::SendQueue,
+    types::{
+        Heartbeat,
+        subscription::{ServerResponse, Subscription, SubscriptionManager},
+    },
+};
+
+/// Server initiated liveness checks of websocket connections. Both are off when not set.
+#[derive(Debug, Clone, Copy)]
+pub struct KeepaliveConfig {
+    /// Send a Ping frame this often. A connection that doesn't answer `max_missed_pongs` pings in a row is closed.
+    pub ping_interval: Option<Duration>,
+    pub max_missed_pongs: u32,
+    /// Send a `heartbeat` message with the latest sequence numbers this often.
+    pub heartbeat_interval: Option<Duration>,
+    /// Send a `time` message with the server's clock this often, for clients to tell their clock's offset by.
+    pub time_interval: Option<Duration>,
+}
+
+pub(crate) enum Tick {
+    Ping,
+    Heartbeat,
+    Time,
+}
+
+// keepalive state of one connection
+pub(crate) struct Keepalive {
+    ping: Option<Interval>,
+    heartbeat: Option<Interval>,
+    time: Option<Interval>,
+    max_missed_pongs: u32,
+    missed_pongs: u32,
+    awaiting_pong: bool,
+    // latest seqs broadcast to the connection, whether or not it is subscribed to them
+    l2_seq: u64,
+    l4_seqs: HashMap<String, u64>,
+}
+
+impl Keepalive {
+    pub(crate) fn new(config: KeepaliveConfig) -> Self {
+        Self {
+            ping: config.ping_interval.map(ticker),
+            heartbeat: config.heartbeat_interval.map(ticker),
+            time: config.time_interval.map(ticker),
+            max_missed_pongs: config.max_missed_pongs.max(1),
+            missed_pongs: 0,
+            awaiting_pong: false,
+            l2_seq: 0,
+            l4_seqs: HashMap::new(),
+        }
+    }
+
+    pub(crate) async fn tick(&mut self) -> Tick {
+        tokio::select! {
+            () = next_tick(&mut self.ping) => Tick::Ping,
+            () = next_tick(&mut self.heartbeat) => Tick::Heartbeat,
+            () = next_tick(&mut self.time) => Tick::Time,
+        }
+    }
+
+    pub(crate) fn on_tick(&mut self, tick: &Tick, queue: &SendQueue, manager: &SubscriptionManager) {
+        match tick {
+            Tick::Ping => {
+                if self.awaiting_pong {
+                    self.missed_pongs += 1;
+                }
+                if self.missed_pongs >= self.max_missed_pongs {
+                    // a dead peer wouldn't complete a closing handshake
+                    info!("Closing connection that missed {} pongs", self.missed_pongs);
+                    METRICS.dead_connections.inc();
+                    queue.abort();
+                    return;
+                }
+                self.awaiting_pong = true;
+                queue.ping();
+            }
+            Tick::Heartbeat => queue.push(None, ServerResponse::Heartbeat(self.heartbeat(manager))),
+            // stamped by the writer, as it goes out
+            Tick::Time => queue.time(),
+        }
+    }
+
+    pub(crate) const fn on_pong(&mut self) {
+        self.awaiting_pong = false;
+        self.missed_pongs = 0;
+    }
+
+    pub(crate) fn observe(&mut self, msg: &InternalMessage) {
+        match msg {
+            InternalMessage::Snapshot { seq, .. } => self.l2_seq = *seq,
+            InternalMessage::L4BookUpdates { updates, .. } => {
+                for (coin, update) in updates {
+                    self.l4_seqs.insert(coin.clone(), update.seq);
+                }
+            }
+            InternalMessage::Fills { .. }
+            | InternalMessage::Candles { .. }
+            | InternalMessage::Analytics { .. }
+            | InternalMessage::L3BookUpdates { .. }
+            | InternalMessage::Checkpoints { .. }
+            | InternalMessage::Status { .. }
+            | InternalMessage::Universe { .. }
+            | InternalMessage::MarketChanges { .. }
+            | InternalMessage::Halt { .. } => {}
+        }
+    }
+
+    fn heartbeat(&self, manager: &SubscriptionManager) -> Heartbeat {
+        let l4_seqs = manager
+            .subscriptions()
+            .iter()
+            .filter_map(|sub| match sub {
+                Subscription::L4Book { coin, .. } => self.l4_seqs.get(coin).map(|seq| (coin.clone(), *seq)),
+                _ => None,
+            })
+            .collect();
+        #[allow(clippy::cast_sign_loss)]
+        let time = Utc::now().timestamp_millis() as u64;
+        Heartbeat { time, l2_seq: self.l2_seq, l4_seqs }
+    }
+}
+
+// never for an interval that is off
+async fn next_tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+fn ticker(period: Duration) -> Interval {
+    let mut interval = interval_at(Instant::now() + period, period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    interval
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing::Span;
+
+    use super::*;
+    use crate::{
+        latency::Stamps,
+        servers::{
+            outbound::SharedResponses,
+            send_queue::{BackpressurePolicy, Outgoing},
+        },
+        types::L4BookUpdates,
+    };
+
+    #[tokio::test]
+    async fn test_missed_pongs_close_the_connection() {
+        let config =
+            KeepaliveConfig { ping_interval: None, max_missed_pongs: 2, heartbeat_interval: None, time_interval: None };
+        let mut keepalive = Keepalive::new(config);
+        let queue = SendQueue::new(BackpressurePolicy::Disconnect, 16);
+        let manager = SubscriptionManager::default();
+        keepalive.on_tick(&Tick::Ping, &queue, &manager);
+        assert!(matches!(queue.next().await, Some(Outgoing::Ping)));
+        keepalive.on_pong();
+        keepalive.on_tick(&Tick::Ping, &queue, &manager);
+        keepalive.on_tick(&Tick::Ping, &queue, &manager);
+        assert!(!queue.is_closing());
+        keepalive.on_tick(&Tick::Ping, &queue, &manager);
+        assert!(queue.is_closing());
+    }
+
+    #[tokio::test]
+    async fn test_time_goes_out_first() {
+        let config = KeepaliveConfig {
+            ping_interval: None,
+            max_missed_pongs: 3,
+            heartbeat_interval: None,
+            time_interval: Some(Duration::from_secs(1)),
+        };
+        let mut keepalive = Keepalive::new(config);
+        let queue = SendQueue::new(BackpressurePolicy::Disconnect, 16);
+        let manager = SubscriptionManager::default();
+        queue.push(None, ServerResponse::Trades(Vec::new()));
+        assert!(matches!(keepalive.tick().await, Tick::Time));
+        keepalive.on_tick(&Tick::Time, &queue, &manager);
+        assert!(matches!(queue.next().await, Some(Outgoing::Time)));
+        assert!(matches!(queue.next().await, Some(Outgoing::Message(..))));
+    }
+
+    #[test]
+    fn test_heartbeat_carries_subscribed_seqs() {
+        let config =
+            KeepaliveConfig { ping_interval: None, max_missed_pongs: 3, heartbeat_interval: None, time_interval: None };
+        let mut keepalive = Keepalive::new(config);
+        let mut update = L4BookUpdates::new(0, 1);
+        update.seq = 7;
+        let updates = HashMap::from([("BTC".to_string(), update.clone()), ("ETH".to_string(), update)]);
+        keepalive.observe(&InternalMessage::L4BookUpdates {
+            updates,
+            trace: Span::none(),
+            stamps: Stamps { node_time: 0, ingest_time: 0 },
+            shared: SharedResponses::default(),
+        });
+        let mut manager = SubscriptionManager::default();
+        manager.subscribe(Subscription::L4Book { coin: "BTC".to_string(), conflate_ms: None });
+        let heartbeat = keepalive.heartbeat(&manager);
+        assert_eq!(heartbeat.l4_seqs.into_iter().collect::<Vec<_>>(), vec![("BTC".to_string(), 7)]);
+    }
+}
@@ -0,0 +1,146 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+use axum::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use yawc::close::CloseCode;
+
+use crate::metrics::METRICS;
+
+// yawc reads no larger messages, whatever the limit
+pub(crate) const MAX_MESSAGE_BYTES: usize = yawc::MAX_PAYLOAD_READ;
+
+/// Caps on what clients may hold open. A limit that isn't set is not enforced.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectionLimits {
+    /// Open websocket connections in total. Connections beyond it are closed with code 1013 (try again later).
+    pub max_connections: Option<usize>,
+    /// Open websocket connections per client IP address. Connections beyond it are closed with code 1008.
+    pub max_connections_per_ip: Option<usize>,
+    /// Size of a single message from a client, at most 1 MiB. Larger messages close the connection with code 1009.
+    pub max_message_bytes: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LimitError {
+    TooManyConnections,
+    TooManyConnectionsPerIp,
+}
+
+impl LimitError {
+    pub(crate) const fn close_code(self) -> CloseCode {
+        match self {
+            Self::TooManyConnections => CloseCode::Again,
+            Self::TooManyConnectionsPerIp => CloseCode::Policy,
+        }
+    }
+
+    // for the requests that are refused before they could be upgraded
+    pub(crate) const fn status(self) -> StatusCode {
+        match self {
+            Self::TooManyConnections => StatusCode::SERVICE_UNAVAILABLE,
+            Self::TooManyConnectionsPerIp => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+}
+
+impl fmt::Display for LimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::TooManyConnections => "too many connections",
+            Self::TooManyConnectionsPerIp => "too many connections from this address",
+        })
+    }
+}
+
+#[derive(Default)]
+struct Counts {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+/// Counts the open websocket connections, in total and per client IP address.
+#[derive(Default)]
+pub(crate) struct ConnectionCounter {
+    counts: Arc<Mutex<Counts>>,
+}
+
+impl ConnectionCounter {
+    // the connection counts against the limits until the slot is dropped
+    pub(crate) fn admit(&self, ip: IpAddr, limits: &ConnectionLimits) -> Result<ConnectionSlot, LimitError> {
+        let res = self.try_admit(ip, limits);
+        if let Err(err) = res {
+            let limit = match err {
+                LimitError::TooManyConnections => "connections",
+                LimitError::TooManyConnectionsPerIp => "connections_per_ip",
+            };
+            METRICS.limit_rejections.with_label_values(&[limit]).inc();
+        }
+        res
+    }
+
+    fn try_admit(&self, ip: IpAddr, limits: &ConnectionLimits) -> Result<ConnectionSlot, LimitError> {
+        let slot = || ConnectionSlot { ip, counts: self.counts.clone() };
+        let Ok(mut counts) = self.counts.lock() else {
+            return Ok(slot());
+        };
+        if limits.max_connections.is_some_and(|limit| counts.total >= limit) {
+            return Err(LimitError::TooManyConnections);
+        }
+        let count = counts.per_ip.get(&ip).copied().unwrap_or_default();
+        if limits.max_connections_per_ip.is_some_and(|limit| count >= limit) {
+            return Err(LimitError::TooManyConnectionsPerIp);
+        }
+        counts.total += 1;
+        counts.per_ip.insert(ip, count + 1);
+        drop(counts);
+        Ok(slot())
+    }
+}
+
+pub(crate) struct ConnectionSlot {
+    ip: IpAddr,
+    counts: Arc<Mutex<Counts>>,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        if let Ok(mut counts) = self.counts.lock()
+            && let Some(count) = counts.per_ip.get_mut(&self.ip)
+        {
+            *count -= 1;
+            if *count == 0 {
+                counts.per_ip.remove(&self.ip);
+            }
+            counts.total -= 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection_limits() {
+        let counter = ConnectionCounter::default();
+        let limits =
+            ConnectionLimits { max_connections: Some(3), max_connections_per_ip: Some(2), ..Default::default() };
+        let (a, b) = (IpAddr::from([10, 0, 0, 1]), IpAddr::from([10, 0, 0, 2]));
+
+        let a1 = counter.admit(a, &limits);
+        let _a2 = counter.admit(a, &limits);
+        assert_eq!(counter.admit(a, &limits).err(), Some(LimitError::TooManyConnectionsPerIp));
+        let _b1 = counter.admit(b, &limits);
+        assert_eq!(counter.admit(b, &limits).err(), Some(LimitError::TooManyConnections));
+        drop(a1);
+        assert!(counter.admit(b, &limits).is_ok());
+        // raised at runtime, the counts carry over
+        assert!(counter.admit(a, &ConnectionLimits::default()).is_ok());
+    }
+}
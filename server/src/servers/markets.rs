@@ -0,0 +1,148 @@
+use std::{collections::HashSet, str::FromStr, sync::Arc};
+
+use tokio::sync::Mutex;
+
+use crate::{
+    listeners::order_book::{CrossedBookPolicy, OrderBookListener, SnapshotStrategy, UpstreamNode},
+    servers::tenants::Tenant,
+    types::{MarketHalt, MarketInfo, subscription::Subscription},
+};
+
+/// A market read by the same process as the primary one, e.g. testnet next to mainnet. Its coins are served as
+/// `<name>:<coin>`, with a book state and sequence numbers of their own.
+#[derive(Debug, Clone)]
+pub struct MarketConfig {
+    pub name: String,
+    /// Nodes to ingest this market's events from, like [`ServerConfig::upstreams`](crate::ServerConfig::upstreams).
+    pub upstreams: Vec<UpstreamNode>,
+    /// Overrides [`ServerConfig::crossed_books`](crate::ServerConfig::crossed_books) for this market.
+    pub crossed_books: Option<CrossedBookPolicy>,
+    /// Overrides [`ServerConfig::snapshot_strategy`](crate::ServerConfig::snapshot_strategy) for this market.
+    pub snapshot_strategy: Option<SnapshotStrategy>,
+}
+
+// `<name>:<upstream>`, a single node of the market; entries with the same name are nodes of the same market
+impl FromStr for MarketConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, upstream) =
+            s.split_once(':').ok_or_else(|| format!("invalid market {s} (expected <name>:<upstream>)"))?;
+        Ok(Self {
+            name: name.to_string(),
+            upstreams: vec![upstream.parse()?],
+            crossed_books: None,
+            snapshot_strategy: None,
+        })
+    }
+}
+
+// the listener of every market, found by the prefix of a coin
+#[derive(Clone)]
+pub(crate) struct Markets {
+    primary: Arc<Mutex<OrderBookListener>>,
+    named: Vec<(String, Arc<Mutex<OrderBookListener>>)>,
+}
+
+impl Markets {
+    pub(crate) const fn new(primary: Arc<Mutex<OrderBookListener>>) -> Self {
+        Self { primary, named: Vec::new() }
+    }
+
+    pub(crate) fn add(&mut self, name: String, listener: Arc<Mutex<OrderBookListener>>) {
+        self.named.push((name, listener));
+    }
+
+    // the market without a prefix; it alone has the status, snapshot store and relay feed
+    pub(crate) const fn primary(&self) -> &Arc<Mutex<OrderBookListener>> {
+        &self.primary
+    }
+
+    // name of the market a coin belongs to, `None` for the primary one
+    pub(crate) fn market_of(&self, coin: &str) -> Option<&str> {
+        let (prefix, _) = coin.split_once(':')?;
+        self.named.iter().map(|(name, _)| name.as_str()).find(|name| *name == prefix)
+    }
+
+    // whether the clients of `tenant` may use the coin; those without a tenant may use every coin
+    pub(crate) fn allows(&self, tenant: Option<&Tenant>, coin: &str) -> bool {
+        tenant.is_none_or(|tenant| tenant.allows(self.market_of(coin)))
+    }
+
+    pub(crate) fn for_coin(&self, coin: &str) -> &Arc<Mutex<OrderBookListener>> {
+        self.market_of(coin)
+            .and_then(|market| self.named.iter().find(|(name, _)| name == market))
+            .map_or(&self.primary, |(_, listener)| listener)
+    }
+
+    // the halt of the market a subscription is to, which is resumed with a fresh snapshot
+    pub(crate) async fn halt(&self, subscription: &Subscription) -> Option<MarketHalt> {
+        let coin = subscription.coin()?;
+        self.for_coin(coin).lock().await.halt(coin)
+    }
+
+    pub(crate) fn for_subscription(&self, subscription: &Subscription) -> Arc<Mutex<OrderBookListener>> {
+        subscription.coin().map_or_else(|| self.primary.clone(), |coin| self.for_coin(coin).clone())
+    }
+
+    // the markets other than the primary one, by name
+    pub(crate) fn named(&self) -> impl Iterator<Item = (&str, &Arc<Mutex<OrderBookListener>>)> {
+        self.named.iter().map(|(name, listener)| (name.as_str(), listener))
+    }
+
+    pub(crate) fn listeners(&self) -> impl Iterator<Item = &Arc<Mutex<OrderBookListener>>> {
+        std::iter::once(&self.primary).chain(self.named.iter().map(|(_, listener)| listener))
+    }
+
+    // the coins of all markets
+    pub(crate) async fn universe(&self) -> HashSet<String> {
+        let mut universe = HashSet::new();
+        for listener in self.listeners() {
+            universe.extend(listener.lock().await.universe().into_iter().map(|c| c.value()));
+        }
+        universe
+    }
+
+    // the metadata of the markets the tenant may use, the primary one first
+    pub(crate) async fn market_info(&self, tenant: Option<&Tenant>) -> Vec<MarketInfo> {
+        let named = self.named.iter().map(|(name, listener)| (Some(name.as_str()), listener));
+        let mut markets = Vec::new();
+        for (_, listener) in std::iter::once((None, &self.primary))
+            .chain(named)
+            .filter(|(name, _)| tenant.is_none_or(|tenant| tenant.allows(*name)))
+        {
+            markets.extend_from_slice(listener.lock().await.market_info());
+        }
+        markets
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_routes_by_prefix() {
+        let listener = || Arc::new(Mutex::new(OrderBookListener::new(None, true)));
+        let mut markets = Markets::new(listener());
+        let testnet = listener();
+        markets.add("testnet".to_string(), testnet.clone());
+        assert!(Arc::ptr_eq(markets.for_coin("testnet:BTC"), &testnet));
+        assert!(Arc::ptr_eq(markets.for_coin("BTC"), markets.primary()));
+        // only configured names are prefixes
+        assert!(Arc::ptr_eq(markets.for_coin("other:BTC"), markets.primary()));
+        assert_eq!(markets.market_of("testnet:@1"), Some("testnet"));
+        assert!(Arc::ptr_eq(&markets.for_subscription(&Subscription::Status), markets.primary()));
+        let tenant = Tenant { markets: vec!["testnet".to_string()], ..Tenant::default() };
+        assert!(markets.allows(Some(&tenant), "testnet:BTC") && !markets.allows(Some(&tenant), "BTC"));
+        assert!(markets.allows(None, "BTC"));
+    }
+
+    #[test]
+    fn test_parse_market() {
+        let market = "testnet:/home/testnet=http://localhost:3002/info".parse::<MarketConfig>().unwrap();
+        assert_eq!(market.name, "testnet");
+        assert_eq!(market.upstreams[0].info_url, "http://localhost:3002/info");
+        assert!("/home/testnet".parse::<MarketConfig>().is_err());
+    }
+}
@@ -1 +1,48 @@
+pub(crate) mod admin;
+pub(crate) mod auth;
+pub(crate) mod check;
+pub(crate) mod coalescing;
+pub(crate) mod config;
+pub(crate) mod cors;
+pub(crate) mod deflate;
+pub(crate) mod election;
+pub(crate) mod encoding;
+pub(crate) mod grpc;
+pub(crate) mod health;
+pub(crate) mod keepalive;
+pub(crate) mod limits;
+pub(crate) mod markets;
+pub(crate) mod outbound;
+pub(crate) mod ownership;
+pub(crate) mod preferences;
+pub(crate) mod priority;
+pub(crate) mod protobuf;
+pub(crate) mod protocol;
+pub(crate) mod proxy;
+pub(crate) mod publisher;
+pub(crate) mod rate_limit;
+pub(crate) mod registry;
+pub(crate) mod replay;
+pub(crate) mod rest;
+pub(crate) mod rpc;
+pub(crate) mod schema;
+pub(crate) mod send_queue;
+pub(crate) mod sessions;
+pub(crate) mod settings;
+pub(crate) mod shared_compression;
+pub(crate) mod shutdown;
+pub(crate) mod socket;
+pub(crate) mod sse;
+pub(crate) mod standby;
+pub(crate) mod tenants;
+pub(crate) mod tls;
+#[cfg(unix)]
+pub(crate) mod unix_socket;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub(crate) mod uring;
+pub(crate) mod watchdog;
+pub(crate) mod webhooks;
 pub(crate) mod websocket_server;
+#[cfg(feature = "webtransport")]
+pub(crate) mod webtransport;
+pub(crate) mod zstd_dictionary;
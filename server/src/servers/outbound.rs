@@ -0,0 +1,365 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use serde_json::Value;
+
+use crate::{
+    metrics::METRICS,
+    prelude::*,
+    servers::{
+        encoding::{Encoding, Numbers, Version},
+        protobuf,
+        protocol::Versioned,
+        shared_compression::deflate,
+        zstd_dictionary::ZstdDictionary,
+    },
+    types::subscription::{ServerResponse, Subscription},
+};
+
+/// A message for any number of connections. It is serialized at most once per encoding and number format, and
+/// compressed at most once per level, and every connection sending it writes the same buffers.
+pub(crate) struct Outbound {
+    msg: ServerResponse,
+    // the optional fields of the message that are sent, all of them if not set
+    fields: Option<Vec<String>>,
+    payloads: [[OnceLock<Bytes>; 3]; 3],
+    compressed: Mutex<HashMap<(Encoding, u32), Bytes>>,
+    zstd: OnceLock<Bytes>,
+}
+
+impl From<ServerResponse> for Arc<Outbound> {
+    fn from(msg: ServerResponse) -> Self {
+        Outbound::projected(msg, None)
+    }
+}
+
+impl Outbound {
+    // a message sent with only the optional fields a subscription asked for
+    pub(crate) fn projected(msg: ServerResponse, fields: Option<&[String]>) -> Arc<Self> {
+        Arc::new(Self {
+            msg,
+            fields: fields.map(<[String]>::to_vec),
+            payloads: Default::default(),
+            compressed: Mutex::default(),
+            zstd: OnceLock::new(),
+        })
+    }
+
+    pub(crate) const fn msg(&self) -> &ServerResponse {
+        &self.msg
+    }
+
+    // the message, without a copy if no one else holds it
+    pub(crate) fn into_msg(self: Arc<Self>) -> ServerResponse {
+        Arc::try_unwrap(self).map_or_else(|shared| shared.msg.clone(), |outbound| outbound.msg)
+    }
+
+    pub(crate) fn payload(&self, encoding: Encoding, version: Version, numbers: Numbers) -> Result<Bytes> {
+        // protobuf messages have decimal strings
+        let numbers = if encoding == Encoding::Protobuf { Numbers::String } else { numbers };
+        // only errors are written differently by version, and they are never sent to more than one connection
+        if version != Version::V1 && matches!(self.msg, ServerResponse::Error(_)) {
+            return Ok(encoding.encode(&Versioned::new(&self.msg, version))?.payload);
+        }
+        let slot = &self.payloads[encoding as usize][numbers as usize];
+        if let Some(payload) = slot.get() {
+            METRICS.encoder_cache.payloads.hit();
+            return Ok(payload.clone());
+        }
+        METRICS.encoder_cache.payloads.miss();
+        let payload = match (&self.fields, numbers) {
+            _ if encoding == Encoding::Protobuf => protobuf::encode(&self.msg, version, self.fields.as_deref())?,
+            (None, Numbers::String) => encoding.encode(&Versioned::new(&self.msg, version))?.payload,
+            (fields, numbers) => {
+                let mut msg = serde_json::to_value(Versioned::new(&self.msg, version))?;
+                if let Some(fields) = fields {
+                    project(&mut msg, fields);
+                }
+                numbers.convert(&mut msg)?;
+                encoding.encode(&msg)?.payload
+            }
+        };
+        Ok(slot.get_or_init(|| payload).clone())
+    }
+
+    // the payload as a raw deflate stream, as written by connections that share compression
+    pub(crate) fn compressed(&self, encoding: Encoding, version: Version, level: u32) -> Result<Bytes> {
+        let payload = self.payload(encoding, version, Numbers::String)?;
+        let Ok(mut compressed) = self.compressed.lock() else {
+            return Ok(deflate(level, &payload)?);
+        };
+        if let Some(compressed) = compressed.get(&(encoding, level)) {
+            return Ok(compressed.clone());
+        }
+        let deflated = deflate(level, &payload)?;
+        compressed.insert((encoding, level), deflated.clone());
+        Ok(deflated)
+    }
+
+    // the MessagePack payload as a zstd frame, as written by connections that negotiated zstd. A server has a
+    // single dictionary, so it is only compressed once
+    pub(crate) fn zstd(&self, version: Version, dictionary: &ZstdDictionary) -> Result<Bytes> {
+        let payload = self.payload(Encoding::MessagePack, version, Numbers::String)?;
+        if version != Version::V1 && matches!(self.msg, ServerResponse::Error(_)) {
+            return dictionary.compress(&payload);
+        }
+        if let Some(compressed) = self.zstd.get() {
+            return Ok(compressed.clone());
+        }
+        let compressed = dictionary.compress(&payload)?;
+        Ok(self.zstd.get_or_init(|| compressed).clone())
+    }
+}
+
+// leaves out the fields of an l2 book, l2 delta or bbo that aren't listed, see `Subscription::fields`
+fn project(msg: &mut Value, fields: &[String]) {
+    fn remove_counts(value: &mut Value) {
+        match value {
+            Value::Array(values) => values.iter_mut().for_each(remove_counts),
+            Value::Object(level) => {
+                level.shift_remove("n");
+            }
+            _ => {}
+        }
+    }
+    let Some(Value::Object(data)) = msg.get_mut("data") else {
+        return;
+    };
+    let listed = |field: &str| fields.iter().any(|listed| listed == field);
+    data.retain(|field, _| {
+        matches!(field.as_str(), "coin" | "levels" | "bid" | "ask" | "prevSeq" | "epoch" | "changes") || listed(field)
+    });
+    if !listed("n") {
+        data.values_mut().for_each(remove_counts);
+    }
+}
+
+/// The payloads of several messages as one array, the frame of a batching connection. The messages are not
+/// serialized again, only copied into the frame.
+pub(crate) fn batch_payload(encoding: Encoding, payloads: &[Bytes]) -> Bytes {
+    let len = payloads.iter().map(Bytes::len).sum::<usize>();
+    let mut batch = BytesMut::with_capacity(len + payloads.len() + 5);
+    match encoding {
+        Encoding::Json => {
+            batch.put_u8(b'[');
+            for (i, payload) in payloads.iter().enumerate() {
+                if i > 0 {
+                    batch.put_u8(b',');
+                }
+                batch.put_slice(payload);
+            }
+            batch.put_u8(b']');
+        }
+        Encoding::MessagePack => {
+            // the array header of the MessagePack spec, followed by its elements
+            let n = payloads.len();
+            if let Ok(n @ 0..16) = u8::try_from(n) {
+                batch.put_u8(0x90 | n);
+            } else if let Ok(n) = u16::try_from(n) {
+                batch.put_u8(0xdc);
+                batch.put_u16(n);
+            } else {
+                batch.put_u8(0xdd);
+                batch.put_u32(u32::try_from(n).unwrap_or(u32::MAX));
+            }
+            for payload in payloads {
+                batch.put_slice(payload);
+            }
+        }
+        Encoding::Protobuf => {
+            // the `messages` of a `Batch`, each a length delimited field 1
+            for payload in payloads {
+                batch.put_u8(0x0a);
+                prost::encoding::encode_varint(payload.len() as u64, &mut batch);
+                batch.put_slice(payload);
+            }
+        }
+    }
+    batch.freeze()
+}
+
+type Entry = Arc<OnceLock<Option<Arc<Outbound>>>>;
+
+/// The messages built from one message of the listener. The first connection that needs one builds it, and the
+/// others send the same one. Subscriptions that only differ in what makes no difference to their messages, such
+/// as their conflation interval, share them, see [`Subscription::variant`].
+#[derive(Default)]
+pub(crate) struct SharedResponses {
+    by_subscription: Mutex<HashMap<Subscription, Entry>>,
+    by_coin: OnceLock<HashMap<String, Arc<Outbound>>>,
+}
+
+impl SharedResponses {
+    // the message for a subscription, if there is one
+    pub(crate) fn for_subscription(
+        &self,
+        subscription: &Subscription,
+        build: impl FnOnce() -> Option<ServerResponse>,
+    ) -> Option<Arc<Outbound>> {
+        let Ok(mut responses) = self.by_subscription.lock() else {
+            return build().map(|msg| Outbound::projected(msg, subscription.fields()));
+        };
+        let entry = responses.entry(subscription.variant()).or_default().clone();
+        drop(responses);
+        if entry.get().is_some() {
+            METRICS.encoder_cache.responses.hit();
+        } else {
+            METRICS.encoder_cache.responses.miss();
+        }
+        // connections asking at once wait for the first one to build it
+        entry.get_or_init(|| build().map(|msg| Outbound::projected(msg, subscription.fields()))).clone()
+    }
+
+    // the messages for every coin, for data that is the same for all the subscriptions of a coin
+    pub(crate) fn by_coin(
+        &self,
+        build: impl FnOnce() -> HashMap<String, ServerResponse>,
+    ) -> &HashMap<String, Arc<Outbound>> {
+        self.by_coin.get_or_init(|| build().into_iter().map(|(coin, msg)| (coin, msg.into())).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+
+    use super::*;
+    use crate::{
+        servers::protobuf::proto::{Batch, ServerMessage},
+        types::{Bbo, L2Book, L4Book, L4BookUpdates, Level, delta::Epoch},
+    };
+
+    fn updates(seq: u64) -> ServerResponse {
+        let mut updates = L4BookUpdates::new(seq, seq);
+        updates.seq = seq;
+        ServerResponse::L4Book(L4Book::Updates(updates))
+    }
+
+    #[test]
+    fn test_serializes_and_compresses_once() -> Result<()> {
+        let msg = Arc::<Outbound>::from(updates(1));
+        let json = msg.payload(Encoding::Json, Version::V1, Numbers::String)?;
+        assert_eq!(json, serde_json::to_string(&updates(1))?.as_bytes());
+        assert_eq!(msg.payload(Encoding::Json, Version::V2, Numbers::String)?.as_ptr(), json.as_ptr());
+        assert_ne!(msg.payload(Encoding::MessagePack, Version::V1, Numbers::String)?, json);
+        let compressed = msg.compressed(Encoding::Json, Version::V1, 6)?;
+        assert_eq!(msg.compressed(Encoding::Json, Version::V1, 6)?.as_ptr(), compressed.as_ptr());
+
+        let responses = SharedResponses::default();
+        let sub = Subscription::L4Book { coin: "BTC".to_string(), conflate_ms: None };
+        let first = responses.for_subscription(&sub, || Some(updates(2)));
+        let second = responses.for_subscription(&sub, || Some(updates(3)));
+        assert!(first.zip(second).is_some_and(|(first, second)| Arc::ptr_eq(&first, &second)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_variants_share_messages() {
+        let l2_book = |n_levels, conflate_ms, fields: &[&str]| Subscription::L2Book {
+            coin: "BTC".to_string(),
+            n_sig_figs: None,
+            n_levels,
+            mantissa: None,
+            conflate_ms,
+            tick_size: None,
+            fields: Some(fields.iter().map(ToString::to_string).collect()),
+            delta: None,
+        };
+        let responses = SharedResponses::default();
+        let mut builds = 0;
+        let mut lookup = |sub: &Subscription| {
+            responses.for_subscription(sub, || {
+                builds += 1;
+                Some(updates(builds))
+            })
+        };
+        let first = lookup(&l2_book(None, None, &["seq", "n"]));
+        // conflated, or with the fields in another order, it is the same message
+        let second = lookup(&l2_book(None, Some(100), &["n", "seq", "n"]));
+        assert!(first.zip(second).is_some_and(|(first, second)| Arc::ptr_eq(&first, &second)));
+        // but not at another depth
+        lookup(&l2_book(Some(5), None, &["seq", "n"]));
+        assert_eq!(builds, 2);
+    }
+
+    #[test]
+    fn test_projected() -> Result<()> {
+        let book = || {
+            let levels = [vec![Level::new("100.0".to_string(), "1.5".to_string(), 2)], Vec::new()];
+            L2Book::from_l2_snapshot("BTC".to_string(), levels, 1, 2)
+        };
+        let fields = ["seq".to_string()];
+        let msg = Outbound::projected(ServerResponse::L2Book(book()), Some(&fields));
+        let json = msg.payload(Encoding::Json, Version::V1, Numbers::String)?;
+        assert_eq!(
+            json,
+            r#"{"channel":"l2Book","data":{"coin":"BTC","levels":[[{"px":"100.0","sz":"1.5"}],[]],"seq":2}}"#
+        );
+        let decoded: Value =
+            rmp_serde::from_slice(&msg.payload(Encoding::MessagePack, Version::V1, Numbers::String)?)?;
+        assert_eq!(decoded, serde_json::from_slice::<Value>(&json)?);
+        // and of each number format
+        assert_eq!(
+            msg.payload(Encoding::Json, Version::V1, Numbers::Integer)?,
+            r#"{"channel":"l2Book","data":{"coin":"BTC","levels":[[{"px":{"units":1000,"decimals":1},"sz":{"units":15,"decimals":1}}],[]],"seq":2}}"#
+        );
+        assert_eq!(msg.payload(Encoding::Json, Version::V1, Numbers::String)?.as_ptr(), json.as_ptr());
+
+        // shared by the connections with the same fields
+        let responses = SharedResponses::default();
+        let sub = Subscription::Bbo { coin: "BTC".to_string(), fields: Some(vec!["n".to_string()]) };
+        let bbo = responses.for_subscription(&sub, || Some(ServerResponse::Bbo(Bbo::from_l2_book(book()))));
+        let json = bbo.ok_or("no bbo")?.payload(Encoding::Json, Version::V1, Numbers::String)?;
+        assert_eq!(json, r#"{"channel":"bbo","data":{"coin":"BTC","bid":{"px":"100.0","sz":"1.5","n":2},"ask":null}}"#);
+
+        // deltas keep what they reference the book they change with
+        let mut delta = Epoch::new(&book());
+        let changed = L2Book::from_l2_snapshot(
+            "BTC".to_string(),
+            [vec![Level::new("100.5".to_string(), "1".to_string(), 1)], Vec::new()],
+            2,
+            3,
+        );
+        let delta = delta.delta(&book(), &changed).ok_or("no delta")?;
+        let msg = Outbound::projected(ServerResponse::L2Delta(delta), Some(&fields));
+        assert_eq!(
+            msg.payload(Encoding::Json, Version::V1, Numbers::Float)?,
+            r#"{"channel":"l2Delta","data":{"coin":"BTC","seq":3,"prevSeq":2,"epoch":2,"changes":[[{"i":0,"sz":0.0},{"i":1,"px":100.5,"sz":1.0}],[]]}}"#
+        );
+
+        // without fields every one is sent
+        let book = ServerResponse::L2Book(book());
+        assert_eq!(
+            Arc::<Outbound>::from(book.clone()).payload(Encoding::Json, Version::V1, Numbers::String)?,
+            serde_json::to_string(&book)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_batch_payload() -> Result<()> {
+        let msgs = (1..=20).map(updates).collect::<Vec<_>>();
+        for encoding in [Encoding::Json, Encoding::MessagePack] {
+            for n in [1, 2, 16, 20] {
+                let payloads = msgs[..n]
+                    .iter()
+                    .map(|msg| Arc::<Outbound>::from(msg.clone()).payload(encoding, Version::V1, Numbers::String))
+                    .collect::<Result<Vec<_>>>()?;
+                let expected = encoding.encode(&&msgs[..n])?.payload;
+                assert_eq!(batch_payload(encoding, &payloads), expected, "{n} messages as {encoding:?}");
+            }
+        }
+
+        let payloads = msgs
+            .iter()
+            .map(|msg| Arc::<Outbound>::from(msg.clone()).payload(Encoding::Protobuf, Version::V1, Numbers::String))
+            .collect::<Result<Vec<_>>>()?;
+        let batch = Batch::decode(batch_payload(Encoding::Protobuf, &payloads))?;
+        let expected = payloads.into_iter().map(ServerMessage::decode).collect::<std::result::Result<Vec<_>, _>>()?;
+        assert_eq!(batch.messages, expected);
+        Ok(())
+    }
+}
@@ -0,0 +1,61 @@
+use alloy::primitives::{Address, Signature};
+
+// how far the timestamp of a proof may be from the server's clock, in ms
+const MAX_PROOF_AGE_MS: u64 = 5 * 60 * 1000;
+
+// the message an account signs to prove that it owns the address, e.g.
+// `userOrders:0x0000000000000000000000000000000000000001:1751430933565`, with the address in lowercase
+pub(crate) fn proof_message(user: &Address, timestamp: u64) -> String {
+    format!("userOrders:{user:#x}:{timestamp}")
+}
+
+// checks that `signature` is the EIP-191 (`personal_sign`) signature of the proof message by `user`, and that the
+// proof is recent, so that one seen by someone else can't be used for long
+pub(crate) fn verify(user: &Address, timestamp: u64, signature: &str, now: u64) -> Result<(), String> {
+    if now.abs_diff(timestamp) > MAX_PROOF_AGE_MS {
+        return Err("the timestamp of the signature is more than 5 minutes off".to_string());
+    }
+    let signature = signature.parse::<Signature>().map_err(|err| format!("invalid signature: {err}"))?;
+    let signer = signature
+        .recover_address_from_msg(proof_message(user, timestamp))
+        .map_err(|err| format!("invalid signature: {err}"))?;
+    if signer != *user {
+        return Err(format!("signed by {signer}, not by {user}"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::{
+        hex,
+        signers::{SignerSync, local::PrivateKeySigner},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        let signer = PrivateKeySigner::random();
+        let user = signer.address();
+        let now = 1_751_430_933_565;
+        let sign = |timestamp| {
+            let signature = signer.sign_message_sync(proof_message(&user, timestamp).as_bytes()).unwrap();
+            hex::encode_prefixed(signature.as_bytes())
+        };
+        assert_eq!(
+            proof_message(&Address::repeat_byte(0xab), now),
+            "userOrders:0xabababababababababababababababababababab:1751430933565"
+        );
+        // what clients sign, with the address as they have it
+        assert_eq!(order_book_client::user_orders_message(&user.to_checksum(None), now), proof_message(&user, now));
+        assert_eq!(verify(&user, now, &sign(now), now), Ok(()));
+        assert_eq!(verify(&user, now - 1000, &sign(now - 1000), now), Ok(()));
+
+        // of another account, proof message or time
+        assert!(verify(&Address::repeat_byte(1), now, &sign(now), now).is_err());
+        assert!(verify(&user, now + 1, &sign(now), now).is_err());
+        assert!(verify(&user, now - MAX_PROOF_AGE_MS - 1, &sign(now - MAX_PROOF_AGE_MS - 1), now).is_err());
+        assert!(verify(&user, now, "0x1234", now).is_err());
+    }
+}
@@ -0,0 +1,270 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    path::PathBuf,
+    sync::RwLock,
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{
+    latency::now_ms,
+    prelude::*,
+    servers::{auth::ConnectionPermit, encoding::Numbers, websocket_server::BATCH_MS_RANGE},
+    types::subscription::{
+        BBO_FIELDS, CONFLATE_MS_RANGE, ClientMessage, DEFAULT_LEVELS, L2_BOOK_FIELDS, MAX_LEVELS, Subscription,
+    },
+};
+
+// changes kept in the audit trail, the oldest are dropped first
+const MAX_CHANGES: usize = 1000;
+
+/// The defaults of the connections of an API key, for what they don't negotiate themselves: the message format
+/// of the connection, and the depth, conflation interval and optional fields of its l2 book and bbo
+/// subscriptions. A connection or subscription that sets its own keeps it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub(crate) struct Preferences {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) numbers: Option<Numbers>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) batch_ms: Option<u64>,
+    // levels per side of l2 books
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) n_levels: Option<usize>,
+    // of l2 books
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) conflate_ms: Option<u64>,
+    // the optional fields of l2 books and bbos, each gets those it has
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) fields: Option<Vec<String>>,
+}
+
+impl Preferences {
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.batch_ms.is_some_and(|batch_ms| !BATCH_MS_RANGE.contains(&batch_ms)) {
+            return Err("batchMs must be between 1 and 1000".into());
+        }
+        if self.n_levels.is_some_and(|n_levels| n_levels == 0 || n_levels > MAX_LEVELS) {
+            return Err(format!("nLevels must be between 1 and {MAX_LEVELS}").into());
+        }
+        if self.conflate_ms.is_some_and(|conflate_ms| !CONFLATE_MS_RANGE.contains(&conflate_ms)) {
+            return Err("conflateMs must be between 10 and 60000".into());
+        }
+        let known = |field: &String| L2_BOOK_FIELDS.contains(&field.as_str()) || BBO_FIELDS.contains(&field.as_str());
+        if let Some(field) = self.fields.iter().flatten().find(|field| !known(field)) {
+            return Err(format!("unknown field {field}").into());
+        }
+        Ok(())
+    }
+
+    // the request with the defaults filled in for its subscription
+    pub(crate) fn apply(&self, mut request: ClientMessage) -> ClientMessage {
+        if let Some(subscription) = request.subscription_mut() {
+            self.apply_to(subscription);
+        }
+        request
+    }
+
+    pub(crate) fn apply_to(&self, subscription: &mut Subscription) {
+        let fields = |known: &[&str]| {
+            self.fields.as_ref().map(|fields| {
+                fields.iter().filter(|field| known.contains(&field.as_str())).cloned().collect::<Vec<_>>()
+            })
+        };
+        match subscription {
+            Subscription::L2Book { n_levels, conflate_ms, fields: own, delta, .. } => {
+                // the default depth is only valid when not set
+                if n_levels.is_none() {
+                    *n_levels = self.n_levels.filter(|n_levels| *n_levels != DEFAULT_LEVELS);
+                }
+                // deltas can't be conflated and need their seqs
+                if *delta == Some(true) {
+                    return;
+                }
+                if conflate_ms.is_none() {
+                    *conflate_ms = self.conflate_ms;
+                }
+                if own.is_none() {
+                    *own = fields(L2_BOOK_FIELDS);
+                }
+            }
+            Subscription::Bbo { fields: own, .. } if own.is_none() => *own = fields(BBO_FIELDS),
+            _ => {}
+        }
+    }
+}
+
+/// A change made through the admin API. `before` is missing for new preferences and `after` for deleted ones.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PreferenceChange {
+    // in ms since the epoch
+    pub(crate) time: u64,
+    pub(crate) key: String,
+    // the admin credential's identity, if the admin API requires one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) by: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) before: Option<Preferences>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) after: Option<Preferences>,
+}
+
+// the contents of the file
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Stored {
+    #[serde(default)]
+    preferences: BTreeMap<String, Preferences>,
+    #[serde(default)]
+    changes: VecDeque<PreferenceChange>,
+}
+
+/// The preferences of every API key that has any, by the name of its identity (`<tenant>:<name>` for identities of
+/// a tenant), with the trail of changes to them. Saved to `path` on every change when set.
+#[derive(Debug, Default)]
+pub(crate) struct PreferenceStore {
+    path: Option<PathBuf>,
+    stored: RwLock<Stored>,
+}
+
+impl PreferenceStore {
+    // starts out empty if the file doesn't exist yet
+    pub(crate) fn load(path: Option<PathBuf>) -> Result<Self> {
+        let stored = match &path {
+            Some(file) => match fs::read_to_string(file) {
+                Ok(contents) => serde_json::from_str(&contents)
+                    .map_err(|err| format!("Invalid preferences file {}: {err}", file.display()))?,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => Stored::default(),
+                Err(err) => return Err(format!("Unable to read preferences {}: {err}", file.display()).into()),
+            },
+            None => Stored::default(),
+        };
+        Ok(Self { path, stored: RwLock::new(stored) })
+    }
+
+    pub(crate) fn key(tenant: Option<&str>, name: &str) -> String {
+        tenant.map_or_else(|| name.to_string(), |tenant| format!("{tenant}:{name}"))
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Option<Preferences> {
+        self.stored.read().ok()?.preferences.get(key).cloned()
+    }
+
+    // those of the connection's API key, none for connections without one
+    pub(crate) fn for_permit(&self, permit: Option<&ConnectionPermit>) -> Preferences {
+        permit
+            .and_then(|permit| self.get(&Self::key(permit.tenant().map(|tenant| tenant.name.as_str()), permit.name())))
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn all(&self) -> BTreeMap<String, Preferences> {
+        self.stored.read().map(|stored| stored.preferences.clone()).unwrap_or_default()
+    }
+
+    pub(crate) fn changes(&self) -> Vec<PreferenceChange> {
+        self.stored.read().map(|stored| stored.changes.iter().cloned().collect()).unwrap_or_default()
+    }
+
+    // sets the key's preferences, or removes them with None, returning what they were
+    pub(crate) fn set(
+        &self,
+        key: &str,
+        preferences: Option<Preferences>,
+        by: Option<String>,
+    ) -> Result<Option<Preferences>> {
+        if let Some(preferences) = &preferences {
+            preferences.validate()?;
+        }
+        let mut stored = self.stored.write().map_err(|_| "Preferences unavailable")?;
+        let before = match preferences.clone() {
+            Some(preferences) => stored.preferences.insert(key.to_string(), preferences),
+            None => stored.preferences.remove(key),
+        };
+        if before == preferences {
+            return Ok(before);
+        }
+        info!("Preferences of {key} changed from {before:?} to {preferences:?}");
+        let change =
+            PreferenceChange { time: now_ms(), key: key.to_string(), by, before: before.clone(), after: preferences };
+        stored.changes.push_back(change);
+        if stored.changes.len() > MAX_CHANGES {
+            stored.changes.pop_front();
+        }
+        if let Some(path) = &self.path {
+            // a crash while writing leaves the previous file in place
+            let tmp = path.with_extension("tmp");
+            // and the lock is held until it is written, so that the file ends up with the last change
+            fs::write(&tmp, serde_json::to_vec_pretty(&*stored)?)?;
+            fs::rename(tmp, path)?;
+        }
+        drop(stored);
+        Ok(before)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_preferences() -> Result<()> {
+        let preferences = Preferences {
+            n_levels: Some(50),
+            conflate_ms: Some(100),
+            fields: Some(vec!["mid".to_string(), "seq".to_string()]),
+            ..Preferences::default()
+        };
+        let l2 = r#"{"method":"subscribe","subscription":{"type":"l2Book","coin":"BTC","nSigFigs":null,"nLevels":null,"mantissa":null}}"#;
+        let request = preferences.apply(serde_json::from_str(l2)?);
+        let ClientMessage::Subscribe { subscription: Subscription::L2Book { n_levels, conflate_ms, fields, .. } } =
+            request
+        else {
+            panic!("not an l2 book subscription: {request:?}");
+        };
+        assert_eq!((n_levels, conflate_ms), (Some(50), Some(100)));
+        assert_eq!(fields, Some(vec!["seq".to_string()]));
+        // delta subscriptions only take the depth
+        let deltas = r#"{"type":"l2Book","coin":"BTC","nSigFigs":null,"nLevels":null,"mantissa":null,"delta":true}"#;
+        let mut deltas: Subscription = serde_json::from_str(deltas)?;
+        preferences.apply_to(&mut deltas);
+        assert!(matches!(deltas, Subscription::L2Book { n_levels: Some(50), conflate_ms: None, fields: None, .. }));
+        // the subscription's own settings stay
+        let mut bbo = Subscription::Bbo { coin: "BTC".to_string(), fields: Some(Vec::new()) };
+        preferences.apply_to(&mut bbo);
+        assert_eq!(bbo, Subscription::Bbo { coin: "BTC".to_string(), fields: Some(Vec::new()) });
+        let mut bbo = Subscription::Bbo { coin: "BTC".to_string(), fields: None };
+        preferences.apply_to(&mut bbo);
+        assert_eq!(
+            bbo,
+            Subscription::Bbo { coin: "BTC".to_string(), fields: Some(vec!["mid".to_string(), "seq".to_string()]) }
+        );
+
+        assert!(Preferences { n_levels: Some(MAX_LEVELS + 1), ..Preferences::default() }.validate().is_err());
+        assert!(Preferences { batch_ms: Some(0), ..Preferences::default() }.validate().is_err());
+        assert!(Preferences { fields: Some(vec!["size".to_string()]), ..Preferences::default() }.validate().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_preference_store() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("preferences.json");
+        let store = PreferenceStore::load(Some(path.clone()))?;
+        let preferences = Preferences { numbers: Some(Numbers::Float), batch_ms: Some(5), ..Preferences::default() };
+        assert_eq!(store.set("acme:alice", Some(preferences.clone()), Some("ops".to_string()))?, None);
+        assert!(store.set("bob", Some(Preferences { batch_ms: Some(5000), ..Preferences::default() }), None).is_err());
+        assert_eq!(PreferenceStore::key(Some("acme"), "alice"), "acme:alice");
+
+        // kept across restarts, with their changes
+        let store = PreferenceStore::load(Some(path))?;
+        assert_eq!(store.get("acme:alice"), Some(preferences.clone()));
+        assert_eq!(store.set("acme:alice", None, None)?, Some(preferences.clone()));
+        let changes = store.changes();
+        assert_eq!(changes.len(), 2);
+        assert_eq!((changes[0].by.as_deref(), &changes[0].after), (Some("ops"), &Some(preferences.clone())));
+        assert_eq!((&changes[1].before, &changes[1].after), (&Some(preferences), &None));
+        assert!(store.all().is_empty());
+        Ok(())
+    }
+}
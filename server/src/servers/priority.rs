@@ -0,0 +1,171 @@
+use std::{
+    fmt,
+    str::FromStr,
+    sync::{
+        Arc, LazyLock,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::prelude::*;
+
+static LOAD: LazyLock<Arc<DeliveryLoad>> = LazyLock::new(Arc::default);
+
+/// Delivery class of an identity's connections, for [`LoadShedding`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    /// Shed first, e.g. public dashboards.
+    Low,
+    #[default]
+    Normal,
+    /// Never shed, e.g. internal market makers.
+    High,
+}
+
+impl FromStr for Priority {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "low" => Ok(Self::Low),
+            "normal" => Ok(Self::Normal),
+            "high" => Ok(Self::High),
+            _ => Err(format!("unknown priority {s} (expected low, normal or high)")),
+        }
+    }
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Low => "low",
+            Self::Normal => "normal",
+            Self::High => "high",
+        })
+    }
+}
+
+/// Sheds the stream data of lower priority connections first when the server falls behind.
+///
+/// High priority connections keep getting every message. Load is the share of the send queue capacity of all
+/// connections together that holds messages waiting to be written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LoadShedding {
+    /// From this load (in percent) on, the stream data of low priority connections is conflated in their queues
+    /// like with [`BackpressurePolicy::Conflate`](crate::BackpressurePolicy::Conflate).
+    pub conflate_percent: u8,
+    /// From this load on, low priority connections also drop their oldest messages beyond a quarter of their queue,
+    /// and the stream data of normal priority connections is conflated.
+    pub drop_percent: u8,
+}
+
+impl Default for LoadShedding {
+    fn default() -> Self {
+        Self { conflate_percent: 25, drop_percent: 50 }
+    }
+}
+
+impl LoadShedding {
+    pub(crate) fn validate(self) -> Result<()> {
+        if self.conflate_percent == 0 || self.conflate_percent > self.drop_percent || self.drop_percent > 100 {
+            return Err("load shedding thresholds have to be 1 to 100 percent, conflating at most at dropping".into());
+        }
+        Ok(())
+    }
+
+    pub(crate) fn shedding(self, priority: Priority, load: &DeliveryLoad) -> Shedding {
+        match priority {
+            Priority::Low if load.is_above(self.drop_percent) => Shedding::Drop,
+            Priority::Low if load.is_above(self.conflate_percent) => Shedding::Conflate,
+            Priority::Normal if load.is_above(self.drop_percent) => Shedding::Conflate,
+            _ => Shedding::None,
+        }
+    }
+}
+
+// what happens to a connection's stream data at the current load
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Shedding {
+    None,
+    Conflate,
+    // conflated, and the oldest messages beyond a quarter of the queue dropped
+    Drop,
+}
+
+// the messages waiting in the send queues of all connections, and how many they hold at most
+#[derive(Debug, Default)]
+pub(crate) struct DeliveryLoad {
+    queued: AtomicUsize,
+    capacity: AtomicUsize,
+    // set by the watchdog to conflate the stream data of every connection, whatever its priority
+    conflate_all: AtomicBool,
+}
+
+impl DeliveryLoad {
+    // shared by every connection of the process
+    pub(crate) fn global() -> Arc<Self> {
+        LOAD.clone()
+    }
+
+    pub(crate) fn add_queue(&self, capacity: usize) {
+        self.capacity.fetch_add(capacity, Ordering::Relaxed);
+    }
+
+    // when the queue is dropped with `queued` messages left in it
+    pub(crate) fn remove_queue(&self, capacity: usize, queued: usize) {
+        self.capacity.fetch_sub(capacity, Ordering::Relaxed);
+        self.taken(queued);
+    }
+
+    pub(crate) fn queued(&self, n: usize) {
+        self.queued.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub(crate) fn taken(&self, n: usize) {
+        self.queued.fetch_sub(n, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_conflate_all(&self, conflate: bool) {
+        self.conflate_all.store(conflate, Ordering::Relaxed);
+    }
+
+    pub(crate) fn is_conflating_all(&self) -> bool {
+        self.conflate_all.load(Ordering::Relaxed)
+    }
+
+    fn is_above(&self, percent: u8) -> bool {
+        let capacity = self.capacity.load(Ordering::Relaxed);
+        capacity > 0 && self.queued.load(Ordering::Relaxed) * 100 >= capacity * usize::from(percent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shedding_by_priority() {
+        let shedding = LoadShedding::default();
+        let load = DeliveryLoad::default();
+        load.add_queue(100);
+        let at = |queued: usize| {
+            load.queued(queued);
+            let tiers =
+                [Priority::Low, Priority::Normal, Priority::High].map(|priority| shedding.shedding(priority, &load));
+            load.taken(queued);
+            tiers
+        };
+        assert_eq!(at(10), [Shedding::None; 3]);
+        assert_eq!(at(25), [Shedding::Conflate, Shedding::None, Shedding::None]);
+        assert_eq!(at(80), [Shedding::Drop, Shedding::Conflate, Shedding::None]);
+
+        load.remove_queue(100, 0);
+        assert_eq!(at(80), [Shedding::None; 3]);
+        assert!(LoadShedding { conflate_percent: 60, drop_percent: 50 }.validate().is_err());
+        assert_eq!("high".parse::<Priority>(), Ok(Priority::High));
+    }
+}
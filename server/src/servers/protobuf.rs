@@ -0,0 +1,239 @@
+use bytes::Bytes;
+use prost::Message;
+
+use crate::{
+    order_book::types::Side,
+    prelude::*,
+    servers::{encoding::Version, protocol::Versioned},
+    types::{self, delta, subscription::ServerResponse},
+};
+
+// `Batch` is only for clients to decode the frames of batching connections with
+#[allow(clippy::all, clippy::pedantic, clippy::nursery, dead_code, unreachable_pub, unused_qualifications)]
+pub(crate) mod proto {
+    tonic::include_proto!("orderbook.v1");
+}
+
+use proto::{Bbo, L2Book, L2Delta, Level, LevelChange, ServerMessage, Trade, Trades, server_message};
+
+/// The payload of a binary frame of a connection that negotiated protobuf, a [`ServerMessage`]. `fields` are the
+/// optional fields of the message that are sent, the others are left at their default, which protobuf doesn't send.
+pub(crate) fn encode(msg: &ServerResponse, version: Version, fields: Option<&[String]>) -> Result<Bytes> {
+    let listed = |field: &str| fields.is_none_or(|fields| fields.iter().any(|listed| listed == field));
+    let message = match msg {
+        ServerResponse::L2Book(book) => {
+            let mut book = L2Book::from(book);
+            if !listed("time") {
+                book.time = 0;
+            }
+            if !listed("seq") {
+                book.seq = 0;
+            }
+            if !listed("checksum") {
+                book.checksum = 0;
+            }
+            if !listed("n") {
+                book.bids.iter_mut().chain(&mut book.asks).for_each(|level| level.n = 0);
+            }
+            server_message::Message::L2Book(book)
+        }
+        ServerResponse::L2Delta(delta) => {
+            let mut delta = L2Delta::from(delta);
+            if !listed("time") {
+                delta.time = 0;
+            }
+            if !listed("seq") {
+                delta.seq = 0;
+            }
+            if !listed("checksum") {
+                delta.checksum = 0;
+            }
+            if !listed("n") {
+                delta.bids.iter_mut().chain(&mut delta.asks).for_each(|change| change.n = 0);
+            }
+            server_message::Message::L2Delta(delta)
+        }
+        ServerResponse::Bbo(bbo) => {
+            let mut bbo = Bbo::from(bbo);
+            if !listed("time") {
+                bbo.time = 0;
+            }
+            if !listed("seq") {
+                bbo.seq = 0;
+            }
+            if !listed("mid") {
+                bbo.mid = None;
+            }
+            if !listed("n") {
+                bbo.bid.iter_mut().chain(&mut bbo.ask).for_each(|level| level.n = 0);
+            }
+            server_message::Message::Bbo(bbo)
+        }
+        ServerResponse::Trades(trades) => {
+            server_message::Message::Trades(Trades { trades: trades.iter().map(Trade::from).collect() })
+        }
+        msg => server_message::Message::Json(serde_json::to_string(&Versioned::new(msg, version))?),
+    };
+    Ok(payload(message))
+}
+
+// any other message, as its JSON
+pub(crate) fn encode_json(json: String) -> Bytes {
+    payload(server_message::Message::Json(json))
+}
+
+fn payload(message: server_message::Message) -> Bytes {
+    ServerMessage { message: Some(message) }.encode_to_vec().into()
+}
+
+impl From<&types::Level> for Level {
+    fn from(level: &types::Level) -> Self {
+        Self { px: level.px.clone(), sz: level.sz.clone(), n: level.n as u64 }
+    }
+}
+
+impl From<&types::L2Book> for L2Book {
+    fn from(book: &types::L2Book) -> Self {
+        let [bids, asks] = &book.levels;
+        Self {
+            coin: book.coin.clone(),
+            time: book.time,
+            seq: book.seq,
+            bids: bids.iter().map(Level::from).collect(),
+            asks: asks.iter().map(Level::from).collect(),
+            checksum: book.checksum,
+        }
+    }
+}
+
+impl From<&delta::LevelChange> for LevelChange {
+    fn from(change: &delta::LevelChange) -> Self {
+        Self { i: change.i as u64, px: change.px.clone(), sz: change.sz.clone(), n: change.n as u64 }
+    }
+}
+
+impl From<&delta::L2Delta> for L2Delta {
+    fn from(delta: &delta::L2Delta) -> Self {
+        let [bids, asks] = &delta.changes;
+        Self {
+            coin: delta.coin.clone(),
+            time: delta.time,
+            seq: delta.seq,
+            prev_seq: delta.prev_seq,
+            epoch: delta.epoch,
+            bids: bids.iter().map(LevelChange::from).collect(),
+            asks: asks.iter().map(LevelChange::from).collect(),
+            checksum: delta.checksum,
+        }
+    }
+}
+
+impl From<&types::Bbo> for Bbo {
+    fn from(bbo: &types::Bbo) -> Self {
+        Self {
+            coin: bbo.coin.clone(),
+            time: bbo.time,
+            seq: bbo.seq,
+            bid: bbo.bid.as_ref().map(Level::from),
+            ask: bbo.ask.as_ref().map(Level::from),
+            mid: bbo.mid.clone(),
+        }
+    }
+}
+
+impl From<&types::Trade> for Trade {
+    fn from(trade: &types::Trade) -> Self {
+        let side = match trade.side {
+            Side::Ask => "A",
+            Side::Bid => "B",
+        };
+        Self {
+            coin: trade.coin.clone(),
+            side: side.to_string(),
+            px: trade.px.clone(),
+            sz: trade.sz.clone(),
+            hash: trade.hash.clone(),
+            time: trade.time,
+            tid: trade.tid,
+            users: trade.users.iter().map(|user| format!("{user:#x}")).collect(),
+            taker: format!("{:#x}", trade.taker),
+            maker: format!("{:#x}", trade.maker),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::{proto::server_message::Message as Data, *};
+    use crate::types::delta::Epoch;
+
+    const TRADES: &str = r#"{"channel":"trades","data":[{"coin":"BTC","side":"A","px":"106296.0","sz":"0.00017","time":1751430933565,"hash":"0xde93a8a0729ade63d8840417805ba9010b008818422ddedb1285744426b73503","tid":293353986402527,"users":["0xcc0a3b6e3267c84361e91d8230868eea53431e4b","0xc64cc00b46101bd40aa1c3121195e85c0b0918d8"]}]}"#;
+
+    fn decode(payload: &Bytes) -> Result<Data> {
+        Ok(ServerMessage::decode(payload.as_ref())?.message.ok_or("no message")?)
+    }
+
+    fn book(seq: u64, px: &str) -> types::L2Book {
+        let levels = [vec![types::Level::new(px.to_string(), "1.5".to_string(), 2)], Vec::new()];
+        types::L2Book::from_l2_snapshot("BTC".to_string(), levels, 1, seq)
+    }
+
+    #[test]
+    fn test_books() -> Result<()> {
+        let Data::L2Book(decoded) = decode(&encode(&ServerResponse::L2Book(book(2, "100.0")), Version::V1, None)?)?
+        else {
+            return Err("not an l2 book".into());
+        };
+        assert_eq!(decoded, L2Book::from(&book(2, "100.0")));
+        assert_eq!((decoded.seq, decoded.bids[0].px.as_str(), decoded.bids[0].n), (2, "100.0", 2));
+        assert!(decoded.asks.is_empty());
+
+        // the fields that aren't listed are left out
+        let fields = ["seq".to_string()];
+        let Data::L2Book(projected) =
+            decode(&encode(&ServerResponse::L2Book(book(2, "100.0")), Version::V1, Some(&fields))?)?
+        else {
+            return Err("not an l2 book".into());
+        };
+        assert_eq!((projected.seq, projected.time, projected.checksum, projected.bids[0].n), (2, 0, 0, 0));
+
+        let delta = Epoch::new(&book(2, "100.0")).delta(&book(2, "100.0"), &book(3, "100.5")).ok_or("no delta")?;
+        let Data::L2Delta(decoded) = decode(&encode(&ServerResponse::L2Delta(delta.clone()), Version::V1, None)?)?
+        else {
+            return Err("not an l2 delta".into());
+        };
+        assert_eq!((decoded.seq, decoded.prev_seq, decoded.epoch), (delta.seq, delta.prev_seq, delta.epoch));
+        let changes = decoded.bids.iter().map(|change| (change.i, change.px.as_deref(), change.sz.as_str()));
+        assert_eq!(changes.collect::<Vec<_>>(), [(0, None, "0"), (1, Some("100.5"), "1.5")]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_trades_are_those_of_json() -> Result<()> {
+        let msg: ServerResponse = serde_json::from_str(TRADES)?;
+        let Data::Trades(Trades { trades }) = decode(&encode(&msg, Version::V1, None)?)? else {
+            return Err("not trades".into());
+        };
+        let json = serde_json::to_value(&msg)?;
+        let trade = &json["data"][0];
+        assert_eq!(trades[0].side, trade["side"].as_str().unwrap_or_default());
+        assert_eq!(trades[0].users, trade["users"].as_array().into_iter().flatten().cloned().collect::<Vec<_>>());
+        assert_eq!(Value::from(trades[0].taker.as_str()), trade["taker"]);
+        assert_eq!((trades[0].tid, trades[0].px.as_str()), (293_353_986_402_527, "106296.0"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_other_messages_as_json() -> Result<()> {
+        let msg = ServerResponse::Error("Invalid subscription".to_string());
+        for version in [Version::V1, Version::V2] {
+            let Data::Json(json) = decode(&encode(&msg, version, None)?)? else {
+                return Err("not json".into());
+            };
+            assert_eq!(json, serde_json::to_string(&Versioned::new(&msg, version))?);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,224 @@
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::Value;
+
+use crate::{
+    servers::encoding::Version,
+    types::subscription::{ClientMessage, ServerResponse},
+};
+
+// the methods of `ClientMessage`, to tell an unknown method from a malformed request
+const METHODS: [&str; 12] = [
+    "subscribe",
+    "unsubscribe",
+    "snapshot",
+    "auth",
+    "replay",
+    "resume",
+    "listMarkets",
+    "getSnapshot",
+    "getMarkets",
+    "serverTime",
+    "ping",
+    "stats",
+];
+
+/// Why a client message was rejected. Sent as its number, see the table in the README.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "u16", try_from = "u16")]
+pub(crate) enum ErrorCode {
+    // requests are JSON text frames, whatever the encoding of the responses
+    BinaryMessage = 1000,
+    InvalidUtf8 = 1001,
+    InvalidJson = 1002,
+    UnknownMethod = 1003,
+    // the method is known, but its fields are missing, of the wrong type or don't go together
+    InvalidRequest = 1004,
+    MessageTooLarge = 1005,
+    // a readable request the server couldn't serve, e.g. for an unknown coin. Version 2 only, the errors of
+    // version 1 connections go to the `error` channel, unless the request had an `id` to answer
+    Rejected = 1006,
+}
+
+impl From<ErrorCode> for u16 {
+    fn from(code: ErrorCode) -> Self {
+        code as Self
+    }
+}
+
+impl TryFrom<u16> for ErrorCode {
+    type Error = String;
+
+    fn try_from(code: u16) -> Result<Self, String> {
+        [
+            Self::BinaryMessage,
+            Self::InvalidUtf8,
+            Self::InvalidJson,
+            Self::UnknownMethod,
+            Self::InvalidRequest,
+            Self::MessageTooLarge,
+            Self::Rejected,
+        ]
+        .into_iter()
+        .find(|known| u16::from(*known) == code)
+        .ok_or_else(|| format!("unknown error code {code}"))
+    }
+}
+
+/// A rejected client message, sent as `{"error": {"code": ..., "msg": ...}}`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct ProtocolError {
+    pub(crate) code: ErrorCode,
+    pub(crate) msg: String,
+}
+
+impl ProtocolError {
+    pub(crate) fn new(code: ErrorCode, msg: impl Into<String>) -> Self {
+        Self { code, msg: msg.into() }
+    }
+}
+
+impl From<ProtocolError> for ServerResponse {
+    fn from(error: ProtocolError) -> Self {
+        Self::ProtocolError { error }
+    }
+}
+
+// a message in the format of a connection's version
+pub(crate) struct Versioned<'a> {
+    msg: &'a ServerResponse,
+    version: Version,
+}
+
+impl<'a> Versioned<'a> {
+    pub(crate) const fn new(msg: &'a ServerResponse, version: Version) -> Self {
+        Self { msg, version }
+    }
+}
+
+impl Serialize for Versioned<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match (self.version, self.msg) {
+            (Version::V2, ServerResponse::Error(msg)) => {
+                ServerResponse::from(ProtocolError::new(ErrorCode::Rejected, msg.clone())).serialize(serializer)
+            }
+            (Version::V1 | Version::V2, msg) => msg.serialize(serializer),
+        }
+    }
+}
+
+// a text frame from a client as a request, validated as far as it doesn't depend on the server's state
+pub(crate) fn parse_request(payload: &[u8]) -> Result<ClientMessage, ProtocolError> {
+    let text =
+        std::str::from_utf8(payload).map_err(|err| ProtocolError::new(ErrorCode::InvalidUtf8, err.to_string()))?;
+    let value = serde_json::from_str::<Value>(text)
+        .map_err(|err| ProtocolError::new(ErrorCode::InvalidJson, err.to_string()))?;
+    let method = match value.get("method") {
+        Some(Value::String(method)) => method,
+        Some(_) => return Err(ProtocolError::new(ErrorCode::InvalidRequest, "method has to be a string")),
+        None => return Err(ProtocolError::new(ErrorCode::InvalidRequest, "missing method")),
+    };
+    if !METHODS.contains(&method.as_str()) {
+        return Err(ProtocolError::new(ErrorCode::UnknownMethod, format!("unknown method {method:?}")));
+    }
+    let request = serde_json::from_value::<ClientMessage>(value)
+        .map_err(|err| ProtocolError::new(ErrorCode::InvalidRequest, err.to_string()))?;
+    if let ClientMessage::Replay { from_seq, from_ts, .. } = &request
+        && from_seq.is_some() == from_ts.is_some()
+    {
+        return Err(ProtocolError::new(ErrorCode::InvalidRequest, "replay takes one of fromSeq and fromTs"));
+    }
+    Ok(request)
+}
+
+/// Parses `data` as a client message, and checks that the result can be sent back: a parsed request still parses
+/// once serialized again, and an error serializes. For the fuzz targets in `fuzz/`.
+#[cfg(feature = "fuzzing")]
+pub fn fuzz_parse_request(data: &[u8]) {
+    match parse_request(data) {
+        Ok(request) => {
+            let json = serde_json::to_vec(&request).unwrap_or_default();
+            assert!(parse_request(&json).is_ok(), "{request:?} doesn't parse again");
+        }
+        Err(err) => assert!(serde_json::to_vec(&ServerResponse::from(err)).is_ok()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    use super::*;
+    use crate::prelude::*;
+
+    fn code(payload: &[u8]) -> Option<ErrorCode> {
+        parse_request(payload).err().map(|err| err.code)
+    }
+
+    #[test]
+    fn test_parse_request() -> Result<()> {
+        let subscribe = br#"{"method":"subscribe","subscription":{"type":"trades","coin":"BTC"}}"#;
+        assert!(matches!(parse_request(subscribe), Ok(ClientMessage::Subscribe { .. })));
+        assert_eq!(code(b"\xff"), Some(ErrorCode::InvalidUtf8));
+        assert_eq!(code(b"{\"method\":"), Some(ErrorCode::InvalidJson));
+        assert_eq!(code(br#"{"method":"post"}"#), Some(ErrorCode::UnknownMethod));
+        assert_eq!(code(br#"{"method":7}"#), Some(ErrorCode::InvalidRequest));
+        assert_eq!(code(b"[]"), Some(ErrorCode::InvalidRequest));
+        assert_eq!(
+            code(br#"{"method":"subscribe","subscription":{"type":"trades"}}"#),
+            Some(ErrorCode::InvalidRequest)
+        );
+        let replay = br#"{"method":"replay","subscription":{"type":"l4Book","coin":"BTC"},"fromSeq":1,"fromTs":2}"#;
+        assert_eq!(code(replay), Some(ErrorCode::InvalidRequest));
+        let resume = br#"{"method":"resume","subscription":{"type":"l4Book","coin":"BTC"},"fromSeq":7}"#;
+        assert!(matches!(parse_request(resume), Ok(ClientMessage::Resume { from_seq: 7, .. })));
+        assert!(matches!(parse_request(br#"{"method":"listMarkets"}"#), Ok(ClientMessage::ListMarkets)));
+        let get_snapshot = br#"{"method":"getSnapshot","id":"a","subscription":{"type":"l4Book","coin":"BTC"}}"#;
+        assert!(matches!(parse_request(get_snapshot), Ok(ClientMessage::GetSnapshot { .. })));
+        assert!(matches!(parse_request(br#"{"method":"ping","id":1}"#), Ok(ClientMessage::Ping { .. })));
+        assert_eq!(code(br#"{"method":"ping"}"#), Some(ErrorCode::InvalidRequest));
+        assert_eq!(code(br#"{"method":"serverTime","id":-1}"#), Some(ErrorCode::InvalidRequest));
+
+        let error = ServerResponse::from(ProtocolError::new(ErrorCode::UnknownMethod, "unknown method \"post\""));
+        let json = serde_json::to_string(&error)?;
+        assert_eq!(json, r#"{"error":{"code":1003,"msg":"unknown method \"post\""}}"#);
+        assert!(matches!(serde_json::from_str(&json)?, ServerResponse::ProtocolError { .. }));
+        Ok(())
+    }
+
+    #[test]
+    fn test_versioned_errors() -> Result<()> {
+        let msg = ServerResponse::Error("Invalid subscription: BTC".to_string());
+        let v1 = serde_json::to_string(&Versioned::new(&msg, Version::V1))?;
+        assert_eq!(v1, r#"{"channel":"error","data":"Invalid subscription: BTC"}"#);
+        let v2 = serde_json::to_string(&Versioned::new(&msg, Version::V2))?;
+        assert_eq!(v2, r#"{"error":{"code":1006,"msg":"Invalid subscription: BTC"}}"#);
+        let trades = ServerResponse::Trades(Vec::new());
+        assert_eq!(serde_json::to_string(&Versioned::new(&trades, Version::V2))?, serde_json::to_string(&trades)?);
+        Ok(())
+    }
+
+    // random bytes and mutations of valid requests are answered, never panicked on
+    #[test]
+    fn test_parse_request_garbage() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let valid = br#"{"method":"replay","subscription":{"type":"l2Book","coin":"BTC","nSigFigs":5},"fromSeq":1}"#;
+        for _ in 0..10_000 {
+            let mut payload = valid.to_vec();
+            for _ in 0..rng.random_range(1..8) {
+                let i = rng.random_range(0..payload.len());
+                match rng.random_range(0..3) {
+                    0 => payload[i] = rng.random(),
+                    1 => {
+                        payload.remove(i);
+                    }
+                    _ => payload.insert(i, b"{}[]\":,0"[rng.random_range(0..8)]),
+                }
+            }
+            let _unused = parse_request(&payload);
+            let random = (0..rng.random_range(0..64)).map(|_| rng.random()).collect::<Vec<u8>>();
+            assert!(parse_request(&random).is_err());
+        }
+        let nested = format!("{}{}", "[".repeat(100_000), "]".repeat(100_000));
+        assert_eq!(code(nested.as_bytes()), Some(ErrorCode::InvalidJson));
+    }
+}
@@ -0,0 +1,292 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::HeaderMap,
+    middleware::Next,
+    response::Response,
+    serve::Listener,
+};
+use tokio::{
+    io::AsyncReadExt,
+    net::{TcpListener, TcpStream},
+    sync::mpsc::{Receiver, Sender, channel},
+    time::timeout,
+};
+use tracing::{error, info};
+
+use crate::{
+    prelude::*,
+    servers::{rate_limit::PeerAddr, socket::SocketOptions},
+};
+
+const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+const PENDING_CONNECTIONS: usize = 128;
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+// longest v1 header, including the CRLF
+const V1_MAX_LEN: usize = 107;
+
+/// How clients reach the server through a load balancer or reverse proxy.
+#[derive(Debug, Clone, Default)]
+pub struct ProxyConfig {
+    /// Expect a PROXY protocol (v1 or v2) header ahead of every connection to the websocket port, and take the
+    /// client's address from it. Connections without one are refused.
+    pub protocol: bool,
+    /// Proxies whose `X-Forwarded-For` header gives the client's address.
+    pub trusted: Vec<TrustedProxy>,
+}
+
+/// A proxy, or a network of them (e.g. `10.0.0.0/8`), whose `X-Forwarded-For` header is trusted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrustedProxy {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl TrustedProxy {
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix_len)).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix_len)).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for TrustedProxy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let err = || format!("invalid proxy {s} (expected an IP address or a network like 10.0.0.0/8)");
+        let (network, prefix_len) = s.split_once('/').map_or((s, None), |(network, len)| (network, Some(len)));
+        let network = network.parse::<IpAddr>().map_err(|_| err())?.to_canonical();
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len.parse().ok().filter(|len| *len <= max_len).ok_or_else(err)?,
+            None => max_len,
+        };
+        Ok(Self { network, prefix_len })
+    }
+}
+
+// the client behind trusted proxies: the rightmost address of `X-Forwarded-For` that isn't a trusted proxy, as
+// the addresses left of it were given by the client. None if the request doesn't come from a trusted proxy
+fn forwarded_for(peer: IpAddr, headers: &HeaderMap, trusted: &[TrustedProxy]) -> Option<IpAddr> {
+    let is_trusted = |ip: IpAddr| trusted.iter().any(|proxy| proxy.contains(ip));
+    if !is_trusted(peer) {
+        return None;
+    }
+    let hops = headers.get_all("x-forwarded-for").iter().filter_map(|value| value.to_str().ok());
+    let hops = hops.flat_map(|value| value.split(',')).map(str::trim).collect::<Vec<_>>();
+    let mut client = None;
+    for hop in hops.into_iter().rev() {
+        let ip = hop.parse::<IpAddr>().or_else(|_| hop.parse::<SocketAddr>().map(|addr| addr.ip())).ok()?;
+        client = Some(ip);
+        if !is_trusted(ip) {
+            break;
+        }
+    }
+    client
+}
+
+// axum middleware: replaces the peer address of requests from a trusted proxy by the client it forwards for.
+// The client's port isn't known, so it is 0
+pub(crate) async fn resolve_client(
+    State(trusted): State<Arc<[TrustedProxy]>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if let Some(ConnectInfo(PeerAddr(peer, wire))) = request.extensions().get::<ConnectInfo<PeerAddr>>().cloned()
+        && let Some(ip) = forwarded_for(peer.ip(), request.headers(), &trusted)
+    {
+        request.extensions_mut().insert(ConnectInfo(PeerAddr(SocketAddr::new(ip, 0), wire)));
+    }
+    next.run(request).await
+}
+
+// reads the PROXY protocol header (https://www.haproxy.org/download/3.0/doc/proxy-protocol.txt) a load balancer
+// sends ahead of the connection's data, and returns the client's address. Connections the load balancer opens
+// itself, e.g. for health checks, keep their own address
+pub(crate) async fn read_proxy_header(stream: &mut TcpStream, peer: SocketAddr) -> Result<SocketAddr> {
+    let mut start = [0; 6];
+    stream.read_exact(&mut start).await?;
+    if &start == b"PROXY " {
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LEN {
+                return Err("PROXY header too long".into());
+            }
+            line.push(stream.read_u8().await?);
+        }
+        return parse_v1(&String::from_utf8(line)?, peer);
+    }
+    if start != V2_SIGNATURE[..6] {
+        return Err("missing PROXY protocol header".into());
+    }
+    let mut header = [0; 10];
+    stream.read_exact(&mut header).await?;
+    if header[..6] != V2_SIGNATURE[6..] || header[6] >> 4 != 2 {
+        return Err("invalid PROXY protocol v2 header".into());
+    }
+    let mut addresses = vec![0; usize::from(u16::from_be_bytes([header[8], header[9]]))];
+    stream.read_exact(&mut addresses).await?;
+    // LOCAL connections and address families other than IPv4 and IPv6 carry no client address
+    if header[6] == 0x20 {
+        return Ok(peer);
+    }
+    let source = match (header[7] >> 4, addresses.as_slice()) {
+        (1, addresses) if addresses.len() >= 12 => {
+            let ip: [u8; 4] = addresses[..4].try_into()?;
+            SocketAddr::new(Ipv4Addr::from(ip).into(), u16::from_be_bytes([addresses[8], addresses[9]]))
+        }
+        (2, addresses) if addresses.len() >= 36 => {
+            let ip: [u8; 16] = addresses[..16].try_into()?;
+            SocketAddr::new(Ipv6Addr::from(ip).into(), u16::from_be_bytes([addresses[32], addresses[33]]))
+        }
+        (1 | 2, _) => return Err("truncated PROXY protocol v2 addresses".into()),
+        _ => peer,
+    };
+    Ok(source)
+}
+
+// `PROXY TCP4 <source> <destination> <source port> <destination port>\r\n`, or `PROXY UNKNOWN ...\r\n`
+fn parse_v1(line: &str, peer: SocketAddr) -> Result<SocketAddr> {
+    let mut fields = line.trim_end().split(' ').skip(1);
+    match fields.next() {
+        Some("TCP4" | "TCP6") => {
+            let (Some(source), Some(_), Some(port)) = (fields.next(), fields.next(), fields.next()) else {
+                return Err(format!("invalid PROXY header {line:?}").into());
+            };
+            Ok(SocketAddr::new(source.parse()?, port.parse()?))
+        }
+        Some("UNKNOWN") => Ok(peer),
+        _ => Err(format!("invalid PROXY header {line:?}").into()),
+    }
+}
+
+/// Accepts TCP connections and reads their PROXY protocol header off the accept path, so that a slow client
+/// can not stall other connections.
+pub(crate) struct ProxyListener {
+    accepted: Receiver<(TcpStream, SocketAddr)>,
+    local_addr: SocketAddr,
+}
+
+impl ProxyListener {
+    pub(crate) fn new(listener: TcpListener, socket: SocketOptions) -> Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let (tx, accepted) = channel(PENDING_CONNECTIONS);
+        tokio::spawn(accept_loop(listener, tx, socket));
+        Ok(Self { accepted, local_addr })
+    }
+}
+
+async fn accept_loop(listener: TcpListener, tx: Sender<(TcpStream, SocketAddr)>, socket: SocketOptions) {
+    loop {
+        let (mut stream, peer) = match listener.accept().await {
+            Ok(ok) => ok,
+            Err(err) => {
+                error!("Failed to accept connection: {err}");
+                continue;
+            }
+        };
+        socket.apply(&stream);
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            match timeout(HEADER_TIMEOUT, read_proxy_header(&mut stream, peer)).await {
+                Ok(Ok(addr)) => {
+                    let _unused = tx.send((stream, addr)).await;
+                }
+                Ok(Err(err)) => info!("Refusing connection from {peer}: {err}"),
+                Err(_) => info!("PROXY header from {peer} timed out"),
+            }
+        });
+    }
+}
+
+impl Listener for ProxyListener {
+    type Io = TcpStream;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        loop {
+            if let Some(conn) = self.accepted.recv().await {
+                return conn;
+            }
+            std::future::pending::<()>().await;
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    async fn read_header(header: &[u8]) -> Result<(SocketAddr, Vec<u8>)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let mut client = TcpStream::connect(listener.local_addr()?).await?;
+        client.write_all(header).await?;
+        client.write_all(b"GET").await?;
+        let (mut stream, peer) = listener.accept().await?;
+        let addr = read_proxy_header(&mut stream, peer).await?;
+        let mut rest = vec![0; 3];
+        stream.read_exact(&mut rest).await?;
+        Ok((addr, rest))
+    }
+
+    #[tokio::test]
+    async fn test_read_proxy_header() -> Result<()> {
+        let (addr, rest) = read_header(b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 8000\r\n").await?;
+        assert_eq!(addr, "203.0.113.7:51234".parse()?);
+        // nothing after the header is consumed
+        assert_eq!(rest, b"GET");
+        let (addr, _) = read_header(b"PROXY TCP6 2001:db8::7 2001:db8::1 51234 8000\r\n").await?;
+        assert_eq!(addr, "[2001:db8::7]:51234".parse()?);
+
+        let mut v2 = V2_SIGNATURE.to_vec();
+        v2.extend_from_slice(&[0x21, 0x11, 0, 12, 203, 0, 113, 7, 10, 0, 0, 1, 0xc8, 0x22, 0x1f, 0x40]);
+        let (addr, rest) = read_header(&v2).await?;
+        assert_eq!(addr, "203.0.113.7:51234".parse()?);
+        assert_eq!(rest, b"GET");
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        let (addr, _) = read_header(&local).await?;
+        assert!(addr.ip().is_loopback());
+
+        assert!(read_header(b"GET / HTTP/1.1\r\n").await.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_forwarded_for_trusted_proxies() -> Result<()> {
+        let trusted = ["10.0.0.0/8".parse()?, "2001:db8::1".parse()?];
+        let mut headers = HeaderMap::new();
+        headers.append("x-forwarded-for", HeaderValue::from_static("198.51.100.1, 203.0.113.7"));
+        headers.append("x-forwarded-for", HeaderValue::from_static("10.1.2.3"));
+        let lb = IpAddr::from([10, 0, 0, 1]);
+        // the first address not of a trusted proxy, from the right
+        assert_eq!(forwarded_for(lb, &headers, &trusted), Some(IpAddr::from([203, 0, 113, 7])));
+        assert_eq!(forwarded_for("::ffff:10.0.0.1".parse()?, &headers, &trusted), Some(IpAddr::from([203, 0, 113, 7])));
+        assert_eq!(forwarded_for(IpAddr::from([192, 0, 2, 1]), &headers, &trusted), None);
+        assert_eq!(forwarded_for("2001:db8::1".parse()?, &HeaderMap::new(), &trusted), None);
+        assert!("10.0.0.0/33".parse::<TrustedProxy>().is_err());
+        Ok(())
+    }
+}
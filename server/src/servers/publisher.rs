@@ -0,0 +1,253 @@
+use std::{
+    fmt,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter},
+    net::TcpStream,
+    select,
+    sync::{
+        broadcast::error::RecvError,
+        mpsc::{self, Receiver, Sender, error::TrySendError},
+    },
+    time::sleep,
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    listeners::order_book::{Broadcast, InternalMessage},
+    metrics::METRICS,
+    prelude::*,
+    servers::{shutdown::Shutdown, standby::Standby, websocket_server::coin_to_trades},
+    types::{L4Book, subscription::ServerResponse},
+};
+
+// messages queued for the NATS connection; more are dropped while it is down or slow
+const NATS_BUFFER: usize = 10_000;
+const NATS_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// A message bus the feed is mirrored onto. NATS is built in as [`NatsSink`]; other buses, e.g. Kafka,
+/// plug in by implementing this.
+pub trait PublishSink: Debug + Send + Sync {
+    /// Called for every message, in order. It must not block the publisher, so a sink usually queues messages
+    /// for a task of its own.
+    fn publish(&self, subject: &str, payload: Vec<u8>) -> Result<()>;
+}
+
+/// Mirrors every l4 book update and trade onto a message bus, alongside the websocket output.
+///
+/// Subjects are `<prefix>.l4Book.<coin>` and `<prefix>.trades.<coin>`, and payloads are the JSON messages of the
+/// websocket channels.
+#[derive(Debug, Clone)]
+pub struct PublisherConfig {
+    pub sink: Arc<dyn PublishSink>,
+    pub subject_prefix: String,
+}
+
+impl PublisherConfig {
+    #[must_use]
+    pub fn new(sink: Arc<dyn PublishSink>) -> Self {
+        Self { sink, subject_prefix: "orderbook".to_string() }
+    }
+}
+
+// runs until shutdown, independently of the websocket connections. Nothing is published while on standby
+pub(crate) fn spawn_publisher(
+    config: PublisherConfig,
+    internal_message_tx: &Broadcast,
+    standby: Standby,
+    shutdown: Shutdown,
+) {
+    // every coin is published
+    let mut rx = internal_message_tx.subscribe_all();
+    tokio::spawn(async move {
+        let mut publisher = Publisher { config, failing: false };
+        loop {
+            select! {
+                msg = rx.recv() => match msg {
+                    Ok(msg) if !standby.is_on() => publisher.on_message(&msg),
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(n)) => {
+                        METRICS.dropped_messages.inc_by(n);
+                        warn!("Publisher fell behind, {n} messages not published");
+                    }
+                    Err(RecvError::Closed) => return,
+                },
+                () = shutdown.cancelled() => return,
+            }
+        }
+    });
+}
+
+struct Publisher {
+    config: PublisherConfig,
+    // failures are logged once until publishing works again
+    failing: bool,
+}
+
+impl Publisher {
+    fn on_message(&mut self, msg: &InternalMessage) {
+        match msg {
+            InternalMessage::L4BookUpdates { updates, .. } => {
+                for (coin, updates) in updates {
+                    self.publish("l4Book", coin, &ServerResponse::L4Book(L4Book::Updates(updates.clone())));
+                }
+            }
+            InternalMessage::Fills { batch, .. } => {
+                for (coin, trades) in coin_to_trades(batch) {
+                    self.publish("trades", &coin, &ServerResponse::Trades(trades));
+                }
+            }
+            InternalMessage::Snapshot { .. }
+            | InternalMessage::Candles { .. }
+            | InternalMessage::Analytics { .. }
+            | InternalMessage::L3BookUpdates { .. }
+            | InternalMessage::Checkpoints { .. }
+            | InternalMessage::Status { .. }
+            | InternalMessage::Universe { .. }
+            | InternalMessage::MarketChanges { .. }
+            | InternalMessage::Halt { .. } => {}
+        }
+    }
+
+    fn publish(&mut self, channel: &str, coin: &str, msg: &ServerResponse) {
+        let subject = format!("{}.{channel}.{coin}", self.config.subject_prefix);
+        let res = serde_json::to_vec(msg)
+            .map_err(Error::from)
+            .and_then(|payload| self.config.sink.publish(&subject, payload));
+        match res {
+            Ok(()) if self.failing => {
+                info!("Publishing to {:?} again", self.config.sink);
+                self.failing = false;
+            }
+            Err(err) if !self.failing => {
+                error!("Unable to publish to {:?}: {err}", self.config.sink);
+                self.failing = true;
+            }
+            Ok(()) | Err(_) => {}
+        }
+    }
+}
+
+/// Publishes to a NATS server at `host:port`, reconnecting whenever the connection drops. It connects on the
+/// first message; messages are dropped while it is disconnected.
+pub struct NatsSink {
+    address: String,
+    tx: OnceLock<Sender<(String, Vec<u8>)>>,
+}
+
+impl NatsSink {
+    #[must_use]
+    pub const fn new(address: String) -> Self {
+        Self { address, tx: OnceLock::new() }
+    }
+}
+
+impl Debug for NatsSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "NATS at {}", self.address)
+    }
+}
+
+impl PublishSink for NatsSink {
+    fn publish(&self, subject: &str, payload: Vec<u8>) -> Result<()> {
+        let tx = self.tx.get_or_init(|| {
+            let (tx, rx) = mpsc::channel(NATS_BUFFER);
+            tokio::spawn(run_nats(self.address.clone(), rx));
+            tx
+        });
+        tx.try_send((subject.to_string(), payload)).map_err(|err| match err {
+            TrySendError::Full(_) => "NATS connection is down or too slow".into(),
+            TrySendError::Closed(_) => "NATS connection stopped".into(),
+        })
+    }
+}
+
+// ends once the sink is dropped
+async fn run_nats(address: String, mut rx: Receiver<(String, Vec<u8>)>) {
+    loop {
+        let res = match TcpStream::connect(&address).await {
+            Ok(stream) => nats_session(stream, &address, &mut rx).await,
+            Err(err) => Err(err.into()),
+        };
+        match res {
+            Ok(()) => return,
+            Err(err) => warn!("NATS connection to {address} failed: {err}"),
+        }
+        sleep(NATS_RECONNECT_DELAY).await;
+        // what queued up in the meantime is stale
+        while rx.try_recv().is_ok() {}
+        if rx.is_closed() {
+            return;
+        }
+    }
+}
+
+// the subset of the NATS protocol a publisher needs: https://docs.nats.io/reference/reference-protocols/nats-protocol
+async fn nats_session(stream: TcpStream, address: &str, rx: &mut Receiver<(String, Vec<u8>)>) -> Result<()> {
+    let (read, write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    let mut write = BufWriter::new(write);
+    if !lines.next_line().await?.is_some_and(|line| line.starts_with("INFO ")) {
+        return Err("the server didn't introduce itself with INFO".into());
+    }
+    write.write_all(br#"CONNECT {"verbose":false,"pedantic":false,"name":"order_book_server","lang":"rust"}"#).await?;
+    write.write_all(b"\r\n").await?;
+    write.flush().await?;
+    info!("Publishing to NATS at {address}");
+    loop {
+        select! {
+            msg = rx.recv() => {
+                let Some((subject, payload)) = msg else {
+                    return Ok(write.flush().await?);
+                };
+                write.write_all(format!("PUB {subject} {}\r\n", payload.len()).as_bytes()).await?;
+                write.write_all(&payload).await?;
+                write.write_all(b"\r\n").await?;
+                if rx.is_empty() {
+                    write.flush().await?;
+                }
+            }
+            // lines are cancel safe, so nothing read is lost when a message comes first
+            line = lines.next_line() => match line?.as_deref() {
+                Some("PING") => {
+                    write.write_all(b"PONG\r\n").await?;
+                    write.flush().await?;
+                }
+                Some(line) if line.starts_with("-ERR") => return Err(line.into()),
+                Some(_) => {}
+                None => return Err("closed by the server".into()),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{io::AsyncReadExt, net::TcpListener};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_nats_sink_publishes() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let sink = NatsSink::new(listener.local_addr()?.to_string());
+        sink.publish("orderbook.trades.BTC", b"[]".to_vec())?;
+
+        let (mut stream, _) = listener.accept().await?;
+        stream.write_all(b"INFO {}\r\nPING\r\n").await?;
+        let mut received = Vec::new();
+        let mut buf = [0; 1024];
+        while !(received.ends_with(b"[]\r\n") && received.windows(6).any(|line| line == b"PONG\r\n")) {
+            let n = stream.read(&mut buf).await?;
+            assert_ne!(n, 0);
+            received.extend_from_slice(&buf[..n]);
+        }
+        let received = String::from_utf8(received)?;
+        assert!(received.starts_with("CONNECT {"));
+        assert!(received.contains("PUB orderbook.trades.BTC 2\r\n[]\r\n"));
+        Ok(())
+    }
+}
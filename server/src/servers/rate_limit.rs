@@ -0,0 +1,321 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU32, Ordering},
+    },
+    time::Duration,
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State, connect_info::Connected},
+    http::{HeaderValue, StatusCode, header::RETRY_AFTER},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    serve::{IncomingStream, Listener},
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    net::TcpListener,
+    time::{Instant, sleep},
+};
+use tracing::info;
+
+use crate::metrics::{METRICS, MeteredListener, WireBytes};
+
+// above this many tracked addresses, addresses whose bucket has refilled are forgotten
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// Limits applied to every client. A limit that isn't set is not enforced.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimits {
+    /// Messages a client may send per second (subscribe, unsubscribe, ...), with bursts of the same size.
+    /// A client exceeding it is closed with code 1008.
+    pub client_messages_per_sec: Option<u32>,
+    /// Messages sent to a single client per second. Excess messages wait in the client's send queue,
+    /// where the backpressure policy applies once it is full.
+    pub outbound_messages_per_sec: Option<u32>,
+    /// New connections (and REST requests) accepted per IP address per minute; excess requests get a 429.
+    pub connections_per_ip_per_min: Option<u32>,
+    /// Payload bytes (before compression) sent to a single websocket client per minute, with bursts of the same
+    /// size. `bandwidth_policy` decides what happens to a client over it.
+    pub outbound_bytes_per_min: Option<u64>,
+    pub bandwidth_policy: BandwidthPolicy,
+}
+
+impl RateLimits {
+    pub(crate) fn validate(self) -> crate::prelude::Result<()> {
+        let Self {
+            client_messages_per_sec,
+            outbound_messages_per_sec,
+            connections_per_ip_per_min,
+            outbound_bytes_per_min,
+            bandwidth_policy: _,
+        } = self;
+        if [client_messages_per_sec, outbound_messages_per_sec, connections_per_ip_per_min].contains(&Some(0))
+            || outbound_bytes_per_min == Some(0)
+        {
+            return Err("rate limits have to be at least 1".into());
+        }
+        Ok(())
+    }
+}
+
+/// What to do with a client that exceeds its bandwidth quota.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BandwidthPolicy {
+    /// Hold its messages back until the quota allows them. They wait in the client's send queue, where the
+    /// backpressure policy applies once it is full.
+    #[default]
+    Throttle,
+    /// Close the connection (close code 1008).
+    Disconnect,
+}
+
+impl FromStr for BandwidthPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "throttle" => Ok(Self::Throttle),
+            "disconnect" => Ok(Self::Disconnect),
+            _ => Err(format!("unknown bandwidth policy {s} (expected throttle or disconnect)")),
+        }
+    }
+}
+
+impl fmt::Display for BandwidthPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Throttle => "throttle",
+            Self::Disconnect => "disconnect",
+        })
+    }
+}
+
+/// Allows `capacity` events at once, refilled at `capacity` per `period`.
+#[derive(Debug, Clone)]
+pub(crate) struct TokenBucket {
+    capacity: f64,
+    // tokens per second
+    refill_rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(capacity: u32, period: Duration, now: Instant) -> Self {
+        let capacity = f64::from(capacity.max(1));
+        Self { capacity, refill_rate: capacity / period.as_secs_f64(), tokens: capacity, updated: now }
+    }
+
+    pub(crate) fn per_second(rate: u32) -> Self {
+        Self::new(rate, Duration::from_secs(1), Instant::now())
+    }
+
+    // a token per byte
+    #[allow(clippy::cast_precision_loss)]
+    pub(crate) fn bytes_per_minute(bytes: u64) -> Self {
+        let capacity = bytes.max(1) as f64;
+        Self { capacity, refill_rate: capacity / 60.0, tokens: capacity, updated: Instant::now() }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = elapsed.mul_add(self.refill_rate, self.tokens).min(self.capacity);
+        self.updated = now;
+    }
+
+    pub(crate) fn try_acquire(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    // takes `n` tokens at once. More than the capacity take a full bucket
+    #[allow(clippy::cast_precision_loss)]
+    pub(crate) fn try_acquire_n(&mut self, n: u64, now: Instant) -> bool {
+        self.refill(now);
+        let n = (n as f64).min(self.capacity);
+        if self.tokens >= n {
+            self.tokens -= n;
+            true
+        } else {
+            false
+        }
+    }
+
+    // time until the next token is available
+    pub(crate) fn wait_time(&mut self, now: Instant) -> Duration {
+        self.wait_time_n(1, now)
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn wait_time_n(&mut self, n: u64, now: Instant) -> Duration {
+        self.refill(now);
+        let n = (n as f64).min(self.capacity);
+        Duration::from_secs_f64(((n - self.tokens) / self.refill_rate).max(0.0))
+    }
+
+    pub(crate) async fn acquire(&mut self) {
+        self.acquire_n(1).await;
+    }
+
+    pub(crate) async fn acquire_n(&mut self, n: u64) {
+        while !self.try_acquire_n(n, Instant::now()) {
+            sleep(self.wait_time_n(n, Instant::now())).await;
+        }
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.capacity
+    }
+}
+
+/// Counts new connections per client IP address.
+pub(crate) struct ConnectionRateLimiter {
+    // 0 when not limited; can change at runtime
+    per_minute: AtomicU32,
+    buckets: Mutex<HashMap<IpAddr, TokenBucket>>,
+}
+
+impl ConnectionRateLimiter {
+    pub(crate) fn new(per_minute: Option<u32>) -> Self {
+        Self { per_minute: AtomicU32::new(per_minute.unwrap_or_default()), buckets: Mutex::default() }
+    }
+
+    // addresses start over with a full bucket of the new size
+    pub(crate) fn set_per_minute(&self, per_minute: Option<u32>) {
+        let per_minute = per_minute.unwrap_or_default();
+        if self.per_minute.swap(per_minute, Ordering::Relaxed) != per_minute
+            && let Ok(mut buckets) = self.buckets.lock()
+        {
+            buckets.clear();
+        }
+    }
+
+    // Err holds how long the address has to wait
+    pub(crate) fn check(&self, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        let per_minute = self.per_minute.load(Ordering::Relaxed);
+        if per_minute == 0 {
+            return Ok(());
+        }
+        let Ok(mut buckets) = self.buckets.lock() else {
+            return Ok(());
+        };
+        if buckets.len() > PRUNE_THRESHOLD {
+            buckets.retain(|_, bucket| !bucket.is_full(now));
+        }
+        let bucket = buckets.entry(ip).or_insert_with(|| TokenBucket::new(per_minute, Duration::from_mins(1), now));
+        if bucket.try_acquire(now) { Ok(()) } else { Err(bucket.wait_time(now)) }
+    }
+}
+
+/// Address of the client a request came in from, and the bytes written to its connection, for use with
+/// `into_make_service_with_connect_info`.
+#[derive(Debug, Clone)]
+pub(crate) struct PeerAddr(pub(crate) SocketAddr, pub(crate) WireBytes);
+
+impl<L: Listener<Addr = SocketAddr>> Connected<IncomingStream<'_, MeteredListener<L>>> for PeerAddr {
+    fn connect_info(stream: IncomingStream<'_, MeteredListener<L>>) -> Self {
+        Self(*stream.remote_addr(), stream.io().wire_bytes())
+    }
+}
+
+// not metered, its connections write no bytes as far as they know
+impl Connected<IncomingStream<'_, TcpListener>> for PeerAddr {
+    fn connect_info(stream: IncomingStream<'_, TcpListener>) -> Self {
+        Self(*stream.remote_addr(), WireBytes::default())
+    }
+}
+
+// axum middleware: `middleware::from_fn_with_state(limiter, limit_connections)`
+pub(crate) async fn limit_connections(
+    State(limiter): State<Arc<ConnectionRateLimiter>>,
+    ConnectInfo(PeerAddr(addr, _)): ConnectInfo<PeerAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match limiter.check(addr.ip(), Instant::now()) {
+        Ok(()) => next.run(request).await,
+        Err(wait) => {
+            info!("Rate limiting connections from {}", addr.ip());
+            METRICS.rate_limited.with_label_values(&["connections"]).inc();
+            let mut response = (StatusCode::TOO_MANY_REQUESTS, "Too many connections, slow down").into_response();
+            let retry_after = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+            response.headers_mut().insert(RETRY_AFTER, HeaderValue::from(retry_after));
+            response
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{Router, middleware::from_fn_with_state, routing::get};
+
+    use super::*;
+    use crate::prelude::*;
+
+    #[test]
+    fn test_token_bucket_refills() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2, Duration::from_secs(1), start);
+        assert!(bucket.try_acquire(start));
+        assert!(bucket.try_acquire(start));
+        assert!(!bucket.try_acquire(start));
+        assert_eq!(bucket.wait_time(start), Duration::from_millis(500));
+        assert!(bucket.try_acquire(start + Duration::from_millis(500)));
+        assert!(!bucket.try_acquire(start + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn test_bytes_per_minute() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::bytes_per_minute(600);
+        assert!(bucket.try_acquire_n(400, start));
+        assert!(!bucket.try_acquire_n(400, start));
+        assert_eq!(bucket.wait_time_n(400, start), Duration::from_secs(20));
+        // a message over the quota waits for a full bucket
+        assert!(bucket.try_acquire_n(1000, start + Duration::from_secs(40)));
+    }
+
+    #[test]
+    fn test_connections_limited_per_ip() {
+        let limiter = ConnectionRateLimiter::new(Some(1));
+        let now = Instant::now();
+        let a = IpAddr::from([10, 0, 0, 1]);
+        assert!(limiter.check(a, now).is_ok());
+        assert_eq!(limiter.check(a, now), Err(Duration::from_mins(1)));
+        assert!(limiter.check(IpAddr::from([10, 0, 0, 2]), now).is_ok());
+        assert!(limiter.check(a, now + Duration::from_mins(1)).is_ok());
+        limiter.set_per_minute(None);
+        assert!(limiter.check(a, now + Duration::from_mins(1)).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_middleware_responds_with_429() -> Result<()> {
+        let limiter = Arc::new(ConnectionRateLimiter::new(Some(1)));
+        let app =
+            Router::new().route("/", get(|| async { "ok" })).layer(from_fn_with_state(limiter, limit_connections));
+        let tcp_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = tcp_listener.local_addr()?;
+        tokio::spawn(
+            async move { axum::serve(tcp_listener, app.into_make_service_with_connect_info::<PeerAddr>()).await },
+        );
+        assert_eq!(reqwest::get(format!("http://{address}/")).await?.status(), StatusCode::OK);
+        let res = reqwest::get(format!("http://{address}/")).await?;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers().get(RETRY_AFTER).and_then(|value| value.to_str().ok()), Some("60"));
+        Ok(())
+    }
+}
@@ -0,0 +1,608 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, VecDeque},
+    net::SocketAddr,
+    sync::{
+        Arc, Mutex, OnceLock,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use prometheus::IntCounter;
+use serde::Serialize;
+use tokio::{
+    sync::{
+        mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+        oneshot,
+    },
+    time::sleep,
+};
+use tracing::{Span, info, warn};
+use yawc::{FrameView, close::CloseCode};
+
+#[cfg(feature = "chaos")]
+use crate::chaos::Stall;
+use crate::{
+    metrics::{METRICS, WireBytes},
+    servers::{
+        priority::Priority,
+        send_queue::{SendQueue, SlowConsumerReason, SlowConsumerReport},
+    },
+    types::{
+        MaintenanceNotice,
+        subscription::{ServerResponse, Subscription},
+    },
+};
+
+// closed connections kept for the admin API
+const CLOSED_CONNECTIONS: usize = 100;
+// and the reports of slow clients
+const SLOW_CONSUMERS: usize = 100;
+
+// the identity of clients that didn't authenticate, in the metrics
+const ANONYMOUS: &str = "anonymous";
+// the tenant of clients that don't belong to one, in the metrics
+const NO_TENANT: &str = "";
+
+// in the range left to applications, like the 503 that new connections get during maintenance
+pub(crate) const MAINTENANCE_CLOSE_CODE: u16 = 4503;
+
+// requests handled by a connection's main loop
+pub(crate) enum Command {
+    Subscriptions(oneshot::Sender<Vec<Subscription>>),
+    // send every subscription a fresh snapshot
+    Resnapshot,
+}
+
+/// Counted by the connection's writer.
+#[derive(Debug, Default)]
+pub(crate) struct ConnectionStats {
+    messages_sent: AtomicU64,
+    // encoded payloads, before compression
+    bytes_sent: AtomicU64,
+    // written to the socket, counted as it is written
+    wire: WireBytes,
+    // of the wire bytes, those already added to the metrics of the identity
+    wire_recorded: AtomicU64,
+    // the payload and wire bytes of the identity, once the connection is registered
+    identity_bytes: OnceLock<(IntCounter, IntCounter)>,
+    #[cfg(feature = "chaos")]
+    pub(crate) stall: Stall,
+}
+
+impl ConnectionStats {
+    pub(crate) fn new(wire: WireBytes) -> Self {
+        Self { wire, ..Self::default() }
+    }
+
+    // one frame, which holds several messages when batching
+    pub(crate) fn record(&self, messages: u64, bytes: u64) {
+        self.messages_sent.fetch_add(messages, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(bytes, Ordering::Relaxed);
+        if let Some((payload, wire)) = self.identity_bytes.get() {
+            let written = self.wire.get();
+            payload.inc_by(bytes);
+            wire.inc_by(written.saturating_sub(self.wire_recorded.swap(written, Ordering::Relaxed)));
+        }
+    }
+
+    fn set_identity(&self, tenant: Option<&str>, identity: Option<&str>) {
+        let labels = [tenant.unwrap_or(NO_TENANT), identity.unwrap_or(ANONYMOUS)];
+        let _unused = self.identity_bytes.set((
+            METRICS.client_payload_bytes.with_label_values(&labels),
+            METRICS.client_wire_bytes.with_label_values(&labels),
+        ));
+    }
+}
+
+struct Connection {
+    address: SocketAddr,
+    identity: Option<String>,
+    tenant: Option<String>,
+    connected_at: u64,
+    queue: Arc<SendQueue>,
+    stats: Arc<ConnectionStats>,
+    commands: UnboundedSender<Command>,
+}
+
+impl Connection {
+    fn info(&self, id: u64) -> ClientInfo {
+        ClientInfo {
+            id,
+            address: self.address,
+            identity: self.identity.clone(),
+            tenant: self.tenant.clone(),
+            connected_at: self.connected_at,
+            disconnected_at: None,
+            queue_depth: self.queue.len(),
+            messages_sent: self.stats.messages_sent.load(Ordering::Relaxed),
+            bytes_sent: self.stats.bytes_sent.load(Ordering::Relaxed),
+            wire_bytes_sent: self.stats.wire.get(),
+            subscriptions: Vec::new(),
+        }
+    }
+}
+
+/// A websocket client, as listed by the admin API. Times are in milliseconds since the epoch.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct ClientInfo {
+    pub(crate) id: u64,
+    pub(crate) address: SocketAddr,
+    pub(crate) identity: Option<String>,
+    pub(crate) tenant: Option<String>,
+    pub(crate) connected_at: u64,
+    pub(crate) disconnected_at: Option<u64>,
+    pub(crate) queue_depth: usize,
+    pub(crate) messages_sent: u64,
+    // before and after compression; the latter includes the HTTP upgrade and websocket framing
+    pub(crate) bytes_sent: u64,
+    pub(crate) wire_bytes_sent: u64,
+    pub(crate) subscriptions: Vec<Subscription>,
+}
+
+/// A client evicted or throttled for reading too slowly, as listed by the admin API.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct SlowConsumer {
+    pub(crate) id: u64,
+    pub(crate) address: SocketAddr,
+    pub(crate) identity: Option<String>,
+    pub(crate) report: SlowConsumerReport,
+}
+
+/// What the connections of an identity were sent since the server started, as listed by the admin API.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub(crate) struct Usage {
+    pub(crate) identity: Option<String>,
+    pub(crate) tenant: Option<String>,
+    pub(crate) connections: u64,
+    pub(crate) messages_sent: u64,
+    pub(crate) bytes_sent: u64,
+    pub(crate) wire_bytes_sent: u64,
+}
+
+impl Usage {
+    const fn add(&mut self, info: &ClientInfo) {
+        self.connections += 1;
+        self.messages_sent += info.messages_sent;
+        self.bytes_sent += info.bytes_sent;
+        self.wire_bytes_sent += info.wire_bytes_sent;
+    }
+}
+
+// tenant and identity
+type UsageKey = (Option<String>, Option<String>);
+
+/// Every websocket connection gets an ID here once it is authenticated. The registry lists the open connections
+/// and the recently closed ones, lets the admin API control them, and logs and measures their lifecycle.
+#[derive(Default)]
+pub(crate) struct ConnectionRegistry {
+    next_id: AtomicU64,
+    connections: Mutex<BTreeMap<u64, Connection>>,
+    // most recent last
+    closed: Mutex<VecDeque<ClientInfo>>,
+    // of all the closed connections, by tenant and identity
+    closed_usage: Mutex<BTreeMap<UsageKey, Usage>>,
+    // most recent last
+    slow_consumers: Mutex<VecDeque<SlowConsumer>>,
+    // new connections are rejected while set
+    maintenance: AtomicBool,
+    // and while the watchdog sheds load
+    overloaded: AtomicBool,
+    // the maintenance announced to clients, from when it is scheduled until it is over
+    notice: Mutex<Option<MaintenanceNotice>>,
+    // changed by every schedule, so that a maintenance called off doesn't start
+    schedule: AtomicU64,
+}
+
+impl ConnectionRegistry {
+    // the connection is listed until the registration is dropped
+    pub(crate) fn register(
+        self: &Arc<Self>,
+        address: SocketAddr,
+        identity: Option<String>,
+        tenant: Option<String>,
+        queue: Arc<SendQueue>,
+        stats: Arc<ConnectionStats>,
+    ) -> Registration {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        // the connection's span, if it has one
+        Span::current().record("client", id);
+        let (commands, rx) = unbounded_channel();
+        info!(
+            "Client {id} connected from {address}{}{}",
+            identity.as_ref().map_or(String::new(), |name| format!(" as {name}")),
+            tenant.as_ref().map_or(String::new(), |tenant| format!(" of tenant {tenant}"))
+        );
+        let connected_at = now_ms();
+        stats.set_identity(tenant.as_deref(), identity.as_deref());
+        if let Some(tenant) = &tenant {
+            METRICS.tenant_connections.with_label_values(&[tenant]).inc();
+        }
+        // clients connecting after the announcement learn of it too
+        if let Some(notice) = self.maintenance() {
+            queue.push(None, ServerResponse::Maintenance(notice));
+        }
+        if let Ok(mut connections) = self.connections.lock() {
+            connections.insert(id, Connection { address, identity, tenant, connected_at, queue, stats, commands });
+        }
+        Registration { id, registry: self.clone(), commands: rx, started: Instant::now(), subscriptions: Vec::new() }
+    }
+
+    pub(crate) async fn clients(&self) -> Vec<ClientInfo> {
+        let pending = self.connections.lock().map_or_else(
+            |_| Vec::new(),
+            |connections| {
+                connections
+                    .iter()
+                    .map(|(id, connection)| {
+                        let (tx, rx) = oneshot::channel();
+                        let _unused = connection.commands.send(Command::Subscriptions(tx));
+                        (connection.info(*id), rx)
+                    })
+                    .collect()
+            },
+        );
+        let mut clients = Vec::with_capacity(pending.len());
+        for (mut info, rx) in pending {
+            // a connection that is closing doesn't answer
+            if let Ok(subscriptions) = rx.await {
+                info.subscriptions = subscriptions;
+                clients.push(info);
+            }
+        }
+        clients
+    }
+
+    pub(crate) fn closed_clients(&self) -> Vec<ClientInfo> {
+        self.closed.lock().map_or_else(|_| Vec::new(), |closed| closed.iter().cloned().collect())
+    }
+
+    pub(crate) fn slow_consumers(&self) -> Vec<SlowConsumer> {
+        self.slow_consumers.lock().map_or_else(|_| Vec::new(), |slow| slow.iter().cloned().collect())
+    }
+
+    // of the open and the closed connections, one per identity, those of a tenant together
+    pub(crate) fn usage(&self) -> Vec<Usage> {
+        let mut usage = self.closed_usage.lock().map_or_else(|_| BTreeMap::new(), |usage| usage.clone());
+        if let Ok(connections) = self.connections.lock() {
+            for (id, connection) in connections.iter() {
+                let key = (connection.tenant.clone(), connection.identity.clone());
+                usage.entry(key).or_default().add(&connection.info(*id));
+            }
+        }
+        usage.into_iter().map(|((tenant, identity), usage)| Usage { identity, tenant, ..usage }).collect()
+    }
+
+    // false if there is no such connection
+    pub(crate) fn kick(&self, id: u64) -> bool {
+        let Ok(connections) = self.connections.lock() else {
+            return false;
+        };
+        connections.get(&id).is_some_and(|connection| {
+            connection.queue.close(FrameView::close(CloseCode::Policy, "disconnected by the server operator"));
+            true
+        })
+    }
+
+    // holds the connection's messages back for the duration, or lifts its stall with a zero one
+    #[cfg(feature = "chaos")]
+    pub(crate) fn stall(&self, id: u64, duration: Duration) -> bool {
+        let Ok(connections) = self.connections.lock() else {
+            return false;
+        };
+        connections.get(&id).is_some_and(|connection| {
+            connection.stats.stall.set(duration);
+            true
+        })
+    }
+
+    // returns the number of connections asked to
+    pub(crate) fn resnapshot(&self) -> usize {
+        self.connections.lock().map_or(0, |connections| {
+            connections.values().filter(|connection| connection.commands.send(Command::Resnapshot).is_ok()).count()
+        })
+    }
+
+    // announces the maintenance to every client; at `start` the connections are closed and new ones rejected
+    pub(crate) fn schedule_maintenance(self: &Arc<Self>, start: u64, message: Option<String>) {
+        let schedule = self.schedule.fetch_add(1, Ordering::Relaxed) + 1;
+        let notice = MaintenanceNotice { start: Some(start), message, close_code: MAINTENANCE_CLOSE_CODE };
+        if let Ok(mut current) = self.notice.lock() {
+            *current = Some(notice.clone());
+        }
+        self.announce(&notice);
+        let delay = start.saturating_sub(now_ms());
+        if delay == 0 {
+            self.start_maintenance(schedule);
+            return;
+        }
+        let registry = self.clone();
+        tokio::spawn(async move {
+            sleep(Duration::from_millis(delay)).await;
+            registry.start_maintenance(schedule);
+        });
+    }
+
+    fn start_maintenance(&self, schedule: u64) {
+        if self.schedule.load(Ordering::Relaxed) != schedule {
+            return;
+        }
+        self.maintenance.store(true, Ordering::Relaxed);
+        if let Ok(connections) = self.connections.lock() {
+            info!("Maintenance started, closing {} connection(s)", connections.len());
+            for connection in connections.values() {
+                // the message of the notice may be too long for a close frame
+                connection.queue.close(FrameView::close(CloseCode::from(MAINTENANCE_CLOSE_CODE), "server maintenance"));
+            }
+        }
+    }
+
+    // calls off a scheduled maintenance, or ends the current one
+    pub(crate) fn end_maintenance(&self) {
+        self.schedule.fetch_add(1, Ordering::Relaxed);
+        let notice = self.notice.lock().ok().and_then(|mut notice| notice.take());
+        if notice.is_some() && !self.maintenance.swap(false, Ordering::Relaxed) {
+            self.announce(&MaintenanceNotice { start: None, message: None, close_code: MAINTENANCE_CLOSE_CODE });
+        }
+    }
+
+    fn announce(&self, notice: &MaintenanceNotice) {
+        if let Ok(connections) = self.connections.lock() {
+            for connection in connections.values() {
+                connection.queue.push(None, ServerResponse::Maintenance(notice.clone()));
+            }
+        }
+    }
+
+    pub(crate) fn maintenance(&self) -> Option<MaintenanceNotice> {
+        self.notice.lock().ok().and_then(|notice| notice.clone())
+    }
+
+    pub(crate) fn is_maintenance(&self) -> bool {
+        self.maintenance.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_overloaded(&self, overloaded: bool) {
+        self.overloaded.store(overloaded, Ordering::Relaxed);
+    }
+
+    pub(crate) fn is_overloaded(&self) -> bool {
+        self.overloaded.load(Ordering::Relaxed)
+    }
+
+    // evicts this share of the connections, those with the most messages queued first. Connections without any
+    // queued and those of high priority are spared. Returns the number evicted
+    pub(crate) fn evict_slowest(&self, percent: u8) -> usize {
+        let Ok(connections) = self.connections.lock() else {
+            return 0;
+        };
+        let n = (connections.len() * usize::from(percent)).div_ceil(100);
+        let mut queues = connections
+            .values()
+            .map(|connection| (connection.queue.len(), &connection.queue))
+            .filter(|(len, queue)| *len > 0 && queue.priority() != Some(Priority::High))
+            .collect::<Vec<_>>();
+        queues.sort_by_key(|(len, _)| Reverse(*len));
+        queues.truncate(n);
+        for (_, queue) in &queues {
+            queue.evict(SlowConsumerReason::Load);
+        }
+        queues.len()
+    }
+}
+
+pub(crate) struct Registration {
+    id: u64,
+    registry: Arc<ConnectionRegistry>,
+    commands: UnboundedReceiver<Command>,
+    started: Instant,
+    // kept with the closed connection
+    subscriptions: Vec<Subscription>,
+}
+
+impl Registration {
+    pub(crate) const fn id(&self) -> u64 {
+        self.id
+    }
+
+    // called once the connection stops handling commands, with the subscriptions it ended with
+    pub(crate) fn finish(&mut self, subscriptions: Vec<Subscription>) {
+        self.subscriptions = subscriptions;
+        // pending requests get an error instead of waiting for the connection to be dropped
+        self.commands.close();
+        while self.commands.try_recv().is_ok() {}
+    }
+
+    // logs the report of a client that fell behind and keeps it for the admin API
+    pub(crate) fn report_slow_consumer(&self, report: &SlowConsumerReport) {
+        let Some((address, identity)) = self.registry.connections.lock().ok().and_then(|connections| {
+            connections.get(&self.id).map(|connection| (connection.address, connection.identity.clone()))
+        }) else {
+            return;
+        };
+        warn!(
+            client = self.id,
+            %address,
+            identity = identity.as_deref().unwrap_or(ANONYMOUS),
+            action = %report.action,
+            reason = %report.reason,
+            queue_capacity = report.queue_capacity,
+            queue_depth_history = ?report.queue_depth_history,
+            drain_rate = report.drain_rate,
+            subscriptions = ?report.subscriptions,
+            "Client {} {} for reading too slowly", self.id, report.action
+        );
+        METRICS.slow_consumers.with_label_values(&[&report.action.to_string(), &report.reason.to_string()]).inc();
+        if let Ok(mut slow) = self.registry.slow_consumers.lock() {
+            if slow.len() >= SLOW_CONSUMERS {
+                slow.pop_front();
+            }
+            slow.push_back(SlowConsumer { id: self.id, address, identity, report: report.clone() });
+        }
+    }
+
+    pub(crate) async fn command(&mut self) -> Command {
+        match self.commands.recv().await {
+            Some(command) => command,
+            // the registry holds the sender until we are dropped
+            None => std::future::pending().await,
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let Some(connection) =
+            self.registry.connections.lock().ok().and_then(|mut connections| connections.remove(&self.id))
+        else {
+            return;
+        };
+        let duration = self.started.elapsed();
+        // the wire bytes written since the last message, for the metrics
+        connection.stats.record(0, 0);
+        let mut info = connection.info(self.id);
+        info.disconnected_at = Some(now_ms());
+        info.subscriptions = std::mem::take(&mut self.subscriptions);
+        info!(
+            "Client {} disconnected after {duration:.1?}, {} messages ({} bytes, {} on the wire) sent",
+            self.id, info.messages_sent, info.bytes_sent, info.wire_bytes_sent
+        );
+        METRICS.observe_connection_duration(duration);
+        if let Some(tenant) = &info.tenant {
+            METRICS.tenant_connections.with_label_values(&[tenant]).dec();
+        }
+        if let Ok(mut usage) = self.registry.closed_usage.lock() {
+            usage.entry((info.tenant.clone(), info.identity.clone())).or_default().add(&info);
+        }
+        if let Ok(mut closed) = self.registry.closed.lock() {
+            if closed.len() >= CLOSED_CONNECTIONS {
+                closed.pop_front();
+            }
+            closed.push_back(info);
+        }
+    }
+}
+
+#[allow(clippy::cast_sign_loss)]
+fn now_ms() -> u64 {
+    Utc::now().timestamp_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::servers::{
+        priority::LoadShedding,
+        send_queue::{BackpressurePolicy, Outgoing, SlowConsumerAction},
+    };
+
+    #[tokio::test]
+    async fn test_clients_and_kick() {
+        let registry = Arc::new(ConnectionRegistry::default());
+        let queue = Arc::new(SendQueue::new(BackpressurePolicy::Disconnect, 16));
+        let stats = Arc::new(ConnectionStats::default());
+        let (identity, tenant) = (Some("alice".to_string()), Some("acme".to_string()));
+        let address = "10.0.0.1:5000".parse().unwrap();
+        let mut registration = registry.register(address, identity, tenant.clone(), queue.clone(), stats.clone());
+        stats.record(1, 100);
+        let subscription = Subscription::Trades { coin: "BTC".to_string() };
+        let answer = {
+            let subscription = subscription.clone();
+            async move {
+                if let Command::Subscriptions(tx) = registration.command().await {
+                    let _unused = tx.send(vec![subscription]);
+                }
+                registration
+            }
+        };
+        let (clients, mut registration) = tokio::join!(registry.clients(), answer);
+        assert_eq!(clients.len(), 1);
+        assert_eq!(clients[0].subscriptions, vec![subscription.clone()]);
+        assert_eq!(clients[0].bytes_sent, 100);
+        let report = queue.report(SlowConsumerAction::Throttled, SlowConsumerReason::QueueFull, Vec::new());
+        registration.report_slow_consumer(&report);
+        let slow = registry.slow_consumers();
+        assert_eq!((slow.len(), slow[0].id, &slow[0].report), (1, clients[0].id, &report));
+        assert!(registry.kick(clients[0].id));
+        assert!(queue.is_closing());
+        registration.finish(vec![subscription]);
+        drop(registration);
+        assert!(!registry.kick(clients[0].id));
+        let closed = registry.closed_clients();
+        assert_eq!(closed.len(), 1);
+        assert!(closed[0].disconnected_at.is_some());
+        assert_eq!(closed[0].subscriptions.len(), 1);
+        let usage = registry.usage();
+        assert_eq!((usage.len(), usage[0].connections, usage[0].bytes_sent), (1, 1, 100));
+        assert_eq!(usage[0].tenant, tenant);
+    }
+
+    #[test]
+    fn test_evict_slowest() {
+        let registry = Arc::new(ConnectionRegistry::default());
+        let connect = |queued: u64, priority: Priority| {
+            let queue = Arc::new(SendQueue::new(BackpressurePolicy::Disconnect, 16));
+            queue.set_priority(Some(LoadShedding::default()), Some(priority));
+            for _ in 0..queued {
+                queue.push(None, ServerResponse::Error("queued".to_string()));
+            }
+            let registration =
+                registry.register("10.0.0.1:5000".parse().unwrap(), None, None, queue.clone(), Arc::default());
+            (queue, registration)
+        };
+        let connections = [
+            connect(5, Priority::Normal),
+            connect(9, Priority::High),
+            connect(2, Priority::Low),
+            connect(0, Priority::Low),
+        ];
+        // a quarter of 4, rounded up, and then the next slowest
+        assert_eq!(registry.evict_slowest(25), 1);
+        assert_eq!(connections.each_ref().map(|(queue, _)| queue.is_closing()), [true, false, false, false]);
+        assert_eq!(registry.evict_slowest(100), 1);
+        assert_eq!(connections.each_ref().map(|(queue, _)| queue.is_closing()), [true, false, true, false]);
+        assert_eq!(connections[2].0.take_eviction(), Some(SlowConsumerReason::Load));
+    }
+
+    #[tokio::test]
+    async fn test_scheduled_maintenance() {
+        let registry = Arc::new(ConnectionRegistry::default());
+        let queue = Arc::new(SendQueue::new(BackpressurePolicy::Disconnect, 16));
+        let _registration =
+            registry.register("10.0.0.1:5000".parse().unwrap(), None, None, queue.clone(), Arc::default());
+        let notice = async |queue: &SendQueue| match queue.next().await {
+            Some(Outgoing::Message(msg, _)) => match msg.msg() {
+                ServerResponse::Maintenance(notice) => Some(notice.clone()),
+                _ => None,
+            },
+            _ => None,
+        };
+
+        // called off before it starts
+        registry.schedule_maintenance(now_ms() + 50, None);
+        registry.end_maintenance();
+        assert!(notice(&queue).await.is_some_and(|notice| notice.start.is_some()));
+        assert_eq!(notice(&queue).await.map(|notice| notice.start), Some(None));
+
+        let start = now_ms() + 50;
+        registry.schedule_maintenance(start, Some("upgrade".to_string()));
+        let announced = notice(&queue).await.unwrap();
+        assert_eq!((announced.start, announced.close_code), (Some(start), MAINTENANCE_CLOSE_CODE));
+        assert!(!registry.is_maintenance() && !queue.is_closing());
+        // connections joining in between are told too
+        let late = Arc::new(SendQueue::new(BackpressurePolicy::Disconnect, 16));
+        let _late = registry.register("10.0.0.2:5000".parse().unwrap(), None, None, late.clone(), Arc::default());
+        assert_eq!(notice(&late).await, Some(announced));
+
+        sleep(Duration::from_millis(200)).await;
+        assert!(registry.is_maintenance());
+        let Some(Outgoing::Close(frame)) = queue.next().await else {
+            panic!("the connection is not closed");
+        };
+        assert_eq!(frame.close_code(), Some(CloseCode::from(MAINTENANCE_CLOSE_CODE)));
+        registry.end_maintenance();
+        assert!(!registry.is_maintenance() && registry.maintenance().is_none());
+    }
+}
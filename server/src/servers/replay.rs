@@ -0,0 +1,360 @@
+use std::{collections::HashMap, sync::Arc};
+
+use tokio::{
+    sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+    task::{AbortHandle, spawn_blocking},
+};
+use tracing::info;
+
+use crate::{
+    journal::{Journal, ReplayCursor, ReplayFrom},
+    servers::send_queue::SendQueue,
+    types::{
+        L4Book, L4BookUpdates,
+        subscription::{ClientMessage, ServerResponse, Subscription, SubscriptionManager},
+    },
+};
+
+// updates read from the journal at a time
+const REPLAY_BATCH: usize = 1000;
+// live updates held back for a replaying subscription before its replay is given up
+const MAX_BUFFERED_UPDATES: usize = 10_000;
+
+// the last replayed seq, if anything was replayed
+type ReplayResult = Result<Option<u64>, String>;
+
+struct ActiveReplay {
+    from: ReplayFrom,
+    task: AbortHandle,
+    // live updates received while replaying
+    buffered: Vec<L4BookUpdates>,
+}
+
+/// Replays of the l4 book journal to one connection, and of the updates a resuming connection missed.
+/// The journaled updates of a replaying subscription are sent by a spawned task while its live updates are held back.
+/// Once the task has caught up with the journal, the held back updates it didn't send follow and the subscription
+/// continues live, skipping live updates up to the last replayed seq.
+pub(crate) struct Replays {
+    journal: Option<Arc<Journal>>,
+    active: HashMap<Subscription, ActiveReplay>,
+    replayed_through: HashMap<Subscription, u64>,
+    done_tx: UnboundedSender<(Subscription, ReplayResult)>,
+    done_rx: UnboundedReceiver<(Subscription, ReplayResult)>,
+}
+
+impl Replays {
+    pub(crate) fn new(journal: Option<Arc<Journal>>) -> Self {
+        let (done_tx, done_rx) = unbounded_channel();
+        Self { journal, active: HashMap::new(), replayed_through: HashMap::new(), done_tx, done_rx }
+    }
+
+    pub(crate) fn is_active(&self, subscription: &Subscription) -> bool {
+        self.active.contains_key(subscription)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.active.len()
+    }
+
+    // the subscriptions still replaying, which the manager only gets once they are live
+    pub(crate) fn subscriptions(&self) -> impl Iterator<Item = &Subscription> {
+        self.active.keys()
+    }
+
+    // acknowledges the replay and starts sending history; the error is meant for the client
+    pub(crate) fn start(
+        &mut self,
+        queue: &Arc<SendQueue>,
+        subscription: Subscription,
+        from: ReplayFrom,
+    ) -> Result<(), String> {
+        let journal = self.journal.clone().ok_or("Replay is not enabled on this server")?;
+        let Subscription::L4Book { coin, .. } = &subscription else {
+            return Err("Only l4Book subscriptions can be replayed".to_string());
+        };
+        match from {
+            ReplayFrom::Seq(seq) => {
+                let last_seq = journal.last_seq(coin).unwrap_or_default();
+                if seq > last_seq + 1 {
+                    return Err(format!("fromSeq {seq} is ahead of the stream (last seq is {last_seq})"));
+                }
+                if let Some(first_seq) = journal.first_seq(coin)
+                    && seq < first_seq
+                {
+                    return Err(format!("fromSeq {seq} is no longer available, the journal starts at {first_seq}"));
+                }
+            }
+            ReplayFrom::Time(time) => {
+                if time < journal.start_time() {
+                    return Err(format!("fromTs {time} is before the journal starts ({})", journal.start_time()));
+                }
+            }
+        }
+        let (from_seq, from_ts) = match from {
+            ReplayFrom::Seq(seq) => (Some(seq), None),
+            ReplayFrom::Time(time) => (None, Some(time)),
+        };
+        let ack = ClientMessage::Replay { subscription: subscription.clone(), from_seq, from_ts };
+        queue.push(None, ServerResponse::SubscriptionResponse(ack));
+
+        info!("Replaying {coin} from {from:?}");
+        let task = {
+            let (queue, subscription, coin, done_tx) =
+                (queue.clone(), subscription.clone(), coin.clone(), self.done_tx.clone());
+            tokio::spawn(async move {
+                let res = replay(journal, &queue, &subscription, coin, from).await;
+                let _unused = done_tx.send((subscription, res));
+            })
+        };
+        self.insert(subscription, from, task.abort_handle());
+        Ok(())
+    }
+
+    // acknowledges the resume and sends the updates the client missed, which the listener kept in memory
+    pub(crate) fn resume(
+        &mut self,
+        queue: &Arc<SendQueue>,
+        subscription: Subscription,
+        from_seq: u64,
+        missed: Vec<L4BookUpdates>,
+    ) {
+        let ack = ClientMessage::Resume { subscription: subscription.clone(), from_seq };
+        queue.push(None, ServerResponse::SubscriptionResponse(ack));
+        let task = {
+            let (queue, subscription, done_tx) = (queue.clone(), subscription.clone(), self.done_tx.clone());
+            tokio::spawn(async move {
+                let mut last_seq = from_seq.checked_sub(1);
+                let res = send(&queue, &subscription, missed, &mut last_seq).await.map(|()| last_seq);
+                let _unused = done_tx.send((subscription, res));
+            })
+        };
+        self.insert(subscription, ReplayFrom::Seq(from_seq), task.abort_handle());
+    }
+
+    fn insert(&mut self, subscription: Subscription, from: ReplayFrom, task: AbortHandle) {
+        self.replayed_through.remove(&subscription);
+        self.active.insert(subscription, ActiveReplay { from, task, buffered: Vec::new() });
+    }
+
+    // stops a replay in progress; false if the subscription isn't replaying
+    pub(crate) fn cancel(&mut self, subscription: &Subscription) -> bool {
+        self.replayed_through.remove(subscription);
+        self.active.remove(subscription).is_some_and(|replay| {
+            replay.task.abort();
+            true
+        })
+    }
+
+    // holds back the live updates of replaying subscriptions
+    pub(crate) fn on_book_updates(&mut self, queue: &SendQueue, updates: &HashMap<String, L4BookUpdates>) {
+        let mut behind = Vec::new();
+        for (subscription, replay) in &mut self.active {
+            if let Subscription::L4Book { coin, .. } = subscription
+                && let Some(update) = updates.get(coin)
+            {
+                if replay.buffered.len() >= MAX_BUFFERED_UPDATES {
+                    behind.push(subscription.clone());
+                } else {
+                    replay.buffered.push(update.clone());
+                }
+            }
+        }
+        for subscription in behind {
+            self.cancel(&subscription);
+            let msg = ServerResponse::Error("Replay fell too far behind the live stream".to_string());
+            queue.push(None, msg);
+        }
+    }
+
+    // the next replay to catch up with the journal
+    pub(crate) async fn finished(&mut self) -> (Subscription, ReplayResult) {
+        match self.done_rx.recv().await {
+            Some(done) => done,
+            // we hold a sender ourselves
+            None => std::future::pending().await,
+        }
+    }
+
+    // switches a replayed subscription to live
+    pub(crate) fn finish(
+        &mut self,
+        queue: &SendQueue,
+        manager: &mut SubscriptionManager,
+        subscription: Subscription,
+        res: ReplayResult,
+    ) {
+        // cancelled in the meantime
+        let Some(replay) = self.active.remove(&subscription) else {
+            return;
+        };
+        match res {
+            Ok(last_seq) => {
+                for update in replay.buffered {
+                    let unsent =
+                        last_seq.map_or_else(|| replay.from.includes(&update), |last_seq| update.seq > last_seq);
+                    if unsent {
+                        queue.push(Some(&subscription), ServerResponse::L4Book(L4Book::Updates(update)));
+                    }
+                }
+                if let Some(last_seq) = last_seq {
+                    self.replayed_through.insert(subscription.clone(), last_seq);
+                }
+                manager.subscribe(subscription);
+            }
+            Err(err) => queue.push(None, ServerResponse::Error(format!("Replay failed: {err}"))),
+        }
+    }
+
+    // whether a live update was already sent by the subscription's replay
+    pub(crate) fn already_sent(&mut self, subscription: &Subscription, update: &L4BookUpdates) -> bool {
+        match self.replayed_through.get(subscription) {
+            Some(last_seq) if update.seq <= *last_seq => true,
+            Some(_) => {
+                self.replayed_through.remove(subscription);
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+impl Drop for Replays {
+    fn drop(&mut self) {
+        for replay in self.active.values() {
+            replay.task.abort();
+        }
+    }
+}
+
+// sends the journaled updates in order
+async fn replay(
+    journal: Arc<Journal>,
+    queue: &SendQueue,
+    subscription: &Subscription,
+    coin: String,
+    from: ReplayFrom,
+) -> ReplayResult {
+    let mut cursor = ReplayCursor::new(from);
+    let mut last_seq = match from {
+        ReplayFrom::Seq(seq) => seq.checked_sub(1),
+        ReplayFrom::Time(_) => None,
+    };
+    loop {
+        let read = {
+            let (journal, coin) = (journal.clone(), coin.clone());
+            spawn_blocking(move || journal.read(&coin, &mut cursor, REPLAY_BATCH).map(|updates| (updates, cursor)))
+        };
+        let (updates, next_cursor) = read.await.map_err(|err| err.to_string())?.map_err(|err| err.to_string())?;
+        cursor = next_cursor;
+        if updates.is_empty() {
+            return Ok(last_seq);
+        }
+        send(queue, subscription, updates, &mut last_seq).await?;
+    }
+}
+
+// sends updates that continue `last_seq`, as fast as the client reads them
+async fn send(
+    queue: &SendQueue,
+    subscription: &Subscription,
+    updates: Vec<L4BookUpdates>,
+    last_seq: &mut Option<u64>,
+) -> Result<(), String> {
+    for update in updates {
+        if let Some(last_seq) = *last_seq
+            && update.seq != last_seq + 1
+        {
+            return Err(format!("updates from seq {} on are no longer available", last_seq + 1));
+        }
+        *last_seq = Some(update.seq);
+        queue.wait_for_room().await;
+        if queue.is_closing() {
+            return Err("connection closed".to_string());
+        }
+        queue.push(Some(subscription), ServerResponse::L4Book(L4Book::Updates(update)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        journal::JournalConfig,
+        prelude::*,
+        servers::send_queue::{BackpressurePolicy, Outgoing},
+    };
+
+    fn updates(seq: u64) -> HashMap<String, L4BookUpdates> {
+        let mut update = L4BookUpdates::new(seq, seq);
+        update.seq = seq;
+        HashMap::from([("BTC".to_string(), update)])
+    }
+
+    #[tokio::test]
+    async fn test_replay_then_live_without_duplicates() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let journal = Arc::new(Journal::create(JournalConfig::new(dir.path().to_path_buf()))?);
+        for seq in 1..=3 {
+            journal.append(&updates(seq))?;
+        }
+        let queue = Arc::new(SendQueue::new(BackpressurePolicy::Disconnect, 16));
+        let mut manager = SubscriptionManager::default();
+        let mut replays = Replays::new(Some(journal));
+        let subscription = Subscription::L4Book { coin: "BTC".to_string(), conflate_ms: None };
+        assert!(replays.start(&queue, subscription.clone(), ReplayFrom::Seq(5)).is_err());
+        replays.start(&queue, subscription.clone(), ReplayFrom::Seq(2))?;
+        // broadcast while replaying: 3 is in the journal already, 4 isn't
+        replays.on_book_updates(&queue, &updates(3));
+        replays.on_book_updates(&queue, &updates(4));
+        let (finished, res) = replays.finished().await;
+        assert_eq!(res, Ok(Some(3)));
+        replays.finish(&queue, &mut manager, finished, res);
+        assert!(manager.subscriptions().contains(&subscription));
+        // late broadcasts of replayed updates are skipped
+        assert!(replays.already_sent(&subscription, &updates(3)["BTC"]));
+        assert!(!replays.already_sent(&subscription, &updates(5)["BTC"]));
+
+        queue.close(yawc::FrameView::close(yawc::close::CloseCode::Normal, ""));
+        let mut seqs = Vec::new();
+        while let Some(outgoing) = queue.next().await {
+            if let Outgoing::Message(msg, _) = outgoing {
+                match msg.msg() {
+                    ServerResponse::L4Book(L4Book::Updates(update)) => seqs.push(update.seq),
+                    msg => assert!(matches!(msg, ServerResponse::SubscriptionResponse(_))),
+                }
+            }
+        }
+        assert_eq!(seqs, vec![2, 3, 4]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resume_then_live() {
+        let queue = Arc::new(SendQueue::new(BackpressurePolicy::Disconnect, 16));
+        let mut manager = SubscriptionManager::default();
+        let mut replays = Replays::new(None);
+        let subscription = Subscription::L4Book { coin: "BTC".to_string(), conflate_ms: None };
+        let missed = (3..=4).map(|seq| updates(seq).remove("BTC").unwrap_or_else(|| L4BookUpdates::new(0, 0)));
+        replays.resume(&queue, subscription.clone(), 3, missed.collect());
+        replays.on_book_updates(&queue, &updates(5));
+        let (finished, res) = replays.finished().await;
+        assert_eq!(res, Ok(Some(4)));
+        replays.finish(&queue, &mut manager, finished, res);
+        assert!(manager.subscriptions().contains(&subscription));
+
+        queue.close(yawc::FrameView::close(yawc::close::CloseCode::Normal, ""));
+        let mut msgs = Vec::new();
+        while let Some(outgoing) = queue.next().await {
+            if let Outgoing::Message(msg, _) = outgoing {
+                msgs.push(match msg.msg() {
+                    ServerResponse::L4Book(L4Book::Updates(update)) => update.seq,
+                    ServerResponse::SubscriptionResponse(ClientMessage::Resume { from_seq, .. }) => *from_seq * 100,
+                    _ => 0,
+                });
+            }
+        }
+        // the ack, the missed updates and the one held back
+        assert_eq!(msgs, vec![300, 3, 4, 5]);
+    }
+}
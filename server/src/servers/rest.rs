@@ -0,0 +1,259 @@
+use std::{collections::HashSet, fmt, path::PathBuf, sync::Arc};
+
+use axum::{
+    Router,
+    extract::{Path, Query},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use serde::Deserialize;
+use tokio::{sync::Mutex, task::spawn_blocking};
+
+use crate::{
+    archive::{HistoryError, book_at},
+    candles::CandleInterval,
+    listeners::order_book::OrderBookListener,
+    servers::{
+        auth::{AuthError, Authenticator},
+        encoding::Encoding,
+        markets::Markets,
+        tenants::Tenant,
+    },
+    types::subscription::{DEFAULT_LEVELS, ServerResponse, Subscription},
+};
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SnapshotQuery {
+    depth: Option<usize>,
+    n_sig_figs: Option<u32>,
+    mantissa: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct HistoryQuery {
+    // block time in milliseconds
+    ts: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CandleQuery {
+    interval: CandleInterval,
+    start_time: Option<u64>,
+    end_time: Option<u64>,
+    limit: Option<usize>,
+}
+
+// point-in-time snapshots, historical books from the archive and candle history for clients that don't want to hold a
+// websocket open. Responses are JSON, or MessagePack for requests accepting it
+pub(crate) fn routes(markets: Markets, auth: Option<Arc<Authenticator>>, archive_dir: Option<PathBuf>) -> Router {
+    let (snapshot_markets, info_markets, history_markets) = (markets.clone(), markets.clone(), markets.clone());
+    let (snapshot_auth, info_auth, history_auth) = (auth.clone(), auth.clone(), auth.clone());
+    Router::new()
+        .route(
+            "/markets",
+            get(async move |headers: HeaderMap| {
+                let tenant = match authorize(info_auth.as_deref(), &headers) {
+                    Ok(tenant) => tenant,
+                    Err(err) => return (StatusCode::UNAUTHORIZED, err.to_string()).into_response(),
+                };
+                Encoding::accepted(&headers).response(&info_markets.market_info(tenant.as_deref()).await)
+            }),
+        )
+        .route(
+            "/orderbook/{market}",
+            get(async move |headers: HeaderMap, Path(market): Path<String>, Query(query): Query<SnapshotQuery>| {
+                if let Some(res) = unauthorized(snapshot_auth.as_deref(), &headers, &snapshot_markets, &market) {
+                    return res;
+                }
+                let encoding = Encoding::accepted(&headers);
+                l2_snapshot(snapshot_markets.for_coin(&market), market, query, encoding).await
+            }),
+        )
+        .route(
+            "/orderbook/{market}/at",
+            get(async move |headers: HeaderMap, Path(market): Path<String>, Query(query): Query<HistoryQuery>| {
+                if let Some(res) = unauthorized(history_auth.as_deref(), &headers, &history_markets, &market) {
+                    return res;
+                }
+                historical_book(archive_dir.clone(), market, query.ts, Encoding::accepted(&headers)).await
+            }),
+        )
+        .route(
+            "/candles/{market}",
+            get(async move |headers: HeaderMap, Path(market): Path<String>, Query(query): Query<CandleQuery>| {
+                if let Some(res) = unauthorized(auth.as_deref(), &headers, &markets, &market) {
+                    return res;
+                }
+                candles(markets.for_coin(&market), &market, &query, Encoding::accepted(&headers)).await
+            }),
+        )
+}
+
+// the tenant of the client, if it belongs to one
+fn authorize(auth: Option<&Authenticator>, headers: &HeaderMap) -> Result<Option<Arc<Tenant>>, AuthError> {
+    let Some(auth) = auth else {
+        return Ok(None);
+    };
+    Ok(auth.tenant(&auth.check(headers)?))
+}
+
+// the coins of markets outside the client's tenant are forbidden
+fn unauthorized(auth: Option<&Authenticator>, headers: &HeaderMap, markets: &Markets, coin: &str) -> Option<Response> {
+    match authorize(auth, headers) {
+        Ok(tenant) if !markets.allows(tenant.as_deref(), coin) => {
+            Some((StatusCode::FORBIDDEN, format!("Market not available to this key: {coin}")).into_response())
+        }
+        Ok(_) => None,
+        Err(err) => Some((StatusCode::UNAUTHORIZED, err.to_string()).into_response()),
+    }
+}
+
+// oldest first; markets that haven't traded since startup have none
+async fn candles(
+    listener: &Arc<Mutex<OrderBookListener>>,
+    coin: &str,
+    query: &CandleQuery,
+    encoding: Encoding,
+) -> Response {
+    let history = {
+        let listener = listener.lock().await;
+        listener
+            .candles()
+            .filter(|candles| candles.has_interval(query.interval))
+            .map(|candles| candles.history(coin, query.interval, query.start_time, query.end_time, query.limit))
+    };
+    let Some(history) = history else {
+        return (StatusCode::NOT_FOUND, format!("Candle interval not enabled: {}", query.interval)).into_response();
+    };
+    encoding.response(&history)
+}
+
+// the book as it was at `time`, rebuilt from the archive
+async fn historical_book(archive_dir: Option<PathBuf>, coin: String, time: u64, encoding: Encoding) -> Response {
+    let Some(dir) = archive_dir else {
+        return (StatusCode::NOT_FOUND, "No archive to read historical books from (see --archive-dir)").into_response();
+    };
+    match spawn_blocking(move || book_at(&dir, &coin, time)).await {
+        Ok(Ok(book)) => encoding.response(&book),
+        Ok(Err(err)) => {
+            let status = match err {
+                HistoryError::NotArchived | HistoryError::InProgress => StatusCode::NOT_FOUND,
+                HistoryError::Unreadable(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            (status, err.to_string()).into_response()
+        }
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    }
+}
+
+async fn l2_snapshot(
+    listener: &Arc<Mutex<OrderBookListener>>,
+    coin: String,
+    query: SnapshotQuery,
+    encoding: Encoding,
+) -> Response {
+    let subscription = match l2_subscription(listener, coin, query.depth, query.n_sig_figs, query.mantissa).await {
+        Ok(subscription) => subscription,
+        Err(err) => {
+            let status = match err {
+                BookRequestError::NotReady => StatusCode::SERVICE_UNAVAILABLE,
+                BookRequestError::UnknownMarket(_) => StatusCode::NOT_FOUND,
+                BookRequestError::InvalidParams => StatusCode::BAD_REQUEST,
+            };
+            return (status, err.to_string()).into_response();
+        }
+    };
+    match subscription.handle_immediate_snapshot(listener.clone()).await {
+        Ok(Some(ServerResponse::L2Book(book))) => encoding.response(&book),
+        Ok(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        Err(err) => {
+            (StatusCode::SERVICE_UNAVAILABLE, format!("Unable to grab order book snapshot: {err}")).into_response()
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum BookRequestError {
+    NotReady,
+    UnknownMarket(String),
+    InvalidParams,
+}
+
+impl fmt::Display for BookRequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotReady => write!(f, "Order book not ready (waiting for snapshot)"),
+            Self::UnknownMarket(coin) => write!(f, "Unknown market: {coin}"),
+            Self::InvalidParams => write!(f, "Invalid depth, nSigFigs or mantissa"),
+        }
+    }
+}
+
+// validated l2 subscription for one-off and non-websocket requests (REST and gRPC)
+pub(crate) async fn l2_subscription(
+    listener: &Arc<Mutex<OrderBookListener>>,
+    coin: String,
+    depth: Option<usize>,
+    n_sig_figs: Option<u32>,
+    mantissa: Option<u64>,
+) -> Result<Subscription, BookRequestError> {
+    let universe: HashSet<String> = {
+        let listener = listener.lock().await;
+        if !listener.is_ready() {
+            return Err(BookRequestError::NotReady);
+        }
+        listener.universe().into_iter().map(|c| c.value()).collect()
+    };
+    if !universe.contains(&coin) {
+        return Err(BookRequestError::UnknownMarket(coin));
+    }
+    let subscription = Subscription::L2Book {
+        coin,
+        n_sig_figs,
+        // the subscription encodes the default depth as None
+        n_levels: depth.filter(|depth| *depth != DEFAULT_LEVELS),
+        mantissa,
+        conflate_ms: None,
+        tick_size: None,
+        fields: None,
+        delta: None,
+    };
+    if !subscription.validate(&universe) {
+        return Err(BookRequestError::InvalidParams);
+    }
+    Ok(subscription)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::prelude::*;
+
+    #[tokio::test]
+    async fn test_snapshot_unavailable_until_ready() -> Result<()> {
+        let listener = Arc::new(Mutex::new(OrderBookListener::new(None, true)));
+        let tcp_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = tcp_listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(tcp_listener, routes(Markets::new(listener), None, None)).await });
+        let res = reqwest::get(format!("http://{address}/orderbook/BTC?depth=50")).await?;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let res = reqwest::Client::new()
+            .get(format!("http://{address}/markets"))
+            .header("accept", "application/msgpack")
+            .send()
+            .await?;
+        assert_eq!(
+            res.headers().get("content-type").and_then(|value| value.to_str().ok()),
+            Some("application/msgpack")
+        );
+        let markets: Vec<serde_json::Value> = rmp_serde::from_slice(&res.bytes().await?)?;
+        assert!(markets.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,168 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    latency::now_ms,
+    servers::{
+        encoding::Negotiated,
+        protocol::{ErrorCode, ProtocolError},
+        send_queue::{QueueStats, SendQueue},
+        websocket_server::Universe,
+    },
+    types::{
+        MarketInfo,
+        subscription::{ClientMessage, ServerResponse, Subscription, SubscriptionManager},
+    },
+};
+
+/// The id of a request, echoed in its response. A number or a string, as the client chooses.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum RequestId {
+    Number(u64),
+    String(String),
+}
+
+/// The answer to a request on the `response` channel: `{"id": ..., "result": ...}`, or `{"id": ..., "error":
+/// {"code": ..., "msg": ...}}` if it couldn't be served.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct RpcResponse {
+    pub(crate) id: RequestId,
+    #[serde(flatten)]
+    pub(crate) outcome: Outcome,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum Outcome {
+    Result(RpcResult),
+    Error(ProtocolError),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum RpcResult {
+    // the message a subscription starts with, e.g. `{"channel": "l2Book", "data": {...}}`
+    Snapshot(Box<ServerResponse>),
+    Markets(Vec<MarketInfo>),
+    // in ms since the epoch, with the client's time of the request if it sent one
+    #[serde(rename_all = "camelCase")]
+    Time {
+        time: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        client_time: Option<u64>,
+    },
+    Pong(String),
+    Stats(ConnectionReport),
+}
+
+/// The answer to a `stats` request: the send queue of the connection that sent it, what it negotiated, and its
+/// subscriptions.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ConnectionReport {
+    pub(crate) queue: QueueStats,
+    #[serde(flatten)]
+    pub(crate) negotiated: Negotiated,
+    pub(crate) subscriptions: Vec<Subscription>,
+}
+
+// the response to a request with an id, None for any other message
+pub(crate) async fn answer(request: &ClientMessage, universe: &Universe) -> Option<ServerResponse> {
+    let (id, result) = match request {
+        ClientMessage::GetSnapshot { id, subscription } => (id, snapshot(subscription, universe).await),
+        ClientMessage::GetMarkets { id } => {
+            (id, Ok(RpcResult::Markets(universe.markets.market_info(universe.tenant.as_deref()).await)))
+        }
+        ClientMessage::ServerTime { id, client_time } => {
+            (id, Ok(RpcResult::Time { time: now_ms(), client_time: *client_time }))
+        }
+        ClientMessage::Ping { id } => (id, Ok(RpcResult::Pong("pong".to_string()))),
+        _ => return None,
+    };
+    let outcome =
+        result.map_or_else(|err| Outcome::Error(ProtocolError::new(ErrorCode::Rejected, err)), Outcome::Result);
+    Some(ServerResponse::Response(RpcResponse { id: id.clone(), outcome }))
+}
+
+// the answer to a `stats` request, from the state of the connection that sent it
+pub(crate) fn stats(
+    id: &RequestId,
+    queue: &SendQueue,
+    manager: &SubscriptionManager,
+    universe: &Universe,
+) -> ServerResponse {
+    let report = ConnectionReport {
+        queue: queue.stats(),
+        negotiated: universe.negotiated.clone(),
+        subscriptions: manager.subscriptions().iter().cloned().collect(),
+    };
+    ServerResponse::Response(RpcResponse { id: id.clone(), outcome: Outcome::Result(RpcResult::Stats(report)) })
+}
+
+// the message a subscription would start with, without subscribing
+async fn snapshot(subscription: &Subscription, universe: &Universe) -> Result<RpcResult, String> {
+    // this is used for display purposes only, hence unwrap_or_default. It also shouldn't fail
+    let sub = serde_json::to_string(subscription).unwrap_or_default();
+    universe.validate(subscription, &sub)?;
+    let listener = universe.markets.for_subscription(subscription);
+    match subscription.handle_immediate_snapshot(listener).await {
+        Ok(Some(msg)) => Ok(RpcResult::Snapshot(Box::new(msg))),
+        Ok(None) => Err(format!("No snapshot available for: {sub}")),
+        Err(err) => Err(format!("Unable to grab order book snapshot: {err}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        prelude::*,
+        servers::{
+            encoding::{Compression, Numbers},
+            send_queue::BackpressurePolicy,
+        },
+    };
+
+    #[test]
+    fn test_response_format() -> Result<()> {
+        let time = RpcResponse {
+            id: RequestId::Number(7),
+            outcome: Outcome::Result(RpcResult::Time { time: 42, client_time: None }),
+        };
+        let json = serde_json::to_string(&ServerResponse::Response(time))?;
+        assert_eq!(json, r#"{"channel":"response","data":{"id":7,"result":{"time":42}}}"#);
+        let echoed = RpcResult::Time { time: 42, client_time: Some(40) };
+        assert_eq!(serde_json::to_string(&echoed)?, r#"{"time":42,"clientTime":40}"#);
+
+        let error = ProtocolError::new(ErrorCode::Rejected, "Invalid subscription");
+        let rejected = RpcResponse { id: RequestId::String("a".to_string()), outcome: Outcome::Error(error) };
+        let json = serde_json::to_string(&rejected)?;
+        assert_eq!(json, r#"{"id":"a","error":{"code":1006,"msg":"Invalid subscription"}}"#);
+
+        let snapshot = RpcResult::Snapshot(Box::new(ServerResponse::Trades(Vec::new())));
+        let json = serde_json::to_string(&snapshot)?;
+        assert_eq!(json, r#"{"channel":"trades","data":[]}"#);
+
+        let queue = SendQueue::new(BackpressurePolicy::DropOldest, 1);
+        queue.push(None, ServerResponse::Error("first".to_string()));
+        queue.push(None, ServerResponse::Error("second".to_string()));
+        let mut manager = SubscriptionManager::default();
+        manager.subscribe(Subscription::Trades { coin: "BTC".to_string() });
+        let negotiated = Negotiated {
+            subprotocol: "orderbook.msgpack".to_string(),
+            compression: Compression::Deflate,
+            numbers: Numbers::Float,
+            batch_ms: None,
+        };
+        let report = ConnectionReport {
+            queue: queue.stats(),
+            negotiated,
+            subscriptions: manager.subscriptions().iter().cloned().collect(),
+        };
+        assert_eq!(
+            serde_json::to_string(&RpcResult::Stats(report))?,
+            r#"{"queue":{"depth":1,"capacity":1,"dropped":1,"conflated":0},"subprotocol":"orderbook.msgpack","compression":"deflate","numbers":"float","batchMs":null,"subscriptions":[{"type":"trades","coin":"BTC"}]}"#
+        );
+        Ok(())
+    }
+}
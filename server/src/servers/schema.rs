@@ -0,0 +1,953 @@
+use std::path::{Path, PathBuf};
+
+use serde_json::{Map, Value, json};
+
+use crate::{prelude::*, servers::encoding::Version};
+
+// the gRPC service and the frames of the protobuf subprotocols, whose messages are versioned by the proto package
+const PROTO: &str = include_str!("../../proto/orderbook.proto");
+
+const DIALECT: &str = "https://json-schema.org/draft/2020-12/schema";
+
+/// Writes the definitions of the messages to `dir`, for clients to generate their types from.
+///
+/// The JSON Schemas of the requests and of the server's messages over the websocket go to a directory per protocol
+/// version (`v1/client.schema.json`, `v1/server.schema.json`, ...), the protobuf definitions of the gRPC service and
+/// of the protobuf frames to `orderbook.proto`. Returns the files written.
+pub fn dump_schema(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for (version, name) in [(Version::V1, "v1"), (Version::V2, "v2")] {
+        let version_dir = dir.join(name);
+        fs::create_dir_all(&version_dir).map_err(|err| format!("Unable to create {}: {err}", version_dir.display()))?;
+        for (file, schema) in
+            [("client.schema.json", client_schema(version)), ("server.schema.json", server_schema(version))]
+        {
+            let path = version_dir.join(file);
+            write(&path, &serde_json::to_string_pretty(&schema)?)?;
+            files.push(path);
+        }
+    }
+    let path = dir.join("orderbook.proto");
+    write(&path, PROTO)?;
+    files.push(path);
+    Ok(files)
+}
+
+fn write(path: &Path, contents: &str) -> Result<()> {
+    Ok(fs::write(path, contents).map_err(|err| format!("Unable to write {}: {err}", path.display()))?)
+}
+
+// the requests of a client, which are JSON text frames whatever the encoding of the connection
+pub(crate) fn client_schema(version: Version) -> Value {
+    let mut defs = Map::new();
+    client_definitions(&mut defs);
+    json!({
+        "$schema": DIALECT,
+        "title": format!("Client message, protocol version {}", version_number(version)),
+        "$ref": "#/$defs/clientMessage",
+        "$defs": defs,
+    })
+}
+
+// a frame of the server: a message, or an array of them on connections that negotiated batching
+pub(crate) fn server_schema(version: Version) -> Value {
+    let mut defs = Map::new();
+    client_definitions(&mut defs);
+    book_definitions(&mut defs);
+    market_definitions(&mut defs);
+    connection_definitions(&mut defs);
+    defs.insert("serverMessage".to_string(), server_message(version));
+    json!({
+        "$schema": DIALECT,
+        "title": format!("Server message, protocol version {}", version_number(version)),
+        "description": "A message, or an array of messages on connections that negotiated batching. MessagePack \
+                        frames carry the same structure.",
+        "oneOf": [reference("serverMessage"), array(reference("serverMessage"))],
+        "$defs": defs,
+    })
+}
+
+const fn version_number(version: Version) -> u8 {
+    match version {
+        Version::V1 => 1,
+        Version::V2 => 2,
+    }
+}
+
+// the channels of `ServerResponse` and the definitions of their data
+const CHANNELS: [(&str, &str); 22] = [
+    ("subscriptionResponse", "clientMessage"),
+    ("l2Book", "l2Book"),
+    ("l2Delta", "l2Delta"),
+    ("bbo", "bbo"),
+    ("l4Book", "l4Book"),
+    ("l3Book", "l3Book"),
+    ("userOrders", "userOrders"),
+    ("trades", "trades"),
+    ("candle", "candle"),
+    ("analytics", "analytics"),
+    ("checkpoint", "checkpoint"),
+    ("heartbeat", "heartbeat"),
+    ("time", "timeSync"),
+    ("halt", "marketHalt"),
+    ("resume", "marketHalt"),
+    ("status", "streamStatus"),
+    ("markets", "markets"),
+    ("marketChanges", "marketChanges"),
+    ("maintenance", "maintenanceNotice"),
+    ("session", "sessionToken"),
+    ("evicted", "slowConsumerReport"),
+    ("response", "rpcResponse"),
+];
+
+fn server_message(version: Version) -> Value {
+    let mut messages = CHANNELS
+        .iter()
+        .map(|(channel, data)| object(vec![("channel", json!({ "const": channel })), ("data", reference(data))], &[]))
+        .collect::<Vec<_>>();
+    // version 2 sends every error as a protocol error
+    if version == Version::V1 {
+        messages.push(object(vec![("channel", json!({ "const": "error" })), ("data", string())], &[]));
+    }
+    messages.push(described(
+        object(vec![("error", reference("protocolError"))], &[]),
+        "A rejected request, without a channel",
+    ));
+    json!({ "oneOf": messages })
+}
+
+fn client_definitions(defs: &mut Map<String, Value>) {
+    let subscription = |method| object(vec![("subscription", reference("subscription"))], &[]).tagged("method", method);
+    let request = |method| object(vec![("id", reference("requestId"))], &[]).tagged("method", method);
+    let client_message = vec![
+        subscription("subscribe"),
+        subscription("unsubscribe"),
+        described(subscription("snapshot"), "A fresh snapshot for an existing subscription"),
+        described(
+            object(vec![("token", string())], &[]).tagged("method", "auth"),
+            "Only accepted as the first message",
+        ),
+        described(
+            object(
+                vec![("subscription", reference("subscription")), ("fromSeq", integer()), ("fromTs", integer())],
+                &["fromSeq", "fromTs"],
+            )
+            .tagged("method", "replay"),
+            "The journaled l4 updates from a seq or block time (in ms) on, then live. Takes one of fromSeq and fromTs",
+        ),
+        object(vec![("subscription", reference("subscription")), ("fromSeq", integer())], &[])
+            .tagged("method", "resume"),
+        object(vec![], &[]).tagged("method", "listMarkets"),
+        object(vec![("id", reference("requestId")), ("subscription", reference("subscription"))], &[])
+            .tagged("method", "getSnapshot"),
+        request("getMarkets"),
+        object(vec![("id", reference("requestId")), ("clientTime", integer())], &["clientTime"])
+            .tagged("method", "serverTime"),
+        request("ping"),
+        request("stats"),
+    ];
+    let coin = |kind| object(vec![("coin", string())], &[]).tagged("type", kind);
+    let subscription = vec![
+        coin("trades"),
+        object(
+            vec![
+                ("coin", string()),
+                ("nSigFigs", nullable(integer())),
+                ("nLevels", nullable(integer())),
+                ("mantissa", nullable(integer())),
+                ("conflateMs", integer()),
+                ("tickSize", decimal()),
+                ("fields", array(string_enum(&["time", "seq", "checksum", "n"]))),
+                ("delta", boolean()),
+            ],
+            &["nSigFigs", "nLevels", "mantissa", "conflateMs", "tickSize", "fields", "delta"],
+        )
+        .tagged("type", "l2Book"),
+        object(vec![("coin", string()), ("fields", array(string_enum(&["time", "seq", "mid", "n"])))], &["fields"])
+            .tagged("type", "bbo"),
+        object(vec![("coin", string()), ("interval", reference("candleInterval"))], &[]).tagged("type", "candle"),
+        coin("analytics"),
+        coin("checkpoint"),
+        object(vec![("coin", string()), ("conflateMs", integer())], &["conflateMs"]).tagged("type", "l4Book"),
+        coin("l3Book"),
+        described(
+            object(vec![("user", reference("address")), ("timestamp", integer()), ("signature", string())], &[])
+                .tagged("type", "userOrders"),
+            "`signature` is the account's personal_sign signature of `userOrders:<user>:<timestamp>`",
+        ),
+        object(vec![], &[]).tagged("type", "status"),
+        object(vec![], &[]).tagged("type", "markets"),
+    ];
+    insert(defs, "clientMessage", json!({ "oneOf": client_message }));
+    insert(defs, "subscription", json!({ "oneOf": subscription }));
+    insert(
+        defs,
+        "requestId",
+        described(json!({ "oneOf": [integer(), string()] }), "Echoed in the response to the request"),
+    );
+    insert(defs, "candleInterval", described(string(), "e.g. 1s, 1m, 5m, 1h or 1d"));
+    insert(
+        defs,
+        "address",
+        described(
+            json!({ "type": "string", "pattern": "^0x[0-9a-fA-F]{40}$" }),
+            "Hex encoded, in MessagePack frames too",
+        ),
+    );
+    insert(
+        defs,
+        "decimal",
+        described(
+            string(),
+            "A price or an amount as a decimal string, `{\"units\": <integer>, \"decimals\": <integer>}` on \
+             connections that negotiated numbers=integer and a number on those that negotiated numbers=float",
+        ),
+    );
+}
+
+#[allow(clippy::too_many_lines)]
+fn book_definitions(defs: &mut Map<String, Value>) {
+    insert(defs, "level", object(vec![("px", decimal()), ("sz", decimal()), ("n", integer())], &["n"]));
+    insert(
+        defs,
+        "l2Book",
+        described(
+            object(
+                vec![
+                    ("coin", string()),
+                    ("time", integer()),
+                    ("levels", sides(reference("level"))),
+                    ("seq", integer()),
+                    ("checksum", integer()),
+                ],
+                &["time", "seq", "checksum"],
+            ),
+            "Bids and asks, best price first. The optional fields are left out when the subscription lists others",
+        ),
+    );
+    insert(
+        defs,
+        "l2Delta",
+        object(
+            vec![
+                ("coin", string()),
+                ("time", integer()),
+                ("seq", integer()),
+                ("prevSeq", integer()),
+                ("epoch", integer()),
+                ("changes", sides(reference("levelChange"))),
+                ("checksum", integer()),
+            ],
+            &["time", "checksum"],
+        ),
+    );
+    insert(
+        defs,
+        "levelChange",
+        described(
+            object(vec![("i", integer()), ("px", decimal()), ("sz", decimal()), ("n", integer())], &["px", "n"]),
+            "A level that changed, or was removed with a size of 0. `px` only the first time the index is referenced",
+        ),
+    );
+    insert(
+        defs,
+        "bbo",
+        object(
+            vec![
+                ("coin", string()),
+                ("time", integer()),
+                ("seq", integer()),
+                ("bid", nullable(reference("level"))),
+                ("ask", nullable(reference("level"))),
+                ("mid", nullable(decimal())),
+            ],
+            &["time", "seq", "mid"],
+        ),
+    );
+    let l4_snapshot = object(
+        vec![
+            ("coin", string()),
+            ("time", integer()),
+            ("height", integer()),
+            ("seq", integer()),
+            ("levels", sides(reference("l4Order"))),
+            ("checksum", integer()),
+            ("checkpoint", reference("checkpoint")),
+        ],
+        &["checkpoint"],
+    );
+    insert(defs, "l4Book", variants(vec![("Snapshot", l4_snapshot), ("Updates", reference("l4BookUpdates"))]));
+    insert(
+        defs,
+        "l4BookUpdates",
+        object(
+            vec![
+                ("time", integer()),
+                ("height", integer()),
+                ("seq", integer()),
+                ("checksum", integer()),
+                ("order_statuses", array(reference("orderStatus"))),
+                ("book_diffs", array(reference("orderDiff"))),
+            ],
+            &[],
+        ),
+    );
+    insert(
+        defs,
+        "l4Order",
+        object(
+            vec![
+                ("user", nullable(reference("address"))),
+                ("coin", string()),
+                ("side", reference("side")),
+                ("limitPx", decimal()),
+                ("sz", decimal()),
+                ("oid", integer()),
+                ("timestamp", integer()),
+                ("triggerCondition", string()),
+                ("isTrigger", boolean()),
+                ("triggerPx", decimal()),
+                ("isPositionTpsl", boolean()),
+                ("reduceOnly", boolean()),
+                ("orderType", string()),
+                ("tif", nullable(string())),
+                ("cloid", nullable(string())),
+            ],
+            &[],
+        ),
+    );
+    insert(defs, "orderStatus", object(order_status(), &[]));
+    insert(
+        defs,
+        "orderDiff",
+        object(
+            vec![
+                ("user", reference("address")),
+                ("oid", integer()),
+                ("px", decimal()),
+                ("coin", string()),
+                (
+                    "raw_book_diff",
+                    json!({ "oneOf": [
+                        variants(vec![("new", object(vec![("sz", decimal())], &[]))]),
+                        variants(vec![("update", object(vec![("origSz", decimal()), ("newSz", decimal())], &[]))]),
+                        json!({ "const": "remove" }),
+                    ]}),
+                ),
+            ],
+            &[],
+        ),
+    );
+    let l3_snapshot = object(
+        vec![
+            ("coin", string()),
+            ("time", integer()),
+            ("height", integer()),
+            ("seq", integer()),
+            ("levels", sides(reference("l3Order"))),
+        ],
+        &[],
+    );
+    let l3_updates = object(
+        vec![
+            ("coin", string()),
+            ("time", integer()),
+            ("height", integer()),
+            ("seq", integer()),
+            ("events", array(reference("orderEvent"))),
+        ],
+        &[],
+    );
+    insert(defs, "l3Book", variants(vec![("Snapshot", l3_snapshot), ("Updates", l3_updates)]));
+    insert(
+        defs,
+        "l3Order",
+        object(vec![("oid", integer()), ("px", decimal()), ("sz", decimal()), ("timestamp", integer())], &[]),
+    );
+    let event = |fields: Vec<(&'static str, Value)>| {
+        [vec![("oid", integer()), ("side", reference("side")), ("px", decimal())], fields].concat()
+    };
+    insert(
+        defs,
+        "orderEvent",
+        json!({ "oneOf": [
+            object(event(vec![("sz", decimal()), ("timestamp", integer())]), &[]).tagged("type", "add"),
+            object(event(vec![("origSz", decimal()), ("sz", decimal())]), &[]).tagged("type", "modify"),
+            object(event(vec![("status", nullable(string()))]), &[]).tagged("type", "cancel"),
+        ]}),
+    );
+    insert(defs, "side", described(string_enum(&["A", "B"]), "Ask or bid"));
+}
+
+fn order_status() -> Vec<(&'static str, Value)> {
+    vec![
+        ("time", described(string(), "e.g. 2025-07-02T04:35:33.565, in UTC")),
+        ("user", reference("address")),
+        ("status", string()),
+        ("order", reference("l4Order")),
+    ]
+}
+
+#[allow(clippy::too_many_lines)]
+fn market_definitions(defs: &mut Map<String, Value>) {
+    insert(
+        defs,
+        "userOrders",
+        object(
+            vec![
+                ("user", reference("address")),
+                ("time", integer()),
+                ("height", integer()),
+                (
+                    "events",
+                    array(json!({ "oneOf": [
+                        object(order_status(), &[]).tagged("type", "order"),
+                        object(fill(), &[]).tagged("type", "fill"),
+                    ]})),
+                ),
+            ],
+            &[],
+        ),
+    );
+    insert(
+        defs,
+        "trades",
+        array(object(
+            vec![
+                ("coin", string()),
+                ("side", described(reference("side"), "Of the taker")),
+                ("px", decimal()),
+                ("sz", decimal()),
+                ("hash", string()),
+                ("time", integer()),
+                ("tid", integer()),
+                ("users", described(pair(reference("address")), "Buyer and seller")),
+                ("taker", reference("address")),
+                ("maker", reference("address")),
+            ],
+            &[],
+        )),
+    );
+    insert(
+        defs,
+        "candle",
+        object(
+            vec![
+                ("t", integer()),
+                ("T", integer()),
+                ("s", string()),
+                ("i", reference("candleInterval")),
+                ("o", decimal()),
+                ("c", decimal()),
+                ("h", decimal()),
+                ("l", decimal()),
+                ("v", decimal()),
+                ("n", integer()),
+            ],
+            &[],
+        ),
+    );
+    insert(
+        defs,
+        "analytics",
+        object(
+            vec![
+                ("coin", string()),
+                ("time", integer()),
+                ("bid", nullable(decimal())),
+                ("ask", nullable(decimal())),
+                ("spread", nullable(decimal())),
+                ("spreadBps", nullable(number())),
+                ("imbalance", nullable(number())),
+                ("depth", integer()),
+                ("vwap", nullable(decimal())),
+                ("vwapWindow", integer()),
+                ("volume", decimal()),
+                ("trades", integer()),
+            ],
+            &[],
+        ),
+    );
+    insert(
+        defs,
+        "checkpoint",
+        object(
+            vec![
+                ("coin", string()),
+                ("time", integer()),
+                ("height", integer()),
+                ("seq", integer()),
+                ("checksum", integer()),
+                ("digest", string()),
+                ("signature", string()),
+            ],
+            &[],
+        ),
+    );
+    insert(
+        defs,
+        "marketHalt",
+        object(vec![("coin", string()), ("reason", string_enum(&["market", "audit"])), ("time", integer())], &[]),
+    );
+    insert(
+        defs,
+        "marketInfo",
+        object(
+            vec![
+                ("coin", string()),
+                ("kind", string_enum(&["perp", "spot"])),
+                ("base", string()),
+                ("quote", string()),
+                ("szDecimals", integer()),
+                ("lotSize", decimal()),
+                ("tickSize", decimal()),
+                ("status", string_enum(&["active", "halted", "delisted"])),
+            ],
+            &[],
+        ),
+    );
+    insert(defs, "markets", array(reference("marketInfo")));
+    insert(
+        defs,
+        "marketChanges",
+        array(object(
+            vec![("change", string_enum(&["added", "changed", "removed"])), ("market", reference("marketInfo"))],
+            &[],
+        )),
+    );
+}
+
+fn fill() -> Vec<(&'static str, Value)> {
+    let liquidation = object(vec![("liquidatedUser", string()), ("markPx", decimal()), ("method", string())], &[]);
+    vec![
+        ("coin", string()),
+        ("px", decimal()),
+        ("sz", decimal()),
+        ("side", reference("side")),
+        ("time", integer()),
+        ("startPosition", decimal()),
+        ("dir", string()),
+        ("closedPnl", decimal()),
+        ("hash", string()),
+        ("oid", integer()),
+        ("crossed", boolean()),
+        ("fee", decimal()),
+        ("tid", integer()),
+        ("feeToken", string()),
+        ("liquidation", nullable(liquidation)),
+    ]
+}
+
+#[allow(clippy::too_many_lines)]
+fn connection_definitions(defs: &mut Map<String, Value>) {
+    insert(
+        defs,
+        "heartbeat",
+        object(
+            vec![
+                ("time", integer()),
+                ("l2Seq", integer()),
+                ("l4Seqs", json!({ "type": "object", "additionalProperties": integer() })),
+            ],
+            &[],
+        ),
+    );
+    insert(defs, "timeSync", object(vec![("sendTime", integer())], &[]));
+    insert(
+        defs,
+        "streamStatus",
+        object(
+            vec![
+                ("market", string()),
+                ("stale", boolean()),
+                ("ready", boolean()),
+                ("lastBlock", integer()),
+                ("lastBlockTime", nullable(integer())),
+                ("lagMs", nullable(integer())),
+                ("upstreams", array(object(vec![("node", string()), ("connected", boolean())], &[]))),
+                ("snapshotInProgress", boolean()),
+                ("gaps", integer()),
+                ("crossedBooks", array(string())),
+                ("maintenance", boolean()),
+                ("time", integer()),
+            ],
+            &["market"],
+        ),
+    );
+    insert(
+        defs,
+        "maintenanceNotice",
+        object(vec![("start", nullable(integer())), ("message", nullable(string())), ("closeCode", integer())], &[]),
+    );
+    insert(
+        defs,
+        "sessionToken",
+        object(vec![("token", string()), ("resumed", boolean()), ("graceSecs", integer())], &[]),
+    );
+    insert(
+        defs,
+        "slowConsumerReport",
+        object(
+            vec![
+                ("action", string_enum(&["evicted", "throttled"])),
+                ("reason", string_enum(&["queueFull", "bandwidthQuota", "load"])),
+                ("time", integer()),
+                ("queueCapacity", integer()),
+                ("queueDepthHistory", array(integer())),
+                ("drainRate", number()),
+                ("subscriptions", array(reference("subscription"))),
+            ],
+            &[],
+        ),
+    );
+    let result = json!({ "oneOf": [
+        described(reference("serverMessage"), "The message a subscription starts with"),
+        reference("markets"),
+        object(vec![("time", integer()), ("clientTime", integer())], &["clientTime"]),
+        described(string(), "pong"),
+        reference("connectionReport"),
+    ]});
+    insert(
+        defs,
+        "rpcResponse",
+        json!({ "oneOf": [
+            object(vec![("id", reference("requestId")), ("result", result)], &[]),
+            object(vec![("id", reference("requestId")), ("error", reference("protocolError"))], &[]),
+        ]}),
+    );
+    insert(
+        defs,
+        "connectionReport",
+        object(
+            vec![
+                (
+                    "queue",
+                    object(
+                        vec![
+                            ("depth", integer()),
+                            ("capacity", integer()),
+                            ("dropped", integer()),
+                            ("conflated", integer()),
+                        ],
+                        &[],
+                    ),
+                ),
+                ("subprotocol", string()),
+                ("compression", string_enum(&["none", "deflate", "sharedDeflate", "zstd"])),
+                ("numbers", string_enum(&["string", "integer", "float"])),
+                ("batchMs", nullable(integer())),
+                ("subscriptions", array(reference("subscription"))),
+            ],
+            &[],
+        ),
+    );
+    insert(
+        defs,
+        "protocolError",
+        object(
+            vec![
+                ("code", described(json!({ "enum": [1000, 1001, 1002, 1003, 1004, 1005, 1006] }), "See the README")),
+                ("msg", string()),
+            ],
+            &[],
+        ),
+    );
+}
+
+fn insert(defs: &mut Map<String, Value>, name: &str, schema: Value) {
+    defs.insert(name.to_string(), schema);
+}
+
+fn reference(name: &str) -> Value {
+    json!({ "$ref": format!("#/$defs/{name}") })
+}
+
+fn string() -> Value {
+    json!({ "type": "string" })
+}
+
+fn integer() -> Value {
+    json!({ "type": "integer", "minimum": 0 })
+}
+
+fn number() -> Value {
+    json!({ "type": "number" })
+}
+
+fn boolean() -> Value {
+    json!({ "type": "boolean" })
+}
+
+fn decimal() -> Value {
+    reference("decimal")
+}
+
+fn string_enum(values: &[&str]) -> Value {
+    json!({ "type": "string", "enum": values })
+}
+
+fn array(items: Value) -> Value {
+    json!({ "type": "array", "items": items })
+}
+
+fn pair(items: Value) -> Value {
+    json!({ "type": "array", "items": items, "minItems": 2, "maxItems": 2 })
+}
+
+// bids and asks
+fn sides(level: Value) -> Value {
+    pair(array(level))
+}
+
+fn nullable(schema: Value) -> Value {
+    json!({ "anyOf": [schema, { "type": "null" }] })
+}
+
+fn described(mut schema: Value, description: &str) -> Value {
+    schema["description"] = description.into();
+    schema
+}
+
+// the fields that aren't `optional` are always sent
+fn object(fields: Vec<(&str, Value)>, optional: &[&str]) -> Value {
+    let required = fields.iter().map(|(name, _)| *name).filter(|name| !optional.contains(name)).collect::<Vec<_>>();
+    let properties = fields.into_iter().map(|(name, schema)| (name.to_string(), schema)).collect::<Map<_, _>>();
+    json!({ "type": "object", "properties": properties, "required": required })
+}
+
+// an enum serialized by serde as `{"<variant>": <data>}`
+fn variants(variants: Vec<(&str, Value)>) -> Value {
+    let variants = variants.into_iter().map(|(name, data)| object(vec![(name, data)], &[])).collect::<Vec<_>>();
+    match <[Value; 1]>::try_from(variants) {
+        Ok([variant]) => variant,
+        Err(variants) => json!({ "oneOf": variants }),
+    }
+}
+
+trait Tagged {
+    // an object as the variant of an internally tagged enum, `{"<tag>": "<name>", ...}`
+    fn tagged(self, tag: &str, name: &str) -> Self;
+}
+
+impl Tagged for Value {
+    fn tagged(mut self, tag: &str, name: &str) -> Self {
+        if let Some(Self::Object(properties)) = self.get_mut("properties") {
+            properties.insert(tag.to_string(), json!({ "const": name }));
+        }
+        if let Some(Self::Array(required)) = self.get_mut("required") {
+            required.insert(0, tag.into());
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        servers::protocol::{Versioned, parse_request},
+        types::subscription::ServerResponse,
+    };
+
+    const ADDRESS: &str = "0x1234567890abcdef1234567890abcdef12345678";
+
+    // whether `value` matches `schema`, for the keywords the schemas use. Stricter than JSON Schema in that every
+    // field of an object has to be described, so that a field added to a message without its schema fails
+    fn matches(root: &Value, schema: &Value, value: &Value) -> std::result::Result<(), String> {
+        if let Some(Value::String(path)) = schema.get("$ref") {
+            let name = path.trim_start_matches("#/$defs/");
+            return matches(root, &root["$defs"][name], value);
+        }
+        for keyword in ["oneOf", "anyOf"] {
+            if let Some(Value::Array(options)) = schema.get(keyword) {
+                let matched = options.iter().filter(|option| matches(root, option, value).is_ok()).count();
+                if matched == 0 || (keyword == "oneOf" && matched > 1) {
+                    return Err(format!("{value} matches {matched} of {}", Value::Array(options.clone())));
+                }
+            }
+        }
+        if let Some(expected) = schema.get("const")
+            && expected != value
+        {
+            return Err(format!("{value} isn't {expected}"));
+        }
+        if let Some(Value::Array(values)) = schema.get("enum")
+            && !values.contains(value)
+        {
+            return Err(format!("{value} isn't one of {values:?}"));
+        }
+        let Some(Value::String(kind)) = schema.get("type") else { return Ok(()) };
+        match (kind.as_str(), value) {
+            ("string", Value::String(_))
+            | ("boolean", Value::Bool(_))
+            | ("null", Value::Null)
+            | ("number", Value::Number(_)) => Ok(()),
+            ("integer", Value::Number(number)) if number.is_u64() => Ok(()),
+            ("array", Value::Array(items)) => {
+                let len = |bound: &str| schema.get(bound).and_then(Value::as_u64);
+                let n = items.len() as u64;
+                if len("minItems").is_some_and(|min| n < min) || len("maxItems").is_some_and(|max| n > max) {
+                    return Err(format!("{value} has {n} items"));
+                }
+                items.iter().try_for_each(|item| matches(root, &schema["items"], item))
+            }
+            ("object", Value::Object(fields)) => {
+                if let Some(Value::Array(required)) = schema.get("required")
+                    && let Some(missing) = required.iter().find(|name| !fields.contains_key(name.as_str().unwrap()))
+                {
+                    return Err(format!("{value} is missing {missing}"));
+                }
+                fields.iter().try_for_each(|(name, field)| {
+                    let field_schema = schema
+                        .get("properties")
+                        .and_then(|properties| properties.get(name))
+                        .or_else(|| schema.get("additionalProperties"))
+                        .ok_or_else(|| format!("{name} of {value} isn't described"))?;
+                    matches(root, field_schema, field).map_err(|err| format!("{name}: {err}"))
+                })
+            }
+            _ => Err(format!("{value} isn't of type {kind}")),
+        }
+    }
+
+    fn server_messages() -> Vec<String> {
+        let l4_order = format!(
+            r#"{{"user":"{ADDRESS}","coin":"BTC","side":"B","limitPx":"106296.0","sz":"0.5","oid":7,"timestamp":1751430933000,"triggerCondition":"N/A","isTrigger":false,"triggerPx":"0.0","isPositionTpsl":false,"reduceOnly":false,"orderType":"Limit","tif":"Alo","cloid":null}}"#
+        );
+        let status =
+            format!(r#"{{"time":"2025-07-02T04:35:33.565","user":"{ADDRESS}","status":"open","order":{l4_order}}}"#);
+        let fill = format!(
+            r#"{{"coin":"BTC","px":"106296.0","sz":"0.5","side":"B","time":1751430933565,"startPosition":"0.0","dir":"Open Long","closedPnl":"0.0","hash":"0xab","oid":7,"crossed":false,"fee":"0.01","tid":3,"feeToken":"USDC","liquidation":{{"liquidatedUser":"{ADDRESS}","markPx":"106000.0","method":"market"}}}}"#
+        );
+        let subscription = r#"{"type":"l2Book","coin":"BTC","nSigFigs":null,"nLevels":null,"mantissa":null}"#;
+        let market = r#"{"coin":"BTC","kind":"perp","base":"BTC","quote":"USDC","szDecimals":5,"lotSize":"0.00001","tickSize":"1","status":"active"}"#;
+        let l2_book =
+            r#"{"coin":"BTC","time":1,"levels":[[{"px":"106296.0","sz":"0.5","n":2}],[]],"seq":4,"checksum":5}"#;
+        [
+            format!(r#"{{"channel":"subscriptionResponse","data":{{"method":"subscribe","subscription":{subscription}}}}}"#),
+            format!(r#"{{"channel":"l2Book","data":{l2_book}}}"#),
+            r#"{"channel":"l2Delta","data":{"coin":"BTC","time":1,"seq":5,"prevSeq":4,"epoch":4,"changes":[[{"i":0,"sz":"0","n":0},{"i":1,"px":"106295.0","sz":"1.25","n":3}],[]],"checksum":6}}"#.to_string(),
+            r#"{"channel":"bbo","data":{"coin":"BTC","time":1,"seq":4,"bid":{"px":"106296.0","sz":"0.5","n":2},"ask":null,"mid":null}}"#.to_string(),
+            format!(r#"{{"channel":"l4Book","data":{{"Snapshot":{{"coin":"BTC","time":1,"height":2,"seq":3,"levels":[[{l4_order}],[]],"checksum":4,"checkpoint":{{"coin":"BTC","time":1,"height":2,"seq":3,"checksum":4,"digest":"ab","signature":"cd"}}}}}}}}"#),
+            format!(r#"{{"channel":"l4Book","data":{{"Updates":{{"time":1,"height":2,"seq":4,"checksum":5,"order_statuses":[{status}],"book_diffs":[{{"user":"{ADDRESS}","oid":7,"px":"106296.0","coin":"BTC","raw_book_diff":{{"new":{{"sz":"0.5"}}}}}},{{"user":"{ADDRESS}","oid":8,"px":"1.0","coin":"BTC","raw_book_diff":{{"update":{{"origSz":"2.0","newSz":"1.0"}}}}}},{{"user":"{ADDRESS}","oid":9,"px":"1.0","coin":"BTC","raw_book_diff":"remove"}}]}}}}}}"#),
+            r#"{"channel":"l3Book","data":{"Snapshot":{"coin":"BTC","time":1,"height":2,"seq":3,"levels":[[{"oid":7,"px":"1.0","sz":"2.0","timestamp":1}],[]]}}}"#.to_string(),
+            r#"{"channel":"l3Book","data":{"Updates":{"coin":"BTC","time":1,"height":2,"seq":4,"events":[{"type":"add","oid":7,"side":"B","px":"1.0","sz":"2.0","timestamp":1},{"type":"modify","oid":7,"side":"B","px":"1.0","origSz":"2.0","sz":"1.0"},{"type":"cancel","oid":7,"side":"B","px":"1.0","status":"canceled"}]}}}"#.to_string(),
+            format!(r#"{{"channel":"userOrders","data":{{"user":"{ADDRESS}","time":1,"height":2,"events":[{{"type":"order",{}}},{{"type":"fill",{}}}]}}}}"#, &status[1..status.len() - 1], &fill[1..fill.len() - 1]),
+            format!(r#"{{"channel":"trades","data":[{{"coin":"BTC","side":"A","px":"106296.0","sz":"0.5","hash":"0xab","time":1,"tid":3,"users":["{ADDRESS}","{ADDRESS}"],"taker":"{ADDRESS}","maker":"{ADDRESS}"}}]}}"#),
+            r#"{"channel":"candle","data":{"t":0,"T":59999,"s":"BTC","i":"1m","o":"1.0","c":"2.0","h":"2.0","l":"1.0","v":"3.0","n":2}}"#.to_string(),
+            r#"{"channel":"analytics","data":{"coin":"BTC","time":1,"bid":"1.0","ask":"2.0","spread":"1.0","spreadBps":6666.6,"imbalance":-0.5,"depth":10,"vwap":null,"vwapWindow":60000,"volume":"0.0","trades":0}}"#.to_string(),
+            r#"{"channel":"checkpoint","data":{"coin":"BTC","time":1,"height":2,"seq":3,"checksum":4,"digest":"ab","signature":"cd"}}"#.to_string(),
+            r#"{"channel":"heartbeat","data":{"time":1,"l2Seq":2,"l4Seqs":{"BTC":3}}}"#.to_string(),
+            r#"{"channel":"time","data":{"sendTime":1}}"#.to_string(),
+            r#"{"channel":"halt","data":{"coin":"BTC","reason":"audit","time":1}}"#.to_string(),
+            r#"{"channel":"resume","data":{"coin":"BTC","reason":"market","time":1}}"#.to_string(),
+            r#"{"channel":"status","data":{"market":"testnet","stale":false,"ready":true,"lastBlock":2,"lastBlockTime":1,"lagMs":null,"upstreams":[{"node":"/home/node","connected":true}],"snapshotInProgress":false,"gaps":0,"crossedBooks":["BTC"],"maintenance":false,"time":1}}"#.to_string(),
+            format!(r#"{{"channel":"markets","data":[{market}]}}"#),
+            format!(r#"{{"channel":"marketChanges","data":[{{"change":"added","market":{market}}}]}}"#),
+            r#"{"channel":"maintenance","data":{"start":1,"message":"upgrade","closeCode":1012}}"#.to_string(),
+            r#"{"channel":"session","data":{"token":"abc","resumed":false,"graceSecs":30}}"#.to_string(),
+            format!(r#"{{"channel":"evicted","data":{{"action":"evicted","reason":"queueFull","time":1,"queueCapacity":8,"queueDepthHistory":[8,8],"drainRate":0.5,"subscriptions":[{subscription}]}}}}"#),
+            format!(r#"{{"channel":"response","data":{{"id":1,"result":{{"channel":"l2Book","data":{l2_book}}}}}}}"#),
+            format!(r#"{{"channel":"response","data":{{"id":"a","result":[{market}]}}}}"#),
+            r#"{"channel":"response","data":{"id":2,"result":{"time":2,"clientTime":1}}}"#.to_string(),
+            r#"{"channel":"response","data":{"id":3,"result":"pong"}}"#.to_string(),
+            format!(r#"{{"channel":"response","data":{{"id":4,"result":{{"queue":{{"depth":1,"capacity":8,"dropped":0,"conflated":0}},"subprotocol":"orderbook.v2","compression":"none","numbers":"string","batchMs":null,"subscriptions":[{subscription}]}}}}}}"#),
+            r#"{"channel":"response","data":{"id":5,"error":{"code":1006,"msg":"Invalid subscription"}}}"#.to_string(),
+            r#"{"channel":"error","data":"Invalid subscription"}"#.to_string(),
+            r#"{"error":{"code":1003,"msg":"unknown method \"foo\""}}"#.to_string(),
+        ]
+        .into()
+    }
+
+    #[test]
+    fn test_server_messages_match() -> Result<()> {
+        for (version, errors) in [(Version::V1, 0), (Version::V2, 1)] {
+            let schema = server_schema(version);
+            let mut sent = Vec::new();
+            for json in server_messages() {
+                let msg = serde_json::from_str::<ServerResponse>(&json).map_err(|err| format!("{json}: {err}"))?;
+                // the fields as the server writes them, rather than as above
+                let value = serde_json::to_value(Versioned::new(&msg, version))?;
+                matches(&schema, &schema, &value).map_err(|err| format!("{json}: {err}"))?;
+                sent.push(value);
+            }
+            // batches, and the error channel only in version 1
+            matches(&schema, &schema, &Value::Array(sent))?;
+            let error = json!({ "channel": "error", "data": "Invalid subscription" });
+            assert_eq!(matches(&schema, &schema, &error).is_err(), errors == 1);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_every_channel_is_covered() -> Result<()> {
+        let covered = server_messages()
+            .iter()
+            .map(|json| serde_json::from_str::<Value>(json).map(|msg| msg["channel"].clone()))
+            .collect::<serde_json::Result<Vec<_>>>()?;
+        for (channel, _) in CHANNELS {
+            assert!(covered.contains(&channel.into()), "no message of the {channel} channel");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_client_messages_match() -> Result<()> {
+        let schema = client_schema(Version::V2);
+        let subscriptions = [
+            r#"{"type":"trades","coin":"BTC"}"#.to_string(),
+            r#"{"type":"l2Book","coin":"BTC","nSigFigs":5,"mantissa":2,"fields":["seq","n"],"delta":true}"#.to_string(),
+            r#"{"type":"l2Book","coin":"BTC","conflateMs":100,"tickSize":"10"}"#.to_string(),
+            r#"{"type":"bbo","coin":"BTC","fields":["mid"]}"#.to_string(),
+            r#"{"type":"candle","coin":"BTC","interval":"5m"}"#.to_string(),
+            r#"{"type":"analytics","coin":"BTC"}"#.to_string(),
+            r#"{"type":"checkpoint","coin":"BTC"}"#.to_string(),
+            r#"{"type":"l4Book","coin":"BTC","conflateMs":100}"#.to_string(),
+            r#"{"type":"l3Book","coin":"BTC"}"#.to_string(),
+            format!(r#"{{"type":"userOrders","user":"{ADDRESS}","timestamp":1,"signature":"0xab"}}"#),
+            r#"{"type":"status"}"#.to_string(),
+            r#"{"type":"markets"}"#.to_string(),
+        ];
+        let mut requests = subscriptions
+            .iter()
+            .flat_map(|sub| {
+                ["subscribe", "unsubscribe", "snapshot"]
+                    .map(|method| format!(r#"{{"method":"{method}","subscription":{sub}}}"#))
+            })
+            .collect::<Vec<_>>();
+        let sub = &subscriptions[7];
+        requests.extend([
+            r#"{"method":"auth","token":"abc"}"#.to_string(),
+            format!(r#"{{"method":"replay","subscription":{sub},"fromTs":1}}"#),
+            format!(r#"{{"method":"resume","subscription":{sub},"fromSeq":1}}"#),
+            r#"{"method":"listMarkets"}"#.to_string(),
+            format!(r#"{{"method":"getSnapshot","id":"a","subscription":{sub}}}"#),
+            r#"{"method":"getMarkets","id":1}"#.to_string(),
+            r#"{"method":"serverTime","id":1,"clientTime":1}"#.to_string(),
+            r#"{"method":"ping","id":1}"#.to_string(),
+            r#"{"method":"stats","id":1}"#.to_string(),
+        ]);
+        for json in requests {
+            // as the client sends it, and as echoed in the subscription response
+            let request = parse_request(json.as_bytes()).map_err(|err| format!("{json}: {}", err.msg))?;
+            for value in [serde_json::from_str(&json)?, serde_json::to_value(&request)?] {
+                matches(&schema, &schema, &value).map_err(|err| format!("{json}: {err}"))?;
+            }
+        }
+        let unknown = json!({ "method": "subscribe", "subscription": { "type": "orders", "coin": "BTC" } });
+        assert!(matches(&schema, &schema, &unknown).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_dump() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("orderbook-schema-{}", std::process::id()));
+        let files = dump_schema(&dir)?;
+        assert_eq!(files.len(), 5);
+        let server = serde_json::from_str::<Value>(&fs::read_to_string(dir.join("v2/server.schema.json"))?)?;
+        assert_eq!(server, server_schema(Version::V2));
+        assert!(fs::read_to_string(dir.join("orderbook.proto"))?.contains("service"));
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,1145 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fmt,
+    str::FromStr,
+    sync::{
+        Arc, Mutex, OnceLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    select,
+    sync::Notify,
+    time::{Instant, sleep_until, timeout_at},
+};
+use yawc::{FrameView, close::CloseCode};
+
+use crate::{
+    latency::{Stamps, now_ms},
+    metrics::METRICS,
+    prelude::*,
+    servers::{
+        outbound::Outbound,
+        priority::{DeliveryLoad, LoadShedding, Priority, Shedding},
+    },
+    types::{
+        L3Book, L4Book,
+        subscription::{ServerResponse, Subscription},
+    },
+};
+
+// most messages sent in a single batched frame
+const MAX_BATCH: usize = 256;
+// seconds of queue depth in the report of a slow client
+const HISTORY_SECS: usize = 30;
+// the channels of stream data, whose messages can be given a TTL
+const TTL_CHANNELS: [&str; 10] =
+    ["l2Book", "l2Delta", "bbo", "l4Book", "l3Book", "userOrders", "trades", "candle", "analytics", "checkpoint"];
+
+/// What to do when a client reads slower than messages are produced for it and its send queue is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BackpressurePolicy {
+    /// Close the connection (close code 1008).
+    #[default]
+    Disconnect,
+    /// Drop the oldest queued message to make room.
+    DropOldest,
+    /// Merge a new message into the one still queued for the same subscription:
+    /// l2 books are replaced by the latest book, l4 updates and trades are concatenated.
+    /// Disconnects if the queue is full of messages that can not be merged.
+    Conflate,
+}
+
+impl FromStr for BackpressurePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "disconnect" => Ok(Self::Disconnect),
+            "drop-oldest" => Ok(Self::DropOldest),
+            "conflate" => Ok(Self::Conflate),
+            _ => Err(format!("unknown backpressure policy {s} (expected disconnect, drop-oldest or conflate)")),
+        }
+    }
+}
+
+impl fmt::Display for BackpressurePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Disconnect => "disconnect",
+            Self::DropOldest => "drop-oldest",
+            Self::Conflate => "conflate",
+        })
+    }
+}
+
+// what was done about a client that reads too slowly
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum SlowConsumerAction {
+    // disconnected
+    Evicted,
+    // kept, but messages were dropped or held back for it
+    Throttled,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum SlowConsumerReason {
+    // its send queue was full
+    QueueFull,
+    // it was over its bandwidth quota
+    BandwidthQuota,
+    // its messages were shed to keep up with higher priority connections
+    Load,
+}
+
+impl SlowConsumerReason {
+    fn close_frame(self) -> FrameView {
+        match self {
+            Self::QueueFull => FrameView::close(CloseCode::Policy, "client is reading too slowly"),
+            Self::BandwidthQuota => FrameView::close(CloseCode::Policy, "bandwidth quota exceeded"),
+            Self::Load => FrameView::close(CloseCode::Again, "server is under load"),
+        }
+    }
+}
+
+impl fmt::Display for SlowConsumerAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Evicted => "evicted",
+            Self::Throttled => "throttled",
+        })
+    }
+}
+
+impl fmt::Display for SlowConsumerReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::QueueFull => "queue_full",
+            Self::BandwidthQuota => "bandwidth_quota",
+            Self::Load => "load",
+        })
+    }
+}
+
+// why a client was evicted or throttled, sent to it before it is evicted. `queueDepthHistory` is the deepest its
+// queue got in each of the last seconds, oldest first, and `drainRate` the messages it took per second over them
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SlowConsumerReport {
+    pub action: SlowConsumerAction,
+    pub reason: SlowConsumerReason,
+    pub time: u64,
+    pub queue_capacity: usize,
+    pub queue_depth_history: Vec<usize>,
+    pub drain_rate: f64,
+    pub subscriptions: Vec<Subscription>,
+}
+
+// the queue of a connection as it stands, for the client's `stats` request. The messages dropped and merged into
+// others are counted over the life of the connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct QueueStats {
+    pub depth: usize,
+    pub capacity: usize,
+    pub dropped: u64,
+    pub conflated: u64,
+}
+
+// the deepest the queue got and the messages taken from it, in each of the last `HISTORY_SECS` seconds
+struct DepthHistory {
+    start: Instant,
+    // seconds since `start` of the current one
+    second: u64,
+    current: (usize, u64),
+    // the seconds before, oldest first
+    past: VecDeque<(usize, u64)>,
+    // after the last push or take
+    depth: usize,
+}
+
+impl DepthHistory {
+    fn new() -> Self {
+        Self { start: Instant::now(), second: 0, current: (0, 0), past: VecDeque::new(), depth: 0 }
+    }
+
+    // moves on to the current second; the depth didn't change in the seconds skipped
+    fn advance(&mut self) {
+        let now = self.start.elapsed().as_secs();
+        if now == self.second {
+            return;
+        }
+        let skipped = usize::try_from(now - self.second - 1).unwrap_or(usize::MAX).min(HISTORY_SECS);
+        self.past.push_back(std::mem::replace(&mut self.current, (self.depth, 0)));
+        self.past.extend(std::iter::repeat_n((self.depth, 0), skipped));
+        while self.past.len() >= HISTORY_SECS {
+            self.past.pop_front();
+        }
+        self.second = now;
+    }
+
+    fn on_push(&mut self, depth: usize) {
+        self.advance();
+        self.current.0 = self.current.0.max(depth);
+        self.depth = depth;
+    }
+
+    fn on_take(&mut self, depth: usize) {
+        self.advance();
+        self.current.1 += 1;
+        self.depth = depth;
+    }
+
+    fn depths(&self) -> Vec<usize> {
+        self.past.iter().chain([&self.current]).map(|(depth, _)| *depth).collect()
+    }
+
+    fn drain_rate(&self) -> f64 {
+        let taken = self.past.iter().chain([&self.current]).map(|(_, taken)| taken).sum::<u64>();
+        taken as f64 / (self.past.len() + 1) as f64
+    }
+}
+
+pub(crate) enum Outgoing {
+    // stream data carries the stamps of the node event it comes from
+    Message(Arc<Outbound>, Option<Stamps>),
+    Ping,
+    // a `time` message, stamped as it is written
+    Time,
+    Close(FrameView),
+}
+
+// latest (merged) book data of a conflated subscription, sent once its deadline passes
+struct Held {
+    subscription: Subscription,
+    deadline: Instant,
+    msg: Arc<Outbound>,
+    stamps: Option<Stamps>,
+}
+
+// messages carrying stream data are tagged with their subscription so they can be conflated, and expire once they
+// waited longer than the TTL of their channel
+type Queued = (Option<Subscription>, Arc<Outbound>, Option<Stamps>, Option<Instant>);
+
+struct State {
+    messages: VecDeque<Queued>,
+    held: Vec<Held>,
+    close_frame: Option<FrameView>,
+    closing: bool,
+    // a ping goes out ahead of the queued messages, and so does a time message
+    ping: bool,
+    time: bool,
+    history: DepthHistory,
+    // evicted, the writer waits for the report and the close frame from `finish_eviction`
+    eviction: Option<SlowConsumerReason>,
+    awaiting_report: bool,
+    // the priority of the connection's identity, with load shedding
+    tier: Option<(Priority, LoadShedding)>,
+    // the seq of the last l4 book message taken by the writer, per subscription, once tracked
+    l4_seqs: Option<HashMap<Subscription, u64>>,
+    // subscriptions whose messages expired while later ones depended on them, until they get a fresh snapshot
+    resyncs: HashSet<Subscription>,
+    // messages dropped, and merged into another one
+    dropped: u64,
+    conflated: u64,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            messages: VecDeque::new(),
+            held: Vec::new(),
+            close_frame: None,
+            closing: false,
+            ping: false,
+            time: false,
+            history: DepthHistory::new(),
+            eviction: None,
+            awaiting_report: false,
+            tier: None,
+            l4_seqs: None,
+            resyncs: HashSet::new(),
+            dropped: 0,
+            conflated: 0,
+        }
+    }
+}
+
+/// Bounded queue of messages waiting to be written to one client.
+/// The connection's main loop pushes into it without waiting on the socket, and a writer task drains it,
+/// so that a slow client only ever holds `capacity` messages in memory.
+pub(crate) struct SendQueue {
+    state: Mutex<State>,
+    notify: Notify,
+    // notified whenever the writer takes a message
+    room: Notify,
+    policy: BackpressurePolicy,
+    capacity: usize,
+    // the first time the client was throttled, reported once
+    throttled: OnceLock<SlowConsumerReason>,
+    throttle_reported: AtomicBool,
+    // counts the queue's messages towards the load of all connections
+    load: Arc<DeliveryLoad>,
+    // how long the messages of a channel may wait, they wait as long as it takes if it has none
+    ttls: Arc<BTreeMap<String, Duration>>,
+}
+
+impl SendQueue {
+    pub(crate) fn new(policy: BackpressurePolicy, capacity: usize) -> Self {
+        Self::with_load(policy, capacity, DeliveryLoad::global())
+    }
+
+    fn with_load(policy: BackpressurePolicy, capacity: usize, load: Arc<DeliveryLoad>) -> Self {
+        let capacity = capacity.max(1);
+        load.add_queue(capacity);
+        Self {
+            state: Mutex::default(),
+            notify: Notify::new(),
+            room: Notify::new(),
+            policy,
+            capacity,
+            throttled: OnceLock::new(),
+            throttle_reported: AtomicBool::new(false),
+            load,
+            ttls: Arc::default(),
+        }
+    }
+
+    // drops the stream data that waited longer than the TTL of its channel instead of sending it
+    pub(crate) fn with_ttls(mut self, ttls: Arc<BTreeMap<String, Duration>>) -> Self {
+        self.ttls = ttls;
+        self
+    }
+
+    // once the connection's identity is known. Unauthenticated connections have normal priority
+    pub(crate) fn set_priority(&self, shedding: Option<LoadShedding>, priority: Option<Priority>) {
+        if let Ok(mut state) = self.state.lock() {
+            state.tier = shedding.map(|shedding| (priority.unwrap_or_default(), shedding));
+        }
+    }
+
+    // when load is shed by priority
+    pub(crate) fn priority(&self) -> Option<Priority> {
+        self.state.lock().ok()?.tier.map(|(priority, _)| priority)
+    }
+
+    // remembers the seq of the last l4 book message of each subscription the writer takes, for its session
+    pub(crate) fn track_l4_seqs(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.l4_seqs.get_or_insert_default();
+        }
+    }
+
+    pub(crate) fn l4_seqs(&self) -> HashMap<Subscription, u64> {
+        self.state.lock().ok().and_then(|state| state.l4_seqs.clone()).unwrap_or_default()
+    }
+
+    pub(crate) fn push(&self, subscription: Option<&Subscription>, msg: ServerResponse) {
+        self.push_with(subscription, Outbound::projected(msg, subscription.and_then(Subscription::fields)), None);
+    }
+
+    // stream data, usually shared with the other connections subscribed to it
+    pub(crate) fn push_stamped(&self, subscription: &Subscription, msg: Arc<Outbound>, stamps: Stamps) {
+        self.push_with(Some(subscription), msg, Some(stamps));
+    }
+
+    fn push_with(&self, subscription: Option<&Subscription>, msg: Arc<Outbound>, stamps: Option<Stamps>) {
+        let Ok(mut state) = self.state.lock() else {
+            return;
+        };
+        if state.closing {
+            return;
+        }
+        if let Some(subscription) = subscription
+            && let Some(interval) = subscription.conflate_interval()
+        {
+            if is_book_data(msg.msg()) {
+                // held back until the subscription's interval is over
+                match state.held.iter_mut().find(|held| &held.subscription == subscription) {
+                    Some(held) => {
+                        held.stamps = merged_age(held.stamps, msg.msg(), stamps);
+                        if let Some(msg) = conflate(&mut held.msg, msg) {
+                            held.msg = msg;
+                        }
+                        state.conflated += 1;
+                        METRICS.conflated_messages.inc();
+                    }
+                    None => state.held.push(Held {
+                        subscription: subscription.clone(),
+                        deadline: Instant::now() + interval,
+                        msg,
+                        stamps,
+                    }),
+                }
+                drop(state);
+                self.notify.notify_one();
+                return;
+            }
+            // a new l4 snapshot supersedes the updates held back for the subscription
+            if matches!(msg.msg(), ServerResponse::L4Book(L4Book::Snapshot { .. })) {
+                state.held.retain(|held| &held.subscription != subscription);
+            }
+        }
+        self.enqueue(&mut state, subscription.cloned(), msg, stamps);
+        drop(state);
+        self.notify.notify_one();
+    }
+
+    fn enqueue(
+        &self,
+        state: &mut State,
+        subscription: Option<Subscription>,
+        msg: Arc<Outbound>,
+        stamps: Option<Stamps>,
+    ) {
+        if let Some(subscription) = &subscription
+            && state.resyncs.contains(subscription)
+        {
+            match continuity(subscription, msg.msg()) {
+                // it can't be applied without the messages that expired
+                Continuity::Continues => {
+                    state.dropped += 1;
+                    METRICS.dropped_messages.inc();
+                    return;
+                }
+                Continuity::StartsOver => {
+                    state.resyncs.remove(subscription);
+                }
+                Continuity::StandsAlone => {}
+            }
+        }
+        let expires = subscription
+            .as_ref()
+            .and_then(|_| channel(msg.msg()))
+            .and_then(|channel| self.ttls.get(channel))
+            .map(|ttl| Instant::now() + *ttl);
+        let shedding = match state.tier.map_or(Shedding::None, |(priority, tier)| tier.shedding(priority, &self.load)) {
+            Shedding::None if self.load.is_conflating_all() => Shedding::Conflate,
+            shedding => shedding,
+        };
+        let msg = match &subscription {
+            Some(subscription) if self.policy == BackpressurePolicy::Conflate || shedding != Shedding::None => {
+                let pending = state.messages.iter_mut().rev().find(|(sub, ..)| sub.as_ref() == Some(subscription));
+                match pending {
+                    Some((_, pending, pending_stamps, pending_expires)) => {
+                        let merged = merged_age(*pending_stamps, msg.msg(), stamps);
+                        let merged_expires = merged_age(*pending_expires, msg.msg(), expires);
+                        match conflate(pending, msg) {
+                            None => {
+                                *pending_stamps = merged;
+                                *pending_expires = merged_expires;
+                                state.conflated += 1;
+                                METRICS.conflated_messages.inc();
+                                return;
+                            }
+                            Some(msg) => msg,
+                        }
+                    }
+                    None => msg,
+                }
+            }
+            _ => msg,
+        };
+        if state.messages.len() >= self.capacity {
+            self.expire(state, true);
+        }
+        let shed_capacity = (self.capacity / 4).max(1);
+        if shedding == Shedding::Drop && subscription.is_some() && state.messages.len() >= shed_capacity {
+            // makes room for the connections of higher priority
+            let dropped = state.messages.len() + 1 - shed_capacity;
+            state.messages.drain(..dropped);
+            self.load.taken(dropped);
+            state.dropped += dropped as u64;
+            METRICS.dropped_messages.inc_by(dropped as u64);
+            self.throttle(SlowConsumerReason::Load);
+        } else if state.messages.len() >= self.capacity {
+            if self.policy == BackpressurePolicy::DropOldest {
+                state.messages.pop_front();
+                self.load.taken(1);
+                state.dropped += 1;
+                METRICS.dropped_messages.inc();
+                self.throttle(SlowConsumerReason::QueueFull);
+            } else {
+                METRICS.dropped_messages.inc_by(state.messages.len() as u64 + 1);
+                self.evict_locked(state, SlowConsumerReason::QueueFull);
+                return;
+            }
+        }
+        state.messages.push_back((subscription, msg, stamps, expires));
+        self.load.queued(1);
+        state.history.on_push(state.messages.len());
+        METRICS.observe_send_queue_depth(state.messages.len());
+    }
+
+    // drops the queued messages and closes the queue once the connection gave the report of the eviction
+    pub(crate) fn evict(&self, reason: SlowConsumerReason) {
+        if let Ok(mut state) = self.state.lock()
+            && !state.closing
+        {
+            self.evict_locked(&mut state, reason);
+        }
+        self.notify.notify_one();
+    }
+
+    fn evict_locked(&self, state: &mut State, reason: SlowConsumerReason) {
+        self.load.taken(state.messages.len());
+        state.messages.clear();
+        state.held.clear();
+        state.closing = true;
+        state.eviction = Some(reason);
+        state.awaiting_report = true;
+        self.room.notify_waiters();
+    }
+
+    // the reason of an eviction whose report is still to be given
+    pub(crate) fn take_eviction(&self) -> Option<SlowConsumerReason> {
+        self.state.lock().ok().and_then(|mut state| state.eviction.take())
+    }
+
+    // the report is the last message the writer sends, before the close frame
+    pub(crate) fn finish_eviction(&self, report: SlowConsumerReport) {
+        if let Ok(mut state) = self.state.lock()
+            && state.awaiting_report
+        {
+            state.awaiting_report = false;
+            state.close_frame = Some(report.reason.close_frame());
+            state.messages.push_back((None, ServerResponse::Evicted(report).into(), None, None));
+            self.load.queued(1);
+        }
+        self.notify.notify_one();
+    }
+
+    // the client stays connected, but fell behind
+    pub(crate) fn throttle(&self, reason: SlowConsumerReason) {
+        let _unused = self.throttled.set(reason);
+    }
+
+    // the reason the client was first throttled for, once
+    pub(crate) fn take_throttle(&self) -> Option<SlowConsumerReason> {
+        self.throttled.get().copied().filter(|_| !self.throttle_reported.swap(true, Ordering::Relaxed))
+    }
+
+    pub(crate) fn report(
+        &self,
+        action: SlowConsumerAction,
+        reason: SlowConsumerReason,
+        subscriptions: Vec<Subscription>,
+    ) -> SlowConsumerReport {
+        let (queue_depth_history, drain_rate) = self
+            .state
+            .lock()
+            .map_or_else(|_| (Vec::new(), 0.0), |state| (state.history.depths(), state.history.drain_rate()));
+        SlowConsumerReport {
+            action,
+            reason,
+            time: now_ms(),
+            queue_capacity: self.capacity,
+            queue_depth_history,
+            drain_rate,
+            subscriptions,
+        }
+    }
+
+    // send the close frame once every queued message has been written
+    pub(crate) fn close(&self, frame: FrameView) {
+        if let Ok(mut state) = self.state.lock()
+            && !state.closing
+        {
+            self.release_held(&mut state, true);
+            state.close_frame = Some(frame);
+            state.closing = true;
+        }
+        self.notify.notify_one();
+        self.room.notify_waiters();
+    }
+
+    pub(crate) fn ping(&self) {
+        if let Ok(mut state) = self.state.lock()
+            && !state.closing
+        {
+            state.ping = true;
+        }
+        self.notify.notify_one();
+    }
+
+    pub(crate) fn time(&self) {
+        if let Ok(mut state) = self.state.lock()
+            && !state.closing
+        {
+            state.time = true;
+        }
+        self.notify.notify_one();
+    }
+
+    // stop writing immediately, e.g. because the connection is gone
+    pub(crate) fn abort(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            self.load.taken(state.messages.len());
+            state.messages.clear();
+            state.held.clear();
+            state.close_frame = None;
+            state.closing = true;
+            state.awaiting_report = false;
+        }
+        self.notify.notify_one();
+        self.room.notify_waiters();
+    }
+
+    // waits until the queue is at most half full (or closing), for producers that can wait for the client
+    pub(crate) async fn wait_for_room(&self) {
+        loop {
+            let room = self.room.notified();
+            tokio::pin!(room);
+            room.as_mut().enable();
+            if self.state.lock().map_or(true, |state| state.closing || state.messages.len() <= self.capacity / 2) {
+                return;
+            }
+            room.await;
+        }
+    }
+
+    // the subscriptions that need a fresh snapshot to go on, their messages having expired. The messages that
+    // depend on the expired ones are dropped until this is called, so the snapshot has to be pushed right after
+    pub(crate) fn take_resyncs(&self) -> Vec<Subscription> {
+        self.state.lock().map(|mut state| state.resyncs.drain().collect()).unwrap_or_default()
+    }
+
+    // messages waiting to be sent, not counting held back ones
+    pub(crate) fn len(&self) -> usize {
+        self.state.lock().map_or(0, |state| state.messages.len())
+    }
+
+    pub(crate) fn stats(&self) -> QueueStats {
+        let (depth, dropped, conflated) =
+            self.state.lock().map_or((0, 0, 0), |state| (state.messages.len(), state.dropped, state.conflated));
+        QueueStats { depth, capacity: self.capacity, dropped, conflated }
+    }
+
+    pub(crate) fn is_closing(&self) -> bool {
+        self.state.lock().map_or(true, |state| state.closing)
+    }
+
+    // None once the queue is closed and drained
+    pub(crate) async fn next(&self) -> Option<Outgoing> {
+        self.next_routed().await.map(|(_, outgoing)| outgoing)
+    }
+
+    // the next message with the subscription it was queued for, if any
+    pub(crate) async fn next_routed(&self) -> Option<(Option<Subscription>, Outgoing)> {
+        loop {
+            let deadline = {
+                let mut state = self.state.lock().ok()?;
+                self.release_held(&mut state, false);
+                if std::mem::take(&mut state.ping) && !state.closing {
+                    return Some((None, Outgoing::Ping));
+                }
+                if std::mem::take(&mut state.time) && !state.closing {
+                    return Some((None, Outgoing::Time));
+                }
+                self.expire(&mut state, false);
+                if let Some((subscription, msg, stamps, _)) = state.messages.pop_front() {
+                    if let (Some(l4_seqs), Some(subscription), ServerResponse::L4Book(book)) =
+                        (&mut state.l4_seqs, &subscription, msg.msg())
+                    {
+                        let seq = match book {
+                            L4Book::Snapshot { seq, .. } => *seq,
+                            L4Book::Updates(updates) => updates.seq,
+                        };
+                        l4_seqs.insert(subscription.clone(), seq);
+                    }
+                    self.load.taken(1);
+                    let depth = state.messages.len();
+                    state.history.on_take(depth);
+                    self.room.notify_waiters();
+                    return Some((subscription, Outgoing::Message(msg, stamps)));
+                }
+                if let Some(frame) = state.close_frame.take() {
+                    return Some((None, Outgoing::Close(frame)));
+                }
+                if state.closing && !state.awaiting_report {
+                    return None;
+                }
+                state.held.iter().map(|held| held.deadline).min()
+            };
+            // there is a single consumer, so a notification sent before we start waiting is kept as a permit
+            if let Some(deadline) = deadline {
+                select! {
+                    () = self.notify.notified() => {}
+                    () = sleep_until(deadline) => {}
+                }
+            } else {
+                self.notify.notified().await;
+            }
+        }
+    }
+
+    // the messages that come out of the queue after `first` until `deadline`, to be sent in one frame.
+    // A ping, time message or close that comes out in the meantime ends the batch and is handed back
+    pub(crate) async fn next_batch(
+        &self,
+        first: (Arc<Outbound>, Option<Stamps>),
+        deadline: Instant,
+    ) -> (Vec<(Arc<Outbound>, Option<Stamps>)>, Option<Outgoing>) {
+        let mut batch = vec![first];
+        while batch.len() < MAX_BATCH {
+            match timeout_at(deadline, self.next()).await {
+                Ok(Some(Outgoing::Message(msg, stamps))) => batch.push((msg, stamps)),
+                Ok(outgoing) => return (batch, outgoing),
+                Err(_) => break,
+            }
+        }
+        (batch, None)
+    }
+
+    // moves held messages whose interval is over (or all of them) to the queue
+    fn release_held(&self, state: &mut State, all: bool) {
+        if state.held.is_empty() {
+            return;
+        }
+        let now = Instant::now();
+        let (due, held): (Vec<_>, _) =
+            std::mem::take(&mut state.held).into_iter().partition(|held| all || held.deadline <= now);
+        state.held = held;
+        for Held { subscription, msg, stamps, .. } in due {
+            let msg = match msg.msg() {
+                ServerResponse::L4Book(L4Book::Updates(_)) => match msg.into_msg() {
+                    ServerResponse::L4Book(L4Book::Updates(updates)) => {
+                        ServerResponse::L4Book(L4Book::Updates(updates.into_net_delta())).into()
+                    }
+                    msg => msg.into(),
+                },
+                _ => msg,
+            };
+            self.enqueue(state, Some(subscription), msg, stamps);
+        }
+    }
+
+    // drops the messages that waited longer than their TTL, those at the front of the queue or all of them. The
+    // messages of their subscription that depend on them go too, up to its next snapshot
+    fn expire(&self, state: &mut State, all: bool) {
+        let now = Instant::now();
+        let mut i = 0;
+        while let Some((_, _, _, expires)) = state.messages.get(i) {
+            if !expires.is_some_and(|expires| expires <= now) {
+                if !all {
+                    return;
+                }
+                i += 1;
+                continue;
+            }
+            let Some((subscription, msg, ..)) = state.messages.remove(i) else { return };
+            let mut dropped = 1;
+            METRICS.expired_messages.inc();
+            if let Some(subscription) = subscription
+                && continuity(&subscription, msg.msg()) != Continuity::StandsAlone
+            {
+                let of_subscription = |sub: &Option<Subscription>| sub.as_ref() == Some(&subscription);
+                let snapshot = state.messages.iter().skip(i).position(|(sub, msg, ..)| {
+                    of_subscription(sub) && continuity(&subscription, msg.msg()) == Continuity::StartsOver
+                });
+                let end = snapshot.map_or(state.messages.len(), |position| i + position);
+                let before = state.messages.len();
+                let mut index = 0;
+                state.messages.retain(|(sub, msg, ..)| {
+                    let depends = (i..end).contains(&index)
+                        && of_subscription(sub)
+                        && continuity(&subscription, msg.msg()) == Continuity::Continues;
+                    index += 1;
+                    !depends
+                });
+                dropped += before - state.messages.len();
+                if snapshot.is_none() {
+                    state.resyncs.insert(subscription);
+                }
+            }
+            self.load.taken(dropped);
+            state.dropped += dropped as u64;
+            METRICS.dropped_messages.inc_by(dropped as u64);
+            self.room.notify_waiters();
+        }
+    }
+}
+
+impl Drop for SendQueue {
+    fn drop(&mut self) {
+        let queued = self.state.lock().map_or(0, |state| state.messages.len());
+        self.load.remove_queue(self.capacity, queued);
+    }
+}
+
+// messages of a conflated subscription that are held back and merged
+const fn is_book_data(msg: &ServerResponse) -> bool {
+    matches!(msg, ServerResponse::L2Book(_) | ServerResponse::L4Book(L4Book::Updates(_)))
+}
+
+// a replaced l2 book is as old as the new one, concatenated data as old as its oldest part
+fn merged_age<T>(pending: Option<T>, msg: &ServerResponse, age: Option<T>) -> Option<T> {
+    match msg {
+        ServerResponse::L2Book(_) => age,
+        _ => pending.or(age),
+    }
+}
+
+// the channel of stream data that can expire
+const fn channel(msg: &ServerResponse) -> Option<&'static str> {
+    Some(match msg {
+        ServerResponse::L2Book(_) => "l2Book",
+        ServerResponse::L2Delta(_) => "l2Delta",
+        ServerResponse::Bbo(_) => "bbo",
+        ServerResponse::L4Book(_) => "l4Book",
+        ServerResponse::L3Book(_) => "l3Book",
+        ServerResponse::UserOrders(_) => "userOrders",
+        ServerResponse::Trades(_) => "trades",
+        ServerResponse::Candle(_) => "candle",
+        ServerResponse::Analytics(_) => "analytics",
+        ServerResponse::Checkpoint(_) => "checkpoint",
+        _ => return None,
+    })
+}
+
+pub(crate) fn validate_ttls(ttls: &BTreeMap<String, Duration>) -> Result<()> {
+    for (channel, ttl) in ttls {
+        if !TTL_CHANNELS.contains(&channel.as_str()) {
+            let channels = TTL_CHANNELS.join(", ");
+            return Err(format!("unknown channel {channel} of a message TTL (expected one of {channels})").into());
+        }
+        if ttl.is_zero() {
+            return Err(format!("the message TTL of {channel} has to be at least a millisecond").into());
+        }
+    }
+    Ok(())
+}
+
+// how a message of a subscription relates to the ones before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Continuity {
+    // applies on top of the previous one, e.g. an l4 update
+    Continues,
+    // the books of the subscription, which the messages after it apply on top of
+    StartsOver,
+    StandsAlone,
+}
+
+const fn continuity(subscription: &Subscription, msg: &ServerResponse) -> Continuity {
+    match msg {
+        ServerResponse::L4Book(L4Book::Updates(_))
+        | ServerResponse::L3Book(L3Book::Updates(_))
+        | ServerResponse::L2Delta(_) => Continuity::Continues,
+        ServerResponse::L4Book(L4Book::Snapshot { .. }) | ServerResponse::L3Book(L3Book::Snapshot { .. }) => {
+            Continuity::StartsOver
+        }
+        // deltas apply on top of it, other l2 subscriptions replace it
+        ServerResponse::L2Book(_) if subscription.is_delta() => Continuity::StartsOver,
+        _ => Continuity::StandsAlone,
+    }
+}
+
+// merges msg into a message still queued for the same subscription, or hands it back if they can't be merged.
+// A merged message is no longer the one the other connections send, so it is copied
+fn conflate(pending: &mut Arc<Outbound>, msg: Arc<Outbound>) -> Option<Arc<Outbound>> {
+    let merged = match (pending.msg(), msg.msg()) {
+        (ServerResponse::L2Book(_), ServerResponse::L2Book(_)) => msg,
+        (ServerResponse::L4Book(L4Book::Updates(queued)), ServerResponse::L4Book(L4Book::Updates(updates))) => {
+            let mut queued = queued.clone();
+            queued.merge(updates.clone());
+            ServerResponse::L4Book(L4Book::Updates(queued)).into()
+        }
+        (ServerResponse::Trades(queued), ServerResponse::Trades(trades)) => {
+            ServerResponse::Trades(queued.iter().chain(trades).cloned().collect()).into()
+        }
+        _ => return Some(msg),
+    };
+    *pending = merged;
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::sleep;
+
+    use super::*;
+    use crate::types::L4BookUpdates;
+
+    fn updates(seq: u64) -> ServerResponse {
+        let mut updates = L4BookUpdates::new(seq, seq);
+        updates.seq = seq;
+        ServerResponse::L4Book(L4Book::Updates(updates))
+    }
+
+    // everything the writer would still send, without waiting
+    fn drain(queue: &SendQueue) -> Vec<Outgoing> {
+        let mut state = queue.state.lock().unwrap();
+        queue.load.taken(state.messages.len());
+        let mut out =
+            state.messages.drain(..).map(|(_, msg, stamps, _)| Outgoing::Message(msg, stamps)).collect::<Vec<_>>();
+        out.extend(state.close_frame.take().map(Outgoing::Close));
+        out
+    }
+
+    fn seqs(out: &[Outgoing]) -> Vec<u64> {
+        out.iter()
+            .filter_map(|outgoing| match outgoing {
+                Outgoing::Message(msg, _) => match msg.msg() {
+                    ServerResponse::L4Book(L4Book::Updates(updates)) => Some(updates.seq),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_disconnect_policy_closes_when_full() {
+        let queue = SendQueue::new(BackpressurePolicy::Disconnect, 2);
+        for seq in 1..=3 {
+            queue.push(None, updates(seq));
+        }
+        assert!(queue.is_closing());
+        // the close frame waits for the report
+        assert!(drain(&queue).is_empty());
+        assert_eq!(queue.take_eviction(), Some(SlowConsumerReason::QueueFull));
+        let subscriptions = vec![Subscription::Trades { coin: "BTC".to_string() }];
+        let report = queue.report(SlowConsumerAction::Evicted, SlowConsumerReason::QueueFull, subscriptions.clone());
+        assert_eq!((report.queue_capacity, report.queue_depth_history.as_slice()), (2, [2].as_slice()));
+        assert_eq!(report.subscriptions, subscriptions);
+        queue.finish_eviction(report);
+        let out = drain(&queue);
+        assert_eq!(out.len(), 2);
+        assert!(matches!(&out[0], Outgoing::Message(msg, _) if matches!(msg.msg(), ServerResponse::Evicted(_))));
+        assert!(matches!(&out[1], Outgoing::Close(frame) if frame.close_code() == Some(CloseCode::Policy)));
+    }
+
+    // moves the history of the queue a second on
+    fn tick(queue: &SendQueue) {
+        let mut state = queue.state.lock().unwrap();
+        state.history.start -= Duration::from_secs(1);
+    }
+
+    #[tokio::test]
+    async fn test_depth_history() {
+        let queue = SendQueue::new(BackpressurePolicy::DropOldest, 3);
+        for seq in 1..=3 {
+            queue.push(None, updates(seq));
+        }
+        tick(&queue);
+        for _ in 0..2 {
+            queue.next().await;
+        }
+        // a second without pushes or takes, at the depth left
+        tick(&queue);
+        tick(&queue);
+        for seq in 4..=6 {
+            queue.push(None, updates(seq));
+        }
+        assert_eq!(queue.take_throttle(), Some(SlowConsumerReason::QueueFull));
+        assert_eq!(queue.take_throttle(), None);
+        let report = queue.report(SlowConsumerAction::Throttled, SlowConsumerReason::QueueFull, Vec::new());
+        assert_eq!(report.queue_depth_history, vec![3, 3, 1, 3]);
+        assert_eq!(report.drain_rate, 0.5);
+    }
+
+    #[test]
+    fn test_drop_oldest_policy_keeps_latest() {
+        let queue = SendQueue::new(BackpressurePolicy::DropOldest, 2);
+        for seq in 1..=4 {
+            queue.push(None, updates(seq));
+        }
+        assert!(!queue.is_closing());
+        assert_eq!(queue.stats(), QueueStats { depth: 2, capacity: 2, dropped: 2, conflated: 0 });
+        assert_eq!(seqs(&drain(&queue)), vec![3, 4]);
+    }
+
+    #[test]
+    fn test_conflate_policy_merges_per_subscription() {
+        let btc = Subscription::L4Book { coin: "BTC".to_string(), conflate_ms: None };
+        let eth = Subscription::L4Book { coin: "ETH".to_string(), conflate_ms: None };
+        let queue = SendQueue::new(BackpressurePolicy::Conflate, 2);
+        queue.push_stamped(&btc, updates(1).into(), Stamps { node_time: 1, ingest_time: 2 });
+        queue.push(Some(&eth), updates(10));
+        queue.push_stamped(&btc, updates(2).into(), Stamps { node_time: 3, ingest_time: 4 });
+        queue.push(Some(&btc), updates(3));
+        assert!(!queue.is_closing());
+        assert_eq!((queue.stats().dropped, queue.stats().conflated), (0, 2));
+        let out = drain(&queue);
+        assert_eq!(seqs(&out), vec![3, 10]);
+        // the merged updates are as old as the first of them
+        assert!(matches!(out[0], Outgoing::Message(_, Some(Stamps { node_time: 1, .. }))));
+    }
+
+    #[test]
+    fn test_conflating_leaves_shared_messages_alone() {
+        let btc = Subscription::L4Book { coin: "BTC".to_string(), conflate_ms: None };
+        let queue = SendQueue::new(BackpressurePolicy::Conflate, 10);
+        let stamps = Stamps { node_time: 1, ingest_time: 2 };
+        let shared = Arc::<Outbound>::from(updates(1));
+        queue.push_stamped(&btc, shared.clone(), stamps);
+        queue.push_stamped(&btc, updates(2).into(), stamps);
+        assert_eq!(seqs(&drain(&queue)), vec![2]);
+        // the other connections still send the update as it was
+        assert!(matches!(shared.msg(), ServerResponse::L4Book(L4Book::Updates(updates)) if updates.seq == 1));
+    }
+
+    #[test]
+    fn test_low_priority_shed_under_load() {
+        let l4_book = |coin: &str| Subscription::L4Book { coin: coin.to_string(), conflate_ms: None };
+        let load = Arc::<DeliveryLoad>::default();
+        let low = SendQueue::with_load(BackpressurePolicy::Disconnect, 8, load.clone());
+        let high = SendQueue::with_load(BackpressurePolicy::Disconnect, 8, load);
+        low.set_priority(Some(LoadShedding::default()), Some(Priority::Low));
+        high.set_priority(Some(LoadShedding::default()), Some(Priority::High));
+        for seq in 1..=6 {
+            high.push(None, updates(seq));
+        }
+        // conflated at 6 of 16 queued
+        low.push(Some(&l4_book("BTC")), updates(100));
+        low.push(Some(&l4_book("BTC")), updates(101));
+        for seq in 7..=8 {
+            high.push(None, updates(seq));
+        }
+        // and the oldest dropped beyond a quarter of the queue at 9 of 16
+        low.push(Some(&l4_book("ETH")), updates(200));
+        low.push(Some(&l4_book("SOL")), updates(300));
+        assert_eq!(seqs(&drain(&low)), vec![200, 300]);
+        assert_eq!(low.take_throttle(), Some(SlowConsumerReason::Load));
+        assert!(!high.is_closing());
+        assert_eq!(seqs(&drain(&high)), (1..=8).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_watchdog_conflates_every_connection() {
+        let btc = Subscription::L4Book { coin: "BTC".to_string(), conflate_ms: None };
+        let load = Arc::<DeliveryLoad>::default();
+        let queue = SendQueue::with_load(BackpressurePolicy::Disconnect, 8, load.clone());
+        queue.set_priority(Some(LoadShedding::default()), Some(Priority::High));
+        load.set_conflate_all(true);
+        queue.push(Some(&btc), updates(1));
+        queue.push(Some(&btc), updates(2));
+        load.set_conflate_all(false);
+        queue.push(Some(&btc), updates(3));
+        assert_eq!(seqs(&drain(&queue)), vec![2, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_conflated_subscription_sends_one_update_per_interval() {
+        let btc = Subscription::L4Book { coin: "BTC".to_string(), conflate_ms: Some(20) };
+        let queue = SendQueue::new(BackpressurePolicy::Disconnect, 10);
+        let start = Instant::now();
+        for seq in 1..=3 {
+            queue.push(Some(&btc), updates(seq));
+        }
+        assert!(drain(&queue).is_empty());
+        let Some(Outgoing::Message(msg, _)) = queue.next().await else {
+            panic!("expected conflated updates");
+        };
+        let ServerResponse::L4Book(L4Book::Updates(updates)) = msg.msg() else {
+            panic!("expected conflated updates");
+        };
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert_eq!(updates.seq, 3);
+    }
+
+    #[tokio::test]
+    async fn test_close_sends_queued_messages_first() {
+        let queue = SendQueue::new(BackpressurePolicy::Disconnect, 10);
+        queue.push(None, updates(1));
+        queue.close(FrameView::close(CloseCode::Away, "bye"));
+        queue.push(None, updates(2));
+        assert!(matches!(queue.next().await, Some(Outgoing::Message(..))));
+        assert!(matches!(queue.next().await, Some(Outgoing::Close(_))));
+        assert!(queue.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_next_batch_collects_messages_within_window() {
+        let queue = SendQueue::new(BackpressurePolicy::Disconnect, 10);
+        queue.push(None, updates(1));
+        queue.push(None, updates(2));
+        let Some(Outgoing::Message(first, stamps)) = queue.next().await else {
+            panic!("expected a message");
+        };
+        let start = Instant::now();
+        let (batch, next) = queue.next_batch((first, stamps), Instant::now() + Duration::from_millis(20)).await;
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        let batch = batch.into_iter().map(|(msg, stamps)| Outgoing::Message(msg, stamps)).collect::<Vec<_>>();
+        assert_eq!(seqs(&batch), vec![1, 2]);
+        assert!(next.is_none());
+
+        queue.push(None, updates(3));
+        queue.close(FrameView::close(CloseCode::Away, "bye"));
+        let (batch, next) = queue.next_batch((updates(0).into(), None), Instant::now() + Duration::from_secs(10)).await;
+        assert_eq!(batch.len(), 2);
+        assert!(matches!(next, Some(Outgoing::Close(_))));
+    }
+
+    #[tokio::test]
+    async fn test_expired_messages_are_dropped() {
+        let btc = Subscription::L4Book { coin: "BTC".to_string(), conflate_ms: None };
+        let trades = Subscription::Trades { coin: "BTC".to_string() };
+        let ttls = BTreeMap::from([("l4Book".to_string(), Duration::from_millis(20))]);
+        let queue = SendQueue::new(BackpressurePolicy::Disconnect, 16).with_ttls(Arc::new(ttls));
+        queue.push(Some(&btc), updates(1));
+        queue.push(Some(&trades), ServerResponse::Trades(Vec::new()));
+        queue.push(Some(&btc), updates(2));
+        sleep(Duration::from_millis(30)).await;
+        // the update after the expired one can't be applied without it either
+        assert!(
+            matches!(queue.next().await, Some(Outgoing::Message(msg, _)) if matches!(msg.msg(), ServerResponse::Trades(_)))
+        );
+        // and neither can the ones until the subscription gets a fresh snapshot
+        queue.push(Some(&btc), updates(3));
+        assert_eq!(queue.take_resyncs(), vec![btc.clone()]);
+        let snapshot = L4Book::Snapshot {
+            coin: "BTC".to_string(),
+            time: 4,
+            height: 4,
+            seq: 4,
+            levels: [Vec::new(), Vec::new()],
+            checksum: 0,
+            checkpoint: None,
+        };
+        queue.push(Some(&btc), ServerResponse::L4Book(snapshot));
+        queue.push(Some(&btc), updates(5));
+        let out = drain(&queue);
+        assert!(
+            matches!(&out[0], Outgoing::Message(msg, _) if matches!(msg.msg(), ServerResponse::L4Book(L4Book::Snapshot { .. })))
+        );
+        assert_eq!(seqs(&out), vec![5]);
+        assert_eq!(queue.stats().dropped, 3);
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_drops_expired_messages_first() {
+        let trades = Subscription::Trades { coin: "BTC".to_string() };
+        let ttls = BTreeMap::from([("trades".to_string(), Duration::from_millis(20))]);
+        let queue = SendQueue::new(BackpressurePolicy::Disconnect, 2).with_ttls(Arc::new(ttls));
+        queue.push(Some(&trades), ServerResponse::Trades(Vec::new()));
+        queue.push(None, updates(1));
+        sleep(Duration::from_millis(30)).await;
+        queue.push(Some(&trades), ServerResponse::Trades(Vec::new()));
+        assert!(!queue.is_closing());
+        assert_eq!(queue.stats(), QueueStats { depth: 2, capacity: 2, dropped: 1, conflated: 0 });
+        // trades stand alone, no snapshot is needed
+        assert!(queue.take_resyncs().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_l4_seqs_of_messages_taken() {
+        let queue = SendQueue::new(BackpressurePolicy::Disconnect, 8);
+        let l4 = Subscription::L4Book { coin: "BTC".to_string(), conflate_ms: None };
+        queue.push(Some(&l4), updates(1));
+        assert!(queue.next().await.is_some());
+        assert!(queue.l4_seqs().is_empty());
+
+        queue.track_l4_seqs();
+        queue.push(Some(&l4), updates(2));
+        queue.push(Some(&l4), updates(3));
+        assert!(queue.next().await.is_some());
+        // the one still queued isn't counted
+        assert_eq!(queue.l4_seqs(), HashMap::from([(l4, 2)]));
+    }
+}
@@ -0,0 +1,135 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use alloy::hex;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    servers::encoding::Numbers,
+    types::subscription::{ClientMessage, Subscription},
+};
+
+// sessions kept at once, so that clients dropping connections in a loop can't fill the memory
+const MAX_SESSIONS: usize = 10_000;
+const TOKEN_BYTES: usize = 16;
+
+// sent once a websocket connection is authenticated, when sessions are enabled. Reconnecting with
+// `?session=<token>` within `grace_secs` of the connection dropping restores it; `resumed` tells whether this
+// connection restored one
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SessionToken {
+    pub token: String,
+    pub resumed: bool,
+    pub grace_secs: u64,
+}
+
+// what a dropped connection negotiated and subscribed to
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Session {
+    // of the connection's API key, which the next one has to have too
+    pub(crate) identity: Option<String>,
+    pub(crate) batch_ms: Option<u64>,
+    pub(crate) numbers: Numbers,
+    pub(crate) subscriptions: Vec<Subscription>,
+    // the seq of the last l4 update or snapshot written to the client, per subscription
+    pub(crate) l4_seqs: HashMap<Subscription, u64>,
+}
+
+impl Session {
+    // the requests that bring a new connection back to where this one was: l4 books resume after the last update
+    // written, which continues with a snapshot if the updates since are gone, and the rest subscribe again
+    pub(crate) fn requests(&self) -> Vec<ClientMessage> {
+        self.subscriptions
+            .iter()
+            .map(|subscription| match (subscription, self.l4_seqs.get(subscription)) {
+                (Subscription::L4Book { .. }, Some(seq)) => {
+                    ClientMessage::Resume { subscription: subscription.clone(), from_seq: seq + 1 }
+                }
+                _ => ClientMessage::Subscribe { subscription: subscription.clone() },
+            })
+            .collect()
+    }
+}
+
+/// The sessions of dropped websocket connections, kept for `grace` under the token each connection was given.
+pub(crate) struct Sessions {
+    grace: Duration,
+    rng: SystemRandom,
+    // with the time they expire
+    parked: Mutex<HashMap<String, (Session, Instant)>>,
+}
+
+impl Sessions {
+    pub(crate) fn new(grace: Duration) -> Self {
+        Self { grace, rng: SystemRandom::new(), parked: Mutex::default() }
+    }
+
+    pub(crate) const fn grace(&self) -> Duration {
+        self.grace
+    }
+
+    // a new random token, hex encoded
+    pub(crate) fn token(&self) -> String {
+        let mut bytes = [0; TOKEN_BYTES];
+        // the system's generator only fails where it doesn't exist at all
+        let _unused = self.rng.fill(&mut bytes);
+        hex::encode(bytes)
+    }
+
+    // keeps the session of a dropped connection; dropped if too many are kept already
+    pub(crate) fn park(&self, token: String, session: Session) {
+        let Ok(mut parked) = self.parked.lock() else {
+            return;
+        };
+        let now = Instant::now();
+        parked.retain(|_, (_, expiry)| *expiry > now);
+        if parked.len() < MAX_SESSIONS {
+            parked.insert(token, (session, now + self.grace));
+        }
+    }
+
+    // the session kept under the token, once, if it hasn't expired
+    pub(crate) fn take(&self, token: &str) -> Option<Session> {
+        let (session, expiry) = self.parked.lock().ok()?.remove(token)?;
+        (expiry > Instant::now()).then_some(session)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sessions() {
+        let sessions = Sessions::new(Duration::from_secs(30));
+        let token = sessions.token();
+        assert_eq!(token.len(), 2 * TOKEN_BYTES);
+        assert_ne!(token, sessions.token());
+
+        let l4 = Subscription::L4Book { coin: "BTC".to_string(), conflate_ms: None };
+        let trades = Subscription::Trades { coin: "BTC".to_string() };
+        let session = Session {
+            subscriptions: vec![l4.clone(), trades.clone()],
+            l4_seqs: HashMap::from([(l4.clone(), 41)]),
+            ..Session::default()
+        };
+        let requests = session.requests();
+        assert!(matches!(&requests[0], ClientMessage::Resume { subscription, from_seq: 42 } if *subscription == l4));
+        assert!(matches!(&requests[1], ClientMessage::Subscribe { subscription } if *subscription == trades));
+
+        sessions.park(token.clone(), session.clone());
+        assert!(sessions.take("unknown").is_none());
+        assert_eq!(sessions.take(&token), Some(session.clone()));
+        // only once
+        assert!(sessions.take(&token).is_none());
+
+        let expired = Sessions::new(Duration::ZERO);
+        expired.park(token.clone(), session);
+        assert!(expired.take(&token).is_none());
+    }
+}
@@ -0,0 +1,221 @@
+use std::{fmt, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::watch;
+use tracing::{info, level_filters::LevelFilter};
+
+use crate::{
+    logging,
+    prelude::*,
+    servers::{
+        config::ServerConfig,
+        limits::{ConnectionLimits, MAX_MESSAGE_BYTES},
+        rate_limit::{ConnectionRateLimiter, RateLimits},
+    },
+};
+
+/// The part of the configuration that can change while the server is running, without dropping connections.
+/// The compression level only applies to connections opened after the change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RuntimeSettings {
+    pub(crate) compression_level: u32,
+    pub(crate) rate_limits: RateLimits,
+    pub(crate) max_subscriptions: Option<usize>,
+    pub(crate) connection_limits: ConnectionLimits,
+    #[serde(with = "level_filter")]
+    pub(crate) log_level: Option<LevelFilter>,
+}
+
+impl RuntimeSettings {
+    pub(crate) const fn new(config: &ServerConfig) -> Self {
+        Self {
+            compression_level: config.compression_level,
+            rate_limits: config.rate_limits,
+            max_subscriptions: config.max_subscriptions,
+            connection_limits: config.connection_limits,
+            log_level: config.log_level,
+        }
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.compression_level > 9 {
+            return Err(format!("compression level {} is out of range (0 to 9)", self.compression_level).into());
+        }
+        self.rate_limits.validate()?;
+        if self.max_subscriptions == Some(0) {
+            return Err("the subscription limit has to be at least 1".into());
+        }
+        let ConnectionLimits { max_connections, max_connections_per_ip, max_message_bytes } = self.connection_limits;
+        if [max_connections, max_connections_per_ip, max_message_bytes].contains(&Some(0)) {
+            return Err("connection limits have to be at least 1".into());
+        }
+        if max_message_bytes.is_some_and(|bytes| bytes > MAX_MESSAGE_BYTES) {
+            return Err(format!("the message size limit can't exceed {MAX_MESSAGE_BYTES} bytes").into());
+        }
+        Ok(())
+    }
+}
+
+/// Produces a fresh configuration on SIGHUP or `POST /reload` on the admin port.
+///
+/// Typically it reads the config file again. Only its runtime settings (rate limits, connection limits, compression
+/// level, subscription limit and log level) are applied; everything else needs a restart.
+#[derive(Clone)]
+pub struct ReloadHook(Arc<dyn Fn() -> Result<ServerConfig> + Send + Sync>);
+
+impl ReloadHook {
+    pub fn new(reload: impl Fn() -> Result<ServerConfig> + Send + Sync + 'static) -> Self {
+        Self(Arc::new(reload))
+    }
+}
+
+impl Debug for ReloadHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ReloadHook")
+    }
+}
+
+// the current runtime settings; connections watch them through a receiver
+#[derive(Clone)]
+pub(crate) struct Settings {
+    tx: Arc<watch::Sender<RuntimeSettings>>,
+    reload: Option<ReloadHook>,
+    connection_limiter: Arc<ConnectionRateLimiter>,
+}
+
+impl Settings {
+    pub(crate) fn new(config: &ServerConfig, connection_limiter: Arc<ConnectionRateLimiter>) -> Self {
+        let settings = RuntimeSettings::new(config);
+        apply_global(&settings, &connection_limiter);
+        let (tx, _) = watch::channel(settings);
+        Self { tx: Arc::new(tx), reload: config.reload.clone(), connection_limiter }
+    }
+
+    // marked as changed, so the first check already picks up the current settings
+    pub(crate) fn subscribe(&self) -> watch::Receiver<RuntimeSettings> {
+        let mut rx = self.tx.subscribe();
+        rx.mark_changed();
+        rx
+    }
+
+    pub(crate) fn current(&self) -> RuntimeSettings {
+        *self.tx.borrow()
+    }
+
+    pub(crate) fn update(&self, settings: RuntimeSettings) -> Result<()> {
+        settings.validate()?;
+        apply_global(&settings, &self.connection_limiter);
+        let changed = self.tx.send_if_modified(|current| {
+            let changed = *current != settings;
+            *current = settings;
+            changed
+        });
+        if changed {
+            info!("Runtime settings changed to {settings:?}");
+        }
+        Ok(())
+    }
+
+    pub(crate) fn reload(&self) -> Result<()> {
+        let reload = self.reload.as_ref().ok_or("No reload hook configured")?;
+        let config = (reload.0)()?;
+        config.validate()?;
+        self.update(RuntimeSettings::new(&config))
+    }
+
+    // SIGHUP keeps its default (terminating) behavior without a reload hook. There is no SIGHUP on Windows, where
+    // reloads go through the admin API
+    #[cfg(unix)]
+    pub(crate) fn reload_on_sighup(&self) -> Result<()> {
+        use tokio::signal::unix::{SignalKind, signal};
+        use tracing::error;
+
+        if self.reload.is_none() {
+            return Ok(());
+        }
+        let mut sighup = signal(SignalKind::hangup())?;
+        let settings = self.clone();
+        tokio::spawn(async move {
+            while sighup.recv().await.is_some() {
+                info!("Received SIGHUP, reloading runtime settings");
+                if let Err(err) = settings.reload() {
+                    error!("Unable to reload runtime settings: {err}");
+                }
+            }
+        });
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    #[allow(clippy::unnecessary_wraps, clippy::unused_self)]
+    pub(crate) const fn reload_on_sighup(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+// settings that aren't read per connection
+fn apply_global(settings: &RuntimeSettings, connection_limiter: &ConnectionRateLimiter) {
+    if let Some(level) = settings.log_level {
+        logging::set_max_level(level);
+    }
+    connection_limiter.set_per_minute(settings.rate_limits.connections_per_ip_per_min);
+}
+
+pub(crate) fn patch_settings(current: RuntimeSettings, patch: Value) -> Result<RuntimeSettings> {
+    let mut value = serde_json::to_value(current)?;
+    merge(&mut value, patch);
+    Ok(serde_json::from_value(value)?)
+}
+
+fn merge(target: &mut Value, patch: Value) {
+    match (target, patch) {
+        (Value::Object(target), Value::Object(patch)) => {
+            for (key, value) in patch {
+                merge(target.entry(key).or_insert(Value::Null), value);
+            }
+        }
+        (target, patch) => *target = patch,
+    }
+}
+
+// log levels as `"info"`, `"debug"`, ...
+mod level_filter {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+    use tracing::level_filters::LevelFilter;
+
+    // serde passes a reference
+    #[allow(clippy::ref_option, clippy::trivially_copy_pass_by_ref)]
+    pub(super) fn serialize<S: Serializer>(level: &Option<LevelFilter>, serializer: S) -> Result<S::Ok, S::Error> {
+        level.map(|level| level.to_string().to_lowercase()).serialize(serializer)
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<LevelFilter>, D::Error> {
+        Option::<String>::deserialize(deserializer)?.map(|level| level.parse().map_err(de::Error::custom)).transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_patch_settings() -> Result<()> {
+        let current = RuntimeSettings::new(&ServerConfig::new("0.0.0.0:8000".parse()?));
+        let patched = patch_settings(
+            current,
+            json!({ "rate_limits": { "client_messages_per_sec": 5 }, "log_level": "debug", "max_subscriptions": 10 }),
+        )?;
+        assert_eq!(patched.rate_limits.client_messages_per_sec, Some(5));
+        assert_eq!(patched.rate_limits.outbound_messages_per_sec, None);
+        assert_eq!(patched.compression_level, current.compression_level);
+        assert_eq!(patched.log_level, Some(LevelFilter::DEBUG));
+        assert_eq!(patched.max_subscriptions, Some(10));
+        assert!(patch_settings(current, json!({ "compression": 3 })).is_err());
+        assert!(patch_settings(current, json!({ "compression_level": 10 }))?.validate().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,233 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    future::poll_fn,
+    io::{self, Write},
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use bytes::Bytes;
+use flate2::{Compression, write::DeflateEncoder};
+use futures_util::{
+    SinkExt, StreamExt,
+    stream::{SplitSink, SplitStream},
+};
+use tokio_util::codec::Framed;
+use yawc::{Frame, FrameView, HttpStream, OpCode, ReadHalf, WebSocket, WebSocketError, close::CloseCode, codec::Codec};
+
+use crate::prelude::*;
+
+// compressed payloads kept for reuse, across all levels
+const CACHE_ENTRIES: usize = 1024;
+
+/// Compresses each outbound payload once per compression level. Without context takeover a compressed message
+/// only depends on its payload, so every connection that sends the same message can send the same frame.
+#[derive(Default)]
+pub(crate) struct SharedCompressor {
+    cache: Mutex<Cache>,
+}
+
+type Entry = Arc<OnceLock<Option<Bytes>>>;
+
+#[derive(Default)]
+struct Cache {
+    entries: HashMap<(u32, Bytes), Entry>,
+    // oldest first
+    order: VecDeque<(u32, Bytes)>,
+}
+
+impl Cache {
+    fn entry(&mut self, level: u32, payload: &Bytes) -> Entry {
+        let key = (level, payload.clone());
+        if let Some(entry) = self.entries.get(&key) {
+            return entry.clone();
+        }
+        if self.order.len() >= CACHE_ENTRIES
+            && let Some(oldest) = self.order.pop_front()
+        {
+            self.entries.remove(&oldest);
+        }
+        let entry = Entry::default();
+        self.entries.insert(key.clone(), entry.clone());
+        self.order.push_back(key);
+        entry
+    }
+}
+
+impl SharedCompressor {
+    pub(crate) fn compress(&self, level: u32, payload: &Bytes) -> Result<Bytes> {
+        let Ok(mut cache) = self.cache.lock() else {
+            return Ok(deflate(level, payload)?);
+        };
+        let entry = cache.entry(level, payload);
+        drop(cache);
+        // connections sending the same payload at once wait for the first one to compress it
+        entry.get_or_init(|| deflate(level, payload).ok()).clone().ok_or_else(|| "Unable to compress message".into())
+    }
+}
+
+// a raw deflate stream ending in a sync flush, whose empty block is left for the receiver to add (RFC 7692, 7.2.1)
+pub(crate) fn deflate(level: u32, payload: &[u8]) -> io::Result<Bytes> {
+    let mut encoder = DeflateEncoder::new(Vec::with_capacity(payload.len() / 2 + 16), Compression::new(level));
+    encoder.write_all(payload)?;
+    encoder.flush()?;
+    let mut compressed = std::mem::take(encoder.get_mut());
+    if compressed.ends_with(&[0, 0, 0xff, 0xff]) {
+        compressed.truncate(compressed.len() - 4);
+    }
+    Ok(compressed.into())
+}
+
+// yawc compresses every frame it writes itself, so shared connections write raw frames and answer pings and
+// closes without it
+pub(crate) struct RawSink {
+    sink: SplitSink<Framed<HttpStream, Codec>, Frame>,
+    // nothing is sent after a close frame
+    closed: bool,
+}
+
+impl RawSink {
+    async fn send(&mut self, frame: Frame) -> Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        self.closed = frame.opcode == OpCode::Close;
+        Ok(self.sink.send(frame).await?)
+    }
+}
+
+pub(crate) enum SocketReader {
+    Plain(SplitStream<WebSocket>),
+    Shared {
+        stream: SplitStream<Framed<HttpStream, Codec>>,
+        read_half: ReadHalf,
+        sink: Arc<tokio::sync::Mutex<RawSink>>,
+        // a pong or close echo still to send, kept if the read is cancelled
+        pending: Option<FrameView>,
+    },
+}
+
+impl SocketReader {
+    // pings are answered and closes echoed before they are returned, like yawc does
+    pub(crate) async fn next(&mut self) -> Option<FrameView> {
+        match self {
+            Self::Plain(stream) => stream.next().await,
+            Self::Shared { stream, read_half, sink, pending } => loop {
+                if let Some(frame) = pending.clone() {
+                    if sink.lock().await.send(frame.into()).await.is_err() {
+                        return None;
+                    }
+                    *pending = None;
+                }
+                let frame = match poll_fn(|cx| read_half.poll_frame(stream, cx)).await {
+                    Ok(frame) => frame,
+                    Err(WebSocketError::ConnectionClosed) => return None,
+                    Err(err) => {
+                        let code = if matches!(err, WebSocketError::FrameTooLarge) {
+                            CloseCode::Size
+                        } else {
+                            CloseCode::Protocol
+                        };
+                        let _unused = sink.lock().await.send(FrameView::close(code, err.to_string()).into()).await;
+                        return None;
+                    }
+                };
+                match frame.opcode {
+                    OpCode::Ping => *pending = Some(FrameView::pong(frame.payload)),
+                    OpCode::Close => {
+                        let _unused = sink.lock().await.send(FrameView::close_raw(frame.payload.clone()).into()).await;
+                        return Some(frame);
+                    }
+                    _ => return Some(frame),
+                }
+            },
+        }
+    }
+}
+
+pub(crate) enum SocketWriter {
+    Plain(SplitSink<WebSocket, FrameView>),
+    Shared { sink: Arc<tokio::sync::Mutex<RawSink>>, compressor: Arc<SharedCompressor>, level: u32 },
+}
+
+impl SocketWriter {
+    pub(crate) async fn send(&mut self, frame: FrameView) -> Result<()> {
+        match self {
+            Self::Plain(sink) => Ok(sink.send(frame).await?),
+            Self::Shared { sink, compressor, level } => {
+                let frame = match frame.opcode {
+                    OpCode::Text | OpCode::Binary => {
+                        Frame::compress(true, frame.opcode, None, compressor.compress(*level, &frame.payload)?.as_ref())
+                    }
+                    _ => frame.into(),
+                };
+                sink.lock().await.send(frame).await
+            }
+        }
+    }
+
+    // like `send`, for a frame whose payload the caller can hand compressed, e.g. from other connections
+    pub(crate) async fn send_compressed(
+        &mut self,
+        frame: FrameView,
+        compressed: impl FnOnce(u32) -> Result<Bytes>,
+    ) -> Result<()> {
+        match self {
+            Self::Plain(sink) => Ok(sink.send(frame).await?),
+            Self::Shared { sink, level, .. } => {
+                let frame = Frame::compress(true, frame.opcode, None, compressed(*level)?.as_ref());
+                sink.lock().await.send(frame).await
+            }
+        }
+    }
+}
+
+// `shared` is the compressor and level to use, for connections that negotiated deflate without context takeover
+pub(crate) fn split_socket(
+    socket: WebSocket,
+    shared: Option<(Arc<SharedCompressor>, u32)>,
+) -> (SocketWriter, SocketReader) {
+    let Some((compressor, level)) = shared else {
+        let (sink, stream) = socket.split();
+        return (SocketWriter::Plain(sink), SocketReader::Plain(stream));
+    };
+    // SAFETY: `split_stream` only moves the framed stream and the two halves out of the socket, so nothing
+    // is aliased; the write half is dropped unused, as every frame is written through the one `RawSink`
+    // behind its mutex. What it makes the caller responsible for is what `WebSocket` would otherwise do:
+    // `SocketReader` queues a pong for every ping, echoes the close frame and closes with `Protocol` or
+    // `Size` on the read half's errors, `RawSink` sends nothing after a close, and the read half still
+    // inflates and unmasks what it reads while `SocketWriter` sets RSV1 on the frames it deflates.
+    #[allow(unsafe_code)]
+    let (framed, read_half, _) = unsafe { socket.split_stream() };
+    let (sink, stream) = framed.split();
+    let sink = Arc::new(tokio::sync::Mutex::new(RawSink { sink, closed: false }));
+    (
+        SocketWriter::Shared { sink: sink.clone(), compressor, level },
+        SocketReader::Shared { stream, read_half, sink, pending: None },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::DeflateDecoder;
+
+    use super::*;
+
+    #[test]
+    fn test_compresses_each_payload_once() -> Result<()> {
+        let compressor = SharedCompressor::default();
+        let payload = Bytes::from(r#"{"channel":"trades","data":[]}"#.repeat(20));
+        let compressed = compressor.compress(1, &payload)?;
+        assert!(compressed.len() < payload.len());
+        // an equal payload from another connection gets the same allocation
+        assert_eq!(compressor.compress(1, &Bytes::from(payload.to_vec()))?.as_ptr(), compressed.as_ptr());
+        assert_ne!(compressor.compress(9, &payload)?.as_ptr(), compressed.as_ptr());
+
+        let mut inflated = Vec::new();
+        DeflateDecoder::new([compressed.as_ref(), &[0, 0, 0xff, 0xff]].concat().as_slice())
+            .read_to_end(&mut inflated)?;
+        assert_eq!(inflated, payload);
+        Ok(())
+    }
+}
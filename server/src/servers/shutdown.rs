@@ -0,0 +1,214 @@
+use std::{
+    future::{Future, pending},
+    pin::Pin,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use futures_util::future::select_all;
+#[cfg(any(unix, windows))]
+use tokio::select;
+use tokio::{sync::watch, time::timeout};
+use tokio_util::{
+    sync::{CancellationToken, WaitForCancellationFuture},
+    task::TaskTracker,
+};
+use tracing::{info, warn};
+use yawc::{FrameView, close::CloseCode};
+
+use crate::prelude::*;
+
+/// Coordinates a graceful shutdown: once triggered the server stops accepting connections,
+/// every connection flushes what it has queued and closes with the shutdown reason,
+/// and [`Shutdown::drain`] waits for the connections to finish.
+#[derive(Clone, Default)]
+pub(crate) struct Shutdown {
+    token: CancellationToken,
+    // first trigger wins
+    reason: Arc<OnceLock<(CloseCode, String)>>,
+    connections: TaskTracker,
+}
+
+impl Shutdown {
+    pub(crate) fn trigger(&self, code: CloseCode, reason: &str) {
+        if self.reason.set((code, reason.to_string())).is_ok() {
+            info!("Shutting down: {reason}");
+        }
+        self.token.cancel();
+    }
+
+    pub(crate) fn cancelled(&self) -> WaitForCancellationFuture<'_> {
+        self.token.cancelled()
+    }
+
+    pub(crate) fn close_frame(&self) -> FrameView {
+        let (code, reason) = self.reason.get().cloned().unwrap_or((CloseCode::Away, String::new()));
+        FrameView::close(code, reason)
+    }
+
+    // connections are detached from the http server once upgraded, so they are tracked here instead
+    pub(crate) fn spawn_connection<F>(&self, connection: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.connections.spawn(connection);
+    }
+
+    // returns false if some connections were still open when the timeout expired
+    pub(crate) async fn drain(&self, drain_timeout: Duration) -> bool {
+        self.connections.close();
+        info!("Draining {} connection(s)", self.connections.len());
+        if timeout(drain_timeout, self.connections.wait()).await.is_err() {
+            warn!("{} connection(s) still open after {drain_timeout:?}", self.connections.len());
+            return false;
+        }
+        true
+    }
+
+    // on the first of the triggers to fire
+    pub(crate) fn trigger_on(&self, triggers: &[Arc<dyn ShutdownTrigger>]) -> Result<()> {
+        let fired = triggers.iter().map(|trigger| trigger.listen()).collect::<Result<Vec<_>>>()?;
+        if fired.is_empty() {
+            return Ok(());
+        }
+        let shutdown = self.clone();
+        tokio::spawn(async move {
+            let (reason, ..) = select_all(fired).await;
+            shutdown.trigger(CloseCode::Away, &reason);
+        });
+        Ok(())
+    }
+}
+
+/// What a [`ShutdownTrigger`] resolves with once it fires: why the server shuts down, e.g. `received SIGTERM`.
+pub type ShutdownRequest = Pin<Box<dyn Future<Output = String> + Send>>;
+
+/// Starts a graceful shutdown when it fires, e.g. on a signal.
+///
+/// Implement this to stop the server from elsewhere. It shuts down on the first of [`Signals`] and
+/// [`ServerConfig::shutdown_triggers`](crate::ServerConfig::shutdown_triggers) to fire, and drains its
+/// connections the same way whichever it is.
+pub trait ShutdownTrigger: Debug + Send + Sync {
+    /// Starts listening, when the server starts. Errors if it can't, e.g. if a signal can't be handled.
+    fn listen(&self) -> Result<ShutdownRequest>;
+}
+
+/// The operating system asking the process to stop.
+///
+/// That is Ctrl-C (SIGINT) and SIGTERM on Unix, and Ctrl-C, Ctrl-Break, closing the console, logging off and
+/// shutting down the system on Windows. Ctrl-C only elsewhere.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Signals;
+
+impl ShutdownTrigger for Signals {
+    #[cfg(unix)]
+    fn listen(&self) -> Result<ShutdownRequest> {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let mut sigint = signal(SignalKind::interrupt())?;
+        let mut sigterm = signal(SignalKind::terminate())?;
+        Ok(Box::pin(async move {
+            let signal = select! {
+                _ = sigint.recv() => "SIGINT",
+                _ = sigterm.recv() => "SIGTERM",
+            };
+            format!("received {signal}")
+        }))
+    }
+
+    // Windows ends the process a few seconds after the last three, so connections get less time to drain
+    #[cfg(windows)]
+    fn listen(&self) -> Result<ShutdownRequest> {
+        use tokio::signal::windows::{ctrl_break, ctrl_c, ctrl_close, ctrl_logoff, ctrl_shutdown};
+
+        let (mut ctrl_c, mut ctrl_break) = (ctrl_c()?, ctrl_break()?);
+        let (mut close, mut logoff, mut system_shutdown) = (ctrl_close()?, ctrl_logoff()?, ctrl_shutdown()?);
+        Ok(Box::pin(async move {
+            let event = select! {
+                _ = ctrl_c.recv() => "Ctrl-C",
+                _ = ctrl_break.recv() => "Ctrl-Break",
+                _ = close.recv() => "console close",
+                _ = logoff.recv() => "logoff",
+                _ = system_shutdown.recv() => "system shutdown",
+            };
+            format!("received {event}")
+        }))
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    fn listen(&self) -> Result<ShutdownRequest> {
+        Ok(Box::pin(async {
+            if tokio::signal::ctrl_c().await.is_err() {
+                pending::<()>().await;
+            }
+            "received Ctrl-C".to_string()
+        }))
+    }
+}
+
+/// Fires on `POST /shutdown` of the admin API.
+#[derive(Debug)]
+pub(crate) struct AdminShutdown {
+    requested: watch::Sender<Option<String>>,
+}
+
+impl Default for AdminShutdown {
+    fn default() -> Self {
+        Self { requested: watch::channel(None).0 }
+    }
+}
+
+impl AdminShutdown {
+    pub(crate) fn request(&self, reason: String) {
+        self.requested.send_replace(Some(reason));
+    }
+}
+
+impl ShutdownTrigger for AdminShutdown {
+    fn listen(&self) -> Result<ShutdownRequest> {
+        let mut requested = self.requested.subscribe();
+        Ok(Box::pin(async move {
+            let reason = requested.wait_for(Option::is_some).await.map(|reason| reason.clone().unwrap_or_default());
+            // never, once the admin API is gone
+            if reason.is_err() {
+                pending::<()>().await;
+            }
+            reason.unwrap_or_default()
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_drain_waits_for_connections() {
+        let shutdown = Shutdown::default();
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        {
+            let shutdown = shutdown.clone();
+            shutdown.clone().spawn_connection(async move {
+                shutdown.cancelled().await;
+                let _unused = rx.await;
+            });
+        }
+        shutdown.trigger(CloseCode::Away, "test");
+        shutdown.trigger(CloseCode::Restart, "ignored");
+        assert_eq!(shutdown.close_frame().close_reason(), Some("test"));
+        assert!(!shutdown.drain(Duration::from_millis(50)).await);
+        let _unused = tx.send(());
+        assert!(shutdown.drain(Duration::from_secs(1)).await);
+    }
+
+    #[tokio::test]
+    async fn test_triggers() -> Result<()> {
+        let shutdown = Shutdown::default();
+        let admin = Arc::new(AdminShutdown::default());
+        shutdown.trigger_on(&[Arc::new(Signals), admin.clone()])?;
+        admin.request("requested through the admin API".to_string());
+        timeout(Duration::from_secs(1), shutdown.cancelled()).await?;
+        assert_eq!(shutdown.close_frame().close_reason(), Some("requested through the admin API"));
+        Ok(())
+    }
+}
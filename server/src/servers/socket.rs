@@ -0,0 +1,224 @@
+#[cfg(windows)]
+use std::os::windows::io::AsSocket;
+#[cfg(unix)]
+use std::{
+    env,
+    os::fd::{AsFd as AsSocket, FromRawFd, RawFd},
+    process,
+};
+use std::{net::SocketAddr, time::Duration};
+
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use tokio::net::TcpListener;
+use tracing::debug;
+
+use crate::prelude::*;
+
+// the first socket systemd passes on, see sd_listen_fds(3)
+#[cfg(unix)]
+const LISTEN_FDS_START: RawFd = 3;
+
+/// TCP options of the connections the websocket port accepts, and the backlog of the server's listening sockets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// Send every frame right away with `TCP_NODELAY`, instead of holding small frames back to fill a segment
+    /// (Nagle's algorithm). On by default.
+    pub nodelay: bool,
+    /// Probe idle connections with `SO_KEEPALIVE`, so that the kernel finds peers that are gone without a trace.
+    /// Off when not set.
+    pub keepalive: Option<TcpKeepalive>,
+    /// `SO_SNDBUF` and `SO_RCVBUF` of a connection, in bytes. The kernel doubles them, and tunes them itself when
+    /// not set.
+    pub send_buffer: Option<usize>,
+    pub recv_buffer: Option<usize>,
+    /// Connections the kernel queues until the server accepts them.
+    pub backlog: u32,
+}
+
+/// When and how often an idle connection is probed: the kernel closes it after `retries` probes in a row went
+/// unanswered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpKeepalive {
+    pub idle: Duration,
+    pub interval: Duration,
+    pub retries: u32,
+}
+
+impl SocketOptions {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self { nodelay: true, keepalive: None, send_buffer: None, recv_buffer: None, backlog: 1024 }
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.backlog == 0 {
+            return Err("the listen backlog has to be at least 1".into());
+        }
+        if self.send_buffer == Some(0) || self.recv_buffer == Some(0) {
+            return Err("socket buffers have to be at least a byte".into());
+        }
+        if let Some(keepalive) = self.keepalive
+            && (keepalive.idle.as_secs() == 0 || keepalive.interval.as_secs() == 0 || keepalive.retries == 0)
+        {
+            return Err("TCP keepalive needs an idle time and an interval of at least a second, and a retry".into());
+        }
+        Ok(())
+    }
+
+    // on an accepted connection. One that fails is most likely closed already, which its reads find out
+    pub(crate) fn apply(&self, socket: impl AsSocket) {
+        if let Err(err) = self.try_apply(&SockRef::from(&socket)) {
+            debug!("Unable to set the socket options of a connection: {err}");
+        }
+    }
+
+    fn try_apply(&self, socket: &SockRef<'_>) -> io::Result<()> {
+        socket.set_nodelay(self.nodelay)?;
+        if let Some(keepalive) = self.keepalive {
+            let params = socket2::TcpKeepalive::new().with_time(keepalive.idle).with_interval(keepalive.interval);
+            // Windows always sends 10 probes
+            #[cfg(unix)]
+            let params = params.with_retries(keepalive.retries);
+            socket.set_tcp_keepalive(&params)?;
+        }
+        if let Some(size) = self.send_buffer {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer {
+            socket.set_recv_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Binds the listening socket ourselves (rather than via `TcpListener::bind`) so that IPv6 sockets can be made
+// dual-stack. With `dual_stack` set, binding to `[::]` also accepts IPv4 clients as IPv4-mapped addresses.
+// With `reuse_port`, another process can listen on the same port at the same time, e.g. the next release while
+// this one drains its connections.
+pub(crate) fn bind_tcp_listener(
+    address: SocketAddr,
+    dual_stack: bool,
+    reuse_port: bool,
+    backlog: u32,
+) -> Result<TcpListener> {
+    if dual_stack && address.is_ipv4() {
+        return Err(format!("Dual-stack requires an IPv6 bind address, got {address}").into());
+    }
+    let socket = Socket::new(Domain::for_address(address), Type::STREAM, Some(Protocol::TCP))?;
+    if address.is_ipv6() {
+        socket.set_only_v6(!dual_stack)?;
+    }
+    // like `TcpListener::bind`: on Windows, SO_REUSEADDR would let another process bind the port too
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(reuse_port)?;
+    #[cfg(not(unix))]
+    if reuse_port {
+        return Err("SO_REUSEPORT is only available on Unix".into());
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(i32::try_from(backlog).unwrap_or(i32::MAX))?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+// the listening socket passed on by systemd socket activation, if the server was started that way. systemd keeps
+// it open between restarts, queueing the connections that arrive in between
+#[cfg(unix)]
+pub(crate) fn activated_listener() -> Result<Option<TcpListener>> {
+    let for_us = env::var("LISTEN_PID").is_ok_and(|pid| pid == process::id().to_string());
+    let fds = env::var("LISTEN_FDS").ok().and_then(|fds| fds.parse::<u32>().ok()).unwrap_or(0);
+    if !for_us || fds == 0 {
+        return Ok(None);
+    }
+    // the process owns the sockets passed on to it, and takes the first one only once
+    #[allow(unsafe_code)]
+    let socket = unsafe { Socket::from_raw_fd(LISTEN_FDS_START) };
+    if socket.r#type()? != Type::STREAM || !socket.is_listener()? {
+        return Err("the socket passed by systemd isn't a listening TCP socket".into());
+    }
+    socket.set_nonblocking(true)?;
+    Ok(Some(TcpListener::from_std(socket.into())?))
+}
+
+// there is no systemd to pass a socket on
+#[cfg(not(unix))]
+#[allow(clippy::unnecessary_wraps)]
+pub(crate) const fn activated_listener() -> Result<Option<TcpListener>> {
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use tokio::net::TcpStream;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_dual_stack_accepts_ipv4_clients() -> Result<()> {
+        let listener = bind_tcp_listener(SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)), true, false, 1024)?;
+        let port = listener.local_addr()?.port();
+        let client = TcpStream::connect((Ipv4Addr::LOCALHOST, port));
+        let (client, accepted) = tokio::join!(client, listener.accept());
+        client?;
+        let (_, peer) = accepted?;
+        assert!(peer.is_ipv6());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_reuse_port() -> Result<()> {
+        let listener = bind_tcp_listener(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), false, true, 1024)?;
+        let address = listener.local_addr()?;
+        // the next process takes over the port while this one still listens
+        assert!(bind_tcp_listener(address, false, true, 1024).is_ok());
+        assert!(bind_tcp_listener(address, false, false, 1024).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_socket_options() -> Result<()> {
+        let listener = bind_tcp_listener(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), false, false, 16)?;
+        let client = TcpStream::connect(listener.local_addr()?).await?;
+        let (stream, _) = listener.accept().await?;
+        let keepalive = TcpKeepalive { idle: Duration::from_secs(30), interval: Duration::from_secs(5), retries: 4 };
+        let options = SocketOptions { keepalive: Some(keepalive), send_buffer: Some(1 << 16), ..SocketOptions::new() };
+        options.validate()?;
+        options.apply(&stream);
+
+        let socket = SockRef::from(&stream);
+        assert!(socket.nodelay()?);
+        assert!(socket.keepalive()?);
+        // Windows doesn't report them
+        #[cfg(unix)]
+        {
+            assert_eq!(socket.keepalive_time()?, keepalive.idle);
+            assert_eq!(socket.keepalive_interval()?, keepalive.interval);
+            assert_eq!(socket.keepalive_retries()?, keepalive.retries);
+        }
+        // doubled for the kernel's bookkeeping
+        #[cfg(target_os = "linux")]
+        assert_eq!(socket.send_buffer_size()?, 1 << 17);
+        assert!(!SockRef::from(&client).keepalive()?);
+
+        assert!(SocketOptions { backlog: 0, ..options }.validate().is_err());
+        let keepalive = TcpKeepalive { retries: 0, ..keepalive };
+        assert!(SocketOptions { keepalive: Some(keepalive), ..options }.validate().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_dual_stack_rejects_ipv4_address() {
+        assert!(bind_tcp_listener(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), true, false, 1024).is_err());
+    }
+}
@@ -0,0 +1,282 @@
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+
+use axum::{
+    http::{HeaderMap, StatusCode},
+    response::{
+        IntoResponse, Response,
+        sse::{Event, KeepAlive, Sse},
+    },
+};
+use futures_util::{Stream, stream};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::{select, sync::broadcast::error::RecvError};
+use tracing::{Instrument, error, field, info, info_span};
+
+use crate::{
+    latency::{Stamped, Stamps, now_ms},
+    metrics::METRICS,
+    servers::{
+        auth::{AuthError, Authenticator, ConnectionPermit},
+        encoding::{Encoding, Numbers, Version},
+        outbound::Outbound,
+        protocol::Versioned,
+        rate_limit::PeerAddr,
+        registry::ConnectionStats,
+        replay::Replays,
+        send_queue::{Outgoing, SendQueue},
+        websocket_server::{
+            ConnectionContext, Universe, on_command, on_resume, receive_client_message, refuse_until_ready,
+            report_slow_consumer, resync_expired, send_internal_message,
+        },
+    },
+    signing::SIGNING_KEY_HEADER,
+    types::subscription::{ClientMessage, Subscription, SubscriptionManager},
+};
+
+const DEFAULT_CHANNEL: &str = "l4Book";
+
+// the query string of `/stream`, e.g. `/stream?markets=BTC,ETH&channels=l2Book,trades`
+#[derive(Debug, Deserialize)]
+pub(crate) struct StreamQuery {
+    markets: String,
+    channels: Option<String>,
+}
+
+impl StreamQuery {
+    // every channel of every market, with the channel's default parameters
+    fn subscriptions(&self) -> Result<Vec<Subscription>, String> {
+        let markets = list(&self.markets);
+        let channels = list(self.channels.as_deref().unwrap_or(DEFAULT_CHANNEL));
+        if markets.is_empty() || channels.is_empty() {
+            return Err("markets and channels can't be empty".to_string());
+        }
+        let mut subscriptions = Vec::new();
+        for channel in channels {
+            for coin in &markets {
+                let subscription = serde_json::from_value(json!({"type": channel, "coin": coin}))
+                    .map_err(|_| format!("Unknown channel: {channel}"))?;
+                subscriptions.push(subscription);
+            }
+        }
+        Ok(subscriptions)
+    }
+}
+
+fn list(value: &str) -> Vec<&str> {
+    value.split(',').map(str::trim).filter(|item| !item.is_empty()).collect()
+}
+
+// stops the connection once the client stops reading, i.e. the response body is dropped
+struct Reader(Arc<SendQueue>);
+
+impl Drop for Reader {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+// the stream of the websocket endpoint as server-sent events, for clients behind proxies that break websockets.
+// Every message is an event of JSON data, in the format of version 1; the connection ends where a websocket
+// would be closed
+pub(crate) fn stream_handler(
+    PeerAddr(address, wire): PeerAddr,
+    query: &StreamQuery,
+    headers: &HeaderMap,
+    context: ConnectionContext,
+) -> Response {
+    if context.registry.is_maintenance() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "Server in maintenance, try again later").into_response();
+    }
+    if context.standby.is_on() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "Server on standby, try again later").into_response();
+    }
+    if context.registry.is_overloaded() {
+        METRICS.limit_rejections.with_label_values(&["overload"]).inc();
+        return (StatusCode::SERVICE_UNAVAILABLE, "Server overloaded, try again later").into_response();
+    }
+    let subscriptions = match query.subscriptions() {
+        Ok(subscriptions) => subscriptions,
+        Err(err) => return (StatusCode::BAD_REQUEST, err).into_response(),
+    };
+    // there is no first message to authenticate with, the credentials have to be in the request
+    let permit = match &context.auth {
+        Some(auth) => match Authenticator::credential(headers).ok_or(AuthError::Missing).and_then(|c| auth.admit(c)) {
+            Ok(permit) => Some(permit),
+            Err(err) => {
+                info!("Rejecting stream request: {err}");
+                let status = if err.is_limit() { StatusCode::TOO_MANY_REQUESTS } else { StatusCode::UNAUTHORIZED };
+                return (status, err.to_string()).into_response();
+            }
+        },
+        None => None,
+    };
+    let slot = match context.connections.admit(address.ip(), &context.settings.current().connection_limits) {
+        Ok(slot) => slot,
+        Err(err) => {
+            info!("Rejecting stream request: {err}");
+            return (err.status(), err.to_string()).into_response();
+        }
+    };
+
+    let queue = Arc::new(
+        SendQueue::new(context.backpressure, context.send_queue_capacity).with_ttls(context.message_ttls.clone()),
+    );
+    queue.set_priority(context.load_shedding, permit.as_ref().map(ConnectionPermit::priority));
+    let stats = Arc::new(ConnectionStats::new(wire));
+    let latency_metadata = context.latency_metadata;
+    let span = info_span!("connection", client = field::Empty, remote_addr = %address);
+    let shutdown = context.shutdown.clone();
+    let signing_key = context.signing_key.clone();
+    let connection = {
+        let (queue, stats) = (queue.clone(), stats.clone());
+        async move {
+            METRICS.connections_total.inc();
+            METRICS.connections.inc();
+            stream_connection(address, subscriptions, queue, stats, permit, context).await;
+            METRICS.connections.dec();
+            drop(slot);
+        }
+    };
+    shutdown.spawn_connection(connection.instrument(span));
+
+    let mut resp =
+        Sse::new(events(Reader(queue), stats, latency_metadata)).keep_alive(KeepAlive::default()).into_response();
+    if let Some(key) = signing_key {
+        resp.headers_mut().insert(SIGNING_KEY_HEADER, key);
+    }
+    resp
+}
+
+// the connection's main loop, as for a websocket without client messages
+async fn stream_connection(
+    address: SocketAddr,
+    subscriptions: Vec<Subscription>,
+    queue: Arc<SendQueue>,
+    stats: Arc<ConnectionStats>,
+    permit: Option<ConnectionPermit>,
+    context: ConnectionContext,
+) {
+    let identity = permit.as_ref().map(|permit| permit.name().to_string());
+    let tenant = permit.as_ref().and_then(ConnectionPermit::tenant).cloned();
+    let tenant_name = tenant.as_ref().map(|tenant| tenant.name.clone());
+    let mut registration = context.registry.register(address, identity, tenant_name, queue.clone(), stats);
+    let mut manager = SubscriptionManager::default();
+    manager.set_limit(context.settings.current().max_subscriptions);
+
+    let mut internal_message_rx = context.internal_message_tx.subscribe();
+    let mut replays = Replays::new(None);
+    let mut universe = Universe::new(context.markets, context.ignore_spot, tenant.clone()).await;
+    universe.preferences = context.preferences.for_permit(permit.as_ref());
+    refuse_until_ready(&queue, universe.markets.primary()).await;
+    if !queue.is_closing() {
+        for subscription in subscriptions {
+            let request = universe.preferences.apply(ClientMessage::Subscribe { subscription });
+            receive_client_message(&queue, &mut manager, &mut replays, &mut internal_message_rx, request, &universe)
+                .await;
+        }
+    }
+    let shutdown = context.shutdown;
+    while !queue.is_closing() {
+        report_slow_consumer(&queue, &registration, &manager);
+        resync_expired(&queue, &mut manager, &universe.markets).await;
+        select! {
+            recv_result = internal_message_rx.recv() => {
+                match recv_result {
+                    Ok(msg) => {
+                        send_internal_message(&queue, &mut manager, &mut replays, &mut universe, &msg);
+                        on_resume(&queue, &mut manager, &universe.markets, &msg).await;
+                    }
+                    Err(err) => {
+                        if let RecvError::Lagged(n) = err {
+                            METRICS.dropped_messages.inc_by(n);
+                        }
+                        error!("Receiver error: {err}");
+                        queue.abort();
+                    }
+                }
+            }
+
+            command = registration.command() => on_command(command, &queue, &mut manager, &universe.markets).await,
+
+            () = shutdown.cancelled() => {
+                while let Ok(msg) = internal_message_rx.try_recv() {
+                    send_internal_message(&queue, &mut manager, &mut replays, &mut universe, &msg);
+                }
+                queue.close(shutdown.close_frame());
+            }
+        }
+    }
+    report_slow_consumer(&queue, &registration, &manager);
+    info!("Client {} stream closed", registration.id());
+    registration.finish(manager.subscriptions().iter().cloned().collect());
+    drop(registration);
+    drop(permit);
+}
+
+// drains the queue into the response body; pings are left to the keep-alive comments of the response
+fn events(
+    reader: Reader,
+    stats: Arc<ConnectionStats>,
+    latency_metadata: bool,
+) -> impl Stream<Item = Result<Event, Infallible>> {
+    stream::unfold(reader, move |reader| {
+        let stats = stats.clone();
+        async move {
+            loop {
+                let (msg, stamps) = match reader.0.next().await? {
+                    Outgoing::Message(msg, stamps) => (msg, stamps),
+                    Outgoing::Ping | Outgoing::Time => continue,
+                    Outgoing::Close(_) => return None,
+                };
+                let data = match encode(&msg, stamps, latency_metadata) {
+                    Ok(data) => data,
+                    Err(err) => {
+                        error!("Server response serialization error: {err}");
+                        continue;
+                    }
+                };
+                if let Some(stamps) = stamps {
+                    stamps.observe(now_ms());
+                }
+                METRICS.messages_sent.inc();
+                METRICS.payload_bytes.inc_by(data.len() as u64);
+                stats.record(1, data.len() as u64);
+                return Some((Ok(Event::default().data(data)), reader));
+            }
+        }
+    })
+}
+
+// the json every websocket connection of the first version sends, unless the event carries its own stamps
+pub(crate) fn encode(msg: &Outbound, stamps: Option<Stamps>, latency_metadata: bool) -> crate::prelude::Result<String> {
+    if latency_metadata {
+        return Ok(serde_json::to_string(&Stamped::new(Versioned::new(msg.msg(), Version::V1), stamps, now_ms()))?);
+    }
+    Ok(String::from_utf8(msg.payload(Encoding::Json, Version::V1, Numbers::String)?.into())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(markets: &str, channels: Option<&str>) -> StreamQuery {
+        StreamQuery { markets: markets.to_string(), channels: channels.map(str::to_string) }
+    }
+
+    #[test]
+    fn test_stream_subscriptions() {
+        let subscriptions = query("BTC, ETH", None).subscriptions();
+        let l4 = |coin: &str| Subscription::L4Book { coin: coin.to_string(), conflate_ms: None };
+        assert_eq!(subscriptions, Ok(vec![l4("BTC"), l4("ETH")]));
+
+        let subscriptions = query("BTC", Some("trades,bbo")).subscriptions();
+        let trades = Subscription::Trades { coin: "BTC".to_string() };
+        assert_eq!(subscriptions, Ok(vec![trades, Subscription::Bbo { coin: "BTC".to_string(), fields: None }]));
+
+        assert!(query("BTC", Some("quotes")).subscriptions().is_err());
+        // candles need an interval, which the query has no room for
+        assert!(query("BTC", Some("candle")).subscriptions().is_err());
+        assert!(query(",", None).subscriptions().is_err());
+    }
+}
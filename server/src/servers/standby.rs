@@ -0,0 +1,115 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use futures_util::future::select_all;
+use tracing::info;
+
+use crate::{metrics::METRICS, prelude::*};
+
+/// Whether the server is a warm standby. It follows its upstreams and keeps its books like an active instance, but
+/// refuses clients and publishes nothing until it is promoted, so that two instances can fail over without both
+/// publishing.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Standby {
+    // until promoted
+    on: Arc<AtomicBool>,
+}
+
+impl Standby {
+    pub(crate) fn new(on: bool) -> Self {
+        if on {
+            info!("Starting on standby");
+            METRICS.standby.set(1);
+        }
+        Self { on: Arc::new(AtomicBool::new(on)) }
+    }
+
+    pub(crate) fn is_on(&self) -> bool {
+        self.on.load(Ordering::Relaxed)
+    }
+
+    // false if it wasn't on standby
+    pub(crate) fn promote(&self, reason: &str) -> bool {
+        let promoted = self.on.swap(false, Ordering::Relaxed);
+        if promoted {
+            info!("Promoted from standby: {reason}");
+            METRICS.standby.set(0);
+        }
+        promoted
+    }
+
+    // on the first of the triggers to fire
+    pub(crate) fn promote_on(&self, triggers: &[Arc<dyn PromotionTrigger>]) -> Result<()> {
+        if !self.is_on() || triggers.is_empty() {
+            return Ok(());
+        }
+        let fired = triggers.iter().map(|trigger| trigger.listen()).collect::<Result<Vec<_>>>()?;
+        let standby = self.clone();
+        tokio::spawn(async move {
+            let (reason, ..) = select_all(fired).await;
+            standby.promote(&reason);
+        });
+        Ok(())
+    }
+}
+
+/// What a [`PromotionTrigger`] resolves with once it fires: why the server takes over, e.g. `elected leader`.
+pub type PromotionRequest = Pin<Box<dyn Future<Output = String> + Send>>;
+
+/// Promotes a server started on [`ServerConfig::standby`](crate::ServerConfig::standby) when it fires.
+///
+/// Implement this to hand over from a leader election, e.g. a lease in etcd or Kubernetes: resolve once this
+/// instance holds the lease. The server is promoted by the first of
+/// [`ServerConfig::promotion_triggers`](crate::ServerConfig::promotion_triggers) to fire, or by `POST /promote` of the
+/// admin API, whichever comes first. A promotion can't be undone; an instance that loses the lease should shut down.
+pub trait PromotionTrigger: Debug + Send + Sync {
+    /// Starts listening, when the server starts on standby. Errors if it can't.
+    fn listen(&self) -> Result<PromotionRequest>;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{sync::Notify, time::sleep};
+
+    use super::*;
+
+    #[derive(Debug)]
+    struct Elected(Arc<Notify>);
+
+    impl PromotionTrigger for Elected {
+        fn listen(&self) -> Result<PromotionRequest> {
+            let elected = self.0.clone();
+            Ok(Box::pin(async move {
+                elected.notified().await;
+                "elected leader".to_string()
+            }))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_promotion() -> Result<()> {
+        assert!(!Standby::default().is_on());
+        let standby = Standby::new(true);
+        let elected = Arc::new(Notify::new());
+        standby.promote_on(&[Arc::new(Elected(elected.clone()))])?;
+        assert!(standby.is_on());
+        elected.notify_one();
+        for _ in 0..100 {
+            if !standby.is_on() {
+                // and only once
+                assert!(!standby.promote("requested through the admin API"));
+                return Ok(());
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        Err("the trigger didn't promote the server".into())
+    }
+}
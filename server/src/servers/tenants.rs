@@ -0,0 +1,109 @@
+use crate::{
+    prelude::*,
+    servers::{markets::MarketConfig, rate_limit::RateLimits},
+};
+
+// how tenants name the market whose coins have no prefix
+pub(crate) const PRIMARY_MARKET: &str = "primary";
+
+/// A customer sharing the server with others.
+///
+/// Its clients authenticate as identities of its own ([`Identity::tenant`](crate::Identity::tenant)), only get the
+/// coins of its markets and are held to its limits, and the metrics of their connections are labelled with its name.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Tenant {
+    pub name: String,
+    /// Names of the markets its clients may use, `primary` for the primary market. Every market when empty.
+    pub markets: Vec<String>,
+    /// Open connections of all its identities together. Each identity's own limit applies as well.
+    pub max_connections: Option<usize>,
+    /// The limits that are set replace those of the server for its clients. `connections_per_ip_per_min` is
+    /// enforced before clients authenticate, so only the server's applies.
+    pub rate_limits: RateLimits,
+}
+
+impl Tenant {
+    // `market` as found by `Markets::market_of`, `None` for the primary one
+    pub(crate) fn allows(&self, market: Option<&str>) -> bool {
+        let market = market.unwrap_or(PRIMARY_MARKET);
+        self.markets.is_empty() || self.markets.iter().any(|name| name == market)
+    }
+
+    // the server's limits, with those the tenant sets instead
+    pub(crate) fn rate_limits(tenant: Option<&Self>, server: RateLimits) -> RateLimits {
+        let Some(Self { rate_limits: own, .. }) = tenant else {
+            return server;
+        };
+        RateLimits {
+            client_messages_per_sec: own.client_messages_per_sec.or(server.client_messages_per_sec),
+            outbound_messages_per_sec: own.outbound_messages_per_sec.or(server.outbound_messages_per_sec),
+            connections_per_ip_per_min: server.connections_per_ip_per_min,
+            outbound_bytes_per_min: own.outbound_bytes_per_min.or(server.outbound_bytes_per_min),
+            bandwidth_policy: if own.outbound_bytes_per_min.is_some() {
+                own.bandwidth_policy
+            } else {
+                server.bandwidth_policy
+            },
+        }
+    }
+}
+
+pub(crate) fn validate(tenants: &[Tenant], markets: &[MarketConfig]) -> Result<()> {
+    for (i, tenant) in tenants.iter().enumerate() {
+        if tenant.name.is_empty() {
+            return Err("tenants need a name".into());
+        }
+        if tenants[..i].iter().any(|other| other.name == tenant.name) {
+            return Err(format!("tenant {} is configured twice", tenant.name).into());
+        }
+        let known = |name: &String| name == PRIMARY_MARKET || markets.iter().any(|market| market.name == *name);
+        if let Some(market) = tenant.markets.iter().find(|name| !known(name)) {
+            return Err(format!("tenant {} is given unknown market {market}", tenant.name).into());
+        }
+        if tenant.max_connections == Some(0) {
+            return Err(format!("the connection limit of tenant {} has to be at least 1", tenant.name).into());
+        }
+        tenant.rate_limits.validate()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{listeners::order_book::UpstreamNode, servers::rate_limit::BandwidthPolicy};
+
+    #[test]
+    fn test_tenant_markets_and_limits() {
+        let mut tenant = Tenant { name: "acme".to_string(), ..Tenant::default() };
+        assert!(tenant.allows(None) && tenant.allows(Some("testnet")));
+        tenant.markets = vec!["testnet".to_string()];
+        assert!(!tenant.allows(None) && tenant.allows(Some("testnet")));
+
+        let server = RateLimits {
+            client_messages_per_sec: Some(10),
+            connections_per_ip_per_min: Some(60),
+            outbound_bytes_per_min: Some(1000),
+            ..RateLimits::default()
+        };
+        tenant.rate_limits = RateLimits {
+            client_messages_per_sec: Some(5),
+            connections_per_ip_per_min: Some(1),
+            bandwidth_policy: BandwidthPolicy::Disconnect,
+            ..RateLimits::default()
+        };
+        let limits = Tenant::rate_limits(Some(&tenant), server);
+        assert_eq!(limits, RateLimits { client_messages_per_sec: Some(5), ..server });
+        assert_eq!(Tenant::rate_limits(None, server), server);
+
+        let testnet = MarketConfig {
+            name: "testnet".to_string(),
+            upstreams: vec![UpstreamNode::new("/tmp".into())],
+            crossed_books: None,
+            snapshot_strategy: None,
+        };
+        assert!(validate(&[tenant.clone()], std::slice::from_ref(&testnet)).is_ok());
+        assert!(validate(&[tenant.clone()], &[]).is_err());
+        assert!(validate(&[tenant.clone(), tenant], &[testnet]).is_err());
+    }
+}
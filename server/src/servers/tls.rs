@@ -0,0 +1,269 @@
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use axum::serve::Listener;
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher, recommended_watcher};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc::{Receiver, Sender, channel},
+    time::{sleep, timeout},
+};
+use tokio_rustls::{
+    TlsAcceptor,
+    rustls::{
+        ServerConfig,
+        crypto::ring::default_provider,
+        pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    },
+    server::TlsStream,
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    prelude::*,
+    servers::{proxy::read_proxy_header, socket::SocketOptions},
+};
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const PENDING_CONNECTIONS: usize = 128;
+
+/// Paths to the PEM encoded certificate chain and private key used to terminate TLS.
+/// Both files are watched and reloaded when they change on disk.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+impl TlsConfig {
+    #[must_use]
+    pub const fn new(cert_path: PathBuf, key_path: PathBuf) -> Self {
+        Self { cert_path, key_path }
+    }
+
+    pub(crate) fn load_acceptor(&self) -> Result<TlsAcceptor> {
+        let certs = CertificateDer::pem_file_iter(&self.cert_path)
+            .map_err(|err| format!("Unable to read certificate {}: {err}", self.cert_path.display()))?
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|err| format!("Unable to parse certificate {}: {err}", self.cert_path.display()))?;
+        if certs.is_empty() {
+            return Err(format!("No certificates found in {}", self.cert_path.display()).into());
+        }
+        let key = PrivateKeyDer::from_pem_file(&self.key_path)
+            .map_err(|err| format!("Unable to read private key {}: {err}", self.key_path.display()))?;
+        let config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+
+    // watch the parent directories rather than the files themselves so that atomic renames (certbot etc.) are seen
+    fn watch(&self, acceptor: Arc<RwLock<TlsAcceptor>>) -> Result<RecommendedWatcher> {
+        let config = self.clone();
+        let mut watcher = recommended_watcher(move |res: notify::Result<Event>| match res {
+            Ok(event) => {
+                if !(event.kind.is_create() || event.kind.is_modify()) || !config.is_affected_by(&event) {
+                    return;
+                }
+                match config.load_acceptor() {
+                    Ok(new_acceptor) => {
+                        if let Ok(mut acceptor) = acceptor.write() {
+                            *acceptor = new_acceptor;
+                            info!("Reloaded TLS certificate from {}", config.cert_path.display());
+                        }
+                    }
+                    Err(err) => warn!("Failed to reload TLS certificate, keeping previous one: {err}"),
+                }
+            }
+            Err(err) => error!("TLS certificate watcher error: {err}"),
+        })?;
+        for dir in [parent_dir(&self.cert_path), parent_dir(&self.key_path)] {
+            watcher.watch(dir, RecursiveMode::NonRecursive)?;
+        }
+        Ok(watcher)
+    }
+
+    fn is_affected_by(&self, event: &Event) -> bool {
+        event
+            .paths
+            .iter()
+            .any(|path| path.file_name() == self.cert_path.file_name() || path.file_name() == self.key_path.file_name())
+    }
+}
+
+fn parent_dir(path: &Path) -> &Path {
+    path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."))
+}
+
+/// Accepts TCP connections and performs the TLS handshake off the accept path,
+/// so that a slow client can not stall other connections. With `proxy_protocol`, the PROXY protocol header
+/// ahead of the handshake is read first.
+pub(crate) struct TlsListener {
+    handshakes: Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+    local_addr: SocketAddr,
+}
+
+impl TlsListener {
+    pub(crate) fn new(
+        listener: TcpListener,
+        config: &TlsConfig,
+        proxy_protocol: bool,
+        socket: SocketOptions,
+    ) -> Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let acceptor = Arc::new(RwLock::new(config.load_acceptor()?));
+        let watcher = config.watch(acceptor.clone())?;
+        let (tx, handshakes) = channel(PENDING_CONNECTIONS);
+        tokio::spawn(accept_loop(listener, acceptor, tx, watcher, proxy_protocol, socket));
+        Ok(Self { handshakes, local_addr })
+    }
+}
+
+async fn accept_loop(
+    listener: TcpListener,
+    acceptor: Arc<RwLock<TlsAcceptor>>,
+    tx: Sender<(TlsStream<TcpStream>, SocketAddr)>,
+    // kept alive for as long as we're accepting connections
+    _watcher: RecommendedWatcher,
+    proxy_protocol: bool,
+    socket: SocketOptions,
+) {
+    loop {
+        let (mut stream, mut addr) = match listener.accept().await {
+            Ok(ok) => ok,
+            Err(err) => {
+                // e.g. out of file descriptors, which a retry right away wouldn't fix
+                error!("Failed to accept connection: {err}");
+                sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        socket.apply(&stream);
+        let acceptor = match acceptor.read() {
+            Ok(acceptor) => acceptor.clone(),
+            Err(err) => {
+                error!("TLS acceptor lock poisoned: {err}");
+                return;
+            }
+        };
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            if proxy_protocol {
+                match timeout(HANDSHAKE_TIMEOUT, read_proxy_header(&mut stream, addr)).await {
+                    Ok(Ok(client)) => addr = client,
+                    Ok(Err(err)) => return info!("Refusing connection from {addr}: {err}"),
+                    Err(_) => return info!("PROXY header from {addr} timed out"),
+                }
+            }
+            match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => {
+                    let _unused = tx.send((stream, addr)).await;
+                }
+                Ok(Err(err)) => info!("TLS handshake with {addr} failed: {err}"),
+                Err(_) => info!("TLS handshake with {addr} timed out"),
+            }
+        });
+    }
+}
+
+impl Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        loop {
+            if let Some(conn) = self.handshakes.recv().await {
+                return conn;
+            }
+            // the accept loop only stops if the lock is poisoned, in which case there is nothing left to serve
+            error!("TLS accept loop stopped");
+            std::future::pending::<()>().await;
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+    use tokio_rustls::{
+        TlsConnector,
+        rustls::{ClientConfig, RootCertStore, pki_types::ServerName},
+    };
+    use wtransport::Identity;
+
+    use super::*;
+
+    // a new self-signed certificate for localhost, written to the paths of `config`
+    fn write_certificate(config: &TlsConfig) -> Result<CertificateDer<'static>> {
+        let identity = Identity::self_signed(["localhost"])?;
+        let cert = &identity.certificate_chain().as_slice()[0];
+        fs::write(&config.cert_path, cert.to_pem())?;
+        fs::write(&config.key_path, identity.private_key().to_secret_pem())?;
+        Ok(CertificateDer::from(cert.der().to_vec()))
+    }
+
+    // a handshake that only trusts `cert`, returning the certificate the server presented
+    async fn handshake(addr: SocketAddr, cert: &CertificateDer<'static>) -> Result<CertificateDer<'static>> {
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.clone())?;
+        let config = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let stream = TcpStream::connect(addr).await?;
+        let stream = TlsConnector::from(Arc::new(config)).connect(ServerName::try_from("localhost")?, stream).await?;
+        let presented = stream.get_ref().1.peer_certificates().and_then(<[_]>::first).ok_or("no certificate")?;
+        Ok(presented.clone().into_owned())
+    }
+
+    #[tokio::test]
+    async fn test_reloads_certificate() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let config = TlsConfig::new(temp_dir.path().join("cert.pem"), temp_dir.path().join("key.pem"));
+        let first = write_certificate(&config)?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let _listener = TlsListener::new(listener, &config, false, SocketOptions::default())?;
+        assert_eq!(handshake(addr, &first).await?, first);
+
+        // new handshakes get the new certificate once the watcher saw the files change
+        let second = write_certificate(&config)?;
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+        loop {
+            match handshake(addr, &second).await {
+                Ok(presented) => {
+                    assert_eq!(presented, second);
+                    break;
+                }
+                Err(err) if tokio::time::Instant::now() > deadline => return Err(err),
+                Err(_) => sleep(Duration::from_millis(50)).await,
+            }
+        }
+        assert!(handshake(addr, &first).await.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_acceptor_rejects_missing_and_invalid_files() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let cert_path = temp_dir.path().join("cert.pem");
+        let key_path = temp_dir.path().join("key.pem");
+        let config = TlsConfig::new(cert_path.clone(), key_path.clone());
+        assert!(config.load_acceptor().is_err());
+
+        fs::write(&cert_path, "not a certificate")?;
+        fs::write(&key_path, "not a key")?;
+        let err = config.load_acceptor().err().ok_or("expected invalid certificate to be rejected")?;
+        assert!(err.to_string().contains("No certificates found"));
+        Ok(())
+    }
+}
@@ -0,0 +1,100 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use axum::serve::Listener;
+use tokio::{
+    net::{UnixListener, UnixStream},
+    time::sleep,
+};
+use tracing::{error, warn};
+
+use crate::prelude::*;
+
+// clients on the socket are on the same host, so they count as 127.0.0.1 for the per-IP limits and the logs
+const PEER_ADDRESS: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+// The websocket endpoint and the other routes of the websocket port, on a Unix domain socket for sidecars.
+// The socket file is removed again once the listener is dropped.
+pub(crate) struct UnixSocketListener {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl UnixSocketListener {
+    pub(crate) fn bind(path: &Path) -> Result<Self> {
+        // left behind by a server that didn't shut down cleanly, nobody accepts connections on it anymore
+        let socket = fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket());
+        if socket && std::os::unix::net::UnixStream::connect(path).is_err() {
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)
+            .map_err(|err| format!("Unable to listen on the Unix socket {}: {err}", path.display()))?;
+        Ok(Self { listener, path: path.to_path_buf() })
+    }
+}
+
+impl Listener for UnixSocketListener {
+    type Io = UnixStream;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        loop {
+            match self.listener.accept().await {
+                Ok((stream, _)) => return (stream, PEER_ADDRESS),
+                Err(err) => {
+                    // e.g. out of file descriptors, which a retry right away wouldn't fix
+                    error!("Unable to accept a connection on {}: {err}", self.path.display());
+                    sleep(Duration::from_secs(1)).await;
+                }
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(PEER_ADDRESS)
+    }
+}
+
+impl Drop for UnixSocketListener {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_file(&self.path) {
+            warn!("Unable to remove the Unix socket {}: {err}", self.path.display());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_accept() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("orderbook.sock");
+        // a stale socket file is replaced, but not one that is in use or any other file
+        drop(std::os::unix::net::UnixListener::bind(&path)?);
+        let mut listener = UnixSocketListener::bind(&path)?;
+
+        let mut client = UnixStream::connect(&path).await?;
+        let (mut stream, address) = listener.accept().await;
+        assert_eq!(address, PEER_ADDRESS);
+        client.write_all(b"ping").await?;
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"ping");
+        assert!(UnixSocketListener::bind(&path).is_err());
+
+        drop(listener);
+        assert!(!path.exists());
+        fs::write(&path, "")?;
+        assert!(UnixSocketListener::bind(&path).is_err());
+        assert!(path.exists());
+        Ok(())
+    }
+}
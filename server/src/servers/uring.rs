@@ -0,0 +1,316 @@
+// the ring's sockets stay on its thread, and so do the futures using them
+#![allow(clippy::future_not_send)]
+
+use std::{
+    net::{Shutdown, SocketAddr},
+    os::fd::{AsRawFd, BorrowedFd},
+    pin::{Pin, pin},
+    sync::mpsc as std_mpsc,
+    task::{Context, Poll, ready},
+    thread,
+    time::Duration,
+};
+
+use axum::serve::Listener;
+use bytes::Bytes;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpListener,
+    sync::{mpsc, oneshot},
+    time::sleep,
+};
+use tokio_uring::net::{TcpListener as RingListener, TcpStream as RingStream};
+use tokio_util::{sync::PollSender, task::TaskTracker};
+use tracing::{error, info};
+
+use crate::{prelude::*, servers::socket::SocketOptions};
+
+// submission queue entries of the ring; a connection has a read and at most a write in flight
+const RING_ENTRIES: u32 = 4096;
+const PENDING_CONNECTIONS: usize = 1024;
+// the largest read of a connection, client messages are small
+const READ_BUFFER: usize = 4096;
+const PENDING_READS: usize = 4;
+// writes of a connection queued for its writer, and how many of them are written at once
+const PENDING_WRITES: usize = 64;
+const WRITE_BATCH: usize = 64;
+
+// Accepts connections, reads from them and writes to them through io_uring, on a thread of its own. Everything
+// else about a connection runs on the server's runtime, which the ring thread exchanges buffers with over channels.
+// The thread stops once the listener is dropped and its connections are closed.
+pub(crate) struct UringListener {
+    accepted: mpsc::Receiver<(UringStream, SocketAddr)>,
+    local_addr: SocketAddr,
+}
+
+impl UringListener {
+    pub(crate) fn new(listener: TcpListener, socket: SocketOptions) -> Result<Self> {
+        let listener = listener.into_std()?;
+        // the ring waits for the socket to be ready, rather than failing with `WouldBlock`
+        listener.set_nonblocking(false)?;
+        let local_addr = listener.local_addr()?;
+        let (tx, accepted) = mpsc::channel(PENDING_CONNECTIONS);
+        let (started_tx, started) = std_mpsc::sync_channel(1);
+        thread::Builder::new().name("io-uring".to_string()).spawn(move || {
+            let runtime = match tokio_uring::Runtime::new(tokio_uring::builder().entries(RING_ENTRIES)) {
+                Ok(runtime) => runtime,
+                Err(err) => {
+                    let _unused = started_tx.send(Err(err));
+                    return;
+                }
+            };
+            let _unused = started_tx.send(Ok(()));
+            runtime.block_on(accept_loop(RingListener::from_std(listener), tx, socket));
+        })?;
+        started.recv()?.map_err(|err| format!("Unable to set up io_uring: {err}"))?;
+        Ok(Self { accepted, local_addr })
+    }
+}
+
+impl Listener for UringListener {
+    type Io = UringStream;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        loop {
+            if let Some(conn) = self.accepted.recv().await {
+                return conn;
+            }
+            // the ring thread only stops once nobody accepts its connections anymore
+            error!("io_uring accept loop stopped");
+            std::future::pending::<()>().await;
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+async fn accept_loop(listener: RingListener, tx: mpsc::Sender<(UringStream, SocketAddr)>, socket: SocketOptions) {
+    let connections = TaskTracker::new();
+    loop {
+        let (stream, addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    // e.g. out of file descriptors, which a retry right away wouldn't fix
+                    error!("Unable to accept a connection through io_uring: {err}");
+                    sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            },
+            () = tx.closed() => break,
+        };
+        // the stream keeps its descriptor open while it is borrowed here
+        #[allow(unsafe_code)]
+        socket.apply(unsafe { BorrowedFd::borrow_raw(stream.as_raw_fd()) });
+        let (reads_tx, reads) = mpsc::channel(PENDING_READS);
+        let (writes, writes_rx) = mpsc::channel(PENDING_WRITES);
+        connections.spawn_local(connection(stream, reads_tx, writes_rx));
+        let stream = UringStream { reads, unread: Bytes::new(), writes: PollSender::new(writes), completion: None };
+        if tx.send((stream, addr)).await.is_err() {
+            break;
+        }
+    }
+    connections.close();
+    info!("Waiting for {} io_uring connection(s) to close", connections.len());
+    connections.wait().await;
+}
+
+// Reads until the client closes the connection and writes until the server does. The pending read is cancelled once
+// the server is done with the connection.
+async fn connection(stream: RingStream, reads: mpsc::Sender<io::Result<Bytes>>, writes: mpsc::Receiver<Write>) {
+    let mut writing = pin!(write_loop(&stream, writes));
+    tokio::select! {
+        () = read_loop(&stream, reads) => writing.await,
+        () = &mut writing => {}
+    }
+}
+
+async fn read_loop(stream: &RingStream, reads: mpsc::Sender<io::Result<Bytes>>) {
+    let mut buf = Vec::with_capacity(READ_BUFFER);
+    loop {
+        let (res, returned) = stream.read(buf).await;
+        buf = returned;
+        let read = match res {
+            Ok(0) => return,
+            Ok(_) => Ok(Bytes::copy_from_slice(&buf)),
+            Err(err) => Err(err),
+        };
+        buf.clear();
+        let failed = read.is_err();
+        if reads.send(read).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+enum Write {
+    Data(Vec<u8>),
+    // answered once everything queued before is written
+    Flush(oneshot::Sender<io::Result<()>>),
+    Shutdown(oneshot::Sender<io::Result<()>>),
+}
+
+// writes the buffers queued since the last write at once. Stops at the first error, which fails the next write
+async fn write_loop(stream: &RingStream, mut writes: mpsc::Receiver<Write>) {
+    let mut batch = Vec::with_capacity(WRITE_BATCH);
+    let mut buf = Vec::new();
+    while writes.recv_many(&mut batch, WRITE_BATCH).await > 0 {
+        for write in std::mem::take(&mut batch) {
+            let (done, res) = match write {
+                Write::Data(data) if buf.is_empty() => {
+                    buf = data;
+                    continue;
+                }
+                Write::Data(data) => {
+                    buf.extend_from_slice(&data);
+                    continue;
+                }
+                Write::Flush(done) => (done, write_all(stream, &mut buf).await),
+                Write::Shutdown(done) => {
+                    let res = write_all(stream, &mut buf).await;
+                    (done, res.and_then(|()| stream.shutdown(Shutdown::Write)))
+                }
+            };
+            let failed = res.is_err();
+            let _unused = done.send(res);
+            if failed {
+                return;
+            }
+        }
+        if write_all(stream, &mut buf).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn write_all(stream: &RingStream, buf: &mut Vec<u8>) -> io::Result<()> {
+    if buf.is_empty() {
+        return Ok(());
+    }
+    let (res, returned) = stream.write_all(std::mem::take(buf)).await;
+    *buf = returned;
+    buf.clear();
+    res
+}
+
+/// A connection accepted by the [`UringListener`]. Writes are queued for the ring thread and complete once they are
+/// queued; flushing waits for them to be written.
+pub(crate) struct UringStream {
+    reads: mpsc::Receiver<io::Result<Bytes>>,
+    // the rest of a read that didn't fit the caller's buffer
+    unread: Bytes,
+    writes: PollSender<Write>,
+    // the flush or shutdown being waited for
+    completion: Option<oneshot::Receiver<io::Result<()>>>,
+}
+
+impl UringStream {
+    fn poll_completion(
+        &mut self,
+        cx: &mut Context<'_>,
+        request: fn(oneshot::Sender<io::Result<()>>) -> Write,
+    ) -> Poll<io::Result<()>> {
+        if self.completion.is_none() {
+            ready!(self.writes.poll_reserve(cx)).map_err(|_| closed())?;
+            let (done, completion) = oneshot::channel();
+            self.writes.send_item(request(done)).map_err(|_| closed())?;
+            self.completion = Some(completion);
+        }
+        let Some(completion) = &mut self.completion else {
+            return Poll::Ready(Ok(()));
+        };
+        let res = ready!(Pin::new(completion).poll(cx)).unwrap_or_else(|_| Err(closed()));
+        self.completion = None;
+        Poll::Ready(res)
+    }
+}
+
+impl AsyncRead for UringStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if self.unread.is_empty() {
+            match ready!(self.reads.poll_recv(cx)) {
+                Some(Ok(read)) => self.unread = read,
+                Some(Err(err)) => return Poll::Ready(Err(err)),
+                // the client closed the connection
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+        let n = self.unread.len().min(buf.remaining());
+        buf.put_slice(&self.unread.split_to(n));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for UringStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        ready!(self.writes.poll_reserve(cx)).map_err(|_| closed())?;
+        self.writes.send_item(Write::Data(buf.to_vec())).map_err(|_| closed())?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    // one buffer for the ring, rather than one per slice
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        ready!(self.writes.poll_reserve(cx)).map_err(|_| closed())?;
+        let data = bufs.iter().flat_map(|buf| buf.iter().copied()).collect::<Vec<_>>();
+        let n = data.len();
+        self.writes.send_item(Write::Data(data)).map_err(|_| closed())?;
+        Poll::Ready(Ok(n))
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_completion(cx, Write::Flush)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_completion(cx, Write::Shutdown)
+    }
+}
+
+// the ring thread is done with the connection, after a failed write
+fn closed() -> io::Error {
+    io::Error::from(io::ErrorKind::BrokenPipe)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_accept() -> Result<()> {
+        let mut listener = UringListener::new(TcpListener::bind("127.0.0.1:0").await?, SocketOptions::new())?;
+        let mut client = TcpStream::connect(listener.local_addr()?).await?;
+        let (mut stream, address) = listener.accept().await;
+        assert_eq!(address, client.local_addr()?);
+
+        client.write_all(b"ping").await?;
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"ping");
+        stream.write_all(b"po").await?;
+        assert_eq!(stream.write_vectored(&[io::IoSlice::new(b"n"), io::IoSlice::new(b"g")]).await?, 2);
+        stream.shutdown().await?;
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await?;
+        assert_eq!(received, b"pong");
+
+        drop(client);
+        assert_eq!(stream.read(&mut buf).await?, 0);
+        Ok(())
+    }
+}
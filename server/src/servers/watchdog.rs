@@ -0,0 +1,148 @@
+use std::{fs, sync::Arc, time::Duration};
+
+use strum_macros::Display;
+use tokio::time::{Instant, sleep};
+use tracing::{info, warn};
+
+use crate::{
+    metrics::METRICS,
+    prelude::*,
+    servers::{priority::DeliveryLoad, registry::ConnectionRegistry, shutdown::Shutdown},
+};
+
+/// Sheds load in steps while the process uses too much memory or its event loop falls behind.
+///
+/// First the stream data of every connection is conflated, then new connections are rejected, then the slowest
+/// connections are evicted.
+/// Shedding goes one step further at every check a threshold is exceeded at, and one step back at every check
+/// none is. Both thresholds are off when not set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchdogConfig {
+    /// Resident memory of the process, in bytes. Only read on Linux.
+    pub max_rss_bytes: Option<u64>,
+    /// How late the runtime's timers fire.
+    pub max_lag: Option<Duration>,
+    pub interval: Duration,
+    /// At the last step, this share of the connections (in percent, the ones with the most messages queued first)
+    /// is evicted at every check. High priority connections are never evicted when shedding load by priority.
+    pub evict_percent: u8,
+}
+
+impl WatchdogConfig {
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.interval.is_zero() {
+            return Err("the watchdog interval has to be at least a second".into());
+        }
+        if self.evict_percent == 0 || self.evict_percent > 100 {
+            return Err("the watchdog has to evict 1 to 100 percent of the connections".into());
+        }
+        Ok(())
+    }
+
+    fn is_over(&self, usage: Usage) -> bool {
+        self.max_rss_bytes.zip(usage.rss_bytes).is_some_and(|(max, rss)| rss > max)
+            || self.max_lag.is_some_and(|max| usage.lag > max)
+    }
+}
+
+// how far the watchdog sheds load, each step including the ones before
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Display)]
+#[strum(serialize_all = "snake_case")]
+enum Step {
+    None,
+    Conflate,
+    Reject,
+    Evict,
+}
+
+impl Step {
+    const fn next(self, over: bool) -> Self {
+        match (self, over) {
+            (Self::None, true) | (Self::Reject, false) => Self::Conflate,
+            (Self::Conflate, true) | (Self::Evict, false) => Self::Reject,
+            (Self::Reject | Self::Evict, true) => Self::Evict,
+            (Self::None | Self::Conflate, false) => Self::None,
+        }
+    }
+}
+
+// what the watchdog measured at a check
+#[derive(Debug, Clone, Copy)]
+struct Usage {
+    rss_bytes: Option<u64>,
+    lag: Duration,
+}
+
+pub(crate) fn spawn_watchdog(config: WatchdogConfig, registry: Arc<ConnectionRegistry>, shutdown: Shutdown) {
+    info!("Watchdog shedding load over {:?} bytes of memory or {:?} of lag", config.max_rss_bytes, config.max_lag);
+    tokio::spawn(async move {
+        let load = DeliveryLoad::global();
+        let mut step = Step::None;
+        loop {
+            let start = Instant::now();
+            tokio::select! {
+                () = sleep(config.interval) => {}
+                () = shutdown.cancelled() => return,
+            }
+            let usage = Usage { rss_bytes: rss_bytes(), lag: start.elapsed().saturating_sub(config.interval) };
+            METRICS.set_resource_usage(usage.rss_bytes, usage.lag);
+            let next = step.next(config.is_over(usage));
+            if next != step {
+                let rss_bytes = usage.rss_bytes.unwrap_or_default();
+                if next > step {
+                    warn!("Shedding load ({next}) at {rss_bytes} bytes of memory and {:.0?} of lag", usage.lag);
+                } else {
+                    info!("Shedding less load ({next}) at {rss_bytes} bytes of memory and {:.0?} of lag", usage.lag);
+                }
+                step = next;
+                load.set_conflate_all(step >= Step::Conflate);
+                registry.set_overloaded(step >= Step::Reject);
+                METRICS.watchdog_step.set(step as i64);
+            }
+            if step == Step::Evict {
+                let evicted = registry.evict_slowest(config.evict_percent);
+                warn!("Evicted the {evicted} slowest connection(s) to shed load");
+            }
+        }
+    });
+}
+
+// the resident memory of the process, from `/proc`
+fn rss_bytes() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let kb = status.lines().find_map(|line| line.strip_prefix("VmRSS:"))?.trim().strip_suffix("kB")?;
+    kb.trim().parse::<u64>().ok().map(|kb| kb * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_steps() {
+        let config = WatchdogConfig {
+            max_rss_bytes: Some(1 << 30),
+            max_lag: Some(Duration::from_millis(100)),
+            interval: Duration::from_secs(1),
+            evict_percent: 10,
+        };
+        let at = |rss_bytes, lag_ms| config.is_over(Usage { rss_bytes, lag: Duration::from_millis(lag_ms) });
+        assert!(!at(Some(1 << 20), 10));
+        assert!(!at(None, 10));
+        assert!(at(Some(2 << 30), 10));
+        assert!(at(None, 200));
+
+        let mut step = Step::None;
+        let mut steps = Vec::new();
+        for over in [true, true, true, true, false, false, false, false] {
+            step = step.next(over);
+            steps.push(step);
+        }
+        let expected =
+            [Step::Conflate, Step::Reject, Step::Evict, Step::Evict, Step::Reject, Step::Conflate, Step::None];
+        assert_eq!(steps[..7], expected);
+        assert_eq!(steps[7], Step::None);
+        // on Linux
+        assert!(rss_bytes().is_none_or(|rss| rss > 0));
+    }
+}
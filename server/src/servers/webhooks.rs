@@ -0,0 +1,391 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+
+use alloy::hex;
+use reqwest::{Client, StatusCode};
+use ring::hmac;
+use serde::Serialize;
+use tokio::{
+    select,
+    sync::{
+        broadcast::error::RecvError,
+        mpsc::{self, Receiver, Sender, error::TrySendError},
+    },
+    time::sleep,
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    latency::now_ms,
+    listeners::order_book::{Broadcast, InternalMessage, L2SnapshotParams},
+    metrics::METRICS,
+    order_book::Px,
+    prelude::*,
+    servers::{shutdown::Shutdown, standby::Standby},
+    types::{MarketChange, MarketChangeKind, MarketStatus, StreamStatus},
+};
+
+// alerts queued for each URL; more are dropped while it is down
+const WEBHOOK_BUFFER: usize = 1000;
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(1);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const SIGNATURE_HEADER: &str = "x-signature-256";
+
+/// POSTs a JSON alert to every URL when a market's spread widens beyond a threshold, a book crosses or locks, the
+/// feed goes stale or a market is halted, and again when that is over.
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    pub urls: Vec<String>,
+    /// Sign every body with HMAC-SHA256, sent as `X-Signature-256: sha256=<hex>`. Unsigned when not set.
+    pub secret: Option<Vec<u8>>,
+    /// Alert when the spread of a market is wider than this many basis points. Off when not set.
+    pub spread_bps: Option<f64>,
+    /// Retries of a failed delivery, a second apart and then twice as long each time. Requests refused with a
+    /// client error other than 429 aren't retried.
+    pub retries: u32,
+}
+
+impl WebhookConfig {
+    #[must_use]
+    pub const fn new(urls: Vec<String>) -> Self {
+        Self { urls, secret: None, spread_bps: None, retries: 5 }
+    }
+}
+
+// the body of a webhook. `resolved` alerts say the condition of an earlier one is over
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "camelCase", rename_all_fields = "camelCase")]
+pub(crate) enum Alert {
+    SpreadWide { coin: String, time: u64, spread_bps: f64, threshold_bps: f64, resolved: bool },
+    // the best bid is at (locked) or above the best ask
+    BookCrossed { coin: String, time: u64, bid: Option<String>, ask: Option<String>, locked: bool, resolved: bool },
+    // the market is none for the primary one
+    FeedStale { market: Option<String>, time: u64, last_block: u64, lag_ms: Option<u64>, resolved: bool },
+    MarketHalted { coin: String, time: u64, resolved: bool },
+}
+
+// the conditions alerted on that aren't resolved yet
+#[derive(Default)]
+struct Alerts {
+    spread_bps: Option<f64>,
+    wide: HashSet<String>,
+    // whether each crossed book is only locked
+    crossed: HashMap<String, bool>,
+    halted: HashSet<String>,
+}
+
+impl Alerts {
+    fn on_message(&mut self, msg: &InternalMessage) -> Vec<Alert> {
+        match msg {
+            InternalMessage::Snapshot { l2_snapshots, time, .. } => {
+                let params = L2SnapshotParams::new(None, None, None);
+                let mut alerts = Vec::new();
+                for (coin, snapshots) in l2_snapshots.as_ref() {
+                    let Some(snapshot) = snapshots.get(&params) else {
+                        continue;
+                    };
+                    let [bids, asks] = snapshot.as_ref();
+                    let (bid, ask) = (bids.first().map(|level| level.px), asks.first().map(|level| level.px));
+                    alerts.extend(self.on_top_of_book(&coin.value(), *time, bid, ask));
+                }
+                alerts
+            }
+            InternalMessage::Status { status, stale_changed: true } => vec![on_status(status)],
+            InternalMessage::MarketChanges { changes } => self.on_market_changes(changes),
+            _ => Vec::new(),
+        }
+    }
+
+    fn on_top_of_book(&mut self, coin: &str, time: u64, bid: Option<Px>, ask: Option<Px>) -> Vec<Alert> {
+        let mut alerts = Vec::new();
+        let crossed = bid.zip(ask).filter(|(bid, ask)| bid >= ask).map(|(bid, ask)| bid == ask);
+        if self.crossed.get(coin).copied() != crossed {
+            let (bid_str, ask_str) = (bid.map(Px::to_str), ask.map(Px::to_str));
+            let alert = |locked, resolved| Alert::BookCrossed {
+                coin: coin.to_string(),
+                time,
+                bid: bid_str.clone(),
+                ask: ask_str.clone(),
+                locked,
+                resolved,
+            };
+            match crossed {
+                Some(locked) => {
+                    self.crossed.insert(coin.to_string(), locked);
+                    alerts.push(alert(locked, false));
+                }
+                None => alerts.extend(self.crossed.remove(coin).map(|locked| alert(locked, true))),
+            }
+        }
+        if let Some(threshold_bps) = self.spread_bps
+            && let Some(spread_bps) = spread_bps(bid, ask)
+        {
+            let wide = spread_bps > threshold_bps;
+            if wide != self.wide.contains(coin) {
+                if wide {
+                    self.wide.insert(coin.to_string());
+                } else {
+                    self.wide.remove(coin);
+                }
+                let coin = coin.to_string();
+                alerts.push(Alert::SpreadWide { coin, time, spread_bps, threshold_bps, resolved: !wide });
+            }
+        }
+        alerts
+    }
+
+    // a halt is a change of a listed market's status, so the markets halted at startup aren't alerted on
+    fn on_market_changes(&mut self, changes: &[MarketChange]) -> Vec<Alert> {
+        let time = now_ms();
+        let mut alerts = Vec::new();
+        for MarketChange { change, market } in changes {
+            let halted = *change == MarketChangeKind::Changed && market.status == MarketStatus::Halted;
+            if halted && self.halted.insert(market.coin.clone()) {
+                alerts.push(Alert::MarketHalted { coin: market.coin.clone(), time, resolved: false });
+            } else if !halted && self.halted.remove(&market.coin) {
+                alerts.push(Alert::MarketHalted { coin: market.coin.clone(), time, resolved: true });
+            }
+        }
+        alerts
+    }
+}
+
+fn on_status(status: &StreamStatus) -> Alert {
+    Alert::FeedStale {
+        market: status.market.clone(),
+        time: status.time,
+        last_block: status.last_block,
+        lag_ms: status.lag_ms,
+        resolved: !status.stale,
+    }
+}
+
+// of the mid price; crossed books have none
+#[allow(clippy::cast_precision_loss)]
+fn spread_bps(bid: Option<Px>, ask: Option<Px>) -> Option<f64> {
+    let (bid, ask) = bid.zip(ask).filter(|(bid, ask)| bid < ask)?;
+    let mid = f64::midpoint(bid.value() as f64, ask.value() as f64);
+    Some((ask.value() as f64 - bid.value() as f64) / mid * 10_000.0)
+}
+
+// `sha256=<hex>` of the body
+fn signature(key: &hmac::Key, body: &[u8]) -> String {
+    format!("sha256={}", hex::encode(hmac::sign(key, body)))
+}
+
+// runs until shutdown, with a task per URL that delivers its alerts in order. Alerts are still tracked on standby,
+// but only sent once promoted
+pub(crate) fn spawn_webhooks(
+    config: WebhookConfig,
+    internal_message_tx: &Broadcast,
+    standby: Standby,
+    shutdown: Shutdown,
+) -> Result<()> {
+    let client = Client::builder().timeout(REQUEST_TIMEOUT).build()?;
+    let key = config.secret.as_deref().map(|secret| hmac::Key::new(hmac::HMAC_SHA256, secret));
+    let urls = config
+        .urls
+        .iter()
+        .map(|url| {
+            let (tx, rx) = mpsc::channel(WEBHOOK_BUFFER);
+            let delivery =
+                Delivery { client: client.clone(), url: url.clone(), key: key.clone(), retries: config.retries };
+            tokio::spawn(delivery.run(rx));
+            (url.clone(), tx)
+        })
+        .collect::<Vec<(String, Sender<Vec<u8>>)>>();
+    let mut alerts = Alerts { spread_bps: config.spread_bps, ..Alerts::default() };
+    // every coin is watched
+    let mut rx = internal_message_tx.subscribe_all();
+    tokio::spawn(async move {
+        loop {
+            select! {
+                msg = rx.recv() => match msg {
+                    Ok(msg) => {
+                        for alert in alerts.on_message(&msg) {
+                            if !standby.is_on() {
+                                send(&urls, &alert);
+                            }
+                        }
+                    }
+                    Err(RecvError::Lagged(n)) => warn!("Webhooks fell behind, {n} messages not checked for alerts"),
+                    Err(RecvError::Closed) => return,
+                },
+                () = shutdown.cancelled() => return,
+            }
+        }
+    });
+    Ok(())
+}
+
+fn send(urls: &[(String, Sender<Vec<u8>>)], alert: &Alert) {
+    info!("Webhook alert: {alert:?}");
+    let body = match serde_json::to_vec(alert) {
+        Ok(body) => body,
+        Err(err) => {
+            error!("Unable to serialize webhook alert: {err}");
+            return;
+        }
+    };
+    for (url, tx) in urls {
+        match tx.try_send(body.clone()) {
+            Err(TrySendError::Full(_)) => {
+                warn!("Webhook {url} is behind, dropping an alert");
+                METRICS.webhook_deliveries.with_label_values(&["dropped"]).inc();
+            }
+            Ok(()) | Err(TrySendError::Closed(_)) => {}
+        }
+    }
+}
+
+struct Delivery {
+    client: Client,
+    url: String,
+    key: Option<hmac::Key>,
+    retries: u32,
+}
+
+impl Delivery {
+    async fn run(self, mut rx: Receiver<Vec<u8>>) {
+        while let Some(body) = rx.recv().await {
+            let mut delay = FIRST_RETRY_DELAY;
+            for attempt in 0..=self.retries {
+                let (res, retry) = match self.post(&body).await {
+                    Ok(status) if status.is_success() => (Ok(()), false),
+                    Ok(status) => (
+                        Err(format!("status {status}")),
+                        status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS,
+                    ),
+                    Err(err) => (Err(err.to_string()), true),
+                };
+                match res {
+                    Ok(()) => {
+                        METRICS.webhook_deliveries.with_label_values(&["delivered"]).inc();
+                        break;
+                    }
+                    Err(err) if retry && attempt < self.retries => {
+                        warn!("Webhook {} failed ({err}), retrying in {delay:?}", self.url);
+                        sleep(delay).await;
+                        delay *= 2;
+                    }
+                    Err(err) => {
+                        error!("Webhook {} failed ({err}), giving up on the alert", self.url);
+                        METRICS.webhook_deliveries.with_label_values(&["failed"]).inc();
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn post(&self, body: &[u8]) -> reqwest::Result<StatusCode> {
+        let mut request = self.client.post(&self.url).header("content-type", "application/json");
+        if let Some(key) = &self.key {
+            request = request.header(SIGNATURE_HEADER, signature(key, body));
+        }
+        Ok(request.body(body.to_vec()).send().await?.status())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    };
+
+    use axum::{Router, http::HeaderMap, routing::post};
+    use bytes::Bytes;
+    use tokio::{net::TcpListener, time::timeout};
+
+    use super::*;
+    use crate::types::{MarketInfo, MarketKind};
+
+    fn px(px: &str) -> Option<Px> {
+        Px::parse_from_str(px).ok()
+    }
+
+    fn change(change: MarketChangeKind, status: MarketStatus) -> MarketChange {
+        let market = MarketInfo {
+            coin: "BTC".to_string(),
+            kind: MarketKind::Perp,
+            base: "BTC".to_string(),
+            quote: "USDC".to_string(),
+            sz_decimals: 5,
+            lot_size: "0.00001".to_string(),
+            tick_size: "1".to_string(),
+            status,
+        };
+        MarketChange { change, market }
+    }
+
+    #[test]
+    fn test_alerts_fire_and_resolve_once() {
+        let mut alerts = Alerts { spread_bps: Some(50.0), ..Alerts::default() };
+        assert!(alerts.on_top_of_book("BTC", 1, px("100"), px("100.1")).is_empty());
+        // 1% wide
+        let fired = alerts.on_top_of_book("BTC", 2, px("99.5"), px("100.5"));
+        assert!(
+            matches!(fired.as_slice(), [Alert::SpreadWide { resolved: false, spread_bps, .. }] if *spread_bps == 100.0)
+        );
+        assert!(alerts.on_top_of_book("BTC", 3, px("99.5"), px("100.5")).is_empty());
+
+        let crossed = alerts.on_top_of_book("BTC", 4, px("100"), px("100"));
+        assert!(matches!(crossed.as_slice(), [Alert::BookCrossed { locked: true, resolved: false, .. }]));
+        let crossed = alerts.on_top_of_book("BTC", 5, px("101"), px("100"));
+        assert!(matches!(crossed.as_slice(), [Alert::BookCrossed { locked: false, resolved: false, .. }]));
+        let resolved = alerts.on_top_of_book("BTC", 6, px("100"), px("100.1"));
+        assert!(matches!(
+            resolved.as_slice(),
+            [Alert::BookCrossed { locked: false, resolved: true, .. }, Alert::SpreadWide { resolved: true, .. }]
+        ));
+
+        assert!(alerts.on_market_changes(&[change(MarketChangeKind::Added, MarketStatus::Halted)]).is_empty());
+        let halted = alerts.on_market_changes(&[change(MarketChangeKind::Changed, MarketStatus::Halted)]);
+        assert!(matches!(halted.as_slice(), [Alert::MarketHalted { resolved: false, .. }]));
+        let resumed = alerts.on_market_changes(&[change(MarketChangeKind::Changed, MarketStatus::Active)]);
+        assert!(matches!(resumed.as_slice(), [Alert::MarketHalted { resolved: true, .. }]));
+    }
+
+    #[test]
+    fn test_alert_body_and_signature() -> Result<()> {
+        let alert = Alert::MarketHalted { coin: "BTC".to_string(), time: 1, resolved: false };
+        let body = serde_json::to_vec(&alert)?;
+        assert_eq!(body, br#"{"event":"marketHalted","coin":"BTC","time":1,"resolved":false}"#);
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"secret");
+        let signed = signature(&key, &body);
+        assert!(hmac::verify(&key, &body, &hex::decode(signed.trim_start_matches("sha256="))?).is_ok());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_delivery_retries_and_signs() -> Result<()> {
+        let (tx, mut received) = mpsc::unbounded_channel();
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let app = Router::new().route(
+            "/hook",
+            post(async move |headers: HeaderMap, body: Bytes| {
+                // the first attempt fails
+                if attempts.fetch_add(1, Ordering::Relaxed) == 0 {
+                    return StatusCode::SERVICE_UNAVAILABLE;
+                }
+                let _unused = tx.send((headers.get(SIGNATURE_HEADER).cloned(), body));
+                StatusCode::OK
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/hook", listener.local_addr()?);
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"secret");
+        let (alerts, rx) = mpsc::channel(1);
+        tokio::spawn(Delivery { client: Client::new(), url, key: Some(key.clone()), retries: 1 }.run(rx));
+        alerts.send(b"{}".to_vec()).await?;
+        let (header, body) = timeout(Duration::from_secs(5), received.recv()).await?.ok_or("not delivered")?;
+        assert_eq!(body.as_ref(), b"{}");
+        assert_eq!(header.and_then(|header| header.to_str().ok().map(str::to_string)), Some(signature(&key, b"{}")));
+        Ok(())
+    }
+}
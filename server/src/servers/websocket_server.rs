@@ -1,299 +1,1838 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     env::home_dir,
-    sync::Arc,
+    net::SocketAddr,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+    time::Duration,
 };
 
 use axum::{
     Router,
-    http::StatusCode,
+    extract::{ConnectInfo, Query},
+    http::{
+        HeaderMap, HeaderValue, StatusCode,
+        header::{SEC_WEBSOCKET_EXTENSIONS, SEC_WEBSOCKET_PROTOCOL},
+    },
+    middleware::from_fn_with_state,
     response::{IntoResponse, Response},
     routing::get,
+    serve::ListenerExt,
 };
-use futures_util::{SinkExt, StreamExt};
-use log::{error, info};
+use bytes::Bytes;
+use futures_util::{SinkExt, future::OptionFuture};
+use serde::{Deserialize, Serialize};
 use tokio::{
     net::TcpListener,
     select,
-    sync::{
-        Mutex,
-        broadcast::{Sender, channel},
-    },
+    sync::{Mutex, Notify, broadcast::error::RecvError, watch},
+    task::JoinHandle,
+    time::{Instant, interval, timeout},
 };
-use yawc::{FrameView, OpCode, WebSocket};
+use tracing::{Instrument, error, field, info, info_span, warn};
+use yawc::{FrameView, OpCode, Options, close::CloseCode};
 
+#[cfg(feature = "chaos")]
+use crate::chaos;
+#[cfg(unix)]
+use crate::servers::unix_socket::UnixSocketListener;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use crate::servers::uring::UringListener;
+#[cfg(feature = "webtransport")]
+use crate::servers::webtransport::serve_webtransport;
+#[cfg(any(test, feature = "testing"))]
+use crate::testing::ScriptedSource;
 use crate::{
+    analytics::{Analytics, MarketAnalytics},
+    archive::start_archiver,
+    candles::{Candle, Candles},
+    journal::{Journal, ReplayFrom},
+    latency::{Stamped, Stamps, now_ms},
     listeners::order_book::{
-        InternalMessage, L2SnapshotParams, L2Snapshots, OrderBookListener, TimedSnapshots, hl_listen,
+        Broadcast, InactivityPolicy, InternalMessage, L2SnapshotParams, NodeSource, OrderBookListener, Receivers,
+        RelaySource, SnapshotStrategy, TimedSnapshots, UpstreamNode, fetch_market_info, listen_source, serve_relay,
     },
+    logging::{self, PIPELINE},
+    metrics::{METRICS, MeteredListener, serve_metrics},
     order_book::{Coin, Snapshot},
     prelude::*,
+    servers::{
+        admin::{AdminState, serve_admin},
+        auth::{AuthError, Authenticator, ConnectionPermit},
+        coalescing::{Coalescer, FrameOptions, IdentityFrames, SharedFrame, batch_deadline},
+        config::ServerConfig,
+        cors::{self, CorsConfig},
+        deflate::{DeflateConfig, accept_deflate},
+        election::spawn_election,
+        encoding::{Compression, Negotiated, Numbers, Subprotocol},
+        grpc::serve_grpc,
+        health::serve_health,
+        keepalive::{Keepalive, KeepaliveConfig},
+        limits::ConnectionCounter,
+        markets::{MarketConfig, Markets},
+        outbound::{Outbound, batch_payload},
+        ownership,
+        preferences::{PreferenceStore, Preferences},
+        priority::LoadShedding,
+        protocol::{ErrorCode, ProtocolError, Versioned, parse_request},
+        proxy::{ProxyListener, TrustedProxy, resolve_client},
+        publisher::spawn_publisher,
+        rate_limit::{BandwidthPolicy, ConnectionRateLimiter, PeerAddr, TokenBucket, limit_connections},
+        registry::{Command, ConnectionRegistry, ConnectionStats, Registration},
+        replay::Replays,
+        rest, rpc,
+        send_queue::{BackpressurePolicy, Outgoing, SendQueue, SlowConsumerAction, SlowConsumerReason},
+        sessions::{Session, SessionToken, Sessions},
+        settings::{RuntimeSettings, Settings},
+        shared_compression::{SharedCompressor, SocketReader, SocketWriter, split_socket},
+        shutdown::{AdminShutdown, Shutdown, Signals},
+        socket::{SocketOptions, activated_listener, bind_tcp_listener},
+        sse::{StreamQuery, stream_handler},
+        standby::Standby,
+        tenants::Tenant,
+        tls::{TlsConfig, TlsListener},
+        watchdog::spawn_watchdog,
+        webhooks::spawn_webhooks,
+        zstd_dictionary::ZstdDictionary,
+    },
+    signing::{Checkpoint, SIGNING_KEY_HEADER, Signer},
+    snapshot_store::start_snapshot_store,
     types::{
-        L2Book, L4Book, L4BookUpdates, L4Order, Trade,
+        Bbo, CHECKSUM_LEVELS, L2Book, L3Book, L3BookUpdates, L3Order, L4Book, L4BookUpdates, L4Order, TimeSync, Trade,
+        UserOrders, checksum,
         inner::InnerLevel,
-        node_data::{Batch, NodeDataFill, NodeDataOrderDiff, NodeDataOrderStatus},
+        node_data::{Batch, NodeDataFill},
         subscription::{ClientMessage, DEFAULT_LEVELS, ServerResponse, Subscription, SubscriptionManager},
     },
 };
 
-pub async fn run_websocket_server(
-    address: &str,
-    ignore_spot: bool,
-    compression_level: u32,
-    inactivity_exit_secs: u64,
-) -> Result<()> {
-    let (internal_message_tx, _) = channel::<Arc<InternalMessage>>(100);
+// how long a client without credentials in its upgrade request has to send its auth message
+const AUTH_TIMEOUT: Duration = Duration::from_secs(5);
+pub(crate) const BATCH_MS_RANGE: RangeInclusive<u64> = 1..=1000;
+const STATUS_INTERVAL: Duration = Duration::from_secs(1);
+// markets are rarely listed or delisted
+const MARKET_INFO_INTERVAL: Duration = Duration::from_mins(1);
+// how often lazy markets are checked for subscribers
+const LAZY_CHECK_INTERVAL: Duration = Duration::from_secs(10);
 
+pub async fn run_websocket_server(config: ServerConfig) -> Result<()> {
+    run_with_source(config, None).await
+}
+
+// `source` replaces the upstreams or relay feed of `config`
+#[allow(clippy::too_many_lines)]
+pub(crate) async fn run_with_source(config: ServerConfig, source: Option<Source>) -> Result<()> {
+    config.validate()?;
+    let connection_limiter = Arc::new(ConnectionRateLimiter::new(None));
+    let settings = Settings::new(&config, connection_limiter.clone());
+    settings.reload_on_sighup()?;
+    logging::toggle_on_sigusr1()?;
     // Central task: listen to messages and forward them for distribution
-    let home_dir = home_dir().ok_or("Could not find home directory")?;
-    let listener = {
+    let internal_message_tx = Broadcast::new(config.broadcast_shards);
+    let journal = config.journal.clone().map(Journal::create).transpose()?.map(Arc::new);
+    let signer = config.signing.as_ref().map(Signer::load).transpose()?.map(Arc::new);
+    let signing_key = signer.as_ref().map(|signer| HeaderValue::try_from(signer.public_key())).transpose()?;
+    let listener = new_listener(&config, internal_message_tx.clone(), journal.clone(), signer);
+    #[cfg(feature = "webtransport")]
+    let webtransport_port = config.webtransport_port;
+    let admin_address = config.admin_address();
+    let ServerConfig {
+        address,
+        dual_stack,
+        reuse_port,
+        unix_socket,
+        io_uring,
+        socket,
+        ignore_spot,
+        upstreams,
+        markets: market_configs,
+        snapshot_strategy,
+        lazy_idle_timeout,
+        shared_compression,
+        coalesce_connections,
+        deflate,
+        inactivity_exit_secs,
+        inactivity_policy,
+        tls,
+        proxy,
+        cors,
+        auth,
+        metrics_port,
+        grpc_port,
+        health_port,
+        drain_timeout,
+        mut shutdown_triggers,
+        standby,
+        promotion_triggers,
+        election,
+        backpressure,
+        send_queue_capacity,
+        message_ttls,
+        load_shedding,
+        watchdog,
+        keepalive,
+        publisher,
+        webhooks,
+        snapshot_store,
+        archive,
+        relay_port,
+        relay_upstream,
+        include_latency_metadata,
+        admin_auth,
+        zstd_dictionary,
+        session_grace,
+        preferences,
+        // the rest are runtime settings, read through `settings`, and those of the listener, read by `new_listener`
+        ..
+    } = config;
+    let zstd_dictionary =
+        zstd_dictionary.as_deref().map_or_else(ZstdDictionary::built_in, ZstdDictionary::from_file)?;
+    let auth = auth.map(|auth| Arc::new(Authenticator::new(auth)));
+    let preferences = Arc::new(PreferenceStore::load(preferences)?);
+    let shutdown = Shutdown::default();
+    let admin_shutdown = admin_address.map(|_| Arc::new(AdminShutdown::default()));
+    shutdown_triggers.push(Arc::new(Signals));
+    if let Some(admin_shutdown) = admin_shutdown.clone() {
+        shutdown_triggers.push(admin_shutdown);
+    }
+    shutdown.trigger_on(&shutdown_triggers)?;
+    let standby = Standby::new(standby || election.is_some());
+    standby.promote_on(&promotion_triggers)?;
+
+    // restored before the node's files are read, so that they are read from their start
+    let snapshot_saver = start_snapshot_store(&listener, snapshot_store, &shutdown).await;
+    let registry = Arc::new(ConnectionRegistry::default());
+    let source =
+        source.unwrap_or_else(|| relay_upstream.map_or(Source::Upstreams(upstreams, inactivity_policy), Source::Relay));
+    let mut listener_tasks =
+        vec![spawn_listener(listener.clone(), source, inactivity_exit_secs, registry.clone(), shutdown.clone())?];
+    let inactivity = (inactivity_exit_secs, inactivity_policy);
+    let lazy = (snapshot_strategy, lazy_idle_timeout);
+    let markets =
+        spawn_markets(&listener, market_configs, inactivity, lazy, &registry, &shutdown, &mut listener_tasks).await?;
+    spawn_status(listener.clone(), registry.clone(), shutdown.clone());
+    let election = election
+        .map(|election| spawn_election(election, listener.clone(), standby.clone(), shutdown.clone()))
+        .transpose()?;
+    if let Some(watchdog) = watchdog {
+        spawn_watchdog(watchdog, registry.clone(), shutdown.clone());
+    }
+
+    if let Some(publisher) = publisher {
+        spawn_publisher(publisher, &internal_message_tx, standby.clone(), shutdown.clone());
+    }
+    if let Some(webhooks) = webhooks {
+        spawn_webhooks(webhooks, &internal_message_tx, standby.clone(), shutdown.clone())?;
+    }
+    let archive_dir = archive.as_ref().map(|archive| archive.dir.clone());
+    let archiver = start_archiver(archive, &internal_message_tx, markets.clone(), shutdown.clone())?;
+    let context = ConnectionContext {
+        internal_message_tx: internal_message_tx.clone(),
+        markets: markets.clone(),
+        ignore_spot,
+        shutdown: shutdown.clone(),
+        backpressure,
+        send_queue_capacity,
+        message_ttls: Arc::new(message_ttls),
+        load_shedding,
+        auth: auth.clone(),
+        settings: settings.clone(),
+        registry: registry.clone(),
+        keepalive,
+        journal,
+        shared_compressor: shared_compression.then(Arc::default),
+        coalescer: coalesce_connections.then(Arc::default),
+        deflate: deflate.with_shared_compression(shared_compression),
+        zstd_dictionary: Arc::new(zstd_dictionary),
+        latency_metadata: include_latency_metadata,
+        connections: Arc::default(),
+        signing_key,
+        sessions: session_grace.map(|grace| Arc::new(Sessions::new(grace))),
+        preferences: preferences.clone(),
+        standby: standby.clone(),
+    };
+    #[cfg(feature = "webtransport")]
+    if let (Some(port), Some(tls)) = (webtransport_port, &tls) {
+        serve_webtransport(SocketAddr::new(address.ip(), port), tls, context.clone()).await?;
+    }
+    let app = app(context, connection_limiter, proxy.trusted, cors, archive_dir);
+
+    // the other servers listen on the same address
+    let bind = |port| bind_tcp_listener(SocketAddr::new(address.ip(), port), dual_stack, reuse_port, socket.backlog);
+    if let Some(port) = metrics_port {
+        serve_metrics(bind(port)?)?;
+    }
+    if let Some(port) = grpc_port {
         let internal_message_tx = internal_message_tx.clone();
-        OrderBookListener::new(Some(internal_message_tx), ignore_spot)
+        serve_grpc(bind(port)?, markets.clone(), internal_message_tx, auth, standby.clone(), shutdown.clone())?;
+    }
+    if let Some(port) = health_port {
+        serve_health(bind(port)?, markets, registry.clone(), standby.clone())?;
+    }
+    if let (Some(admin_address), Some(admin_shutdown)) = (admin_address, admin_shutdown) {
+        let admin_auth = admin_auth.map(|auth| Arc::new(Authenticator::new(auth)));
+        // for the viewer, whose page fills in its own host
+        let feed = format!("{}://{{host}}:{}/ws", if tls.is_some() { "wss" } else { "ws" }, address.port());
+        let admin = AdminState { settings, registry, preferences, shutdown: admin_shutdown, standby };
+        let listener = bind_tcp_listener(admin_address, dual_stack, reuse_port, socket.backlog)?;
+        serve_admin(listener, admin, admin_auth, &feed)?;
+    }
+    if let Some(port) = relay_port {
+        serve_relay(bind(port)?, listener, shutdown.clone())?;
+    }
+
+    // systemd's socket takes the place of the websocket port
+    let websocket_listener = activated_listener()?.map_or_else(|| bind(address.port()), Ok)?;
+    let served =
+        serve(websocket_listener, unix_socket, io_uring, socket, tls, proxy.protocol, app, shutdown.clone()).await;
+    if let Err(err) = served {
+        error!("Server fatal error: {err}");
+        std::process::exit(2);
+    }
+
+    shutdown.drain(drain_timeout).await;
+    let _unused = OptionFuture::from(snapshot_saver).await;
+    let _unused = OptionFuture::from(archiver).await;
+    let _unused = OptionFuture::from(election).await;
+    listener_result(listener_tasks).await
+}
+
+// the error of the listener whose failure shut the server down, if that is why it stopped
+async fn listener_result(tasks: Vec<JoinHandle<Result<()>>>) -> Result<()> {
+    for task in tasks {
+        if task.is_finished() {
+            return task.await?;
+        }
+    }
+    Ok(())
+}
+
+// stops accepting new connections once shutdown starts; open websockets are drained after
+#[allow(clippy::too_many_arguments)]
+async fn serve(
+    listener: TcpListener,
+    unix_socket: Option<PathBuf>,
+    io_uring: bool,
+    socket: SocketOptions,
+    tls: Option<TlsConfig>,
+    proxy_protocol: bool,
+    app: Router,
+    shutdown: Shutdown,
+) -> Result<()> {
+    let address = listener.local_addr()?;
+    let unix_socket = unix_socket.map(|path| serve_unix_socket(&path, app.clone(), shutdown.clone())).transpose()?;
+    let stop_accepting = async move { shutdown.cancelled().await };
+    if let Some(tls) = tls {
+        let listener = MeteredListener(TlsListener::new(listener, &tls, proxy_protocol, socket)?);
+        info!("WebSocket server running at wss://{address}");
+        axum::serve(listener, app.into_make_service_with_connect_info::<PeerAddr>())
+            .with_graceful_shutdown(stop_accepting)
+            .await?;
+    } else if proxy_protocol {
+        let listener = MeteredListener(ProxyListener::new(listener, socket)?);
+        info!("WebSocket server running at ws://{address}, behind a PROXY protocol load balancer");
+        axum::serve(listener, app.into_make_service_with_connect_info::<PeerAddr>())
+            .with_graceful_shutdown(stop_accepting)
+            .await?;
+    } else if io_uring {
+        // `ServerConfig::validate` refuses io_uring without the feature
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        {
+            let listener = MeteredListener(UringListener::new(listener, socket)?);
+            info!("WebSocket server running at ws://{address}, through io_uring");
+            axum::serve(listener, app.into_make_service_with_connect_info::<PeerAddr>())
+                .with_graceful_shutdown(stop_accepting)
+                .await?;
+        }
+    } else {
+        info!("WebSocket server running at ws://{address}");
+        let listener = MeteredListener(listener.tap_io(move |stream| socket.apply(&*stream)));
+        axum::serve(listener, app.into_make_service_with_connect_info::<PeerAddr>())
+            .with_graceful_shutdown(stop_accepting)
+            .await?;
+    }
+    if let Some(unix_socket) = unix_socket {
+        unix_socket.await??;
+    }
+    Ok(())
+}
+
+// the same routes for clients on this host, without TLS or the PROXY protocol
+#[cfg(unix)]
+fn serve_unix_socket(path: &Path, app: Router, shutdown: Shutdown) -> Result<JoinHandle<io::Result<()>>> {
+    let listener = MeteredListener(UnixSocketListener::bind(path)?);
+    info!("WebSocket server running at unix:{}", path.display());
+    let serve = axum::serve(listener, app.into_make_service_with_connect_info::<PeerAddr>())
+        .with_graceful_shutdown(async move { shutdown.cancelled().await });
+    Ok(tokio::spawn(serve.into_future()))
+}
+
+// `ServerConfig::validate` refuses a Unix socket elsewhere
+#[cfg(not(unix))]
+fn serve_unix_socket(path: &Path, _app: Router, _shutdown: Shutdown) -> Result<JoinHandle<io::Result<()>>> {
+    Err(format!("Unix domain sockets such as {} are only served on Unix", path.display()).into())
+}
+
+// the websocket endpoint, its server-sent events fallback and the REST routes, served on the same port
+fn app(
+    context: ConnectionContext,
+    connection_limiter: Arc<ConnectionRateLimiter>,
+    trusted_proxies: Vec<TrustedProxy>,
+    cors: Option<CorsConfig>,
+    archive_dir: Option<PathBuf>,
+) -> Router {
+    let rest = rest::routes(context.markets.clone(), context.auth.clone(), archive_dir);
+    let stream_context = context.clone();
+    let deflate = context.deflate;
+    let zstd_dictionary = context.zstd_dictionary.clone();
+    let app = Router::new()
+        .route(
+            "/ws",
+            get(
+                async move |ConnectInfo(peer): ConnectInfo<PeerAddr>,
+                            Query(options): Query<ConnectOptions>,
+                            headers: HeaderMap,
+                            ws_upgrade| {
+                    ws_handler(ws_upgrade, peer, options, &headers, context.clone())
+                },
+            )
+            .layer(from_fn_with_state(deflate, accept_deflate)),
+        )
+        .route(
+            "/stream",
+            get(
+                async move |ConnectInfo(peer): ConnectInfo<PeerAddr>,
+                            Query(query): Query<StreamQuery>,
+                            headers: HeaderMap| {
+                    stream_handler(peer, &query, &headers, stream_context.clone())
+                },
+            ),
+        )
+        .route("/zstd/dictionary", get(async move || zstd_dictionary.response()))
+        .merge(rest)
+        .layer(from_fn_with_state(connection_limiter, limit_connections))
+        // ahead of the rate limit, which counts the clients behind the proxies
+        .layer(from_fn_with_state(Arc::from(trusted_proxies), resolve_client));
+    // outermost, so that preflight requests and refused origins never reach the rate limit
+    match cors {
+        Some(cors) => app.layer(from_fn_with_state(Arc::new(cors), cors::cors)),
+        None => app,
+    }
+}
+
+fn new_listener(
+    config: &ServerConfig,
+    internal_message_tx: Broadcast,
+    journal: Option<Arc<Journal>>,
+    signer: Option<Arc<Signer>>,
+) -> Arc<Mutex<OrderBookListener>> {
+    let mut listener = OrderBookListener::new(Some(internal_message_tx), config.ignore_spot);
+    listener.set_crossed_books(config.crossed_books);
+    listener.set_audit(config.audit);
+    if config.relay_port.is_some() {
+        listener.enable_relay();
+    }
+    if let Some(journal) = journal {
+        listener.set_journal(journal);
+    }
+    if let Some(window) = config.resume_window {
+        listener.set_catch_up(window);
+    }
+    if let Some(candles) = config.candles.clone() {
+        listener.set_candles(Candles::new(candles));
+    }
+    if let Some(analytics) = config.analytics.clone() {
+        listener.set_analytics(Analytics::new(analytics));
+    }
+    if let (Some(signer), Some(signing)) = (signer, &config.signing) {
+        listener.set_signer(signer, signing.checkpoint_interval);
+    }
+    Arc::new(Mutex::new(listener))
+}
+
+// where the listener gets its events from
+pub(crate) enum Source {
+    Upstreams(Vec<UpstreamNode>, InactivityPolicy),
+    // the address of an ingest instance's relay feed
+    Relay(String),
+    #[cfg(any(test, feature = "testing"))]
+    Scripted(ScriptedSource),
+}
+
+// a listener failure shuts the server down; its error is returned once the connections are drained. The clients in
+// `registry` get fresh snapshots when the book was replaced, after a gap in the events or a reconnect to the relay
+fn spawn_listener(
+    listener: Arc<Mutex<OrderBookListener>>,
+    source: Source,
+    inactivity_exit_secs: u64,
+    registry: Arc<ConnectionRegistry>,
+    shutdown: Shutdown,
+) -> Result<JoinHandle<Result<()>>> {
+    let source = match source {
+        Source::Upstreams(upstreams, policy) if upstreams.is_empty() => {
+            Source::Upstreams(vec![UpstreamNode::new(home_dir().ok_or("Could not find home directory")?)], policy)
+        }
+        source => source,
     };
-    let listener = Arc::new(Mutex::new(listener));
-    {
-        let listener = listener.clone();
-        tokio::spawn(async move {
-            if let Err(err) = hl_listen(listener, home_dir, inactivity_exit_secs).await {
-                error!("Listener fatal error: {err}");
-                std::process::exit(1);
+    if let Source::Upstreams(upstreams, _) = &source {
+        spawn_market_info(listener.clone(), upstreams.clone(), shutdown.clone());
+    }
+    Ok(tokio::spawn(async move {
+        let res = listen(listener, source, inactivity_exit_secs, registry).await;
+        if let Err(err) = &res {
+            error!("Listener fatal error: {err}");
+            shutdown.trigger(CloseCode::Restart, "order book stream stopped");
+        }
+        res
+    }))
+}
+
+async fn listen(
+    listener: Arc<Mutex<OrderBookListener>>,
+    source: Source,
+    inactivity_exit_secs: u64,
+    registry: Arc<ConnectionRegistry>,
+) -> Result<()> {
+    let resynced = move || {
+        registry.resnapshot();
+    };
+    let inactivity_timeout = Duration::from_secs(inactivity_exit_secs);
+    match source {
+        Source::Upstreams(upstreams, policy) => {
+            let source = NodeSource::new(listener.clone(), upstreams, inactivity_timeout, policy).await?;
+            listen_source(listener, source, resynced).await
+        }
+        Source::Relay(address) => {
+            listen_source(listener, RelaySource::new(address, inactivity_timeout), resynced).await
+        }
+        #[cfg(any(test, feature = "testing"))]
+        Source::Scripted(source) => listen_source(listener, source, resynced).await,
+    }
+}
+
+// the listener of a lazy market only runs from the first subscription to the market until no connection
+// subscribed to it for `idle_timeout`, and its books are dropped in between. Its metadata is fetched all along, for
+// the coins clients may subscribe to
+fn spawn_lazy_market(
+    listener: Arc<Mutex<OrderBookListener>>,
+    wake: Arc<Notify>,
+    (name, upstreams, policy): (String, Vec<UpstreamNode>, InactivityPolicy),
+    (inactivity_exit_secs, idle_timeout): (u64, Duration),
+    registry: Arc<ConnectionRegistry>,
+    shutdown: Shutdown,
+) -> JoinHandle<Result<()>> {
+    spawn_market_info(listener.clone(), upstreams.clone(), shutdown.clone());
+    tokio::spawn(async move {
+        let prefix = format!("{name}:");
+        loop {
+            wake.notified().await;
+            info!("Loading the books of market {name} for its first subscriber");
+            listener.lock().await.load();
+            let source = Source::Upstreams(upstreams.clone(), policy);
+            let listening = listen(listener.clone(), source, inactivity_exit_secs, registry.clone());
+            tokio::pin!(listening);
+            let mut ticker = interval(LAZY_CHECK_INTERVAL);
+            loop {
+                select! {
+                    res = &mut listening => {
+                        if let Err(err) = &res {
+                            error!("Listener fatal error: {err}");
+                            shutdown.trigger(CloseCode::Restart, "order book stream stopped");
+                        }
+                        return res;
+                    }
+                    _ = ticker.tick() => {
+                        if unload_if_idle(&listener, &registry, &prefix, idle_timeout).await {
+                            info!("Dropped the books of market {name}, without subscribers for {idle_timeout:?}");
+                            break;
+                        }
+                    }
+                }
             }
-        });
+        }
+    })
+}
+
+// drops the books of the market if no connection subscribed to one of its coins for `idle_timeout`. Under the
+// same lock as the check, so that a subscription in between wakes the loader again
+async fn unload_if_idle(
+    listener: &Mutex<OrderBookListener>,
+    registry: &ConnectionRegistry,
+    prefix: &str,
+    idle_timeout: Duration,
+) -> bool {
+    let watched = registry
+        .clients()
+        .await
+        .iter()
+        .flat_map(|client| &client.subscriptions)
+        .any(|subscription| subscription.coin().is_some_and(|coin| coin.starts_with(prefix)));
+    let mut listener = listener.lock().await;
+    if watched {
+        listener.watch();
+    }
+    let idle = listener.idle_for() >= idle_timeout;
+    if idle {
+        listener.unload();
     }
+    idle
+}
+
+// every further market has a listener of its own, broadcasting on the same channel, and a status of its own. Lazy
+// markets load their books once they have subscribers
+async fn spawn_markets(
+    primary: &Arc<Mutex<OrderBookListener>>,
+    configs: Vec<MarketConfig>,
+    (inactivity_exit_secs, policy): (u64, InactivityPolicy),
+    (snapshot_strategy, idle_timeout): (SnapshotStrategy, Duration),
+    registry: &Arc<ConnectionRegistry>,
+    shutdown: &Shutdown,
+    tasks: &mut Vec<JoinHandle<Result<()>>>,
+) -> Result<Markets> {
+    let mut markets = Markets::new(primary.clone());
+    for MarketConfig { name, upstreams, crossed_books, snapshot_strategy: own } in configs {
+        let mut listener = primary.lock().await.for_market(&name);
+        if let Some(policy) = crossed_books {
+            listener.set_crossed_books(policy);
+        }
+        let wake = (own.unwrap_or(snapshot_strategy) == SnapshotStrategy::Lazy).then(|| listener.set_lazy());
+        let listener = Arc::new(Mutex::new(listener));
+        let task = if let Some(wake) = wake {
+            let (market, timeouts) = ((name.clone(), upstreams, policy), (inactivity_exit_secs, idle_timeout));
+            spawn_lazy_market(listener.clone(), wake, market, timeouts, registry.clone(), shutdown.clone())
+        } else {
+            let source = Source::Upstreams(upstreams, policy);
+            spawn_listener(listener.clone(), source, inactivity_exit_secs, registry.clone(), shutdown.clone())?
+        };
+        tasks.push(task);
+        spawn_status(listener.clone(), registry.clone(), shutdown.clone());
+        markets.add(name, listener);
+    }
+    Ok(markets)
+}
 
-    let websocket_opts =
-        yawc::Options::default().with_compression_level(yawc::CompressionLevel::new(compression_level));
-    let app = Router::new().route(
-        "/ws",
-        get({
-            let internal_message_tx = internal_message_tx.clone();
-            async move |ws_upgrade| {
-                ws_handler(ws_upgrade, internal_message_tx.clone(), listener.clone(), ignore_spot, websocket_opts)
+// fetches the metadata of the market from the first node that answers, once a minute
+fn spawn_market_info(listener: Arc<Mutex<OrderBookListener>>, upstreams: Vec<UpstreamNode>, shutdown: Shutdown) {
+    tokio::spawn(async move {
+        let mut ticker = interval(MARKET_INFO_INTERVAL);
+        loop {
+            select! {
+                _ = ticker.tick() => {}
+                () = shutdown.cancelled() => return,
             }
-        }),
-    );
+            let mut errors = Vec::new();
+            for upstream in &upstreams {
+                match fetch_market_info(upstream).await {
+                    Ok(markets) => {
+                        listener.lock().await.set_market_info(markets);
+                        errors.clear();
+                        break;
+                    }
+                    Err(err) => errors.push(format!("{}: {err}", upstream.info_url)),
+                }
+            }
+            if !errors.is_empty() {
+                warn!("Unable to fetch the market metadata: {}", errors.join(", "));
+            }
+        }
+    });
+}
 
-    let listener = TcpListener::bind(address).await?;
-    info!("WebSocket server running at ws://{address}");
+// sends the health of the feed every second
+fn spawn_status(listener: Arc<Mutex<OrderBookListener>>, registry: Arc<ConnectionRegistry>, shutdown: Shutdown) {
+    tokio::spawn(async move {
+        let mut ticker = interval(STATUS_INTERVAL);
+        loop {
+            select! {
+                _ = ticker.tick() => listener.lock().await.send_status(registry.is_maintenance()),
+                () = shutdown.cancelled() => return,
+            }
+        }
+    });
+}
 
-    if let Err(err) = axum::serve(listener, app.into_make_service()).await {
-        error!("Server fatal error: {err}");
-        std::process::exit(2);
+// everything a connection needs from the server, cloned into every connection
+#[derive(Clone)]
+pub(crate) struct ConnectionContext {
+    pub(crate) internal_message_tx: Broadcast,
+    pub(crate) markets: Markets,
+    pub(crate) ignore_spot: bool,
+    pub(crate) shutdown: Shutdown,
+    pub(crate) backpressure: BackpressurePolicy,
+    pub(crate) send_queue_capacity: usize,
+    pub(crate) message_ttls: Arc<BTreeMap<String, Duration>>,
+    pub(crate) load_shedding: Option<LoadShedding>,
+    pub(crate) auth: Option<Arc<Authenticator>>,
+    pub(crate) settings: Settings,
+    pub(crate) registry: Arc<ConnectionRegistry>,
+    pub(crate) keepalive: KeepaliveConfig,
+    pub(crate) journal: Option<Arc<Journal>>,
+    // compresses messages once for the connections that accept compression without context takeover
+    pub(crate) shared_compressor: Option<Arc<SharedCompressor>>,
+    // shares the frames of the connections of an identity, with `coalesce_connections`
+    pub(crate) coalescer: Option<Arc<Coalescer>>,
+    pub(crate) deflate: DeflateConfig,
+    // for the connections that negotiated zstd
+    pub(crate) zstd_dictionary: Arc<ZstdDictionary>,
+    // include the latency metadata of stream data in the messages
+    pub(crate) latency_metadata: bool,
+    pub(crate) connections: Arc<ConnectionCounter>,
+    // the public key of the signatures, sent in the handshake
+    pub(crate) signing_key: Option<HeaderValue>,
+    // of dropped connections, for clients reconnecting with their token
+    pub(crate) sessions: Option<Arc<Sessions>>,
+    // the defaults of the connections of each API key
+    pub(crate) preferences: Arc<PreferenceStore>,
+    // clients are refused until it is promoted
+    pub(crate) standby: Standby,
+}
+
+// options of a single connection, given in the query string of the upgrade request (e.g. `/ws?batchMs=5`)
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ConnectOptions {
+    // send the messages of this many milliseconds in one frame, as an array
+    batch_ms: Option<u64>,
+    // how prices and sizes are written, as strings if not set
+    numbers: Option<Numbers>,
+    // the token of a dropped connection whose session this one restores
+    session: Option<String>,
+}
+
+// how messages are put into frames for a connection
+#[derive(Clone)]
+struct Framing {
+    subprotocol: Subprotocol,
+    batch_window: Option<Duration>,
+    numbers: Numbers,
+    latency_metadata: bool,
+    // compresses every frame, if the connection negotiated zstd
+    zstd: Option<Arc<ZstdDictionary>>,
+    compression: Compression,
+}
+
+impl Framing {
+    fn negotiated(&self) -> Negotiated {
+        Negotiated {
+            subprotocol: self.subprotocol.as_str().to_string(),
+            compression: self.compression,
+            numbers: self.numbers,
+            batch_ms: self.batch_window.and_then(|window| u64::try_from(window.as_millis()).ok()),
+        }
     }
 
-    Ok(())
+    fn encode(&self, msgs: &[(Arc<Outbound>, Option<Stamps>)], send_time: u64) -> Result<FrameView> {
+        let Subprotocol { encoding, version, .. } = self.subprotocol;
+        if self.latency_metadata {
+            let msgs =
+                msgs.iter().map(|(msg, stamps)| Stamped::new(Versioned::new(msg.msg(), version), *stamps, send_time));
+            if self.numbers == Numbers::String {
+                return self.encode_all(&msgs.collect::<Vec<_>>());
+            }
+            let msgs = msgs.map(|msg| {
+                let mut msg = serde_json::to_value(msg)?;
+                self.numbers.convert(&mut msg)?;
+                Ok(msg)
+            });
+            return self.encode_all(&msgs.collect::<Result<Vec<_>>>()?);
+        }
+        // the messages are serialized once for all connections, a batch only copies them
+        let payload = match (&self.zstd, self.batch_window, msgs) {
+            // and so is a message in a frame of its own compressed
+            (Some(dictionary), None, [(msg, _)]) if self.numbers == Numbers::String => {
+                return Ok(encoding.frame(msg.zstd(version, dictionary)?));
+            }
+            (_, None, [(msg, _)]) => msg.payload(encoding, version, self.numbers)?,
+            _ => {
+                let payloads = msgs
+                    .iter()
+                    .map(|(msg, _)| msg.payload(encoding, version, self.numbers))
+                    .collect::<Result<Vec<_>>>()?;
+                batch_payload(encoding, &payloads)
+            }
+        };
+        self.frame(payload)
+    }
+
+    // a batched frame is always an array, even of a single message
+    fn encode_all<T: Serialize>(&self, msgs: &[T]) -> Result<FrameView> {
+        let Subprotocol { encoding, .. } = self.subprotocol;
+        let payload = match (self.batch_window, msgs) {
+            (None, [msg]) => encoding.encode(msg)?.payload,
+            _ => batch_payload(
+                encoding,
+                &msgs.iter().map(|msg| Ok(encoding.encode(msg)?.payload)).collect::<Result<Vec<_>>>()?,
+            ),
+        };
+        self.frame(payload)
+    }
+
+    // a frame of a payload serialized before
+    fn frame(&self, payload: Bytes) -> Result<FrameView> {
+        let Subprotocol { encoding, .. } = self.subprotocol;
+        match &self.zstd {
+            Some(dictionary) => Ok(encoding.frame(dictionary.compress(&payload)?)),
+            None => Ok(encoding.frame(payload)),
+        }
+    }
+
+    // how an identity's connections build frames: those of the same messages and options are the same
+    // the frame, built once for the identity's connections with the same subscriptions when they share `frames`,
+    // along with the frame they share
+    fn encode_shared(
+        &self,
+        frames: Option<&IdentityFrames>,
+        msgs: &[(Arc<Outbound>, Option<Stamps>)],
+        send_time: u64,
+    ) -> (Result<FrameView>, Option<Arc<SharedFrame>>) {
+        // frames with the time they are sent in them differ between connections
+        let Some(frames) = frames.filter(|_| !self.latency_metadata && self.shared(msgs).is_none()) else {
+            return (self.encode(msgs, send_time), None);
+        };
+        let options =
+            FrameOptions { subprotocol: self.subprotocol, numbers: self.numbers, batched: self.batch_window.is_some() };
+        let frame = frames.frame(options, &msgs.iter().map(|(msg, _)| msg.clone()).collect::<Vec<_>>());
+        (frame.get_or_build(|| self.encode(msgs, send_time)), Some(frame))
+    }
+
+    // the message of a frame that holds nothing but it, which other connections send the same
+    fn shared<'a>(&self, msgs: &'a [(Arc<Outbound>, Option<Stamps>)]) -> Option<&'a Arc<Outbound>> {
+        match (self.latency_metadata, self.batch_window, msgs) {
+            (false, None, [(msg, _)]) if self.numbers == Numbers::String => Some(msg),
+            _ => None,
+        }
+    }
 }
 
 fn ws_handler(
     incoming: yawc::IncomingUpgrade,
-    internal_message_tx: Sender<Arc<InternalMessage>>,
-    listener: Arc<Mutex<OrderBookListener>>,
-    ignore_spot: bool,
-    websocket_opts: yawc::Options,
+    PeerAddr(address, wire): PeerAddr,
+    options: ConnectOptions,
+    headers: &HeaderMap,
+    context: ConnectionContext,
 ) -> Response {
-    let (resp, fut) = match incoming.upgrade(websocket_opts) {
+    if context.registry.is_maintenance() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "Server in maintenance, try again later").into_response();
+    }
+    if context.standby.is_on() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "Server on standby, try again later").into_response();
+    }
+    if context.registry.is_overloaded() {
+        METRICS.limit_rejections.with_label_values(&["overload"]).inc();
+        return (StatusCode::SERVICE_UNAVAILABLE, "Server overloaded, try again later").into_response();
+    }
+    if options.batch_ms.is_some_and(|batch_ms| !BATCH_MS_RANGE.contains(&batch_ms)) {
+        return (StatusCode::BAD_REQUEST, "batchMs must be between 1 and 1000").into_response();
+    }
+    let subprotocol = match Subprotocol::negotiate(headers) {
+        Ok(subprotocol) => subprotocol,
+        Err(err) => {
+            info!("Rejecting websocket upgrade: {err}");
+            return (StatusCode::BAD_REQUEST, err.to_string()).into_response();
+        }
+    };
+    let settings = context.settings.current();
+    // refused once upgraded, so that browsers see the close code
+    let slot = context.connections.admit(address.ip(), &settings.connection_limits);
+    // clients without credentials in the request get to authenticate with their first message instead
+    let permit = match (&context.auth, Authenticator::credential(headers)) {
+        (Some(auth), Some(credential)) => match auth.admit(credential) {
+            Ok(permit) => Some(permit),
+            Err(err) => {
+                info!("Rejecting websocket upgrade: {err}");
+                let status = if err.is_limit() { StatusCode::TOO_MANY_REQUESTS } else { StatusCode::UNAUTHORIZED };
+                return (status, err.to_string()).into_response();
+            }
+        },
+        _ => None,
+    };
+    // the format of the dropped connection applies unless the request sets another. Its subscriptions are only
+    // restored for the same API key, which clients authenticating with their first message have yet to show
+    let session = options.session.zip(context.sessions.as_ref()).and_then(|(token, sessions)| sessions.take(&token));
+    // then the preferences of the API key, if it is in the request
+    let preferences = context.preferences.for_permit(permit.as_ref());
+    let batch_ms =
+        options.batch_ms.or_else(|| session.as_ref().and_then(|session| session.batch_ms)).or(preferences.batch_ms);
+    let numbers = options
+        .numbers
+        .or_else(|| session.as_ref().map(|session| session.numbers))
+        .or(preferences.numbers)
+        .unwrap_or_default();
+    let batch_window = batch_ms.map(Duration::from_millis);
+    // a changed compression level applies from the next connection on
+    let level = settings.compression_level;
+    // zstd frames aren't compressed again
+    let options = if subprotocol.is_some_and(|subprotocol| subprotocol.zstd) {
+        Options::default()
+    } else {
+        context.deflate.options(level)
+    };
+    let (mut resp, fut) = match incoming.upgrade(options) {
         Ok(ok) => ok,
         Err(err) => {
             error!("failed to start websocket upgrade: {err}");
             return StatusCode::BAD_REQUEST.into_response();
         }
     };
-    tokio::spawn(async move {
-        let ws = match fut.await {
+    // only echo a subprotocol if the client asked for one
+    if let Some(subprotocol) = subprotocol {
+        resp.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, subprotocol.name());
+    }
+    if let Some(key) = &context.signing_key {
+        resp.headers_mut().insert(SIGNING_KEY_HEADER, key.clone());
+    }
+    let subprotocol = subprotocol.unwrap_or_default();
+    // clients that didn't ask for compression get uncompressed frames
+    let deflate = resp.headers().contains_key(SEC_WEBSOCKET_EXTENSIONS);
+    let shared = context.shared_compressor.clone().filter(|_| deflate).map(|compressor| (compressor, level));
+    // `client` is filled in once the connection is registered
+    let span = info_span!("connection", client = field::Empty, remote_addr = %address);
+    let shutdown = context.shutdown.clone();
+    let connection = async move {
+        let mut ws = match fut.await {
             Ok(ok) => ok,
             Err(err) => {
-                log::error!("failed to upgrade websocket connection: {err}");
+                tracing::error!("failed to upgrade websocket connection: {err}");
+                return;
+            }
+        };
+        let slot = match slot {
+            Ok(slot) => slot,
+            Err(err) => {
+                info!("Closing websocket connection: {err}");
+                let _unused = ws.send(FrameView::close(err.close_code(), err.to_string())).await;
                 return;
             }
         };
 
-        handle_socket(ws, internal_message_tx, listener, ignore_spot).await;
-    });
+        METRICS.connections_total.inc();
+        METRICS.connections.inc();
+        let compression = Compression::negotiated(subprotocol, deflate, shared.is_some());
+        let (sink, stream) = split_socket(ws, shared);
+        let zstd = subprotocol.zstd.then(|| context.zstd_dictionary.clone());
+        let latency_metadata = context.latency_metadata;
+        let framing = Framing { subprotocol, batch_window, numbers, latency_metadata, zstd, compression };
+        let stats = Arc::new(ConnectionStats::new(wire));
+        handle_socket(sink, stream, address, framing, permit, session, stats, context).await;
+        METRICS.connections.dec();
+        drop(slot);
+    };
+    shutdown.spawn_connection(connection.instrument(span));
 
     resp.into_response()
 }
 
+#[allow(clippy::too_many_lines, clippy::too_many_arguments)]
 async fn handle_socket(
-    mut socket: WebSocket,
-    internal_message_tx: Sender<Arc<InternalMessage>>,
-    listener: Arc<Mutex<OrderBookListener>>,
-    ignore_spot: bool,
+    sink: SocketWriter,
+    mut stream: SocketReader,
+    address: SocketAddr,
+    framing: Framing,
+    permit: Option<ConnectionPermit>,
+    restored: Option<Session>,
+    stats: Arc<ConnectionStats>,
+    context: ConnectionContext,
 ) {
-    let mut internal_message_rx = internal_message_tx.subscribe();
-    let is_ready = listener.lock().await.is_ready();
+    let ConnectionContext {
+        internal_message_tx,
+        markets,
+        ignore_spot,
+        shutdown,
+        backpressure,
+        send_queue_capacity,
+        message_ttls,
+        load_shedding,
+        auth,
+        settings,
+        registry,
+        keepalive,
+        journal,
+        coalescer,
+        sessions,
+        preferences,
+        // the rest is for the upgrade
+        ..
+    } = context;
+    let queue = Arc::new(SendQueue::new(backpressure, send_queue_capacity).with_ttls(message_ttls));
+    if sessions.is_some() {
+        queue.track_l4_seqs();
+    }
+    // kept for the session, and reported by the connection's stats
+    let negotiated = framing.negotiated();
+    let format = (negotiated.batch_ms, negotiated.numbers);
+    // the frames shared with the identity's other connections, once it is authenticated
+    let coalesced = Arc::new(OnceLock::new());
+    // and the tenant's rate limits; both are set before anything is queued for the client
+    let tenant = Arc::new(OnceLock::new());
+    let writer = write_loop(
+        sink,
+        queue.clone(),
+        framing,
+        settings.subscribe(),
+        stats.clone(),
+        coalesced.clone(),
+        tenant.clone(),
+    );
+    let writer = tokio::spawn(writer.in_current_span());
+    let mut settings = settings.subscribe();
+    let mut inbound_limit = None;
     let mut manager = SubscriptionManager::default();
-    let mut universe = listener.lock().await.universe().into_iter().map(|c| c.value()).collect();
-    if !is_ready {
-        let msg = ServerResponse::Error("Order book not ready for streaming (waiting for snapshot)".to_string());
-        send_socket_message(&mut socket, msg).await;
-        return;
+
+    // held until the connection ends
+    let permit = match (permit, auth) {
+        (None, Some(auth)) => authenticate_first_message(&mut stream, &queue, &auth).await,
+        (permit, _) => permit,
+    };
+    queue.set_priority(load_shedding, permit.as_ref().map(ConnectionPermit::priority));
+    let identity = permit.as_ref().map(|permit| permit.name().to_string());
+    if let (Some(coalescer), Some(identity)) = (&coalescer, &identity) {
+        let _unused = coalesced.set(coalescer.frames(identity));
     }
-    loop {
+    if let Some(own) = permit.as_ref().and_then(ConnectionPermit::tenant) {
+        let _unused = tenant.set(own.clone());
+    }
+    let tenant = tenant.get().cloned();
+    refresh_settings(&mut settings, &mut inbound_limit, &mut manager, tenant.as_deref());
+    let restored = restored.filter(|session| session.identity == identity);
+    let mut registration = registry.register(
+        address,
+        identity.clone(),
+        tenant.as_ref().map(|tenant| tenant.name.clone()),
+        queue.clone(),
+        stats,
+    );
+
+    let mut rx = internal_message_tx.subscribe();
+    let mut replays = Replays::new(journal);
+    let mut keepalive = Keepalive::new(keepalive);
+    let mut universe = Universe::new(markets, ignore_spot, tenant.clone()).await;
+    universe.preferences = preferences.for_permit(permit.as_ref());
+    universe.negotiated = negotiated;
+    refuse_until_ready(&queue, universe.markets.primary()).await;
+    let token = sessions.as_deref().filter(|_| !queue.is_closing()).map(|sessions| {
+        let token = sessions.token();
+        let grace_secs = sessions.grace().as_secs();
+        let resumed = restored.is_some();
+        queue.push(None, ServerResponse::Session(SessionToken { token: token.clone(), resumed, grace_secs }));
+        token
+    });
+    if let Some(restored) = restored.filter(|_| !queue.is_closing()) {
+        info!("Restoring a session of {} subscriptions", restored.subscriptions.len());
+        for request in restored.requests() {
+            receive_client_message(&queue, &mut manager, &mut replays, &mut rx, request, &universe).await;
+        }
+    }
+    while !queue.is_closing() {
+        report_slow_consumer(&queue, &registration, &manager);
+        resync_expired(&queue, &mut manager, &universe.markets).await;
         select! {
-            recv_result = internal_message_rx.recv() => {
+            recv_result = rx.recv() => {
                 match recv_result {
                     Ok(msg) => {
-                        match msg.as_ref() {
-                            InternalMessage::Snapshot{ l2_snapshots, time } => {
-                                universe = new_universe(l2_snapshots, ignore_spot);
-                                for sub in manager.subscriptions() {
-                                    send_ws_data_from_snapshot(&mut socket, sub, l2_snapshots.as_ref(), *time).await;
-                                }
-                            },
-                            InternalMessage::Fills{ batch } => {
-                                let mut trades = coin_to_trades(batch);
-                                for sub in manager.subscriptions() {
-                                    send_ws_data_from_trades(&mut socket, sub, &mut trades).await;
-                                }
-                            },
-                            InternalMessage::L4BookUpdates{ diff_batch, status_batch } => {
-                                let mut book_updates = coin_to_book_updates(diff_batch, status_batch);
-                                for sub in manager.subscriptions() {
-                                    send_ws_data_from_book_updates(&mut socket, sub, &mut book_updates).await;
-                                }
-                            },
-                        }
-
+                        keepalive.observe(&msg);
+                        send_internal_message(&queue, &mut manager, &mut replays, &mut universe, &msg);
+                        on_resume(&queue, &mut manager, &universe.markets, &msg).await;
                     }
                     Err(err) => {
+                        if let RecvError::Lagged(n) = err {
+                            METRICS.dropped_messages.inc_by(n);
+                        }
                         error!("Receiver error: {err}");
-                        return;
+                        queue.abort();
                     }
                 }
             }
 
-            msg = socket.next() => {
+            tick = keepalive.tick() => keepalive.on_tick(&tick, &queue, &manager),
+
+            (subscription, res) = replays.finished() => replays.finish(&queue, &mut manager, subscription, res),
+
+            command = registration.command() => on_command(command, &queue, &mut manager, &universe.markets).await,
+
+            () = shutdown.cancelled() => {
+                // flush whatever was broadcast before the shutdown, then start the closing handshake
+                while let Ok(msg) = rx.try_recv() {
+                    send_internal_message(&queue, &mut manager, &mut replays, &mut universe, &msg);
+                }
+                queue.close(shutdown.close_frame());
+                // wait for the client to acknowledge the close; bounded by the drain timeout
+                while let Some(frame) = stream.next().await {
+                    if frame.opcode == OpCode::Close {
+                        break;
+                    }
+                }
+            }
+
+            msg = stream.next() => {
                 if let Some(frame) = msg {
                     match frame.opcode {
                         OpCode::Text => {
-                            let text = match std::str::from_utf8(&frame.payload) {
-                                Ok(text) => text,
-                                Err(err) => {
-                                    log::warn!("unable to parse websocket content: {err}: {:?}", frame.payload.as_ref());
-                                    // deserves to close the connection because the payload is not a valid utf8 string.
-                                    return;
-                                }
-                            };
-
-                            info!("Client message: {text}");
-
-                            if let Ok(value) = serde_json::from_str::<ClientMessage>(text) {
-                                receive_client_message(&mut socket, &mut manager, value, &universe, listener.clone()).await;
-                            }
-                            else {
-                                let msg = ServerResponse::Error(format!("Error parsing JSON into valid websocket request: {text}"));
-                                send_socket_message(&mut socket, msg).await;
+                            let len = frame.payload.len();
+                            let tenant = tenant.as_deref();
+                            if !admit_message(&queue, &mut settings, &mut inbound_limit, &mut manager, tenant, len) {
+                                continue;
                             }
+                            receive_text(&queue, &mut manager, &mut replays, &mut rx, &frame.payload, &universe).await;
+                        }
+                        OpCode::Binary => {
+                            let err = ProtocolError::new(ErrorCode::BinaryMessage, "requests have to be text frames");
+                            queue.push(None, err.into());
                         }
+                        OpCode::Pong => keepalive.on_pong(),
                         OpCode::Close => {
-                            info!("Client disconnected");
-                            return;
+                            info!("Client {} disconnected", registration.id());
+                            queue.abort();
                         }
                         _ => {}
                     }
                 } else {
-                    info!("Client connection closed");
+                    info!("Client {} connection closed", registration.id());
+                    queue.abort();
+                }
+            }
+        }
+    }
+    report_slow_consumer(&queue, &registration, &manager);
+    registration.finish(manager.subscriptions().iter().cloned().collect());
+    let _unused = writer.await;
+    if let (Some(sessions), Some(token)) = (sessions, token) {
+        let (batch_ms, numbers) = format;
+        let subscriptions = manager.subscriptions().iter().chain(replays.subscriptions()).cloned().collect();
+        let session = Session { identity, batch_ms, numbers, subscriptions, l4_seqs: queue.l4_seqs() };
+        sessions.park(token, session);
+    }
+    drop(registration);
+    drop(permit);
+}
+
+// a client that fell behind is logged and listed by the admin API, and told why when it is evicted
+pub(crate) fn report_slow_consumer(queue: &SendQueue, registration: &Registration, manager: &SubscriptionManager) {
+    let subscriptions = || manager.subscriptions().iter().cloned().collect();
+    if let Some(reason) = queue.take_eviction() {
+        let report = queue.report(SlowConsumerAction::Evicted, reason, subscriptions());
+        registration.report_slow_consumer(&report);
+        queue.finish_eviction(report);
+    } else if let Some(reason) = queue.take_throttle() {
+        registration.report_slow_consumer(&queue.report(SlowConsumerAction::Throttled, reason, subscriptions()));
+    }
+}
+
+// clients connecting before the first snapshot are asked to come back
+pub(crate) async fn refuse_until_ready(queue: &SendQueue, listener: &Mutex<OrderBookListener>) {
+    if !listener.lock().await.is_ready() {
+        queue
+            .push(None, ServerResponse::Error("Order book not ready for streaming (waiting for snapshot)".to_string()));
+        queue.close(FrameView::close(CloseCode::Again, "order book not ready"));
+    }
+}
+
+pub(crate) async fn receive_text(
+    queue: &Arc<SendQueue>,
+    manager: &mut SubscriptionManager,
+    replays: &mut Replays,
+    receivers: &mut Receivers,
+    payload: &[u8],
+    universe: &Universe,
+) {
+    info!("Client message: {}", String::from_utf8_lossy(payload));
+
+    match parse_request(payload) {
+        Ok(request) => {
+            let request = universe.preferences.apply(request);
+            receive_client_message(queue, manager, replays, receivers, request, universe).await;
+        }
+        Err(err) => {
+            info!("Rejecting client message: {}", err.msg);
+            queue.push(None, err.into());
+        }
+    }
+}
+
+pub(crate) async fn on_command(
+    command: Command,
+    queue: &SendQueue,
+    manager: &mut SubscriptionManager,
+    markets: &Markets,
+) {
+    match command {
+        Command::Subscriptions(tx) => {
+            let _unused = tx.send(manager.subscriptions().iter().cloned().collect());
+        }
+        Command::Resnapshot => resnapshot(queue, manager, markets, None).await,
+    }
+}
+
+// sends the subscriptions, those to a coin if given, a fresh snapshot
+async fn resnapshot(queue: &SendQueue, manager: &mut SubscriptionManager, markets: &Markets, coin: Option<&str>) {
+    let subscriptions = manager.subscriptions().clone();
+    for subscription in subscriptions.into_iter().filter(|sub| coin.is_none() || sub.coin() == coin) {
+        send_snapshot(queue, manager, markets, &subscription).await;
+    }
+}
+
+// the subscriptions whose book messages expired in the send queue get a fresh snapshot to go on from
+pub(crate) async fn resync_expired(queue: &SendQueue, manager: &mut SubscriptionManager, markets: &Markets) {
+    for subscription in queue.take_resyncs() {
+        if manager.subscriptions().contains(&subscription) {
+            send_snapshot(queue, manager, markets, &subscription).await;
+        }
+    }
+}
+
+async fn send_snapshot(queue: &SendQueue, manager: &mut SubscriptionManager, markets: &Markets, sub: &Subscription) {
+    match sub.handle_immediate_snapshot(markets.for_subscription(sub)).await {
+        Ok(Some(msg)) => {
+            manager.book_sent(sub, &msg);
+            queue.push(Some(sub), msg);
+        }
+        Ok(None) => {}
+        Err(err) => {
+            queue.push(None, ServerResponse::Error(format!("Unable to grab order book snapshot: {err}")));
+        }
+    }
+}
+
+// picks up changed runtime settings; existing subscriptions are kept when the limit is lowered
+fn refresh_settings(
+    settings: &mut watch::Receiver<RuntimeSettings>,
+    inbound_limit: &mut Option<TokenBucket>,
+    manager: &mut SubscriptionManager,
+    tenant: Option<&Tenant>,
+) {
+    if settings.has_changed().unwrap_or_default() {
+        let settings = *settings.borrow_and_update();
+        let rate_limits = Tenant::rate_limits(tenant, settings.rate_limits);
+        *inbound_limit = rate_limits.client_messages_per_sec.map(TokenBucket::per_second);
+        manager.set_limit(settings.max_subscriptions);
+    }
+}
+
+// whether a client message gets handled; a message breaking a limit closes the connection instead
+pub(crate) fn admit_message(
+    queue: &SendQueue,
+    settings: &mut watch::Receiver<RuntimeSettings>,
+    inbound_limit: &mut Option<TokenBucket>,
+    manager: &mut SubscriptionManager,
+    tenant: Option<&Tenant>,
+    len: usize,
+) -> bool {
+    refresh_settings(settings, inbound_limit, manager, tenant);
+    if let Some(max) = settings.borrow().connection_limits.max_message_bytes
+        && len > max
+    {
+        info!("Closing connection sending a message of {len} bytes");
+        METRICS.limit_rejections.with_label_values(&["message_size"]).inc();
+        let msg = format!("message of {len} bytes is over the limit of {max} bytes");
+        queue.push(None, ProtocolError::new(ErrorCode::MessageTooLarge, msg).into());
+        queue.close(FrameView::close(CloseCode::Size, "message too large"));
+        return false;
+    }
+    if let Some(limit) = inbound_limit
+        && !limit.try_acquire(Instant::now())
+    {
+        info!("Closing connection sending too many messages");
+        METRICS.rate_limited.with_label_values(&["client_messages"]).inc();
+        queue.push(None, ServerResponse::Error("Rate limit exceeded".to_string()));
+        queue.close(FrameView::close(CloseCode::Policy, "too many messages"));
+        return false;
+    }
+    true
+}
+
+// the first message has to be `{"method": "auth", "token": ...}`, otherwise the connection is closed
+async fn authenticate_first_message(
+    stream: &mut SocketReader,
+    queue: &SendQueue,
+    auth: &Authenticator,
+) -> Option<ConnectionPermit> {
+    let res = match timeout(AUTH_TIMEOUT, stream.next()).await {
+        Ok(Some(frame)) if frame.opcode == OpCode::Text => match serde_json::from_slice(&frame.payload) {
+            Ok(ClientMessage::Auth { token }) => auth.admit(&token),
+            _ => Err(AuthError::Missing),
+        },
+        _ => Err(AuthError::Missing),
+    };
+    match res {
+        Ok(permit) => {
+            info!("Client authenticated as {}", permit.name());
+            Some(permit)
+        }
+        Err(err) => {
+            info!("Closing unauthenticated connection: {err}");
+            queue.push(None, ServerResponse::Error(format!("Authentication failed: {err}")));
+            queue.close(FrameView::close(CloseCode::Policy, err.to_string()));
+            None
+        }
+    }
+}
+
+// the only place that writes to the socket, so that a slow client never blocks the connection's main loop
+#[allow(clippy::too_many_lines)]
+async fn write_loop(
+    mut sink: SocketWriter,
+    queue: Arc<SendQueue>,
+    framing: Framing,
+    mut settings: watch::Receiver<RuntimeSettings>,
+    stats: Arc<ConnectionStats>,
+    coalesced: Arc<OnceLock<Arc<IdentityFrames>>>,
+    tenant: Arc<OnceLock<Arc<Tenant>>>,
+) {
+    let batch_window = framing.batch_window;
+    let mut limit = None;
+    let mut quota = None;
+    // taken from the queue while collecting a batch
+    let mut next = None;
+    loop {
+        let outgoing = match next.take() {
+            Some(outgoing) => outgoing,
+            None => match queue.next().await {
+                Some(outgoing) => outgoing,
+                None => return,
+            },
+        };
+        match outgoing {
+            Outgoing::Message(msg, stamps) => {
+                let msgs = match batch_window {
+                    Some(window) => {
+                        let deadline = batch_deadline(window, coalesced.get().is_some());
+                        let (msgs, after) = queue.next_batch((msg, stamps), deadline).await;
+                        next = after;
+                        msgs
+                    }
+                    None => vec![(msg, stamps)],
+                };
+                let send_time = now_ms();
+                let span = info_span!(target: PIPELINE, parent: None, "serialize", messages = msgs.len());
+                let (res, shared_frame) =
+                    span.in_scope(|| framing.encode_shared(coalesced.get().map(Arc::as_ref), &msgs, send_time));
+                let frame = match res {
+                    Ok(frame) => frame,
+                    Err(err) => {
+                        error!("Server response serialization error: {err}");
+                        continue;
+                    }
+                };
+                if settings.has_changed().unwrap_or_default() {
+                    let rate_limits =
+                        Tenant::rate_limits(tenant.get().map(Arc::as_ref), settings.borrow_and_update().rate_limits);
+                    limit = rate_limits.outbound_messages_per_sec.map(TokenBucket::per_second);
+                    quota = rate_limits
+                        .outbound_bytes_per_min
+                        .map(|bytes| (TokenBucket::bytes_per_minute(bytes), rate_limits.bandwidth_policy));
+                }
+                // messages queue up behind the limit, so a client that is always over it runs into backpressure
+                if let Some(limit) = &mut limit {
+                    for _ in &msgs {
+                        limit.acquire().await;
+                    }
+                }
+                let len = frame.payload.len() as u64;
+                if let Some((bucket, policy)) = &mut quota
+                    && !bucket.try_acquire_n(len, Instant::now())
+                {
+                    if *policy == BandwidthPolicy::Throttle {
+                        queue.throttle(SlowConsumerReason::BandwidthQuota);
+                        bucket.acquire_n(len).await;
+                    } else {
+                        // the report of the eviction still goes out
+                        info!("Closing connection over its bandwidth quota");
+                        METRICS.rate_limited.with_label_values(&["bandwidth"]).inc();
+                        quota = None;
+                        queue.evict(SlowConsumerReason::BandwidthQuota);
+                        continue;
+                    }
+                }
+                #[cfg(feature = "chaos")]
+                if !chaos::outbound_frame(&stats.stall).await {
+                    continue;
+                }
+                let res = match (framing.shared(&msgs), &shared_frame) {
+                    (Some(msg), _) => {
+                        let Subprotocol { encoding, version, .. } = framing.subprotocol;
+                        sink.send_compressed(frame, |level| msg.compressed(encoding, version, level)).await
+                    }
+                    (None, Some(shared)) => sink.send_compressed(frame, |level| shared.compressed(level)).await,
+                    (None, None) => sink.send(frame).await,
+                };
+                if let Err(err) = res {
+                    error!("Failed to send: {err}");
+                    queue.abort();
                     return;
                 }
+                let sent = now_ms();
+                for stamps in msgs.iter().filter_map(|(_, stamps)| *stamps) {
+                    stamps.observe(sent);
+                }
+                METRICS.messages_sent.inc_by(msgs.len() as u64);
+                METRICS.payload_bytes.inc_by(len);
+                stats.record(msgs.len() as u64, len);
+            }
+            Outgoing::Ping => {
+                if let Err(err) = sink.send(FrameView::ping(Vec::new())).await {
+                    error!("Failed to send ping: {err}");
+                    queue.abort();
+                    return;
+                }
+            }
+            // skips the batch window and the rate limits, which would make its time late
+            Outgoing::Time => {
+                let send_time = now_ms();
+                let msg = ServerResponse::Time(TimeSync { send_time });
+                let res = match framing.encode(&[(msg.into(), None)], send_time) {
+                    Ok(frame) => sink.send(frame).await,
+                    Err(err) => {
+                        error!("Server response serialization error: {err}");
+                        continue;
+                    }
+                };
+                if let Err(err) = res {
+                    error!("Failed to send: {err}");
+                    queue.abort();
+                    return;
+                }
+            }
+            Outgoing::Close(frame) => {
+                if let Err(err) = sink.send(frame).await {
+                    info!("Failed to send close frame: {err}");
+                }
+                return;
+            }
+        }
+    }
+}
+
+pub(crate) fn send_internal_message(
+    queue: &SendQueue,
+    manager: &mut SubscriptionManager,
+    replays: &mut Replays,
+    universe: &mut Universe,
+    msg: &InternalMessage,
+) {
+    let _span = msg.trace().map(|trace| info_span!(target: PIPELINE, parent: trace, "fan_out").entered());
+    match msg {
+        InternalMessage::Snapshot { l2_snapshots, time, seq, stamps, shared, .. } => {
+            manager.for_each_changed_l2_book(
+                |sub| {
+                    shared.for_subscription(sub, || {
+                        l2_book_from_snapshots(sub, l2_snapshots.as_ref(), *time, *seq)
+                            .map(|book| book_response(sub, book))
+                    })
+                },
+                |sub, book| queue.push_stamped(sub, book, *stamps),
+            );
+        }
+        InternalMessage::Fills { batch, stamps, shared, .. } => {
+            let trades = shared.by_coin(|| {
+                coin_to_trades(batch).into_iter().map(|(coin, trades)| (coin, ServerResponse::Trades(trades))).collect()
+            });
+            for sub in manager.subscriptions() {
+                send_ws_data_from_trades(queue, sub, trades, *stamps);
+                if let Subscription::UserOrders { user, .. } = sub
+                    && let Some(orders) = UserOrders::from_fills(*user, batch)
+                {
+                    queue.push_stamped(sub, ServerResponse::UserOrders(orders).into(), *stamps);
+                }
+            }
+        }
+        InternalMessage::L4BookUpdates { updates, stamps, shared, .. } => {
+            replays.on_book_updates(queue, updates);
+            let msgs = shared.by_coin(|| {
+                updates
+                    .iter()
+                    .map(|(coin, updates)| (coin.clone(), ServerResponse::L4Book(L4Book::Updates(updates.clone()))))
+                    .collect()
+            });
+            for sub in manager.subscriptions() {
+                send_ws_data_from_book_updates(queue, sub, updates, msgs, replays, *stamps);
+                if let Subscription::UserOrders { user, .. } = sub
+                    && let Some(orders) = UserOrders::from_book_updates(*user, updates)
+                {
+                    queue.push_stamped(sub, ServerResponse::UserOrders(orders).into(), *stamps);
+                }
+            }
+        }
+        InternalMessage::L3BookUpdates { updates, stamps, shared, .. } => {
+            let msgs = shared.by_coin(|| {
+                updates
+                    .iter()
+                    .map(|(coin, updates)| (coin.clone(), ServerResponse::L3Book(L3Book::Updates(updates.clone()))))
+                    .collect()
+            });
+            for sub in manager.subscriptions() {
+                send_ws_data_from_l3_updates(queue, sub, updates, msgs, *stamps);
+            }
+        }
+        InternalMessage::Candles { candles } => {
+            for sub in manager.subscriptions() {
+                send_ws_data_from_candles(queue, sub, candles);
+            }
+        }
+        InternalMessage::Analytics { analytics } => {
+            for sub in manager.subscriptions() {
+                send_ws_data_from_analytics(queue, sub, analytics);
+            }
+        }
+        InternalMessage::Checkpoints { checkpoints } => {
+            for sub in manager.subscriptions() {
+                send_ws_data_from_checkpoints(queue, sub, checkpoints);
+            }
+        }
+        InternalMessage::Status { status, stale_changed } => {
+            let msg = ServerResponse::Status(status.clone());
+            if manager.subscriptions().contains(&Subscription::Status) {
+                queue.push(Some(&Subscription::Status), msg);
+            } else if *stale_changed {
+                queue.push(None, msg);
+            }
+        }
+        InternalMessage::Universe { coins } => universe.update(coins),
+        InternalMessage::MarketChanges { changes } => {
+            // the changes of a single market
+            if manager.subscriptions().contains(&Subscription::Markets)
+                && changes.first().is_some_and(|change| universe.allows(&change.market.coin))
+            {
+                queue.push(Some(&Subscription::Markets), ServerResponse::MarketChanges(changes.clone()));
+            }
+        }
+        // once for all subscriptions to the market; the fresh snapshots of a resume are sent by `on_resume`
+        InternalMessage::Halt { halt, resumed } => {
+            if manager.subscriptions().iter().any(|sub| sub.coin() == Some(halt.coin.as_str())) {
+                let msg =
+                    if *resumed { ServerResponse::Resume(halt.clone()) } else { ServerResponse::Halt(halt.clone()) };
+                queue.push(None, msg);
             }
         }
     }
 }
 
-async fn receive_client_message(
-    socket: &mut WebSocket,
+// the subscriptions to a market that resumed get fresh snapshots, its book data having been held back
+pub(crate) async fn on_resume(
+    queue: &SendQueue,
     manager: &mut SubscriptionManager,
+    markets: &Markets,
+    msg: &InternalMessage,
+) {
+    if let InternalMessage::Halt { halt, resumed: true } = msg {
+        resnapshot(queue, manager, markets, Some(&halt.coin)).await;
+    }
+}
+
+pub(crate) async fn receive_client_message(
+    queue: &Arc<SendQueue>,
+    manager: &mut SubscriptionManager,
+    replays: &mut Replays,
+    receivers: &mut Receivers,
     client_message: ClientMessage,
-    universe: &HashSet<String>,
-    listener: Arc<Mutex<OrderBookListener>>,
+    universe: &Universe,
 ) {
     let subscription = match &client_message {
-        ClientMessage::Unsubscribe { subscription } | ClientMessage::Subscribe { subscription } => subscription.clone(),
+        ClientMessage::Unsubscribe { subscription }
+        | ClientMessage::Subscribe { subscription }
+        | ClientMessage::Snapshot { subscription }
+        | ClientMessage::Replay { subscription, .. }
+        | ClientMessage::Resume { subscription, .. } => subscription.clone(),
+        // auth, listMarkets and the requests with an id
+        _ => {
+            queue.push(None, request_response(&client_message, queue, manager, universe).await);
+            return;
+        }
     };
     // this is used for display purposes only, hence unwrap_or_default. It also shouldn't fail
     let sub = serde_json::to_string(&subscription).unwrap_or_default();
-    if !subscription.validate(universe) {
-        let msg = ServerResponse::Error(format!("Invalid subscription: {sub}"));
-        send_socket_message(socket, msg).await;
+    if let Err(err) = universe.validate(&subscription, &sub) {
+        queue.push(None, ServerResponse::Error(err));
         return;
     }
+    let listener = universe.markets.for_subscription(&subscription);
+    if let Err(err) = prepare_subscription(&listener, &client_message, &subscription, &sub).await {
+        queue.push(None, ServerResponse::Error(err));
+        return;
+    }
+    // before the snapshot is taken, so that every update after it is received
+    if matches!(
+        client_message,
+        ClientMessage::Subscribe { .. } | ClientMessage::Replay { .. } | ClientMessage::Resume { .. }
+    ) {
+        receivers.follow(&subscription);
+    }
+    // a resume whose updates are gone continues as a subscribe, with a snapshot
+    let client_message = match client_message {
+        ClientMessage::Resume { subscription, from_seq } => {
+            if resume(queue, manager, replays, &listener, &subscription, from_seq, &sub).await {
+                return;
+            }
+            ClientMessage::Subscribe { subscription }
+        }
+        client_message => client_message,
+    };
     let (word, success) = match &client_message {
-        ClientMessage::Subscribe { .. } => ("", manager.subscribe(subscription)),
-        ClientMessage::Unsubscribe { .. } => ("un", manager.unsubscribe(subscription)),
+        ClientMessage::Subscribe { .. } => {
+            if !manager.subscriptions().contains(&subscription) && manager.is_full(replays.len()) {
+                METRICS.limit_rejections.with_label_values(&["subscriptions"]).inc();
+                queue.push(None, ServerResponse::Error(format!("Subscription limit reached: {sub}")));
+                return;
+            }
+            ("", !replays.is_active(&subscription) && manager.subscribe(subscription))
+        }
+        ClientMessage::Unsubscribe { .. } => ("un", replays.cancel(&subscription) || manager.unsubscribe(subscription)),
+        ClientMessage::Replay { from_seq, from_ts, .. } => {
+            // `parse_request` made sure that exactly one of them is set
+            let from = from_seq.map_or_else(|| ReplayFrom::Time(from_ts.unwrap_or_default()), ReplayFrom::Seq);
+            if let Err(err) = can_replay(manager, replays, &subscription, &sub)
+                .and_then(|()| replays.start(queue, subscription, from))
+            {
+                queue.push(None, ServerResponse::Error(err));
+            }
+            return;
+        }
+        ClientMessage::Snapshot { .. } => {
+            // re-send the snapshot that started the stream, e.g. after the client detected a sequence gap
+            let msg = if manager.subscriptions().contains(&subscription) {
+                match subscription.handle_immediate_snapshot(listener).await {
+                    Ok(Some(msg)) => msg,
+                    Ok(None) => ServerResponse::Error(format!("No snapshot available for: {sub}")),
+                    Err(err) => ServerResponse::Error(format!("Unable to grab order book snapshot: {err}")),
+                }
+            } else {
+                ServerResponse::Error(format!("Not subscribed: {sub}"))
+            };
+            manager.book_sent(&subscription, &msg);
+            queue.push(Some(&subscription), msg);
+            return;
+        }
+        // resumes continue as subscribes, the requests without a subscription were answered above
+        _ => return,
     };
     if success {
-        let snapshot_msg = if let ClientMessage::Subscribe { subscription } = &client_message {
+        let (snapshot_msg, halt) = if let ClientMessage::Subscribe { subscription } = &client_message {
+            let halt = universe.markets.halt(subscription).await;
             let msg = subscription.handle_immediate_snapshot(listener).await;
             match msg {
-                Ok(msg) => msg,
+                Ok(msg) => (msg.map(|msg| (subscription.clone(), msg)), halt),
                 Err(err) => {
                     manager.unsubscribe(subscription.clone());
                     let msg = ServerResponse::Error(format!("Unable to grab order book snapshot: {err}"));
-                    send_socket_message(socket, msg).await;
+                    queue.push(None, msg);
                     return;
                 }
             }
         } else {
-            None
+            (None, None)
         };
-        let msg = ServerResponse::SubscriptionResponse(client_message);
-        send_socket_message(socket, msg).await;
-        if let Some(snapshot_msg) = snapshot_msg {
-            send_socket_message(socket, snapshot_msg).await;
+        queue.push(None, ServerResponse::SubscriptionResponse(client_message));
+        if let Some((subscription, snapshot_msg)) = snapshot_msg {
+            manager.book_sent(&subscription, &snapshot_msg);
+            queue.push(Some(&subscription), snapshot_msg);
+        }
+        if let Some(halt) = halt {
+            queue.push(None, ServerResponse::Halt(halt));
         }
     } else {
-        let msg = ServerResponse::Error(format!("Already {word}subscribed: {sub}"));
-        send_socket_message(socket, msg).await;
+        queue.push(None, ServerResponse::Error(format!("Already {word}subscribed: {sub}")));
     }
 }
 
-async fn send_socket_message(socket: &mut WebSocket, msg: ServerResponse) {
-    let msg = serde_json::to_string(&msg);
-    match msg {
-        Ok(msg) => {
-            if let Err(err) = socket.send(FrameView::text(msg)).await {
-                error!("Failed to send: {err}");
-            }
-        }
-        Err(err) => {
-            error!("Server response serialization error: {err}");
+// the answer to a request that isn't about a subscription
+async fn request_response(
+    client_message: &ClientMessage,
+    queue: &SendQueue,
+    manager: &SubscriptionManager,
+    universe: &Universe,
+) -> ServerResponse {
+    // answered from the connection's own state
+    if let ClientMessage::Stats { id } = client_message {
+        return rpc::stats(id, queue, manager, universe);
+    }
+    if let Some(response) = rpc::answer(client_message, universe).await {
+        return response;
+    }
+    match client_message {
+        ClientMessage::ListMarkets => {
+            ServerResponse::Markets(universe.markets.market_info(universe.tenant.as_deref()).await)
         }
+        _ => ServerResponse::Error("Auth is only accepted as the first message".to_string()),
     }
 }
 
-// derive it from l2_snapshots because thats convenient
-fn new_universe(l2_snapshots: &L2Snapshots, ignore_spot: bool) -> HashSet<String> {
-    l2_snapshots
-        .as_ref()
-        .iter()
-        .filter_map(|(c, _)| if !c.is_spot() || !ignore_spot { Some(c.clone().value()) } else { None })
-        .collect()
+// whether a subscription can start with a replay or resume
+fn can_replay(
+    manager: &SubscriptionManager,
+    replays: &Replays,
+    subscription: &Subscription,
+    sub: &str,
+) -> std::result::Result<(), String> {
+    if manager.subscriptions().contains(subscription) || replays.is_active(subscription) {
+        return Err(format!("Already subscribed: {sub}"));
+    }
+    if manager.is_full(replays.len()) {
+        METRICS.limit_rejections.with_label_values(&["subscriptions"]).inc();
+        return Err(format!("Subscription limit reached: {sub}"));
+    }
+    Ok(())
+}
+
+// sends the l4 updates a reconnecting client missed, if the listener still has all of them. False if it doesn't,
+// for the client to get a snapshot instead
+async fn resume(
+    queue: &Arc<SendQueue>,
+    manager: &SubscriptionManager,
+    replays: &mut Replays,
+    listener: &Mutex<OrderBookListener>,
+    subscription: &Subscription,
+    from_seq: u64,
+    sub: &str,
+) -> bool {
+    if let Err(err) = can_replay(manager, replays, subscription, sub) {
+        queue.push(None, ServerResponse::Error(err));
+        return true;
+    }
+    let Subscription::L4Book { coin, .. } = subscription else {
+        queue.push(None, ServerResponse::Error("Only l4Book subscriptions can be resumed".to_string()));
+        return true;
+    };
+    let Some(missed) = listener.lock().await.missed_updates(coin, from_seq) else {
+        return false;
+    };
+    info!("Resuming {coin} from seq {from_seq}, {} updates behind", missed.len());
+    replays.resume(queue, subscription.clone(), from_seq, missed);
+    true
+}
+
+// what a valid subscription needs from the listener
+async fn prepare_subscription(
+    listener: &Mutex<OrderBookListener>,
+    client_message: &ClientMessage,
+    subscription: &Subscription,
+    sub: &str,
+) -> std::result::Result<(), String> {
+    // the books of a lazy market are loaded for its first subscriber
+    if let ClientMessage::Subscribe { .. } | ClientMessage::Replay { .. } | ClientMessage::Resume { .. } =
+        client_message
+    {
+        listener.lock().await.watch();
+    }
+    if let Subscription::Candle { interval, .. } = subscription
+        && !listener.lock().await.has_candles(*interval)
+    {
+        return Err(format!("Candle interval not enabled: {sub}"));
+    }
+    if let Subscription::Analytics { .. } = subscription
+        && listener.lock().await.analytics().is_none()
+    {
+        return Err(format!("Analytics not enabled: {sub}"));
+    }
+    if let Subscription::Checkpoint { .. } = subscription
+        && listener.lock().await.checkpoints().is_none()
+    {
+        return Err(format!("Signing not enabled: {sub}"));
+    }
+    // the proof of owning the account is only checked when the stream starts, unsubscribing takes a stale one
+    if let ClientMessage::Subscribe { .. } | ClientMessage::Replay { .. } | ClientMessage::Resume { .. } =
+        client_message
+        && let Subscription::UserOrders { user, timestamp, signature } = subscription
+    {
+        ownership::verify(user, *timestamp, signature, now_ms())
+            .map_err(|err| format!("Not authorized ({err}): {sub}"))?;
+    }
+    // the book is aggregated by the tick size from the first subscription to it on
+    if let ClientMessage::Subscribe { .. } = client_message
+        && let Subscription::L2Book { coin, .. } = subscription
+        && let Some(tick) = subscription.tick_size()
+    {
+        let res = listener.lock().await.add_tick_group(&Coin::new(coin), tick);
+        res.map_err(|err| format!("Unable to aggregate by tick size: {err}"))?;
+    }
+    Ok(())
 }
 
-async fn send_ws_data_from_snapshot(
-    socket: &mut WebSocket,
+// the coins a connection can subscribe to, kept up to date from the snapshots of every market
+pub(crate) struct Universe {
+    coins: HashSet<String>,
+    pub(crate) markets: Markets,
+    ignore_spot: bool,
+    // whose markets alone the connection may use
+    pub(crate) tenant: Option<Arc<Tenant>>,
+    // of the connection's API key, for the subscriptions it requests
+    pub(crate) preferences: Preferences,
+    pub(crate) negotiated: Negotiated,
+}
+
+impl Universe {
+    pub(crate) async fn new(markets: Markets, ignore_spot: bool, tenant: Option<Arc<Tenant>>) -> Self {
+        let preferences = Preferences::default();
+        let negotiated = Negotiated::default();
+        Self { coins: markets.universe().await, markets, ignore_spot, tenant, preferences, negotiated }
+    }
+
+    fn allows(&self, coin: &str) -> bool {
+        self.markets.allows(self.tenant.as_deref(), coin)
+    }
+
+    // a valid subscription to a coin the connection may use
+    pub(crate) fn validate(&self, subscription: &Subscription, sub: &str) -> std::result::Result<(), String> {
+        if subscription.coin().is_some_and(|coin| !self.allows(coin)) {
+            return Err(format!("Market not available to this key: {sub}"));
+        }
+        if !subscription.validate(&self.coins) {
+            return Err(format!("Invalid subscription: {sub}"));
+        }
+        Ok(())
+    }
+
+    // the coins of a market replace those of that market only
+    fn update(&mut self, coins: &HashSet<Coin>) {
+        let Some(coin) = coins.iter().next() else {
+            return;
+        };
+        let market = self.markets.market_of(coin.as_str()).map(str::to_string);
+        self.coins.retain(|coin| self.markets.market_of(coin) != market.as_deref());
+        self.coins.extend(coins.iter().filter(|coin| !coin.is_spot() || !self.ignore_spot).map(Coin::value));
+    }
+}
+
+// the book an l2 subscription should receive out of a published set of snapshots
+pub(crate) fn l2_book_from_snapshots(
     subscription: &Subscription,
     snapshot: &HashMap<Coin, HashMap<L2SnapshotParams, Snapshot<InnerLevel>>>,
     time: u64,
-) {
-    if let Subscription::L2Book { coin, n_sig_figs, n_levels, mantissa } = subscription {
-        let snapshot = snapshot.get(&Coin::new(coin));
-        if let Some(snapshot) =
-            snapshot.and_then(|snapshot| snapshot.get(&L2SnapshotParams::new(*n_sig_figs, *mantissa)))
-        {
-            let n_levels = n_levels.unwrap_or(DEFAULT_LEVELS);
-            let snapshot = snapshot.truncate(n_levels);
-            let snapshot = snapshot.export_inner_snapshot();
-            let l2_book = L2Book::from_l2_snapshot(coin.clone(), snapshot, time);
-            let msg = ServerResponse::L2Book(l2_book);
-            send_socket_message(socket, msg).await;
-        } else {
-            error!("Coin {coin} not found");
+    seq: u64,
+) -> Option<L2Book> {
+    let (coin, n_sig_figs, n_levels, mantissa) = match subscription {
+        Subscription::L2Book { coin, n_sig_figs, n_levels, mantissa, .. } => {
+            (coin, *n_sig_figs, n_levels.unwrap_or(DEFAULT_LEVELS), *mantissa)
         }
+        Subscription::Bbo { coin, .. } => (coin, None, 1, None),
+        Subscription::Trades { .. }
+        | Subscription::L4Book { .. }
+        | Subscription::L3Book { .. }
+        | Subscription::Candle { .. }
+        | Subscription::Analytics { .. }
+        | Subscription::Checkpoint { .. }
+        | Subscription::UserOrders { .. }
+        | Subscription::Status
+        | Subscription::Markets => return None,
+    };
+    // absent from the snapshots of other markets
+    let snapshot = snapshot.get(&Coin::new(coin))?;
+    let Some(snapshot) = snapshot.get(&L2SnapshotParams::new(n_sig_figs, mantissa, subscription.tick_size())) else {
+        error!("Coin {coin} not found");
+        return None;
+    };
+    let snapshot = snapshot.truncate(n_levels);
+    let snapshot = snapshot.export_inner_snapshot();
+    Some(L2Book::from_l2_snapshot(coin.clone(), snapshot, time, seq))
+}
+
+// bbo subscriptions get the top of the book only
+fn book_response(subscription: &Subscription, book: L2Book) -> ServerResponse {
+    match subscription {
+        Subscription::Bbo { .. } => ServerResponse::Bbo(Bbo::from_l2_book(book)),
+        _ => ServerResponse::L2Book(book),
     }
 }
 
-fn coin_to_trades(batch: &Batch<NodeDataFill>) -> HashMap<String, Vec<Trade>> {
+pub(crate) fn coin_to_trades(batch: &Batch<NodeDataFill>) -> HashMap<String, Vec<Trade>> {
     let mut fills = batch.clone().events();
     let mut trades = HashMap::new();
     while fills.len() >= 2 {
@@ -316,76 +1855,170 @@ fn coin_to_trades(batch: &Batch<NodeDataFill>) -> HashMap<String, Vec<Trade>> {
     trades
 }
 
-fn coin_to_book_updates(
-    diff_batch: &Batch<NodeDataOrderDiff>,
-    status_batch: &Batch<NodeDataOrderStatus>,
-) -> HashMap<String, L4BookUpdates> {
-    let diffs = diff_batch.clone().events();
-    let statuses = status_batch.clone().events();
-    let time = diff_batch.block_time();
-    let height = diff_batch.block_number();
-    let mut updates = HashMap::new();
-    for diff in diffs {
-        let coin = diff.coin().value();
-        updates.entry(coin).or_insert_with(|| L4BookUpdates::new(time, height)).book_diffs.push(diff);
-    }
-    for status in statuses {
-        let coin = status.order.coin.clone();
-        updates.entry(coin).or_insert_with(|| L4BookUpdates::new(time, height)).order_statuses.push(status);
+fn send_ws_data_from_book_updates(
+    queue: &SendQueue,
+    subscription: &Subscription,
+    book_updates: &HashMap<String, L4BookUpdates>,
+    msgs: &HashMap<String, Arc<Outbound>>,
+    replays: &mut Replays,
+    stamps: Stamps,
+) {
+    if let Subscription::L4Book { coin, .. } = subscription
+        && let Some(updates) = book_updates.get(coin)
+        && !replays.already_sent(subscription, updates)
+        && let Some(msg) = msgs.get(coin)
+    {
+        queue.push_stamped(subscription, msg.clone(), stamps);
     }
-    updates
 }
 
-async fn send_ws_data_from_book_updates(
-    socket: &mut WebSocket,
+fn send_ws_data_from_l3_updates(
+    queue: &SendQueue,
     subscription: &Subscription,
-    book_updates: &mut HashMap<String, L4BookUpdates>,
+    updates: &HashMap<String, L3BookUpdates>,
+    msgs: &HashMap<String, Arc<Outbound>>,
+    stamps: Stamps,
 ) {
-    if let Subscription::L4Book { coin } = subscription
-        && let Some(updates) = book_updates.remove(coin)
+    if let Subscription::L3Book { coin } = subscription
+        && updates.contains_key(coin)
+        && let Some(msg) = msgs.get(coin)
     {
-        let msg = ServerResponse::L4Book(L4Book::Updates(updates));
-        send_socket_message(socket, msg).await;
+        queue.push_stamped(subscription, msg.clone(), stamps);
     }
 }
 
-async fn send_ws_data_from_trades(
-    socket: &mut WebSocket,
+fn send_ws_data_from_trades(
+    queue: &SendQueue,
     subscription: &Subscription,
-    trades: &mut HashMap<String, Vec<Trade>>,
+    trades: &HashMap<String, Arc<Outbound>>,
+    stamps: Stamps,
 ) {
     if let Subscription::Trades { coin } = subscription
-        && let Some(trades) = trades.remove(coin)
+        && let Some(msg) = trades.get(coin)
     {
-        let msg = ServerResponse::Trades(trades);
-        send_socket_message(socket, msg).await;
+        queue.push_stamped(subscription, msg.clone(), stamps);
+    }
+}
+
+fn send_ws_data_from_candles(queue: &SendQueue, subscription: &Subscription, candles: &[Candle]) {
+    if let Subscription::Candle { coin, interval } = subscription {
+        for candle in candles.iter().filter(|candle| candle.s == *coin && candle.i == *interval) {
+            queue.push(Some(subscription), ServerResponse::Candle(candle.clone()));
+        }
+    }
+}
+
+fn send_ws_data_from_analytics(queue: &SendQueue, subscription: &Subscription, analytics: &[MarketAnalytics]) {
+    if let Subscription::Analytics { coin } = subscription
+        && let Some(market) = analytics.iter().find(|market| market.coin == *coin)
+    {
+        queue.push(Some(subscription), ServerResponse::Analytics(market.clone()));
+    }
+}
+
+fn send_ws_data_from_checkpoints(queue: &SendQueue, subscription: &Subscription, checkpoints: &[Checkpoint]) {
+    if let Subscription::Checkpoint { coin } = subscription
+        && let Some(checkpoint) = checkpoints.iter().find(|checkpoint| checkpoint.coin == *coin)
+    {
+        queue.push(Some(subscription), ServerResponse::Checkpoint(checkpoint.clone()));
     }
 }
 
 impl Subscription {
     // snapshots that begin a stream
-    async fn handle_immediate_snapshot(
+    pub(crate) async fn handle_immediate_snapshot(
         &self,
         listener: Arc<Mutex<OrderBookListener>>,
     ) -> Result<Option<ServerResponse>> {
-        if let Self::L4Book { coin } = self {
-            let snapshot = listener.lock().await.compute_snapshot();
-            if let Some(TimedSnapshots { time, height, snapshot }) = snapshot {
-                let snapshot =
-                    snapshot.value().into_iter().filter(|(c, _)| *c == Coin::new(coin)).collect::<Vec<_>>().pop();
-                if let Some((coin, snapshot)) = snapshot {
-                    let snapshot =
-                        snapshot.as_ref().clone().map(|orders| orders.into_iter().map(L4Order::from).collect());
-                    return Ok(Some(ServerResponse::L4Book(L4Book::Snapshot {
-                        coin: coin.value(),
-                        time,
-                        height,
-                        levels: snapshot,
-                    })));
+        // those of a lazy market get theirs once its books are loaded
+        if listener.lock().await.is_loading() {
+            return Ok(None);
+        }
+        match self {
+            Self::L4Book { coin, .. } => {
+                let coin = Coin::new(coin);
+                let (snapshot, seq, levels, checkpoint) = {
+                    let mut listener = listener.lock().await;
+                    let levels = listener.l2_snapshot(&coin, CHECKSUM_LEVELS, None, None);
+                    let snapshot = listener.compute_snapshot();
+                    let checkpoint = snapshot
+                        .as_ref()
+                        .and_then(|snapshot| listener.sign_book(&coin, snapshot.time, snapshot.height));
+                    (snapshot, listener.l4_seq(&coin), levels, checkpoint)
+                };
+                let checksum = levels.map_or(0, |(_, _, levels)| checksum(&levels.export_inner_snapshot()));
+                if let Some(TimedSnapshots { time, height, snapshot }) = snapshot {
+                    let snapshot = snapshot.value().into_iter().filter(|(c, _)| *c == coin).collect::<Vec<_>>().pop();
+                    if let Some((coin, snapshot)) = snapshot {
+                        let snapshot =
+                            snapshot.as_ref().clone().map(|orders| orders.into_iter().map(L4Order::from).collect());
+                        return Ok(Some(ServerResponse::L4Book(L4Book::Snapshot {
+                            coin: coin.value(),
+                            time,
+                            height,
+                            seq,
+                            levels: snapshot,
+                            checksum,
+                            checkpoint,
+                        })));
+                    }
                 }
+                Err("Snapshot Failed".into())
+            }
+            Self::L3Book { coin } => {
+                let coin = Coin::new(coin);
+                let (snapshot, seq) = {
+                    let mut listener = listener.lock().await;
+                    (listener.compute_snapshot(), listener.l4_seq(&coin))
+                };
+                let TimedSnapshots { time, height, snapshot } = snapshot.ok_or("Snapshot Failed")?;
+                let mut snapshot = snapshot.value();
+                let orders = snapshot.remove(&coin).ok_or("Snapshot Failed")?;
+                let levels = orders.as_ref().each_ref().map(|orders| orders.iter().map(L3Order::from).collect());
+                Ok(Some(ServerResponse::L3Book(L3Book::Snapshot { coin: coin.value(), time, height, seq, levels })))
+            }
+            Self::L2Book { coin, n_sig_figs, n_levels, mantissa, .. } => {
+                let n_levels = n_levels.unwrap_or(DEFAULT_LEVELS);
+                let snapshot = {
+                    let listener = listener.lock().await;
+                    self.tick_size().map_or_else(
+                        || listener.l2_snapshot(&Coin::new(coin), n_levels, *n_sig_figs, *mantissa),
+                        |tick| listener.tick_snapshot(&Coin::new(coin), tick, n_levels),
+                    )
+                };
+                let (time, seq, snapshot) = snapshot.ok_or("Snapshot Failed")?;
+                let l2_book = L2Book::from_l2_snapshot(coin.clone(), snapshot.export_inner_snapshot(), time, seq);
+                Ok(Some(ServerResponse::L2Book(l2_book)))
+            }
+            Self::Bbo { coin, .. } => {
+                let snapshot = listener.lock().await.l2_snapshot(&Coin::new(coin), 1, None, None);
+                let (time, seq, snapshot) = snapshot.ok_or("Snapshot Failed")?;
+                let l2_book = L2Book::from_l2_snapshot(coin.clone(), snapshot.export_inner_snapshot(), time, seq);
+                Ok(Some(ServerResponse::Bbo(Bbo::from_l2_book(l2_book))))
+            }
+            // the candle still open, if the market traded since startup
+            Self::Candle { coin, interval } => {
+                let listener = listener.lock().await;
+                Ok(listener.candles().and_then(|candles| candles.latest(coin, *interval)).map(ServerResponse::Candle))
             }
-            return Err("Snapshot Failed".into());
+            // the metrics last published, until the next ones
+            Self::Analytics { coin } => Ok(listener
+                .lock()
+                .await
+                .analytics()
+                .and_then(|analytics| analytics.latest(coin))
+                .map(ServerResponse::Analytics)),
+            // the last checkpoint, until the next one
+            Self::Checkpoint { coin } => Ok(listener
+                .lock()
+                .await
+                .checkpoints()
+                .and_then(|checkpoints| checkpoints.latest(coin))
+                .map(ServerResponse::Checkpoint)),
+            // the status of the last second, until the next one
+            Self::Status => Ok(listener.lock().await.last_status().map(ServerResponse::Status)),
+            // the markets are listed with `listMarkets`, the subscription only gets their changes
+            Self::Trades { .. } | Self::UserOrders { .. } | Self::Markets => Ok(None),
         }
-        Ok(None)
     }
 }
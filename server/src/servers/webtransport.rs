@@ -0,0 +1,392 @@
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use futures_util::{StreamExt, future::join_all};
+use tokio::{select, sync::broadcast::error::RecvError, time::timeout};
+use tokio_util::codec::{FramedRead, LinesCodec, LinesCodecError};
+use tracing::{Instrument, error, field, info, info_span};
+use wtransport::{
+    Connection, Endpoint, Identity, RecvStream, SendStream, ServerConfig as EndpointConfig, VarInt,
+    endpoint::{IncomingSession, SessionRequest, endpoint_side::Server},
+};
+use yawc::{FrameView, close::CloseCode};
+
+use crate::{
+    latency::now_ms,
+    metrics::{METRICS, WireBytes},
+    prelude::*,
+    servers::{
+        auth::{AuthError, Authenticator, ConnectionPermit},
+        limits::ConnectionSlot,
+        protocol::{ErrorCode, ProtocolError},
+        registry::ConnectionStats,
+        replay::Replays,
+        send_queue::{Outgoing, SendQueue},
+        sse::encode,
+        tls::TlsConfig,
+        websocket_server::{
+            ConnectionContext, Universe, admit_message, on_command, on_resume, receive_text, refuse_until_ready,
+            report_slow_consumer, resync_expired, send_internal_message,
+        },
+    },
+    types::subscription::{ClientMessage, ServerResponse, Subscription, SubscriptionManager},
+};
+
+// the path of the session, as that of the websocket endpoint
+const SESSION_PATH: &str = "/ws";
+// QUIC notices a client that is gone by the missing acknowledgements of these, there are no websocket pings
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+// for the client to open the control stream after the session is accepted
+const CONTROL_STREAM_TIMEOUT: Duration = Duration::from_secs(5);
+// for the client to acknowledge what was written before the session is closed
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+// the longest request on the control stream without a message size limit
+const MAX_LINE_BYTES: usize = 64 * 1024;
+
+// An experimental WebTransport (HTTP/3 over QUIC) listener with the protocol of the websocket endpoint. Every
+// subscription gets a unidirectional stream of its own, so that a lost packet only holds up the messages of its
+// subscription instead of those of every subscription, as on a TCP connection.
+// Requests go to the first bidirectional stream the client opens, the control stream, as JSON lines. The
+// responses that aren't data of a subscription come back on it, and each subscription's stream starts with a
+// line of the subscription, followed by its messages. Every line is a message of the first protocol version.
+pub(crate) async fn serve_webtransport(address: SocketAddr, tls: &TlsConfig, context: ConnectionContext) -> Result<()> {
+    let identity = Identity::load_pemfiles(&tls.cert_path, &tls.key_path)
+        .await
+        .map_err(|err| format!("Unable to load the WebTransport certificate: {err}"))?;
+    let config = EndpointConfig::builder()
+        .with_bind_address(address)
+        .with_identity(identity)
+        .keep_alive_interval(Some(KEEPALIVE_INTERVAL))
+        .build();
+    let endpoint = Endpoint::server(config)?;
+    info!("WebTransport server running at https://{address}{SESSION_PATH} (experimental)");
+    tokio::spawn(accept_loop(endpoint, context));
+    Ok(())
+}
+
+async fn accept_loop(endpoint: Endpoint<Server>, context: ConnectionContext) {
+    let shutdown = context.shutdown.clone();
+    loop {
+        let incoming = select! {
+            incoming = endpoint.accept() => incoming,
+            () = shutdown.cancelled() => return,
+        };
+        tokio::spawn(accept_session(incoming, context.clone()));
+    }
+}
+
+// the checks of a websocket upgrade; the credentials have to be in the request, as for server-sent events
+async fn accept_session(incoming: IncomingSession, context: ConnectionContext) {
+    let request = match incoming.await {
+        Ok(request) => request,
+        Err(err) => return info!("WebTransport handshake failed: {err}"),
+    };
+    let address = request.remote_address();
+    if request.path() != SESSION_PATH {
+        return request.not_found().await;
+    }
+    if context.registry.is_maintenance() {
+        info!("Rejecting WebTransport session from {address}: server in maintenance");
+        return request.too_many_requests().await;
+    }
+    if context.standby.is_on() {
+        info!("Rejecting WebTransport session from {address}: server on standby");
+        return request.too_many_requests().await;
+    }
+    let permit = match &context.auth {
+        Some(auth) => {
+            let headers = headers(&request);
+            match Authenticator::credential(&headers).ok_or(AuthError::Missing).and_then(|c| auth.admit(c)) {
+                Ok(permit) => Some(permit),
+                Err(err) => {
+                    info!("Rejecting WebTransport session from {address}: {err}");
+                    if err.is_limit() {
+                        request.too_many_requests().await;
+                    } else {
+                        request.forbidden().await;
+                    }
+                    return;
+                }
+            }
+        }
+        None => None,
+    };
+    let slot = match context.connections.admit(address.ip(), &context.settings.current().connection_limits) {
+        Ok(slot) => slot,
+        Err(err) => {
+            info!("Rejecting WebTransport session from {address}: {err}");
+            return request.too_many_requests().await;
+        }
+    };
+    let connection = match request.accept().await {
+        Ok(connection) => connection,
+        Err(err) => return info!("Unable to accept WebTransport session from {address}: {err}"),
+    };
+    let span = info_span!("connection", client = field::Empty, remote_addr = %address);
+    let shutdown = context.shutdown.clone();
+    shutdown.spawn_connection(session(connection, address, permit, slot, context).instrument(span));
+}
+
+fn headers(request: &SessionRequest) -> HeaderMap {
+    let header = |(name, value): (&String, &String)| {
+        Some((HeaderName::try_from(name.as_str()).ok()?, HeaderValue::try_from(value.as_str()).ok()?))
+    };
+    request.headers().iter().filter_map(header).collect()
+}
+
+async fn session(
+    connection: Connection,
+    address: SocketAddr,
+    permit: Option<ConnectionPermit>,
+    slot: ConnectionSlot,
+    context: ConnectionContext,
+) {
+    let (control, requests) = match timeout(CONTROL_STREAM_TIMEOUT, connection.accept_bi()).await {
+        Ok(Ok(control)) => control,
+        Ok(Err(err)) => return info!("WebTransport session closed before its control stream: {err}"),
+        Err(_) => {
+            info!("Closing WebTransport session without a control stream");
+            return connection.close(VarInt::from_u32(u16::from(CloseCode::Policy).into()), b"no control stream");
+        }
+    };
+    METRICS.connections_total.inc();
+    METRICS.connections.inc();
+    let queue = Arc::new(
+        SendQueue::new(context.backpressure, context.send_queue_capacity).with_ttls(context.message_ttls.clone()),
+    );
+    queue.set_priority(context.load_shedding, permit.as_ref().map(ConnectionPermit::priority));
+    // QUIC's bytes on the wire aren't counted
+    let stats = Arc::new(ConnectionStats::new(WireBytes::default()));
+    let writer = write_loop(connection, control, queue.clone(), stats.clone(), context.latency_metadata);
+    let writer = tokio::spawn(writer.in_current_span());
+    session_loop(requests, address, queue, stats, permit, context).await;
+    let _unused = writer.await;
+    METRICS.connections.dec();
+    drop(slot);
+}
+
+// the connection's main loop, as for a websocket
+async fn session_loop(
+    requests: RecvStream,
+    address: SocketAddr,
+    queue: Arc<SendQueue>,
+    stats: Arc<ConnectionStats>,
+    permit: Option<ConnectionPermit>,
+    context: ConnectionContext,
+) {
+    let identity = permit.as_ref().map(|permit| permit.name().to_string());
+    let tenant = permit.as_ref().and_then(ConnectionPermit::tenant).cloned();
+    let tenant_name = tenant.as_ref().map(|tenant| tenant.name.clone());
+    let mut registration = context.registry.register(address, identity, tenant_name, queue.clone(), stats);
+    let mut settings = context.settings.subscribe();
+    let mut inbound_limit = None;
+    let mut manager = SubscriptionManager::default();
+    manager.set_limit(context.settings.current().max_subscriptions);
+    let max_line = context.settings.current().connection_limits.max_message_bytes.unwrap_or(MAX_LINE_BYTES);
+    let mut requests = FramedRead::new(requests, LinesCodec::new_with_max_length(max_line));
+
+    let mut rx = context.internal_message_tx.subscribe();
+    let mut replays = Replays::new(context.journal);
+    let mut universe = Universe::new(context.markets, context.ignore_spot, tenant.clone()).await;
+    universe.preferences = context.preferences.for_permit(permit.as_ref());
+    refuse_until_ready(&queue, universe.markets.primary()).await;
+    let shutdown = context.shutdown;
+    while !queue.is_closing() {
+        report_slow_consumer(&queue, &registration, &manager);
+        resync_expired(&queue, &mut manager, &universe.markets).await;
+        select! {
+            recv_result = rx.recv() => {
+                match recv_result {
+                    Ok(msg) => {
+                        send_internal_message(&queue, &mut manager, &mut replays, &mut universe, &msg);
+                        on_resume(&queue, &mut manager, &universe.markets, &msg).await;
+                    }
+                    Err(err) => {
+                        if let RecvError::Lagged(n) = err {
+                            METRICS.dropped_messages.inc_by(n);
+                        }
+                        error!("Receiver error: {err}");
+                        queue.abort();
+                    }
+                }
+            }
+
+            (subscription, res) = replays.finished() => replays.finish(&queue, &mut manager, subscription, res),
+
+            command = registration.command() => on_command(command, &queue, &mut manager, &universe.markets).await,
+
+            () = shutdown.cancelled() => {
+                while let Ok(msg) = rx.try_recv() {
+                    send_internal_message(&queue, &mut manager, &mut replays, &mut universe, &msg);
+                }
+                queue.close(shutdown.close_frame());
+            }
+
+            request = requests.next() => match request {
+                Some(Ok(line)) => {
+                    let (tenant, len) = (tenant.as_deref(), line.len());
+                    if admit_message(&queue, &mut settings, &mut inbound_limit, &mut manager, tenant, len) {
+                        receive_text(&queue, &mut manager, &mut replays, &mut rx, line.as_bytes(), &universe).await;
+                    }
+                }
+                Some(Err(LinesCodecError::MaxLineLengthExceeded)) => {
+                    info!("Closing WebTransport session sending a request over {max_line} bytes");
+                    let msg = format!("requests are limited to {max_line} bytes");
+                    queue.push(None, ProtocolError::new(ErrorCode::MessageTooLarge, msg).into());
+                    queue.close(FrameView::close(CloseCode::Size, "message too large"));
+                }
+                Some(Err(LinesCodecError::Io(err))) => {
+                    info!("Client {} session failed: {err}", registration.id());
+                    queue.abort();
+                }
+                None => {
+                    info!("Client {} closed its control stream", registration.id());
+                    queue.abort();
+                }
+            },
+        }
+    }
+    report_slow_consumer(&queue, &registration, &manager);
+    registration.finish(manager.subscriptions().iter().cloned().collect());
+    drop(registration);
+    drop(permit);
+}
+
+// the only place that writes to the session. Pings are left to QUIC, and the close frame closes the session
+// with its code and reason
+async fn write_loop(
+    connection: Connection,
+    mut control: SendStream,
+    queue: Arc<SendQueue>,
+    stats: Arc<ConnectionStats>,
+    latency_metadata: bool,
+) {
+    let mut streams = HashMap::new();
+    let mut close = FrameView::close(CloseCode::Normal, "");
+    while let Some((subscription, outgoing)) = queue.next_routed().await {
+        let (msg, stamps) = match outgoing {
+            Outgoing::Message(msg, stamps) => (msg, stamps),
+            Outgoing::Ping | Outgoing::Time => continue,
+            Outgoing::Close(frame) => {
+                close = frame;
+                break;
+            }
+        };
+        let line = match encode(&msg, stamps, latency_metadata) {
+            Ok(data) => data + "\n",
+            Err(err) => {
+                error!("Server response serialization error: {err}");
+                continue;
+            }
+        };
+        let res = match subscription {
+            Some(subscription) => write_to_stream(&connection, &mut streams, subscription, &line).await,
+            None => control.write_all(line.as_bytes()).await.map_err(Into::into),
+        };
+        if let Err(err) = res {
+            info!("WebTransport write failed: {err}");
+            return queue.abort();
+        }
+        // the subscription's messages end with the acknowledgement on the control stream
+        if let ServerResponse::SubscriptionResponse(ClientMessage::Unsubscribe { subscription }) = msg.msg()
+            && let Some(mut stream) = streams.remove(subscription)
+        {
+            let _unused = stream.finish().await;
+        }
+        if let Some(stamps) = stamps {
+            stamps.observe(now_ms());
+        }
+        METRICS.messages_sent.inc();
+        METRICS.payload_bytes.inc_by(line.len() as u64);
+        stats.record(1, line.len() as u64);
+    }
+    let finished = streams.values_mut().chain([&mut control]).map(SendStream::finish);
+    let _unused = timeout(CLOSE_TIMEOUT, join_all(finished)).await;
+    let code = close.close_code().map_or(1000, u16::from);
+    connection.close(VarInt::from_u32(code.into()), close.close_reason().unwrap_or_default().as_bytes());
+}
+
+// a subscription's first message opens its stream, which starts with the subscription
+async fn write_to_stream(
+    connection: &Connection,
+    streams: &mut HashMap<Subscription, SendStream>,
+    subscription: Subscription,
+    line: &str,
+) -> Result<()> {
+    let stream = match streams.entry(subscription) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => {
+            let mut stream = connection.open_uni().await?.await?;
+            let header = serde_json::to_string(&ClientMessage::Subscribe { subscription: entry.key().clone() })?;
+            stream.write_all((header + "\n").as_bytes()).await?;
+            entry.insert(stream)
+        }
+    };
+    stream.write_all(line.as_bytes()).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncBufReadExt, BufReader};
+    use wtransport::{ClientConfig, error::ConnectionError};
+
+    use super::*;
+    use crate::servers::send_queue::BackpressurePolicy;
+
+    async fn read_line(lines: &mut (impl AsyncBufReadExt + Unpin)) -> Result<String> {
+        let mut line = String::new();
+        lines.read_line(&mut line).await?;
+        Ok(line.trim_end().to_string())
+    }
+
+    #[tokio::test]
+    async fn test_subscription_streams() -> Result<()> {
+        let identity = Identity::self_signed(["localhost"])?;
+        let config = EndpointConfig::builder().with_bind_default(0).with_identity(identity).build();
+        let server = Endpoint::server(config)?;
+        let port = server.local_addr()?.port();
+        let client = Endpoint::client(ClientConfig::builder().with_bind_default().with_no_cert_validation().build())?;
+        let (client, server) = tokio::join!(client.connect(format!("https://localhost:{port}{SESSION_PATH}")), async {
+            server.accept().await.await?.accept().await.map_err(Error::from)
+        },);
+        let (client, server) = (client?, server?);
+        let (mut requests, responses) = client.open_bi().await?.await?;
+        requests.write_all(b"\n").await?;
+        let (control, _) = server.accept_bi().await?;
+
+        let queue = Arc::new(SendQueue::new(BackpressurePolicy::default(), 10));
+        let writer = tokio::spawn(write_loop(server, control, queue.clone(), Arc::default(), false));
+        let trades = Subscription::Trades { coin: "BTC".to_string() };
+        let subscribe = ClientMessage::Subscribe { subscription: trades.clone() };
+        queue.push(None, ServerResponse::SubscriptionResponse(subscribe));
+        queue.push(Some(&trades), ServerResponse::Trades(Vec::new()));
+        queue.push(None, ServerResponse::SubscriptionResponse(ClientMessage::Unsubscribe { subscription: trades }));
+        queue.close(FrameView::close(CloseCode::Away, "shutting down"));
+
+        let mut responses = BufReader::new(responses);
+        let response = read_line(&mut responses).await?;
+        assert!(response.starts_with(r#"{"channel":"subscriptionResponse","data":{"method":"subscribe""#));
+        let mut stream = BufReader::new(client.accept_uni().await?);
+        let header = r#"{"method":"subscribe","subscription":{"type":"trades","coin":"BTC"}}"#;
+        assert_eq!(read_line(&mut stream).await?, header);
+        assert_eq!(read_line(&mut stream).await?, r#"{"channel":"trades","data":[]}"#);
+        // finished once the client unsubscribed
+        assert_eq!(read_line(&mut stream).await?, "");
+        let response = read_line(&mut responses).await?;
+        assert!(response.starts_with(r#"{"channel":"subscriptionResponse","data":{"method":"unsubscribe""#));
+
+        writer.await?;
+        let ConnectionError::ApplicationClosed(close) = client.closed().await else {
+            return Err("the session wasn't closed by the server".into());
+        };
+        assert_eq!(close.code(), VarInt::from_u32(1001));
+        assert_eq!(close.reason(), b"shutting down");
+        Ok(())
+    }
+}
@@ -0,0 +1,118 @@
+use std::{cell::RefCell, path::Path};
+
+use axum::{
+    http::header::{CONTENT_TYPE, HeaderName},
+    response::{IntoResponse, Response},
+};
+use bytes::Bytes;
+use zstd::{
+    dict::EncoderDictionary,
+    zstd_safe::{self, CCtx},
+};
+
+use crate::prelude::*;
+
+// level 3 compresses these payloads better and faster than deflate at 9
+const LEVEL: i32 = 3;
+
+// trained on the MessagePack l2 books, l4 snapshots and l4 updates of the benchmarks' books; `zstd_dictionary` in
+// the binaries trains one on the frames of a running server
+const BUILT_IN: &[u8] = include_bytes!("../../assets/orderbook.zstd.dict");
+
+const ID_HEADER: HeaderName = HeaderName::from_static("x-zstd-dictionary-id");
+
+thread_local! {
+    // a compression context per thread, reused for every frame
+    static CONTEXT: RefCell<CCtx<'static>> = RefCell::new(CCtx::create());
+}
+
+/// The zstd dictionary `MessagePack` frames are compressed with on connections that negotiated zstd. Clients
+/// decompress with the same dictionary, which the server serves at `/zstd/dictionary`.
+pub(crate) struct ZstdDictionary {
+    raw: Bytes,
+    id: u32,
+    encoder: EncoderDictionary<'static>,
+}
+
+impl ZstdDictionary {
+    pub(crate) fn built_in() -> Result<Self> {
+        Self::new(Bytes::from_static(BUILT_IN))
+    }
+
+    pub(crate) fn from_file(path: &Path) -> Result<Self> {
+        let raw = fs::read(path).map_err(|err| format!("unable to read {}: {err}", path.display()))?;
+        Self::new(raw.into())
+    }
+
+    fn new(raw: Bytes) -> Result<Self> {
+        // raw content dictionaries have no id, so clients couldn't tell which one a frame needs
+        let id = zstd_safe::get_dict_id_from_dict(&raw).ok_or("not a zstd dictionary")?.get();
+        let encoder = EncoderDictionary::copy(&raw, LEVEL);
+        Ok(Self { raw, id, encoder })
+    }
+
+    // the dictionary for clients to decompress with, and its id for them to check frames against
+    pub(crate) fn response(&self) -> Response {
+        let headers = [(CONTENT_TYPE, "application/octet-stream".to_string()), (ID_HEADER, self.id.to_string())];
+        (headers, self.raw.clone()).into_response()
+    }
+
+    // a zstd frame of the payload, with the id of the dictionary in its header
+    pub(crate) fn compress(&self, payload: &[u8]) -> Result<Bytes> {
+        CONTEXT.with_borrow_mut(|context| {
+            let mut compressed = Vec::with_capacity(zstd_safe::compress_bound(payload.len()));
+            context
+                .compress_using_cdict(&mut compressed, payload, self.encoder.as_cdict())
+                .map_err(|code| zstd_safe::get_error_name(code))?;
+            Ok(compressed.into())
+        })
+    }
+}
+
+/// Trains a zstd dictionary of at most `max_size` bytes on the payloads of `MessagePack` frames, for
+/// [`ServerConfig::zstd_dictionary`](crate::ServerConfig::zstd_dictionary).
+pub fn train_zstd_dictionary(samples: &[Vec<u8>], max_size: usize) -> Result<Vec<u8>> {
+    Ok(zstd::dict::from_samples(samples, max_size).map_err(|err| format!("unable to train dictionary: {err}"))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU32;
+
+    use zstd::bulk::Decompressor;
+
+    use super::*;
+    use crate::{
+        servers::{
+            encoding::{Encoding, Version},
+            outbound::batch_payload,
+            protocol::Versioned,
+        },
+        types::subscription::ServerResponse,
+    };
+
+    const L2_BOOK: &str = r#"{"channel":"l2Book","data":{"coin":"BTC","time":1751430933565,"levels":[[{"px":"106296.0","sz":"0.5","n":2},{"px":"106295.0","sz":"1.25","n":3}],[{"px":"106297.0","sz":"0.017","n":1}]]}}"#;
+
+    #[test]
+    fn test_round_trip() -> Result<()> {
+        let dictionary = ZstdDictionary::built_in()?;
+        let msg: ServerResponse = serde_json::from_str(L2_BOOK)?;
+        let payload = Encoding::MessagePack.encode(&Versioned::new(&msg, Version::V1))?.payload;
+        let batch = batch_payload(Encoding::MessagePack, &[payload.clone(), payload.clone(), payload]);
+        let compressed = dictionary.compress(&batch)?;
+        assert!(compressed.len() < batch.len());
+        // the frame names its dictionary
+        assert_eq!(zstd_safe::get_dict_id_from_frame(&compressed).map(NonZeroU32::get), Some(dictionary.id));
+
+        let decompressed = Decompressor::with_dictionary(&dictionary.raw)?.decompress(&compressed, batch.len())?;
+        assert_eq!(decompressed, batch);
+        let decoded: Vec<ServerResponse> = rmp_serde::from_slice(&decompressed)?;
+        assert_eq!(serde_json::to_string(&decoded[2])?, serde_json::to_string(&msg)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rejects_raw_content() {
+        assert!(ZstdDictionary::new(Bytes::from_static(b"{\"channel\":\"l2Book\"}")).is_err());
+    }
+}
@@ -0,0 +1,169 @@
+use std::{collections::HashMap, path::PathBuf, sync::Arc, time::Duration};
+
+use alloy::hex;
+use axum::http::HeaderName;
+use ring::{
+    digest::{SHA256, digest},
+    signature::{Ed25519KeyPair, KeyPair},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    prelude::*,
+    types::{CHECKSUM_LEVELS, Level, checksum},
+};
+
+/// Ed25519 signatures of the books, so that clients can prove that the data they stored came from this server.
+/// The l4 snapshots carry a signed checkpoint of their book, and every book is checkpointed on the `checkpoint`
+/// channel every `checkpoint_interval`.
+// the response header of the websocket upgrade and of `/stream` with the public key, hex encoded
+pub(crate) const SIGNING_KEY_HEADER: HeaderName = HeaderName::from_static("x-signing-key");
+
+#[derive(Debug, Clone)]
+pub struct SigningConfig {
+    /// File holding the 32 byte seed of the private key, hex encoded, e.g. written by `openssl rand -hex 32`.
+    pub key_file: PathBuf,
+    /// How often every book is checkpointed, in block time.
+    pub checkpoint_interval: Duration,
+}
+
+impl SigningConfig {
+    #[must_use]
+    pub const fn new(key_file: PathBuf) -> Self {
+        Self { key_file, checkpoint_interval: Duration::from_secs(10) }
+    }
+}
+
+// the state of a coin's book at `seq`, signed by the server. `checksum` is the book's checksum, `digest` the SHA-256
+// of the string the checksum is taken of, but over every level, and `signature` the Ed25519 signature of
+// `coin:time:height:seq:checksum:digest`. Both are hex encoded
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Checkpoint {
+    pub coin: String,
+    pub time: u64,
+    pub height: u64,
+    pub seq: u64,
+    pub checksum: u32,
+    pub digest: String,
+    pub signature: String,
+}
+
+pub(crate) struct Signer {
+    key: Ed25519KeyPair,
+}
+
+impl Signer {
+    pub(crate) fn load(config: &SigningConfig) -> Result<Self> {
+        let seed = fs::read_to_string(&config.key_file)
+            .map_err(|err| format!("Unable to read the signing key {}: {err}", config.key_file.display()))?;
+        let seed = hex::decode(seed.trim()).map_err(|err| format!("The signing key isn't hex encoded: {err}"))?;
+        let key = Ed25519KeyPair::from_seed_unchecked(&seed)
+            .map_err(|_| format!("The signing key has to be 32 bytes, not {}", seed.len()))?;
+        Ok(Self { key })
+    }
+
+    // hex encoded, as sent to clients
+    pub(crate) fn public_key(&self) -> String {
+        hex::encode(self.key.public_key().as_ref())
+    }
+
+    // `levels` are every level of the book, best first
+    pub(crate) fn checkpoint(
+        &self,
+        coin: String,
+        time: u64,
+        height: u64,
+        seq: u64,
+        levels: &[Vec<Level>; 2],
+    ) -> Checkpoint {
+        let top = levels.clone().map(|side| side.into_iter().take(CHECKSUM_LEVELS).collect());
+        let checksum = checksum(&top);
+        let digest = hex::encode(digest(&SHA256, book_string(levels).as_bytes()));
+        let signature =
+            hex::encode(self.key.sign(format!("{coin}:{time}:{height}:{seq}:{checksum}:{digest}").as_bytes()));
+        Checkpoint { coin, time, height, seq, checksum, digest, signature }
+    }
+}
+
+// `bid px:bid sz:ask px:ask sz:...`, like the checksum
+fn book_string([bids, asks]: &[Vec<Level>; 2]) -> String {
+    let mut fields = Vec::with_capacity(2 * (bids.len() + asks.len()));
+    for i in 0..bids.len().max(asks.len()) {
+        for level in [bids.get(i), asks.get(i)].into_iter().flatten() {
+            fields.push(level.px.as_str());
+            fields.push(level.sz.as_str());
+        }
+    }
+    fields.join(":")
+}
+
+// the checkpoints of a listener's books, due every checkpoint interval of block time
+pub(crate) struct Checkpoints {
+    signer: Arc<Signer>,
+    interval: u64,
+    last: Option<u64>,
+    latest: HashMap<String, Checkpoint>,
+}
+
+impl Checkpoints {
+    pub(crate) fn new(signer: Arc<Signer>, interval: Duration) -> Self {
+        let interval = u64::try_from(interval.as_millis()).unwrap_or(u64::MAX);
+        Self { signer, interval, last: None, latest: HashMap::new() }
+    }
+
+    pub(crate) const fn signer(&self) -> &Arc<Signer> {
+        &self.signer
+    }
+
+    pub(crate) const fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval)
+    }
+
+    // true once per interval; the books are checkpointed then
+    pub(crate) fn is_due(&mut self, time: u64) -> bool {
+        if self.last.is_some_and(|last| time < last.saturating_add(self.interval)) {
+            return false;
+        }
+        self.last = Some(time);
+        true
+    }
+
+    pub(crate) fn publish(&mut self, checkpoints: &[Checkpoint]) {
+        self.latest = checkpoints.iter().map(|checkpoint| (checkpoint.coin.clone(), checkpoint.clone())).collect();
+    }
+
+    pub(crate) fn latest(&self, coin: &str) -> Option<Checkpoint> {
+        self.latest.get(coin).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::signature::{ED25519, UnparsedPublicKey};
+
+    use super::*;
+
+    #[test]
+    fn test_checkpoint() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let key_file = dir.path().join("signing.key");
+        fs::write(&key_file, format!("{}\n", "07".repeat(32)))?;
+        let signer = Signer::load(&SigningConfig::new(key_file.clone()))?;
+
+        let level = |px: &str, sz: &str| Level::new(px.to_string(), sz.to_string(), 1);
+        let levels = [vec![level("3366.1", "7"), level("3366", "6")], vec![level("3366.8", "9")]];
+        let checkpoint = signer.checkpoint("BTC".to_string(), 1, 2, 3, &levels);
+        assert_eq!(checkpoint.checksum, checksum(&levels));
+        assert_eq!(checkpoint.digest, hex::encode(digest(&SHA256, b"3366.1:7:3366.8:9:3366:6")));
+        // what a client checks, with the public key from the handshake
+        let public_key = UnparsedPublicKey::new(&ED25519, hex::decode(signer.public_key())?);
+        let message = format!("BTC:1:2:3:{}:{}", checkpoint.checksum, checkpoint.digest);
+        assert!(public_key.verify(message.as_bytes(), &hex::decode(&checkpoint.signature)?).is_ok());
+        assert!(public_key.verify(b"BTC:1:2:4", &hex::decode(&checkpoint.signature)?).is_err());
+
+        fs::write(&key_file, "0707")?;
+        assert!(Signer::load(&SigningConfig::new(key_file)).is_err());
+        Ok(())
+    }
+}
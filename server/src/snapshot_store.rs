@@ -0,0 +1,267 @@
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use alloy::primitives::Address;
+use flate2::{Compression, read::GzDecoder, write::GzEncoder};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    select,
+    sync::Mutex,
+    task::{JoinHandle, spawn_blocking},
+    time::{MissedTickBehavior, interval},
+};
+use tracing::{error, info, warn};
+
+use crate::{listeners::order_book::OrderBookListener, prelude::*, servers::shutdown::Shutdown, types::L4Order};
+
+// large books take a while to transfer
+const REDIS_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where the order book state is kept between restarts. A restarted server continues from the saved state and
+/// the node's events after it, instead of waiting for a fresh snapshot from the node.
+///
+/// Calls block, and are made from a blocking task.
+pub trait SnapshotStore: Debug + Send + Sync {
+    fn save(&self, state: &[u8]) -> Result<()>;
+    /// `None` if nothing was saved yet.
+    fn load(&self) -> Result<Option<Vec<u8>>>;
+}
+
+/// How often the order book state is saved, and where to. It is also saved once more on shutdown.
+#[derive(Debug, Clone)]
+pub struct SnapshotStoreConfig {
+    pub store: Arc<dyn SnapshotStore>,
+    pub interval: Duration,
+}
+
+impl SnapshotStoreConfig {
+    #[must_use]
+    pub const fn new(store: Arc<dyn SnapshotStore>) -> Self {
+        Self { store, interval: Duration::from_secs(30) }
+    }
+}
+
+/// Keeps the state in a file, replaced as a whole on every save.
+#[derive(Debug, Clone)]
+pub struct FileSnapshotStore {
+    pub path: PathBuf,
+}
+
+impl SnapshotStore for FileSnapshotStore {
+    fn save(&self, state: &[u8]) -> Result<()> {
+        // a crash while writing leaves the previous state in place
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, state)?;
+        Ok(fs::rename(tmp, &self.path)?)
+    }
+
+    fn load(&self) -> Result<Option<Vec<u8>>> {
+        match fs::read(&self.path) {
+            Ok(state) => Ok(Some(state)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// Keeps the state under `key` on the Redis server at `address` (`host:port`).
+#[derive(Debug, Clone)]
+pub struct RedisSnapshotStore {
+    pub address: String,
+    pub key: String,
+}
+
+impl RedisSnapshotStore {
+    #[must_use]
+    pub fn new(address: String) -> Self {
+        Self { address, key: "orderbook:snapshot".to_string() }
+    }
+
+    // sends a single command on a fresh connection, returning its bulk string reply
+    fn command(&self, args: &[&[u8]]) -> Result<Option<Vec<u8>>> {
+        let mut stream = TcpStream::connect(&self.address)?;
+        stream.set_read_timeout(Some(REDIS_TIMEOUT))?;
+        stream.set_write_timeout(Some(REDIS_TIMEOUT))?;
+        let mut request = format!("*{}\r\n", args.len()).into_bytes();
+        for arg in args {
+            request.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+            request.extend_from_slice(arg);
+            request.extend_from_slice(b"\r\n");
+        }
+        stream.write_all(&request)?;
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let line = line.trim_end();
+        match line.split_at_checked(1) {
+            Some(("+", _) | ("$", "-1")) => Ok(None),
+            Some(("-", err)) => Err(format!("Redis error: {err}").into()),
+            Some(("$", len)) => {
+                let len = len.parse::<usize>()?;
+                let mut value = vec![0; len + 2];
+                reader.read_exact(&mut value)?;
+                value.truncate(len);
+                Ok(Some(value))
+            }
+            _ => Err(format!("unexpected reply from Redis: {line:?}").into()),
+        }
+    }
+}
+
+impl SnapshotStore for RedisSnapshotStore {
+    fn save(&self, state: &[u8]) -> Result<()> {
+        self.command(&[b"SET", self.key.as_bytes(), state]).map(|_| ())
+    }
+
+    fn load(&self) -> Result<Option<Vec<u8>>> {
+        self.command(&[b"GET", self.key.as_bytes()])
+    }
+}
+
+// the orders of a book, bids first
+pub(crate) type StoredBook = [Vec<(Address, L4Order)>; 2];
+
+// the books in the node's snapshot format, with the sequence numbers to continue from. Saved as gzipped JSON
+#[derive(Serialize, Deserialize)]
+pub(crate) struct StoredBooks {
+    pub(crate) height: u64,
+    pub(crate) time: u64,
+    pub(crate) l2_seq: u64,
+    pub(crate) l4_seqs: HashMap<String, u64>,
+    pub(crate) books: Vec<(String, StoredBook)>,
+    // whether the l2 books of this block were already published
+    #[serde(default)]
+    pub(crate) snapped: bool,
+    // last block whose fills were published, if not the block of the books
+    #[serde(default)]
+    pub(crate) last_fill: Option<u64>,
+}
+
+impl StoredBooks {
+    pub(crate) fn encode(&self) -> Result<Vec<u8>> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+        serde_json::to_writer(&mut encoder, self)?;
+        Ok(encoder.finish()?)
+    }
+
+    pub(crate) fn decode(state: &[u8]) -> Result<Self> {
+        Ok(serde_json::from_reader(GzDecoder::new(state))?)
+    }
+}
+
+// restores the saved state and saves it from then on. The returned task ends after the last save on shutdown
+pub(crate) async fn start_snapshot_store(
+    listener: &Arc<Mutex<OrderBookListener>>,
+    config: Option<SnapshotStoreConfig>,
+    shutdown: &Shutdown,
+) -> Option<JoinHandle<()>> {
+    let config = config?;
+    restore(listener, config.store.clone()).await;
+    Some(spawn_snapshot_saver(listener.clone(), config, shutdown.clone()))
+}
+
+// loads the saved state into the listener, which takes it up once the node's events continue where it ends
+async fn restore(listener: &Mutex<OrderBookListener>, store: Arc<dyn SnapshotStore>) {
+    let res = spawn_blocking(move || store.load()?.map(|state| StoredBooks::decode(&state)).transpose()).await;
+    match res.map_err(Error::from).and_then(|res| res) {
+        Ok(Some(books)) => {
+            let height = books.height;
+            let res = listener.lock().await.restore(books);
+            match res {
+                Ok(()) => info!("Loaded the order book state of block {height}, catching up from the node's events"),
+                Err(err) => warn!("Unable to use the saved order book state: {err}"),
+            }
+        }
+        Ok(None) => info!("No saved order book state, waiting for a snapshot from the node"),
+        Err(err) => warn!("Unable to load the saved order book state: {err}"),
+    }
+}
+
+// saves the state every interval and once more on shutdown
+fn spawn_snapshot_saver(
+    listener: Arc<Mutex<OrderBookListener>>,
+    config: SnapshotStoreConfig,
+    shutdown: Shutdown,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = interval(config.interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        ticker.tick().await;
+        loop {
+            let stopping = select! {
+                _ = ticker.tick() => false,
+                () = shutdown.cancelled() => true,
+            };
+            if let Some(books) = OrderBookListener::stored_books(&listener).await {
+                let height = books.height;
+                let store = config.store.clone();
+                let res = spawn_blocking(move || store.save(&books.encode()?)).await;
+                match res.map_err(Error::from).and_then(|res| res) {
+                    Ok(()) => info!("Saved the order book state of block {height}"),
+                    Err(err) => error!("Unable to save the order book state: {err}"),
+                }
+            }
+            if stopping {
+                return;
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, thread};
+
+    use super::*;
+
+    #[test]
+    fn test_redis_store() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let store = RedisSnapshotStore::new(listener.local_addr()?.to_string());
+        let server = thread::spawn(move || -> Result<Vec<u8>> {
+            let mut requests = Vec::new();
+            for reply in [&b"+OK\r\n"[..], b"$5\r\nstate\r\n", b"$-1\r\n"] {
+                let (mut stream, _) = listener.accept()?;
+                let mut buf = [0; 256];
+                let n = stream.read(&mut buf)?;
+                requests.extend_from_slice(&buf[..n]);
+                stream.write_all(reply)?;
+            }
+            Ok(requests)
+        });
+        store.save(b"state")?;
+        assert_eq!(store.load()?, Some(b"state".to_vec()));
+        assert_eq!(store.load()?, None);
+        let requests = server.join().unwrap()?;
+        assert!(
+            requests.starts_with(b"*3\r\n$3\r\nSET\r\n$18\r\norderbook:snapshot\r\n$5\r\nstate\r\n*2\r\n$3\r\nGET\r\n")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_store() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let store = FileSnapshotStore { path: dir.path().join("books.json.gz") };
+        assert_eq!(store.load()?, None);
+        let books = StoredBooks {
+            height: 5,
+            time: 1,
+            l2_seq: 2,
+            l4_seqs: HashMap::new(),
+            books: Vec::new(),
+            snapped: true,
+            last_fill: None,
+        };
+        store.save(&books.encode()?)?;
+        let loaded = StoredBooks::decode(&store.load()?.unwrap())?;
+        assert_eq!((loaded.height, loaded.l2_seq), (5, 2));
+        Ok(())
+    }
+}
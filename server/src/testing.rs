@@ -0,0 +1,89 @@
+//! Entry points for the integration tests in `tests/`: the server fed by a script of node events instead of a node,
+//! to test the whole pipeline with.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    ServerConfig,
+    listeners::order_book::{RelaySnapshot, SourceEvent, UpstreamSource},
+    prelude::*,
+    servers::websocket_server::{Source, run_with_source},
+    types::{UpstreamStatus, node_data::EventSource},
+};
+
+/// Node events played to the server in order, in place of a node's event files. It reports a single connected
+/// upstream, `script`, and ends after the last event, leaving the books as they are.
+pub struct ScriptedSource {
+    events: VecDeque<SourceEvent>,
+    // why the listener asked for a new state, e.g. because the events skipped blocks
+    pub(crate) resyncs: Arc<Mutex<Vec<String>>>,
+}
+
+impl ScriptedSource {
+    #[must_use]
+    pub fn new() -> Self {
+        let status = UpstreamStatus { node: "script".to_string(), connected: true };
+        Self { events: VecDeque::from([SourceEvent::Upstreams(vec![status])]), resyncs: Arc::default() }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn from_events(events: impl IntoIterator<Item = SourceEvent>) -> Self {
+        Self { events: events.into_iter().collect(), resyncs: Arc::default() }
+    }
+
+    /// Starts the books over, without orders, at block `height`. The events of the blocks after it add the orders.
+    #[must_use]
+    pub fn empty_books(mut self, height: u64) -> Self {
+        self.events.push_back(SourceEvent::State(Box::new(RelaySnapshot::empty(height))));
+        self
+    }
+
+    /// Lines of the node's order status files, a block each.
+    #[must_use]
+    pub fn order_statuses(self, lines: impl Into<String>) -> Self {
+        self.events(EventSource::OrderStatuses, lines.into())
+    }
+
+    /// Lines of the node's raw book diff files, a block each.
+    #[must_use]
+    pub fn order_diffs(self, lines: impl Into<String>) -> Self {
+        self.events(EventSource::OrderDiffs, lines.into())
+    }
+
+    /// Lines of the node's fill files, a block each.
+    #[must_use]
+    pub fn fills(self, lines: impl Into<String>) -> Self {
+        self.events(EventSource::Fills, lines.into())
+    }
+
+    fn events(mut self, event_source: EventSource, data: String) -> Self {
+        self.events.push_back(SourceEvent::Events { event_source, data });
+        self
+    }
+}
+
+impl Default for ScriptedSource {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UpstreamSource for ScriptedSource {
+    async fn next_event(&mut self) -> Result<Option<SourceEvent>> {
+        Ok(self.events.pop_front())
+    }
+
+    fn resync(&mut self, err: Error) -> Result<()> {
+        self.resyncs.lock().map_err(|_| "poisoned")?.push(err.to_string());
+        Ok(())
+    }
+}
+
+/// Runs the server like [`run_websocket_server`](crate::run_websocket_server), with the events of `source` in
+/// place of `config.upstreams`.
+pub async fn run_scripted_server(config: ServerConfig, source: ScriptedSource) -> Result<()> {
+    run_with_source(config, Some(Source::Scripted(source))).await
+}
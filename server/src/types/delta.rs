@@ -0,0 +1,137 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::types::{L2Book, Level, subscription::MAX_LEVELS};
+
+// most prices an epoch references per side before a full book starts a new one
+const MAX_PRICES: usize = 4 * MAX_LEVELS;
+
+/// The changes to the l2 book of a delta subscription since the previous message, which referenced its levels by
+/// index. The indices of a side are those of its levels in the l2 book that started the epoch, followed by the
+/// prices that were referenced for the first time since, in that order.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct L2Delta {
+    pub coin: String,
+    pub time: u64,
+    pub seq: u64,
+    // of the l2 book or delta it applies to
+    pub prev_seq: u64,
+    // seq of the l2 book that started the epoch
+    pub epoch: u64,
+    // bids, asks
+    pub changes: [Vec<LevelChange>; 2],
+    // of the levels of the book after the changes
+    pub checksum: u32,
+}
+
+/// A level that changed, or was removed with a size of 0.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct LevelChange {
+    pub i: usize,
+    // only the first time the price is referenced in the epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub px: Option<String>,
+    pub sz: String,
+    pub n: usize,
+}
+
+/// The indices of the prices a delta subscription referenced since its last full book.
+#[derive(Debug, Clone)]
+pub(crate) struct Epoch {
+    // of the l2 book that started it
+    seq: u64,
+    indices: [HashMap<String, usize>; 2],
+}
+
+impl Epoch {
+    pub(crate) fn new(book: &L2Book) -> Self {
+        let indices = book
+            .levels
+            .each_ref()
+            .map(|levels| levels.iter().enumerate().map(|(i, level)| (level.px.clone(), i)).collect());
+        Self { seq: book.seq, indices }
+    }
+
+    // `book` as its changes to `sent`, the book last sent. None once the epoch references too many prices, or if
+    // `sent` isn't of the epoch, for `book` to be sent in full and start a new one
+    pub(crate) fn delta(&mut self, sent: &L2Book, book: &L2Book) -> Option<L2Delta> {
+        let mut changes: [Vec<LevelChange>; 2] = Default::default();
+        for (side, changes) in changes.iter_mut().enumerate() {
+            let indices = &mut self.indices[side];
+            let (sent, levels) = (&sent.levels[side], &book.levels[side]);
+            let kept = levels.iter().map(|level| level.px.as_str()).collect::<HashSet<_>>();
+            for level in sent.iter().filter(|level| !kept.contains(level.px.as_str())) {
+                changes.push(LevelChange { i: *indices.get(&level.px)?, px: None, sz: "0".to_string(), n: 0 });
+            }
+            let sent = sent.iter().map(|level| (level.px.as_str(), level)).collect::<HashMap<_, _>>();
+            for level in levels.iter().filter(|level| sent.get(level.px.as_str()) != Some(level)) {
+                let Level { px, sz, n } = level.clone();
+                let next = indices.len();
+                let i = *indices.entry(px.clone()).or_insert(next);
+                changes.push(LevelChange { i, px: (i == next).then_some(px), sz, n });
+            }
+            if indices.len() > MAX_PRICES {
+                return None;
+            }
+        }
+        Some(L2Delta {
+            coin: book.coin.clone(),
+            time: book.time,
+            seq: book.seq,
+            prev_seq: sent.seq,
+            epoch: self.seq,
+            changes,
+            checksum: book.checksum,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn book(seq: u64, bids: &[(&str, &str)], asks: &[(&str, &str)]) -> L2Book {
+        let levels = |levels: &[(&str, &str)]| {
+            levels.iter().map(|(px, sz)| Level::new((*px).to_string(), (*sz).to_string(), 1)).collect()
+        };
+        L2Book::from_l2_snapshot("BTC".to_string(), [levels(bids), levels(asks)], seq, seq)
+    }
+
+    fn change(i: usize, px: Option<&str>, sz: &str, n: usize) -> LevelChange {
+        LevelChange { i, px: px.map(str::to_string), sz: sz.to_string(), n }
+    }
+
+    #[test]
+    fn test_epoch_deltas() {
+        let first = book(1, &[("100", "1"), ("99", "2")], &[("101", "1")]);
+        let mut epoch = Epoch::new(&first);
+        let second = book(2, &[("100", "3"), ("98", "1")], &[("101", "1")]);
+        let delta = epoch.delta(&first, &second).unwrap();
+        assert_eq!((delta.seq, delta.prev_seq, delta.epoch, delta.checksum), (2, 1, 1, second.checksum));
+        // the new price takes the next index, the removed one keeps its own
+        assert_eq!(
+            delta.changes,
+            [vec![change(1, None, "0", 0), change(0, None, "3", 1), change(2, Some("98"), "1", 1)], Vec::new()]
+        );
+        // a price referenced before is sent by its index only
+        let third = book(3, &[("100", "3"), ("99", "5")], &[("101", "1")]);
+        let delta = epoch.delta(&second, &third).unwrap();
+        assert_eq!(delta.changes, [vec![change(2, None, "0", 0), change(1, None, "5", 1)], Vec::new()]);
+
+        // a sent level the epoch doesn't know, or too many prices, start a new one
+        assert!(Epoch::new(&first).delta(&book(4, &[("97", "1")], &[]), &first).is_none());
+        let mut epoch = Epoch::new(&first);
+        let mut sent = first;
+        for seq in 0..MAX_PRICES as u64 {
+            let next = book(seq + 2, &[(&(1000 + seq).to_string(), "1")], &[]);
+            if epoch.delta(&sent, &next).is_none() {
+                assert!(seq > 0);
+                return;
+            }
+            sent = next;
+        }
+        panic!("the epoch wasn't restarted");
+    }
+}
@@ -7,7 +7,7 @@ use crate::{
         types::{Coin, InnerOrder, Px, Side, Sz},
     },
     prelude::*,
-    types::{L4Order, OrderDiff, node_data::NodeDataOrderStatus},
+    types::{L3Order, L4Order, OrderDiff, node_data::NodeDataOrderStatus},
 };
 
 // L4Order: the struct we keep in the orderbook (computationally better)
@@ -121,6 +121,12 @@ impl TryFrom<(Address, L4Order)> for InnerL4Order {
     }
 }
 
+impl From<&InnerL4Order> for L3Order {
+    fn from(order: &InnerL4Order) -> Self {
+        Self { oid: order.oid, px: order.limit_px.to_str(), sz: order.sz.to_str(), timestamp: order.timestamp }
+    }
+}
+
 impl From<InnerL4Order> for L4Order {
     fn from(value: InnerL4Order) -> Self {
         let InnerL4Order {
@@ -185,14 +191,8 @@ impl From<InnerLevel> for Level {
 
 #[derive(Debug, Clone)]
 pub(crate) enum InnerOrderDiff {
-    New {
-        sz: Sz,
-    },
-    #[allow(dead_code)]
-    Update {
-        orig_sz: Sz,
-        new_sz: Sz,
-    },
+    New { sz: Sz },
+    Update { orig_sz: Sz, new_sz: Sz },
     Remove,
 }
 
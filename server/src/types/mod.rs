@@ -1,34 +1,46 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 
 use alloy::primitives::Address;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    order_book::types::Side,
-    types::node_data::{NodeDataFill, NodeDataOrderDiff, NodeDataOrderStatus},
+    order_book::{
+        Oid,
+        types::{Px, Side},
+    },
+    signing::Checkpoint,
+    types::node_data::{Batch, NodeDataFill, NodeDataOrderDiff, NodeDataOrderStatus},
 };
 
+pub(crate) mod delta;
 pub(crate) mod inner;
 pub(crate) mod node_data;
 pub(crate) mod subscription;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct Trade {
     pub coin: String,
-    side: Side,
-    px: String,
-    sz: String,
-    hash: String,
-    time: u64,
-    tid: u64,
-    users: [Address; 2],
+    // the taker's side
+    pub side: Side,
+    pub px: String,
+    pub sz: String,
+    pub hash: String,
+    pub time: u64,
+    pub tid: u64,
+    // buyer, seller
+    pub users: [Address; 2],
+    // the users again, by whether they took or provided liquidity
+    #[serde(default)]
+    pub taker: Address,
+    #[serde(default)]
+    pub maker: Address,
 }
 
-#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub(crate) struct Level {
-    px: String,
-    sz: String,
-    n: usize,
+    pub px: String,
+    pub sz: String,
+    pub n: usize,
 }
 
 impl Level {
@@ -37,22 +49,327 @@ impl Level {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct L2Book {
-    coin: String,
-    time: u64,
-    levels: [Vec<Level>; 2],
+    pub coin: String,
+    pub time: u64,
+    // bids, asks
+    pub levels: [Vec<Level>; 2],
+    // every l2 message is a full book, so a gap in seq only means intermediate books were skipped
+    #[serde(default)]
+    pub seq: u64,
+    // of the levels in this message
+    #[serde(default)]
+    pub checksum: u32,
+}
+
+// top of an l2 book, for clients that only need the spread
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Bbo {
+    pub coin: String,
+    pub time: u64,
+    // seq of the l2 book it was taken from
+    pub seq: u64,
+    pub bid: Option<Level>,
+    pub ask: Option<Level>,
+    // halfway between the best bid and ask, when the book has both
+    pub mid: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+// sent periodically, so that clients can tell a quiet market from a stalled stream or a gap
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Heartbeat {
+    pub time: u64,
+    // seq of the latest l2 book published
+    pub l2_seq: u64,
+    // seq of the latest update of every subscribed l4 coin that had one
+    pub l4_seqs: BTreeMap<String, u64>,
+}
+
+// sent periodically, for clients to tell how far their clock is off the server's
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct TimeSync {
+    // when the message was written to the connection, in ms since the epoch
+    pub send_time: u64,
+}
+
+// a market whose book data is held back, sent on the `halt` channel to its subscribers when that starts, and on the
+// `resume` channel, followed by fresh snapshots, when it ends
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct MarketHalt {
+    pub coin: String,
+    pub reason: HaltReason,
+    pub time: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum HaltReason {
+    // the node has no book for the market, e.g. because the chain paused it
+    Market,
+    // the book diverged from the node's and couldn't be healed
+    Audit,
+}
+
+// the health of the feed, sent every second to status subscribers, and to every client when the node stream
+// goes stale and again when it recovers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct StreamStatus {
+    // the market it is the feed of, none for the primary one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub market: Option<String>,
+    pub stale: bool,
+    // books are being served
+    pub ready: bool,
+    // latest block received, and its block time
+    pub last_block: u64,
+    pub last_block_time: Option<u64>,
+    // how far the latest block is behind the current time, in ms
+    pub lag_ms: Option<u64>,
+    pub upstreams: Vec<UpstreamStatus>,
+    // a snapshot from the node is being validated against the books
+    pub snapshot_in_progress: bool,
+    // gaps in the block numbers of the node events since the start
+    pub gaps: u64,
+    // the coins whose books the node's deltas left crossed or locked
+    #[serde(default)]
+    pub crossed_books: Vec<String>,
+    // new connections are rejected
+    pub maintenance: bool,
+    pub time: u64,
+}
+
+// sent to every client when a maintenance is scheduled, and again without a start when it is called off. At
+// `start` (ms since the epoch) the connections are closed with `close_code`, and new ones are rejected until the
+// maintenance is over
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct MaintenanceNotice {
+    pub start: Option<u64>,
+    pub message: Option<String>,
+    pub close_code: u16,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct UpstreamStatus {
+    pub node: String,
+    // nodes count as connected while they deliver new blocks
+    pub connected: bool,
+}
+
+// a market of the node's metadata. Sizes are multiples of `lot_size` and prices of `tick_size`, with at most 5
+// significant figures
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct MarketInfo {
+    pub coin: String,
+    pub kind: MarketKind,
+    pub base: String,
+    pub quote: String,
+    pub sz_decimals: u32,
+    pub lot_size: String,
+    pub tick_size: String,
+    pub status: MarketStatus,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum MarketKind {
+    Perp,
+    Spot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum MarketStatus {
+    Active,
+    // listed, but the node has no book for it
+    Halted,
+    Delisted,
+}
+
+// a market that was added to the metadata, changed in it or removed from it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct MarketChange {
+    pub change: MarketChangeKind,
+    pub market: MarketInfo,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum MarketChangeKind {
+    Added,
+    Changed,
+    Removed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) enum L4Book {
-    Snapshot { coin: String, time: u64, height: u64, levels: [Vec<L4Order>; 2] },
+    Snapshot {
+        coin: String,
+        time: u64,
+        height: u64,
+        // updates following this snapshot start at seq + 1
+        #[serde(default)]
+        seq: u64,
+        levels: [Vec<L4Order>; 2],
+        // of the book aggregated into levels
+        #[serde(default)]
+        checksum: u32,
+        // signed by the server, when signing is enabled
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        checkpoint: Option<Checkpoint>,
+    },
     Updates(L4BookUpdates),
 }
 
+// the orders of a book without their users, for clients modelling their place in the queues
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum L3Book {
+    Snapshot {
+        coin: String,
+        time: u64,
+        height: u64,
+        // updates following this snapshot start at seq + 1
+        seq: u64,
+        // bids and asks, best price first and in the order they are matched at each price
+        levels: [Vec<L3Order>; 2],
+    },
+    Updates(L3BookUpdates),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct L3Order {
+    pub oid: u64,
+    pub px: String,
+    pub sz: String,
+    // when it entered the book
+    pub timestamp: u64,
+}
+
+// the changes of a block to the orders of a book, in the order they were made
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct L3BookUpdates {
+    pub coin: String,
+    pub time: u64,
+    pub height: u64,
+    // that of the l4 update of the same block
+    pub seq: u64,
+    pub events: Vec<OrderEvent>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub(crate) enum OrderEvent {
+    // behind the orders resting at its price
+    Add {
+        oid: u64,
+        side: Side,
+        px: String,
+        sz: String,
+        timestamp: u64,
+    },
+    // partially filled or resized, keeping its place
+    #[serde(rename_all = "camelCase")]
+    Modify {
+        oid: u64,
+        side: Side,
+        px: String,
+        orig_sz: String,
+        sz: String,
+    },
+    // off the book, with the order's status in the block if there is one, e.g. `filled` or `canceled`
+    Cancel {
+        oid: u64,
+        side: Side,
+        px: String,
+        status: Option<String>,
+    },
+}
+
+// the events of one account's orders in a block, on any coin
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct UserOrders {
+    pub user: Address,
+    pub time: u64,
+    pub height: u64,
+    pub events: Vec<UserEvent>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub(crate) enum UserEvent {
+    // placed, filled, canceled, rejected or triggered
+    Order(NodeDataOrderStatus),
+    // a fill of one of its orders, partial unless the order's status is `filled`
+    Fill(Fill),
+}
+
+impl UserOrders {
+    // `None` if none of the fills are of the user
+    pub(crate) fn from_fills(user: Address, batch: &Batch<NodeDataFill>) -> Option<Self> {
+        let events = batch
+            .events_ref()
+            .iter()
+            .filter(|NodeDataFill(fill_user, _)| *fill_user == user)
+            .map(|NodeDataFill(_, fill)| UserEvent::Fill(fill.clone()))
+            .collect::<Vec<_>>();
+        (!events.is_empty()).then(|| Self { user, time: batch.block_time(), height: batch.block_number(), events })
+    }
+
+    // `None` if none of the order statuses are of the user
+    pub(crate) fn from_book_updates(user: Address, updates: &HashMap<String, L4BookUpdates>) -> Option<Self> {
+        let (time, height) = updates.values().next().map(|update| (update.time, update.height))?;
+        let events = updates
+            .values()
+            .flat_map(|update| &update.order_statuses)
+            .filter(|status| status.user == user)
+            .map(|status| UserEvent::Order(status.clone()))
+            .collect::<Vec<_>>();
+        (!events.is_empty()).then_some(Self { user, time, height, events })
+    }
+}
+
 impl L2Book {
-    pub(crate) const fn from_l2_snapshot(coin: String, snapshot: [Vec<Level>; 2], time: u64) -> Self {
-        Self { coin, time, levels: snapshot }
+    pub(crate) fn from_l2_snapshot(coin: String, snapshot: [Vec<Level>; 2], time: u64, seq: u64) -> Self {
+        let checksum = checksum(&snapshot);
+        Self { coin, time, levels: snapshot, seq, checksum }
+    }
+}
+
+// levels per side covered by a checksum
+pub(crate) const CHECKSUM_LEVELS: usize = 25;
+
+// CRC32 of the top levels of a book, so clients can check the book they maintain. Like OKX's, it is taken of
+// `bid px:bid sz:ask px:ask sz:...` over the top levels, a side that runs out first is left out. Prices and sizes are
+// written as in the messages: without trailing zeros, and without the decimal point for whole numbers.
+pub(crate) fn checksum(levels: &[Vec<Level>; 2]) -> u32 {
+    let [bids, asks] = levels;
+    let mut fields = Vec::with_capacity(4 * CHECKSUM_LEVELS);
+    for i in 0..CHECKSUM_LEVELS {
+        for level in [bids.get(i), asks.get(i)].into_iter().flatten() {
+            fields.push(level.px.as_str());
+            fields.push(level.sz.as_str());
+        }
+    }
+    crc32fast::hash(fields.join(":").as_bytes())
+}
+
+impl Bbo {
+    pub(crate) fn from_l2_book(book: L2Book) -> Self {
+        let L2Book { coin, time, levels: [bids, asks], seq, .. } = book;
+        let bid = bids.into_iter().next();
+        let ask = asks.into_iter().next();
+        let mid = bid.as_ref().zip(ask.as_ref()).and_then(|(bid, ask)| {
+            let (bid, ask) = (Px::parse_from_str(&bid.px).ok()?, Px::parse_from_str(&ask.px).ok()?);
+            Some(Px::new(u64::midpoint(bid.value(), ask.value())).to_str())
+        });
+        Self { coin, time, seq, bid, ask, mid }
     }
 }
 
@@ -72,21 +389,80 @@ impl Trade {
         let hash = ask_fill.hash;
         let time = ask_fill.time;
         let users = [buyer, seller];
-        Self { coin, side, px, sz, hash, time, tid, users }
+        let (taker, maker) = if ask_is_taker { (seller, buyer) } else { (buyer, seller) };
+        Self { coin, side, px, sz, hash, time, tid, users, taker, maker }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct L4BookUpdates {
     pub time: u64,
     pub height: u64,
+    // per coin, increases by exactly one for every update sent, so clients can detect missed updates
+    #[serde(default)]
+    pub seq: u64,
+    // of the coin's book once this update is applied, aggregated into levels
+    #[serde(default)]
+    pub checksum: u32,
     pub order_statuses: Vec<NodeDataOrderStatus>,
     pub book_diffs: Vec<NodeDataOrderDiff>,
 }
 
 impl L4BookUpdates {
     pub(crate) const fn new(time: u64, height: u64) -> Self {
-        Self { time, height, order_statuses: Vec::new(), book_diffs: Vec::new() }
+        Self { time, height, seq: 0, checksum: 0, order_statuses: Vec::new(), book_diffs: Vec::new() }
+    }
+
+    // appends a later update for the same coin, so that applying the result equals applying both in order
+    pub(crate) fn merge(&mut self, later: Self) {
+        self.time = later.time;
+        self.height = later.height;
+        self.seq = later.seq;
+        self.checksum = later.checksum;
+        self.order_statuses.extend(later.order_statuses);
+        self.book_diffs.extend(later.book_diffs);
+    }
+
+    // collapses the diffs into one net diff per order: orders added and removed within the update disappear,
+    // size changes are folded into a single new/update diff. Only the statuses opening orders that are new in
+    // the net delta are kept, since those are needed to apply it.
+    pub(crate) fn into_net_delta(self) -> Self {
+        let Self { time, height, seq, checksum, order_statuses, book_diffs } = self;
+        let mut net: Vec<Option<NodeDataOrderDiff>> = Vec::with_capacity(book_diffs.len());
+        let mut positions = HashMap::new();
+        for diff in book_diffs {
+            let oid = diff.oid();
+            let Some(&position) = positions.get(&oid) else {
+                positions.insert(oid, net.len());
+                net.push(Some(diff));
+                continue;
+            };
+            let Some(pending) = net.get_mut(position) else { continue };
+            let merged = match (pending.take(), diff.raw_book_diff.clone()) {
+                (Some(mut pending), OrderDiff::Update { new_sz, .. }) => {
+                    match &mut pending.raw_book_diff {
+                        OrderDiff::New { sz } | OrderDiff::Update { new_sz: sz, .. } => *sz = new_sz,
+                        OrderDiff::Remove => {}
+                    }
+                    Some(pending)
+                }
+                // an order that was added in this update and is gone again by the end of it
+                (Some(NodeDataOrderDiff { raw_book_diff: OrderDiff::New { .. }, .. }), OrderDiff::Remove) => None,
+                _ => Some(diff),
+            };
+            *pending = merged;
+        }
+        let book_diffs = net.into_iter().flatten().collect::<Vec<_>>();
+        let new_orders = book_diffs
+            .iter()
+            .filter(|diff| matches!(diff.raw_book_diff, OrderDiff::New { .. }))
+            .map(NodeDataOrderDiff::oid)
+            .collect::<HashSet<_>>();
+        let order_statuses = order_statuses
+            .into_iter()
+            .filter(|status| status.is_inserted_into_book() && new_orders.contains(&Oid::new(status.order.oid)))
+            .collect();
+        Self { time, height, seq, checksum, order_statuses, book_diffs }
     }
 }
 
@@ -155,3 +531,113 @@ pub(crate) struct Liquidation {
     mark_px: String,
     method: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diff(oid: u64, raw_book_diff: &str) -> NodeDataOrderDiff {
+        serde_json::from_str(&format!(
+            r#"{{"user":"0x0000000000000000000000000000000000000000","oid":{oid},"px":"1.0","coin":"BTC","raw_book_diff":{raw_book_diff}}}"#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_net_delta_collapses_diffs_per_order() {
+        let mut updates = L4BookUpdates::new(0, 1);
+        updates.book_diffs = vec![
+            diff(1, r#"{"new":{"sz":"1.0"}}"#),
+            diff(2, r#"{"update":{"origSz":"5.0","newSz":"4.0"}}"#),
+            diff(1, r#"{"update":{"origSz":"1.0","newSz":"0.5"}}"#),
+            diff(3, r#"{"new":{"sz":"2.0"}}"#),
+            diff(2, r#"{"update":{"origSz":"4.0","newSz":"3.0"}}"#),
+            diff(3, r#""remove""#),
+            diff(4, r#"{"update":{"origSz":"1.0","newSz":"0.5"}}"#),
+            diff(4, r#""remove""#),
+        ];
+        let net = updates.into_net_delta();
+        let net = net.book_diffs.iter().map(|diff| (diff.oid(), diff.diff())).collect::<Vec<_>>();
+        assert_eq!(net.len(), 3);
+        assert!(matches!(&net[0], (oid, OrderDiff::New { sz }) if *oid == Oid::new(1) && sz == "0.5"));
+        assert!(
+            matches!(&net[1], (oid, OrderDiff::Update { orig_sz, new_sz }) if *oid == Oid::new(2) && orig_sz == "5.0" && new_sz == "3.0")
+        );
+        assert!(matches!(&net[2], (oid, OrderDiff::Remove) if *oid == Oid::new(4)));
+    }
+
+    #[test]
+    fn test_trade_taker_and_maker() {
+        let fill = |user: u8, side: &str, crossed: bool| {
+            let fill = serde_json::from_str(&format!(
+                r#"{{"coin":"BTC","px":"100.0","sz":"1.0","side":"{side}","time":1,"startPosition":"0.0","dir":"","closedPnl":"0.0","hash":"0x0","oid":1,"crossed":{crossed},"fee":"0.0","tid":7,"feeToken":"USDC","liquidation":null}}"#
+            ))
+            .unwrap();
+            NodeDataFill(Address::repeat_byte(user), fill)
+        };
+        let fills = HashMap::from([(Side::Bid, fill(1, "B", false)), (Side::Ask, fill(2, "A", true))]);
+        let trade = Trade::from_fills(fills);
+        assert_eq!(trade.side, Side::Ask);
+        assert_eq!(trade.users, [Address::repeat_byte(1), Address::repeat_byte(2)]);
+        assert_eq!((trade.taker, trade.maker), (Address::repeat_byte(2), Address::repeat_byte(1)));
+    }
+
+    #[test]
+    fn test_user_orders() {
+        let fill = |user: u8, oid: u64| {
+            format!(
+                r#"["{}",{{"coin":"BTC","px":"100.0","sz":"1.0","side":"B","time":1,"startPosition":"0.0","dir":"","closedPnl":"0.0","hash":"0x0","oid":{oid},"crossed":false,"fee":"0.0","tid":7,"feeToken":"USDC","liquidation":null}}]"#,
+                Address::repeat_byte(user)
+            )
+        };
+        let batch: Batch<NodeDataFill> = serde_json::from_str(&format!(
+            r#"{{"local_time":"2025-06-24T02:56:36.2","block_time":"2025-06-24T02:56:36.1","block_number":9,"events":[{},{},{}]}}"#,
+            fill(1, 10),
+            fill(2, 11),
+            fill(1, 12)
+        ))
+        .unwrap();
+        let orders = UserOrders::from_fills(Address::repeat_byte(1), &batch).unwrap();
+        assert_eq!(orders.height, 9);
+        let oids = orders.events.iter().map(|event| match event {
+            UserEvent::Fill(fill) => fill.oid,
+            UserEvent::Order(status) => status.order.oid,
+        });
+        assert_eq!(oids.collect::<Vec<_>>(), vec![10, 12]);
+        assert!(UserOrders::from_fills(Address::repeat_byte(3), &batch).is_none());
+
+        let status = |user: u8, coin: &str| {
+            serde_json::from_str::<NodeDataOrderStatus>(&format!(
+                r#"{{"time":"2025-06-24T02:56:36.172847427","user":"{}","status":"canceled","order":{{"user":null,"coin":"{coin}","side":"B","limitPx":"100.0","sz":"1.5","oid":1,"timestamp":1,"triggerCondition":"N/A","isTrigger":false,"triggerPx":"0.0","isPositionTpsl":false,"reduceOnly":false,"orderType":"Limit","tif":"Gtc","cloid":null}}}}"#,
+                Address::repeat_byte(user)
+            ))
+            .unwrap()
+        };
+        let update = |statuses| L4BookUpdates { order_statuses: statuses, ..L4BookUpdates::new(5, 6) };
+        let updates = HashMap::from([
+            ("BTC".to_string(), update(vec![status(1, "BTC"), status(2, "BTC")])),
+            ("ETH".to_string(), update(vec![status(1, "ETH")])),
+        ]);
+        let orders = UserOrders::from_book_updates(Address::repeat_byte(1), &updates).unwrap();
+        assert_eq!((orders.time, orders.height, orders.events.len()), (5, 6, 2));
+        let json = serde_json::to_value(&orders.events[0]).unwrap();
+        assert_eq!((json["type"].as_str(), json["status"].as_str()), (Some("order"), Some("canceled")));
+        assert!(UserOrders::from_book_updates(Address::repeat_byte(3), &updates).is_none());
+    }
+
+    #[test]
+    fn test_checksum() {
+        let levels = |levels: &[(&str, &str)]| {
+            levels.iter().map(|(px, sz)| Level::new((*px).to_string(), (*sz).to_string(), 1)).collect::<Vec<_>>()
+        };
+        // the example in OKX's docs, whose checksum is -1881014294 as a signed integer
+        let book = [levels(&[("3366.1", "7"), ("3366", "6")]), levels(&[("3366.8", "9"), ("3368", "8")])];
+        assert_eq!(checksum(&book), 2_413_953_002);
+        let book = [levels(&[("3366.1", "7"), ("3366", "6")]), levels(&[("3366.8", "9")])];
+        assert_eq!(checksum(&book), crc32fast::hash(b"3366.1:7:3366.8:9:3366:6"));
+        let deep = (1..=30).map(|px| (px.to_string(), "1".to_string())).collect::<Vec<_>>();
+        let deep = deep.iter().map(|(px, sz)| (px.as_str(), sz.as_str())).collect::<Vec<_>>();
+        assert_eq!(checksum(&[levels(&deep), Vec::new()]), checksum(&[levels(&deep[..CHECKSUM_LEVELS]), Vec::new()]));
+        assert_eq!(checksum(&[Vec::new(), Vec::new()]), 0);
+    }
+}
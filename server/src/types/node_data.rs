@@ -49,6 +49,29 @@ impl NodeDataOrderStatus {
     }
 }
 
+// the coins of markets other than the primary one are namespaced by the market's name, e.g. `testnet:BTC`
+pub(crate) trait PrefixCoin {
+    fn prefix_coin(&mut self, prefix: &str);
+}
+
+impl PrefixCoin for NodeDataOrderDiff {
+    fn prefix_coin(&mut self, prefix: &str) {
+        self.coin.insert_str(0, prefix);
+    }
+}
+
+impl PrefixCoin for NodeDataFill {
+    fn prefix_coin(&mut self, prefix: &str) {
+        self.1.coin.insert_str(0, prefix);
+    }
+}
+
+impl PrefixCoin for NodeDataOrderStatus {
+    fn prefix_coin(&mut self, prefix: &str) {
+        self.order.coin.insert_str(0, prefix);
+    }
+}
+
 #[derive(Clone, Copy, strum_macros::Display)]
 pub(crate) enum EventSource {
     Fills,
@@ -88,4 +111,31 @@ impl<E> Batch<E> {
     pub(crate) fn events(self) -> Vec<E> {
         self.events
     }
+
+    pub(crate) fn events_ref(&self) -> &[E] {
+        &self.events
+    }
+
+    // the events divided into batches of the same block by `part`, below `n`; parts without events are left out
+    pub(crate) fn split(self, n: usize, part: impl Fn(&E) -> usize) -> Vec<(usize, Self)> {
+        let Self { local_time, block_time, block_number, events } = self;
+        let mut parts = (0..n).map(|_| Vec::new()).collect::<Vec<_>>();
+        for event in events {
+            parts[part(&event)].push(event);
+        }
+        parts
+            .into_iter()
+            .enumerate()
+            .filter(|(_, events)| !events.is_empty())
+            .map(|(i, events)| (i, Self { local_time, block_time, block_number, events }))
+            .collect()
+    }
+}
+
+impl<E: PrefixCoin> Batch<E> {
+    pub(crate) fn prefix_coins(&mut self, prefix: &str) {
+        for event in &mut self.events {
+            event.prefix_coin(prefix);
+        }
+    }
 }
@@ -1,19 +1,97 @@
-use std::collections::HashSet;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
 
-use log::info;
+use alloy::primitives::Address;
 use serde::{Deserialize, Serialize};
+use tracing::info;
 
-use crate::types::{L2Book, L4Book, Trade};
+use crate::{
+    analytics::MarketAnalytics,
+    candles::{Candle, CandleInterval},
+    order_book::Px,
+    servers::{
+        outbound::Outbound,
+        protocol::ProtocolError,
+        rpc::{RequestId, RpcResponse},
+        send_queue::SlowConsumerReport,
+        sessions::SessionToken,
+    },
+    signing::Checkpoint,
+    types::{
+        Bbo, Heartbeat, L2Book, L3Book, L4Book, MaintenanceNotice, MarketChange, MarketHalt, MarketInfo, StreamStatus,
+        TimeSync, Trade, UserOrders,
+        delta::{Epoch, L2Delta},
+    },
+};
 
-const MAX_LEVELS: usize = 100;
+pub(crate) const MAX_LEVELS: usize = 100;
 pub(crate) const DEFAULT_LEVELS: usize = 20;
+pub(crate) const CONFLATE_MS_RANGE: std::ops::RangeInclusive<u64> = 10..=60_000;
+// the fields of l2 books and bbos a subscription can leave out; the coin and the prices and sizes of the levels are
+// always sent, `n` is the order count of every level
+pub(crate) const L2_BOOK_FIELDS: &[&str] = &["time", "seq", "checksum", "n"];
+pub(crate) const BBO_FIELDS: &[&str] = &["time", "seq", "mid", "n"];
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "method")]
 #[serde(rename_all = "camelCase")]
 pub(crate) enum ClientMessage {
-    Subscribe { subscription: Subscription },
-    Unsubscribe { subscription: Subscription },
+    Subscribe {
+        subscription: Subscription,
+    },
+    Unsubscribe {
+        subscription: Subscription,
+    },
+    // request a fresh snapshot for an existing subscription (e.g. after a sequence gap)
+    Snapshot {
+        subscription: Subscription,
+    },
+    // only accepted as the first message, from clients that can't send credentials in the upgrade request
+    Auth {
+        token: String,
+    },
+    // stream the journaled l4 updates from a seq or block time (in ms) on, then continue live
+    #[serde(rename_all = "camelCase")]
+    Replay {
+        subscription: Subscription,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        from_seq: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        from_ts: Option<u64>,
+    },
+    // the l4 updates from a seq on after a reconnect, from memory, then live; a snapshot if they are gone
+    #[serde(rename_all = "camelCase")]
+    Resume {
+        subscription: Subscription,
+        from_seq: u64,
+    },
+    // the markets of the node's metadata, of every market the server reads
+    ListMarkets,
+    // requests answered on the `response` channel with their `id`, see `rpc`
+    GetSnapshot {
+        id: RequestId,
+        subscription: Subscription,
+    },
+    GetMarkets {
+        id: RequestId,
+    },
+    // the client's clock when it sent the request, echoed with the server's
+    #[serde(rename_all = "camelCase")]
+    ServerTime {
+        id: RequestId,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        client_time: Option<u64>,
+    },
+    Ping {
+        id: RequestId,
+    },
+    // the connection's queue, format and subscriptions, for the client to tell whether it or the server falls behind
+    Stats {
+        id: RequestId,
+    },
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
@@ -21,22 +99,132 @@ pub(crate) enum ClientMessage {
 #[serde(rename_all = "camelCase")]
 pub(crate) enum Subscription {
     #[serde(rename_all = "camelCase")]
-    Trades { coin: String },
+    Trades {
+        coin: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    L2Book {
+        coin: String,
+        n_sig_figs: Option<u32>,
+        // levels per side; books are only sent when these change
+        #[serde(alias = "depth")]
+        n_levels: Option<usize>,
+        mantissa: Option<u64>,
+        // send at most one (latest) book per interval
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        conflate_ms: Option<u64>,
+        // aggregate levels into buckets of this price increment, instead of by significant figures
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        tick_size: Option<String>,
+        // the optional fields to send, all of them if not set
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        fields: Option<Vec<String>>,
+        // send the changes to the book since the previous message on the `l2Delta` channel, see `Epoch`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        delta: Option<bool>,
+    },
+    // best bid and ask, sent when either changes
+    #[serde(rename_all = "camelCase")]
+    Bbo {
+        coin: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        fields: Option<Vec<String>>,
+    },
+    // OHLCV candles, sent whenever a trade changes the open one
+    #[serde(rename_all = "camelCase")]
+    Candle {
+        coin: String,
+        interval: CandleInterval,
+    },
+    // spread, imbalance and VWAP of a market, every analytics interval
+    #[serde(rename_all = "camelCase")]
+    Analytics {
+        coin: String,
+    },
+    // signed checkpoints of the book, every checkpoint interval
+    #[serde(rename_all = "camelCase")]
+    Checkpoint {
+        coin: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    L4Book {
+        coin: String,
+        // coalesce the updates of each interval into a single net delta
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        conflate_ms: Option<u64>,
+    },
+    // the orders of a book and their changes, without their users
     #[serde(rename_all = "camelCase")]
-    L2Book { coin: String, n_sig_figs: Option<u32>, n_levels: Option<usize>, mantissa: Option<u64> },
+    L3Book {
+        coin: String,
+    },
+    // the statuses and fills of an account's orders, on every coin. `signature` is the account's `personal_sign`
+    // signature of `userOrders:<user>:<timestamp>`, checked when subscribing
     #[serde(rename_all = "camelCase")]
-    L4Book { coin: String },
+    UserOrders {
+        user: Address,
+        timestamp: u64,
+        signature: String,
+    },
+    // the health of the feed, every second
+    Status,
+    // markets added to, changed in or removed from the node's metadata
+    Markets,
+}
+
+impl ClientMessage {
+    // the subscription the request is about, if any
+    pub(crate) const fn subscription_mut(&mut self) -> Option<&mut Subscription> {
+        match self {
+            Self::Subscribe { subscription }
+            | Self::Unsubscribe { subscription }
+            | Self::Snapshot { subscription }
+            | Self::Replay { subscription, .. }
+            | Self::Resume { subscription, .. }
+            | Self::GetSnapshot { subscription, .. } => Some(subscription),
+            Self::Auth { .. }
+            | Self::ListMarkets
+            | Self::GetMarkets { .. }
+            | Self::ServerTime { .. }
+            | Self::Ping { .. }
+            | Self::Stats { .. } => None,
+        }
+    }
 }
 
 impl Subscription {
     pub(crate) fn validate(&self, universe: &HashSet<String>) -> bool {
         match self {
-            Self::Trades { coin } => universe.contains(coin),
-            Self::L2Book { coin, n_sig_figs, n_levels, mantissa } => {
-                if !universe.contains(coin) || coin.starts_with('@') {
+            Self::Trades { coin } | Self::Candle { coin, .. } => universe.contains(coin),
+            Self::L2Book { coin, n_sig_figs, n_levels, mantissa, conflate_ms, tick_size, fields, delta } => {
+                if !universe.contains(coin) || is_spot_index(coin) {
                     info!("Invalid subscription: coin not found");
                     return false;
                 }
+                if !validate_conflate_ms(*conflate_ms) || !validate_fields(fields.as_deref(), L2_BOOK_FIELDS) {
+                    return false;
+                }
+                if *delta == Some(true) {
+                    // conflating would drop the deltas in between, and without seqs gaps go unnoticed
+                    if conflate_ms.is_some() {
+                        info!("Invalid subscription: delta can not be combined with conflateMs");
+                        return false;
+                    }
+                    if fields.as_ref().is_some_and(|fields| !fields.iter().any(|field| field == "seq")) {
+                        info!("Invalid subscription: delta needs the seq field");
+                        return false;
+                    }
+                }
+                if tick_size.is_some() {
+                    if n_sig_figs.is_some() || mantissa.is_some() {
+                        info!("Invalid subscription: tickSize can not be combined with nSigFigs or mantissa");
+                        return false;
+                    }
+                    if self.tick_size().is_none() {
+                        info!("Invalid subscription: tickSize must be a positive price");
+                        return false;
+                    }
+                }
                 if *n_levels == Some(DEFAULT_LEVELS) {
                     info!("Invalid subscription: set n_levels to this by using null");
                     return false;
@@ -63,32 +251,201 @@ impl Subscription {
                 info!("Valid subscription");
                 true
             }
-            Self::L4Book { coin } => {
-                if !universe.contains(coin) || coin.starts_with('@') {
+            Self::Bbo { coin, fields } => {
+                if !universe.contains(coin) || is_spot_index(coin) {
+                    info!("Invalid subscription: coin not found");
+                    return false;
+                }
+                if !validate_fields(fields.as_deref(), BBO_FIELDS) {
+                    return false;
+                }
+                info!("Valid subscription");
+                true
+            }
+            Self::Analytics { coin } | Self::Checkpoint { coin } | Self::L3Book { coin } => {
+                if !universe.contains(coin) || is_spot_index(coin) {
+                    info!("Invalid subscription: coin not found");
+                    return false;
+                }
+                true
+            }
+            Self::L4Book { coin, conflate_ms } => {
+                if !universe.contains(coin) || is_spot_index(coin) {
                     info!("Invalid subscription: coin not found");
                     return false;
                 }
+                if !validate_conflate_ms(*conflate_ms) {
+                    return false;
+                }
                 info!("Valid subscription");
                 true
             }
+            Self::UserOrders { .. } | Self::Status | Self::Markets => true,
+        }
+    }
+}
+
+impl Subscription {
+    // `None` for the subscriptions that aren't of a coin
+    pub(crate) fn coin(&self) -> Option<&str> {
+        match self {
+            Self::Trades { coin }
+            | Self::L2Book { coin, .. }
+            | Self::Bbo { coin, .. }
+            | Self::Candle { coin, .. }
+            | Self::Analytics { coin }
+            | Self::Checkpoint { coin }
+            | Self::L4Book { coin, .. }
+            | Self::L3Book { coin } => Some(coin),
+            Self::UserOrders { .. } | Self::Status | Self::Markets => None,
+        }
+    }
+
+    // `None` for anything but l2 books with a valid tick size
+    pub(crate) fn tick_size(&self) -> Option<Px> {
+        let Self::L2Book { tick_size: Some(tick_size), .. } = self else {
+            return None;
+        };
+        Px::parse_from_str(tick_size).ok().filter(|tick| tick.value() > 0)
+    }
+
+    pub(crate) fn conflate_interval(&self) -> Option<Duration> {
+        match self {
+            Self::L2Book { conflate_ms, .. } | Self::L4Book { conflate_ms, .. } => {
+                conflate_ms.map(Duration::from_millis)
+            }
+            Self::Trades { .. }
+            | Self::Bbo { .. }
+            | Self::Candle { .. }
+            | Self::Analytics { .. }
+            | Self::Checkpoint { .. }
+            | Self::L3Book { .. }
+            | Self::UserOrders { .. }
+            | Self::Status
+            | Self::Markets => None,
         }
     }
+
+    // whether its l2 books are sent as deltas
+    pub(crate) const fn is_delta(&self) -> bool {
+        matches!(self, Self::L2Book { delta: Some(true), .. })
+    }
+
+    // the optional fields its messages are sent with, `None` for all of them
+    // the subscription as far as the messages sent for it go: its data, depth and fields. How often they are
+    // conflated, whether they are turned into deltas and the order the fields are listed in make no difference to
+    // them
+    pub(crate) fn variant(&self) -> Self {
+        match self {
+            Self::L2Book { coin, n_sig_figs, n_levels, mantissa, tick_size, fields, .. } => Self::L2Book {
+                coin: coin.clone(),
+                n_sig_figs: *n_sig_figs,
+                n_levels: *n_levels,
+                mantissa: *mantissa,
+                conflate_ms: None,
+                tick_size: tick_size.clone(),
+                fields: fields.as_deref().map(sorted_fields),
+                delta: None,
+            },
+            Self::Bbo { coin, fields } => {
+                Self::Bbo { coin: coin.clone(), fields: fields.as_deref().map(sorted_fields) }
+            }
+            Self::L4Book { coin, .. } => Self::L4Book { coin: coin.clone(), conflate_ms: None },
+            other => other.clone(),
+        }
+    }
+
+    pub(crate) fn fields(&self) -> Option<&[String]> {
+        match self {
+            Self::L2Book { fields, .. } | Self::Bbo { fields, .. } => fields.as_deref(),
+            Self::Trades { .. }
+            | Self::Candle { .. }
+            | Self::Analytics { .. }
+            | Self::Checkpoint { .. }
+            | Self::L4Book { .. }
+            | Self::L3Book { .. }
+            | Self::UserOrders { .. }
+            | Self::Status
+            | Self::Markets => None,
+        }
+    }
+}
+
+// spot pairs by their index, which have no l2 and l4 books; `<market>:@<index>` for other markets
+fn is_spot_index(coin: &str) -> bool {
+    coin.rsplit(':').next().is_some_and(|coin| coin.starts_with('@'))
+}
+
+fn sorted_fields(fields: &[String]) -> Vec<String> {
+    let mut fields = fields.to_vec();
+    fields.sort_unstable();
+    fields.dedup();
+    fields
+}
+
+fn validate_conflate_ms(conflate_ms: Option<u64>) -> bool {
+    if let Some(conflate_ms) = conflate_ms
+        && !CONFLATE_MS_RANGE.contains(&conflate_ms)
+    {
+        info!("Invalid subscription: conflateMs must be between 10 and 60000");
+        return false;
+    }
+    true
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+fn validate_fields(fields: Option<&[String]>, known: &[&str]) -> bool {
+    if let Some(field) = fields.into_iter().flatten().find(|field| !known.contains(&field.as_str())) {
+        info!("Invalid subscription: unknown field {field}");
+        return false;
+    }
+    true
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "channel", content = "data")]
 #[serde(rename_all = "camelCase")]
 pub(crate) enum ServerResponse {
     SubscriptionResponse(ClientMessage),
     L2Book(L2Book),
+    L2Delta(L2Delta),
+    Bbo(Bbo),
     L4Book(L4Book),
+    L3Book(L3Book),
+    UserOrders(UserOrders),
     Trades(Vec<Trade>),
+    Candle(Candle),
+    Analytics(MarketAnalytics),
+    Checkpoint(Checkpoint),
+    Heartbeat(Heartbeat),
+    Time(TimeSync),
+    Halt(MarketHalt),
+    Resume(MarketHalt),
+    Status(StreamStatus),
+    Markets(Vec<MarketInfo>),
+    MarketChanges(Vec<MarketChange>),
+    Maintenance(MaintenanceNotice),
+    // the token to restore the connection with after it drops
+    Session(SessionToken),
+    // the last message to a client evicted for reading too slowly
+    Evicted(SlowConsumerReport),
+    Response(RpcResponse),
     Error(String),
+    // a malformed request, as `{"error": {"code": ..., "msg": ...}}`
+    #[serde(untagged)]
+    ProtocolError {
+        error: ProtocolError,
+    },
 }
 
 #[derive(Default)]
 pub(crate) struct SubscriptionManager {
     subscriptions: HashSet<Subscription>,
+    // the l2 book or bbo last sent for each l2 and bbo subscription
+    sent_books: HashMap<Subscription, Arc<Outbound>>,
+    // the epoch of each delta subscription, started by the last full book sent for it
+    epochs: HashMap<Subscription, Epoch>,
+    // maximum number of subscriptions, lowering it keeps the ones over the limit
+    limit: Option<usize>,
 }
 
 impl SubscriptionManager {
@@ -97,18 +454,110 @@ impl SubscriptionManager {
     }
 
     pub(crate) fn unsubscribe(&mut self, sub: Subscription) -> bool {
+        self.sent_books.remove(&sub);
+        self.epochs.remove(&sub);
         self.subscriptions.remove(&sub)
     }
 
+    // remembers a book sent outside of `for_each_changed_l2_book`, e.g. the snapshot starting a subscription
+    pub(crate) fn book_sent(&mut self, sub: &Subscription, msg: &ServerResponse) {
+        if matches!(msg, ServerResponse::L2Book(_) | ServerResponse::Bbo(_)) {
+            self.sent_books.insert(sub.clone(), msg.clone().into());
+        }
+        if let ServerResponse::L2Book(book) = msg
+            && sub.is_delta()
+        {
+            self.epochs.insert(sub.clone(), Epoch::new(book));
+        }
+    }
+
+    // sends the l2 book or bbo of every subscription whose levels changed, as a delta to delta subscriptions
+    pub(crate) fn for_each_changed_l2_book(
+        &mut self,
+        book: impl Fn(&Subscription) -> Option<Arc<Outbound>>,
+        mut send: impl FnMut(&Subscription, Arc<Outbound>),
+    ) {
+        for sub in &self.subscriptions {
+            let Some(book) = book(sub) else {
+                continue;
+            };
+            let last = self.sent_books.get(sub).cloned();
+            if !is_changed(&mut self.sent_books, sub, &book) {
+                continue;
+            }
+            let delta = sub.is_delta().then(|| delta(&mut self.epochs, sub, last.as_deref(), &book)).flatten();
+            send(sub, delta.unwrap_or(book));
+        }
+    }
+
+    pub(crate) const fn set_limit(&mut self, limit: Option<usize>) {
+        self.limit = limit;
+    }
+
+    // whether another subscription would exceed the limit, counting `pending` ones that aren't added yet
+    pub(crate) fn is_full(&self, pending: usize) -> bool {
+        self.limit.is_some_and(|limit| self.subscriptions.len() + pending >= limit)
+    }
+
     pub(crate) const fn subscriptions(&self) -> &HashSet<Subscription> {
         &self.subscriptions
     }
 }
 
+// the changes from the book last sent to a delta subscription, or None for `book` to be sent in full and start a
+// new epoch
+fn delta(
+    epochs: &mut HashMap<Subscription, Epoch>,
+    sub: &Subscription,
+    sent: Option<&Outbound>,
+    book: &Outbound,
+) -> Option<Arc<Outbound>> {
+    let ServerResponse::L2Book(book) = book.msg() else {
+        return None;
+    };
+    let delta = match (epochs.get_mut(sub), sent.map(Outbound::msg)) {
+        (Some(epoch), Some(ServerResponse::L2Book(sent))) => epoch.delta(sent, book),
+        _ => None,
+    };
+    if delta.is_none() {
+        epochs.insert(sub.clone(), Epoch::new(book));
+    }
+    delta.map(|delta| Outbound::projected(ServerResponse::L2Delta(delta), sub.fields()))
+}
+
+fn is_changed(sent_books: &mut HashMap<Subscription, Arc<Outbound>>, sub: &Subscription, book: &Arc<Outbound>) -> bool {
+    let same_levels = |sent: &Arc<Outbound>| {
+        Arc::ptr_eq(sent, book)
+            || match (sent.msg(), book.msg()) {
+                (ServerResponse::L2Book(sent), ServerResponse::L2Book(book)) => sent.levels == book.levels,
+                (ServerResponse::Bbo(sent), ServerResponse::Bbo(bbo)) => sent.bid == bbo.bid && sent.ask == bbo.ask,
+                _ => false,
+            }
+    };
+    if sent_books.get(sub).is_some_and(same_levels) {
+        return false;
+    }
+    sent_books.insert(sub.clone(), book.clone());
+    true
+}
+
 #[cfg(test)]
 mod test {
-    use super::{ClientMessage, ServerResponse};
-    use crate::types::subscription::Subscription;
+    use std::{collections::HashSet, time::Duration};
+
+    use super::{ClientMessage, ServerResponse, SubscriptionManager};
+    use crate::{
+        servers::outbound::Outbound,
+        types::{Bbo, L2Book, Level, subscription::Subscription},
+    };
+
+    fn seq_of(msg: &Outbound) -> u64 {
+        match msg.msg() {
+            ServerResponse::L2Book(book) => book.seq,
+            ServerResponse::Bbo(bbo) => bbo.seq,
+            _ => 0,
+        }
+    }
 
     #[test]
     fn test_message_deserialization_subscription_response() {
@@ -150,4 +599,238 @@ mod test {
             }
         ));
     }
+
+    #[test]
+    fn test_subscription_manager_tracks_markets_independently() {
+        let mut manager = SubscriptionManager::default();
+        let btc = Subscription::Trades { coin: "BTC".to_string() };
+        let eth = Subscription::Trades { coin: "ETH".to_string() };
+        assert!(manager.subscribe(btc.clone()));
+        assert!(!manager.subscribe(btc.clone()));
+        assert!(manager.subscribe(eth.clone()));
+        assert!(manager.unsubscribe(btc.clone()));
+        assert!(!manager.unsubscribe(btc));
+        assert_eq!(manager.subscriptions().iter().collect::<Vec<_>>(), vec![&eth]);
+    }
+
+    #[test]
+    fn test_depth_limited_books_only_sent_on_change() {
+        let message = r#"{"type":"l2Book","coin":"BTC","nSigFigs":null,"depth":5,"mantissa":null}"#;
+        let sub: Subscription = serde_json::from_str(message).unwrap();
+        assert!(matches!(sub, Subscription::L2Book { n_levels: Some(5), .. }));
+        let book = |sz: &str, seq| {
+            let levels = [vec![Level::new("100.0".to_string(), sz.to_string(), 1)], Vec::new()];
+            L2Book::from_l2_snapshot("BTC".to_string(), levels, seq, seq)
+        };
+        let mut manager = SubscriptionManager::default();
+        manager.subscribe(sub);
+        let mut sent = Vec::new();
+        for (sz, seq) in [("1.0", 1), ("1.0", 2), ("2.0", 3)] {
+            let book = |_: &Subscription| Some(ServerResponse::L2Book(book(sz, seq)).into());
+            manager.for_each_changed_l2_book(book, |_, book| sent.push(seq_of(&book)));
+        }
+        assert_eq!(sent, vec![1, 3]);
+    }
+
+    #[test]
+    fn test_delta_subscriptions() {
+        let message = r#"{"type":"l2Book","coin":"BTC","nSigFigs":null,"nLevels":null,"mantissa":null,"delta":true}"#;
+        let sub: Subscription = serde_json::from_str(message).unwrap();
+        let universe = HashSet::from(["BTC".to_string()]);
+        assert!(sub.validate(&universe) && sub.is_delta());
+        let conflated = Subscription::L2Book {
+            coin: "BTC".to_string(),
+            n_sig_figs: None,
+            n_levels: None,
+            mantissa: None,
+            conflate_ms: Some(100),
+            tick_size: None,
+            fields: None,
+            delta: Some(true),
+        };
+        assert!(!conflated.validate(&universe));
+
+        let book = |sz: &str, seq| {
+            let levels = [vec![Level::new("100.0".to_string(), sz.to_string(), 1)], Vec::new()];
+            L2Book::from_l2_snapshot("BTC".to_string(), levels, seq, seq)
+        };
+        let mut manager = SubscriptionManager::default();
+        manager.subscribe(sub.clone());
+        let mut sent = Vec::new();
+        let mut publish = |manager: &mut SubscriptionManager, sz: &str, seq| {
+            let book = |_: &Subscription| Some(ServerResponse::L2Book(book(sz, seq)).into());
+            manager.for_each_changed_l2_book(book, |_, msg| sent.push(msg.msg().clone()));
+        };
+        // the first book is sent in full, the changes to it as deltas of its epoch
+        publish(&mut manager, "1.0", 1);
+        publish(&mut manager, "2.0", 2);
+        publish(&mut manager, "2.0", 3);
+        // and a snapshot starts a new epoch
+        manager.book_sent(&sub, &ServerResponse::L2Book(book("3.0", 4)));
+        publish(&mut manager, "4.0", 5);
+        let epochs = sent
+            .iter()
+            .map(|msg| match msg {
+                ServerResponse::L2Book(book) => (book.seq, None),
+                ServerResponse::L2Delta(delta) => (delta.seq, Some((delta.prev_seq, delta.epoch))),
+                _ => panic!("not an l2 message: {msg:?}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(epochs, vec![(1, None), (2, Some((1, 1))), (5, Some((4, 4)))]);
+    }
+
+    #[test]
+    fn test_bbo_only_sent_when_top_of_book_changes() {
+        let sub: Subscription = serde_json::from_str(r#"{"type":"bbo","coin":"BTC"}"#).unwrap();
+        let level = |px: &str| Level::new(px.to_string(), "1.0".to_string(), 1);
+        let book = |bid: &str, seq| {
+            L2Book::from_l2_snapshot("BTC".to_string(), [vec![level(bid)], vec![level("101.0")]], seq, seq)
+        };
+        let bbo = Bbo::from_l2_book(book("100.0", 1));
+        assert_eq!(bbo.mid.as_deref(), Some("100.5"));
+        let mut manager = SubscriptionManager::default();
+        manager.subscribe(sub.clone());
+        manager.book_sent(&sub, &ServerResponse::Bbo(bbo));
+        let mut sent = Vec::new();
+        for (bid, seq) in [("100.0", 2), ("99.0", 3)] {
+            let bbo = |_: &Subscription| Some(ServerResponse::Bbo(Bbo::from_l2_book(book(bid, seq))).into());
+            manager.for_each_changed_l2_book(bbo, |_, bbo| sent.push(seq_of(&bbo)));
+        }
+        assert_eq!(sent, vec![3]);
+        let empty = Bbo::from_l2_book(L2Book::from_l2_snapshot("BTC".to_string(), [Vec::new(), Vec::new()], 0, 0));
+        assert!(empty.bid.is_none() && empty.mid.is_none());
+    }
+
+    #[test]
+    fn test_client_message_deserialization_unsubscribe() {
+        let message = r#"
+            { "method": "unsubscribe", "subscription":{ "type": "trades", "coin": "ETH" }}
+        "#;
+        let msg: ClientMessage = serde_json::from_str(message).unwrap();
+        assert!(matches!(
+            msg,
+            ClientMessage::Unsubscribe { subscription: Subscription::Trades { coin } } if coin == "ETH"
+        ));
+    }
+
+    #[test]
+    fn test_subscription_conflate_ms() {
+        let message = r#"{"type":"l4Book","coin":"BTC","conflateMs":100}"#;
+        let subscription: Subscription = serde_json::from_str(message).unwrap();
+        assert_eq!(subscription.conflate_interval(), Some(Duration::from_millis(100)));
+        assert_eq!(serde_json::to_string(&subscription).unwrap(), message);
+        let universe = HashSet::from(["BTC".to_string()]);
+        assert!(subscription.validate(&universe));
+
+        let subscription = Subscription::L4Book { coin: "BTC".to_string(), conflate_ms: Some(1) };
+        assert!(!subscription.validate(&universe));
+        // not conflated subscriptions serialize as before
+        let subscription = Subscription::L4Book { coin: "BTC".to_string(), conflate_ms: None };
+        assert_eq!(serde_json::to_string(&subscription).unwrap(), r#"{"type":"l4Book","coin":"BTC"}"#);
+    }
+
+    #[test]
+    fn test_subscription_fields() {
+        let message = r#"{"type":"bbo","coin":"BTC","fields":["seq","mid"]}"#;
+        let subscription: Subscription = serde_json::from_str(message).unwrap();
+        assert_eq!(subscription.fields(), Some(&["seq".to_string(), "mid".to_string()][..]));
+        assert_eq!(serde_json::to_string(&subscription).unwrap(), message);
+        let universe = HashSet::from(["BTC".to_string()]);
+        assert!(subscription.validate(&universe));
+
+        // l2 books have no mid, bbos no checksum
+        let message = r#"{"type":"l2Book","coin":"BTC","fields":["time","mid"]}"#;
+        let subscription: Subscription = serde_json::from_str(message).unwrap();
+        assert!(!subscription.validate(&universe));
+        let subscription = Subscription::Bbo { coin: "BTC".to_string(), fields: Some(vec!["checksum".to_string()]) };
+        assert!(!subscription.validate(&universe));
+        let subscription = Subscription::Bbo { coin: "BTC".to_string(), fields: None };
+        assert!(subscription.fields().is_none());
+        assert_eq!(serde_json::to_string(&subscription).unwrap(), r#"{"type":"bbo","coin":"BTC"}"#);
+    }
+
+    #[test]
+    fn test_l3_book_subscription() {
+        let subscription: Subscription = serde_json::from_str(r#"{"type":"l3Book","coin":"BTC"}"#).unwrap();
+        assert_eq!(subscription.coin(), Some("BTC"));
+        assert!(subscription.validate(&HashSet::from(["BTC".to_string(), "@1".to_string()])));
+        assert!(!Subscription::L3Book { coin: "@1".to_string() }.validate(&HashSet::from(["@1".to_string()])));
+    }
+
+    #[test]
+    fn test_user_orders_subscription() {
+        let message = r#"{"type":"userOrders","user":"0x0000000000000000000000000000000000000001","timestamp":1,"signature":"0xab"}"#;
+        let subscription: Subscription = serde_json::from_str(message).unwrap();
+        assert_eq!(subscription.coin(), None);
+        // the signature is checked when subscribing
+        assert!(subscription.validate(&HashSet::new()));
+        assert_eq!(serde_json::to_string(&subscription).unwrap(), message);
+    }
+
+    #[test]
+    fn test_status_subscription() {
+        let subscription: Subscription = serde_json::from_str(r#"{"type":"status"}"#).unwrap();
+        assert_eq!(subscription, Subscription::Status);
+        assert!(subscription.validate(&HashSet::new()));
+    }
+
+    // every message the server sends reads the same with the client crate's types
+    #[test]
+    fn test_client_messages() {
+        let order = r#"{"user":null,"coin":"BTC","side":"B","limitPx":"100.0","sz":"1.5","oid":1,"timestamp":1,"triggerCondition":"N/A","isTrigger":false,"triggerPx":"0.0","isPositionTpsl":false,"reduceOnly":false,"orderType":"Limit","tif":"Gtc","cloid":null}"#;
+        let user = "0x0000000000000000000000000000000000000001";
+        let messages = [
+            r#"{"channel":"subscriptionResponse","data":{"method":"subscribe","subscription":{"type":"l2Book","coin":"BTC","nSigFigs":5,"nLevels":null,"mantissa":null}}}"#.to_string(),
+            r#"{"channel":"l2Book","data":{"coin":"BTC","time":1,"levels":[[{"px":"100","sz":"1.5","n":1}],[]],"seq":2,"checksum":3}}"#.to_string(),
+            r#"{"channel":"bbo","data":{"coin":"BTC","time":1,"seq":2,"bid":{"px":"100","sz":"1.5","n":1},"ask":null,"mid":null}}"#.to_string(),
+            format!(r#"{{"channel":"l4Book","data":{{"Snapshot":{{"coin":"BTC","time":1,"height":2,"seq":3,"levels":[[{order}],[]],"checksum":4}}}}}}"#),
+            format!(
+                r#"{{"channel":"l4Book","data":{{"Snapshot":{{"coin":"BTC","time":1,"height":2,"seq":3,"levels":[[{order}],[]],"checksum":4,"checkpoint":{{"coin":"BTC","time":1,"height":2,"seq":3,"checksum":4,"digest":"ab","signature":"cd"}}}}}}}}"#
+            ),
+            format!(
+                r#"{{"channel":"l4Book","data":{{"Updates":{{"time":1,"height":2,"seq":3,"checksum":4,"order_statuses":[{{"time":"2025-06-24T02:56:36.172847427","user":"{user}","status":"open","order":{order}}}],"book_diffs":[{{"user":"{user}","oid":1,"px":"100.0","coin":"BTC","raw_book_diff":{{"new":{{"sz":"1.5"}}}}}},{{"user":"{user}","oid":2,"px":"100.0","coin":"BTC","raw_book_diff":"remove"}}]}}}}}}"#
+            ),
+            r#"{"channel":"l3Book","data":{"Snapshot":{"coin":"BTC","time":1,"height":2,"seq":3,"levels":[[{"oid":1,"px":"100","sz":"1.5","timestamp":1}],[]]}}}"#.to_string(),
+            r#"{"channel":"l3Book","data":{"Updates":{"coin":"BTC","time":1,"height":2,"seq":3,"events":[{"type":"add","oid":1,"side":"B","px":"100","sz":"1.5","timestamp":1},{"type":"modify","oid":1,"side":"B","px":"100","origSz":"1.5","sz":"1"},{"type":"cancel","oid":1,"side":"B","px":"100","status":"filled"},{"type":"cancel","oid":2,"side":"A","px":"101","status":null}]}}}"#.to_string(),
+            format!(
+                r#"{{"channel":"subscriptionResponse","data":{{"method":"subscribe","subscription":{{"type":"userOrders","user":"{user}","timestamp":1,"signature":"0xab"}}}}}}"#
+            ),
+            format!(
+                r#"{{"channel":"userOrders","data":{{"user":"{user}","time":1,"height":2,"events":[{{"type":"order","time":"2025-06-24T02:56:36.172847427","user":"{user}","status":"filled","order":{order}}},{{"type":"fill","coin":"BTC","px":"100.0","sz":"1.5","side":"B","time":1,"startPosition":"0.0","dir":"Open Long","closedPnl":"0.0","hash":"0x0","oid":1,"crossed":true,"fee":"0.01","tid":2,"feeToken":"USDC","liquidation":null}}]}}}}"#
+            ),
+            format!(
+                r#"{{"channel":"trades","data":[{{"coin":"BTC","side":"A","px":"100.0","sz":"1.5","hash":"0x0","time":1,"tid":2,"users":["{user}","{user}"],"taker":"{user}","maker":"{user}"}}]}}"#
+            ),
+            r#"{"channel":"candle","data":{"t":0,"T":59999,"s":"BTC","i":"1m","o":"1","c":"2","h":"3","l":"1","v":"4","n":5}}"#.to_string(),
+            r#"{"channel":"analytics","data":{"coin":"BTC","time":1,"bid":"99","ask":"101","spread":"2","spreadBps":200.0,"imbalance":0.5,"depth":10,"vwap":null,"vwapWindow":60000,"volume":"0","trades":0}}"#.to_string(),
+            r#"{"channel":"checkpoint","data":{"coin":"BTC","time":1,"height":2,"seq":3,"checksum":4,"digest":"ab","signature":"cd"}}"#.to_string(),
+            r#"{"channel":"heartbeat","data":{"time":1,"l2Seq":2,"l4Seqs":{"BTC":3}}}"#.to_string(),
+            r#"{"channel":"status","data":{"market":"testnet","stale":false,"ready":true,"lastBlock":1,"lastBlockTime":2,"lagMs":3,"upstreams":[{"node":"a","connected":true}],"snapshotInProgress":false,"gaps":0,"crossedBooks":["BTC"],"maintenance":false,"time":4}}"#.to_string(),
+            r#"{"channel":"markets","data":[{"coin":"BTC","kind":"perp","base":"BTC","quote":"USDC","szDecimals":5,"lotSize":"0.00001","tickSize":"0.1","status":"active"}]}"#.to_string(),
+            r#"{"channel":"marketChanges","data":[{"change":"added","market":{"coin":"@1","kind":"spot","base":"HFUN","quote":"USDC","szDecimals":2,"lotSize":"0.01","tickSize":"0.000001","status":"halted"}}]}"#.to_string(),
+            r#"{"channel":"maintenance","data":{"start":1,"message":"upgrade","closeCode":4503}}"#.to_string(),
+            r#"{"channel":"halt","data":{"coin":"BTC","reason":"audit","time":1}}"#.to_string(),
+            r#"{"channel":"resume","data":{"coin":"BTC","reason":"market","time":2}}"#.to_string(),
+            r#"{"channel":"evicted","data":{"action":"evicted","reason":"queueFull","time":1,"queueCapacity":4096,"queueDepthHistory":[12,4096],"drainRate":2.5,"subscriptions":[{"type":"trades","coin":"BTC"}]}}"#.to_string(),
+            r#"{"channel":"error","data":"Invalid subscription"}"#.to_string(),
+            r#"{"error":{"code":1003,"msg":"unknown method"}}"#.to_string(),
+        ];
+        for json in messages {
+            let msg: ServerResponse = serde_json::from_str(&json).unwrap();
+            let sent = serde_json::to_value(&msg).unwrap();
+            let received = order_book_client::Message::parse(&sent.to_string()).unwrap();
+            assert_eq!(received.len(), 1);
+            assert_eq!(serde_json::to_value(&received[0]).unwrap(), sent, "{json}");
+            // the same message in the frames of `orderbook.msgpack` connections
+            let payload = crate::servers::encoding::Encoding::MessagePack.encode(&msg).unwrap().payload;
+            assert_eq!(order_book_client::Message::parse_msgpack(&payload).unwrap(), received, "{json}");
+        }
+
+        let levels = [vec![Level::new("100".to_string(), "1.5".to_string(), 1)], Vec::new()];
+        let client_levels = [
+            vec![order_book_client::messages::Level { px: "100".to_string(), sz: "1.5".to_string(), n: 1 }],
+            Vec::new(),
+        ];
+        assert_eq!(crate::types::checksum(&levels), order_book_client::checksum(&client_levels));
+    }
 }
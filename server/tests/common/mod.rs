@@ -0,0 +1,513 @@
+// An in-process server reading a mock node, and websocket clients to talk to it.
+//
+// The mock node writes a block of events for `COIN` every `BLOCK_TIME` to its event files, like a node does, and
+// answers the info requests the server makes: the market metadata, and the snapshot of its books written to the
+// path the server asks for. The server gets its first snapshot about 6 seconds after it starts, which `start`
+// waits for.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs::{OpenOptions, create_dir_all},
+    io::{Read, Write},
+    net::{Ipv4Addr, SocketAddr, TcpListener as StdTcpListener},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::{Json, Router, extract::State, http::StatusCode, routing::post};
+use chrono::Utc;
+use futures_util::{SinkExt, StreamExt};
+use order_book_client::{Message, Request};
+use serde_json::{Value, json};
+#[cfg(feature = "testing")]
+use server::testing::{ScriptedSource, run_scripted_server};
+use server::{Result, ServerConfig, ShutdownRequest, ShutdownTrigger, UpstreamNode, run_websocket_server};
+use tempfile::TempDir;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{Mutex, Notify},
+    task::JoinHandle,
+    time::{Instant, interval, sleep, timeout},
+};
+use tokio_tungstenite::{
+    MaybeTlsStream, WebSocketStream, connect_async,
+    tungstenite::{
+        Message as Frame,
+        client::IntoClientRequest,
+        handshake::client::{Request as Handshake, Response as HandshakeResponse},
+        protocol::{CloseFrame, frame::coding::CloseCode},
+    },
+};
+use yawc::{CompressionLevel, FrameView, OpCode, Options, WebSocket};
+
+pub(crate) const COIN: &str = "BTC";
+const USER: &str = "0x0000000000000000000000000000000000000001";
+const BLOCK_TIME: Duration = Duration::from_millis(100);
+// orders resting on the book, the oldest is removed as a new one is added
+const RESTING: usize = 24;
+const MID: u64 = 100_000;
+const SOURCES: [&str; 3] = ["node_order_statuses_by_block", "node_raw_book_diffs_by_block", "node_fills_by_block"];
+// the first snapshot is fetched 5 seconds after the server starts
+const READY_TIMEOUT: Duration = Duration::from_secs(30);
+const MESSAGE_TIMEOUT: Duration = Duration::from_secs(10);
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy)]
+struct Order {
+    side: &'static str,
+    px: u64,
+    sz: u64,
+}
+
+// the node's books, and the blocks it wrote so far
+struct Chain {
+    dir: PathBuf,
+    height: u64,
+    // by oid, oldest first
+    orders: BTreeMap<u64, Order>,
+}
+
+impl Chain {
+    fn advance(&mut self) -> Result<()> {
+        for (source, line) in SOURCES.into_iter().zip(self.next_block()) {
+            let path = event_dir(&self.dir, source).join("0");
+            OpenOptions::new().create(true).append(true).open(path)?.write_all(line.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    // the lines of the next block for each of `SOURCES`. Every block adds an order, grows the one added before every
+    // third block and removes the oldest once the book is full
+    fn next_block(&mut self) -> [String; 3] {
+        self.height += 1;
+        let oid = self.height;
+        let order = if oid.is_multiple_of(2) {
+            Order { side: "B", px: MID - 1 - oid % 7, sz: 1 + oid % 3 }
+        } else {
+            Order { side: "A", px: MID + 1 + oid % 7, sz: 1 + oid % 3 }
+        };
+        let statuses =
+            vec![json!({"time": block_time(), "user": USER, "status": "open", "order": status_order(oid, order)})];
+        let mut diffs = vec![diff(oid, order, json!({"new": {"sz": order.sz.to_string()}}))];
+        if oid.is_multiple_of(3)
+            && let Some(previous) = self.orders.get_mut(&(oid - 1))
+        {
+            let update = json!({"update": {"origSz": previous.sz.to_string(), "newSz": (previous.sz + 1).to_string()}});
+            diffs.push(diff(oid - 1, *previous, update));
+            previous.sz += 1;
+        }
+        if self.orders.len() >= RESTING
+            && let Some((oldest, removed)) = self.orders.pop_first()
+        {
+            diffs.push(diff(oldest, removed, json!("remove")));
+        }
+        self.orders.insert(oid, order);
+        let time = block_time();
+        [statuses, diffs, Vec::new()].map(|events| {
+            let batch = json!({"local_time": time, "block_time": time, "block_number": self.height, "events": events});
+            format!("{batch}\n")
+        })
+    }
+
+    // `[height, [[coin, [bids, asks]]]]`, each side best first
+    fn snapshot(&self) -> Value {
+        let mut orders = self.orders.iter().collect::<Vec<_>>();
+        orders.sort_by_key(|(oid, order)| (if order.side == "B" { MID - order.px } else { order.px }, **oid));
+        let (bids, asks): (Vec<_>, Vec<_>) = orders.into_iter().partition(|(_, order)| order.side == "B");
+        let side = |orders: Vec<(&u64, &Order)>| {
+            orders.into_iter().map(|(oid, order)| json!([USER, snapshot_order(*oid, *order)])).collect::<Vec<_>>()
+        };
+        json!([self.height, [[COIN, [side(bids), side(asks)]]]])
+    }
+}
+
+// the node writes a file per hour, in a directory per day; the mock writes to a single one
+fn event_dir(dir: &Path, source: &str) -> PathBuf {
+    dir.join(format!("hl/data/{source}/hourly/20250624"))
+}
+
+fn block_time() -> String {
+    Utc::now().naive_utc().format("%Y-%m-%dT%H:%M:%S%.f").to_string()
+}
+
+fn status_order(oid: u64, order: Order) -> Value {
+    json!({
+        "user": null,
+        "coin": COIN,
+        "side": order.side,
+        "limitPx": order.px.to_string(),
+        "sz": order.sz.to_string(),
+        "oid": oid,
+        "timestamp": oid,
+        "triggerCondition": "N/A",
+        "isTrigger": false,
+        "triggerPx": "0.0",
+        "isPositionTpsl": false,
+        "reduceOnly": false,
+        "orderType": "Limit",
+        "tif": "Gtc",
+        "cloid": null,
+    })
+}
+
+fn snapshot_order(oid: u64, order: Order) -> Value {
+    let mut snapshot_order = status_order(oid, order);
+    snapshot_order["children"] = json!([]);
+    snapshot_order["origSz"] = json!(order.sz.to_string());
+    if let Some(fields) = snapshot_order.as_object_mut() {
+        fields.remove("user");
+    }
+    snapshot_order
+}
+
+fn diff(oid: u64, order: Order, raw_book_diff: Value) -> Value {
+    json!({"user": USER, "oid": oid, "px": order.px.to_string(), "coin": COIN, "raw_book_diff": raw_book_diff})
+}
+
+async fn info(State(chain): State<Arc<Mutex<Chain>>>, Json(request): Json<Value>) -> (StatusCode, Json<Value>) {
+    match request["type"].as_str() {
+        Some("fileSnapshot") => {
+            let Some(path) = request["outPath"].as_str() else {
+                return (StatusCode::BAD_REQUEST, Json(Value::Null));
+            };
+            // the snapshot is of the last block written
+            let chain = chain.lock().await;
+            match tokio::fs::write(path, chain.snapshot().to_string()).await {
+                Ok(()) => (StatusCode::OK, Json(Value::Null)),
+                Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(Value::Null)),
+            }
+        }
+        Some("meta") => (StatusCode::OK, Json(json!({"universe": [{"name": COIN, "szDecimals": 5}]}))),
+        Some("spotMeta") => (StatusCode::OK, Json(json!({"universe": [], "tokens": []}))),
+        _ => (StatusCode::BAD_REQUEST, Json(Value::Null)),
+    }
+}
+
+/// The first `blocks` blocks of a [`MockNode`], played after books without orders.
+#[cfg(feature = "testing")]
+pub(crate) fn script(blocks: u64) -> ScriptedSource {
+    let mut chain = Chain { dir: PathBuf::new(), height: 0, orders: BTreeMap::new() };
+    (0..blocks).fold(ScriptedSource::new().empty_books(0), |source, _| {
+        let [statuses, diffs, fills] = chain.next_block();
+        source.order_statuses(statuses).order_diffs(diffs).fills(fills)
+    })
+}
+
+/// A node writing blocks to a temporary directory, with an info endpoint on an ephemeral port.
+pub(crate) struct MockNode {
+    dir: TempDir,
+    info_url: String,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl MockNode {
+    pub(crate) async fn start() -> Result<Self> {
+        let dir = TempDir::new()?;
+        for source in SOURCES {
+            create_dir_all(event_dir(dir.path(), source))?;
+        }
+        let chain = Arc::new(Mutex::new(Chain { dir: dir.path().to_path_buf(), height: 0, orders: BTreeMap::new() }));
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let info_url = format!("http://{}/info", listener.local_addr()?);
+        let app = Router::new().route("/info", post(info)).with_state(chain.clone());
+        let serve = tokio::spawn(async move {
+            let _unused = axum::serve(listener, app).await;
+        });
+        let produce = tokio::spawn(async move {
+            let mut ticker = interval(BLOCK_TIME);
+            loop {
+                ticker.tick().await;
+                if chain.lock().await.advance().is_err() {
+                    return;
+                }
+            }
+        });
+        Ok(Self { dir, info_url, tasks: vec![serve, produce] })
+    }
+
+    pub(crate) fn upstream(&self) -> UpstreamNode {
+        UpstreamNode { data_dir: self.dir.path().to_path_buf(), info_url: self.info_url.clone() }
+    }
+}
+
+impl Drop for MockNode {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+// fires once the test asks the server to stop
+#[derive(Debug)]
+struct TestShutdown(Arc<Notify>);
+
+impl ShutdownTrigger for TestShutdown {
+    fn listen(&self) -> Result<ShutdownRequest> {
+        let notify = self.0.clone();
+        Ok(Box::pin(async move {
+            notify.notified().await;
+            "test finished".to_string()
+        }))
+    }
+}
+
+/// [`run_websocket_server`] on ephemeral ports, reading a [`MockNode`] or a scripted source.
+pub(crate) struct TestServer {
+    port: u16,
+    health_port: u16,
+    shutdown: Arc<Notify>,
+    task: JoinHandle<Result<()>>,
+}
+
+impl TestServer {
+    /// Starts the server with the settings `configure` changes, and waits until its books are ready.
+    pub(crate) async fn start(node: &MockNode, configure: impl FnOnce(&mut ServerConfig)) -> Result<Self> {
+        let configure = |config: &mut ServerConfig| {
+            config.upstreams = vec![node.upstream()];
+            configure(config);
+        };
+        Self::spawn(configure, run_websocket_server).await
+    }
+
+    /// Starts the server reading `source` instead of a node, and waits until its books are ready.
+    #[cfg(feature = "testing")]
+    pub(crate) async fn start_scripted(source: ScriptedSource) -> Result<Self> {
+        Self::spawn(|_| {}, |config| run_scripted_server(config, source)).await
+    }
+
+    async fn spawn<F>(configure: impl FnOnce(&mut ServerConfig), run: impl FnOnce(ServerConfig) -> F) -> Result<Self>
+    where
+        F: Future<Output = Result<()>> + Send + 'static,
+    {
+        let [port, health_port] = free_ports()?;
+        let shutdown = Arc::new(Notify::new());
+        let mut config = ServerConfig::new(SocketAddr::from((Ipv4Addr::LOCALHOST, port)));
+        config.health_port = Some(health_port);
+        config.drain_timeout = Duration::from_secs(2);
+        config.shutdown_triggers.push(Arc::new(TestShutdown(shutdown.clone())));
+        configure(&mut config);
+        let server = Self { port, health_port, shutdown, task: tokio::spawn(run(config)) };
+        server.ready().await?;
+        Ok(server)
+    }
+
+    async fn ready(&self) -> Result<()> {
+        let deadline = Instant::now() + READY_TIMEOUT;
+        let url = format!("http://{}/readyz", SocketAddr::from((Ipv4Addr::LOCALHOST, self.health_port)));
+        while Instant::now() < deadline {
+            if self.task.is_finished() {
+                return Err("server stopped before it was ready".into());
+            }
+            if reqwest::get(&url).await.is_ok_and(|res| res.status().is_success()) {
+                return Ok(());
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        Err("server not ready in time".into())
+    }
+
+    pub(crate) fn url(&self, path: &str) -> String {
+        format!("http://127.0.0.1:{}{path}", self.port)
+    }
+
+    pub(crate) fn ws_url(&self) -> String {
+        format!("ws://127.0.0.1:{}/ws", self.port)
+    }
+
+    /// Shuts the server down gracefully, returning what [`run_websocket_server`] did once it drained.
+    pub(crate) async fn shutdown(self) -> Result<()> {
+        self.shutdown.notify_one();
+        timeout(SHUTDOWN_TIMEOUT, self.task).await.map_err(|_| "server didn't stop in time")??
+    }
+}
+
+// held at once, so that they differ
+fn free_ports<const N: usize>() -> Result<[u16; N]> {
+    let listeners =
+        (0..N).map(|_| StdTcpListener::bind((Ipv4Addr::LOCALHOST, 0))).collect::<std::io::Result<Vec<_>>>()?;
+    let ports = listeners.iter().map(|listener| Ok(listener.local_addr()?.port())).collect::<Result<Vec<_>>>()?;
+    ports.try_into().map_err(|_| "no ports".into())
+}
+
+// tungstenite can't negotiate permessage-deflate, so those connections are yawc's
+enum Socket {
+    Tungstenite(Box<WebSocketStream<MaybeTlsStream<TcpStream>>>),
+    Yawc(Box<WebSocket>),
+}
+
+impl Socket {
+    async fn send(&mut self, frame: Frame) -> Result<()> {
+        match self {
+            Self::Tungstenite(ws) => Ok(ws.send(frame).await?),
+            Self::Yawc(ws) => {
+                let frame = match frame {
+                    Frame::Text(text) => FrameView::text(text.as_str().to_string()),
+                    Frame::Binary(payload) => FrameView::binary(payload),
+                    Frame::Ping(payload) => FrameView::ping(payload),
+                    Frame::Close(close) => close.map_or_else(
+                        || FrameView::close_raw(Vec::new()),
+                        |close| FrameView::close(u16::from(close.code).into(), close.reason.as_str()),
+                    ),
+                    Frame::Pong(_) | Frame::Frame(_) => return Err("not sent by the tests".into()),
+                };
+                Ok(ws.send(frame).await?)
+            }
+        }
+    }
+
+    // as tungstenite's frames, with yawc's decompressed
+    async fn next(&mut self) -> Option<Result<Frame>> {
+        match self {
+            Self::Tungstenite(ws) => ws.next().await.map(|frame| frame.map_err(Into::into)),
+            Self::Yawc(ws) => {
+                let frame = ws.next().await?;
+                Some(Ok(match frame.opcode {
+                    OpCode::Text => Frame::text(frame.as_str()),
+                    OpCode::Binary => Frame::binary(frame.payload),
+                    OpCode::Ping => Frame::Ping(frame.payload),
+                    OpCode::Pong => Frame::Pong(frame.payload),
+                    OpCode::Close => Frame::Close(frame.close_code().map(|code| CloseFrame {
+                        code: CloseCode::from(u16::from(code)),
+                        reason: frame.close_reason().unwrap_or_default().into(),
+                    })),
+                    OpCode::Continuation => return Some(Err("unassembled continuation frame".into())),
+                }))
+            }
+        }
+    }
+}
+
+/// A websocket connection to a [`TestServer`], reading the messages of its frames one by one.
+pub(crate) struct TestClient {
+    ws: Socket,
+    received: VecDeque<Message>,
+    // binary frames are zstd compressed with it, on `.zstd` connections
+    zstd_dictionary: Option<Vec<u8>>,
+}
+
+impl TestClient {
+    pub(crate) async fn connect(server: &TestServer) -> Result<Self> {
+        Ok(Self::connect_with(server.ws_url().into_client_request()?).await?.0)
+    }
+
+    /// Connects with the headers of `handshake`, returning the server's response to it too.
+    pub(crate) async fn connect_with(handshake: Handshake) -> Result<(Self, HandshakeResponse)> {
+        let (ws, response) = timeout(MESSAGE_TIMEOUT, connect_async(handshake)).await??;
+        Ok((Self { ws: Socket::Tungstenite(Box::new(ws)), received: VecDeque::new(), zstd_dictionary: None }, response))
+    }
+
+    /// Connects offering permessage-deflate, which the server may or may not accept.
+    pub(crate) async fn connect_deflate(server: &TestServer) -> Result<Self> {
+        let options = Options::default().with_compression_level(CompressionLevel::fast());
+        let connect = WebSocket::connect(server.ws_url().parse()?).with_options(options);
+        let ws = timeout(MESSAGE_TIMEOUT, connect).await??;
+        Ok(Self { ws: Socket::Yawc(Box::new(ws)), received: VecDeque::new(), zstd_dictionary: None })
+    }
+
+    pub(crate) fn decompress_with(&mut self, zstd_dictionary: Vec<u8>) {
+        self.zstd_dictionary = Some(zstd_dictionary);
+    }
+
+    pub(crate) async fn send(&mut self, request: &Request) -> Result<()> {
+        self.ws.send(Frame::text(serde_json::to_string(request)?)).await
+    }
+
+    /// Sends a ping, and waits for the pong with its payload, keeping the messages that arrive before it.
+    pub(crate) async fn ping(&mut self, payload: &'static [u8]) -> Result<()> {
+        self.ws.send(Frame::Ping(payload.into())).await?;
+        let deadline = Instant::now() + MESSAGE_TIMEOUT;
+        loop {
+            let frame = timeout(deadline.saturating_duration_since(Instant::now()), self.ws.next()).await;
+            match frame.map_err(|_| "no pong in time")?.ok_or("connection closed")?? {
+                Frame::Pong(pong) if pong.as_ref() == payload => return Ok(()),
+                Frame::Text(text) => self.received.extend(Message::parse(&text)?),
+                Frame::Close(close) => return Err(format!("connection closed: {close:?}").into()),
+                _ => {}
+            }
+        }
+    }
+
+    /// Starts the closing handshake, and returns the close frame the server echoes.
+    pub(crate) async fn close(&mut self, code: CloseCode, reason: &str) -> Result<Option<CloseFrame>> {
+        self.ws.send(Frame::Close(Some(CloseFrame { code, reason: reason.into() }))).await?;
+        self.closed().await
+    }
+
+    /// The next message, other than the heartbeats and statuses the server sends by itself.
+    pub(crate) async fn next(&mut self) -> Result<Message> {
+        loop {
+            let Some(msg) = self.received.pop_front() else {
+                self.receive(MESSAGE_TIMEOUT).await?.ok_or("no message in time")?;
+                continue;
+            };
+            if !matches!(msg, Message::Heartbeat(_) | Message::Status(_) | Message::Time(_)) {
+                return Ok(msg);
+            }
+        }
+    }
+
+    /// The first of the next messages that `f` picks.
+    pub(crate) async fn next_where<T>(&mut self, mut f: impl FnMut(Message) -> Option<T>) -> Result<T> {
+        let deadline = Instant::now() + MESSAGE_TIMEOUT;
+        while Instant::now() < deadline {
+            if let Some(picked) = f(self.next().await?) {
+                return Ok(picked);
+            }
+        }
+        Err("no such message in time".into())
+    }
+
+    /// Every message that arrives within `period`.
+    pub(crate) async fn drain(&mut self, period: Duration) -> Result<Vec<Message>> {
+        let deadline = Instant::now() + period;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() || self.receive(remaining).await?.is_none() {
+                return Ok(self.received.drain(..).collect());
+            }
+        }
+    }
+
+    /// Reads until the server closes the connection, returning its close frame.
+    pub(crate) async fn closed(&mut self) -> Result<Option<CloseFrame>> {
+        let deadline = Instant::now() + MESSAGE_TIMEOUT;
+        loop {
+            let frame = timeout(deadline.saturating_duration_since(Instant::now()), self.ws.next()).await;
+            match frame.map_err(|_| "not closed in time")? {
+                Some(Ok(Frame::Close(close))) => return Ok(close),
+                Some(Ok(_)) => {}
+                Some(Err(err)) => return Err(err),
+                None => return Ok(None),
+            }
+        }
+    }
+
+    // the messages of the next data frame, None if there was none within `wait`
+    async fn receive(&mut self, wait: Duration) -> Result<Option<()>> {
+        let deadline = Instant::now() + wait;
+        loop {
+            let Ok(frame) = timeout(deadline.saturating_duration_since(Instant::now()), self.ws.next()).await else {
+                return Ok(None);
+            };
+            let msgs = match frame.ok_or("connection closed")?? {
+                Frame::Text(text) => Message::parse(&text)?,
+                Frame::Binary(payload) => match &self.zstd_dictionary {
+                    Some(dictionary) => Message::parse_msgpack(&decompress(&payload, dictionary)?)?,
+                    None => Message::parse_msgpack(&payload)?,
+                },
+                Frame::Close(close) => return Err(format!("connection closed: {close:?}").into()),
+                Frame::Ping(_) | Frame::Pong(_) | Frame::Frame(_) => continue,
+            };
+            self.received.extend(msgs);
+            return Ok(Some(()));
+        }
+    }
+}
+
+fn decompress(payload: &[u8], dictionary: &[u8]) -> Result<Vec<u8>> {
+    let mut decompressed = Vec::new();
+    zstd::stream::read::Decoder::with_dictionary(payload, dictionary)?.read_to_end(&mut decompressed)?;
+    Ok(decompressed)
+}
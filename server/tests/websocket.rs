@@ -0,0 +1,278 @@
+// End-to-end tests of the websocket server: `run_websocket_server` reading a mock node, with real clients.
+#![allow(unused_crate_dependencies)]
+
+mod common;
+
+use std::time::Duration;
+
+#[cfg(feature = "testing")]
+use common::script;
+use common::{COIN, MockNode, TestClient, TestServer};
+use order_book_client::{
+    OrderBook, Request, Subscription,
+    messages::{L2Book, L4Book, Message, RequestId},
+};
+use serde_json::Value;
+use server::Result;
+use tokio_tungstenite::tungstenite::{
+    client::IntoClientRequest,
+    http::{HeaderValue, header::SEC_WEBSOCKET_EXTENSIONS, header::SEC_WEBSOCKET_PROTOCOL},
+    protocol::frame::coding::CloseCode,
+};
+
+// past the data of the subscriptions the client already has
+async fn subscribe(client: &mut TestClient, subscription: Subscription) -> Result<()> {
+    let response = Message::SubscriptionResponse(Request::Subscribe { subscription: subscription.clone() });
+    client.send(&Request::Subscribe { subscription }).await?;
+    client.next_where(|msg| (msg == response).then_some(())).await
+}
+
+// the result of a `stats` request
+async fn stats(client: &mut TestClient) -> Result<Value> {
+    client.send(&Request::Stats { id: RequestId::Number(1) }).await?;
+    client
+        .next_where(|msg| match msg {
+            Message::Response(response) if response.id == RequestId::Number(1) => response.result,
+            _ => None,
+        })
+        .await
+}
+
+#[tokio::test]
+async fn test_snapshot_on_subscribe() -> Result<()> {
+    let node = MockNode::start().await?;
+    let server = TestServer::start(&node, |_| {}).await?;
+    let mut client = TestClient::connect(&server).await?;
+
+    subscribe(&mut client, Subscription::l4_book(COIN)).await?;
+    let Message::L4Book(L4Book::Snapshot { coin, height, levels, .. }) = client.next().await? else {
+        return Err("no l4 snapshot after subscribing".into());
+    };
+    assert_eq!(coin, COIN);
+    assert!(height > 0);
+    assert!(levels.iter().all(|side| !side.is_empty()));
+    assert!(levels.iter().flatten().all(|order| order.coin == COIN));
+
+    subscribe(&mut client, Subscription::l2_book(COIN)).await?;
+    let book = client
+        .next_where(|msg| match msg {
+            Message::L2Book(book) => Some(book),
+            _ => None,
+        })
+        .await?;
+    assert_eq!(book.coin, COIN);
+    let [bids, asks] = &book.levels;
+    assert!(!bids.is_empty() && !asks.is_empty());
+    assert!(bids[0].px.parse::<f64>()? < asks[0].px.parse::<f64>()?);
+
+    drop(client);
+    server.shutdown().await
+}
+
+#[cfg(feature = "testing")]
+#[tokio::test]
+async fn test_scripted_source() -> Result<()> {
+    let server = TestServer::start_scripted(script(30)).await?;
+    let mut client = TestClient::connect(&server).await?;
+
+    subscribe(&mut client, Subscription::l4_book(COIN)).await?;
+    let Message::L4Book(L4Book::Snapshot { height, levels, .. }) = client.next().await? else {
+        return Err("no l4 snapshot after subscribing".into());
+    };
+    // the script ended at block 30, with the last 24 orders resting
+    assert_eq!(height, 30);
+    assert_eq!(levels.iter().map(Vec::len).sum::<usize>(), 24);
+    assert!(levels.iter().flatten().all(|order| order.oid > 6));
+
+    drop(client);
+    server.shutdown().await
+}
+
+#[tokio::test]
+async fn test_updates_continue_the_snapshot() -> Result<()> {
+    let node = MockNode::start().await?;
+    let server = TestServer::start(&node, |_| {}).await?;
+    let mut client = TestClient::connect(&server).await?;
+    subscribe(&mut client, Subscription::l4_book(COIN)).await?;
+
+    // the client's book checks the seq and checksum of every update against the snapshot
+    let mut book = OrderBook::new(COIN);
+    let snapshot = client.next().await?;
+    let Message::L4Book(L4Book::Snapshot { mut seq, mut height, .. }) = snapshot else {
+        return Err("no l4 snapshot after subscribing".into());
+    };
+    assert!(book.apply(&snapshot)?);
+    for _ in 0..20 {
+        let msg = client.next_where(|msg| matches!(msg, Message::L4Book(L4Book::Updates(_))).then_some(msg)).await?;
+        let Message::L4Book(L4Book::Updates(updates)) = &msg else {
+            unreachable!();
+        };
+        // the node changes the book in every block
+        assert_eq!((updates.seq, updates.height), (seq + 1, height + 1));
+        (seq, height) = (updates.seq, updates.height);
+        assert!(book.apply(&msg)?);
+    }
+    assert_eq!(book.seq(), Some(seq));
+    let (best_bid, best_ask) = (book.best_bid().ok_or("no bids")?, book.best_ask().ok_or("no asks")?);
+    assert!(best_bid.px.parse::<f64>()? < best_ask.px.parse::<f64>()?);
+
+    drop(client);
+    server.shutdown().await
+}
+
+#[tokio::test]
+async fn test_l2_deltas_continue_the_snapshot() -> Result<()> {
+    let node = MockNode::start().await?;
+    let server = TestServer::start(&node, |_| {}).await?;
+    let mut client = TestClient::connect(&server).await?;
+    subscribe(&mut client, Subscription::l2_deltas(COIN)).await?;
+
+    // the client's book checks that every delta follows the message before it, and its checksum
+    let mut book = OrderBook::new(COIN);
+    let snapshot = client.next().await?;
+    let Message::L2Book(L2Book { seq, .. }) = snapshot else {
+        return Err("no l2 snapshot after subscribing".into());
+    };
+    assert!(book.apply(&snapshot)?);
+    let (mut seq, epoch) = (seq, seq);
+    for _ in 0..10 {
+        let Message::L2Delta(delta) = client.next().await? else {
+            return Err("no l2 delta after the snapshot".into());
+        };
+        assert_eq!((delta.prev_seq, delta.epoch), (seq, epoch));
+        assert!(delta.seq > seq);
+        seq = delta.seq;
+        assert!(book.apply(&Message::L2Delta(delta))?);
+    }
+
+    // a fresh snapshot starts a new epoch, which the deltas after it follow
+    client.send(&Request::Snapshot { subscription: Subscription::l2_deltas(COIN) }).await?;
+    let snapshot = client.next_where(|msg| matches!(msg, Message::L2Book(_)).then_some(msg)).await?;
+    let Message::L2Book(L2Book { seq, .. }) = snapshot else {
+        unreachable!();
+    };
+    assert!(book.apply(&snapshot)?);
+    let delta = client.next_where(|msg| matches!(msg, Message::L2Delta(_)).then_some(msg)).await?;
+    assert!(matches!(&delta, Message::L2Delta(delta) if delta.prev_seq == seq && delta.epoch == seq));
+    assert!(book.apply(&delta)?);
+
+    drop(client);
+    server.shutdown().await
+}
+
+#[tokio::test]
+async fn test_unsubscribe() -> Result<()> {
+    let node = MockNode::start().await?;
+    let server = TestServer::start(&node, |_| {}).await?;
+    let mut client = TestClient::connect(&server).await?;
+    subscribe(&mut client, Subscription::l2_book(COIN)).await?;
+    client.next_where(|msg| matches!(msg, Message::L2Book(_)).then_some(())).await?;
+
+    let request = Request::Unsubscribe { subscription: Subscription::l2_book(COIN) };
+    client.send(&request).await?;
+    let response = Message::SubscriptionResponse(request);
+    client.next_where(|msg| (msg == response).then_some(())).await?;
+    // a few blocks
+    let msgs = client.drain(Duration::from_secs(1)).await?;
+    assert!(msgs.iter().all(|msg| !matches!(msg, Message::L2Book(_))), "{msgs:?}");
+    assert_eq!(stats(&mut client).await?["subscriptions"], Value::Array(Vec::new()));
+
+    drop(client);
+    server.shutdown().await
+}
+
+#[tokio::test]
+async fn test_compression_negotiation() -> Result<()> {
+    let node = MockNode::start().await?;
+    let server = TestServer::start(&node, |config| config.compression_level = 1).await?;
+
+    let mut handshake = server.ws_url().into_client_request()?;
+    handshake.headers_mut().insert(SEC_WEBSOCKET_EXTENSIONS, HeaderValue::from_static("permessage-deflate"));
+    let (deflate, response) = TestClient::connect_with(handshake).await?;
+    assert_eq!(response.headers().get(SEC_WEBSOCKET_EXTENSIONS), Some(&HeaderValue::from_static("permessage-deflate")));
+    drop(deflate);
+
+    let (mut plain, response) = TestClient::connect_with(server.ws_url().into_client_request()?).await?;
+    assert_eq!(response.headers().get(SEC_WEBSOCKET_EXTENSIONS), None);
+    let negotiated = stats(&mut plain).await?;
+    assert_eq!(negotiated["subprotocol"], "orderbook.json");
+    assert_eq!(negotiated["compression"], "none");
+
+    // zstd compresses the MessagePack frames with the dictionary the server serves
+    let mut handshake = server.ws_url().into_client_request()?;
+    handshake.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static("orderbook.msgpack.zstd"));
+    handshake.headers_mut().insert(SEC_WEBSOCKET_EXTENSIONS, HeaderValue::from_static("permessage-deflate"));
+    let (mut zstd, response) = TestClient::connect_with(handshake).await?;
+    let headers = response.headers();
+    assert_eq!(headers.get(SEC_WEBSOCKET_PROTOCOL), Some(&HeaderValue::from_static("orderbook.msgpack.zstd")));
+    assert_eq!(headers.get(SEC_WEBSOCKET_EXTENSIONS), None);
+    zstd.decompress_with(
+        reqwest::get(server.url("/zstd/dictionary")).await?.error_for_status()?.bytes().await?.to_vec(),
+    );
+    subscribe(&mut zstd, Subscription::l2_book(COIN)).await?;
+    let book = zstd.next_where(|msg| matches!(msg, Message::L2Book(_)).then_some(msg)).await?;
+    assert!(matches!(book, Message::L2Book(book) if book.coin == COIN));
+    assert_eq!(stats(&mut zstd).await?["compression"], "zstd");
+
+    drop((plain, zstd));
+    server.shutdown().await
+}
+
+#[tokio::test]
+async fn test_graceful_shutdown() -> Result<()> {
+    let node = MockNode::start().await?;
+    let server = TestServer::start(&node, |_| {}).await?;
+    let url = server.ws_url();
+    let mut client = TestClient::connect(&server).await?;
+    subscribe(&mut client, Subscription::l4_book(COIN)).await?;
+    client.next_where(|msg| matches!(msg, Message::L4Book(L4Book::Snapshot { .. })).then_some(())).await?;
+
+    // the server drains once the client completed the closing handshake
+    let (close, stopped) = tokio::join!(client.closed(), server.shutdown());
+    stopped?;
+    let close = close?.ok_or("closed without a close frame")?;
+    assert_eq!(close.code, CloseCode::Away);
+    assert_eq!(close.reason, "test finished");
+    assert!(TestClient::connect_with(url.into_client_request()?).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_shared_compression() -> Result<()> {
+    let node = MockNode::start().await?;
+    let server = TestServer::start(&node, |config| config.shared_compression = true).await?;
+    let mut plain = TestClient::connect(&server).await?;
+    let mut deflate = TestClient::connect_deflate(&server).await?;
+    assert_eq!(stats(&mut plain).await?["compression"], "none");
+    assert_eq!(stats(&mut deflate).await?["compression"], "sharedDeflate");
+
+    // the books both clients decode from the same blocks are the same
+    subscribe(&mut plain, Subscription::l2_book(COIN)).await?;
+    subscribe(&mut deflate, Subscription::l2_book(COIN)).await?;
+    deflate.ping(b"between books").await?;
+    let (plain_msgs, deflate_msgs) =
+        tokio::join!(plain.drain(Duration::from_secs(1)), deflate.drain(Duration::from_secs(1)));
+    let books = |msgs: Vec<Message>| {
+        msgs.into_iter()
+            .filter_map(|msg| match msg {
+                Message::L2Book(book) => Some((book.time, book)),
+                _ => None,
+            })
+            .collect::<std::collections::BTreeMap<_, _>>()
+    };
+    let (plain_books, deflate_books) = (books(plain_msgs?), books(deflate_msgs?));
+    let common = deflate_books.iter().filter(|(time, book)| plain_books.get(time) == Some(book)).count();
+    assert!(common >= 3 && common + 1 >= deflate_books.len(), "{plain_books:?}\n{deflate_books:?}");
+
+    // the close the client starts is echoed, and the server's own close on shutdown is the plain one
+    let close = deflate.close(CloseCode::Normal, "done").await?.ok_or("closed without a close frame")?;
+    assert_eq!(close.code, CloseCode::Normal);
+    let mut deflate = TestClient::connect_deflate(&server).await?;
+    subscribe(&mut deflate, Subscription::l4_book(COIN)).await?;
+    let (close, stopped) = tokio::join!(deflate.closed(), server.shutdown());
+    stopped?;
+    let close = close?.ok_or("closed without a close frame")?;
+    assert_eq!((close.code, close.reason.as_str()), (CloseCode::Away, "test finished"));
+    drop(plain);
+    Ok(())
+}